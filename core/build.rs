@@ -0,0 +1,26 @@
+//! Embeds the commit the daemon is built from, reported by `/info/daemon`
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    // a hash given by the build environment, e.g. a CI build without the .git directory, wins
+    println!("cargo:rerun-if-env-changed=LODESTONE_GIT_HASH");
+    if std::env::var_os("LODESTONE_GIT_HASH").is_some() {
+        return;
+    }
+    for path in ["../.git/HEAD", "../.git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=LODESTONE_GIT_HASH={}", hash.trim());
+    }
+}
@@ -0,0 +1,383 @@
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::eyre;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::scheduler::parse_cron;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum AnnouncementSchedule {
+    Interval {
+        seconds: u64,
+    },
+    /// Standard 5 field crontab expression, evaluated in UTC.
+    /// A leading seconds field is accepted as well
+    Cron {
+        expression: String,
+    },
+}
+
+impl AnnouncementSchedule {
+    fn validate(&self) -> Result<(), Error> {
+        match self {
+            AnnouncementSchedule::Interval { seconds } if *seconds == 0 => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Announcement interval must be greater than 0"),
+            }),
+            AnnouncementSchedule::Interval { .. } => Ok(()),
+            AnnouncementSchedule::Cron { expression } => parse_cron(expression).map(|_| ()),
+        }
+    }
+
+    /// First time the schedule fires strictly after `after`
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            AnnouncementSchedule::Interval { seconds } => {
+                Some(after + Duration::seconds(*seconds as i64))
+            }
+            AnnouncementSchedule::Cron { expression } => {
+                parse_cron(expression).ok()?.after(&after).next()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum RotationMode {
+    /// Entries sharing a schedule take turns in list order
+    #[default]
+    Sequential,
+    Random,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Announcement {
+    pub id: String,
+    /// Sent with `say`. Supports `{instance_name}`, `{online_players}` and `{max_players}`
+    pub message: String,
+    /// JSON text component sent with `tellraw` instead of `message` when present.
+    /// Supports the same placeholders
+    #[serde(default)]
+    pub json_component: Option<String>,
+    pub schedule: AnnouncementSchedule,
+    /// How this entry rotates with the other entries on the same schedule.
+    /// The mode of the first entry of a schedule applies to the whole group
+    #[serde(default)]
+    pub rotation: RotationMode,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct AnnouncementsConfig {
+    pub entries: Vec<Announcement>,
+    /// Skip announcements while nobody is online so empty servers don't fill their logs
+    #[serde(default = "default_true")]
+    pub require_players_online: bool,
+}
+
+impl Default for AnnouncementsConfig {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            require_players_online: true,
+        }
+    }
+}
+
+impl AnnouncementsConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.id.is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Announcement id cannot be empty"),
+                });
+            }
+            if self.entries[..i].iter().any(|e| e.id == entry.id) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Duplicate announcement id \"{}\"", entry.id),
+                });
+            }
+            if entry.message.trim().is_empty() && entry.json_component.is_none() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Announcement \"{}\" has no message", entry.id),
+                });
+            }
+            if let Some(component) = &entry.json_component {
+                validate_json_component(component).map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Announcement \"{}\": {}", entry.id, e.source),
+                })?;
+            }
+            entry.schedule.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Announcement> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+}
+
+/// A text component is a JSON string, object or array
+fn validate_json_component(component: &str) -> Result<(), Error> {
+    match serde_json::from_str::<serde_json::Value>(component) {
+        Ok(serde_json::Value::String(_))
+        | Ok(serde_json::Value::Object(_))
+        | Ok(serde_json::Value::Array(_)) => Ok(()),
+        Ok(_) => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("JSON component must be a string, an object or an array"),
+        }),
+        Err(e) => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Malformed JSON component: {}", e),
+        }),
+    }
+}
+
+/// Values substituted into announcement templates
+#[derive(Debug, Clone)]
+pub struct AnnouncementContext {
+    pub instance_name: String,
+    pub online_players: u32,
+    pub max_players: u32,
+}
+
+impl AnnouncementContext {
+    fn render(&self, template: &str, escape: impl Fn(&str) -> String) -> String {
+        template
+            .replace("{instance_name}", &escape(&self.instance_name))
+            .replace("{online_players}", &self.online_players.to_string())
+            .replace("{max_players}", &self.max_players.to_string())
+    }
+}
+
+impl Announcement {
+    /// The console command that sends this announcement
+    pub fn command(&self, context: &AnnouncementContext) -> String {
+        match &self.json_component {
+            Some(component) => {
+                // substituted values must not break out of the JSON strings they land in
+                let escape = |s: &str| {
+                    let quoted = serde_json::Value::String(s.to_string()).to_string();
+                    quoted[1..quoted.len() - 1].to_string()
+                };
+                format!("tellraw @a {}", context.render(component, escape))
+            }
+            None => format!(
+                "say {}",
+                context
+                    .render(&self.message, |s| s.to_string())
+                    .replace('\n', " ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ScheduleGroup {
+    schedule: AnnouncementSchedule,
+    rotation: RotationMode,
+    entry_ids: Vec<String>,
+    next_index: usize,
+    next_fire: Option<DateTime<Utc>>,
+}
+
+/// Decides which announcements are due.
+///
+/// Enabled entries sharing a schedule form a group, and each time the schedule fires one
+/// entry of the group is picked according to its rotation mode
+#[derive(Debug)]
+pub struct AnnouncementScheduler {
+    groups: Vec<ScheduleGroup>,
+}
+
+impl AnnouncementScheduler {
+    pub fn new(config: &AnnouncementsConfig, now: DateTime<Utc>) -> Self {
+        let mut groups: Vec<ScheduleGroup> = Vec::new();
+        for entry in config.entries.iter().filter(|e| e.enabled) {
+            match groups.iter_mut().find(|g| g.schedule == entry.schedule) {
+                Some(group) => group.entry_ids.push(entry.id.clone()),
+                None => groups.push(ScheduleGroup {
+                    schedule: entry.schedule.clone(),
+                    rotation: entry.rotation,
+                    entry_ids: vec![entry.id.clone()],
+                    next_index: 0,
+                    next_fire: entry.schedule.next_after(now),
+                }),
+            }
+        }
+        Self { groups }
+    }
+
+    /// Returns the ids of the entries due at `now`, at most one per schedule.
+    ///
+    /// Firings missed while the caller wasn't polling are skipped rather than replayed
+    pub fn due(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let mut due = Vec::new();
+        for group in self.groups.iter_mut() {
+            let next_fire = match group.next_fire {
+                Some(next_fire) if next_fire <= now => next_fire,
+                _ => continue,
+            };
+            let index = match group.rotation {
+                RotationMode::Sequential => {
+                    let index = group.next_index;
+                    group.next_index = (index + 1) % group.entry_ids.len();
+                    index
+                }
+                RotationMode::Random => thread_rng().gen_range(0, group.entry_ids.len()),
+            };
+            due.push(group.entry_ids[index].clone());
+            let mut next = group.schedule.next_after(next_fire);
+            while let Some(n) = next {
+                if n > now {
+                    break;
+                }
+                next = group.schedule.next_after(n);
+            }
+            group.next_fire = next;
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, schedule: AnnouncementSchedule, rotation: RotationMode) -> Announcement {
+        Announcement {
+            id: id.to_string(),
+            message: format!("{id} {{online_players}}/{{max_players}}"),
+            json_component: None,
+            schedule,
+            rotation,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_announcement_rotation() {
+        let every_minute = AnnouncementSchedule::Interval { seconds: 60 };
+        let hourly = AnnouncementSchedule::Cron {
+            expression: "0 * * * *".to_string(),
+        };
+        let mut disabled = entry("disabled", every_minute.clone(), RotationMode::Sequential);
+        disabled.enabled = false;
+        let config = AnnouncementsConfig {
+            entries: vec![
+                entry("a", every_minute.clone(), RotationMode::Sequential),
+                disabled,
+                entry("b", every_minute.clone(), RotationMode::Sequential),
+                entry("hourly", hourly, RotationMode::Sequential),
+                entry("c", every_minute, RotationMode::Sequential),
+            ],
+            require_players_online: true,
+        };
+        config.validate().unwrap();
+
+        let start = DateTime::parse_from_rfc3339("2023-01-01T00:00:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut scheduler = AnnouncementScheduler::new(&config, start);
+        assert!(scheduler.due(start).is_empty());
+
+        let mut fired = Vec::new();
+        for minute in 1..=5 {
+            fired.extend(scheduler.due(start + Duration::minutes(minute)));
+        }
+        assert_eq!(fired, vec!["a", "b", "c", "a", "b"]);
+
+        // missed firings are not replayed
+        let later = start + Duration::minutes(90);
+        assert_eq!(scheduler.due(later), vec!["c", "hourly"]);
+        assert!(scheduler.due(later).is_empty());
+    }
+
+    #[test]
+    fn test_announcement_random_rotation() {
+        let schedule = AnnouncementSchedule::Interval { seconds: 1 };
+        let config = AnnouncementsConfig {
+            entries: vec![
+                entry("a", schedule.clone(), RotationMode::Random),
+                entry("b", schedule, RotationMode::Random),
+            ],
+            require_players_online: true,
+        };
+        let start = Utc::now();
+        let mut scheduler = AnnouncementScheduler::new(&config, start);
+        for second in 1..=20 {
+            let due = scheduler.due(start + Duration::seconds(second));
+            assert_eq!(due.len(), 1);
+            assert!(due[0] == "a" || due[0] == "b");
+        }
+    }
+
+    #[test]
+    fn test_announcement_command() {
+        let context = AnnouncementContext {
+            instance_name: "My \"cool\" server".to_string(),
+            online_players: 3,
+            max_players: 20,
+        };
+        let mut announcement = entry(
+            "vote",
+            AnnouncementSchedule::Interval { seconds: 60 },
+            RotationMode::Sequential,
+        );
+        assert_eq!(announcement.command(&context), "say vote 3/20");
+
+        announcement.json_component =
+            Some(r#"{"text":"Welcome to {instance_name}","color":"gold"}"#.to_string());
+        let command = announcement.command(&context);
+        let component = command.strip_prefix("tellraw @a ").unwrap();
+        let value: serde_json::Value = serde_json::from_str(component).unwrap();
+        assert_eq!(value["text"], "Welcome to My \"cool\" server");
+    }
+
+    #[test]
+    fn test_announcements_validation() {
+        let schedule = AnnouncementSchedule::Interval { seconds: 60 };
+        let mut config = AnnouncementsConfig {
+            entries: vec![entry("a", schedule.clone(), RotationMode::Sequential)],
+            require_players_online: true,
+        };
+        assert!(config.validate().is_ok());
+
+        config.entries[0].json_component = Some("{\"text\": ".to_string());
+        assert!(config.validate().is_err());
+        config.entries[0].json_component = Some("42".to_string());
+        assert!(config.validate().is_err());
+        config.entries[0].json_component = Some("[\"\", {\"text\": \"hi\"}]".to_string());
+        assert!(config.validate().is_ok());
+
+        config
+            .entries
+            .push(entry("a", schedule, RotationMode::Sequential));
+        assert!(config.validate().is_err());
+        config.entries[1].id = "b".to_string();
+        config.entries[1].schedule = AnnouncementSchedule::Cron {
+            expression: "not a cron".to_string(),
+        };
+        assert!(config.validate().is_err());
+        config.entries[1].schedule = AnnouncementSchedule::Interval { seconds: 0 };
+        assert!(config.validate().is_err());
+    }
+}
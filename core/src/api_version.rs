@@ -0,0 +1,288 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::{request::Parts, HeaderValue, Request},
+    middleware::{from_fn_with_state, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::error::Error;
+
+/// Legacy unprefixed paths stop being served after this date
+pub const LEGACY_SUNSET: &str = "Thu, 01 Jul 2027 00:00:00 GMT";
+
+/// A version of the HTTP API, handlers take it as an extractor when their wire format differs
+/// between versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ApiVersion {
+    V1,
+}
+
+impl ApiVersion {
+    pub const ALL: [ApiVersion; 1] = [ApiVersion::V1];
+    /// What the legacy unprefixed paths are an alias of
+    pub const CURRENT: ApiVersion = ApiVersion::V1;
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/api/v1",
+        }
+    }
+
+    /// How this version differs from the one before it, empty for the first version
+    pub fn changes(self) -> Vec<ApiChange> {
+        match self {
+            ApiVersion::V1 => Vec::new(),
+        }
+    }
+
+    /// Converts a handler result into this version's wire format.
+    ///
+    /// Every version is currently the same envelope, a version changing it only adds a match arm
+    /// here instead of a copy of each handler
+    pub fn render<T: Serialize>(self, result: Result<T, Error>) -> Response {
+        match self {
+            ApiVersion::V1 => match result {
+                Ok(value) => Json(value).into_response(),
+                Err(e) => e.into_response(),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ApiVersion {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ApiVersion>()
+            .copied()
+            .unwrap_or(ApiVersion::CURRENT))
+    }
+}
+
+/// A machine-readable difference from the previous version
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ApiChange {
+    /// Route pattern the change applies to, relative to the version prefix
+    pub route: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ApiVersionInfo {
+    pub version: ApiVersion,
+    pub prefix: String,
+    pub changes: Vec<ApiChange>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct LegacyApiInfo {
+    pub alias_of: ApiVersion,
+    pub sunset: String,
+    /// Requests served on legacy paths since the core started
+    pub requests: u64,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ApiVersions {
+    pub current: ApiVersion,
+    pub versions: Vec<ApiVersionInfo>,
+    pub legacy: LegacyApiInfo,
+}
+
+/// Counts requests served on the deprecated unprefixed paths
+#[derive(Debug, Clone, Default)]
+pub struct LegacyTraffic(Arc<AtomicU64>);
+
+impl LegacyTraffic {
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+async fn legacy_alias<B>(
+    State(traffic): State<LegacyTraffic>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    traffic.0.fetch_add(1, Ordering::Relaxed);
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        ApiVersion::CURRENT.prefix(),
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("Deprecation", HeaderValue::from_static("true"));
+    headers.insert("Sunset", HeaderValue::from_static(LEGACY_SUNSET));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert("Link", link);
+    }
+    response
+}
+
+/// Serves `api_routes` under every version prefix and, deprecated, without a prefix
+pub fn mount(api_routes: Router, legacy_traffic: LegacyTraffic) -> Router {
+    let mut router = Router::new();
+    for version in ApiVersion::ALL {
+        router = router.nest(
+            version.prefix(),
+            api_routes.clone().layer(Extension(version)),
+        );
+    }
+    router.merge(
+        api_routes
+            .layer(Extension(ApiVersion::CURRENT))
+            .layer(from_fn_with_state(legacy_traffic, legacy_alias)),
+    )
+}
+
+pub async fn get_api_versions(State(legacy_traffic): State<LegacyTraffic>) -> Json<ApiVersions> {
+    Json(ApiVersions {
+        current: ApiVersion::CURRENT,
+        versions: ApiVersion::ALL
+            .into_iter()
+            .map(|version| ApiVersionInfo {
+                version,
+                prefix: version.prefix().to_string(),
+                changes: version.changes(),
+            })
+            .collect(),
+        legacy: LegacyApiInfo {
+            alias_of: ApiVersion::CURRENT,
+            sunset: LEGACY_SUNSET.to_string(),
+            requests: legacy_traffic.count(),
+        },
+    })
+}
+
+pub fn get_api_versions_routes(legacy_traffic: LegacyTraffic) -> Router {
+    Router::new()
+        .route("/api/versions", get(get_api_versions))
+        .with_state(legacy_traffic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::feature_stubs::feature_stub_routes;
+    use crate::handlers::system::get_features;
+    use axum::http::StatusCode;
+    use color_eyre::eyre::eyre;
+    use std::net::{SocketAddr, TcpListener};
+
+    async fn versioned_echo(version: ApiVersion) -> Response {
+        version.render(Ok(version))
+    }
+
+    async fn versioned_error(version: ApiVersion) -> Response {
+        version.render::<()>(Err(Error {
+            kind: crate::error::ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        }))
+    }
+
+    async fn serve(legacy_traffic: LegacyTraffic) -> SocketAddr {
+        let api_routes = Router::new()
+            .route("/system/features", get(get_features))
+            .route("/test/version", get(versioned_echo))
+            .route("/test/error", get(versioned_error))
+            .merge(feature_stub_routes(&[]));
+        let app = mount(api_routes, legacy_traffic.clone())
+            .merge(get_api_versions_routes(legacy_traffic));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_legacy_paths_match_v1() {
+        let legacy_traffic = LegacyTraffic::default();
+        let addr = serve(legacy_traffic.clone()).await;
+        let client = reqwest::Client::new();
+        let routes = [
+            "/system/features",
+            "/test/version",
+            "/test/error",
+            "/instance/INSTANCE_test/mods/search",
+        ];
+        for route in routes {
+            let v1 = client
+                .get(format!("http://{}/api/v1{}", addr, route))
+                .send()
+                .await
+                .unwrap();
+            assert!(v1.headers().get("Deprecation").is_none(), "{route}");
+            assert!(v1.headers().get("Sunset").is_none(), "{route}");
+            let v1_status = v1.status();
+            let v1_body = v1.text().await.unwrap();
+
+            let legacy = client
+                .get(format!("http://{}{}", addr, route))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(legacy.headers()["Deprecation"], "true", "{route}");
+            assert_eq!(legacy.headers()["Sunset"], LEGACY_SUNSET, "{route}");
+            assert_eq!(
+                legacy.headers()["Link"],
+                format!("</api/v1{}>; rel=\"successor-version\"", route).as_str()
+            );
+            assert_eq!(legacy.status(), v1_status, "{route}");
+            assert_eq!(legacy.text().await.unwrap(), v1_body, "{route}");
+        }
+        assert_eq!(legacy_traffic.count(), routes.len() as u64);
+
+        let versions: serde_json::Value = client
+            .get(format!("http://{}/api/versions", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(versions["current"], "v1");
+        assert_eq!(versions["versions"][0]["prefix"], "/api/v1");
+        assert_eq!(versions["legacy"]["requests"], routes.len() as u64);
+        // the discovery endpoint itself is not legacy traffic
+        assert_eq!(legacy_traffic.count(), routes.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_version_extractor() {
+        let addr = serve(LegacyTraffic::default()).await;
+        let body = reqwest::get(format!("http://{}/api/v1/test/version", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "\"v1\"");
+        let response = reqwest::get(format!("http://{}/test/error", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
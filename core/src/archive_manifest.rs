@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Name of the manifest inside archives produced by lodestone, at the archive root
+pub const MANIFEST_FILE_NAME: &str = ".lodestone_manifest.json";
+/// Upper bound on the threads hashing files during verification
+const MAX_VERIFY_THREADS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ManifestEntry {
+    pub size: u64,
+    /// Hex encoded blake3 hash of the content
+    pub blake3: String,
+}
+
+/// Size and hash of every file in an archive, keyed by `/` separated path relative to the
+/// archive root
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct MismatchedEntry {
+    pub path: String,
+    pub expected: ManifestEntry,
+    pub actual: ManifestEntry,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct VerificationReport {
+    /// Number of entries listed in the manifest
+    pub checked: u64,
+    pub mismatched: Vec<MismatchedEntry>,
+    /// Entries listed in the manifest that are absent or could not be read
+    pub missing: Vec<String>,
+}
+
+impl VerificationReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+
+    /// Turns a failed report into a `BadRequest` whose source is the report itself
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: self.into(),
+            })
+        }
+    }
+}
+
+impl Display for VerificationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Verification of {} files failed: {} mismatched, {} missing",
+            self.checked,
+            self.mismatched.len(),
+            self.missing.len()
+        )?;
+        for entry in &self.mismatched {
+            write!(f, "\nmismatched: {}", entry.path)?;
+        }
+        for path in &self.missing {
+            write!(f, "\nmissing: {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerificationReport {}
+
+/// Hashes everything read through it, so an archive entry's manifest line is produced while
+/// the file is streamed in
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        }
+    }
+
+    pub fn finish(self) -> ManifestEntry {
+        ManifestEntry {
+            size: self.size,
+            blake3: self.hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+impl ArchiveManifest {
+    pub fn insert(&mut self, path: &Path, entry: ManifestEntry) {
+        self.entries.insert(manifest_key(path), entry);
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec_pretty(self).context("Failed to serialize archive manifest")?)
+    }
+
+    /// Reads the manifest at the root of an extracted archive, `None` if there is none
+    pub fn read_from_dir(root: &Path) -> Result<Option<Self>, Error> {
+        let path = root.join(MANIFEST_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let manifest = serde_json::from_reader(
+            std::fs::File::open(&path).context("Failed to open archive manifest")?,
+        )
+        .map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Archive manifest is malformed: {}", e),
+        })?;
+        Ok(Some(manifest))
+    }
+
+    /// Checks the manifest against the hashes actually found, keyed the same way
+    pub fn compare(&self, actual: &BTreeMap<String, ManifestEntry>) -> VerificationReport {
+        let mut report = VerificationReport {
+            checked: self.entries.len() as u64,
+            ..Default::default()
+        };
+        for (key, expected) in &self.entries {
+            match actual.get(key) {
+                Some(actual) if actual == expected => {}
+                Some(actual) => report.mismatched.push(MismatchedEntry {
+                    path: key.clone(),
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                }),
+                None => report.missing.push(key.clone()),
+            }
+        }
+        report
+    }
+
+    /// Checks every file listed in the manifest against its copy under `root`.
+    ///
+    /// Blocks while hashing, files are spread over a bounded number of threads
+    pub fn verify_dir(&self, root: &Path) -> Result<VerificationReport, Error> {
+        let mut paths = Vec::with_capacity(self.entries.len());
+        for key in self.entries.keys() {
+            paths.push((key, key_to_path(root, key)?));
+        }
+        let queue = Mutex::new(paths.iter());
+        let report = Mutex::new(VerificationReport {
+            checked: self.entries.len() as u64,
+            ..Default::default()
+        });
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_VERIFY_THREADS)
+            .min(paths.len().max(1));
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let (key, path) = match queue.lock().unwrap().next() {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let expected = &self.entries[*key];
+                    match hash_file(path) {
+                        Ok(actual) if actual == *expected => {}
+                        Ok(actual) => report.lock().unwrap().mismatched.push(MismatchedEntry {
+                            path: key.to_string(),
+                            expected: expected.clone(),
+                            actual,
+                        }),
+                        Err(_) => report.lock().unwrap().missing.push(key.to_string()),
+                    }
+                });
+            }
+        });
+        let mut report = report.into_inner().unwrap();
+        report.mismatched.sort_by(|a, b| a.path.cmp(&b.path));
+        report.missing.sort();
+        Ok(report)
+    }
+}
+
+/// Hashes every file of a zip or tar.gz archive without extracting it and checks the result
+/// against the archive's manifest, `None` if the archive has none
+pub fn verify_archive(file: &Path) -> Result<Option<VerificationReport>, Error> {
+    let mut hashes = BTreeMap::new();
+    let mut manifest = None;
+    let mut visit = |name: &Path, reader: &mut dyn Read| -> Result<(), Error> {
+        let key = manifest_key(name);
+        if key == MANIFEST_FILE_NAME {
+            manifest = Some(
+                serde_json::from_reader::<_, ArchiveManifest>(reader).map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Archive manifest is malformed: {}", e),
+                })?,
+            );
+            return Ok(());
+        }
+        let mut reader = HashingReader::new(reader);
+        // an unreadable entry is left out and reported as missing
+        if std::io::copy(&mut reader, &mut std::io::sink()).is_ok() {
+            hashes.insert(key, reader.finish());
+        }
+        Ok(())
+    };
+    let archive_file =
+        std::fs::File::open(file).context(format!("Failed to open {}", file.display()))?;
+    if file.extension().map_or(false, |ext| ext == "zip") {
+        let mut archive = zip::ZipArchive::new(archive_file)
+            .context(format!("Failed to read archive {}", file.display()))?;
+        for i in 0..archive.len() {
+            let mut entry = archive
+                .by_index(i)
+                .context(format!("Failed to read entry of {}", file.display()))?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = match entry.enclosed_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            visit(&name, &mut entry)?;
+        }
+    } else {
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive_file));
+        for entry in archive
+            .entries()
+            .context(format!("Failed to read archive {}", file.display()))?
+        {
+            let mut entry = entry.context(format!("Failed to read entry of {}", file.display()))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry
+                .path()
+                .context(format!("Failed to read entry of {}", file.display()))?
+                .into_owned();
+            visit(&name, &mut entry)?;
+        }
+    }
+    Ok(manifest.map(|manifest| manifest.compare(&hashes)))
+}
+
+pub fn hash_file(path: &Path) -> std::io::Result<ManifestEntry> {
+    let mut reader = HashingReader::new(std::fs::File::open(path)?);
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.finish())
+}
+
+fn manifest_key(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Resolves a manifest key under `root`, refusing keys that would escape it
+pub(crate) fn key_to_path(root: &Path, key: &str) -> Result<PathBuf, Error> {
+    let mut path = root.to_path_buf();
+    for part in key.split('/') {
+        if part.is_empty() || part == "." || part == ".." || Path::new(part).has_root() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Archive manifest has an unsafe path {}", key),
+            });
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join("world")).unwrap();
+        std::fs::write(root.join("world/level.dat"), "level").unwrap();
+        std::fs::write(root.join("server.properties"), "motd=hi").unwrap();
+
+        let mut manifest = ArchiveManifest::default();
+        for path in ["world/level.dat", "server.properties"] {
+            manifest.insert(Path::new(path), hash_file(&root.join(path)).unwrap());
+        }
+        assert!(manifest.verify_dir(root).unwrap().is_ok());
+
+        std::fs::write(root.join("world/level.dat"), "levem").unwrap();
+        std::fs::remove_file(root.join("server.properties")).unwrap();
+        let report = manifest.verify_dir(root).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(report.mismatched[0].path, "world/level.dat");
+        assert_eq!(report.missing, vec!["server.properties".to_string()]);
+
+        manifest.entries.insert(
+            "../escape".to_string(),
+            manifest.entries["world/level.dat"].clone(),
+        );
+        assert!(manifest.verify_dir(root).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::Context;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::Error;
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, EventInner, FSOperation, FSTarget};
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::GameInstance;
+use crate::types::{InstanceUuid, Snowflake};
+
+/// A log past this size is rotated, so each instance keeps at most twice as much
+const MAX_AUDIT_LOG_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_AUDIT_PAGE_SIZE: usize = 50;
+pub const MAX_AUDIT_PAGE_SIZE: usize = 500;
+/// Stands in for the value of settings marked secret
+const REDACTED: &str = "<redacted>";
+/// Changes to the daemon settings are logged as if by an instance of this UUID
+pub const DAEMON_AUDIT_UUID: &str = "DAEMON";
+
+/// What an audit entry changed
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum AuditTarget {
+    /// A setting of the instance's configurable manifest
+    Setting {
+        section_id: String,
+        setting_id: String,
+    },
+    /// A key of the game's own settings file, e.g. server.properties
+    GameSetting { key: String },
+    /// An instance property with its own endpoint, e.g. the name or restart policy
+    Property { name: String },
+    File {
+        path: PathBuf,
+        operation: FSOperation,
+    },
+    /// A setting of the daemon itself, see [`DAEMON_AUDIT_UUID`]
+    DaemonSetting { name: String },
+    /// A start let through over the capacity limits of the daemon, the new value holds the limit
+    /// and the usage at the time
+    CapacityOverride,
+    /// A console command refused by the user's command policy, the new value holds the command
+    RefusedCommand,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct AuditEntry {
+    pub id: Snowflake,
+    /// Unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub instance_uuid: InstanceUuid,
+    pub caused_by: CausedBy,
+    pub target: AuditTarget,
+    #[ts(type = "unknown")]
+    pub old_value: Option<serde_json::Value>,
+    #[ts(type = "unknown")]
+    pub new_value: Option<serde_json::Value>,
+}
+
+/// Serializes a value for an audit entry, hiding it if it's secret
+pub fn audit_value(value: impl Serialize, is_secret: bool) -> Option<serde_json::Value> {
+    if is_secret {
+        return Some(serde_json::Value::String(REDACTED.to_string()));
+    }
+    serde_json::to_value(value).ok()
+}
+
+/// Append-only log of the changes made to each instance, one JSON lines file per instance
+#[derive(Clone)]
+pub struct AuditLog {
+    dir: PathBuf,
+    /// Held across appends and rotations so concurrent writes don't interleave
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn log_path(&self, instance_uuid: &InstanceUuid) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", instance_uuid))
+    }
+
+    fn rotated_log_path(&self, instance_uuid: &InstanceUuid) -> PathBuf {
+        self.dir.join(format!("{}.1.jsonl", instance_uuid))
+    }
+
+    /// Records a change, unchanged values are skipped.
+    ///
+    /// A failure to write is only logged, the change itself already happened
+    pub async fn record(
+        &self,
+        instance_uuid: &InstanceUuid,
+        caused_by: CausedBy,
+        target: AuditTarget,
+        old_value: Option<serde_json::Value>,
+        new_value: Option<serde_json::Value>,
+    ) {
+        if old_value.is_some() && old_value == new_value {
+            return;
+        }
+        let id = Snowflake::new();
+        let entry = AuditEntry {
+            id,
+            timestamp: id.timestamp_millis(),
+            instance_uuid: instance_uuid.clone(),
+            caused_by,
+            target,
+            old_value,
+            new_value,
+        };
+        if let Err(e) = self.append(&entry).await {
+            warn!(
+                "Failed to write audit entry for {}: {}",
+                instance_uuid, e.source
+            );
+        }
+    }
+
+    /// Records a change to a daemon setting
+    pub async fn record_daemon_setting(
+        &self,
+        caused_by: CausedBy,
+        name: &str,
+        old_value: Option<serde_json::Value>,
+        new_value: Option<serde_json::Value>,
+    ) {
+        self.record(
+            &InstanceUuid::from(DAEMON_AUDIT_UUID.to_string()),
+            caused_by,
+            AuditTarget::DaemonSetting {
+                name: name.to_string(),
+            },
+            old_value,
+            new_value,
+        )
+        .await;
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry).context("Failed to serialize audit entry")?;
+        line.push('\n');
+        let _lock = self.lock.lock().await;
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context("Failed to create audit log directory")?;
+        let path = self.log_path(&entry.instance_uuid);
+        if tokio::fs::metadata(&path)
+            .await
+            .map(|metadata| metadata.len() >= MAX_AUDIT_LOG_SIZE)
+            .unwrap_or(false)
+        {
+            tokio::fs::rename(&path, self.rotated_log_path(&entry.instance_uuid))
+                .await
+                .context("Failed to rotate audit log")?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .context("Failed to open audit log")?;
+        file.write_all(line.as_bytes())
+            .await
+            .context("Failed to write audit log")?;
+        Ok(())
+    }
+
+    /// Up to `limit` entries of an instance older than `before`, newest first
+    pub async fn read(
+        &self,
+        instance_uuid: &InstanceUuid,
+        limit: usize,
+        before: Option<Snowflake>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        let _lock = self.lock.lock().await;
+        let mut entries = Vec::new();
+        for path in [
+            self.log_path(instance_uuid),
+            self.rotated_log_path(instance_uuid),
+        ] {
+            let mut older: Vec<AuditEntry> = read_entries(&path)
+                .await?
+                .into_iter()
+                .filter(|entry| before.map_or(true, |before| entry.id < before))
+                .collect();
+            older.reverse();
+            entries.extend(older.into_iter().take(limit - entries.len()));
+            if entries.len() >= limit {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Like [`AuditLog::read`] across every instance with a log, including deleted ones
+    pub async fn read_all(
+        &self,
+        limit: usize,
+        before: Option<Snowflake>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        let mut instance_uuids = Vec::new();
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => Err(e).context("Failed to read audit log directory")?,
+        };
+        while let Some(entry) = dir
+            .next_entry()
+            .await
+            .context("Failed to read audit log directory")?
+        {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(instance_uuid) = file_name.strip_suffix(".jsonl") {
+                let instance_uuid = instance_uuid.trim_end_matches(".1");
+                if !instance_uuids.iter().any(|uuid| uuid == instance_uuid) {
+                    instance_uuids.push(instance_uuid.to_string());
+                }
+            }
+        }
+        let mut entries = Vec::new();
+        for instance_uuid in instance_uuids {
+            entries.extend(self.read(&instance_uuid.into(), limit, before).await?);
+        }
+        entries.sort_by(|a, b| b.id.cmp(&a.id));
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    /// Records file changes inside instance directories until the event broadcaster closes.
+    ///
+    /// Subscribes right away so no event sent after this call is missed
+    pub fn run(
+        self,
+        instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+        event_broadcaster: &EventBroadcaster,
+    ) -> impl std::future::Future<Output = ()> {
+        let mut rx = event_broadcaster.subscribe();
+        async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                let fs_event = match event.event_inner {
+                    EventInner::FSEvent(fs_event) => fs_event,
+                    _ => continue,
+                };
+                if matches!(
+                    fs_event.operation,
+                    FSOperation::Read | FSOperation::Download
+                ) {
+                    continue;
+                }
+                let path = match &fs_event.target {
+                    FSTarget::File(path) | FSTarget::Directory(path) => path.clone(),
+                };
+                let instances: Vec<GameInstance> = instances
+                    .iter()
+                    .map(|entry| entry.value().clone())
+                    .collect();
+                for instance in instances {
+                    let root = instance.path().await;
+                    if let Ok(relative_path) = path.strip_prefix(&root) {
+                        let operation = match &fs_event.operation {
+                            FSOperation::Move { source } => FSOperation::Move {
+                                source: source.strip_prefix(&root).unwrap_or(source).to_path_buf(),
+                            },
+                            operation => operation.clone(),
+                        };
+                        self.record(
+                            &instance.uuid().await,
+                            event.caused_by.clone(),
+                            AuditTarget::File {
+                                path: relative_path.to_path_buf(),
+                                operation,
+                            },
+                            None,
+                            None,
+                        )
+                        .await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Entries of one log file oldest first, lines that don't parse are skipped
+async fn read_entries(path: &Path) -> Result<Vec<AuditEntry>, Error> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => Err(e).context("Failed to read audit log")?,
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn property(name: &str) -> AuditTarget {
+        AuditTarget::Property {
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_paginate() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path().join("audit"));
+        let instance_uuid = InstanceUuid::default();
+        for i in 0..5 {
+            log.record(
+                &instance_uuid,
+                CausedBy::System,
+                property("name"),
+                audit_value(format!("name {}", i), false),
+                audit_value(format!("name {}", i + 1), false),
+            )
+            .await;
+        }
+        // nothing changed, nothing recorded
+        log.record(
+            &instance_uuid,
+            CausedBy::System,
+            property("name"),
+            audit_value("same", false),
+            audit_value("same", false),
+        )
+        .await;
+
+        let page = log.read(&instance_uuid, 3, None).await.unwrap();
+        assert_eq!(page.len(), 3);
+        assert_eq!(page[0].new_value, audit_value("name 5", false));
+        assert!(page[0].id > page[1].id);
+        let rest = log.read(&instance_uuid, 3, Some(page[2].id)).await.unwrap();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[1].old_value, audit_value("name 0", false));
+
+        let all = log.read_all(10, None).await.unwrap();
+        assert_eq!(all.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path().to_path_buf());
+        let instance_uuid = InstanceUuid::default();
+        let big_value = "x".repeat(64 * 1024);
+        for _ in 0..40 {
+            log.record(
+                &instance_uuid,
+                CausedBy::System,
+                property("description"),
+                None,
+                audit_value(&big_value, false),
+            )
+            .await;
+        }
+        let current = tokio::fs::metadata(log.log_path(&instance_uuid))
+            .await
+            .unwrap();
+        let rotated = tokio::fs::metadata(log.rotated_log_path(&instance_uuid))
+            .await
+            .unwrap();
+        assert!(current.len() <= MAX_AUDIT_LOG_SIZE + 128 * 1024);
+        assert!(rotated.len() >= MAX_AUDIT_LOG_SIZE);
+        // entries from both files are still readable
+        let entries = log.read(&instance_uuid, 40, None).await.unwrap();
+        assert!(entries.len() > 16);
+        assert!(entries.windows(2).all(|pair| pair[0].id > pair[1].id));
+    }
+
+    #[test]
+    fn test_secret_values_are_redacted() {
+        assert_eq!(
+            audit_value("hunter2", true),
+            Some(serde_json::Value::String(REDACTED.to_string()))
+        );
+        assert_eq!(
+            audit_value(25565, false),
+            Some(serde_json::Value::from(25565))
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ts_rs::TS;
+
+use crate::{
+    types::{InstanceUuid, Snowflake},
+    util::rand_alphanumeric,
+};
+
+use super::user::UserAction;
+
+/// Tells access tokens apart from session JWTs
+const TOKEN_PREFIX: &str = "lst_";
+const SECRET_LENGTH: usize = 40;
+
+/// What a personal access token may do, on top of what its owner may do
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, TS)]
+#[ts(export)]
+pub struct TokenScopes {
+    /// Only view instances and read their resources and files
+    #[serde(default)]
+    pub read_only: bool,
+    /// Restricts instance actions to these instances, all of them when unset
+    #[serde(default)]
+    pub instances: Option<HashSet<InstanceUuid>>,
+    /// Allows reading, and unless read only writing, instance and global files
+    #[serde(default)]
+    pub file_access: bool,
+}
+
+impl TokenScopes {
+    pub fn allows(&self, action: &UserAction) -> bool {
+        let instance = match action {
+            UserAction::ViewInstance(instance_uuid)
+            | UserAction::StartInstance(instance_uuid)
+            | UserAction::StopInstance(instance_uuid)
+            | UserAction::AccessConsole(instance_uuid)
+            | UserAction::ReadConsole(instance_uuid)
+            | UserAction::AccessSetting(instance_uuid)
+            | UserAction::ReadResource(instance_uuid)
+            | UserAction::WriteResource(instance_uuid)
+            | UserAction::ReadInstanceFile(instance_uuid)
+            | UserAction::ListInstanceFiles(instance_uuid)
+            | UserAction::WriteInstanceFile(instance_uuid)
+            | UserAction::ManageProtectedFiles(instance_uuid)
+            | UserAction::BypassMaintenance(instance_uuid)
+            | UserAction::SendChat(instance_uuid) => Some(Some(instance_uuid)),
+            UserAction::AccessMacro(instance_uuid)
+            | UserAction::AdministerInstance(instance_uuid) => Some(instance_uuid.as_ref()),
+            _ => None,
+        };
+        if let (Some(instances), Some(instance)) = (&self.instances, instance) {
+            if !instance.map_or(false, |instance_uuid| instances.contains(instance_uuid)) {
+                return false;
+            }
+        }
+        let is_file_action = matches!(
+            action,
+            UserAction::ReadInstanceFile(_)
+                | UserAction::ListInstanceFiles(_)
+                | UserAction::WriteInstanceFile(_)
+                | UserAction::ManageProtectedFiles(_)
+                | UserAction::ReadGlobalFile
+                | UserAction::WriteGlobalFile
+        );
+        if is_file_action && !self.file_access {
+            return false;
+        }
+        if self.read_only {
+            return matches!(
+                action,
+                UserAction::ViewInstance(_)
+                    | UserAction::ReadConsole(_)
+                    | UserAction::ReadResource(_)
+                    | UserAction::ReadInstanceFile(_)
+                    | UserAction::ListInstanceFiles(_)
+                    | UserAction::ReadGlobalFile
+                    | UserAction::ViewAudit
+            );
+        }
+        // instance restricted tokens can't act on lodestone as a whole
+        self.instances.is_none() || instance.is_some()
+    }
+}
+
+/// A personal access token as stored with its user, only a hash of the secret is kept
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessToken {
+    pub id: Snowflake,
+    pub name: String,
+    hashed_secret: String,
+    pub scopes: TokenScopes,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds
+    pub expires_at: Option<i64>,
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+impl AccessToken {
+    /// Creates a token along with the only copy of its full value
+    pub fn generate(name: String, scopes: TokenScopes, expires_at: Option<i64>) -> (Self, String) {
+        let id = Snowflake::new();
+        let secret = rand_alphanumeric(SECRET_LENGTH);
+        let token = format!("{}{}_{}", TOKEN_PREFIX, id.to_string(), secret);
+        (
+            AccessToken {
+                id,
+                name,
+                hashed_secret: hash_secret(&secret),
+                scopes,
+                created_at: chrono::Utc::now().timestamp(),
+                expires_at,
+            },
+            token,
+        )
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| {
+            expires_at <= chrono::Utc::now().timestamp()
+        })
+    }
+
+    /// Whether `token` is this token and still valid
+    pub fn verify(&self, token: &str) -> bool {
+        match parse_token(token) {
+            Some((id, secret)) => {
+                id == self.id.to_string()
+                    && hash_secret(secret) == self.hashed_secret
+                    && !self.is_expired()
+            }
+            None => false,
+        }
+    }
+}
+
+/// Splits an access token into its id and secret, None for anything else such as a JWT
+pub fn parse_token(token: &str) -> Option<(&str, &str)> {
+    token.strip_prefix(TOKEN_PREFIX)?.split_once('_')
+}
+
+/// An access token as listed to its owner
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct AccessTokenInfo {
+    pub id: Snowflake,
+    pub name: String,
+    pub scopes: TokenScopes,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+impl From<&AccessToken> for AccessTokenInfo {
+    fn from(token: &AccessToken) -> Self {
+        AccessTokenInfo {
+            id: token.id,
+            name: token.name.clone(),
+            scopes: token.scopes.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let (access_token, token) =
+            AccessToken::generate("ci".to_string(), TokenScopes::default(), None);
+        assert!(access_token.verify(&token));
+        assert!(!access_token.verify(&format!("{}x", token)));
+        assert!(!access_token.verify("eyJhbGciOiJIUzUxMiJ9.e30.sig"));
+
+        let (expired, token) = AccessToken::generate(
+            "old".to_string(),
+            TokenScopes::default(),
+            Some(chrono::Utc::now().timestamp() - 1),
+        );
+        assert!(!expired.verify(&token));
+    }
+
+    #[test]
+    fn test_scopes() {
+        let instance_uuid = InstanceUuid::default();
+        let read_only = TokenScopes {
+            read_only: true,
+            instances: Some(HashSet::from([instance_uuid.clone()])),
+            file_access: false,
+        };
+        assert!(read_only.allows(&UserAction::ViewInstance(instance_uuid.clone())));
+        assert!(!read_only.allows(&UserAction::ViewInstance(InstanceUuid::default())));
+        assert!(!read_only.allows(&UserAction::StartInstance(instance_uuid.clone())));
+        assert!(!read_only.allows(&UserAction::ReadInstanceFile(instance_uuid.clone())));
+        assert!(!read_only.allows(&UserAction::CreateInstance));
+        assert!(!read_only.allows(&UserAction::ManageDaemon));
+        assert!(!read_only.allows(&UserAction::AdministerInstance(Some(instance_uuid.clone()))));
+
+        // owner and admin actions are refused to tokens limited to some instances
+        let limited = TokenScopes {
+            read_only: false,
+            instances: Some(HashSet::from([instance_uuid.clone()])),
+            file_access: false,
+        };
+        assert!(!limited.allows(&UserAction::ManageDaemon));
+        assert!(!limited.allows(&UserAction::AdministerInstance(None)));
+        assert!(limited.allows(&UserAction::AdministerInstance(Some(instance_uuid.clone()))));
+
+        let full = TokenScopes {
+            read_only: false,
+            instances: None,
+            file_access: true,
+        };
+        assert!(full.allows(&UserAction::WriteInstanceFile(instance_uuid)));
+        assert!(full.allows(&UserAction::CreateInstance));
+        assert!(full.allows(&UserAction::ManageDaemon));
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+use crate::global_settings::LoginRateLimit;
+
+/// Why a login attempt was turned away before the password was checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginRejection {
+    RateLimited { retry_after: Duration },
+    LockedOut { retry_after: Duration },
+}
+
+#[derive(Default)]
+struct Failures {
+    consecutive: u32,
+    last_failure: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    attempts_by_ip: HashMap<IpAddr, VecDeque<Instant>>,
+    attempts_by_username: HashMap<String, VecDeque<Instant>>,
+    failures: HashMap<String, Failures>,
+}
+
+/// Takes an attempt out of a sliding window, or says how long until one frees up
+fn take_attempt(
+    window: &mut VecDeque<Instant>,
+    now: Instant,
+    limits: &LoginRateLimit,
+) -> Result<(), Duration> {
+    let window_length = Duration::from_secs(limits.window_seconds);
+    while window.front().map_or(false, |attempt| {
+        now.duration_since(*attempt) >= window_length
+    }) {
+        window.pop_front();
+    }
+    if window.len() >= limits.max_attempts as usize {
+        let oldest = *window.front().unwrap_or(&now);
+        return Err(window_length.saturating_sub(now.duration_since(oldest)));
+    }
+    window.push_back(now);
+    Ok(())
+}
+
+impl LimiterState {
+    fn check(
+        &mut self,
+        ip: Option<IpAddr>,
+        username: &str,
+        limits: &LoginRateLimit,
+        now: Instant,
+    ) -> Result<(), LoginRejection> {
+        if let Some(failures) = self.failures.get_mut(username) {
+            match failures.locked_until {
+                Some(locked_until) if locked_until > now => {
+                    return Err(LoginRejection::LockedOut {
+                        retry_after: locked_until - now,
+                    })
+                }
+                Some(_) => *failures = Failures::default(),
+                None => {}
+            }
+        }
+        // forget windows nobody has used lately so the maps don't grow without bound
+        let window_length = Duration::from_secs(limits.window_seconds);
+        let is_recent = |window: &VecDeque<Instant>| {
+            window.back().map_or(false, |attempt| {
+                now.duration_since(*attempt) < window_length
+            })
+        };
+        self.attempts_by_ip.retain(|_, window| is_recent(window));
+        self.attempts_by_username
+            .retain(|_, window| is_recent(window));
+        self.failures.retain(|_, failures| {
+            failures
+                .locked_until
+                .map_or(false, |locked_until| locked_until > now)
+                || failures.last_failure.map_or(false, |last_failure| {
+                    now.duration_since(last_failure) < window_length
+                })
+        });
+
+        if let Some(ip) = ip {
+            take_attempt(self.attempts_by_ip.entry(ip).or_default(), now, limits)
+                .map_err(|retry_after| LoginRejection::RateLimited { retry_after })?;
+        }
+        take_attempt(
+            self.attempts_by_username
+                .entry(username.to_string())
+                .or_default(),
+            now,
+            limits,
+        )
+        .map_err(|retry_after| LoginRejection::RateLimited { retry_after })
+    }
+
+    fn record_failure(
+        &mut self,
+        username: &str,
+        limits: &LoginRateLimit,
+        now: Instant,
+    ) -> Option<Duration> {
+        let failures = self.failures.entry(username.to_string()).or_default();
+        failures.consecutive += 1;
+        failures.last_failure = Some(now);
+        if failures.consecutive >= limits.lockout_threshold {
+            let lockout = Duration::from_secs(limits.lockout_seconds);
+            failures.locked_until = Some(now + lockout);
+            Some(lockout)
+        } else {
+            None
+        }
+    }
+
+    /// The IP's window is left to expire, or logging into any account between guesses would
+    /// reset it
+    fn record_success(&mut self, username: &str) {
+        self.attempts_by_username.remove(username);
+        self.failures.remove(username);
+    }
+}
+
+/// In-memory login attempt counters, they reset when the core restarts
+#[derive(Clone, Default)]
+pub struct LoginLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl LoginLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an attempt against the IP and the username, rejecting it if either is over
+    /// the limit or the username is locked out
+    pub async fn check(
+        &self,
+        ip: Option<IpAddr>,
+        username: &str,
+        limits: &LoginRateLimit,
+    ) -> Result<(), LoginRejection> {
+        self.state
+            .lock()
+            .await
+            .check(ip, username, limits, Instant::now())
+    }
+
+    /// Returns how long the username is now locked out for, if this failure locked it
+    pub async fn record_failure(
+        &self,
+        username: &str,
+        limits: &LoginRateLimit,
+    ) -> Option<Duration> {
+        self.state
+            .lock()
+            .await
+            .record_failure(username, limits, Instant::now())
+    }
+
+    pub async fn record_success(&self, username: &str) {
+        self.state.lock().await.record_success(username)
+    }
+
+    /// Lifts a lockout early, returns false if the username wasn't locked out
+    pub async fn unlock(&self, username: &str) -> bool {
+        let mut state = self.state.lock().await;
+        state.attempts_by_username.remove(username);
+        state
+            .failures
+            .remove(username)
+            .map_or(false, |failures| failures.locked_until.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> LoginRateLimit {
+        LoginRateLimit {
+            max_attempts: 3,
+            window_seconds: 60,
+            lockout_threshold: 2,
+            lockout_seconds: 300,
+        }
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut state = LimiterState::default();
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        let start = Instant::now();
+        for i in 0..3 {
+            let username = format!("user{}", i);
+            assert!(state
+                .check(
+                    ip,
+                    &username,
+                    &limits(),
+                    start + Duration::from_secs(i * 10)
+                )
+                .is_ok());
+        }
+        assert_eq!(
+            state.check(ip, "user3", &limits(), start + Duration::from_secs(30)),
+            Err(LoginRejection::RateLimited {
+                retry_after: Duration::from_secs(30)
+            })
+        );
+        // the first attempt has left the window
+        assert!(state
+            .check(ip, "user3", &limits(), start + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_lockout() {
+        let mut state = LimiterState::default();
+        let start = Instant::now();
+        assert!(state.check(None, "admin", &limits(), start).is_ok());
+        assert_eq!(state.record_failure("admin", &limits(), start), None);
+        assert!(state.check(None, "admin", &limits(), start).is_ok());
+        assert_eq!(
+            state.record_failure("admin", &limits(), start),
+            Some(Duration::from_secs(300))
+        );
+        assert!(matches!(
+            state.check(None, "admin", &limits(), start + Duration::from_secs(100)),
+            Err(LoginRejection::LockedOut { .. })
+        ));
+        // the lockout expires and failures start over
+        assert!(state
+            .check(None, "admin", &limits(), start + Duration::from_secs(300))
+            .is_ok());
+        assert_eq!(
+            state.record_failure("admin", &limits(), start + Duration::from_secs(300)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_failures_are_forgotten() {
+        let mut state = LimiterState::default();
+        let start = Instant::now();
+        // a bot trying a new username every time
+        for i in 0..100 {
+            let username = format!("user{}", i);
+            state.record_failure(&username, &limits(), start);
+            state.record_failure(&username, &limits(), start);
+        }
+        state.record_failure("admin", &limits(), start + Duration::from_secs(250));
+        assert_eq!(state.failures.len(), 101);
+
+        // the lockouts are still enforced until they expire
+        assert!(matches!(
+            state.check(None, "user0", &limits(), start + Duration::from_secs(280)),
+            Err(LoginRejection::LockedOut { .. })
+        ));
+        state
+            .check(None, "someone", &limits(), start + Duration::from_secs(300))
+            .unwrap();
+        assert_eq!(state.failures.len(), 1);
+        assert!(state.failures.contains_key("admin"));
+    }
+
+    #[test]
+    fn test_success_resets() {
+        let mut state = LimiterState::default();
+        let start = Instant::now();
+        state.check(None, "admin", &limits(), start).unwrap();
+        state.record_failure("admin", &limits(), start);
+        state.record_success("admin");
+        state.check(None, "admin", &limits(), start).unwrap();
+        assert_eq!(state.record_failure("admin", &limits(), start), None);
+    }
+
+    #[test]
+    fn test_success_keeps_ip_window() {
+        let mut state = LimiterState::default();
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        let start = Instant::now();
+        // guesses at another account, with logins to the attacker's own in between
+        state.check(ip, "admin", &limits(), start).unwrap();
+        state.check(ip, "attacker", &limits(), start).unwrap();
+        state.record_success("attacker");
+        state.check(ip, "admin", &limits(), start).unwrap();
+        assert!(matches!(
+            state.check(ip, "admin", &limits(), start),
+            Err(LoginRejection::RateLimited { .. })
+        ));
+    }
+}
@@ -1,5 +1,7 @@
+pub mod access_token;
 pub mod hashed_password;
 pub mod jwt_token;
+pub mod login_limiter;
 pub mod permission;
 pub mod user;
 pub mod user_id;
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
@@ -11,6 +11,9 @@ pub struct UserPermission {
     pub can_start_instance: HashSet<InstanceUuid>,
     pub can_stop_instance: HashSet<InstanceUuid>,
     pub can_access_instance_console: HashSet<InstanceUuid>,
+    /// Watch the console without sending commands to it
+    #[serde(default)]
+    pub can_read_instance_console: HashSet<InstanceUuid>,
     pub can_access_instance_setting: HashSet<InstanceUuid>,
     pub can_read_instance_resource: HashSet<InstanceUuid>,
     // unsafe permission, owner exclusive unless explicitly granted
@@ -18,8 +21,17 @@ pub struct UserPermission {
     // unsafe permission, owner exclusive unless explicitly granted
     pub can_access_instance_macro: HashSet<InstanceUuid>,
     pub can_read_instance_file: HashSet<InstanceUuid>,
+    /// Browse names, sizes and modification times without reading what the files contain
+    #[serde(default)]
+    pub can_list_instance_files: HashSet<InstanceUuid>,
     // unsafe permission, owner exclusive unless explicitly granted
     pub can_write_instance_file: HashSet<InstanceUuid>,
+    /// Start these instances while someone else has them under maintenance, admins need it too
+    #[serde(default)]
+    pub can_bypass_maintenance: HashSet<InstanceUuid>,
+    /// Send chat messages to the players without other console access
+    #[serde(default)]
+    pub can_send_chat: HashSet<InstanceUuid>,
 
     pub can_create_instance: bool,
     pub can_delete_instance: bool,
@@ -30,6 +42,17 @@ pub struct UserPermission {
     pub can_manage_permission: bool,
     #[serde(default)]
     pub can_install_extension: bool,
+    #[serde(default)]
+    pub can_view_audit: bool,
+    #[serde(default)]
+    pub can_manage_notifications: bool,
+    // owner exclusive unless explicitly granted
+    #[serde(default)]
+    pub can_manage_users: bool,
+    /// Limits the commands the user may send to the console of these instances, owners and
+    /// admins aren't limited
+    #[serde(default)]
+    pub command_policies: HashMap<InstanceUuid, CommandPolicy>,
 }
 
 impl UserPermission {
@@ -39,20 +62,42 @@ impl UserPermission {
             can_start_instance: HashSet::new(),
             can_stop_instance: HashSet::new(),
             can_access_instance_console: HashSet::new(),
+            can_read_instance_console: HashSet::new(),
             can_access_instance_setting: HashSet::new(),
             can_read_instance_resource: HashSet::new(),
             can_write_instance_resource: HashSet::new(),
             can_access_instance_macro: HashSet::new(),
             can_read_instance_file: HashSet::new(),
+            can_list_instance_files: HashSet::new(),
             can_write_instance_file: HashSet::new(),
+            can_bypass_maintenance: HashSet::new(),
+            can_send_chat: HashSet::new(),
             can_create_instance: false,
             can_delete_instance: false,
             can_read_global_file: false,
             can_write_global_file: false,
             can_manage_permission: false,
             can_install_extension: false,
+            can_view_audit: false,
+            can_manage_notifications: false,
+            can_manage_users: false,
+            command_policies: HashMap::new(),
         }
     }
+
+    /// The built-in observer role: sees the instances, their players and their console, and can
+    /// browse their files without reading them, but changes nothing
+    pub fn observer(instances: impl IntoIterator<Item = InstanceUuid>) -> Self {
+        let mut permissions = Self::new();
+        for instance in instances {
+            for capability in InstanceCapability::OBSERVER {
+                permissions
+                    .instances_mut(capability)
+                    .insert(instance.clone());
+            }
+        }
+        permissions
+    }
 }
 
 impl Default for UserPermission {
@@ -60,3 +105,178 @@ impl Default for UserPermission {
         Self::new()
     }
 }
+
+/// One of the per-instance sets of [`UserPermission`]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS, Debug)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum InstanceCapability {
+    View,
+    Start,
+    Stop,
+    AccessConsole,
+    ReadConsole,
+    AccessSetting,
+    ReadResource,
+    WriteResource,
+    AccessMacro,
+    ReadFile,
+    ListFiles,
+    WriteFile,
+    BypassMaintenance,
+    SendChat,
+}
+
+/// A built-in set of permissions to start a user from
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS, Debug)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum PermissionPreset {
+    Observer,
+}
+
+impl PermissionPreset {
+    pub fn permissions(self, instances: impl IntoIterator<Item = InstanceUuid>) -> UserPermission {
+        match self {
+            PermissionPreset::Observer => UserPermission::observer(instances),
+        }
+    }
+}
+
+impl InstanceCapability {
+    /// What the observer role is granted on each of its instances
+    pub const OBSERVER: [InstanceCapability; 3] = [
+        InstanceCapability::View,
+        InstanceCapability::ReadConsole,
+        InstanceCapability::ListFiles,
+    ];
+}
+
+impl UserPermission {
+    pub fn instances_mut(&mut self, capability: InstanceCapability) -> &mut HashSet<InstanceUuid> {
+        match capability {
+            InstanceCapability::View => &mut self.can_view_instance,
+            InstanceCapability::Start => &mut self.can_start_instance,
+            InstanceCapability::Stop => &mut self.can_stop_instance,
+            InstanceCapability::AccessConsole => &mut self.can_access_instance_console,
+            InstanceCapability::ReadConsole => &mut self.can_read_instance_console,
+            InstanceCapability::AccessSetting => &mut self.can_access_instance_setting,
+            InstanceCapability::ReadResource => &mut self.can_read_instance_resource,
+            InstanceCapability::WriteResource => &mut self.can_write_instance_resource,
+            InstanceCapability::AccessMacro => &mut self.can_access_instance_macro,
+            InstanceCapability::ReadFile => &mut self.can_read_instance_file,
+            InstanceCapability::ListFiles => &mut self.can_list_instance_files,
+            InstanceCapability::WriteFile => &mut self.can_write_instance_file,
+            InstanceCapability::BypassMaintenance => &mut self.can_bypass_maintenance,
+            InstanceCapability::SendChat => &mut self.can_send_chat,
+        }
+    }
+}
+
+/// Which console commands a user may send to an instance. Entries match whole words from the
+/// start of a command, `whitelist` covers `whitelist add Steve` while `whitelist add` leaves out
+/// `whitelist remove`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, TS, Debug)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum CommandPolicy {
+    /// Only commands starting with one of these
+    Allow(Vec<String>),
+    /// Every command but those starting with one of these
+    Deny(Vec<String>),
+}
+
+/// Words of a command in lowercase, its name without the leading slash or `minecraft:` namespace
+fn command_words(command: &str) -> Vec<String> {
+    let mut words: Vec<String> = command.split_whitespace().map(str::to_lowercase).collect();
+    if let Some(name) = words.first_mut() {
+        let trimmed = name.trim_start_matches('/');
+        *name = trimmed
+            .strip_prefix("minecraft:")
+            .unwrap_or(trimmed)
+            .to_string();
+    }
+    words
+}
+
+/// The command and whatever it could run through `execute ... run`. Every word after a `run` is
+/// taken as the start of a command, a player named `run` can't hide the real one
+fn invoked_commands(command: &str) -> Vec<Vec<String>> {
+    let words = command_words(command);
+    let mut commands = Vec::new();
+    if words.first().map(String::as_str) == Some("execute") {
+        for (i, word) in words.iter().enumerate() {
+            if word == "run" {
+                commands.push(command_words(&words[i + 1..].join(" ")));
+            }
+        }
+    }
+    commands.push(words);
+    commands
+}
+
+impl CommandPolicy {
+    fn entries(&self) -> &[String] {
+        match self {
+            CommandPolicy::Allow(entries) | CommandPolicy::Deny(entries) => entries,
+        }
+    }
+
+    /// Why the command is refused, if it is
+    pub fn check(&self, command: &str) -> Result<(), String> {
+        // one payload must not carry a second command past the check
+        if command.chars().any(char::is_control) {
+            return Err(
+                "Commands with line breaks or control characters can't be sent".to_string(),
+            );
+        }
+        for words in invoked_commands(command) {
+            if words.is_empty() {
+                continue;
+            }
+            let listed = self.entries().iter().any(|entry| {
+                let entry = command_words(entry);
+                !entry.is_empty() && words.starts_with(&entry)
+            });
+            if listed != matches!(self, CommandPolicy::Allow(_)) {
+                return Err(format!(
+                    "You aren't allowed to run {} on this instance",
+                    words[0]
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_policy() {
+        let moderator = CommandPolicy::Allow(vec![
+            "kick".to_string(),
+            "ban".to_string(),
+            "whitelist add".to_string(),
+        ]);
+        assert!(moderator.check("kick Steve griefing").is_ok());
+        assert!(moderator.check("/minecraft:ban Steve").is_ok());
+        assert!(moderator.check("WHITELIST add Alex").is_ok());
+        assert!(moderator.check("whitelist remove Alex").is_err());
+        assert!(moderator.check("kickall").is_err());
+        assert!(moderator.check("op Steve").is_err());
+        assert!(moderator.check("kick Steve\nop Steve").is_err());
+        assert!(moderator.check("execute as @a run kick @s").is_err());
+
+        let no_ops = CommandPolicy::Deny(vec!["op".to_string(), "stop".to_string()]);
+        assert!(no_ops.check("say hello").is_ok());
+        assert!(no_ops.check("/stop").is_err());
+        assert!(no_ops.check("execute as @a run op @s").is_err());
+        assert!(no_ops.check("execute as run run op Steve").is_err());
+        assert!(no_ops
+            .check("execute run execute at @p run minecraft:stop")
+            .is_err());
+        assert!(no_ops.check("say one\rstop").is_err());
+    }
+}
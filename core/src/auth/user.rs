@@ -9,13 +9,15 @@ use tracing::warn;
 use ts_rs::TS;
 
 use crate::{
-    error::{Error, ErrorKind},
+    error::{Error, ErrorCode, ErrorKind},
     event_broadcaster::EventBroadcaster,
     events::{CausedBy, Event, EventInner, UserEvent, UserEventInner},
+    request_context,
     types::{InstanceUuid, Snowflake},
 };
 
 use super::{
+    access_token::{parse_token, AccessToken, TokenScopes},
     hashed_password::{hash_password, HashedPassword},
     jwt_token::JwtToken,
     permission::UserPermission,
@@ -37,6 +39,27 @@ pub struct User {
     pub is_admin: bool,
     pub permissions: UserPermission,
     pub secret: UserSecret,
+    /// A disabled user can't log in and their tokens are rejected
+    #[serde(default)]
+    pub is_disabled: bool,
+    /// Set by an admin, cleared once the user changes their own password
+    #[serde(default)]
+    pub password_reset_required: bool,
+    #[serde(default)]
+    pub access_tokens: Vec<AccessToken>,
+    /// Scopes of the access token the user authenticated with, None for a session token
+    #[serde(skip)]
+    pub token_scopes: Option<TokenScopes>,
+}
+
+/// The coarse role of a user, finer grained access comes from [`UserPermission`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum UserRole {
+    Owner,
+    Admin,
+    User,
 }
 
 impl User {
@@ -55,8 +78,48 @@ impl User {
             is_admin,
             permissions,
             secret: UserSecret::default(),
+            is_disabled: false,
+            password_reset_required: false,
+            access_tokens: Vec::new(),
+            token_scopes: None,
         }
     }
+    pub fn role(&self) -> UserRole {
+        if self.is_owner {
+            UserRole::Owner
+        } else if self.is_admin {
+            UserRole::Admin
+        } else {
+            UserRole::User
+        }
+    }
+    /// Checks that this user may create `target` or change its role to `new_role`.
+    ///
+    /// Nobody can be made owner or stop being one, and only the owner hands out or takes away
+    /// admin
+    pub fn can_assign_role(&self, target: Option<&User>, new_role: UserRole) -> Result<(), Error> {
+        if new_role == UserRole::Owner {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("There can only be one owner"),
+            });
+        }
+        if target.map_or(false, |target| target.is_owner) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The owner's role cannot be changed"),
+            });
+        }
+        if !self.is_owner
+            && (new_role == UserRole::Admin || target.map_or(false, |target| target.is_admin))
+        {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Only the owner can grant or revoke admin"),
+            });
+        }
+        Ok(())
+    }
     fn get_permission_level(&self) -> u8 {
         if self.is_owner {
             u8::MAX
@@ -86,6 +149,7 @@ impl User {
                 || !permissions.can_access_instance_macro.is_empty()
                 || permissions.can_write_global_file
                 || permissions.can_manage_permission
+                || permissions.can_manage_users
                 || !permissions.can_write_instance_file.is_empty()
             {
                 Err(Error {
@@ -107,6 +171,11 @@ impl User {
     }
 
     pub fn can_perform_action(&self, action: &UserAction) -> bool {
+        if let Some(token_scopes) = &self.token_scopes {
+            if !token_scopes.allows(action) {
+                return false;
+            }
+        }
         if self.is_owner {
             return true;
         }
@@ -127,6 +196,17 @@ impl User {
                         .can_access_instance_console
                         .contains(instance_id)
             }
+            UserAction::ReadConsole(instance_id) => {
+                self.is_admin
+                    || self
+                        .permissions
+                        .can_access_instance_console
+                        .contains(instance_id)
+                    || self
+                        .permissions
+                        .can_read_instance_console
+                        .contains(instance_id)
+            }
             UserAction::AccessSetting(instance_id) => {
                 self.is_admin
                     || self
@@ -153,6 +233,18 @@ impl User {
                         .can_read_instance_file
                         .contains(instance_id)
             }
+            UserAction::ListInstanceFiles(instance_id) => {
+                self.is_admin
+                    || self.permissions.can_read_global_file
+                    || self
+                        .permissions
+                        .can_read_instance_file
+                        .contains(instance_id)
+                    || self
+                        .permissions
+                        .can_list_instance_files
+                        .contains(instance_id)
+            }
             UserAction::WriteInstanceFile(instance_id) => {
                 self.permissions.can_write_global_file
                     || self
@@ -160,6 +252,21 @@ impl User {
                         .can_write_instance_file
                         .contains(instance_id)
             }
+            UserAction::ManageProtectedFiles(_) => self.is_admin,
+            // not implied by admin, maintenance is there to keep the other admins out
+            UserAction::BypassMaintenance(instance_id) => self
+                .permissions
+                .can_bypass_maintenance
+                .contains(instance_id),
+            // the console could send the same message
+            UserAction::SendChat(instance_id) => {
+                self.is_admin
+                    || self
+                        .permissions
+                        .can_access_instance_console
+                        .contains(instance_id)
+                    || self.permissions.can_send_chat.contains(instance_id)
+            }
             UserAction::AccessMacro(Some(instance_id)) => self
                 .permissions
                 .can_access_instance_macro
@@ -170,9 +277,33 @@ impl User {
             UserAction::DeleteInstance => self.is_admin || self.permissions.can_delete_instance,
             UserAction::ReadGlobalFile => self.permissions.can_read_global_file,
             UserAction::WriteGlobalFile => self.permissions.can_write_global_file,
-            UserAction::ManageUser => self.is_owner,
+            UserAction::ManageUser => self.permissions.can_manage_users,
             UserAction::ManagePermission => self.permissions.can_manage_permission,
             UserAction::InstallExtension => self.permissions.can_install_extension,
+            UserAction::ViewAudit => self.is_admin || self.permissions.can_view_audit,
+            UserAction::ManageNotifications => {
+                self.is_admin || self.permissions.can_manage_notifications
+            }
+            UserAction::AdministerInstance(_) => self.is_admin,
+            UserAction::ManageDaemon => false,
+        }
+    }
+
+    /// Refuses a console command the user's policy for the instance doesn't allow, checked on top
+    /// of `AccessConsole`
+    pub fn check_console_command(
+        &self,
+        instance_uuid: &InstanceUuid,
+        command: &str,
+    ) -> Result<(), Error> {
+        if self.is_owner || self.is_admin {
+            return Ok(());
+        }
+        match self.permissions.command_policies.get(instance_uuid) {
+            Some(policy) => policy
+                .check(command)
+                .map_err(|reason| Error::coded(ErrorCode::CommandNotAllowed, reason)),
+            None => Ok(()),
         }
     }
 
@@ -197,6 +328,9 @@ impl User {
                     UserAction::AccessConsole(_) => {
                         eyre!("You don't have permission to access this instance's console")
                     }
+                    UserAction::ReadConsole(_) => {
+                        eyre!("You don't have permission to read this instance's console")
+                    }
                     UserAction::AccessSetting(_) => {
                         eyre!("You don't have permission to access this instance's setting")
                     }
@@ -212,9 +346,21 @@ impl User {
                     UserAction::ReadInstanceFile(_) => {
                         eyre!("You don't have permission to read this instance's file")
                     }
+                    UserAction::ListInstanceFiles(_) => {
+                        eyre!("You don't have permission to list this instance's files")
+                    }
                     UserAction::WriteInstanceFile(_) => {
                         eyre!("You don't have permission to write this instance's file")
                     }
+                    UserAction::ManageProtectedFiles(_) => {
+                        eyre!("You don't have permission to manage this instance's protected files")
+                    }
+                    UserAction::BypassMaintenance(_) => {
+                        eyre!("You don't have permission to start this instance under maintenance")
+                    }
+                    UserAction::SendChat(_) => {
+                        eyre!("You don't have permission to chat on this instance")
+                    }
                     UserAction::CreateInstance => {
                         eyre!("You don't have permission to create instance")
                     }
@@ -234,13 +380,27 @@ impl User {
                     UserAction::InstallExtension => {
                         eyre!("You don't have permission to install extension")
                     }
+                    UserAction::ViewAudit => {
+                        eyre!("You don't have permission to view the audit log")
+                    }
+                    UserAction::ManageNotifications => {
+                        eyre!("You don't have permission to manage notifications")
+                    }
+                    UserAction::AdministerInstance(_) => {
+                        eyre!("Only the owner and admins can do this")
+                    }
+                    UserAction::ManageDaemon => eyre!("Only the owner can do this"),
                 },
             })
         }
     }
 
     pub fn can_view_event(&self, event: impl AsRef<Event>) -> bool {
-        match &event.as_ref().event_inner {
+        self.can_view_event_inner(&event.as_ref().event_inner)
+    }
+
+    pub fn can_view_event_inner(&self, event_inner: &EventInner) -> bool {
+        match event_inner {
             EventInner::InstanceEvent(event) => {
                 self.can_perform_action(&UserAction::ViewInstance(event.instance_uuid.clone()))
             }
@@ -252,6 +412,8 @@ impl User {
             // TODO!,
             EventInner::ProgressionEvent(_progression_event) => true,
             EventInner::PlayitggRunnerEvent(_playitgg_runner_event) => true,
+            // only owners can change them, the values include the instances path
+            EventInner::DaemonSettingsEvent(_) => self.is_owner,
         }
     }
 
@@ -269,18 +431,32 @@ impl User {
     }
 }
 
+#[derive(Debug, Clone)]
 pub enum UserAction {
     // instance specific actions:
     ViewInstance(InstanceUuid),
     StartInstance(InstanceUuid),
     StopInstance(InstanceUuid),
     AccessConsole(InstanceUuid),
+    /// Watch the console, sending commands takes `AccessConsole`
+    ReadConsole(InstanceUuid),
     AccessSetting(InstanceUuid),
     ReadResource(InstanceUuid),
     WriteResource(InstanceUuid),
     AccessMacro(Option<InstanceUuid>),
+    /// Read what files contain
     ReadInstanceFile(InstanceUuid),
+    /// See names, sizes and modification times of files, but not their contents
+    ListInstanceFiles(InstanceUuid),
     WriteInstanceFile(InstanceUuid),
+    /// Edit the protected files policy and write files it protects
+    ManageProtectedFiles(InstanceUuid),
+    /// Start the instance while someone else has it under maintenance
+    BypassMaintenance(InstanceUuid),
+    /// Send chat messages to the players, a narrower `AccessConsole`
+    SendChat(InstanceUuid),
+    /// What only the owner and admins may do to an instance, `None` for one yet to be created
+    AdministerInstance(Option<InstanceUuid>),
 
     // global actions:
     CreateInstance,
@@ -290,6 +466,12 @@ pub enum UserAction {
     ManageUser,
     ManagePermission,
     InstallExtension,
+    /// Read the audit log of every instance
+    ViewAudit,
+    /// Register webhook targets and pick the events they're notified of
+    ManageNotifications,
+    /// Change or shut down the daemon itself, owner only
+    ManageDaemon,
 }
 
 impl UserAction {
@@ -299,12 +481,18 @@ impl UserAction {
             UserAction::StartInstance(_) => true,
             UserAction::StopInstance(_) => true,
             UserAction::AccessConsole(_) => true,
+            UserAction::ReadConsole(_) => true,
             UserAction::AccessSetting(_) => true,
             UserAction::ReadResource(_) => true,
             UserAction::WriteResource(_) => true,
             UserAction::AccessMacro(_) => true,
             UserAction::ReadInstanceFile(_) => true,
+            UserAction::ListInstanceFiles(_) => true,
             UserAction::WriteInstanceFile(_) => true,
+            UserAction::ManageProtectedFiles(_) => false,
+            UserAction::BypassMaintenance(_) => true,
+            UserAction::SendChat(_) => true,
+            UserAction::AdministerInstance(_) => true,
             UserAction::CreateInstance => true,
             UserAction::DeleteInstance => true,
             UserAction::ReadGlobalFile => false,
@@ -312,6 +500,10 @@ impl UserAction {
             UserAction::ManageUser => false,
             UserAction::ManagePermission => false,
             UserAction::InstallExtension => false,
+            UserAction::ViewAudit => true,
+            UserAction::ManageNotifications => true,
+            // safe mode is turned off with it
+            UserAction::ManageDaemon => true,
         }
     }
 }
@@ -324,6 +516,8 @@ pub struct PublicUser {
     pub is_owner: bool,
     pub is_admin: bool,
     pub permissions: UserPermission,
+    pub is_disabled: bool,
+    pub password_reset_required: bool,
 }
 
 impl From<&User> for PublicUser {
@@ -334,6 +528,8 @@ impl From<&User> for PublicUser {
             is_owner: user.is_owner,
             is_admin: user.is_admin,
             permissions: user.permissions.clone(),
+            is_disabled: user.is_disabled,
+            password_reset_required: user.password_reset_required,
         }
     }
 }
@@ -346,6 +542,8 @@ impl From<User> for PublicUser {
             is_owner: user.is_owner,
             is_admin: user.is_admin,
             permissions: user.permissions,
+            is_disabled: user.is_disabled,
+            password_reset_required: user.password_reset_required,
         }
     }
 }
@@ -486,23 +684,19 @@ impl UsersManager {
         Ok(user)
     }
 
+    /// Rotates the user's token secret and revokes their access tokens, so nothing issued before
+    /// is accepted anymore
     pub async fn logout_user(
         &mut self,
         uid: impl AsRef<UserId>,
         caused_by: CausedBy,
     ) -> Result<(), Error> {
-        let old_secret = self
-            .users
-            .get_mut(uid.as_ref())
-            .ok_or_else(|| Error {
-                kind: ErrorKind::NotFound,
-                source: eyre!("User id not found"),
-            })?
-            .secret
-            .clone();
-        if let Some(user) = self.users.get_mut(uid.as_ref()) {
-            user.secret = UserSecret::default();
-        }
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let old_secret = std::mem::replace(&mut user.secret, UserSecret::default());
+        let old_access_tokens = std::mem::take(&mut user.access_tokens);
 
         match self.write_to_file().await {
             Ok(_) => {
@@ -513,13 +707,27 @@ impl UsersManager {
                     }),
                     details: "".to_string(),
                     snowflake: Snowflake::default(),
-                    caused_by,
+                    caused_by: caused_by.clone(),
                 });
+                for access_token in old_access_tokens {
+                    self.event_broadcaster.send(Event {
+                        event_inner: EventInner::UserEvent(UserEvent {
+                            user_id: uid.as_ref().to_owned(),
+                            user_event_inner: UserEventInner::AccessTokenRevoked {
+                                token_id: access_token.id,
+                            },
+                        }),
+                        details: "".to_string(),
+                        snowflake: Snowflake::default(),
+                        caused_by: caused_by.clone(),
+                    });
+                }
                 Ok(())
             }
             Err(e) => {
                 if let Some(user) = self.users.get_mut(uid.as_ref()) {
-                    user.secret = old_secret
+                    user.secret = old_secret;
+                    user.access_tokens = old_access_tokens;
                 }
                 Err(e)
             }
@@ -589,6 +797,7 @@ impl UsersManager {
             })?
             .hashed_psw
             .clone();
+        let is_self_change = old_password.is_some();
         if let Some(old_password) = old_password {
             Argon2::default()
                 .verify_password(
@@ -600,8 +809,15 @@ impl UsersManager {
                     source: eyre!("Credential mismatch"),
                 })?;
         }
+        let old_reset_required = self
+            .users
+            .get(uid.as_ref())
+            .map_or(false, |user| user.password_reset_required);
         if let Some(user) = self.users.get_mut(uid.as_ref()) {
             user.hashed_psw = hash_password(password);
+            if is_self_change {
+                user.password_reset_required = false;
+            }
         }
         match self.write_to_file().await {
             Ok(_) => {
@@ -619,6 +835,7 @@ impl UsersManager {
             Err(e) => {
                 if let Some(user) = self.users.get_mut(uid.as_ref()) {
                     user.hashed_psw = old_data;
+                    user.password_reset_required = old_reset_required;
                 }
                 Err(e)
             }
@@ -674,9 +891,161 @@ impl UsersManager {
         }
     }
 
+    /// Applies `update` to a user and persists it, rolling back if the write fails
+    async fn update_user(
+        &mut self,
+        uid: &UserId,
+        update: impl FnOnce(&mut User),
+        user_event_inner: UserEventInner,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        let user = self.users.get_mut(uid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        let old_user = user.clone();
+        update(user);
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.to_owned(),
+                        user_event_inner,
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                self.users.insert(uid.to_owned(), old_user);
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn set_role(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        new_role: UserRole,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        self.update_user(
+            uid.as_ref(),
+            |user| user.is_admin = new_role == UserRole::Admin,
+            UserEventInner::RoleChanged { new_role },
+            caused_by,
+        )
+        .await
+    }
+
+    /// Disabling also logs the user out, so their tokens stay invalid once re-enabled
+    pub async fn set_disabled(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        is_disabled: bool,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        self.update_user(
+            uid.as_ref(),
+            |user| user.is_disabled = is_disabled,
+            UserEventInner::DisabledChanged { is_disabled },
+            caused_by.clone(),
+        )
+        .await?;
+        if is_disabled {
+            self.logout_user(uid, caused_by).await?;
+        }
+        Ok(())
+    }
+
+    /// Logs the user out and flags them to pick a new password on their next login
+    pub async fn require_password_reset(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        self.update_user(
+            uid.as_ref(),
+            |user| user.password_reset_required = true,
+            UserEventInner::PasswordResetRequired,
+            caused_by.clone(),
+        )
+        .await?;
+        self.logout_user(uid, caused_by).await
+    }
+
+    /// Adds an access token to the user, returning its full value which isn't stored anywhere
+    pub async fn create_access_token(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        name: String,
+        scopes: TokenScopes,
+        expires_at: Option<i64>,
+        caused_by: CausedBy,
+    ) -> Result<(AccessToken, String), Error> {
+        let (access_token, token) = AccessToken::generate(name, scopes, expires_at);
+        let token_id = access_token.id;
+        let new_token = access_token.clone();
+        self.update_user(
+            uid.as_ref(),
+            |user| user.access_tokens.push(new_token),
+            UserEventInner::AccessTokenCreated { token_id },
+            caused_by,
+        )
+        .await?;
+        Ok((access_token, token))
+    }
+
+    pub async fn revoke_access_token(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        token_id: Snowflake,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        if !self.users.get(uid.as_ref()).map_or(false, |user| {
+            user.access_tokens.iter().any(|t| t.id == token_id)
+        }) {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Access token not found"),
+            });
+        }
+        self.update_user(
+            uid.as_ref(),
+            |user| user.access_tokens.retain(|t| t.id != token_id),
+            UserEventInner::AccessTokenRevoked { token_id },
+            caused_by,
+        )
+        .await
+    }
+
+    fn try_auth_access_token(&self, token: &str) -> Option<User> {
+        let (token_id, _) = parse_token(token)?;
+        self.users.values().find_map(|user| {
+            let access_token = user
+                .access_tokens
+                .iter()
+                .find(|access_token| access_token.id.to_string() == token_id)?;
+            if user.is_disabled || !access_token.verify(token) {
+                return None;
+            }
+            let mut user = user.to_owned();
+            user.token_scopes = Some(access_token.scopes.clone());
+            Some(user)
+        })
+    }
+
     pub fn try_auth(&self, token: &str) -> Option<User> {
+        if parse_token(token).is_some() {
+            return self.try_auth_access_token(token);
+        }
         let claimed_uid = decode_no_verify(token)?;
         let claimed_requester = self.users.get(&claimed_uid)?;
+        if claimed_requester.is_disabled {
+            return None;
+        }
         let requester_uid = decode_token(token, &claimed_requester.secret)?;
         if claimed_uid != requester_uid {
             return None;
@@ -685,10 +1054,12 @@ impl UsersManager {
     }
 
     pub fn try_auth_or_err(&self, token: &str) -> Result<User, Error> {
-        self.try_auth(token).ok_or_else(|| Error {
+        let user = self.try_auth(token).ok_or_else(|| Error {
             kind: ErrorKind::Unauthorized,
             source: eyre!("Unauthorized"),
-        })
+        })?;
+        request_context::set_user(&user.username);
+        Ok(user)
     }
 
     pub fn login(
@@ -709,6 +1080,12 @@ impl UsersManager {
                 kind: ErrorKind::Unauthorized,
                 source: eyre!("Credential mismatch"),
             })?;
+        if user.is_disabled {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("This account is disabled"),
+            });
+        }
         user.create_jwt()
     }
 }
@@ -805,6 +1182,41 @@ mod tests {
         users_manager.login("test_user1", "54321").unwrap();
     }
 
+    #[tokio::test]
+    async fn test_password_change_invalidates_tokens() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_invalidate")
+            .unwrap()
+            .into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let test_user1 = User::new(
+            "test_user1".to_string(),
+            "12345",
+            false,
+            false,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(test_user1.clone(), CausedBy::System)
+            .await
+            .unwrap();
+        let old_token = users_manager.login("test_user1", "12345").unwrap();
+        users_manager
+            .change_password(
+                &test_user1.uid,
+                None::<&str>,
+                "54321".to_string(),
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(old_token.as_ref()).is_none());
+        let new_token = users_manager.login("test_user1", "54321").unwrap();
+        assert!(users_manager.try_auth(new_token.as_ref()).is_some());
+    }
+
     #[tokio::test]
     async fn test_persistent() {
         use super::*;
@@ -861,4 +1273,297 @@ mod tests {
 
         assert!(users_manager.get_user_by_username("test_user1").is_some());
     }
+
+    #[tokio::test]
+    async fn test_disable_and_password_reset() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_disable").unwrap().into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let test_user1 = User::new(
+            "test_user1".to_string(),
+            "12345",
+            false,
+            false,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(test_user1.clone(), CausedBy::System)
+            .await
+            .unwrap();
+        let token = users_manager.login("test_user1", "12345").unwrap();
+
+        users_manager
+            .set_disabled(&test_user1.uid, true, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(token.as_ref()).is_none());
+        assert!(users_manager.login("test_user1", "12345").is_err());
+
+        // re-enabling doesn't bring back the old token
+        users_manager
+            .set_disabled(&test_user1.uid, false, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(token.as_ref()).is_none());
+
+        let token = users_manager.login("test_user1", "12345").unwrap();
+        users_manager
+            .require_password_reset(&test_user1.uid, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(token.as_ref()).is_none());
+        assert!(
+            users_manager
+                .get_user(&test_user1.uid)
+                .unwrap()
+                .password_reset_required
+        );
+        users_manager
+            .change_password(
+                &test_user1.uid,
+                Some("12345"),
+                "54321".to_string(),
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+        assert!(
+            !users_manager
+                .get_user(&test_user1.uid)
+                .unwrap()
+                .password_reset_required
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_token() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_access_token")
+            .unwrap()
+            .into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let owner = User::new(
+            "owner".to_string(),
+            "12345",
+            true,
+            false,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(owner.clone(), CausedBy::System)
+            .await
+            .unwrap();
+        let scopes = TokenScopes {
+            read_only: true,
+            instances: None,
+            file_access: false,
+        };
+        let (access_token, token) = users_manager
+            .create_access_token(&owner.uid, "ci".to_string(), scopes, None, CausedBy::System)
+            .await
+            .unwrap();
+
+        // the owner's token is still bound by its scopes
+        let requester = users_manager.try_auth(&token).unwrap();
+        assert_eq!(requester.uid, owner.uid);
+        assert!(requester.can_perform_action(&UserAction::ViewInstance(InstanceUuid::default())));
+        assert!(!requester.can_perform_action(&UserAction::CreateInstance));
+        assert!(!requester.can_perform_action(&UserAction::ManageDaemon));
+        assert!(!requester.can_perform_action(&UserAction::AdministerInstance(None)));
+
+        users_manager
+            .revoke_access_token(&owner.uid, access_token.id, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(&token).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_logout_revokes_access_tokens() {
+        use super::*;
+        let temp_dir = tempdir::TempDir::new("test_logout_revokes")
+            .unwrap()
+            .into_path();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx.clone(), HashMap::new(), temp_dir.join("users.json"));
+        let admin = User::new(
+            "admin".to_string(),
+            "12345",
+            false,
+            true,
+            UserPermission::default(),
+        );
+        users_manager
+            .add_user(admin.clone(), CausedBy::System)
+            .await
+            .unwrap();
+        async fn create_token(users_manager: &mut UsersManager, uid: &UserId) -> String {
+            users_manager
+                .create_access_token(
+                    uid,
+                    "ci".to_string(),
+                    TokenScopes::default(),
+                    None,
+                    CausedBy::System,
+                )
+                .await
+                .unwrap()
+                .1
+        }
+
+        let token = create_token(&mut users_manager, &admin.uid).await;
+        assert!(users_manager.try_auth(&token).is_some());
+        users_manager
+            .logout_user(&admin.uid, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(&token).is_none());
+
+        let token = create_token(&mut users_manager, &admin.uid).await;
+        users_manager
+            .change_password(
+                &admin.uid,
+                None::<&str>,
+                "54321".to_string(),
+                CausedBy::System,
+            )
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(&token).is_none());
+
+        // off-boarding doesn't leave a way back in
+        let token = create_token(&mut users_manager, &admin.uid).await;
+        users_manager
+            .require_password_reset(&admin.uid, CausedBy::System)
+            .await
+            .unwrap();
+        assert!(users_manager.try_auth(&token).is_none());
+        assert!(users_manager
+            .get_user(&admin.uid)
+            .unwrap()
+            .access_tokens
+            .is_empty());
+    }
+
+    #[test]
+    fn test_assign_role() {
+        use super::*;
+        let owner = User::new(
+            "owner".to_string(),
+            "1",
+            true,
+            false,
+            UserPermission::default(),
+        );
+        let admin = User::new(
+            "admin".to_string(),
+            "1",
+            false,
+            true,
+            UserPermission::default(),
+        );
+        let user = User::new(
+            "user".to_string(),
+            "1",
+            false,
+            false,
+            UserPermission::default(),
+        );
+        assert!(owner.can_assign_role(Some(&user), UserRole::Admin).is_ok());
+        assert!(owner
+            .can_assign_role(Some(&owner), UserRole::Admin)
+            .is_err());
+        assert!(owner.can_assign_role(None, UserRole::Owner).is_err());
+        assert!(admin.can_assign_role(None, UserRole::User).is_ok());
+        assert!(admin.can_assign_role(None, UserRole::Admin).is_err());
+        assert!(admin.can_assign_role(Some(&admin), UserRole::User).is_err());
+    }
+
+    #[test]
+    fn test_observer() {
+        use super::*;
+        use crate::auth::permission::PermissionPreset;
+        let instance_uuid = InstanceUuid::default();
+        let observer = User::new(
+            "observer".to_string(),
+            "1",
+            false,
+            false,
+            PermissionPreset::Observer.permissions([instance_uuid.clone()]),
+        );
+        for action in [
+            UserAction::ViewInstance(instance_uuid.clone()),
+            UserAction::ReadConsole(instance_uuid.clone()),
+            UserAction::ListInstanceFiles(instance_uuid.clone()),
+        ] {
+            assert!(observer.try_action(&action, true).is_ok());
+        }
+        for action in [
+            UserAction::ReadInstanceFile(instance_uuid.clone()),
+            UserAction::AccessConsole(instance_uuid.clone()),
+            UserAction::SendChat(instance_uuid.clone()),
+            UserAction::WriteInstanceFile(instance_uuid.clone()),
+            UserAction::StartInstance(instance_uuid.clone()),
+            UserAction::AccessSetting(instance_uuid.clone()),
+            UserAction::ListInstanceFiles(InstanceUuid::default()),
+        ] {
+            assert!(observer.try_action(&action, true).is_err());
+        }
+
+        // whoever could read files or use the console before still can
+        let mut permissions = UserPermission::default();
+        permissions
+            .can_read_instance_file
+            .insert(instance_uuid.clone());
+        permissions
+            .can_access_instance_console
+            .insert(instance_uuid.clone());
+        let user = User::new("user".to_string(), "1", false, false, permissions);
+        assert!(user.can_perform_action(&UserAction::ListInstanceFiles(instance_uuid.clone())));
+        assert!(user.can_perform_action(&UserAction::SendChat(instance_uuid.clone())));
+        assert!(user.can_perform_action(&UserAction::ReadConsole(instance_uuid)));
+    }
+
+    #[test]
+    fn test_command_policy() {
+        use super::*;
+        use crate::auth::permission::CommandPolicy;
+        let instance_uuid = InstanceUuid::default();
+        let mut permissions = UserPermission::default();
+        permissions
+            .can_access_instance_console
+            .insert(instance_uuid.clone());
+        permissions.command_policies.insert(
+            instance_uuid.clone(),
+            CommandPolicy::Allow(vec!["kick".to_string()]),
+        );
+        let moderator = User::new(
+            "moderator".to_string(),
+            "1",
+            false,
+            false,
+            permissions.clone(),
+        );
+        assert!(moderator
+            .check_console_command(&instance_uuid, "kick Steve")
+            .is_ok());
+        let refused = moderator
+            .check_console_command(&instance_uuid, "op Steve")
+            .unwrap_err();
+        assert_eq!(refused.code(), ErrorCode::CommandNotAllowed);
+        // other instances aren't limited by the policy
+        assert!(moderator
+            .check_console_command(&InstanceUuid::default(), "op Steve")
+            .is_ok());
+
+        let admin = User::new("admin".to_string(), "1", false, true, permissions);
+        assert!(admin
+            .check_console_command(&instance_uuid, "op Steve")
+            .is_ok());
+    }
 }
@@ -0,0 +1,154 @@
+//! Starting the instances flagged to auto start once the daemon has restored them
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use futures::stream::{self, StreamExt};
+use tracing::{error, info, warn};
+
+use crate::capacity::Admission;
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::handlers::instance_fs::read_dot_lodestone_config;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::TServer;
+use crate::types::{AutoStartOrder, InstanceUuid, Snowflake};
+
+/// Instances of the same priority started at once
+const MAX_CONCURRENT_STARTS: usize = 2;
+/// How long a start may take before lower priorities go ahead without it
+const START_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Groups entries by priority, highest first
+fn start_waves<T>(mut entries: Vec<(T, AutoStartOrder)>) -> Vec<Vec<(T, AutoStartOrder)>> {
+    entries.sort_by_key(|(_, order)| std::cmp::Reverse(order.priority));
+    let mut waves: Vec<Vec<(T, AutoStartOrder)>> = Vec::new();
+    for entry in entries {
+        match waves.last_mut() {
+            Some(wave) if wave[0].1.priority == entry.1.priority => wave.push(entry),
+            _ => waves.push(vec![entry]),
+        }
+    }
+    waves
+}
+
+/// Starts every instance flagged to auto start, a priority only once the higher ones are running
+/// or have failed. A failed start, including one refused for going over the daemon's capacity
+/// limits, is reported as an event and doesn't hold up the others
+pub async fn auto_start_instances(
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    admission: Admission,
+    event_broadcaster: EventBroadcaster,
+) {
+    // cloned out so no map guard is held while starting
+    let candidates: Vec<GameInstance> = instances
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+    let mut flagged = Vec::new();
+    for instance in candidates {
+        if !instance.auto_start().await {
+            continue;
+        }
+        let order = match read_dot_lodestone_config(&instance.path().await).await {
+            Ok(config) => config.auto_start_order(),
+            Err(e) => {
+                warn!(
+                    "Auto starting {} with the default priority: {}",
+                    instance.name().await,
+                    e
+                );
+                AutoStartOrder::default()
+            }
+        };
+        flagged.push((instance, order));
+    }
+    for wave in start_waves(flagged) {
+        stream::iter(wave)
+            .for_each_concurrent(MAX_CONCURRENT_STARTS, |(instance, order)| {
+                auto_start(
+                    instance,
+                    order,
+                    admission.clone(),
+                    event_broadcaster.clone(),
+                )
+            })
+            .await;
+    }
+}
+
+async fn auto_start(
+    instance: GameInstance,
+    order: AutoStartOrder,
+    admission: Admission,
+    event_broadcaster: EventBroadcaster,
+) {
+    let uuid = instance.uuid().await;
+    let name = instance.name().await;
+    if order.delay_seconds > 0 {
+        tokio::time::sleep(Duration::from_secs(order.delay_seconds.into())).await;
+    }
+    info!("Auto starting instance {}", name);
+    event_broadcaster.send(Event::new_system_message(
+        uuid.clone(),
+        name.clone(),
+        "Starting the server, it is set to auto start".to_string(),
+    ));
+    let start = admission.start(&instance, CausedBy::System, true);
+    let message = match tokio::time::timeout(START_TIMEOUT, start).await {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => format!("Failed to auto start the server: {}", e.source),
+        Err(_) => {
+            warn!(
+                "Instance {} is still starting after {}s, starting the rest without it",
+                name,
+                START_TIMEOUT.as_secs()
+            );
+            return;
+        }
+    };
+    error!("Failed to auto start instance {}: {}", name, message);
+    event_broadcaster.send(Event {
+        event_inner: EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid: uuid,
+            instance_name: name,
+            instance_event_inner: InstanceEventInner::InstanceError { message },
+        }),
+        details: "".to_string(),
+        snowflake: Snowflake::default(),
+        caused_by: CausedBy::System,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(priority: i32) -> AutoStartOrder {
+        AutoStartOrder {
+            priority,
+            delay_seconds: 0,
+        }
+    }
+
+    #[test]
+    fn test_start_waves() {
+        let waves = start_waves(vec![
+            ("backend_a", order(0)),
+            ("proxy", order(10)),
+            ("backend_b", order(0)),
+            ("lobby", order(5)),
+        ]);
+        let names: Vec<Vec<&str>> = waves
+            .iter()
+            .map(|wave| wave.iter().map(|(name, _)| *name).collect())
+            .collect();
+        assert_eq!(
+            names,
+            vec![vec!["proxy"], vec!["lobby"], vec!["backend_a", "backend_b"]]
+        );
+        assert!(start_waves::<&str>(Vec::new()).is_empty());
+    }
+}
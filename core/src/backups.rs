@@ -0,0 +1,1471 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::archive_manifest::{
+    hash_file, key_to_path, verify_archive, ArchiveManifest, HashingReader, VerificationReport,
+    MANIFEST_FILE_NAME,
+};
+use crate::auth::user::UserAction;
+use crate::cancellation::{checkpoint, CancellationRegistry};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{
+    CausedBy, Event, EventInner, InstanceEventInner, ProgressionEndValue, ProgressionStartValue,
+};
+use crate::global_settings::GlobalSettings;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::{GameInstance, TInstance};
+use crate::trash::{trash_dir, TRASH_DIR_NAME};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::util::{
+    format_byte_download, strip_extended_length_prefix, unzip_file, walk_dir, UnzipOption,
+    MAX_TRAVERSAL_DEPTH,
+};
+
+/// Directory inside the instance holding its backups, never included in a backup
+pub const BACKUPS_DIR_NAME: &str = "backups";
+const BACKUP_INDEX_FILE_NAME: &str = "index.json";
+/// Archived bytes between two progression updates
+const PROGRESS_REPORT_BYTES: u64 = 16 * 1024 * 1024;
+const SCHEDULE_TICK_INTERVAL: Duration = Duration::from_secs(60);
+/// How far back `keep_daily` keeps one scheduled backup per day
+const KEEP_DAILY_DAYS: i64 = 7;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS, Default)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum BackupFormat {
+    #[default]
+    Zip,
+    TarGz,
+    /// A directory snapshot that only copies the files changed since the previous snapshot,
+    /// unchanged ones are hard linked to it
+    Incremental,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS, Default)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum BackupKind {
+    /// Holds every file of the instance
+    #[default]
+    Full,
+    /// Builds on the snapshot taken before it
+    Incremental,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(tag = "status", rename_all = "snake_case")]
+#[ts(export)]
+pub enum VerificationStatus {
+    Unverified,
+    Passed,
+    Failed {
+        report: VerificationReport,
+    },
+    /// The archive predates checksum manifests
+    NoManifest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Backup {
+    pub id: Snowflake,
+    pub name: String,
+    pub format: BackupFormat,
+    /// Size of the archive in bytes
+    pub size: u64,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    pub verification: VerificationStatus,
+    /// Unix timestamp in seconds of the last verification
+    pub verified_at: Option<i64>,
+    /// Taken by the backup schedule, only those are subject to retention
+    #[serde(default)]
+    pub scheduled: bool,
+    #[serde(default)]
+    pub kind: BackupKind,
+    /// Snapshot an incremental backup was taken against
+    #[serde(default)]
+    pub base: Option<Snowflake>,
+    /// Incremental snapshots between this one and the full snapshot of its chain, itself included
+    #[serde(default)]
+    pub chain_depth: u32,
+    /// Size of the instance content once restored, unknown for backups predating it
+    #[serde(default)]
+    pub restored_size: Option<u64>,
+    /// Key of the copy on the remote backup target
+    #[serde(default)]
+    pub remote_key: Option<String>,
+}
+
+impl Backup {
+    pub(crate) fn file_name(&self) -> String {
+        match self.format {
+            BackupFormat::Zip => format!("{}.zip", self.id.to_string()),
+            BackupFormat::TarGz => format!("{}.tar.gz", self.id.to_string()),
+            BackupFormat::Incremental => self.id.to_string(),
+        }
+    }
+}
+
+/// The backup followed by the snapshots it builds on, down to the full one
+fn backup_chain<'a>(backups: &'a [Backup], backup: &'a Backup) -> Vec<&'a Backup> {
+    let mut chain = vec![backup];
+    while let Some(base) = chain
+        .last()
+        .and_then(|b| b.base)
+        .and_then(|id| backups.iter().find(|b| b.id == id))
+    {
+        // a malformed index must not loop forever
+        if chain.iter().any(|b| b.id == base.id) {
+            break;
+        }
+        chain.push(base);
+    }
+    chain
+}
+
+#[derive(Debug, Clone, Default, Deserialize, TS)]
+#[ts(export)]
+pub struct BackupConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub format: BackupFormat,
+    #[serde(skip)]
+    pub scheduled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct BackupSchedule {
+    pub enabled: bool,
+    /// Hours between two scheduled backups
+    pub interval_hours: u32,
+    /// Newest scheduled backups kept regardless of their age
+    pub keep_last: u32,
+    /// Also keep the newest scheduled backup of each of the last 7 days
+    pub keep_daily: bool,
+    /// Skip a scheduled backup when no player was online since the previous one
+    pub skip_without_players: bool,
+    pub format: BackupFormat,
+}
+
+impl Default for BackupSchedule {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 6,
+            keep_last: 5,
+            keep_daily: false,
+            skip_without_players: false,
+            format: BackupFormat::default(),
+        }
+    }
+}
+
+impl BackupSchedule {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.interval_hours == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Backup interval must be at least 1 hour"),
+            });
+        }
+        if self.keep_last == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("At least 1 scheduled backup must be kept"),
+            });
+        }
+        Ok(())
+    }
+
+    /// `last` is when the newest scheduled backup was taken, in unix seconds
+    pub fn is_due(&self, last: Option<i64>, now: i64) -> bool {
+        self.enabled
+            && last.map_or(true, |last| {
+                now - last >= self.interval_hours as i64 * 60 * 60
+            })
+    }
+
+    /// Scheduled backups falling out of the retention policy, manual backups are never pruned
+    pub fn backups_to_prune(&self, backups: &[Backup], now: i64) -> Vec<Snowflake> {
+        let mut scheduled: Vec<&Backup> = backups.iter().filter(|b| b.scheduled).collect();
+        scheduled.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+        let mut keep: HashSet<Snowflake> = scheduled
+            .iter()
+            .take(self.keep_last as usize)
+            .map(|b| b.id)
+            .collect();
+        if self.keep_daily {
+            let mut days = HashSet::new();
+            for backup in &scheduled {
+                if now - backup.created_at < KEEP_DAILY_DAYS * SECONDS_PER_DAY
+                    && days.insert(backup.created_at.div_euclid(SECONDS_PER_DAY))
+                {
+                    keep.insert(backup.id);
+                }
+            }
+        }
+        let mut pruned: Vec<Snowflake> = scheduled
+            .into_iter()
+            .filter(|b| !keep.contains(&b.id))
+            .map(|b| b.id)
+            .collect();
+        // the full snapshot of a chain stays as long as an increment left builds on it
+        let remaining: Vec<&Backup> = backups.iter().filter(|b| !pruned.contains(&b.id)).collect();
+        for backup in remaining {
+            if let Some(full) = backup_chain(backups, backup).last() {
+                pruned.retain(|id| *id != full.id);
+            }
+        }
+        pruned
+    }
+}
+
+pub fn backups_dir(instance_path: &Path) -> PathBuf {
+    instance_path.join(BACKUPS_DIR_NAME)
+}
+
+/// Manages instance backups, stored as archives in the instance's `backups` directory along
+/// with an index of their metadata
+#[derive(Clone, Default)]
+pub struct BackupManager {
+    /// Serializes read-modify-write cycles of the index files
+    index_lock: Arc<Mutex<()>>,
+    /// Instances with a backup being created, restored or deleted
+    busy: Arc<Mutex<HashSet<InstanceUuid>>>,
+    /// Instances without any player online since their last scheduled backup.
+    /// Unknown instances, like right after the daemon started, count as active
+    idle: Arc<Mutex<HashSet<InstanceUuid>>>,
+    /// When the schedule last tried to back up each instance, in unix seconds
+    last_scheduled_attempt: Arc<Mutex<HashMap<InstanceUuid, i64>>>,
+    /// Backups being archived can be cancelled through their progression
+    cancellation_registry: CancellationRegistry,
+}
+
+impl BackupManager {
+    pub fn new(cancellation_registry: CancellationRegistry) -> Self {
+        Self {
+            cancellation_registry,
+            ..Default::default()
+        }
+    }
+
+    pub async fn try_begin(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        if self.busy.lock().await.insert(instance_uuid.clone()) {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Another backup operation is in progress for this instance"),
+            })
+        }
+    }
+
+    pub async fn end(&self, instance_uuid: &InstanceUuid) {
+        self.busy.lock().await.remove(instance_uuid);
+    }
+
+    async fn read_index(dir: &Path) -> Result<Vec<Backup>, Error> {
+        let path = dir.join(BACKUP_INDEX_FILE_NAME);
+        let backups: Vec<Backup> = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).context(format!(
+                "Failed to parse backup index at {}",
+                path.display()
+            ))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!("Failed to read backup index at {}: {}", path.display(), e),
+                })
+            }
+        };
+        // archives removed by hand are dropped from the listing, unless a remote copy is left
+        Ok(backups
+            .into_iter()
+            .filter(|backup| backup.remote_key.is_some() || dir.join(backup.file_name()).exists())
+            .collect())
+    }
+
+    async fn write_index(dir: &Path, backups: &[Backup]) -> Result<(), Error> {
+        let path = dir.join(BACKUP_INDEX_FILE_NAME);
+        tokio::fs::write(
+            &path,
+            serde_json::to_string_pretty(backups).context("Failed to serialize backup index")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write backup index at {}",
+            path.display()
+        ))?;
+        Ok(())
+    }
+
+    pub(crate) async fn update<T>(
+        &self,
+        instance_path: &Path,
+        f: impl FnOnce(&mut Vec<Backup>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let _lock = self.index_lock.lock().await;
+        let dir = backups_dir(instance_path);
+        let mut backups = Self::read_index(&dir).await?;
+        let ret = f(&mut backups)?;
+        Self::write_index(&dir, &backups).await?;
+        Ok(ret)
+    }
+
+    pub async fn list(&self, instance_path: &Path) -> Result<Vec<Backup>, Error> {
+        let _lock = self.index_lock.lock().await;
+        Self::read_index(&backups_dir(instance_path)).await
+    }
+
+    pub async fn get(&self, instance_path: &Path, id: &Snowflake) -> Result<Backup, Error> {
+        self.list(instance_path)
+            .await?
+            .into_iter()
+            .find(|backup| backup.id == *id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Backup not found"),
+            })
+    }
+
+    /// Archives the instance directory, reporting the bytes processed through a progression
+    /// event. Cancelling the progression stops the archiving between two files.
+    ///
+    /// An incremental backup builds on the newest snapshot of the instance, the first one is full
+    pub async fn create(
+        &self,
+        instance_uuid: &InstanceUuid,
+        instance_path: &Path,
+        instance_name: &str,
+        id: Snowflake,
+        config: BackupConfig,
+        event_broadcaster: EventBroadcaster,
+        caused_by: CausedBy,
+    ) -> Result<Backup, Error> {
+        let dir = backups_dir(instance_path);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context(format!("Failed to create {}", dir.display()))?;
+        let base = match config.format {
+            BackupFormat::Incremental => self
+                .list(instance_path)
+                .await?
+                .into_iter()
+                .filter(|b| b.format == BackupFormat::Incremental)
+                .max_by_key(|b| b.created_at),
+            _ => None,
+        };
+        let mut backup = Backup {
+            id,
+            name: config.name.unwrap_or_else(|| {
+                format!(
+                    "Backup {}",
+                    chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
+                )
+            }),
+            format: config.format,
+            size: 0,
+            created_at: chrono::Utc::now().timestamp(),
+            verification: VerificationStatus::Unverified,
+            verified_at: None,
+            scheduled: config.scheduled,
+            kind: if base.is_some() {
+                BackupKind::Incremental
+            } else {
+                BackupKind::Full
+            },
+            base: base.as_ref().map(|base| base.id),
+            chain_depth: base.as_ref().map_or(0, |base| base.chain_depth + 1),
+            restored_size: None,
+            remote_key: None,
+        };
+        let base_path = base.map(|base| dir.join(base.file_name()));
+        let archive_path = dir.join(backup.file_name());
+        let partial_path = dir.join(format!(".{}.partial", backup.file_name()));
+
+        let (event_id, cancellation, result) = tokio::task::spawn_blocking({
+            let instance_path = instance_path.to_owned();
+            let partial_path = partial_path.clone();
+            let format = backup.format;
+            let progression_name = format!("Backing up {}", instance_name);
+            let start_value = ProgressionStartValue::BackupRun {
+                instance_uuid: instance_uuid.clone(),
+                backup_id: id,
+                scheduled: backup.scheduled,
+            };
+            let event_broadcaster = event_broadcaster.clone();
+            let cancellation_registry = self.cancellation_registry.clone();
+            let permission = UserAction::WriteInstanceFile(instance_uuid.clone());
+            move || {
+                let entries = match archive_entries(&instance_path) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        let (start, event_id) = Event::new_progression_event_start(
+                            progression_name,
+                            None,
+                            Some(start_value),
+                            caused_by,
+                        );
+                        event_broadcaster.send(start);
+                        let cancellation = cancellation_registry.register(&event_id, permission);
+                        return (event_id, cancellation, Err(e));
+                    }
+                };
+                let total: u64 = entries.iter().filter_map(|entry| entry.size).sum();
+                let (start, event_id) = Event::new_progression_event_start(
+                    progression_name,
+                    Some(total as f64),
+                    Some(start_value),
+                    caused_by,
+                );
+                event_broadcaster.send(start);
+                let cancellation = cancellation_registry.register(&event_id, permission);
+                let mut reported = 0;
+                let mut on_progress = |archived: u64| {
+                    if archived - reported >= PROGRESS_REPORT_BYTES || archived == total {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            &event_id,
+                            format!("Archived {}", format_byte_download(archived, total)),
+                            (archived - reported) as f64,
+                        ));
+                        reported = archived;
+                    }
+                    checkpoint(&cancellation)
+                };
+                let result = match format {
+                    BackupFormat::Incremental => write_snapshot(
+                        &entries,
+                        &partial_path,
+                        base_path.as_deref(),
+                        &mut on_progress,
+                    )
+                    .map(Some),
+                    _ => write_archive(&entries, &partial_path, format, &mut on_progress)
+                        .map(|_| None),
+                };
+                (event_id, cancellation, result.map(|copied| (copied, total)))
+            }
+        })
+        .await
+        .context("Failed to spawn blocking task")?;
+
+        let result = match result {
+            Ok((copied, total)) => {
+                backup.restored_size = Some(total);
+                self.store(
+                    instance_path,
+                    &partial_path,
+                    &archive_path,
+                    copied,
+                    &mut backup,
+                )
+                .await
+                .map(|_| backup)
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = remove_backup_path(&partial_path).await;
+        }
+        event_broadcaster.send(match &result {
+            Ok(backup) => Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(format!("Created backup \"{}\"", backup.name)),
+                Some(ProgressionEndValue::BackupRun {
+                    instance_uuid: instance_uuid.clone(),
+                    backup_id: backup.id,
+                    size: backup.size,
+                }),
+            ),
+            Err(_) if cancellation.is_cancelled() => {
+                Event::new_progression_event_cancelled(event_id, Some(instance_uuid.clone()))
+            }
+            Err(e) => Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(format!("Backup failed: {}", e.source)),
+                None,
+            ),
+        });
+        result
+    }
+
+    /// Moves a finished archive into place and adds it to the index. A snapshot's size is the
+    /// bytes it `copied`, what it shares with earlier snapshots takes no more space
+    async fn store(
+        &self,
+        instance_path: &Path,
+        partial_path: &Path,
+        archive_path: &Path,
+        copied: Option<u64>,
+        backup: &mut Backup,
+    ) -> Result<(), Error> {
+        tokio::fs::rename(partial_path, archive_path)
+            .await
+            .context(format!(
+                "Failed to move backup to {}",
+                archive_path.display()
+            ))?;
+        backup.size = match copied {
+            Some(copied) => copied,
+            None => tokio::fs::metadata(archive_path)
+                .await
+                .context(format!("Failed to read {}", archive_path.display()))?
+                .len(),
+        };
+        self.update(instance_path, |backups| {
+            backups.push(backup.clone());
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deletes a backup. A full snapshot can't be deleted while increments build on it, the
+    /// increments built on a deleted one move to its base. The deleted backup is returned, its
+    /// remote copy is left to the caller
+    pub async fn delete(&self, instance_path: &Path, id: &Snowflake) -> Result<Backup, Error> {
+        let dir = backups_dir(instance_path);
+        let backup = self
+            .update(instance_path, |backups| {
+                let index = backups
+                    .iter()
+                    .position(|backup| backup.id == *id)
+                    .ok_or_else(|| Error {
+                        kind: ErrorKind::NotFound,
+                        source: eyre!("Backup not found"),
+                    })?;
+                if backups[index].kind == BackupKind::Full
+                    && backups.iter().any(|backup| backup.base == Some(*id))
+                {
+                    return Err(Error {
+                        kind: ErrorKind::Conflict,
+                        source: eyre!(
+                            "Incremental backups build on {}, delete them first",
+                            backups[index].name
+                        ),
+                    });
+                }
+                let removed = backups.remove(index);
+                // the index is in creation order, so a base always comes before its increments.
+                // Their unchanged files are links or copies of their own, nothing is lost
+                let mut descendants = HashSet::from([removed.id]);
+                for backup in backups.iter_mut() {
+                    if let Some(base) = backup.base.filter(|base| descendants.contains(base)) {
+                        descendants.insert(backup.id);
+                        backup.chain_depth = backup.chain_depth.saturating_sub(1);
+                        if base == removed.id {
+                            backup.base = removed.base;
+                        }
+                    }
+                }
+                Ok(removed)
+            })
+            .await?;
+        match remove_backup_path(&dir.join(backup.file_name())).await {
+            Ok(()) => {}
+            // only the remote copy was left
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && backup.remote_key.is_some() => {}
+            Err(e) => {
+                return Err(Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!("Failed to delete backup {}: {}", backup.name, e),
+                })
+            }
+        }
+        Ok(backup)
+    }
+
+    async fn set_verification(
+        &self,
+        instance_path: &Path,
+        id: &Snowflake,
+        verification: VerificationStatus,
+    ) -> Result<Backup, Error> {
+        self.update(instance_path, |backups| {
+            let backup = backups
+                .iter_mut()
+                .find(|backup| backup.id == *id)
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("Backup not found"),
+                })?;
+            backup.verification = verification;
+            backup.verified_at = Some(chrono::Utc::now().timestamp());
+            Ok(backup.clone())
+        })
+        .await
+    }
+
+    /// Re-hashes the archive in place and records the result in the index
+    pub async fn verify(&self, instance_path: &Path, id: &Snowflake) -> Result<Backup, Error> {
+        let backup = self.get(instance_path, id).await?;
+        let archive_path = backups_dir(instance_path).join(backup.file_name());
+        ensure_local(&backup, &archive_path)?;
+        let report = tokio::task::spawn_blocking(move || match backup.format {
+            BackupFormat::Incremental => ArchiveManifest::read_from_dir(&archive_path)?
+                .map(|manifest| manifest.verify_dir(&archive_path))
+                .transpose(),
+            _ => verify_archive(&archive_path),
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        let verification = match report {
+            None => VerificationStatus::NoManifest,
+            Some(report) if report.is_ok() => VerificationStatus::Passed,
+            Some(report) => VerificationStatus::Failed { report },
+        };
+        self.set_verification(instance_path, id, verification).await
+    }
+
+    /// Replaces the content of the instance directory with the backup.
+    ///
+    /// The current content is moved aside rather than deleted, its location is returned.
+    /// The caller must make sure the instance is stopped and stays stopped
+    pub async fn restore(
+        &self,
+        instance_path: &Path,
+        id: &Snowflake,
+        max_path_length: usize,
+        verify: bool,
+    ) -> Result<PathBuf, Error> {
+        let backups = self.list(instance_path).await?;
+        let backup = backups
+            .iter()
+            .find(|backup| backup.id == *id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Backup not found"),
+            })?;
+        let dir = backups_dir(instance_path);
+        let archive_path = dir.join(backup.file_name());
+        ensure_local(backup, &archive_path)?;
+        let chain: Vec<PathBuf> = backup_chain(&backups, backup)
+            .into_iter()
+            .map(|backup| dir.join(backup.file_name()))
+            .collect();
+        let format = backup.format;
+        let staging = dir.join(format!(".restore-{}", backup.id.to_string()));
+        let aside = dir.join(format!(
+            "pre-restore-{}",
+            chrono::Utc::now().format("%Y%m%d-%H%M%S")
+        ));
+        let result = tokio::task::spawn_blocking({
+            let instance_path = instance_path.to_owned();
+            let staging = staging.clone();
+            let aside = aside.clone();
+            move || {
+                if staging.exists() {
+                    std::fs::remove_dir_all(&staging)
+                        .context(format!("Failed to clear {}", staging.display()))?;
+                }
+                match format {
+                    BackupFormat::Incremental => restore_snapshot(&chain, &staging, verify)?,
+                    _ => {
+                        unzip_file(
+                            &archive_path,
+                            UnzipOption::ToDir(staging.clone()),
+                            max_path_length,
+                            verify,
+                        )?;
+                    }
+                }
+                swap_in(&instance_path, &staging, &aside)
+            }
+        })
+        .await
+        .context("Failed to spawn blocking task")?;
+        if staging.exists() {
+            if let Err(e) = tokio::fs::remove_dir_all(&staging).await {
+                error!("Failed to clean up {}: {}", staging.display(), e);
+            }
+        }
+        if let Err(e) = &result {
+            if let Some(report) = e.source.downcast_ref::<VerificationReport>() {
+                self.set_verification(
+                    instance_path,
+                    id,
+                    VerificationStatus::Failed {
+                        report: report.clone(),
+                    },
+                )
+                .await?;
+            }
+        }
+        result.map(|_| aside)
+    }
+}
+
+impl BackupManager {
+    /// Takes scheduled backups until the daemon shuts down
+    pub async fn run_schedules(
+        self,
+        instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+        event_broadcaster: EventBroadcaster,
+        global_settings: Arc<Mutex<GlobalSettings>>,
+    ) {
+        let mut events = event_broadcaster.subscribe();
+        let mut interval = tokio::time::interval(SCHEDULE_TICK_INTERVAL);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => self.record_player_activity(&event).await,
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return,
+                },
+                _ = interval.tick() => {
+                    let instances: Vec<(InstanceUuid, GameInstance)> = instances
+                        .iter()
+                        .map(|entry| (entry.key().clone(), entry.value().clone()))
+                        .collect();
+                    for (instance_uuid, instance) in instances {
+                        tokio::spawn(self.clone().run_scheduled(
+                            instance_uuid,
+                            instance,
+                            event_broadcaster.clone(),
+                            global_settings.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn record_player_activity(&self, event: &Event) {
+        if let EventInner::InstanceEvent(instance_event) = &event.event_inner {
+            if let InstanceEventInner::PlayerChange { player_list, .. } =
+                &instance_event.instance_event_inner
+            {
+                if !player_list.is_empty() {
+                    self.idle.lock().await.remove(&instance_event.instance_uuid);
+                }
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "s3-backups"), allow(unused_variables))]
+    async fn run_scheduled(
+        self,
+        instance_uuid: InstanceUuid,
+        instance: GameInstance,
+        event_broadcaster: EventBroadcaster,
+        global_settings: Arc<Mutex<GlobalSettings>>,
+    ) {
+        let schedule = match instance.backup_schedule().await {
+            Ok(schedule) if schedule.enabled => schedule,
+            _ => return,
+        };
+        let path = instance.path().await;
+        let backups = match self.list(&path).await {
+            Ok(backups) => backups,
+            Err(e) => {
+                error!("Failed to list backups of {}: {}", instance_uuid, e);
+                return;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        // a failed attempt waits for the next interval rather than retrying every tick
+        let last = backups
+            .iter()
+            .filter(|b| b.scheduled)
+            .map(|b| b.created_at)
+            .chain(
+                self.last_scheduled_attempt
+                    .lock()
+                    .await
+                    .get(&instance_uuid)
+                    .copied(),
+            )
+            .max();
+        if !schedule.is_due(last, now)
+            || (schedule.skip_without_players && self.idle.lock().await.contains(&instance_uuid))
+        {
+            return;
+        }
+        let name = instance.name().await;
+        if self.try_begin(&instance_uuid).await.is_err() {
+            info!(
+                "Skipping scheduled backup of {}, another backup operation is in progress",
+                name
+            );
+            return;
+        }
+        self.last_scheduled_attempt
+            .lock()
+            .await
+            .insert(instance_uuid.clone(), now);
+        let result = self
+            .create(
+                &instance_uuid,
+                &path,
+                &name,
+                Snowflake::default(),
+                BackupConfig {
+                    name: Some(format!(
+                        "Scheduled backup {}",
+                        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
+                    )),
+                    format: schedule.format,
+                    scheduled: true,
+                },
+                event_broadcaster.clone(),
+                CausedBy::System,
+            )
+            .await;
+        match result {
+            Ok(_backup) => {
+                #[cfg(feature = "s3-backups")]
+                crate::remote_backups::upload_new_backup(
+                    &self,
+                    &global_settings,
+                    &instance_uuid,
+                    &path,
+                    &_backup,
+                    &event_broadcaster,
+                )
+                .await;
+                // players still online count as activity for the next period
+                if instance.snapshot().player_count.unwrap_or(0) == 0 {
+                    self.idle.lock().await.insert(instance_uuid.clone());
+                } else {
+                    self.idle.lock().await.remove(&instance_uuid);
+                }
+                match self.prune(&path, &schedule).await {
+                    #[cfg(feature = "s3-backups")]
+                    Ok(pruned) => {
+                        crate::remote_backups::delete_remote_copies(&pruned, &global_settings).await
+                    }
+                    #[cfg(not(feature = "s3-backups"))]
+                    Ok(_) => {}
+                    Err(e) => error!("Failed to prune scheduled backups of {}: {}", name, e),
+                }
+            }
+            Err(e) => error!("Scheduled backup of {} failed: {}", name, e),
+        }
+        self.end(&instance_uuid).await;
+    }
+
+    /// Deletes the scheduled backups past retention and returns them
+    async fn prune(
+        &self,
+        instance_path: &Path,
+        schedule: &BackupSchedule,
+    ) -> Result<Vec<Backup>, Error> {
+        let backups = self.list(instance_path).await?;
+        let mut pruned = Vec::new();
+        for id in schedule.backups_to_prune(&backups, chrono::Utc::now().timestamp()) {
+            pruned.push(self.delete(instance_path, &id).await?);
+        }
+        Ok(pruned)
+    }
+}
+
+pub(crate) struct ArchiveEntry {
+    pub(crate) path: PathBuf,
+    /// `/` separated path inside the archive
+    pub(crate) name: String,
+    /// `None` for directories
+    pub(crate) size: Option<u64>,
+}
+
+/// Everything under the instance directory except its backups and trash
+pub(crate) fn archive_entries(instance_path: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    let backups = backups_dir(instance_path);
+    let trash = trash_dir(instance_path);
+    let mut entries = Vec::new();
+    for entry in walk_dir(instance_path, MAX_TRAVERSAL_DEPTH) {
+        let entry = entry?;
+        let path = strip_extended_length_prefix(entry.path());
+        if path.starts_with(&backups) || path.starts_with(&trash) {
+            continue;
+        }
+        let relative = match path.strip_prefix(instance_path) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => continue,
+        };
+        let name = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let size = if entry.file_type().is_dir() {
+            None
+        } else if entry.file_type().is_file() {
+            Some(
+                entry
+                    .metadata()
+                    .context(format!("Failed to read metadata of {}", path.display()))?
+                    .len(),
+            )
+        } else {
+            continue;
+        };
+        entries.push(ArchiveEntry {
+            path: entry.path().to_owned(),
+            name,
+            size,
+        });
+    }
+    Ok(entries)
+}
+
+enum ArchiveWriter {
+    Zip(zip::ZipWriter<std::fs::File>),
+    TarGz(tar::Builder<GzEncoder<std::fs::File>>),
+}
+
+impl ArchiveWriter {
+    fn add_directory(&mut self, name: &str) -> Result<(), Error> {
+        match self {
+            ArchiveWriter::Zip(writer) => writer
+                .add_directory(
+                    name,
+                    zip::write::FileOptions::default().unix_permissions(0o755),
+                )
+                .context(format!("Failed to add {} to archive", name))?,
+            ArchiveWriter::TarGz(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                header.set_mode(0o755);
+                builder
+                    .append_data(&mut header, name, std::io::empty())
+                    .context(format!("Failed to add {} to archive", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, name: &str, size: u64, mut reader: impl Read) -> Result<(), Error> {
+        match self {
+            ArchiveWriter::Zip(writer) => {
+                writer
+                    .start_file(
+                        name,
+                        zip::write::FileOptions::default()
+                            .unix_permissions(0o644)
+                            .large_file(size >= u32::MAX as u64),
+                    )
+                    .context(format!("Failed to add {} to archive", name))?;
+                std::io::copy(&mut reader, writer)
+                    .context(format!("Failed to write {} to archive", name))?;
+            }
+            ArchiveWriter::TarGz(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(size);
+                header.set_mode(0o644);
+                builder
+                    .append_data(&mut header, name, reader)
+                    .context(format!("Failed to write {} to archive", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            ArchiveWriter::Zip(mut writer) => {
+                writer.finish().context("Failed to finish archive")?;
+            }
+            ArchiveWriter::TarGz(builder) => {
+                builder
+                    .into_inner()
+                    .context("Failed to finish archive")?
+                    .finish()
+                    .context("Failed to finish archive")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `entries` and their checksum manifest to `dest`, hashing each file as it is
+/// streamed into the archive. `on_progress` gets the total bytes archived so far
+fn write_archive(
+    entries: &[ArchiveEntry],
+    dest: &Path,
+    format: BackupFormat,
+    on_progress: &mut dyn FnMut(u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let file =
+        std::fs::File::create(dest).context(format!("Failed to create {}", dest.display()))?;
+    let mut writer = match format {
+        BackupFormat::Zip => ArchiveWriter::Zip(zip::ZipWriter::new(file)),
+        BackupFormat::TarGz => ArchiveWriter::TarGz(tar::Builder::new(GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ))),
+    };
+    let mut manifest = ArchiveManifest::default();
+    let mut archived = 0;
+    for entry in entries {
+        let size = match entry.size {
+            Some(size) => size,
+            None => {
+                writer.add_directory(&entry.name)?;
+                continue;
+            }
+        };
+        let mut reader = HashingReader::new(
+            std::fs::File::open(&entry.path).context(format!("Failed to open {}", entry.name))?,
+        );
+        writer.add_file(&entry.name, size, &mut reader)?;
+        manifest.insert(Path::new(&entry.name), reader.finish());
+        archived += size;
+        on_progress(archived)?;
+    }
+    let manifest = manifest.to_bytes()?;
+    writer.add_file(
+        MANIFEST_FILE_NAME,
+        manifest.len() as u64,
+        manifest.as_slice(),
+    )?;
+    writer.finish()
+}
+
+/// Copies `entries` into the snapshot directory `dest` along with their checksum manifest.
+/// A file whose hash matches the manifest of the `base` snapshot is hard linked to its copy
+/// there, or copied again where links aren't supported. Returns the bytes copied
+fn write_snapshot(
+    entries: &[ArchiveEntry],
+    dest: &Path,
+    base: Option<&Path>,
+    on_progress: &mut dyn FnMut(u64) -> Result<(), Error>,
+) -> Result<u64, Error> {
+    let base_manifest = match base {
+        Some(base) => ArchiveManifest::read_from_dir(base)?.unwrap_or_default(),
+        None => ArchiveManifest::default(),
+    };
+    std::fs::create_dir_all(dest).context(format!("Failed to create {}", dest.display()))?;
+    let mut manifest = ArchiveManifest::default();
+    let mut processed = 0;
+    let mut copied = 0;
+    for entry in entries {
+        let target = key_to_path(dest, &entry.name)?;
+        let size = match entry.size {
+            Some(size) => size,
+            None => {
+                std::fs::create_dir_all(&target)
+                    .context(format!("Failed to create {}", target.display()))?;
+                continue;
+            }
+        };
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        let hash = hash_file(&entry.path).context(format!("Failed to read {}", entry.name))?;
+        let linked = match (base, base_manifest.entries.get(&entry.name)) {
+            (Some(base), Some(previous)) if *previous == hash => {
+                std::fs::hard_link(key_to_path(base, &entry.name)?, &target).is_ok()
+            }
+            _ => false,
+        };
+        if linked {
+            manifest.insert(Path::new(&entry.name), hash);
+        } else {
+            // hashed again while copying, the file may have changed in between
+            let mut reader = HashingReader::new(
+                std::fs::File::open(&entry.path)
+                    .context(format!("Failed to open {}", entry.name))?,
+            );
+            let mut file = std::fs::File::create(&target)
+                .context(format!("Failed to create {}", target.display()))?;
+            copied += std::io::copy(&mut reader, &mut file)
+                .context(format!("Failed to copy {}", entry.name))?;
+            manifest.insert(Path::new(&entry.name), reader.finish());
+        }
+        processed += size;
+        on_progress(processed)?;
+    }
+    std::fs::write(dest.join(MANIFEST_FILE_NAME), manifest.to_bytes()?)
+        .context("Failed to write snapshot manifest")?;
+    Ok(copied)
+}
+
+/// Rebuilds the tree of the snapshot `chain[0]` in `staging`, `chain` going down to its full
+/// snapshot. A file missing from the snapshot is taken from the first earlier one holding the
+/// same content. Files are copied, a restored instance must not write through to a backup
+fn restore_snapshot(chain: &[PathBuf], staging: &Path, verify: bool) -> Result<(), Error> {
+    let mut manifests = Vec::with_capacity(chain.len());
+    for snapshot in chain {
+        manifests.push((
+            snapshot,
+            ArchiveManifest::read_from_dir(snapshot)?.unwrap_or_default(),
+        ));
+    }
+    let (snapshot, manifest) = manifests.first().ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Backup not found"),
+    })?;
+    std::fs::create_dir_all(staging).context(format!("Failed to create {}", staging.display()))?;
+    // directories are recreated from the snapshot itself, the manifest only lists files
+    for entry in walk_dir(snapshot, MAX_TRAVERSAL_DEPTH) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        if let Ok(relative) = strip_extended_length_prefix(entry.path()).strip_prefix(snapshot) {
+            std::fs::create_dir_all(staging.join(relative))
+                .context(format!("Failed to create {}", relative.display()))?;
+        }
+    }
+    for (key, expected) in &manifest.entries {
+        let target = key_to_path(staging, key)?;
+        let mut source = None;
+        for (snapshot, earlier) in &manifests {
+            let path = key_to_path(snapshot, key)?;
+            if earlier.entries.get(key) == Some(expected) && path.is_file() {
+                source = Some(path);
+                break;
+            }
+        }
+        let source = source.ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!(
+                "{} is missing from the backup and the snapshots it builds on",
+                key
+            ),
+        })?;
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        std::fs::copy(&source, &target).context(format!("Failed to restore {}", key))?;
+    }
+    if verify {
+        manifest.verify_dir(staging)?.into_result()?;
+    }
+    Ok(())
+}
+
+/// Fails with `NotFound` for a backup whose local copy is gone
+fn ensure_local(backup: &Backup, archive_path: &Path) -> Result<(), Error> {
+    if archive_path.exists() {
+        return Ok(());
+    }
+    Err(Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!(
+            "{} is only stored on the remote backup target, fetch it first",
+            backup.name
+        ),
+    })
+}
+
+async fn remove_backup_path(path: &Path) -> std::io::Result<()> {
+    if tokio::fs::metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+/// Moves the instance content into `aside` and the restored content from `staging` into the
+/// instance, putting everything back if a move fails halfway
+fn swap_in(instance_path: &Path, staging: &Path, aside: &Path) -> Result<(), Error> {
+    let top_level = |dir: &Path| -> Result<Vec<std::ffi::OsString>, Error> {
+        Ok(std::fs::read_dir(dir)
+            .context(format!("Failed to read {}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.file_name()))
+            .filter(|name| name != BACKUPS_DIR_NAME && name != TRASH_DIR_NAME)
+            .collect())
+    };
+    std::fs::create_dir_all(aside).context(format!("Failed to create {}", aside.display()))?;
+    let mut moved_aside = Vec::new();
+    let mut moved_in = Vec::new();
+    let result = (|| -> Result<(), Error> {
+        for name in top_level(instance_path)? {
+            std::fs::rename(instance_path.join(&name), aside.join(&name))
+                .context(format!("Failed to move {} aside", name.to_string_lossy()))?;
+            moved_aside.push(name);
+        }
+        for name in top_level(staging)? {
+            std::fs::rename(staging.join(&name), instance_path.join(&name))
+                .context(format!("Failed to restore {}", name.to_string_lossy()))?;
+            moved_in.push(name);
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        for name in moved_in {
+            if let Err(e) = std::fs::rename(instance_path.join(&name), staging.join(&name)) {
+                error!("Failed to roll back {}: {}", name.to_string_lossy(), e);
+            }
+        }
+        for name in moved_aside {
+            if let Err(e) = std::fs::rename(aside.join(&name), instance_path.join(&name)) {
+                error!("Failed to roll back {}: {}", name.to_string_lossy(), e);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::init_paths;
+
+    #[tokio::test]
+    async fn test_backup_round_trip() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let instance_path = temp.path().join("instance");
+        std::fs::create_dir_all(instance_path.join("world/region")).unwrap();
+        std::fs::write(instance_path.join("world/level.dat"), "level").unwrap();
+        std::fs::write(instance_path.join("server.properties"), "motd=hi").unwrap();
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let manager = BackupManager::new(CancellationRegistry::new());
+
+        for format in [BackupFormat::Zip, BackupFormat::TarGz] {
+            let backup = manager
+                .create(
+                    &InstanceUuid::default(),
+                    &instance_path,
+                    "test",
+                    Snowflake::default(),
+                    BackupConfig {
+                        name: None,
+                        format,
+                        scheduled: false,
+                    },
+                    event_broadcaster.clone(),
+                    CausedBy::System,
+                )
+                .await
+                .unwrap();
+            let backup = manager.verify(&instance_path, &backup.id).await.unwrap();
+            assert_eq!(backup.verification, VerificationStatus::Passed);
+        }
+        let backups = manager.list(&instance_path).await.unwrap();
+        assert_eq!(backups.len(), 2);
+        // the second backup must not contain the first one
+        let entries = archive_entries(&instance_path).unwrap();
+        assert!(entries
+            .iter()
+            .all(|e| !e.name.starts_with(BACKUPS_DIR_NAME)));
+
+        std::fs::write(instance_path.join("world/level.dat"), "changed").unwrap();
+        std::fs::write(instance_path.join("new.txt"), "new").unwrap();
+        let aside = manager
+            .restore(&instance_path, &backups[1].id, 4096, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(instance_path.join("world/level.dat")).unwrap(),
+            "level"
+        );
+        assert!(instance_path.join("world/region").is_dir());
+        assert!(!instance_path.join("new.txt").exists());
+        assert!(!instance_path.join(MANIFEST_FILE_NAME).exists());
+        assert_eq!(
+            std::fs::read_to_string(aside.join("world/level.dat")).unwrap(),
+            "changed"
+        );
+        assert!(aside.join("new.txt").is_file());
+        assert_eq!(manager.list(&instance_path).await.unwrap().len(), 2);
+
+        manager
+            .delete(&instance_path, &backups[0].id)
+            .await
+            .unwrap();
+        let backups_left = manager.list(&instance_path).await.unwrap();
+        assert_eq!(backups_left, vec![backups[1].clone()]);
+        assert!(matches!(
+            manager.delete(&instance_path, &backups[0].id).await,
+            Err(Error {
+                kind: ErrorKind::NotFound,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_incremental_backup_chain() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let instance_path = temp.path().join("instance");
+        std::fs::create_dir_all(instance_path.join("world/region")).unwrap();
+        std::fs::write(instance_path.join("world/level.dat"), "level").unwrap();
+        std::fs::write(instance_path.join("server.properties"), "motd=hi").unwrap();
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let manager = BackupManager::new(CancellationRegistry::new());
+        let (manager, instance_path, event_broadcaster) =
+            (&manager, &instance_path, &event_broadcaster);
+        let create = || async move {
+            manager
+                .create(
+                    &InstanceUuid::default(),
+                    instance_path,
+                    "test",
+                    Snowflake::default(),
+                    BackupConfig {
+                        name: None,
+                        format: BackupFormat::Incremental,
+                        scheduled: false,
+                    },
+                    event_broadcaster.clone(),
+                    CausedBy::System,
+                )
+                .await
+        };
+
+        let full = create().await.unwrap();
+        assert_eq!(full.kind, BackupKind::Full);
+        assert_eq!(full.chain_depth, 0);
+        assert_eq!(full.size, 12);
+        assert_eq!(full.restored_size, Some(12));
+
+        std::fs::write(instance_path.join("world/level.dat"), "changed").unwrap();
+        std::fs::write(instance_path.join("new.txt"), "new").unwrap();
+        let first = create().await.unwrap();
+        assert_eq!(first.kind, BackupKind::Incremental);
+        assert_eq!(first.base, Some(full.id));
+        assert_eq!(first.chain_depth, 1);
+        // only the changed and new files are copied
+        assert_eq!(first.size, 10);
+        assert_eq!(first.restored_size, Some(17));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let unchanged = backups_dir(instance_path)
+                .join(first.file_name())
+                .join("server.properties");
+            assert_eq!(std::fs::metadata(unchanged).unwrap().nlink(), 2);
+        }
+
+        std::fs::remove_file(instance_path.join("new.txt")).unwrap();
+        let second = create().await.unwrap();
+        assert_eq!(second.base, Some(first.id));
+        assert_eq!(second.chain_depth, 2);
+        assert_eq!(second.size, 0);
+        let second = manager.verify(instance_path, &second.id).await.unwrap();
+        assert_eq!(second.verification, VerificationStatus::Passed);
+
+        assert!(matches!(
+            manager.delete(instance_path, &full.id).await,
+            Err(Error {
+                kind: ErrorKind::Conflict,
+                ..
+            })
+        ));
+        manager.delete(instance_path, &first.id).await.unwrap();
+        let second = manager.get(instance_path, &second.id).await.unwrap();
+        assert_eq!(second.base, Some(full.id));
+        assert_eq!(second.chain_depth, 1);
+
+        // a file lost from the snapshot is found down the chain
+        std::fs::remove_file(
+            backups_dir(instance_path)
+                .join(second.file_name())
+                .join("server.properties"),
+        )
+        .unwrap();
+        std::fs::write(instance_path.join("server.properties"), "motd=bye").unwrap();
+        manager
+            .restore(instance_path, &second.id, 4096, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(instance_path.join("world/level.dat")).unwrap(),
+            "changed"
+        );
+        assert_eq!(
+            std::fs::read_to_string(instance_path.join("server.properties")).unwrap(),
+            "motd=hi"
+        );
+        assert!(instance_path.join("world/region").is_dir());
+        assert!(!instance_path.join("new.txt").exists());
+        assert!(!instance_path.join(MANIFEST_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_backup_schedule_retention() {
+        let now = 100 * SECONDS_PER_DAY;
+        let backup = |hours_ago: i64, scheduled: bool| Backup {
+            id: Snowflake::default(),
+            name: "".to_string(),
+            format: BackupFormat::Zip,
+            size: 0,
+            created_at: now - hours_ago * 60 * 60,
+            verification: VerificationStatus::Unverified,
+            verified_at: None,
+            scheduled,
+            kind: BackupKind::Full,
+            base: None,
+            chain_depth: 0,
+            restored_size: None,
+            remote_key: None,
+        };
+        // one scheduled backup every 12 hours for 10 days, plus an old manual one
+        let mut backups: Vec<Backup> = (0..20).map(|i| backup(i * 12, true)).collect();
+        backups.push(backup(24 * 30, false));
+        let schedule = BackupSchedule {
+            enabled: true,
+            interval_hours: 12,
+            keep_last: 3,
+            ..Default::default()
+        };
+
+        let pruned = schedule.backups_to_prune(&backups, now);
+        assert_eq!(pruned.len(), 17);
+        assert!(backups[..3].iter().all(|b| !pruned.contains(&b.id)));
+        assert!(!pruned.contains(&backups[20].id));
+
+        let daily = BackupSchedule {
+            keep_daily: true,
+            ..schedule
+        };
+        let pruned = daily.backups_to_prune(&backups, now);
+        // the 3 newest, then the newest of each remaining day within the week
+        let kept: Vec<i64> = backups[..20]
+            .iter()
+            .filter(|b| !pruned.contains(&b.id))
+            .map(|b| (now - b.created_at) / (60 * 60))
+            .collect();
+        assert_eq!(kept, vec![0, 12, 24, 36, 60, 84, 108, 132, 156]);
+
+        assert!(schedule.is_due(None, now));
+        assert!(!schedule.is_due(Some(now - 11 * 60 * 60), now));
+        assert!(schedule.is_due(Some(now - 12 * 60 * 60), now));
+        assert!(!BackupSchedule::default().is_due(None, now));
+        assert!(BackupSchedule {
+            interval_hours: 0,
+            ..schedule
+        }
+        .validate()
+        .is_err());
+
+        // the full snapshot stays while a kept increment builds on it
+        let mut chain: Vec<Backup> = (0..5).rev().map(|i| backup(i * 12, true)).collect();
+        for i in 1..chain.len() {
+            chain[i].kind = BackupKind::Incremental;
+            chain[i].base = Some(chain[i - 1].id);
+            chain[i].chain_depth = i as u32;
+        }
+        let pruned = schedule.backups_to_prune(&chain, now);
+        assert_eq!(pruned, vec![chain[1].id]);
+    }
+}
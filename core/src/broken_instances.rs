@@ -0,0 +1,101 @@
+//! Instance directories that could not be loaded, kept so they can be repaired instead of
+//! silently missing from the instance list
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use ts_rs::TS;
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct BrokenInstance {
+    /// Name of the directory in the instances directory
+    pub name: String,
+    pub path: PathBuf,
+    /// Why loading the instance failed
+    pub error: String,
+    pub detected_at: i64,
+}
+
+impl BrokenInstance {
+    pub fn new(path: PathBuf, error: impl std::fmt::Display) -> Self {
+        Self {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            path,
+            error: error.to_string(),
+            detected_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct BrokenInstances {
+    by_name: Arc<Mutex<BTreeMap<String, BrokenInstance>>>,
+}
+
+impl BrokenInstances {
+    pub fn new(broken: Vec<BrokenInstance>) -> Self {
+        Self {
+            by_name: Arc::new(Mutex::new(
+                broken
+                    .into_iter()
+                    .map(|instance| (instance.name.clone(), instance))
+                    .collect(),
+            )),
+        }
+    }
+
+    pub async fn list(&self) -> Vec<BrokenInstance> {
+        self.by_name.lock().await.values().cloned().collect()
+    }
+
+    pub async fn get(&self, name: &str) -> Option<BrokenInstance> {
+        self.by_name.lock().await.get(name).cloned()
+    }
+
+    /// Records a new error for the directory, replacing the one it had
+    pub async fn insert(&self, instance: BrokenInstance) {
+        self.by_name
+            .lock()
+            .await
+            .insert(instance.name.clone(), instance);
+    }
+
+    pub async fn remove(&self, name: &str) -> Option<BrokenInstance> {
+        self.by_name.lock().await.remove(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_broken_instances() {
+        let broken = BrokenInstances::new(vec![BrokenInstance::new(
+            PathBuf::from("instances").join("survival"),
+            "Failed to parse .lodestone_config file",
+        )]);
+        assert_eq!(broken.list().await.len(), 1);
+        assert!(broken.get("survival").await.is_some());
+
+        broken
+            .insert(BrokenInstance::new(
+                PathBuf::from("instances").join("survival"),
+                "The server jar is missing",
+            ))
+            .await;
+        let list = broken.list().await;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].error, "The server jar is missing");
+
+        assert!(broken.remove("survival").await.is_some());
+        assert!(broken.get("survival").await.is_none());
+    }
+}
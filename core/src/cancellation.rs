@@ -0,0 +1,208 @@
+//! Cancelling long-running operations through their progression event
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::user::UserAction;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, ProgressionEventID, ProgressionEventInner};
+use crate::types::Snowflake;
+
+/// How long a finished operation is still told apart from an unknown one
+const FINISHED_RETENTION: Duration = Duration::from_secs(5 * 60);
+
+enum Entry {
+    Running {
+        token: CancellationToken,
+        /// What the requester needs to be allowed to do to cancel it
+        permission: UserAction,
+    },
+    Finished,
+}
+
+/// Cancellation tokens of the cancellable operations in flight, keyed by their progression event.
+///
+/// An operation registers when it starts, its progression end retires the token
+#[derive(Clone)]
+pub struct CancellationRegistry {
+    entries: Arc<Mutex<HashMap<Snowflake, Entry>>>,
+    retention: Duration,
+}
+
+impl CancellationRegistry {
+    pub fn new() -> Self {
+        Self {
+            entries: Default::default(),
+            retention: FINISHED_RETENTION,
+        }
+    }
+
+    /// The token the operation checks at its checkpoints
+    pub fn register(
+        &self,
+        event_id: &ProgressionEventID,
+        permission: UserAction,
+    ) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.lock().insert(
+            event_id.inner(),
+            Entry::Running {
+                token: token.clone(),
+                permission,
+            },
+        );
+        token
+    }
+
+    /// The action a requester must be allowed to take to cancel the operation
+    pub fn permission(&self, event_id: &Snowflake) -> Result<UserAction, Error> {
+        match self.lock().get(event_id) {
+            Some(Entry::Running { permission, .. }) => Ok(permission.clone()),
+            Some(Entry::Finished) => Err(already_finished()),
+            None => Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("No cancellable operation has this event id"),
+            }),
+        }
+    }
+
+    /// Signals the operation, it stops at its next checkpoint and ends its progression as
+    /// cancelled
+    pub fn cancel(&self, event_id: &Snowflake) -> Result<(), Error> {
+        match self.lock().get(event_id) {
+            Some(Entry::Running { token, .. }) => {
+                token.cancel();
+                Ok(())
+            }
+            Some(Entry::Finished) => Err(already_finished()),
+            None => Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("No cancellable operation has this event id"),
+            }),
+        }
+    }
+
+    /// Retires the tokens of the operations whose progression ended, until the event
+    /// broadcaster closes
+    pub fn run(self, event_broadcaster: &EventBroadcaster) -> impl Future<Output = ()> {
+        let mut rx = event_broadcaster.subscribe();
+        async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                if let EventInner::ProgressionEvent(progression) = &event.event_inner {
+                    if let ProgressionEventInner::ProgressionEnd { .. } =
+                        progression.progression_event_inner()
+                    {
+                        self.finish(progression.event_id());
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(&self, event_id: Snowflake) {
+        match self.lock().get_mut(&event_id) {
+            Some(entry) => *entry = Entry::Finished,
+            None => return,
+        }
+        let registry = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(registry.retention).await;
+            registry.lock().remove(&event_id);
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Snowflake, Entry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for CancellationRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn already_finished() -> Error {
+    Error {
+        kind: ErrorKind::Conflict,
+        source: eyre!("The operation already finished"),
+    }
+}
+
+/// What a cancelled operation fails with
+pub fn cancelled_error() -> Error {
+    Error {
+        kind: ErrorKind::Conflict,
+        source: eyre!("The operation was cancelled"),
+    }
+}
+
+/// A checkpoint of a cancellable operation, errors once it is cancelled
+pub fn checkpoint(token: &CancellationToken) -> Result<(), Error> {
+    if token.is_cancelled() {
+        Err(cancelled_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{CausedBy, Event};
+
+    #[tokio::test]
+    async fn test_cancellation_registry() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let registry = CancellationRegistry::new();
+        tokio::spawn(registry.clone().run(&event_broadcaster));
+
+        let (_, event_id) =
+            Event::new_progression_event_start("Backing up", None, None, CausedBy::System);
+        let id = event_id.inner();
+        let token = registry.register(&event_id, UserAction::CreateInstance);
+        assert!(matches!(
+            registry.permission(&id),
+            Ok(UserAction::CreateInstance)
+        ));
+        assert!(checkpoint(&token).is_ok());
+        registry.cancel(&id).unwrap();
+        assert!(checkpoint(&token).is_err());
+
+        event_broadcaster.send(Event::new_progression_event_end(
+            event_id,
+            false,
+            None::<&str>,
+            None,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(
+            registry.cancel(&id),
+            Err(Error {
+                kind: ErrorKind::Conflict,
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry.cancel(&Snowflake::default()),
+            Err(Error {
+                kind: ErrorKind::NotFound,
+                ..
+            })
+        ));
+    }
+}
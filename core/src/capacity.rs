@@ -0,0 +1,215 @@
+//! Daemon wide limits on how many instances run at once and how much heap they reserve together,
+//! so one start too many can't take the whole host down
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorCode};
+use crate::events::CausedBy;
+use crate::global_settings::GlobalSettings;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+/// What the running instances take up of the limits set in the daemon settings
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct Capacity {
+    pub running_instances: u32,
+    pub max_running_instances: Option<u32>,
+    /// Sum of the `max_ram` of running instances, in MB
+    pub reserved_ram: u32,
+    pub max_total_ram: Option<u32>,
+}
+
+impl Capacity {
+    /// Why starting one more instance reserving `ram` MB would go over a limit
+    pub fn exceeded_by(&self, ram: u32) -> Option<String> {
+        if let Some(max) = self.max_running_instances {
+            if self.running_instances >= max {
+                return Some(format!(
+                    "{} instances are running, the limit is {}",
+                    self.running_instances, max
+                ));
+            }
+        }
+        if let Some(max) = self.max_total_ram {
+            if self.reserved_ram.saturating_add(ram) > max {
+                return Some(format!(
+                    "running instances reserve {} MB of heap, {} MB more goes over the limit of {} MB",
+                    self.reserved_ram, ram, max
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// A start let through over the limits, recorded in the audit log
+#[derive(Serialize, Clone, Debug)]
+pub struct CapacityOverride {
+    pub reason: String,
+    pub capacity: Capacity,
+}
+
+/// Heap an instance reserves while running, in MB. Only Minecraft servers set one
+async fn reserved_ram(instance: &GameInstance) -> u32 {
+    match instance {
+        GameInstance::MinecraftInstance(instance) => instance.max_ram().await,
+        _ => 0,
+    }
+}
+
+/// A suspended server keeps its memory, so it still counts
+fn holds_capacity(state: State) -> bool {
+    !matches!(state, State::Stopped | State::Error)
+}
+
+/// Lets instances start only while the limits of the daemon settings leave room for them
+#[derive(Clone)]
+pub struct Admission {
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    /// Held from measuring to counting the instance in, so two starts can't take the same room
+    admitting: Arc<Mutex<()>>,
+    /// Admitted instances whose start hasn't returned yet, they may not look running yet
+    starting: Arc<std::sync::Mutex<HashSet<InstanceUuid>>>,
+}
+
+/// Keeps an admitted instance counted until its start returns
+pub struct AdmissionTicket {
+    uuid: Option<InstanceUuid>,
+    starting: Arc<std::sync::Mutex<HashSet<InstanceUuid>>>,
+    /// Set if the start goes over a limit it was allowed to
+    pub overridden: Option<CapacityOverride>,
+}
+
+impl Drop for AdmissionTicket {
+    fn drop(&mut self) {
+        if let Some(uuid) = &self.uuid {
+            self.starting.lock().unwrap().remove(uuid);
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(
+        instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+        global_settings: Arc<Mutex<GlobalSettings>>,
+    ) -> Self {
+        Self {
+            instances,
+            global_settings,
+            admitting: Arc::new(Mutex::new(())),
+            starting: Arc::new(std::sync::Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub async fn capacity(&self) -> Capacity {
+        let settings = self.global_settings.lock().await.daemon_settings();
+        // cloned out so no map guard is held while the instances are asked
+        let instances: Vec<GameInstance> = self
+            .instances
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        let starting = self.starting.lock().unwrap().clone();
+        let mut running_instances = 0;
+        let mut reserved = 0u32;
+        for instance in instances {
+            if holds_capacity(instance.state().await) || starting.contains(&instance.uuid().await) {
+                running_instances += 1;
+                reserved = reserved.saturating_add(reserved_ram(&instance).await);
+            }
+        }
+        Capacity {
+            running_instances,
+            max_running_instances: settings.max_running_instances,
+            reserved_ram: reserved,
+            max_total_ram: settings.max_total_ram,
+        }
+    }
+
+    /// Counts the instance in if the limits leave room for it, or regardless with `allow_override`.
+    /// An instance already running is left to its own start to refuse
+    pub async fn admit(
+        &self,
+        instance: &GameInstance,
+        allow_override: bool,
+    ) -> Result<AdmissionTicket, Error> {
+        let _admitting = self.admitting.lock().await;
+        let uuid = instance.uuid().await;
+        let mut ticket = AdmissionTicket {
+            uuid: None,
+            starting: self.starting.clone(),
+            overridden: None,
+        };
+        if holds_capacity(instance.state().await) || self.starting.lock().unwrap().contains(&uuid) {
+            return Ok(ticket);
+        }
+        let capacity = self.capacity().await;
+        if let Some(reason) = capacity.exceeded_by(reserved_ram(instance).await) {
+            if !allow_override {
+                return Err(Error::coded(
+                    ErrorCode::CapacityExceeded,
+                    format!("Not starting {}, {}", instance.name().await, reason),
+                )
+                .with_details(&capacity));
+            }
+            ticket.overridden = Some(CapacityOverride { reason, capacity });
+        }
+        self.starting.lock().unwrap().insert(uuid.clone());
+        ticket.uuid = Some(uuid);
+        Ok(ticket)
+    }
+
+    /// Starts the instance if the limits leave room for it
+    pub async fn start(
+        &self,
+        instance: &GameInstance,
+        caused_by: CausedBy,
+        block: bool,
+    ) -> Result<(), Error> {
+        let _ticket = self.admit(instance, false).await?;
+        instance.start(caused_by, block).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeded_by() {
+        let capacity = Capacity {
+            running_instances: 2,
+            max_running_instances: Some(3),
+            reserved_ram: 6144,
+            max_total_ram: Some(8192),
+        };
+        assert_eq!(capacity.exceeded_by(2048), None);
+        assert!(capacity
+            .exceeded_by(4096)
+            .unwrap()
+            .contains("limit of 8192 MB"));
+
+        let full = Capacity {
+            running_instances: 3,
+            ..capacity.clone()
+        };
+        assert!(full.exceeded_by(0).unwrap().contains("the limit is 3"));
+
+        let unlimited = Capacity {
+            max_running_instances: None,
+            max_total_ram: None,
+            ..full
+        };
+        assert_eq!(unlimited.exceeded_by(u32::MAX), None);
+    }
+}
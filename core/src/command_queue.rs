@@ -0,0 +1,588 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CommandQueueConfig {
+    /// Sustained number of commands written to the server per second
+    pub commands_per_sec: u32,
+    /// Number of commands that can be written back to back before throttling kicks in
+    pub burst: u32,
+    /// Maximum number of commands waiting to be written
+    pub capacity: u32,
+    /// Identical consecutive commands enqueued within this window are merged into one
+    pub coalesce_window_ms: u64,
+}
+
+impl Default for CommandQueueConfig {
+    fn default() -> Self {
+        Self {
+            commands_per_sec: 20,
+            burst: 40,
+            capacity: 256,
+            coalesce_window_ms: 500,
+        }
+    }
+}
+
+impl CommandQueueConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.commands_per_sec == 0 || self.burst == 0 || self.capacity == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("commands_per_sec, burst and capacity must be greater than 0"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Ordering of the variants is the order in which commands are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CommandPriority {
+    /// Lifecycle commands issued by lodestone itself, e.g. `stop`.
+    /// Not rate limited and never dropped
+    Control,
+    /// Console input typed by a user
+    Interactive,
+    /// Everything else: macros, triggers, schedulers
+    Automation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum CommandSource {
+    User,
+    Macro,
+    Instance,
+    System,
+}
+
+impl From<&CausedBy> for CommandSource {
+    fn from(caused_by: &CausedBy) -> Self {
+        match caused_by {
+            CausedBy::User { .. } => CommandSource::User,
+            CausedBy::Macro { .. } => CommandSource::Macro,
+            CausedBy::Instance { .. } => CommandSource::Instance,
+            CausedBy::Schedule { .. }
+            | CausedBy::Trigger { .. }
+            | CausedBy::System
+            | CausedBy::Unknown => CommandSource::System,
+        }
+    }
+}
+
+impl CommandSource {
+    pub fn priority(&self) -> CommandPriority {
+        match self {
+            CommandSource::User => CommandPriority::Interactive,
+            _ => CommandPriority::Automation,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CommandSourceStats {
+    pub source: CommandSource,
+    pub attempts: u64,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CommandQueueStatus {
+    pub config: CommandQueueConfig,
+    pub depth: u32,
+    pub interactive_depth: u32,
+    pub automation_depth: u32,
+    pub written: u64,
+    pub dropped: u64,
+    pub sources: Vec<CommandSourceStats>,
+}
+
+#[derive(Debug)]
+pub struct QueuedCommand {
+    pub command: String,
+    pub caused_by: CausedBy,
+    /// Number of identical commands merged into this one
+    pub count: u32,
+    enqueued_at: Instant,
+    /// Where the response goes, for a command pushed with `push_for_response`
+    reply: Option<oneshot::Sender<Option<String>>>,
+}
+
+/// What the writer task sends commands through
+#[async_trait]
+pub trait CommandTransport: Send + 'static {
+    /// Sends a command, returning the server's response if the transport carries one
+    async fn send(&mut self, cmd: &QueuedCommand) -> std::io::Result<Option<String>>;
+}
+
+/// Writes commands to the server's stdin, one per line
+pub struct Stdin<W>(pub W);
+
+#[async_trait]
+impl<W> CommandTransport for Stdin<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn send(&mut self, cmd: &QueuedCommand) -> std::io::Result<Option<String>> {
+        self.0
+            .write_all(format!("{}\n", cmd.command).as_bytes())
+            .await?;
+        self.0.flush().await?;
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+struct QueueInner {
+    config: CommandQueueConfig,
+    control: VecDeque<QueuedCommand>,
+    interactive: VecDeque<QueuedCommand>,
+    automation: VecDeque<QueuedCommand>,
+    tokens: f64,
+    last_refill: Instant,
+    written: u64,
+    sources: Vec<CommandSourceStats>,
+    closed: bool,
+}
+
+impl QueueInner {
+    fn source_stats(&mut self, source: CommandSource) -> &mut CommandSourceStats {
+        let idx = match self.sources.iter().position(|s| s.source == source) {
+            Some(idx) => idx,
+            None => {
+                self.sources.push(CommandSourceStats {
+                    source,
+                    attempts: 0,
+                    dropped: 0,
+                    coalesced: 0,
+                });
+                self.sources.len() - 1
+            }
+        };
+        &mut self.sources[idx]
+    }
+
+    fn lane(&mut self, priority: CommandPriority) -> &mut VecDeque<QueuedCommand> {
+        match priority {
+            CommandPriority::Control => &mut self.control,
+            CommandPriority::Interactive => &mut self.interactive,
+            CommandPriority::Automation => &mut self.automation,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.interactive.len() + self.automation.len()
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.commands_per_sec as f64)
+            .min(self.config.burst as f64);
+        self.last_refill = now;
+    }
+}
+
+/// Outbound command queue of an instance.
+///
+/// Every write to the server's stdin goes through here, and the writer task spawned by
+/// `spawn_writer` is the only thing holding the pipe, so commands are written in a
+/// deterministic order no matter how many sources are submitting them.
+#[derive(Debug, Clone)]
+pub struct CommandQueue {
+    inner: Arc<Mutex<QueueInner>>,
+    notify: Arc<Notify>,
+}
+
+impl CommandQueue {
+    pub fn new(config: CommandQueueConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(QueueInner {
+                config,
+                control: VecDeque::new(),
+                interactive: VecDeque::new(),
+                automation: VecDeque::new(),
+                tokens: config.burst as f64,
+                last_refill: Instant::now(),
+                written: 0,
+                sources: Vec::new(),
+                closed: true,
+            })),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub async fn config(&self) -> CommandQueueConfig {
+        self.inner.lock().await.config
+    }
+
+    pub async fn set_config(&self, config: CommandQueueConfig) -> Result<(), Error> {
+        config.validate()?;
+        let mut inner = self.inner.lock().await;
+        inner.config = config;
+        inner.tokens = inner.tokens.min(config.burst as f64);
+        Ok(())
+    }
+
+    pub async fn push(&self, command: &str, caused_by: CausedBy) -> Result<(), Error> {
+        let priority = CommandSource::from(&caused_by).priority();
+        self.push_with_priority(command, caused_by, priority).await
+    }
+
+    pub async fn push_with_priority(
+        &self,
+        command: &str,
+        caused_by: CausedBy,
+        priority: CommandPriority,
+    ) -> Result<(), Error> {
+        self.enqueue(command, caused_by, priority, None).await
+    }
+
+    /// Pushes a command that is never coalesced, the receiver gets the transport's response once
+    /// it's written, or an error if it's dropped
+    pub async fn push_for_response(
+        &self,
+        command: &str,
+        caused_by: CausedBy,
+    ) -> Result<oneshot::Receiver<Option<String>>, Error> {
+        let priority = CommandSource::from(&caused_by).priority();
+        let (tx, rx) = oneshot::channel();
+        self.enqueue(command, caused_by, priority, Some(tx)).await?;
+        Ok(rx)
+    }
+
+    async fn enqueue(
+        &self,
+        command: &str,
+        caused_by: CausedBy,
+        priority: CommandPriority,
+        reply: Option<oneshot::Sender<Option<String>>>,
+    ) -> Result<(), Error> {
+        let source = CommandSource::from(&caused_by);
+        let mut inner = self.inner.lock().await;
+        inner.source_stats(source).attempts += 1;
+        if inner.closed {
+            return Err(eyre!("Instance is not accepting commands").into());
+        }
+        let now = Instant::now();
+        let coalesce_window = Duration::from_millis(inner.config.coalesce_window_ms);
+        if priority != CommandPriority::Control {
+            if let Some(last) = inner.lane(priority).back_mut() {
+                // a command waiting on its response is never merged, nor merged into
+                if reply.is_none()
+                    && last.reply.is_none()
+                    && last.command == command
+                    && last.caused_by == caused_by
+                    && now.duration_since(last.enqueued_at) <= coalesce_window
+                {
+                    last.count += 1;
+                    inner.source_stats(source).coalesced += 1;
+                    return Ok(());
+                }
+            }
+            if inner.depth() >= inner.config.capacity as usize {
+                // interactive input preempts the oldest pending automation command
+                let evicted = if priority == CommandPriority::Interactive {
+                    inner.automation.pop_front()
+                } else {
+                    None
+                };
+                match evicted {
+                    Some(evicted) => {
+                        inner
+                            .source_stats(CommandSource::from(&evicted.caused_by))
+                            .dropped += evicted.count as u64;
+                    }
+                    None => {
+                        inner.source_stats(source).dropped += 1;
+                        return Err(Error {
+                            kind: ErrorKind::CommandQueueFull,
+                            source: eyre!(
+                                "Command queue is full ({} pending)",
+                                inner.config.capacity
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+        inner.lane(priority).push_back(QueuedCommand {
+            command: command.to_string(),
+            caused_by,
+            count: 1,
+            enqueued_at: now,
+            reply,
+        });
+        drop(inner);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next command that is allowed to be written.
+    ///
+    /// Returns `None` once the queue is closed
+    async fn pop(&self) -> Option<QueuedCommand> {
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().await;
+                if inner.closed {
+                    return None;
+                }
+                if let Some(cmd) = inner.control.pop_front() {
+                    return Some(cmd);
+                }
+                inner.refill();
+                if inner.depth() == 0 {
+                    None
+                } else if inner.tokens >= 1.0 {
+                    inner.tokens -= 1.0;
+                    return inner
+                        .interactive
+                        .pop_front()
+                        .or_else(|| inner.automation.pop_front());
+                } else {
+                    Some(Duration::from_secs_f64(
+                        (1.0 - inner.tokens) / inner.config.commands_per_sec as f64,
+                    ))
+                }
+            };
+            match wait {
+                Some(wait) => {
+                    // a control command may arrive while we are throttled
+                    let _ = tokio::time::timeout(wait, self.notify.notified()).await;
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    /// Start accepting commands. Pending commands from a previous run are discarded
+    pub async fn open(&self) {
+        let mut inner = self.inner.lock().await;
+        inner.control.clear();
+        inner.interactive.clear();
+        inner.automation.clear();
+        inner.tokens = inner.config.burst as f64;
+        inner.last_refill = Instant::now();
+        inner.closed = false;
+    }
+
+    /// Stop accepting commands and shut down the writer task
+    pub async fn close(&self) {
+        let mut guard = self.inner.lock().await;
+        let inner = &mut *guard;
+        inner.closed = true;
+        let pending = inner
+            .interactive
+            .drain(..)
+            .chain(inner.automation.drain(..))
+            .collect::<Vec<_>>();
+        for cmd in pending {
+            inner
+                .source_stats(CommandSource::from(&cmd.caused_by))
+                .dropped += cmd.count as u64;
+        }
+        inner.control.clear();
+        drop(guard);
+        self.notify.notify_one();
+    }
+
+    pub async fn status(&self) -> CommandQueueStatus {
+        let inner = self.inner.lock().await;
+        CommandQueueStatus {
+            config: inner.config,
+            depth: inner.depth() as u32,
+            interactive_depth: inner.interactive.len() as u32,
+            automation_depth: inner.automation.len() as u32,
+            written: inner.written,
+            dropped: inner.sources.iter().map(|s| s.dropped).sum(),
+            sources: inner.sources.clone(),
+        }
+    }
+
+    /// Spawns the task owning `writer`, which writes commands as the rate limit allows.
+    ///
+    /// `on_written` is called after each successful write
+    pub fn spawn_writer<W, F>(&self, writer: W, on_written: F) -> JoinHandle<()>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+        F: Fn(&QueuedCommand) + Send + 'static,
+    {
+        self.spawn_transport(Stdin(writer), on_written)
+    }
+
+    /// Like `spawn_writer`, for a server taking commands through something other than stdin
+    pub fn spawn_transport<T, F>(&self, mut transport: T, on_written: F) -> JoinHandle<()>
+    where
+        T: CommandTransport,
+        F: Fn(&QueuedCommand) + Send + 'static,
+    {
+        let queue = self.clone();
+        tokio::task::spawn(async move {
+            while let Some(mut cmd) = queue.pop().await {
+                let response = match transport.send(&cmd).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Failed to write command to stdin: {}", e);
+                        queue.close().await;
+                        break;
+                    }
+                };
+                queue.inner.lock().await.written += 1;
+                on_written(&cmd);
+                if let Some(reply) = cmd.reply.take() {
+                    let _ = reply.send(response);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    fn trigger() -> CausedBy {
+        CausedBy::Macro {
+            macro_pid: crate::macro_executor::MacroPID(0),
+        }
+    }
+
+    fn user() -> CausedBy {
+        CausedBy::User {
+            user_id: "user".to_string().into(),
+            user_name: "user".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_command_preempts_flood() {
+        let queue = CommandQueue::new(CommandQueueConfig {
+            commands_per_sec: 10,
+            burst: 1,
+            capacity: 32,
+            coalesce_window_ms: 0,
+        });
+        queue.open().await;
+        let (writer, reader) = tokio::io::duplex(4096);
+        queue.spawn_writer(writer, |_| {});
+
+        let mut rejected = 0;
+        for i in 0..1000 {
+            match queue.push(&format!("say spam {i}"), trigger()).await {
+                Ok(_) => {}
+                Err(e) => {
+                    assert!(matches!(e.kind, ErrorKind::CommandQueueFull));
+                    rejected += 1;
+                }
+            }
+        }
+        assert!(rejected > 0);
+
+        queue.push("list", user()).await.unwrap();
+
+        let mut lines = BufReader::new(reader).lines();
+        let found = tokio::time::timeout(Duration::from_millis(500), async {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line == "list" {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+        .unwrap_or(false);
+        assert!(found, "user command was not written promptly");
+
+        let status = queue.status().await;
+        let macro_stats = status
+            .sources
+            .iter()
+            .find(|s| s.source == CommandSource::Macro)
+            .unwrap();
+        assert_eq!(macro_stats.attempts, 1000);
+        assert_eq!(macro_stats.dropped, rejected);
+        assert!(status.depth <= 32);
+    }
+
+    #[tokio::test]
+    async fn test_user_command_accepted_when_full() {
+        let queue = CommandQueue::new(CommandQueueConfig {
+            commands_per_sec: 1,
+            burst: 1,
+            capacity: 2,
+            coalesce_window_ms: 0,
+        });
+        queue.open().await;
+        queue.push("a", trigger()).await.unwrap();
+        queue.push("b", trigger()).await.unwrap();
+        assert!(queue.push("c", trigger()).await.is_err());
+        queue.push("list", user()).await.unwrap();
+        let status = queue.status().await;
+        assert_eq!(status.depth, 2);
+        assert_eq!(status.interactive_depth, 1);
+        assert_eq!(status.dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let queue = CommandQueue::new(CommandQueueConfig::default());
+        queue.open().await;
+        for _ in 0..5 {
+            queue.push("save-all", trigger()).await.unwrap();
+        }
+        queue.push("list", trigger()).await.unwrap();
+        let status = queue.status().await;
+        assert_eq!(status.depth, 2);
+        assert_eq!(status.sources[0].coalesced, 4);
+        let cmd = queue.pop().await.unwrap();
+        assert_eq!(cmd.command, "save-all");
+        assert_eq!(cmd.count, 5);
+    }
+
+    #[tokio::test]
+    async fn test_response_is_not_coalesced() {
+        let queue = CommandQueue::new(CommandQueueConfig::default());
+        queue.open().await;
+        let first = queue.push_for_response("list", user()).await.unwrap();
+        let second = queue.push_for_response("list", user()).await.unwrap();
+        assert_eq!(queue.status().await.depth, 2);
+        let (writer, reader) = tokio::io::duplex(4096);
+        queue.spawn_writer(writer, |_| {});
+        // stdin carries no response, the caller reads the console instead
+        assert_eq!(first.await.unwrap(), None);
+        assert_eq!(second.await.unwrap(), None);
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "list");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "list");
+    }
+
+    #[tokio::test]
+    async fn test_closed_queue_rejects() {
+        let queue = CommandQueue::new(CommandQueueConfig::default());
+        assert!(queue.push("list", user()).await.is_err());
+        queue.open().await;
+        queue.push("list", user()).await.unwrap();
+        queue.close().await;
+        assert!(queue.pop().await.is_none());
+    }
+}
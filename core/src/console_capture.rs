@@ -0,0 +1,190 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard};
+use ts_rs::TS;
+
+use crate::events::CausedBy;
+
+pub const DEFAULT_CAPTURE_TIMEOUT: Duration = Duration::from_secs(2);
+pub const MAX_CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Output the server printed after a command was sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CommandOutput {
+    pub lines: Vec<String>,
+    /// True if a line known to answer the command was seen, false if capture ran until the timeout
+    pub complete: bool,
+}
+
+struct ActiveCapture {
+    caused_by: CausedBy,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+/// Routes console output to whoever sent the last command through [`ConsoleCapture::begin`]
+#[derive(Clone, Default)]
+pub struct ConsoleCapture {
+    /// Held for a whole capture so concurrent commands don't read each other's output
+    exclusive: Arc<Mutex<()>>,
+    active: Arc<std::sync::Mutex<Option<ActiveCapture>>>,
+}
+
+pub struct CaptureSession {
+    active: Arc<std::sync::Mutex<Option<ActiveCapture>>>,
+    receiver: mpsc::UnboundedReceiver<String>,
+    _exclusive: OwnedMutexGuard<()>,
+}
+
+impl ConsoleCapture {
+    /// Waits for any capture in progress to finish, then starts capturing on behalf of `caused_by`
+    pub async fn begin(&self, caused_by: CausedBy) -> CaptureSession {
+        let exclusive = self.exclusive.clone().lock_owned().await;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.active.lock().unwrap() = Some(ActiveCapture { caused_by, sender });
+        CaptureSession {
+            active: self.active.clone(),
+            receiver,
+            _exclusive: exclusive,
+        }
+    }
+
+    /// Hands a console line to the capture in progress, returning who the line is attributed to
+    pub fn offer(&self, line: &str) -> Option<CausedBy> {
+        self.active.lock().unwrap().as_ref().map(|capture| {
+            let _ = capture.sender.send(line.trim_end().to_string());
+            capture.caused_by.clone()
+        })
+    }
+}
+
+impl CaptureSession {
+    /// Collects lines until one answers `command` or `timeout` runs out
+    pub async fn collect(mut self, command: &str, timeout: Duration) -> CommandOutput {
+        let patterns = response_patterns(command);
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut lines = Vec::new();
+        while let Ok(Some(line)) = tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+            let answers = is_response(patterns, &line);
+            lines.push(line);
+            if answers {
+                return CommandOutput {
+                    lines,
+                    complete: true,
+                };
+            }
+        }
+        CommandOutput {
+            lines,
+            complete: false,
+        }
+    }
+}
+
+impl Drop for CaptureSession {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().take();
+    }
+}
+
+/// Printed in response to any command the server couldn't run
+const ERROR_PATTERNS: &[&str] = &[
+    "Unknown or incomplete command",
+    "Incorrect argument for command",
+    "<--[HERE]",
+];
+
+/// Fragments of the line a vanilla server prints to answer common commands
+fn response_patterns(command: &str) -> &'static [&'static str] {
+    let name = command
+        .trim_start_matches('/')
+        .split_whitespace()
+        .next()
+        .unwrap_or_default();
+    match name {
+        "list" => &["There are "],
+        "seed" => &["Seed: ["],
+        "data" => &[
+            "has the following",
+            "Found no elements",
+            "No entity was found",
+            "is not a block entity",
+            "Modified",
+        ],
+        "time" => &["The time is", "Set the time to"],
+        "difficulty" => &["The difficulty"],
+        "weather" => &["Set the weather to", "Changing to"],
+        "whitelist" => &[
+            "to the whitelist",
+            "from the whitelist",
+            "whitelisted player",
+            "Whitelist is",
+            "Nothing changed",
+        ],
+        "op" | "deop" => &["Made ", "Nothing changed"],
+        _ => &[],
+    }
+}
+
+fn is_response(patterns: &[&str], line: &str) -> bool {
+    patterns
+        .iter()
+        .chain(ERROR_PATTERNS)
+        .any(|pattern| line.contains(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_capture_stops_on_response() {
+        let capture = ConsoleCapture::default();
+        assert!(capture.offer("before").is_none());
+        let session = capture
+            .begin(CausedBy::User {
+                user_id: "uid".into(),
+                user_name: "admin".into(),
+            })
+            .await;
+        assert!(matches!(
+            capture.offer("[12:00:00] [Server thread/INFO]: Saving chunks\n"),
+            Some(CausedBy::User { .. })
+        ));
+        capture
+            .offer("[12:00:00] [Server thread/INFO]: There are 0 of a max of 20 players online: ");
+        capture.offer("after the response");
+        let output = session.collect("list", Duration::from_millis(500)).await;
+        assert!(output.complete);
+        assert_eq!(output.lines.len(), 2);
+        assert_eq!(
+            output.lines[0],
+            "[12:00:00] [Server thread/INFO]: Saving chunks"
+        );
+        // the session ending stops attribution
+        assert!(capture.offer("later").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_capture_times_out() {
+        let capture = ConsoleCapture::default();
+        let session = capture.begin(CausedBy::System).await;
+        capture.offer("something unrelated");
+        let output = session
+            .collect("custommodcommand", Duration::from_millis(100))
+            .await;
+        assert!(!output.complete);
+        assert_eq!(output.lines, vec!["something unrelated".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_is_a_response() {
+        let capture = ConsoleCapture::default();
+        let session = capture.begin(CausedBy::System).await;
+        capture.offer("Unknown or incomplete command, see below for error");
+        let output = session.collect("lsit", Duration::from_secs(5)).await;
+        assert!(output.complete);
+    }
+}
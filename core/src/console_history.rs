@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::types::Snowflake;
+
+pub const DEFAULT_CONSOLE_HISTORY_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ConsoleLine {
+    /// Monotonically increasing, never reused within a daemon run
+    pub seq: u64,
+    /// Snowflake of the `InstanceOutput` event carrying this line on the live stream
+    pub snowflake: Snowflake,
+    /// Unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ConsoleHistoryPage {
+    pub lines: Vec<ConsoleLine>,
+    /// Sequence number of the oldest line still in the buffer
+    pub first_seq: u64,
+    /// Sequence number the next line will get
+    pub next_seq: u64,
+}
+
+/// Bounded history of an instance's console output
+#[derive(Debug)]
+pub struct ConsoleHistory {
+    lines: VecDeque<ConsoleLine>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl ConsoleHistory {
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            capacity: capacity.max(1) as usize,
+            next_seq: 0,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: u32) {
+        self.capacity = capacity.max(1) as usize;
+        while self.lines.len() > self.capacity {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, line: String, snowflake: Snowflake) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(ConsoleLine {
+            seq,
+            snowflake,
+            timestamp: snowflake.timestamp_millis(),
+            line,
+        });
+        seq
+    }
+
+    fn first_seq(&self) -> u64 {
+        self.lines.front().map_or(self.next_seq, |l| l.seq)
+    }
+
+    /// Returns up to `count` lines starting at sequence number `offset`.
+    ///
+    /// Without an offset the most recent `count` lines are returned
+    pub fn page(&self, offset: Option<u64>, count: usize) -> ConsoleHistoryPage {
+        let first_seq = self.first_seq();
+        let start = match offset {
+            Some(offset) => offset.saturating_sub(first_seq) as usize,
+            None => self.lines.len().saturating_sub(count),
+        };
+        ConsoleHistoryPage {
+            lines: self.lines.iter().skip(start).take(count).cloned().collect(),
+            first_seq,
+            next_seq: self.next_seq,
+        }
+    }
+
+    /// Drops every line, sequence numbers keep increasing
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_history() {
+        let mut history = ConsoleHistory::new(3);
+        for i in 0..5 {
+            history.push(format!("line {i}"), Snowflake::default());
+        }
+        let page = history.page(None, 10);
+        assert_eq!(page.first_seq, 2);
+        assert_eq!(page.next_seq, 5);
+        assert_eq!(
+            page.lines.iter().map(|l| l.seq).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(page.lines[0].line, "line 2");
+
+        // offsets older than the buffer start at the oldest line
+        let page = history.page(Some(0), 2);
+        assert_eq!(
+            page.lines.iter().map(|l| l.seq).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        let page = history.page(Some(4), 10);
+        assert_eq!(page.lines.len(), 1);
+        assert!(history.page(Some(5), 10).lines.is_empty());
+        assert_eq!(history.page(None, 1).lines[0].seq, 4);
+
+        history.clear();
+        assert!(history.page(None, 10).lines.is_empty());
+        assert_eq!(history.push("after".to_string(), Snowflake::default()), 5);
+        assert_eq!(history.page(None, 10).first_seq, 5);
+    }
+}
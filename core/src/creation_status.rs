@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use ts_rs::TS;
+
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{
+    Event, EventInner, ProgressionEndValue, ProgressionEventInner, ProgressionStartValue,
+};
+use crate::traits::InstanceInfo;
+use crate::types::{InstanceUuid, Snowflake};
+
+/// How long a finished creation can still be polled, whether or not anyone is polling it
+const FINISHED_RETENTION: Duration = Duration::from_secs(5 * 60);
+/// Upper bound of a single long-poll
+pub const MAX_POLL_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum CreationState {
+    Pending {
+        phase: String,
+        progress: f64,
+        total: Option<f64>,
+    },
+    Succeeded {
+        instance: InstanceInfo,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+impl CreationState {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, CreationState::Pending { .. })
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct CreationStatus {
+    /// Bumped on every change, sent back as `If-None-Match` to wait for the next one
+    pub cursor: u64,
+    pub state: CreationState,
+}
+
+pub enum CreationPoll {
+    Changed(CreationStatus),
+    /// Nothing happened past the caller's cursor within the wait
+    Unchanged(u64),
+}
+
+#[derive(Default)]
+struct Inner {
+    creations: HashMap<InstanceUuid, watch::Sender<CreationStatus>>,
+    /// Progression event of each creation in flight
+    progressions: HashMap<Snowflake, InstanceUuid>,
+}
+
+/// Status of the instance creations in flight, fed by their progression events.
+///
+/// Each creation has one watch channel, long-polls wait on it and the event task writes to it
+#[derive(Clone)]
+pub struct CreationRegistry {
+    inner: Arc<Mutex<Inner>>,
+    retention: Duration,
+}
+
+impl CreationRegistry {
+    pub fn new() -> Self {
+        Self {
+            inner: Default::default(),
+            retention: FINISHED_RETENTION,
+        }
+    }
+
+    /// Makes the creation pollable before its setup task has sent anything
+    pub fn register(&self, instance_uuid: InstanceUuid) {
+        let (tx, _) = watch::channel(CreationStatus {
+            cursor: 0,
+            state: CreationState::Pending {
+                phase: "Queued".to_string(),
+                progress: 0.0,
+                total: None,
+            },
+        });
+        self.lock().creations.entry(instance_uuid).or_insert(tx);
+    }
+
+    /// `None` if the instance is not being created, or finished long enough ago to be forgotten
+    pub async fn poll(
+        &self,
+        instance_uuid: &InstanceUuid,
+        cursor: Option<u64>,
+        wait: Duration,
+    ) -> Option<CreationPoll> {
+        let mut rx = self.lock().creations.get(instance_uuid)?.subscribe();
+        let is_new = |status: &CreationStatus| cursor != Some(status.cursor);
+        if !is_new(&rx.borrow()) {
+            let _ = tokio::time::timeout(wait.min(MAX_POLL_WAIT), async {
+                // an error means the entry was cleaned up, the last value is still readable
+                while rx.changed().await.is_ok() {
+                    if is_new(&rx.borrow()) {
+                        return;
+                    }
+                }
+            })
+            .await;
+        }
+        let status = rx.borrow().clone();
+        Some(if is_new(&status) {
+            CreationPoll::Changed(status)
+        } else {
+            CreationPoll::Unchanged(status.cursor)
+        })
+    }
+
+    /// Follows the creation progressions until the event broadcaster closes.
+    ///
+    /// Subscribes right away so no event sent after this call is missed
+    pub fn run(self, event_broadcaster: &EventBroadcaster) -> impl Future<Output = ()> {
+        let mut rx = event_broadcaster.subscribe();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => self.apply(&event),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        }
+    }
+
+    fn apply(&self, event: &Event) {
+        let progression = match &event.event_inner {
+            EventInner::ProgressionEvent(progression) => progression,
+            _ => return,
+        };
+        let event_id = progression.event_id();
+        let inner = &mut *self.lock();
+        match progression.progression_event_inner() {
+            ProgressionEventInner::ProgressionStart {
+                progression_name,
+                total,
+                inner: Some(ProgressionStartValue::InstanceCreation { instance_uuid }),
+            } => {
+                inner.progressions.insert(event_id, instance_uuid.clone());
+                let state = CreationState::Pending {
+                    phase: progression_name.clone(),
+                    progress: 0.0,
+                    total: *total,
+                };
+                match inner.creations.get(instance_uuid) {
+                    Some(tx) => publish(tx, state),
+                    None => {
+                        let (tx, _) = watch::channel(CreationStatus { cursor: 0, state });
+                        inner.creations.insert(instance_uuid.clone(), tx);
+                    }
+                }
+            }
+            ProgressionEventInner::ProgressionUpdate {
+                progress_message,
+                progress: delta,
+            } => {
+                let tx = match inner
+                    .progressions
+                    .get(&event_id)
+                    .and_then(|uuid| inner.creations.get(uuid))
+                {
+                    Some(tx) => tx,
+                    None => return,
+                };
+                tx.send_modify(|status| {
+                    if let CreationState::Pending {
+                        phase, progress, ..
+                    } = &mut status.state
+                    {
+                        *phase = progress_message.clone();
+                        *progress += delta;
+                        status.cursor += 1;
+                    }
+                });
+            }
+            ProgressionEventInner::ProgressionEnd {
+                success,
+                message,
+                inner: end_value,
+            } => {
+                let instance_uuid = match inner.progressions.remove(&event_id) {
+                    Some(uuid) => uuid,
+                    None => return,
+                };
+                let state = match (success, end_value) {
+                    (true, Some(ProgressionEndValue::InstanceCreation(info))) => {
+                        CreationState::Succeeded {
+                            instance: info.clone(),
+                        }
+                    }
+                    _ => CreationState::Failed {
+                        message: message
+                            .clone()
+                            .unwrap_or_else(|| "Instance creation failed".to_string()),
+                    },
+                };
+                if let Some(tx) = inner.creations.get(&instance_uuid) {
+                    publish(tx, state);
+                }
+                let registry = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(registry.retention).await;
+                    registry.lock().creations.remove(&instance_uuid);
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for CreationRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn publish(tx: &watch::Sender<CreationStatus>, state: CreationState) {
+    tx.send_modify(|status| {
+        status.cursor += 1;
+        status.state = state;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{CausedBy, ProgressionStartBuilder};
+    use crate::traits::t_configurable::{Game, MinecraftVariant};
+    use crate::traits::t_server::State;
+
+    fn instance_info(uuid: &InstanceUuid) -> InstanceInfo {
+        InstanceInfo {
+            uuid: uuid.clone(),
+            name: "test".to_string(),
+            game_type: Game::MinecraftJava {
+                variant: MinecraftVariant::Vanilla,
+            },
+            description: "".to_string(),
+            version: "1.20.1".to_string(),
+            port: 25565,
+            creation_time: 0,
+            path: "test".to_string(),
+            auto_start: false,
+            restart_on_crash: false,
+            state: State::Stopped,
+            player_count: None,
+            max_player_count: None,
+            player_list: None,
+            launch_command: None,
+            eula_accepted: None,
+            slug: "test".to_string(),
+            last_started: None,
+            last_started_at: None,
+            last_stopped_at: None,
+            uptime_seconds: None,
+            last_exit_code: None,
+            last_exit_reason: None,
+            tags: Vec::new(),
+            maintenance: None,
+            degraded: false,
+        }
+    }
+
+    /// A slow creation sending its progression the way the setup task does
+    fn spawn_creation(
+        event_broadcaster: EventBroadcaster,
+        uuid: InstanceUuid,
+        succeed: bool,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let (start, event_id) = ProgressionStartBuilder::new(
+                "Setting up Minecraft server test",
+                ProgressionStartValue::InstanceCreation {
+                    instance_uuid: uuid.clone(),
+                },
+                CausedBy::System,
+            )
+            .total(3.0)
+            .build();
+            event_broadcaster.send(start);
+            for step in ["Downloading server jar", "Downloading Java runtime"] {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                event_broadcaster.send(Event::new_progression_event_update(&event_id, step, 1.0));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            event_broadcaster.send(if succeed {
+                Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some("Instance created"),
+                    Some(ProgressionEndValue::InstanceCreation(instance_info(&uuid))),
+                )
+            } else {
+                Event::new_progression_event_end(event_id, false, Some("Disk full"), None)
+            });
+        })
+    }
+
+    /// Long-polls like a client would until the creation finishes, returning every state seen
+    async fn poll_to_end(registry: &CreationRegistry, uuid: &InstanceUuid) -> Vec<CreationState> {
+        let mut cursor = None;
+        let mut seen = Vec::new();
+        loop {
+            match registry
+                .poll(uuid, cursor, Duration::from_secs(5))
+                .await
+                .unwrap()
+            {
+                CreationPoll::Changed(status) => {
+                    cursor = Some(status.cursor);
+                    let finished = status.state.is_finished();
+                    seen.push(status.state);
+                    if finished {
+                        return seen;
+                    }
+                }
+                CreationPoll::Unchanged(_) => panic!("long-poll timed out during a creation"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poll_follows_creation_to_success() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let registry = CreationRegistry::new();
+        tokio::spawn(registry.clone().run(&event_broadcaster));
+        let uuid = InstanceUuid::default();
+        registry.register(uuid.clone());
+        let creation = spawn_creation(event_broadcaster.clone(), uuid.clone(), true);
+
+        let seen = poll_to_end(&registry, &uuid).await;
+        creation.await.unwrap();
+        assert!(seen.len() >= 2);
+        assert!(matches!(
+            seen.last(),
+            Some(CreationState::Succeeded { instance }) if instance.uuid == uuid
+        ));
+
+        // nothing changes after the end, a caller at the final cursor gets told so
+        let cursor = match registry.poll(&uuid, None, Duration::ZERO).await.unwrap() {
+            CreationPoll::Changed(status) => status.cursor,
+            CreationPoll::Unchanged(_) => unreachable!(),
+        };
+        assert!(matches!(
+            registry
+                .poll(&uuid, Some(cursor), Duration::from_millis(20))
+                .await,
+            Some(CreationPoll::Unchanged(c)) if c == cursor
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_poll_reports_failure() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let registry = CreationRegistry::new();
+        tokio::spawn(registry.clone().run(&event_broadcaster));
+        let uuid = InstanceUuid::default();
+        registry.register(uuid.clone());
+        spawn_creation(event_broadcaster.clone(), uuid.clone(), false);
+
+        let seen = poll_to_end(&registry, &uuid).await;
+        assert_eq!(
+            seen.last(),
+            Some(&CreationState::Failed {
+                message: "Disk full".to_string()
+            })
+        );
+        assert!(registry
+            .poll(&InstanceUuid::default(), None, Duration::ZERO)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_finished_creation_is_cleaned_up_without_pollers() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let registry = CreationRegistry {
+            retention: Duration::from_millis(200),
+            ..CreationRegistry::new()
+        };
+        tokio::spawn(registry.clone().run(&event_broadcaster));
+        let uuid = InstanceUuid::default();
+        spawn_creation(event_broadcaster.clone(), uuid.clone(), true)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(registry.poll(&uuid, None, Duration::ZERO).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(registry.poll(&uuid, None, Duration::ZERO).await.is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use fs3::FileExt;
+
+use crate::error::{Error, ErrorKind};
+
+/// Held for the daemon's lifetime inside its data directory, each daemon on a host needs its own
+pub const LOCK_FILE_NAME: &str = "lodestone.lock";
+pub const DEFAULT_HTTP_PORT: u16 = 16_662;
+/// How many ports after the default are tried before giving up
+const HTTP_PORT_SEARCH_RANGE: u16 = 100;
+
+/// Takes the advisory lock on a data directory and records our PID in it.
+///
+/// The lock is released when the returned file is dropped or the process exits
+pub fn lock_data_dir(lodestone_path: &Path) -> Result<File, Error> {
+    let path = lodestone_path.join(LOCK_FILE_NAME);
+    // not truncated before locking, the content is the PID of whoever holds it
+    let mut lock_file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&path)
+        .context(format!("Failed to open lock file {}", path.display()))?;
+    if lock_file.try_lock_exclusive().is_err() {
+        let mut owner = String::new();
+        let _ = lock_file.read_to_string(&mut owner);
+        let owner = match owner.trim() {
+            "" => "another process".to_string(),
+            pid => format!("process {}", pid),
+        };
+        return Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "{} is already in use by another lodestone daemon ({}), give each daemon its own data directory",
+                lodestone_path.display(),
+                owner
+            ),
+        });
+    }
+    lock_file
+        .set_len(0)
+        .and_then(|_| lock_file.rewind())
+        .and_then(|_| write!(lock_file, "{}", std::process::id()))
+        .and_then(|_| lock_file.flush())
+        .context("Failed to write lock file")?;
+    Ok(lock_file)
+}
+
+/// Picks the HTTP port, `requested` must be free while the default moves on to the next free
+/// port so several daemons can share a host without configuration
+pub fn pick_http_port(requested: Option<u16>) -> Result<u16, Error> {
+    if let Some(port) = requested {
+        return if port_scanner::local_port_available(port) {
+            Ok(port)
+        } else {
+            Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!("Port {} is already in use", port),
+            })
+        };
+    }
+    (DEFAULT_HTTP_PORT..DEFAULT_HTTP_PORT.saturating_add(HTTP_PORT_SEARCH_RANGE))
+        .find(|port| port_scanner::local_port_available(*port))
+        .ok_or_else(|| Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "No free port between {} and {}",
+                DEFAULT_HTTP_PORT,
+                DEFAULT_HTTP_PORT + HTTP_PORT_SEARCH_RANGE - 1
+            ),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_dir_lock_is_exclusive() {
+        let first_dir = tempfile::tempdir().unwrap();
+        let second_dir = tempfile::tempdir().unwrap();
+
+        let first = lock_data_dir(first_dir.path()).unwrap();
+        let err = lock_data_dir(first_dir.path()).unwrap_err();
+        assert!(err
+            .source
+            .to_string()
+            .contains(&std::process::id().to_string()));
+        // a daemon on a separate data directory is unaffected
+        let _second = lock_data_dir(second_dir.path()).unwrap();
+
+        drop(first);
+        lock_data_dir(first_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_pick_http_port_skips_taken_ports() {
+        let first = pick_http_port(None).unwrap();
+        let _listener = std::net::TcpListener::bind(("0.0.0.0", first)).unwrap();
+        let second = pick_http_port(None).unwrap();
+        assert_ne!(first, second);
+        assert!(pick_http_port(Some(first)).is_err());
+    }
+}
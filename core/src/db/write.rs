@@ -7,13 +7,19 @@ use crate::{
 use color_eyre::eyre::Context;
 use sqlx::sqlite::SqlitePool;
 use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, warn};
 
 use super::types::ClientEventRow;
 
 // TODO clean up all unwraps
 
-pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_pool: SqlitePool) {
+/// Writes events until `flush` is cancelled, the events sent before that are written first
+pub async fn write_event_to_db_task(
+    mut event_receiver: Receiver<Event>,
+    sqlite_pool: SqlitePool,
+    flush: CancellationToken,
+) {
     let init_result = init_client_events_table(&sqlite_pool).await;
     if let Err(error) = init_result.as_ref() {
         warn!("Failed to initialize client events table: {}", error);
@@ -21,7 +27,12 @@ pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_
     }
 
     loop {
-        let result = event_receiver.recv().await;
+        let result = tokio::select! {
+            biased;
+            result = event_receiver.recv() => result,
+            // only reached once no event is waiting
+            _ = flush.cancelled() => break,
+        };
         if let Err(error) = result.as_ref() {
             match error {
                 RecvError::Lagged(_) => {
@@ -35,7 +46,11 @@ pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_
             }
         }
 
-        let client_event: ClientEvent = result.unwrap().into();
+        let event = result.unwrap();
+        if event.is_event_file_change() {
+            continue;
+        }
+        let client_event: ClientEvent = event.into();
         if let EventInner::ProgressionEvent(pe) = &client_event.event_inner {
             if let ProgressionEventInner::ProgressionUpdate { .. } = pe.progression_event_inner() {
                 continue;
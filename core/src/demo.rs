@@ -0,0 +1,171 @@
+use tracing::info;
+
+use crate::auth::permission::UserPermission;
+use crate::auth::user::{User, UsersManager};
+use crate::error::Error;
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, ProgressionStartBuilder, ProgressionStartValue};
+use crate::types::InstanceUuid;
+
+/// Password of every seeded user, demo data is throwaway so it is printed on startup
+pub const DEMO_PASSWORD: &str = "lodestone-demo";
+/// Username of the seeded owner
+pub const DEMO_OWNER: &str = "demo";
+
+const USERNAMES: [&str; 8] = [
+    "alex", "steve", "kai", "noor", "sam", "robin", "jules", "ari",
+];
+const SERVER_NAMES: [&str; 6] = [
+    "Survival",
+    "Creative Build",
+    "Skyblock",
+    "Modded Adventure",
+    "Minigames",
+    "Friends SMP",
+];
+const SETUP_STEPS: [&str; 4] = [
+    "Downloading server jar",
+    "Downloading Java runtime",
+    "Writing server.properties",
+    "Accepting EULA",
+];
+
+/// splitmix64, kept here so the generated data does not change with the rand crate version
+struct DemoRng(u64);
+
+impl DemoRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DemoRole {
+    Owner,
+    Admin,
+    /// May create instances and browse global files
+    Operator,
+    Viewer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemoUser {
+    pub username: String,
+    pub role: DemoRole,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemoSetup {
+    pub instance_name: String,
+    /// Steps already done out of the 10 a Minecraft setup reports
+    pub completed_steps: u32,
+}
+
+/// Everything demo mode seeds, derived only from the seed so screenshots are reproducible
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemoData {
+    pub users: Vec<DemoUser>,
+    /// An instance creation left mid-way
+    pub setup: DemoSetup,
+}
+
+impl DemoData {
+    pub fn generate(seed: u64) -> Self {
+        let mut rng = DemoRng(seed);
+        let mut usernames = USERNAMES.to_vec();
+        let mut users = vec![DemoUser {
+            username: DEMO_OWNER.to_string(),
+            role: DemoRole::Owner,
+        }];
+        for role in [
+            DemoRole::Admin,
+            DemoRole::Operator,
+            DemoRole::Operator,
+            DemoRole::Viewer,
+        ] {
+            let username = usernames.remove(rng.below(usernames.len()));
+            users.push(DemoUser {
+                username: username.to_string(),
+                role,
+            });
+        }
+        let setup = DemoSetup {
+            instance_name: SERVER_NAMES[rng.below(SERVER_NAMES.len())].to_string(),
+            completed_steps: 1 + rng.below(SETUP_STEPS.len() - 1) as u32,
+        };
+        DemoData { users, setup }
+    }
+
+    /// Adds the users, must run before the first time setup key is decided
+    pub async fn seed_users(&self, users_manager: &mut UsersManager) -> Result<(), Error> {
+        for user in &self.users {
+            let mut permissions = UserPermission::new();
+            if user.role == DemoRole::Operator {
+                permissions.can_create_instance = true;
+                permissions.can_read_global_file = true;
+            }
+            users_manager
+                .add_user(
+                    User::new(
+                        user.username.clone(),
+                        DEMO_PASSWORD,
+                        user.role == DemoRole::Owner,
+                        user.role == DemoRole::Admin,
+                        permissions,
+                    ),
+                    CausedBy::System,
+                )
+                .await?;
+        }
+        info!(
+            "Demo mode: log in as \"{}\" with password \"{}\"",
+            DEMO_OWNER, DEMO_PASSWORD
+        );
+        Ok(())
+    }
+
+    /// Emits the event history, once the event buffer and database writer are listening
+    pub fn seed_events(&self, event_broadcaster: &EventBroadcaster) {
+        let (start, event_id) = ProgressionStartBuilder::new(
+            format!("Setting up Minecraft server {}", self.setup.instance_name),
+            ProgressionStartValue::InstanceCreation {
+                instance_uuid: InstanceUuid::default(),
+            },
+            CausedBy::System,
+        )
+        .total(10.0)
+        .build();
+        event_broadcaster.send(start);
+        for step in SETUP_STEPS.iter().take(self.setup.completed_steps as usize) {
+            event_broadcaster.send(Event::new_progression_event_update(&event_id, step, 1.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demo_data_is_deterministic() {
+        assert_eq!(DemoData::generate(42), DemoData::generate(42));
+        let seeds: Vec<DemoData> = (0..16).map(DemoData::generate).collect();
+        assert!(seeds.iter().any(|data| *data != seeds[0]));
+        for data in seeds {
+            assert_eq!(data.users[0].role, DemoRole::Owner);
+            let mut usernames: Vec<&str> = data.users.iter().map(|u| u.username.as_str()).collect();
+            usernames.sort();
+            usernames.dedup();
+            assert_eq!(usernames.len(), data.users.len());
+            assert!((data.setup.completed_steps as usize) < SETUP_STEPS.len());
+        }
+    }
+}
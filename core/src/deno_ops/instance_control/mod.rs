@@ -40,9 +40,12 @@ async fn start_instance(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
-    instance
+    app_state()
+        .admission
         .start(
+            &instance,
             CausedBy::Macro {
                 macro_pid: task_pid,
             },
@@ -61,6 +64,7 @@ async fn stop_instance(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     instance
         .stop(
@@ -82,6 +86,7 @@ async fn restart_instance(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     instance
         .restart(
@@ -102,6 +107,7 @@ async fn kill_instance(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     instance
         .kill(CausedBy::Macro {
@@ -116,6 +122,7 @@ async fn get_instance_state(instance_uuid: InstanceUuid) -> Result<State, anyhow
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
 
     Ok(instance.state().await)
@@ -130,6 +137,7 @@ async fn send_command(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     instance
         .send_command(
@@ -147,6 +155,7 @@ async fn monitor_instance(instance_uuid: InstanceUuid) -> Result<MonitorReport,
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.monitor().await)
 }
@@ -156,6 +165,7 @@ async fn get_instance_player_count(instance_uuid: InstanceUuid) -> Result<u32, a
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.get_player_count().await?)
 }
@@ -165,6 +175,7 @@ async fn get_instance_max_players(instance_uuid: InstanceUuid) -> Result<u32, an
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.get_max_player_count().await?)
 }
@@ -176,6 +187,7 @@ async fn get_instance_player_list(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.get_player_list().await?)
 }
@@ -185,6 +197,7 @@ async fn get_instance_name(instance_uuid: InstanceUuid) -> Result<String, anyhow
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.name().await)
 }
@@ -194,6 +207,7 @@ async fn get_instance_game(instance_uuid: InstanceUuid) -> Result<Game, anyhow::
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.game_type().await)
 }
@@ -203,6 +217,7 @@ async fn get_instance_game_version(instance_uuid: InstanceUuid) -> Result<String
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.version().await)
 }
@@ -212,6 +227,7 @@ async fn get_instance_description(instance_uuid: InstanceUuid) -> Result<String,
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.description().await)
 }
@@ -221,6 +237,7 @@ async fn get_instance_port(instance_uuid: InstanceUuid) -> Result<u32, anyhow::E
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.port().await)
 }
@@ -230,6 +247,7 @@ async fn get_instance_path(instance_uuid: InstanceUuid) -> Result<String, anyhow
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     Ok(instance.path().await.to_string_lossy().to_string())
 }
@@ -239,6 +257,7 @@ async fn set_instance_name(instance_uuid: InstanceUuid, name: String) -> Result<
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
 
     instance
@@ -255,6 +274,7 @@ async fn set_instance_description(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
 
     instance
@@ -268,6 +288,7 @@ async fn set_instance_port(instance_uuid: InstanceUuid, port: u32) -> Result<(),
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
 
     instance
@@ -284,6 +305,7 @@ async fn set_instance_auto_start(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
 
     instance
@@ -297,6 +319,7 @@ async fn is_rcon_available(instance_uuid: InstanceUuid) -> Result<bool, anyhow::
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     match instance.value() {
         crate::prelude::GameInstance::MinecraftInstance(v) => {
@@ -305,6 +328,9 @@ async fn is_rcon_available(instance_uuid: InstanceUuid) -> Result<bool, anyhow::
         crate::prelude::GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
+        crate::prelude::GameInstance::CommandInstance(_) => {
+            bail!("RCON not available for command instances")
+        }
     }
 }
 
@@ -316,12 +342,16 @@ async fn try_send_rcon_command(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     match instance.value() {
         crate::prelude::GameInstance::MinecraftInstance(v) => Ok(v.send_rcon(&command).await.ok()),
         crate::prelude::GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
+        crate::prelude::GameInstance::CommandInstance(_) => {
+            bail!("RCON not available for command instances")
+        }
     }
 }
 
@@ -333,6 +363,7 @@ async fn send_rcon_command(
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     match instance.value() {
         crate::prelude::GameInstance::MinecraftInstance(v) => {
@@ -347,6 +378,9 @@ async fn send_rcon_command(
         crate::prelude::GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
+        crate::prelude::GameInstance::CommandInstance(_) => {
+            bail!("RCON not available for command instances")
+        }
     }
 }
 
@@ -355,6 +389,7 @@ async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), any
     let instance = app_state()
         .instances
         .get(&instance_uuid)
+        .map(|entry| entry.value().clone())
         .ok_or(anyhow::anyhow!("Instance not found"))?;
     match instance.value() {
         crate::prelude::GameInstance::MinecraftInstance(v) => {
@@ -369,6 +404,9 @@ async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), any
         crate::prelude::GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
         }
+        crate::prelude::GameInstance::CommandInstance(_) => {
+            bail!("RCON not available for command instances")
+        }
     }
 }
 
@@ -0,0 +1,699 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use serde_json::json;
+use ts_rs::TS;
+
+use crate::disk_usage::volume_space;
+use crate::error::{Error, ErrorCode};
+use crate::types::InstanceUuid;
+use crate::util::{dont_spawn_terminal, format_byte};
+
+/// Each check is reported as timed out past this, so the suite never hangs
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Level name Minecraft uses when server.properties does not set one
+const DEFAULT_LEVEL_NAME: &str = "world";
+/// Less than this left on the instance's volume and the server may fail to save its world
+pub const MIN_FREE_DISK_SPACE: u64 = 512 * 1024 * 1024;
+
+/// What the pre-flight checks need to know about an instance
+#[derive(Debug, Clone)]
+pub struct PreflightTarget {
+    pub path: PathBuf,
+    pub port: u32,
+    pub java: PathBuf,
+    /// `None` when the server is not launched from a single jar, e.g. modern Forge
+    pub server_jar: Option<PathBuf>,
+    pub max_ram_mb: u32,
+}
+
+/// Host state the checks compare the instance against
+#[derive(Debug, Clone, Copy)]
+pub struct DiagnosticsContext {
+    pub available_memory_mb: u64,
+    /// A running instance holds its own port, so the port probe is skipped
+    pub instance_running: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticCheck {
+    Port,
+    Java,
+    ServerJar,
+    Eula,
+    Memory,
+    World,
+    Disk,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingId {
+    PortInUse,
+    JavaMissing,
+    JavaBroken,
+    ServerJarMissing,
+    ServerJarCorrupted,
+    EulaNotAccepted,
+    MemoryOverBudget,
+    WorldMissingLevelDat,
+    DiskSpaceLow,
+    CheckTimedOut,
+}
+
+impl FindingId {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FindingId::PortInUse => "port_in_use",
+            FindingId::JavaMissing => "java_missing",
+            FindingId::JavaBroken => "java_broken",
+            FindingId::ServerJarMissing => "server_jar_missing",
+            FindingId::ServerJarCorrupted => "server_jar_corrupted",
+            FindingId::EulaNotAccepted => "eula_not_accepted",
+            FindingId::MemoryOverBudget => "memory_over_budget",
+            FindingId::WorldMissingLevelDat => "world_missing_level_dat",
+            FindingId::DiskSpaceLow => "disk_space_low",
+            FindingId::CheckTimedOut => "check_timed_out",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+pub enum Severity {
+    /// The server will not start, or not correctly
+    Error,
+    Warning,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum RemediationAction {
+    AcceptEula,
+    ReassignPort,
+    SetJavaCmd,
+    LowerMaxRam,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+pub enum Automation {
+    /// Applied by every fix request
+    Safe,
+    /// Applied only when the fix request opts in, e.g. moving the server to another port
+    OptIn,
+    Manual,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct Remediation {
+    pub action: RemediationAction,
+    /// Method and path of the endpoint that applies it, e.g. `PUT /instance/{uuid}/eula`
+    pub endpoint: String,
+    #[ts(type = "unknown")]
+    pub body_template: serde_json::Value,
+    pub automation: Automation,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct Finding {
+    pub id: FindingId,
+    pub check: DiagnosticCheck,
+    pub severity: Severity,
+    pub message: String,
+    pub remediation: Option<Remediation>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct DiagnosticsReport {
+    pub findings: Vec<Finding>,
+}
+
+impl DiagnosticsReport {
+    pub fn has(&self, id: FindingId) -> bool {
+        self.findings.iter().any(|finding| finding.id == id)
+    }
+
+    /// Ids of the findings that stop the server from starting
+    pub fn blocking_ids(&self) -> Vec<FindingId> {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .map(|finding| finding.id)
+            .collect()
+    }
+
+    /// The error a start fails fast with, coded after the first finding that blocks it
+    pub fn start_error(&self) -> Option<Error> {
+        let finding = self
+            .findings
+            .iter()
+            .find(|finding| finding.severity == Severity::Error)?;
+        let code = match finding.id {
+            FindingId::PortInUse => ErrorCode::PortInUse,
+            FindingId::JavaMissing | FindingId::JavaBroken => ErrorCode::JavaUnavailable,
+            FindingId::ServerJarMissing | FindingId::ServerJarCorrupted => {
+                ErrorCode::ServerJarInvalid
+            }
+            FindingId::DiskSpaceLow => ErrorCode::InsufficientStorage,
+            _ => ErrorCode::BadRequest,
+        };
+        Some(
+            Error::coded(code, &finding.message)
+                .with_details(json!({ "findings": self.blocking_ids() })),
+        )
+    }
+}
+
+/// Attached to a failed start so the error body can name the findings behind it
+#[derive(Debug, Clone)]
+pub struct StartDiagnosis(pub Vec<FindingId>);
+
+impl std::fmt::Display for StartDiagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Diagnostics found: {}",
+            self.0
+                .iter()
+                .map(FindingId::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+fn setting_endpoint(uuid: &InstanceUuid, section_id: &str, setting_id: &str) -> String {
+    format!("PUT /instance/{uuid}/settings/{section_id}/{setting_id}")
+}
+
+async fn bounded(
+    check: DiagnosticCheck,
+    timeout: Duration,
+    run: impl Future<Output = Vec<Finding>>,
+) -> Vec<Finding> {
+    tokio::time::timeout(timeout, run)
+        .await
+        .unwrap_or_else(|_| {
+            vec![Finding {
+                id: FindingId::CheckTimedOut,
+                check,
+                severity: Severity::Warning,
+                message: format!("The {:?} check did not finish in {:?}", check, timeout),
+                remediation: None,
+            }]
+        })
+}
+
+/// Runs every check at once, each bounded by its own timeout
+pub async fn run_diagnostics(
+    uuid: &InstanceUuid,
+    target: &PreflightTarget,
+    context: DiagnosticsContext,
+) -> DiagnosticsReport {
+    let (port, java, server_jar, eula, memory, world, disk) = tokio::join!(
+        bounded(
+            DiagnosticCheck::Port,
+            CHECK_TIMEOUT,
+            check_port(uuid, target, context)
+        ),
+        bounded(
+            DiagnosticCheck::Java,
+            CHECK_TIMEOUT,
+            check_java(uuid, target)
+        ),
+        bounded(
+            DiagnosticCheck::ServerJar,
+            CHECK_TIMEOUT,
+            check_server_jar(target)
+        ),
+        bounded(
+            DiagnosticCheck::Eula,
+            CHECK_TIMEOUT,
+            check_eula(uuid, target)
+        ),
+        bounded(
+            DiagnosticCheck::Memory,
+            CHECK_TIMEOUT,
+            check_memory(uuid, target, context)
+        ),
+        bounded(DiagnosticCheck::World, CHECK_TIMEOUT, check_world(target)),
+        bounded(DiagnosticCheck::Disk, CHECK_TIMEOUT, check_disk(target)),
+    );
+    DiagnosticsReport {
+        findings: [port, java, server_jar, eula, memory, world, disk].concat(),
+    }
+}
+
+/// The checks a start can't get past, run before the server is spawned.
+///
+/// Leaves out the EULA, which the server reports itself, and the memory and world checks,
+/// which only warn or need the user to decide. A running server's own port isn't reported
+pub async fn run_preflight(
+    uuid: &InstanceUuid,
+    target: &PreflightTarget,
+    instance_running: bool,
+) -> DiagnosticsReport {
+    let context = DiagnosticsContext {
+        available_memory_mb: 0,
+        instance_running,
+    };
+    let (port, java, server_jar, disk) = tokio::join!(
+        bounded(
+            DiagnosticCheck::Port,
+            CHECK_TIMEOUT,
+            check_port(uuid, target, context)
+        ),
+        bounded(
+            DiagnosticCheck::Java,
+            CHECK_TIMEOUT,
+            check_java(uuid, target)
+        ),
+        bounded(
+            DiagnosticCheck::ServerJar,
+            CHECK_TIMEOUT,
+            check_server_jar(target)
+        ),
+        bounded(DiagnosticCheck::Disk, CHECK_TIMEOUT, check_disk(target)),
+    );
+    DiagnosticsReport {
+        findings: [port, java, server_jar, disk].concat(),
+    }
+}
+
+/// Whether the server could listen on `port`, on every interface like it does
+fn port_bindable(port: u32) -> bool {
+    u16::try_from(port).ok().map_or(false, |port| {
+        std::net::TcpListener::bind(("0.0.0.0", port)).is_ok()
+    })
+}
+
+async fn check_port(
+    uuid: &InstanceUuid,
+    target: &PreflightTarget,
+    context: DiagnosticsContext,
+) -> Vec<Finding> {
+    if context.instance_running || port_bindable(target.port) {
+        return Vec::new();
+    }
+    let suggested_port = (target.port + 1..=u16::MAX as u32).find(|port| port_bindable(*port));
+    vec![Finding {
+        id: FindingId::PortInUse,
+        check: DiagnosticCheck::Port,
+        severity: Severity::Error,
+        message: format!("Port {} is already in use by another program", target.port),
+        remediation: Some(Remediation {
+            action: RemediationAction::ReassignPort,
+            endpoint: setting_endpoint(uuid, "server_properties_section", "server-port"),
+            body_template: json!({ "type": "UnsignedInteger", "value": suggested_port }),
+            automation: Automation::OptIn,
+        }),
+    }]
+}
+
+async fn check_java(uuid: &InstanceUuid, target: &PreflightTarget) -> Vec<Finding> {
+    let remediation = Some(Remediation {
+        action: RemediationAction::SetJavaCmd,
+        endpoint: setting_endpoint(uuid, "cmd_args_section", "java_cmd"),
+        body_template: json!({ "type": "String", "value": "/path/to/java" }),
+        automation: Automation::Manual,
+    });
+    let mut command = tokio::process::Command::new(&target.java);
+    let output = dont_spawn_terminal(&mut command)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+    let (id, message) = match output {
+        Ok(output) if output.status.success() => return Vec::new(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+            FindingId::JavaMissing,
+            format!("Java was not found at {}", target.java.display()),
+        ),
+        Err(e) => (
+            FindingId::JavaBroken,
+            format!("Java at {} could not run: {}", target.java.display(), e),
+        ),
+        Ok(output) => (
+            FindingId::JavaBroken,
+            format!(
+                "Java at {} exited with {} when asked for its version",
+                target.java.display(),
+                output.status
+            ),
+        ),
+    };
+    vec![Finding {
+        id,
+        check: DiagnosticCheck::Java,
+        severity: Severity::Error,
+        message,
+        remediation,
+    }]
+}
+
+async fn check_server_jar(target: &PreflightTarget) -> Vec<Finding> {
+    let jar = match &target.server_jar {
+        Some(jar) => jar.clone(),
+        None => return Vec::new(),
+    };
+    let finding = |id, message| {
+        vec![Finding {
+            id,
+            check: DiagnosticCheck::ServerJar,
+            severity: Severity::Error,
+            message,
+            remediation: None,
+        }]
+    };
+    if !jar.is_file() {
+        return finding(
+            FindingId::ServerJarMissing,
+            format!("{} is missing", jar.display()),
+        );
+    }
+    // reading the central directory catches truncated downloads
+    let readable = tokio::task::spawn_blocking({
+        let jar = jar.clone();
+        move || {
+            std::fs::File::open(jar)
+                .ok()
+                .and_then(|file| zip::ZipArchive::new(file).ok())
+                .is_some()
+        }
+    })
+    .await
+    .unwrap_or(false);
+    if readable {
+        Vec::new()
+    } else {
+        finding(
+            FindingId::ServerJarCorrupted,
+            format!(
+                "{} is not a valid jar, it may be a partial download",
+                jar.display()
+            ),
+        )
+    }
+}
+
+async fn check_disk(target: &PreflightTarget) -> Vec<Finding> {
+    let path = target.path.clone();
+    let free = tokio::task::spawn_blocking(move || {
+        volume_space(&mut sysinfo::System::new(), &path).map(|space| space.free)
+    })
+    .await
+    .ok()
+    .flatten();
+    match free {
+        Some(free) if free < MIN_FREE_DISK_SPACE => vec![Finding {
+            id: FindingId::DiskSpaceLow,
+            check: DiagnosticCheck::Disk,
+            severity: Severity::Error,
+            message: format!(
+                "Only {} is free on the instance's disk, at least {} is needed",
+                format_byte(free),
+                format_byte(MIN_FREE_DISK_SPACE)
+            ),
+            remediation: None,
+        }],
+        // an unknown volume isn't reported
+        _ => Vec::new(),
+    }
+}
+
+fn is_eula_accepted(content: &str) -> bool {
+    content
+        .lines()
+        .any(|line| line.trim().eq_ignore_ascii_case("eula=true"))
+}
+
+/// Accepts the Minecraft EULA for the instance at `path`
+pub async fn accept_eula(path: &Path) -> Result<(), Error> {
+    tokio::fs::write(
+        path.join("eula.txt"),
+        "#accepted through Lodestone\neula=true\n",
+    )
+    .await
+    .context("Failed to write eula.txt")?;
+    Ok(())
+}
+
+/// Whether `eula.txt` of the instance at `path` accepts the EULA, a missing file doesn't
+pub async fn eula_accepted(path: &Path) -> bool {
+    tokio::fs::read_to_string(path.join("eula.txt"))
+        .await
+        .map(|content| is_eula_accepted(&content))
+        .unwrap_or(false)
+}
+
+async fn check_eula(uuid: &InstanceUuid, target: &PreflightTarget) -> Vec<Finding> {
+    if eula_accepted(&target.path).await {
+        return Vec::new();
+    }
+    vec![Finding {
+        id: FindingId::EulaNotAccepted,
+        check: DiagnosticCheck::Eula,
+        severity: Severity::Error,
+        message: "The Minecraft EULA has not been accepted, the server will stop right away"
+            .to_string(),
+        remediation: Some(Remediation {
+            action: RemediationAction::AcceptEula,
+            endpoint: format!("PUT /instance/{uuid}/eula"),
+            body_template: serde_json::Value::Null,
+            automation: Automation::Safe,
+        }),
+    }]
+}
+
+async fn check_memory(
+    uuid: &InstanceUuid,
+    target: &PreflightTarget,
+    context: DiagnosticsContext,
+) -> Vec<Finding> {
+    if target.max_ram_mb as u64 <= context.available_memory_mb {
+        return Vec::new();
+    }
+    vec![Finding {
+        id: FindingId::MemoryOverBudget,
+        check: DiagnosticCheck::Memory,
+        severity: Severity::Warning,
+        message: format!(
+            "The server may use up to {} MB but only {} MB are available",
+            target.max_ram_mb, context.available_memory_mb
+        ),
+        remediation: Some(Remediation {
+            action: RemediationAction::LowerMaxRam,
+            endpoint: setting_endpoint(uuid, "cmd_args_section", "max_ram"),
+            body_template: json!({
+                "type": "UnsignedInteger",
+                "value": context.available_memory_mb * 3 / 4,
+            }),
+            automation: Automation::Manual,
+        }),
+    }]
+}
+
+async fn level_name(path: &Path) -> String {
+    tokio::fs::read_to_string(path.join("server.properties"))
+        .await
+        .ok()
+        .and_then(|properties| {
+            properties.lines().find_map(|line| {
+                line.strip_prefix("level-name=")
+                    .map(|name| name.trim().to_string())
+            })
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| DEFAULT_LEVEL_NAME.to_string())
+}
+
+async fn check_world(target: &PreflightTarget) -> Vec<Finding> {
+    let world = target.path.join(level_name(&target.path).await);
+    // no world yet is fine, the server generates one
+    if !world.is_dir() || world.join("level.dat").is_file() {
+        return Vec::new();
+    }
+    let message = if world.join("level.dat_old").is_file() {
+        format!(
+            "{} has no level.dat, restore it from level.dat_old or a backup",
+            world.display()
+        )
+    } else {
+        format!(
+            "{} has no level.dat, restore it from a backup",
+            world.display()
+        )
+    };
+    vec![Finding {
+        id: FindingId::WorldMissingLevelDat,
+        check: DiagnosticCheck::World,
+        severity: Severity::Error,
+        message,
+        remediation: None,
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn target(path: &Path, port: u32) -> PreflightTarget {
+        PreflightTarget {
+            path: path.to_path_buf(),
+            port,
+            java: path.join("no-java-here"),
+            server_jar: Some(path.join("server.jar")),
+            max_ram_mb: 4096,
+        }
+    }
+
+    const ROOMY: DiagnosticsContext = DiagnosticsContext {
+        available_memory_mb: 16384,
+        instance_running: false,
+    };
+
+    #[tokio::test]
+    async fn test_diagnostics_findings() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = std::net::TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let uuid = InstanceUuid::default();
+        std::fs::create_dir(dir.path().join("survival")).unwrap();
+        std::fs::write(
+            dir.path().join("server.properties"),
+            "level-name=survival\n",
+        )
+        .unwrap();
+        let target = target(dir.path(), port);
+
+        let report = run_diagnostics(
+            &uuid,
+            &target,
+            DiagnosticsContext {
+                available_memory_mb: 1024,
+                ..ROOMY
+            },
+        )
+        .await;
+        for id in [
+            FindingId::PortInUse,
+            FindingId::JavaMissing,
+            FindingId::ServerJarMissing,
+            FindingId::EulaNotAccepted,
+            FindingId::MemoryOverBudget,
+            FindingId::WorldMissingLevelDat,
+        ] {
+            assert!(report.has(id), "{id:?} not found");
+        }
+        assert!(!report.blocking_ids().contains(&FindingId::MemoryOverBudget));
+        let eula = report
+            .findings
+            .iter()
+            .find(|finding| finding.id == FindingId::EulaNotAccepted)
+            .unwrap();
+        assert_eq!(
+            eula.remediation.as_ref().unwrap().endpoint,
+            format!("PUT /instance/{uuid}/eula")
+        );
+
+        // a running instance holds its own port
+        let report = run_diagnostics(
+            &uuid,
+            &target,
+            DiagnosticsContext {
+                instance_running: true,
+                ..ROOMY
+            },
+        )
+        .await;
+        assert!(!report.has(FindingId::PortInUse));
+        assert!(!report.has(FindingId::MemoryOverBudget));
+
+        std::fs::write(dir.path().join("server.jar"), "not a zip").unwrap();
+        assert!(run_diagnostics(&uuid, &target, ROOMY)
+            .await
+            .has(FindingId::ServerJarCorrupted));
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = std::net::TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let uuid = InstanceUuid::default();
+
+        let report = run_preflight(&uuid, &target(dir.path(), port), false).await;
+        assert!(report.has(FindingId::PortInUse));
+        // the EULA is left to the server
+        assert!(!report.has(FindingId::EulaNotAccepted));
+        let error = report.start_error().unwrap();
+        assert_eq!(error.code(), ErrorCode::PortInUse);
+
+        drop(listener);
+        let report = run_preflight(&uuid, &target(dir.path(), port), false).await;
+        assert_eq!(
+            report.start_error().unwrap().code(),
+            ErrorCode::JavaUnavailable
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_clear_once_fixed() {
+        let dir = tempfile::tempdir().unwrap();
+        let uuid = InstanceUuid::default();
+        let port = crate::daemon::pick_http_port(None).unwrap() as u32;
+        let target = target(dir.path(), port);
+
+        accept_eula(dir.path()).await.unwrap();
+        let mut jar =
+            zip::ZipWriter::new(std::fs::File::create(dir.path().join("server.jar")).unwrap());
+        jar.start_file("META-INF/MANIFEST.MF", Default::default())
+            .unwrap();
+        jar.write_all(b"Manifest-Version: 1.0\n").unwrap();
+        jar.finish().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        std::fs::write(dir.path().join("world/level.dat"), "").unwrap();
+
+        let report = run_diagnostics(&uuid, &target, ROOMY).await;
+        // the Java path is made up, everything else is healthy
+        assert!(report
+            .findings
+            .iter()
+            .all(|finding| finding.check == DiagnosticCheck::Java));
+    }
+
+    #[tokio::test]
+    async fn test_hanging_check_times_out() {
+        let findings = bounded(
+            DiagnosticCheck::Java,
+            Duration::from_millis(20),
+            std::future::pending(),
+        )
+        .await;
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].id, FindingId::CheckTimedOut);
+        assert_eq!(findings[0].check, DiagnosticCheck::Java);
+    }
+
+    #[test]
+    fn test_eula_parsing() {
+        assert!(is_eula_accepted("#comment\neula=TRUE\n"));
+        assert!(!is_eula_accepted("eula=false"));
+        assert!(!is_eula_accepted(""));
+    }
+}
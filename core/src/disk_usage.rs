@@ -0,0 +1,237 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use serde::Serialize;
+use sysinfo::{DiskExt, SystemExt};
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorCode, ErrorKind};
+use crate::types::InstanceUuid;
+use crate::util::{extended_length_path, format_byte};
+
+/// A cached size older than this is recomputed in the background the next time it's read
+const STALE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy)]
+struct CachedSize {
+    bytes: u64,
+    computed_at: Instant,
+    /// Unix timestamp in seconds of the walk the size comes from
+    timestamp: i64,
+    /// Set once lodestone itself changed the directory since the walk
+    stale: bool,
+}
+
+/// Space used by an instance directory, as last measured
+#[derive(Serialize, Clone, Copy, Debug, TS)]
+#[ts(export)]
+pub struct DirSize {
+    pub bytes: u64,
+    /// Unix timestamp in seconds of the walk the size comes from
+    pub computed_at: i64,
+}
+
+/// Total and free space of a volume in bytes
+#[derive(Serialize, Clone, Copy, Debug, TS)]
+#[ts(export)]
+pub struct VolumeSpace {
+    pub total: u64,
+    pub free: u64,
+}
+
+/// Sums the size of every file under `path` without following links
+pub fn dir_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(extended_length_path(path))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Caches the size of each instance directory so listing them doesn't walk every world.
+///
+/// Writes made through lodestone are added to the cached size right away, anything else
+/// (such as the game saving its world) shows up once the cache goes stale
+#[derive(Clone, Default)]
+pub struct DiskUsageTracker {
+    sizes: Arc<DashMap<InstanceUuid, CachedSize>>,
+    /// Instances with a walk in progress, so a slow walk isn't started twice
+    refreshing: Arc<DashMap<InstanceUuid, ()>>,
+}
+
+impl DiskUsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn refresh(&self, instance_uuid: &InstanceUuid, root: PathBuf) -> Result<DirSize, Error> {
+        let bytes = tokio::task::spawn_blocking(move || dir_size(&root))
+            .await
+            .context("Failed to measure instance directory")?;
+        let timestamp = chrono::Utc::now().timestamp();
+        self.sizes.insert(
+            instance_uuid.clone(),
+            CachedSize {
+                bytes,
+                computed_at: Instant::now(),
+                timestamp,
+                stale: false,
+            },
+        );
+        Ok(DirSize {
+            bytes,
+            computed_at: timestamp,
+        })
+    }
+
+    /// The size of an instance directory. Only the first call waits for the walk, later ones
+    /// get the cached size and start a new walk in the background if it's stale
+    pub async fn size(&self, instance_uuid: &InstanceUuid, root: &Path) -> Result<DirSize, Error> {
+        let cached = self.sizes.get(instance_uuid).map(|cached| *cached);
+        let cached = match cached {
+            Some(cached) => cached,
+            None => return self.refresh(instance_uuid, root.to_path_buf()).await,
+        };
+        if (cached.stale || cached.computed_at.elapsed() >= STALE_AFTER)
+            && self.refreshing.insert(instance_uuid.clone(), ()).is_none()
+        {
+            let tracker = self.clone();
+            let instance_uuid = instance_uuid.clone();
+            let root = root.to_path_buf();
+            tokio::spawn(async move {
+                if let Err(e) = tracker.refresh(&instance_uuid, root).await {
+                    warn!("Failed to measure {}: {}", instance_uuid, e);
+                }
+                tracker.refreshing.remove(&instance_uuid);
+            });
+        }
+        Ok(DirSize {
+            bytes: cached.bytes,
+            computed_at: cached.timestamp,
+        })
+    }
+
+    /// Accounts for `bytes` written into the instance until the next walk measures them
+    pub fn add(&self, instance_uuid: &InstanceUuid, bytes: u64) {
+        if let Some(mut cached) = self.sizes.get_mut(instance_uuid) {
+            cached.bytes += bytes;
+            cached.stale = true;
+        }
+    }
+
+    /// Marks the size as outdated, e.g. after files were removed
+    pub fn invalidate(&self, instance_uuid: &InstanceUuid) {
+        if let Some(mut cached) = self.sizes.get_mut(instance_uuid) {
+            cached.stale = true;
+        }
+    }
+
+    pub fn forget(&self, instance_uuid: &InstanceUuid) {
+        self.sizes.remove(instance_uuid);
+    }
+}
+
+/// Space of the volume holding `path`, None if no mounted disk contains it
+pub fn volume_space(system: &mut sysinfo::System, path: &Path) -> Option<VolumeSpace> {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    system.refresh_disks_list();
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| VolumeSpace {
+            total: disk.total_space(),
+            free: disk.available_space(),
+        })
+}
+
+/// Checks that `needed` more bytes fit both under the instance's quota and on the volume
+pub fn check_space(
+    used: u64,
+    quota: Option<u64>,
+    free: Option<u64>,
+    needed: u64,
+) -> Result<(), Error> {
+    if let Some(quota) = quota {
+        let available = quota.saturating_sub(used);
+        if needed > available {
+            return Err(Error::coded(
+                ErrorCode::QuotaExceeded,
+                format!(
+                    "This needs {} but only {} of the instance's {} quota is left",
+                    format_byte(needed),
+                    format_byte(available),
+                    format_byte(quota)
+                ),
+            )
+            .with_details(serde_json::json!({
+                "needed": needed,
+                "available": available,
+                "quota": quota,
+            })));
+        }
+    }
+    if let Some(free) = free {
+        if needed > free {
+            return Err(Error {
+                kind: ErrorKind::InsufficientStorage,
+                source: eyre!(
+                    "This needs {} but only {} is free on the disk",
+                    format_byte(needed),
+                    format_byte(free)
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_space() {
+        assert!(check_space(900, Some(1000), Some(10_000), 100).is_ok());
+        assert!(check_space(900, Some(1000), Some(10_000), 101).is_err());
+        assert!(check_space(2000, Some(1000), None, 1).is_err());
+        assert!(check_space(0, None, Some(50), 100).is_err());
+        assert!(check_space(0, None, None, u64::MAX).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tracker() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), vec![0u8; 1000]).unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        std::fs::write(dir.path().join("world/b"), vec![0u8; 500]).unwrap();
+        let tracker = DiskUsageTracker::new();
+        let instance_uuid = InstanceUuid::default();
+        assert_eq!(
+            tracker
+                .size(&instance_uuid, dir.path())
+                .await
+                .unwrap()
+                .bytes,
+            1500
+        );
+        // served from the cache, with lodestone's own writes added
+        std::fs::write(dir.path().join("c"), vec![0u8; 200]).unwrap();
+        tracker.add(&instance_uuid, 200);
+        assert_eq!(
+            tracker
+                .size(&instance_uuid, dir.path())
+                .await
+                .unwrap()
+                .bytes,
+            1700
+        );
+    }
+}
@@ -16,7 +16,7 @@ use virtual_fs::{get_virtual_path, to_virtual_path};
 
 use crate::handlers::global_fs::FileEntry;
 use crate::traits::t_configurable::Game::Generic;
-use crate::util::{list_dir, scoped_join_win_safe};
+use crate::util::{list_dir, scoped_join_win_safe, slugify};
 use crate::{
     error::Error,
     event_broadcaster::EventBroadcaster,
@@ -149,6 +149,7 @@ impl DockerBridge {
             }
             let instance = InstanceInfo {
                 uuid,
+                slug: slugify(&name),
                 name,
                 game_type: Generic {
                     game_name: GameType::Generic,
@@ -165,6 +166,17 @@ impl DockerBridge {
                 player_count: None,
                 max_player_count: None,
                 player_list: None,
+                launch_command: None,
+                eula_accepted: None,
+                last_started: None,
+                last_started_at: None,
+                last_stopped_at: None,
+                uptime_seconds: None,
+                last_exit_code: None,
+                last_exit_reason: None,
+                tags: Vec::new(),
+                maintenance: None,
+                degraded: false,
             };
             ret.push(instance);
         }
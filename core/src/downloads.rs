@@ -0,0 +1,389 @@
+//! Downloads that survive a flaky connection: a transfer that drops is resumed with a range
+//! request, and the file is checked against the checksum upstream publishes before it is used
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Context};
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::error::{Error, ErrorKind};
+use crate::prelude::{path_to_binaries, path_to_tmp};
+use crate::util::{bytes_per_second, DownloadProgress};
+
+pub(crate) const MAX_ATTEMPTS: u32 = 5;
+pub(crate) const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(16);
+
+/// A checksum published next to a download, as lowercase hex
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Sha1(String),
+    Sha256(String),
+}
+
+impl Checksum {
+    pub fn sha1(hex: &str) -> Self {
+        Checksum::Sha1(hex.to_ascii_lowercase())
+    }
+
+    pub fn sha256(hex: &str) -> Self {
+        Checksum::Sha256(hex.to_ascii_lowercase())
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            Checksum::Sha1(_) => "sha1",
+            Checksum::Sha256(_) => "sha256",
+        }
+    }
+
+    fn hex(&self) -> &str {
+        match self {
+            Checksum::Sha1(hex) | Checksum::Sha256(hex) => hex,
+        }
+    }
+
+    /// Hashes the file with the same algorithm, off the async runtime
+    async fn of_file(&self, path: &Path) -> Result<Checksum, Error> {
+        let path = path.to_owned();
+        let sha1 = matches!(self, Checksum::Sha1(_));
+        tokio::task::spawn_blocking(move || {
+            Ok(if sha1 {
+                Checksum::Sha1(hash_file::<Sha1>(&path)?)
+            } else {
+                Checksum::Sha256(hash_file::<Sha256>(&path)?)
+            })
+        })
+        .await
+        .context("Failed to hash the download")?
+    }
+}
+
+fn hash_file<D: Digest>(path: &Path) -> Result<String, Error> {
+    let mut file =
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = D::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .context(format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// What a response means for the bytes already downloaded
+#[derive(Debug, PartialEq, Eq)]
+enum Continuation {
+    /// The server sends the rest of the file
+    Append,
+    /// The server ignored the range, the file is sent from the start
+    Restart,
+    /// Nothing is left to download
+    Complete,
+}
+
+fn continuation(status: StatusCode, partial_len: u64) -> Result<Continuation, Error> {
+    match status {
+        StatusCode::PARTIAL_CONTENT if partial_len > 0 => Ok(Continuation::Append),
+        StatusCode::RANGE_NOT_SATISFIABLE if partial_len > 0 => Ok(Continuation::Complete),
+        status if status.is_success() => Ok(Continuation::Restart),
+        status => Err(Error {
+            kind: ErrorKind::External,
+            source: eyre!("The server answered with {}", status),
+        }),
+    }
+}
+
+/// Client errors won't go away by asking again, except for timeouts and rate limits
+pub(crate) fn is_retryable(status: StatusCode) -> bool {
+    !status.is_client_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+enum AttemptError {
+    /// Asking again may succeed, the bytes written so far are kept
+    Retry(Error),
+    Fatal(Error),
+}
+
+/// Downloads `url` to `destination`, retrying with backoff when the connection drops. The
+/// file only appears at `destination` once it is complete and matches `checksum`, a corrupt
+/// download is thrown away and fetched again
+pub async fn download_verified(
+    url: &str,
+    checksum: Option<&Checksum>,
+    destination: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<(), Error> {
+    let download_name = destination
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| url.to_string());
+    crate::util::fs::create_dir_all(path_to_tmp()).await?;
+    // removed on drop, so a failed download leaves nothing behind
+    let partial = tempfile::NamedTempFile::new_in(path_to_tmp())
+        .context("Failed to create temporary file")?
+        .into_temp_path();
+    let client = reqwest::Client::new();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let result =
+            match download_attempt(&client, url, &partial, &download_name, on_download).await {
+                Ok(()) => match checksum {
+                    Some(expected) => {
+                        let actual = expected.of_file(&partial).await?;
+                        if actual == *expected {
+                            Ok(())
+                        } else {
+                            // the corrupt bytes can't be resumed from
+                            tokio::fs::File::create(&partial)
+                                .await
+                                .context("Failed to discard the corrupt download")?;
+                            Err(AttemptError::Retry(
+                                eyre!(
+                                    "{} has {} {}, expected {}",
+                                    download_name,
+                                    actual.algorithm(),
+                                    actual.hex(),
+                                    expected.hex()
+                                )
+                                .into(),
+                            ))
+                        }
+                    }
+                    None => Ok(()),
+                },
+                Err(e) => Err(e),
+            };
+        match result {
+            Ok(()) => break,
+            Err(AttemptError::Fatal(e)) => return Err(e),
+            Err(AttemptError::Retry(e)) if attempt < MAX_ATTEMPTS => {
+                warn!(
+                    "Downloading {} failed (attempt {}/{}), retrying in {:?}: {}",
+                    url, attempt, MAX_ATTEMPTS, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(AttemptError::Retry(e)) => return Err(e),
+        }
+    }
+    partial.persist(destination).map_err(|e| {
+        eyre!(
+            "Failed to move the download to {}: {}",
+            destination.display(),
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// Continues the download into `partial` from where the last attempt stopped
+async fn download_attempt(
+    client: &reqwest::Client,
+    url: &str,
+    partial: &Path,
+    download_name: &str,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<(), AttemptError> {
+    let partial_len = tokio::fs::metadata(partial)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let mut request = client.get(url);
+    if partial_len > 0 {
+        request = request.header(RANGE, format!("bytes={}-", partial_len));
+    }
+    let response = request
+        .send()
+        .await
+        .context(format!("Failed to request {}", url))
+        .map_err(|e| AttemptError::Retry(e.into()))?;
+    let status = response.status();
+    let (file, mut downloaded) = match continuation(status, partial_len) {
+        Ok(Continuation::Complete) => return Ok(()),
+        Ok(Continuation::Append) => (
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(partial)
+                .await,
+            partial_len,
+        ),
+        Ok(Continuation::Restart) => (tokio::fs::File::create(partial).await, 0),
+        Err(e) if is_retryable(status) => return Err(AttemptError::Retry(e)),
+        Err(e) => return Err(AttemptError::Fatal(e)),
+    };
+    let mut file =
+        file.map_err(|e| AttemptError::Fatal(eyre!("Failed to open the download: {}", e).into()))?;
+    let total = response.content_length().map(|len| len + downloaded);
+    let threshold = total.unwrap_or(500000) / 100;
+    let started = Instant::now();
+    let mut transferred = 0;
+    let mut reported = downloaded;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk
+            .context(format!("Connection to {} dropped", url))
+            .map_err(|e| AttemptError::Retry(e.into()))?;
+        file.write_all(&chunk).await.map_err(|e| {
+            AttemptError::Fatal(eyre!("Failed to write {}: {}", download_name, e).into())
+        })?;
+        downloaded += chunk.len() as u64;
+        transferred += chunk.len() as u64;
+        if downloaded - reported > threshold {
+            on_download(DownloadProgress {
+                total,
+                downloaded: reported,
+                step: downloaded - reported,
+                download_name: download_name.to_string(),
+                speed: bytes_per_second(transferred, started.elapsed()),
+            });
+            reported = downloaded;
+        }
+    }
+    file.flush().await.map_err(|e| {
+        AttemptError::Fatal(eyre!("Failed to write {}: {}", download_name, e).into())
+    })?;
+    if let Some(total) = total {
+        if downloaded < total {
+            return Err(AttemptError::Retry(
+                eyre!(
+                    "Connection to {} closed after {} of {} bytes",
+                    url,
+                    downloaded,
+                    total
+                )
+                .into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Held while a file is put in the cache, so setups running at once download it once
+static CACHE_LOCKS: Lazy<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Downloads a jar into the cache shared by all instances unless a jar with the same checksum
+/// is already there, returning its path in the cache
+pub async fn cached_download(
+    url: &str,
+    checksum: &Checksum,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<PathBuf, Error> {
+    let path = path_to_binaries().join("jar_cache").join(format!(
+        "{}-{}.jar",
+        checksum.algorithm(),
+        checksum.hex()
+    ));
+    let lock = CACHE_LOCKS
+        .lock()
+        .await
+        .entry(path.clone())
+        .or_default()
+        .clone();
+    let _guard = lock.lock().await;
+    if !path.is_file() {
+        if let Some(parent) = path.parent() {
+            crate::util::fs::create_dir_all(parent).await?;
+        }
+        download_verified(url, Some(checksum), &path, on_download).await?;
+    }
+    Ok(path)
+}
+
+/// Puts the jar at `destination`, from the shared cache when its checksum is known
+pub async fn download_jar(
+    url: &str,
+    checksum: Option<&Checksum>,
+    destination: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<(), Error> {
+    match checksum {
+        Some(checksum) => {
+            let cached = cached_download(url, checksum, on_download).await?;
+            if let Some(parent) = destination.parent() {
+                crate::util::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::copy(&cached, destination)
+                .await
+                .context(format!(
+                    "Failed to copy {} to {}",
+                    cached.display(),
+                    destination.display()
+                ))?;
+            Ok(())
+        }
+        None => download_verified(url, None, destination, on_download).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_continuation() {
+        assert_eq!(
+            continuation(StatusCode::PARTIAL_CONTENT, 1024).unwrap(),
+            Continuation::Append
+        );
+        assert_eq!(
+            continuation(StatusCode::OK, 1024).unwrap(),
+            Continuation::Restart
+        );
+        assert_eq!(
+            continuation(StatusCode::OK, 0).unwrap(),
+            Continuation::Restart
+        );
+        assert_eq!(
+            continuation(StatusCode::RANGE_NOT_SATISFIABLE, 1024).unwrap(),
+            Continuation::Complete
+        );
+        assert!(continuation(StatusCode::RANGE_NOT_SATISFIABLE, 0).is_err());
+        assert!(continuation(StatusCode::NOT_FOUND, 0).is_err());
+
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn test_checksum_of_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.jar");
+        tokio::fs::write(&path, "lodestone").await.unwrap();
+
+        let sha1 = Checksum::sha1(&hex::encode(Sha1::digest(b"lodestone")).to_uppercase());
+        assert_eq!(sha1.of_file(&path).await.unwrap(), sha1);
+        let sha256 = Checksum::sha256(&hex::encode(Sha256::digest(b"lodestone")));
+        assert_eq!(sha256.of_file(&path).await.unwrap(), sha256);
+        assert_ne!(
+            Checksum::sha256(&hex::encode(Sha256::digest(b"corrupt")))
+                .of_file(&path)
+                .await
+                .unwrap(),
+            Checksum::sha256(&hex::encode(Sha256::digest(b"corrupt")))
+        );
+    }
+}
@@ -8,10 +8,15 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
 use ts_rs::TS;
+use utoipa::openapi::{ArrayBuilder, ObjectBuilder, Ref, RefOr, Schema, SchemaType};
+use utoipa::ToSchema;
 
+use crate::diagnostics::StartDiagnosis;
 use crate::error;
+use crate::request_context::current_request_id;
+use crate::traits::t_configurable::manifest::SettingValidationErrors;
 
-#[derive(Debug, Clone, Deserialize, Serialize, TS)]
+#[derive(Debug, Clone, Deserialize, Serialize, TS, ToSchema)]
 #[ts(export)]
 pub enum ErrorKind {
     NotFound,
@@ -21,8 +26,164 @@ pub enum ErrorKind {
     Unauthorized,
     External,
     Internal,
+    CommandQueueFull,
+    FeatureDisabled,
+    Conflict,
+    RateLimited,
+    InsufficientStorage,
+    PreconditionFailed,
 }
 
+/// Stable, machine-readable reason of an error, clients branch on it instead of the message.
+///
+/// Every kind has a generic code, errors clients commonly need to tell apart have their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, TS, ToSchema)]
+#[ts(export)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    NotFound,
+    UnsupportedOperation,
+    BadRequest,
+    PermissionDenied,
+    Unauthorized,
+    External,
+    Internal,
+    CommandQueueFull,
+    FeatureDisabled,
+    Conflict,
+    RateLimited,
+    InsufficientStorage,
+    InstanceNotFound,
+    InstanceNotStopped,
+    InstanceNameTaken,
+    /// Several instances the requester can see go by the name looked up
+    InstanceNameAmbiguous,
+    InstanceUuidTaken,
+    /// The endpoint only applies to another game than the instance's
+    UnsupportedGameType,
+    PortInUse,
+    PathOutsideInstance,
+    ProtectedFile,
+    FileNotFound,
+    DestinationInsideSource,
+    MissingFileName,
+    InvalidRange,
+    PathTooLong,
+    UploadTooLarge,
+    QuotaExceeded,
+    InvalidSettings,
+    PreconditionFailed,
+    /// The file changed since the client read it, the details carry its current ETag
+    FileChanged,
+    /// The instance's Java runtime is missing or doesn't run
+    JavaUnavailable,
+    /// The server jar is missing or not a valid jar
+    ServerJarInvalid,
+    /// The instance's state doesn't allow it, the details carry the state
+    InvalidStateTransition,
+    /// A hook command failed, the details carry its exit code and last lines of output
+    HookFailed,
+    /// The data pack is made for another version, the details carry both pack formats
+    IncompatibleDatapack,
+    /// Starting the instance would go over a limit of the daemon settings, the details carry the
+    /// current usage and the limits
+    CapacityExceeded,
+    /// The `Idempotency-Key` was sent before with a different request
+    IdempotencyKeyReused,
+    /// The command policy of the user for the instance refuses the console command
+    CommandNotAllowed,
+    /// The instance is under maintenance, the details carry the reason and who set it
+    MaintenanceMode,
+    /// The instance's directory is being migrated
+    InstanceMigrating,
+}
+
+impl ErrorCode {
+    /// The kind, and so the status, an error with this code is answered with
+    pub fn kind(self) -> ErrorKind {
+        match self {
+            ErrorCode::NotFound | ErrorCode::InstanceNotFound | ErrorCode::FileNotFound => {
+                ErrorKind::NotFound
+            }
+            ErrorCode::UnsupportedOperation | ErrorCode::UnsupportedGameType => {
+                ErrorKind::UnsupportedOperation
+            }
+            ErrorCode::BadRequest
+            | ErrorCode::InstanceNotStopped
+            | ErrorCode::DestinationInsideSource
+            | ErrorCode::MissingFileName
+            | ErrorCode::InvalidRange
+            | ErrorCode::PathTooLong
+            | ErrorCode::UploadTooLarge
+            | ErrorCode::InvalidSettings
+            | ErrorCode::JavaUnavailable
+            | ErrorCode::ServerJarInvalid
+            | ErrorCode::IncompatibleDatapack => ErrorKind::BadRequest,
+            ErrorCode::PermissionDenied
+            | ErrorCode::PathOutsideInstance
+            | ErrorCode::ProtectedFile
+            | ErrorCode::CommandNotAllowed => ErrorKind::PermissionDenied,
+            ErrorCode::Unauthorized => ErrorKind::Unauthorized,
+            ErrorCode::External | ErrorCode::HookFailed => ErrorKind::External,
+            ErrorCode::Internal => ErrorKind::Internal,
+            ErrorCode::CommandQueueFull => ErrorKind::CommandQueueFull,
+            ErrorCode::FeatureDisabled => ErrorKind::FeatureDisabled,
+            ErrorCode::Conflict
+            | ErrorCode::InstanceNameTaken
+            | ErrorCode::InstanceNameAmbiguous
+            | ErrorCode::InstanceUuidTaken
+            | ErrorCode::PortInUse
+            | ErrorCode::InvalidStateTransition
+            | ErrorCode::CapacityExceeded
+            | ErrorCode::IdempotencyKeyReused
+            | ErrorCode::MaintenanceMode
+            | ErrorCode::InstanceMigrating => ErrorKind::Conflict,
+            ErrorCode::RateLimited => ErrorKind::RateLimited,
+            ErrorCode::InsufficientStorage | ErrorCode::QuotaExceeded => {
+                ErrorKind::InsufficientStorage
+            }
+            ErrorCode::PreconditionFailed | ErrorCode::FileChanged => ErrorKind::PreconditionFailed,
+        }
+    }
+}
+
+/// The generic code of errors that weren't given one
+impl From<&ErrorKind> for ErrorCode {
+    fn from(kind: &ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::UnsupportedOperation => ErrorCode::UnsupportedOperation,
+            ErrorKind::BadRequest => ErrorCode::BadRequest,
+            ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            ErrorKind::Unauthorized => ErrorCode::Unauthorized,
+            ErrorKind::External => ErrorCode::External,
+            ErrorKind::Internal => ErrorCode::Internal,
+            ErrorKind::CommandQueueFull => ErrorCode::CommandQueueFull,
+            ErrorKind::FeatureDisabled => ErrorCode::FeatureDisabled,
+            ErrorKind::Conflict => ErrorCode::Conflict,
+            ErrorKind::RateLimited => ErrorCode::RateLimited,
+            ErrorKind::InsufficientStorage => ErrorCode::InsufficientStorage,
+            ErrorKind::PreconditionFailed => ErrorCode::PreconditionFailed,
+        }
+    }
+}
+
+/// The source of an error made by `Error::coded`, found again under any context added later
+#[derive(Debug)]
+struct Coded {
+    code: ErrorCode,
+    message: String,
+    details: Option<serde_json::Value>,
+}
+
+impl Display for Coded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Coded {}
+
 #[derive(Error, Debug)]
 #[error("An error occurred ({kind}): {source}")]
 pub struct Error {
@@ -32,7 +193,9 @@ pub struct Error {
 
 impl Error {
     pub fn log(self) -> Self {
+        let request_id = current_request_id();
         error!(
+            request_id = request_id.as_deref(),
             "An error occurred ({kind}): {source}",
             kind = self.kind,
             source = self.source
@@ -42,6 +205,46 @@ impl Error {
 }
 
 impl Error {
+    /// An error with its own code, the kind follows from the code
+    pub fn coded(code: ErrorCode, message: impl Display) -> Self {
+        Error {
+            kind: code.kind(),
+            source: Report::new(Coded {
+                code,
+                message: message.to_string(),
+                details: None,
+            }),
+        }
+    }
+
+    /// Structured details of a coded error for the client, e.g. the port that is taken
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        if let Some(coded) = self.source.downcast_mut::<Coded>() {
+            coded.details = serde_json::to_value(details).ok();
+        }
+        self
+    }
+
+    fn coded_source(&self) -> Option<&Coded> {
+        self.source
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Coded>())
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        if let Some(coded) = self.coded_source() {
+            coded.code
+        } else if self
+            .source
+            .downcast_ref::<SettingValidationErrors>()
+            .is_some()
+        {
+            ErrorCode::InvalidSettings
+        } else {
+            ErrorCode::from(&self.kind)
+        }
+    }
+
     pub fn ts_syntax_error(context: &str) -> Error {
         Error {
             kind: ErrorKind::Internal,
@@ -59,7 +262,13 @@ impl Display for ErrorKind {
             ErrorKind::PermissionDenied => write!(f, "Permission Denied"),
             ErrorKind::Unauthorized => write!(f, "Unauthorized"),
             ErrorKind::Internal => write!(f, "Internal Error"),
-            ErrorKind::External => write!(f, "External Error")
+            ErrorKind::External => write!(f, "External Error"),
+            ErrorKind::CommandQueueFull => write!(f, "Command Queue Full"),
+            ErrorKind::FeatureDisabled => write!(f, "Feature Disabled"),
+            ErrorKind::Conflict => write!(f, "Conflict"),
+            ErrorKind::RateLimited => write!(f, "Rate Limited"),
+            ErrorKind::InsufficientStorage => write!(f, "Insufficient Storage"),
+            ErrorKind::PreconditionFailed => write!(f, "Precondition Failed"),
         }
     }
 }
@@ -69,14 +278,72 @@ impl Serialize for Error {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("Error", 2)?;
+        // a failed start carries the diagnostic findings behind it
+        let diagnosis = self.source.downcast_ref::<StartDiagnosis>();
+        // a refused settings update names each field it refused
+        let invalid_settings = self.source.downcast_ref::<SettingValidationErrors>();
+        // ties the error a client reports back to the request's log line
+        let request_id = current_request_id();
+        let details = self.coded_source().and_then(|coded| coded.details.as_ref());
+        let mut state = serializer.serialize_struct(
+            "Error",
+            4 + details.is_some() as usize
+                + diagnosis.is_some() as usize
+                + invalid_settings.is_some() as usize
+                + request_id.is_some() as usize,
+        )?;
         state.serialize_field("kind", &self.kind)?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("message", &self.source.to_string())?;
         let vec: Vec<String> = self.source.chain().map(|cause| cause.to_string()).collect();
         state.serialize_field("causes", &vec)?;
+        if let Some(details) = details {
+            state.serialize_field("details", details)?;
+        }
+        if let Some(StartDiagnosis(finding_ids)) = diagnosis {
+            state.serialize_field("diagnostics", finding_ids)?;
+        }
+        if let Some(SettingValidationErrors(errors)) = invalid_settings {
+            state.serialize_field("invalid_settings", errors)?;
+        }
+        if let Some(request_id) = request_id {
+            state.serialize_field("request_id", &request_id)?;
+        }
         state.end()
     }
 }
 
+/// Written by hand to match the `Serialize` impl above
+impl<'s> ToSchema<'s> for Error {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let string = || ObjectBuilder::new().schema_type(SchemaType::String);
+        (
+            "Error",
+            ObjectBuilder::new()
+                .property("kind", Ref::from_schema_name("ErrorKind"))
+                .required("kind")
+                .property("code", Ref::from_schema_name("ErrorCode"))
+                .required("code")
+                .property("message", string())
+                .required("message")
+                .property("causes", ArrayBuilder::new().items(string()))
+                .required("causes")
+                .property(
+                    "details",
+                    ObjectBuilder::new()
+                        .description(Some("Shaped by the code, e.g. the port taken")),
+                )
+                .property("diagnostics", ArrayBuilder::new().items(string()))
+                .property(
+                    "invalid_settings",
+                    ArrayBuilder::new().items(Ref::from_schema_name("SettingValidationError")),
+                )
+                .property("request_id", string())
+                .into(),
+        )
+    }
+}
+
 #[test]
 fn test_error_serialization() {
     let error = Error {
@@ -84,7 +351,95 @@ fn test_error_serialization() {
         source: Report::msg("Test"),
     };
     let json = serde_json::to_string(&error).unwrap();
-    assert_eq!(json, r#"{"kind":"NotFound","causes":["Test"]}"#);
+    assert_eq!(
+        json,
+        r#"{"kind":"NotFound","code":"NOT_FOUND","message":"Test","causes":["Test"]}"#
+    );
+}
+
+#[test]
+fn test_coded_error_serialization() {
+    let error = Error::coded(
+        ErrorCode::PortInUse,
+        "Port 25565 is already in use by another program",
+    )
+    .with_details(json!({ "port": 25565 }));
+    assert!(matches!(error.kind, ErrorKind::Conflict));
+    let json = serde_json::to_string(&error).unwrap();
+    assert_eq!(
+        json,
+        r#"{"kind":"Conflict","code":"PORT_IN_USE","message":"Port 25565 is already in use by another program","causes":["Port 25565 is already in use by another program"],"details":{"port":25565}}"#
+    );
+
+    // context added on the way up keeps the code and details, the message is the outermost one
+    let error = Error {
+        kind: ErrorKind::Conflict,
+        source: error.source.wrap_err("Failed to change the port"),
+    };
+    let json = serde_json::to_value(&error).unwrap();
+    assert_eq!(json["code"], "PORT_IN_USE");
+    assert_eq!(json["message"], "Failed to change the port");
+    assert_eq!(json["details"]["port"], 25565);
+}
+
+#[test]
+fn test_error_serialization_with_diagnosis() {
+    let error = Error {
+        kind: ErrorKind::Internal,
+        source: Report::msg("Failed to start").wrap_err(StartDiagnosis(vec![
+            crate::diagnostics::FindingId::EulaNotAccepted,
+        ])),
+    };
+    let json = serde_json::to_value(&error).unwrap();
+    assert_eq!(json["diagnostics"], json!(["eula_not_accepted"]));
+    assert_eq!(json["causes"][1], "Failed to start");
+}
+
+#[test]
+fn test_error_serialization_with_invalid_settings() {
+    use crate::traits::t_configurable::manifest::SettingValidationError;
+
+    let error: Error = SettingValidationErrors(vec![
+        SettingValidationError::new("max-players", "Expected unsigned integer, found string"),
+        SettingValidationError::new("difficulty", "Must be one of easy, hard, got medium"),
+    ])
+    .into();
+    assert!(matches!(error.kind, ErrorKind::BadRequest));
+    let json = serde_json::to_value(&error).unwrap();
+    assert_eq!(json["invalid_settings"][0]["setting_id"], "max-players");
+    assert_eq!(json["invalid_settings"][1]["setting_id"], "difficulty");
+    assert!(json.get("diagnostics").is_none());
+    assert_eq!(json["code"], "INVALID_SETTINGS");
+}
+
+#[test]
+fn test_io_error_kinds() {
+    use color_eyre::eyre::Context;
+
+    let io_error = |e: std::io::Error| -> Error {
+        Err::<(), _>(e)
+            .context("Failed to read file")
+            .unwrap_err()
+            .into()
+    };
+    let error = io_error(std::io::ErrorKind::NotFound.into());
+    assert!(matches!(error.kind, ErrorKind::NotFound));
+    let error = io_error(std::io::ErrorKind::PermissionDenied.into());
+    assert!(matches!(error.kind, ErrorKind::PermissionDenied));
+    assert!(matches!(
+        io_error(std::io::ErrorKind::Other.into()).kind,
+        ErrorKind::Internal
+    ));
+    #[cfg(unix)]
+    {
+        let error = io_error(std::io::Error::from_raw_os_error(libc::ENOSPC));
+        assert!(matches!(error.kind, ErrorKind::InsufficientStorage));
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["causes"][0], "Failed to read file");
+        assert!(json["causes"][1].as_str().unwrap().contains("os error"));
+        let error = io_error(std::io::Error::from_raw_os_error(libc::EISDIR));
+        assert!(matches!(error.kind, ErrorKind::BadRequest));
+    }
 }
 
 impl IntoResponse for Error {
@@ -97,24 +452,56 @@ impl IntoResponse for Error {
             ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
             ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
             ErrorKind::External => StatusCode::BAD_GATEWAY,
+            ErrorKind::CommandQueueFull => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::FeatureDisabled => StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorKind::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
         };
         (status, json!(self).to_string()).into_response()
     }
 }
 
+#[cfg(windows)]
+mod win_error {
+    pub const ERROR_HANDLE_DISK_FULL: i32 = 39;
+    pub const ERROR_DISK_FULL: i32 = 112;
+    pub const ERROR_DIRECTORY: i32 = 267;
+}
+
+/// What an IO error tells the client, a missing file, a denied one and a full disk must not all
+/// come back as an internal error
+fn io_error_kind(io_error: &std::io::Error) -> ErrorKind {
+    match io_error.kind() {
+        std::io::ErrorKind::NotFound => return ErrorKind::NotFound,
+        std::io::ErrorKind::PermissionDenied => return ErrorKind::PermissionDenied,
+        std::io::ErrorKind::AlreadyExists => return ErrorKind::Conflict,
+        _ => {}
+    }
+    // the kinds of these aren't stable, so they are told apart by their OS code
+    match io_error.raw_os_error() {
+        #[cfg(unix)]
+        Some(libc::EISDIR) | Some(libc::ENOTDIR) => ErrorKind::BadRequest,
+        #[cfg(unix)]
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) => ErrorKind::InsufficientStorage,
+        #[cfg(windows)]
+        Some(win_error::ERROR_DIRECTORY) => ErrorKind::BadRequest,
+        #[cfg(windows)]
+        Some(win_error::ERROR_HANDLE_DISK_FULL) | Some(win_error::ERROR_DISK_FULL) => {
+            ErrorKind::InsufficientStorage
+        }
+        _ => ErrorKind::Internal,
+    }
+}
+
 impl From<Report> for Error {
     fn from(source: Report) -> Self {
-        // try downcasting to a known error
-        let kind = if let Some(io_error) = source.downcast_ref::<std::io::Error>() {
-            // check if the error is a not found error
-            if io_error.kind() == std::io::ErrorKind::NotFound {
-                ErrorKind::NotFound
-            } else {
-                ErrorKind::Internal
-            }
-        } else {
-            ErrorKind::Internal
-        };
+        // an IO error anywhere under the context decides the kind, its OS message stays a cause
+        let kind = source
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .map_or(ErrorKind::Internal, io_error_kind);
 
         Self { kind, source }
     }
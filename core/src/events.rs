@@ -6,11 +6,14 @@ use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::{
-    auth::{permission::UserPermission, user_id::UserId},
+    auth::{permission::UserPermission, user::UserRole, user_id::UserId},
+    global_settings::DaemonSettings,
+    implementations::minecraft::launch_failure::LaunchFailure,
     macro_executor::MacroPID,
     output_types::ClientEvent,
+    request_context::current_request_id,
     traits::{t_macro::ExitStatus, t_player::Player, t_server::State, InstanceInfo},
-    types::{InstanceUuid, Snowflake, TimeRange},
+    types::{InstanceUuid, Maintenance, Snowflake, TimeRange},
 };
 
 pub trait EventFilter {
@@ -120,6 +123,41 @@ pub enum InstanceEventInner {
         player: String,
         player_message: String,
     },
+    /// A message sent to the players through Lodestone, `sender` is the name shown in front of it
+    ChatRelayed {
+        sender: String,
+        message: String,
+    },
+    /// The server stopped because its EULA isn't accepted, it won't start until it is
+    EulaRequired,
+    /// The server exited before it finished starting
+    LaunchFailed {
+        failure: LaunchFailure,
+    },
+    /// Something changed in the instance directory while it is watched, see
+    /// [`crate::fs_watch`]
+    FileChanged {
+        change: FileChange,
+        /// Relative to the instance directory
+        path: PathBuf,
+    },
+    /// The instance was put under maintenance, or taken out of it if `maintenance` is `None`
+    MaintenanceChanged {
+        maintenance: Option<Maintenance>,
+    },
+    /// The instance's tags were replaced, `tags` as normalized
+    TagsChanged {
+        tags: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChange {
+    Created,
+    Modified,
+    Removed,
 }
 
 impl AsRef<InstanceEventInner> for InstanceEventInner {
@@ -151,6 +189,30 @@ pub enum UserEventInner {
     PermissionChanged {
         new_permissions: Box<UserPermission>,
     },
+    RoleChanged {
+        new_role: UserRole,
+    },
+    DisabledChanged {
+        is_disabled: bool,
+    },
+    PasswordResetRequired,
+    AccessTokenCreated {
+        token_id: Snowflake,
+    },
+    AccessTokenRevoked {
+        token_id: Snowflake,
+    },
+    /// The user id is empty when no user has the username
+    LoginFailed {
+        username: String,
+        source_ip: Option<String>,
+    },
+    LoginLockedOut {
+        username: String,
+        source_ip: Option<String>,
+        /// Seconds until the lockout ends
+        retry_after: u64,
+    },
 }
 
 impl AsRef<UserEventInner> for UserEventInner {
@@ -205,20 +267,216 @@ pub enum ProgressionEndValue {
     InstanceCreation(InstanceInfo),
     InstanceDelete {
         instance_uuid: InstanceUuid,
+        /// Where the final export went when the instance was archived first
+        #[serde(default)]
+        archive_path: Option<String>,
     },
     FSOperationCompleted {
         instance_uuid: InstanceUuid,
         success: bool,
         message: String,
     },
+    BackupRun {
+        instance_uuid: InstanceUuid,
+        backup_id: Snowflake,
+        /// Size of the archive in bytes
+        size: u64,
+    },
+    Restore {
+        instance_uuid: InstanceUuid,
+        backup_id: Snowflake,
+        /// Where the files replaced by the restore were moved
+        previous_files: PathBuf,
+    },
+    VersionUpgrade {
+        instance_uuid: InstanceUuid,
+        version: String,
+    },
+    FsOperation {
+        instance_uuid: Option<InstanceUuid>,
+        kind: FsOperationKind,
+        /// Paths created by the operation, e.g. the archive of a zip
+        paths: Vec<PathBuf>,
+        bytes: u64,
+    },
+    ModpackInstall {
+        instance_uuid: InstanceUuid,
+        installed_mods: u32,
+    },
+    WorldOptimize {
+        instance_uuid: InstanceUuid,
+        world: String,
+        chunks: u64,
+    },
+    GracefulStop {
+        instance_uuid: InstanceUuid,
+        /// False if the countdown was cancelled or the stop failed
+        stopped: bool,
+    },
+    /// The instance was pinned to the downloaded runtime
+    JavaDownload {
+        instance_uuid: InstanceUuid,
+        major_version: u32,
+        path: PathBuf,
+    },
+    MacroRun {
+        instance_uuid: InstanceUuid,
+        macro_name: String,
+        pid: MacroPID,
+        exit_status: ExitStatus,
+    },
+    InstanceExport {
+        instance_uuid: InstanceUuid,
+        /// Uncompressed size of the exported files
+        bytes: u64,
+    },
+    InstanceMigration {
+        instance_uuid: InstanceUuid,
+        /// Where the instance is now
+        path: PathBuf,
+        bytes: u64,
+    },
+    /// The operation was cancelled on request and its partial output removed
+    Cancelled {
+        instance_uuid: Option<InstanceUuid>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
 #[ts(export)]
 #[serde(tag = "type")]
 pub enum ProgressionStartValue {
-    InstanceCreation { instance_uuid: InstanceUuid },
-    InstanceDelete { instance_uuid: InstanceUuid },
+    InstanceCreation {
+        instance_uuid: InstanceUuid,
+    },
+    InstanceDelete {
+        instance_uuid: InstanceUuid,
+    },
+    BackupRun {
+        instance_uuid: InstanceUuid,
+        backup_id: Snowflake,
+        scheduled: bool,
+    },
+    Restore {
+        instance_uuid: InstanceUuid,
+        backup_id: Snowflake,
+    },
+    VersionUpgrade {
+        instance_uuid: InstanceUuid,
+        from: String,
+        to: String,
+    },
+    FsOperation {
+        /// `None` for operations on the global filesystem
+        instance_uuid: Option<InstanceUuid>,
+        kind: FsOperationKind,
+        paths: Vec<PathBuf>,
+    },
+    ModpackInstall {
+        instance_uuid: InstanceUuid,
+        modpack_name: String,
+        mod_count: Option<u32>,
+    },
+    WorldOptimize {
+        instance_uuid: InstanceUuid,
+        world: String,
+    },
+    /// Players are being warned before the server stops
+    GracefulStop {
+        instance_uuid: InstanceUuid,
+        seconds: u32,
+    },
+    JavaDownload {
+        instance_uuid: InstanceUuid,
+        major_version: u32,
+    },
+    MacroRun {
+        instance_uuid: InstanceUuid,
+        macro_name: String,
+        pid: MacroPID,
+    },
+    InstanceExport {
+        instance_uuid: InstanceUuid,
+    },
+    InstanceMigration {
+        instance_uuid: InstanceUuid,
+        /// The directory the instance is moved under
+        destination: PathBuf,
+    },
+}
+
+impl ProgressionStartValue {
+    /// The instance the progression is about, if any
+    pub fn instance_uuid(&self) -> Option<&InstanceUuid> {
+        match self {
+            ProgressionStartValue::InstanceCreation { instance_uuid }
+            | ProgressionStartValue::InstanceDelete { instance_uuid }
+            | ProgressionStartValue::BackupRun { instance_uuid, .. }
+            | ProgressionStartValue::Restore { instance_uuid, .. }
+            | ProgressionStartValue::VersionUpgrade { instance_uuid, .. }
+            | ProgressionStartValue::ModpackInstall { instance_uuid, .. }
+            | ProgressionStartValue::WorldOptimize { instance_uuid, .. }
+            | ProgressionStartValue::GracefulStop { instance_uuid, .. }
+            | ProgressionStartValue::JavaDownload { instance_uuid, .. }
+            | ProgressionStartValue::MacroRun { instance_uuid, .. }
+            | ProgressionStartValue::InstanceMigration { instance_uuid, .. }
+            | ProgressionStartValue::InstanceExport { instance_uuid } => Some(instance_uuid),
+            ProgressionStartValue::FsOperation { instance_uuid, .. } => instance_uuid.as_ref(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum FsOperationKind {
+    Zip,
+    Unzip,
+    Upload,
+    Copy,
+    Move,
+    Delete,
+    Search,
+}
+
+/// Starts a progression for a long-running operation.
+///
+/// Unlike [`Event::new_progression_event_start`] a typed value is required, so frontends can
+/// tell what the progression is about without parsing its name
+#[must_use]
+pub struct ProgressionStartBuilder {
+    progression_name: String,
+    total: Option<f64>,
+    inner: ProgressionStartValue,
+    caused_by: CausedBy,
+}
+
+impl ProgressionStartBuilder {
+    pub fn new(
+        progression_name: impl AsRef<str>,
+        inner: ProgressionStartValue,
+        caused_by: CausedBy,
+    ) -> Self {
+        Self {
+            progression_name: progression_name.as_ref().to_string(),
+            total: None,
+            inner,
+            caused_by,
+        }
+    }
+
+    pub fn total(mut self, total: f64) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    pub fn build(self) -> (Event, ProgressionEventID) {
+        Event::new_progression_event_start(
+            self.progression_name,
+            self.total,
+            Some(self.inner),
+            self.caused_by,
+        )
+    }
 }
 
 // the backend will keep exactly 1 copy of ProgressionStart, and 1 copy of ProgressionUpdate OR ProgressionEnd
@@ -302,6 +560,9 @@ impl ProgressionEventID {
 pub struct ProgressionEvent {
     event_id: Snowflake,
     progression_event_inner: ProgressionEventInner,
+    /// The API request that started the operation, see [`crate::request_context`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
@@ -310,6 +571,15 @@ pub struct PlayitggRunnerEvent {
     pub playitgg_runner_event_inner: PlayitggRunnerEventInner,
 }
 
+/// The daemon settings changed, so other sessions can show the new values
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct DaemonSettingsEvent {
+    pub settings: DaemonSettings,
+    /// Names of the settings the update changed
+    pub changed: Vec<String>,
+}
+
 impl ProgressionEvent {
     pub fn event_id(&self) -> Snowflake {
         self.event_id
@@ -317,6 +587,9 @@ impl ProgressionEvent {
     pub fn progression_event_inner(&self) -> &ProgressionEventInner {
         &self.progression_event_inner
     }
+    pub fn request_id(&self) -> Option<&String> {
+        self.request_id.as_ref()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
@@ -331,6 +604,7 @@ pub enum EventInner {
     FSEvent(FSEvent),
     ProgressionEvent(ProgressionEvent),
     PlayitggRunnerEvent(PlayitggRunnerEvent),
+    DaemonSettingsEvent(DaemonSettingsEvent),
 }
 
 impl AsRef<EventInner> for EventInner {
@@ -352,6 +626,8 @@ pub enum CausedBy {
     User { user_id: UserId, user_name: String },
     Instance { instance_uuid: InstanceUuid },
     Macro { macro_pid: MacroPID },
+    Schedule { task_id: String },
+    Trigger { trigger_id: String },
     System,
     Unknown,
 }
@@ -431,6 +707,18 @@ impl Event {
             _ => None,
         }
     }
+    /// File watch events come in bursts and are only useful live, so they aren't buffered or
+    /// written to the database
+    pub fn is_event_file_change(&self) -> bool {
+        matches!(
+            &self.event_inner,
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_event_inner: InstanceEventInner::FileChanged { .. },
+                ..
+            })
+        )
+    }
+
     pub fn get_instance_uuid(&self) -> Option<InstanceUuid> {
         match &self.event_inner {
             EventInner::InstanceEvent(instance_event) => Some(instance_event.instance_uuid.clone()),
@@ -518,6 +806,8 @@ impl Event {
             caused_by: CausedBy::System,
         }
     }
+    /// New operations should go through [`ProgressionStartBuilder`], `inner` is only optional
+    /// for macros and events persisted before typed values existed
     #[must_use]
     pub fn new_progression_event_start(
         progression_name: impl AsRef<str>,
@@ -537,6 +827,7 @@ impl Event {
                         total,
                         inner,
                     },
+                    request_id: current_request_id(),
                 }),
                 caused_by,
             },
@@ -558,6 +849,7 @@ impl Event {
                     progress_message: progress_message.as_ref().to_string(),
                     progress,
                 },
+                request_id: current_request_id(),
             }),
             caused_by: CausedBy::System,
         }
@@ -579,11 +871,38 @@ impl Event {
                     message: message.map(|s| s.as_ref().to_string()),
                     inner,
                 },
+                request_id: current_request_id(),
             }),
             caused_by: CausedBy::System,
         }
     }
 
+    /// Ends a progression whose operation was cancelled through [`crate::cancellation`]
+    pub fn new_progression_event_cancelled(
+        event_id: ProgressionEventID,
+        instance_uuid: Option<InstanceUuid>,
+    ) -> Event {
+        Event::new_progression_event_end(
+            event_id,
+            false,
+            Some("Cancelled"),
+            Some(ProgressionEndValue::Cancelled { instance_uuid }),
+        )
+    }
+
+    pub fn new_daemon_settings_event(
+        settings: DaemonSettings,
+        changed: Vec<String>,
+        caused_by: CausedBy,
+    ) -> Event {
+        Event {
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            event_inner: EventInner::DaemonSettingsEvent(DaemonSettingsEvent { settings, changed }),
+            caused_by,
+        }
+    }
+
     pub fn new_macro_detach_event(macro_pid: MacroPID) -> Event {
         Event {
             details: "".to_string(),
@@ -597,3 +916,237 @@ impl Event {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::t_configurable::{Game, MinecraftVariant};
+
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug>(
+        value: T,
+    ) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{json}");
+    }
+
+    #[test]
+    fn test_progression_values_round_trip() {
+        let instance_uuid = InstanceUuid::default();
+        let backup_id = Snowflake::default();
+        for value in [
+            ProgressionStartValue::InstanceCreation {
+                instance_uuid: instance_uuid.clone(),
+            },
+            ProgressionStartValue::InstanceDelete {
+                instance_uuid: instance_uuid.clone(),
+            },
+            ProgressionStartValue::BackupRun {
+                instance_uuid: instance_uuid.clone(),
+                backup_id,
+                scheduled: true,
+            },
+            ProgressionStartValue::Restore {
+                instance_uuid: instance_uuid.clone(),
+                backup_id,
+            },
+            ProgressionStartValue::VersionUpgrade {
+                instance_uuid: instance_uuid.clone(),
+                from: "1.19.4".to_string(),
+                to: "1.20.1".to_string(),
+            },
+            ProgressionStartValue::FsOperation {
+                instance_uuid: None,
+                kind: FsOperationKind::Unzip,
+                paths: vec![PathBuf::from("world.zip")],
+            },
+            ProgressionStartValue::ModpackInstall {
+                instance_uuid: instance_uuid.clone(),
+                modpack_name: "pack".to_string(),
+                mod_count: Some(120),
+            },
+            ProgressionStartValue::WorldOptimize {
+                instance_uuid: instance_uuid.clone(),
+                world: "world".to_string(),
+            },
+            ProgressionStartValue::GracefulStop {
+                instance_uuid: instance_uuid.clone(),
+                seconds: 60,
+            },
+            ProgressionStartValue::JavaDownload {
+                instance_uuid: instance_uuid.clone(),
+                major_version: 17,
+            },
+            ProgressionStartValue::InstanceExport {
+                instance_uuid: instance_uuid.clone(),
+            },
+            ProgressionStartValue::InstanceMigration {
+                instance_uuid: instance_uuid.clone(),
+                destination: PathBuf::from("/mnt/disk2"),
+            },
+        ] {
+            round_trip(value);
+        }
+        for value in [
+            ProgressionEndValue::InstanceCreation(InstanceInfo {
+                uuid: instance_uuid.clone(),
+                name: "test".to_string(),
+                game_type: Game::MinecraftJava {
+                    variant: MinecraftVariant::Vanilla,
+                },
+                description: "".to_string(),
+                version: "1.20.1".to_string(),
+                port: 25565,
+                creation_time: 0,
+                path: "/instances/test".to_string(),
+                auto_start: false,
+                restart_on_crash: false,
+                state: State::Stopped,
+                player_count: None,
+                max_player_count: None,
+                player_list: None,
+                launch_command: None,
+                eula_accepted: Some(true),
+                slug: "test".to_string(),
+                last_started: None,
+                last_started_at: None,
+                last_stopped_at: None,
+                uptime_seconds: None,
+                last_exit_code: None,
+                last_exit_reason: None,
+                tags: Vec::new(),
+                maintenance: None,
+                degraded: false,
+            }),
+            ProgressionEndValue::InstanceDelete {
+                instance_uuid: instance_uuid.clone(),
+                archive_path: None,
+            },
+            ProgressionEndValue::FSOperationCompleted {
+                instance_uuid: instance_uuid.clone(),
+                success: true,
+                message: "done".to_string(),
+            },
+            ProgressionEndValue::BackupRun {
+                instance_uuid: instance_uuid.clone(),
+                backup_id,
+                size: 1024,
+            },
+            ProgressionEndValue::Restore {
+                instance_uuid: instance_uuid.clone(),
+                backup_id,
+                previous_files: PathBuf::from("backups/pre-restore-0"),
+            },
+            ProgressionEndValue::VersionUpgrade {
+                instance_uuid: instance_uuid.clone(),
+                version: "1.20.1".to_string(),
+            },
+            ProgressionEndValue::FsOperation {
+                instance_uuid: Some(instance_uuid.clone()),
+                kind: FsOperationKind::Zip,
+                paths: vec![PathBuf::from("world.zip")],
+                bytes: 4096,
+            },
+            ProgressionEndValue::ModpackInstall {
+                instance_uuid: instance_uuid.clone(),
+                installed_mods: 120,
+            },
+            ProgressionEndValue::WorldOptimize {
+                instance_uuid: instance_uuid.clone(),
+                world: "world".to_string(),
+                chunks: 4096,
+            },
+            ProgressionEndValue::GracefulStop {
+                instance_uuid: instance_uuid.clone(),
+                stopped: true,
+            },
+            ProgressionEndValue::JavaDownload {
+                instance_uuid: instance_uuid.clone(),
+                major_version: 17,
+                path: PathBuf::from("bin/java/jre17/bin/java"),
+            },
+            ProgressionEndValue::InstanceExport {
+                instance_uuid: instance_uuid.clone(),
+                bytes: 4096,
+            },
+            ProgressionEndValue::InstanceMigration {
+                instance_uuid: instance_uuid.clone(),
+                path: PathBuf::from("/mnt/disk2/survival"),
+                bytes: 4096,
+            },
+            ProgressionEndValue::Cancelled {
+                instance_uuid: Some(instance_uuid),
+            },
+        ] {
+            round_trip(value);
+        }
+    }
+
+    #[test]
+    fn test_old_progression_events_deserialize() {
+        let start: ProgressionEventInner = serde_json::from_str(
+            r#"{"type":"ProgressionStart","progression_name":"Deleting instance test","total":10.0,"inner":null}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            start,
+            ProgressionEventInner::ProgressionStart {
+                progression_name: "Deleting instance test".to_string(),
+                total: Some(10.0),
+                inner: None,
+            }
+        );
+        let start: ProgressionEventInner = serde_json::from_str(
+            r#"{"type":"ProgressionStart","progression_name":"Setting up","total":null,"inner":{"type":"InstanceCreation","instance_uuid":"INSTANCE_test"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            start,
+            ProgressionEventInner::ProgressionStart {
+                progression_name: "Setting up".to_string(),
+                total: None,
+                inner: Some(ProgressionStartValue::InstanceCreation {
+                    instance_uuid: InstanceUuid::from("INSTANCE_test".to_string()),
+                }),
+            }
+        );
+        let end: ProgressionEventInner = serde_json::from_str(
+            r#"{"type":"ProgressionEnd","success":true,"message":"Zipped","inner":{"type":"FSOperationCompleted","instance_uuid":"INSTANCE_test","success":true,"message":"Zipped"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            end,
+            ProgressionEventInner::ProgressionEnd {
+                inner: Some(ProgressionEndValue::FSOperationCompleted { .. }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_progression_start_builder() {
+        let instance_uuid = InstanceUuid::default();
+        let (event, event_id) = ProgressionStartBuilder::new(
+            "Deleting instance",
+            ProgressionStartValue::InstanceDelete {
+                instance_uuid: instance_uuid.clone(),
+            },
+            CausedBy::System,
+        )
+        .total(10.0)
+        .build();
+        match event.event_inner {
+            EventInner::ProgressionEvent(progression) => {
+                assert_eq!(progression.event_id(), event_id.inner());
+                assert_eq!(
+                    progression.progression_event_inner,
+                    ProgressionEventInner::ProgressionStart {
+                        progression_name: "Deleting instance".to_string(),
+                        total: Some(10.0),
+                        inner: Some(ProgressionStartValue::InstanceDelete { instance_uuid }),
+                    }
+                );
+            }
+            _ => panic!("expected a progression event"),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Optional subsystems gated behind cargo features
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum Feature {
+    Webdav,
+    S3Backups,
+    Thumbnails,
+    OtlpExport,
+    ModProviders,
+    MockGames,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 6] = [
+        Feature::Webdav,
+        Feature::S3Backups,
+        Feature::Thumbnails,
+        Feature::OtlpExport,
+        Feature::ModProviders,
+        Feature::MockGames,
+    ];
+
+    /// Name of the cargo feature
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Webdav => "webdav",
+            Feature::S3Backups => "s3-backups",
+            Feature::Thumbnails => "thumbnails",
+            Feature::OtlpExport => "otlp-export",
+            Feature::ModProviders => "mod-providers",
+            Feature::MockGames => "mock-games",
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Feature::Webdav => cfg!(feature = "webdav"),
+            Feature::S3Backups => cfg!(feature = "s3-backups"),
+            Feature::Thumbnails => cfg!(feature = "thumbnails"),
+            Feature::OtlpExport => cfg!(feature = "otlp-export"),
+            Feature::ModProviders => cfg!(feature = "mod-providers"),
+            Feature::MockGames => cfg!(feature = "mock-games"),
+        }
+    }
+
+    /// Routes owned by the subsystem, relative to `/api/v1`.
+    ///
+    /// When the feature is compiled out these are answered by stub handlers instead
+    pub fn routes(&self) -> &'static [&'static str] {
+        match self {
+            Feature::Webdav => &["/webdav", "/webdav/*path"],
+            Feature::S3Backups => &[
+                "/instance/:uuid/backups/s3",
+                "/instance/:uuid/backups/s3/:backup_id/upload",
+                "/instance/:uuid/backups/s3/:backup_id/restore",
+                "/system/backup_target/test",
+            ],
+            // the icon route also reads and removes icons, so its upload refuses by itself
+            Feature::Thumbnails => &[],
+            Feature::OtlpExport => &["/otlp", "/otlp/*path"],
+            Feature::ModProviders => &[
+                "/instance/:uuid/mods/search",
+                "/instance/:uuid/mods/install",
+                "/instance/:uuid/mods/updates",
+            ],
+            Feature::MockGames => &["/mock_game", "/mock_game/*path"],
+        }
+    }
+
+    /// What a request for the feature gets when it's compiled out
+    pub fn disabled_error(&self) -> Error {
+        Error {
+            kind: ErrorKind::FeatureDisabled,
+            source: eyre!(
+                "This build of lodestone core was compiled without the \"{}\" feature",
+                self.name()
+            ),
+        }
+    }
+}
+
+pub fn enabled_features() -> Vec<Feature> {
+    Feature::ALL
+        .into_iter()
+        .filter(|feature| feature.is_enabled())
+        .collect()
+}
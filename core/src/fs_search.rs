@@ -0,0 +1,323 @@
+//! Searching the text files of a directory for a literal or a regex
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::util::extended_length_path;
+
+pub const DEFAULT_MAX_RESULTS: usize = 500;
+const MAX_RESULTS_LIMIT: usize = 5000;
+/// A search over a huge world folder returns what it found so far instead of hanging
+const MAX_SCAN_DURATION: Duration = Duration::from_secs(30);
+const MAX_SCAN_BYTES: u64 = 1024 * 1024 * 1024;
+/// Larger files are logs or data nobody greps through the dashboard
+const MAX_FILE_SIZE: u64 = 32 * 1024 * 1024;
+/// How much of a file is looked at to tell whether it is binary
+const SNIFF_LENGTH: usize = 8 * 1024;
+const MAX_EXCERPT_CHARS: usize = 200;
+const LINES_PER_CHECKPOINT: u64 = 10_000;
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct SearchQuery {
+    pub query: String,
+    /// Treat `query` as a regex instead of a literal
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    /// Only files whose path relative to the instance matches, e.g. `config/**/*.toml`
+    pub glob: Option<String>,
+    pub max_results: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct SearchMatch {
+    /// Relative to the searched directory
+    pub path: PathBuf,
+    /// Starts at 1
+    pub line_number: u64,
+    /// The matching line, cut short if it's long
+    pub line: String,
+}
+
+/// Why a search returned before looking at every file
+#[derive(Debug, Clone, Copy, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchLimit {
+    MaxResults,
+    Duration,
+    Bytes,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct SearchResult {
+    pub matches: Vec<SearchMatch>,
+    pub files_scanned: u64,
+    pub bytes_scanned: u64,
+    /// `None` if every file was searched
+    pub limit_reached: Option<SearchLimit>,
+}
+
+enum Matcher {
+    Literal {
+        needle: String,
+        case_sensitive: bool,
+    },
+    Regex(fancy_regex::Regex),
+}
+
+impl Matcher {
+    fn new(query: &SearchQuery) -> Result<Self, Error> {
+        if query.query.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The search query is empty"),
+            });
+        }
+        if !query.regex {
+            return Ok(Matcher::Literal {
+                needle: if query.case_sensitive {
+                    query.query.clone()
+                } else {
+                    query.query.to_lowercase()
+                },
+                case_sensitive: query.case_sensitive,
+            });
+        }
+        fancy_regex::RegexBuilder::new(&query.query)
+            .case_insensitive(!query.case_sensitive)
+            .build()
+            .map(Matcher::Regex)
+            .map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid regex: {}", e),
+            })
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            Matcher::Literal {
+                needle,
+                case_sensitive: true,
+            } => line.contains(needle.as_str()),
+            Matcher::Literal { needle, .. } => line.to_lowercase().contains(needle.as_str()),
+            // a regex that backtracks too much on a line doesn't match it
+            Matcher::Regex(regex) => regex.is_match(line).unwrap_or(false),
+        }
+    }
+}
+
+fn excerpt(line: &str) -> String {
+    match line.char_indices().nth(MAX_EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+/// Files with a NUL byte near the start are taken as binary, like git and grep do
+fn is_binary(reader: &mut BufReader<std::fs::File>) -> std::io::Result<bool> {
+    let buf = reader.fill_buf()?;
+    Ok(buf[..buf.len().min(SNIFF_LENGTH)].contains(&0))
+}
+
+/// Searches the text files under `root` line by line, in file name order.
+///
+/// `checkpoint` runs before every file with the time spent so far, an error from it aborts the
+/// search. Blocks, so it belongs on a blocking thread
+pub fn search_dir(
+    root: &Path,
+    query: &SearchQuery,
+    checkpoint: &mut dyn FnMut(Duration, &SearchResult) -> Result<(), Error>,
+) -> Result<SearchResult, Error> {
+    let matcher = Matcher::new(query)?;
+    let glob = query
+        .glob
+        .as_deref()
+        .map(|glob| {
+            globset::Glob::new(glob)
+                .map(|glob| glob.compile_matcher())
+                .map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid glob: {}", e),
+                })
+        })
+        .transpose()?;
+    let max_results = query
+        .max_results
+        .unwrap_or(DEFAULT_MAX_RESULTS)
+        .clamp(1, MAX_RESULTS_LIMIT);
+    let started = Instant::now();
+    let mut result = SearchResult {
+        matches: Vec::new(),
+        files_scanned: 0,
+        bytes_scanned: 0,
+        limit_reached: None,
+    };
+
+    'files: for entry in walkdir::WalkDir::new(extended_length_path(root))
+        .sort_by_file_name()
+        .into_iter()
+        // unreadable directories are skipped like unreadable files
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        checkpoint(started.elapsed(), &result)?;
+        if started.elapsed() > MAX_SCAN_DURATION {
+            result.limit_reached = Some(SearchLimit::Duration);
+            break;
+        }
+        if result.bytes_scanned > MAX_SCAN_BYTES {
+            result.limit_reached = Some(SearchLimit::Bytes);
+            break;
+        }
+        let relative = match entry.path().strip_prefix(extended_length_path(root)).ok() {
+            Some(relative) => relative.to_path_buf(),
+            None => continue,
+        };
+        if let Some(glob) = &glob {
+            if !glob.is_match(&relative) {
+                continue;
+            }
+        }
+        match entry.metadata() {
+            Ok(metadata) if metadata.len() <= MAX_FILE_SIZE => {}
+            _ => continue,
+        }
+        let mut reader = match std::fs::File::open(entry.path()) {
+            Ok(file) => BufReader::new(file),
+            Err(_) => continue,
+        };
+        if is_binary(&mut reader).unwrap_or(true) {
+            continue;
+        }
+        result.files_scanned += 1;
+        let mut line = Vec::new();
+        let mut line_number = 0;
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => result.bytes_scanned += read as u64,
+            }
+            line_number += 1;
+            // a single large file can take a while on its own
+            if line_number % LINES_PER_CHECKPOINT == 0 {
+                checkpoint(started.elapsed(), &result)?;
+                if started.elapsed() > MAX_SCAN_DURATION {
+                    result.limit_reached = Some(SearchLimit::Duration);
+                    break 'files;
+                }
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+            if matcher.is_match(text) {
+                result.matches.push(SearchMatch {
+                    path: relative.clone(),
+                    line_number,
+                    line: excerpt(text),
+                });
+                if result.matches.len() >= max_results {
+                    result.limit_reached = Some(SearchLimit::MaxResults);
+                    break 'files;
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(query: &str) -> SearchQuery {
+        SearchQuery {
+            query: query.to_string(),
+            regex: false,
+            case_sensitive: false,
+            glob: None,
+            max_results: None,
+        }
+    }
+
+    fn search(root: &Path, query: &SearchQuery) -> SearchResult {
+        search_dir(root, query, &mut |_, _| Ok(())).unwrap()
+    }
+
+    #[test]
+    fn test_search_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("config/mod")).unwrap();
+        std::fs::write(
+            dir.path().join("server.properties"),
+            "motd=hi\nspawn-protection=16\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("config/mod/settings.toml"),
+            "# Spawn-Protection is handled by the server\nradius = 3\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("world.dat"), b"spawn-protection\0\x01").unwrap();
+
+        let result = search(dir.path(), &query("spawn-protection"));
+        assert_eq!(
+            result.matches,
+            vec![
+                SearchMatch {
+                    path: PathBuf::from("config/mod/settings.toml"),
+                    line_number: 1,
+                    line: "# Spawn-Protection is handled by the server".to_string(),
+                },
+                SearchMatch {
+                    path: PathBuf::from("server.properties"),
+                    line_number: 2,
+                    line: "spawn-protection=16".to_string(),
+                },
+            ]
+        );
+        assert_eq!(result.files_scanned, 2);
+        assert_eq!(result.limit_reached, None);
+
+        let mut case_sensitive = query("spawn-protection");
+        case_sensitive.case_sensitive = true;
+        case_sensitive.glob = Some("**/*.toml".to_string());
+        assert!(search(dir.path(), &case_sensitive).matches.is_empty());
+
+        let mut regex = query(r"^\w+ = \d+$");
+        regex.regex = true;
+        regex.max_results = Some(1);
+        let result = search(dir.path(), &regex);
+        assert_eq!(result.matches[0].line, "radius = 3");
+        assert_eq!(result.limit_reached, Some(SearchLimit::MaxResults));
+    }
+
+    #[test]
+    fn test_search_dir_rejects_bad_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(search_dir(dir.path(), &query(""), &mut |_, _| Ok(())).is_err());
+        let mut regex = query("(unclosed");
+        regex.regex = true;
+        assert!(search_dir(dir.path(), &regex, &mut |_, _| Ok(())).is_err());
+        let mut glob = query("a");
+        glob.glob = Some("[".to_string());
+        assert!(search_dir(dir.path(), &glob, &mut |_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_excerpt() {
+        assert_eq!(excerpt("short"), "short");
+        let long = "é".repeat(MAX_EXCERPT_CHARS + 10);
+        assert_eq!(excerpt(&long).chars().count(), MAX_EXCERPT_CHARS + 1);
+    }
+}
@@ -0,0 +1,397 @@
+//! Watching instance directories for changes made outside the file manager, such as by the
+//! server or a macro, so clients can refresh their listings
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, FileChange, InstanceEvent, InstanceEventInner};
+use crate::trash::TRASH_DIR_NAME;
+use crate::types::{InstanceUuid, Snowflake};
+
+/// Region files and logs change every few seconds while the server runs
+pub const DEFAULT_EXCLUDES: &[&str] = &["logs/**", "**/*.mca", "**/*.mcr"];
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Changes to a path within this window are merged into one event
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Past this many changed paths in one window the rest are dropped, a client seeing that many
+/// should reload the whole listing anyway
+const MAX_PENDING_CHANGES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct WatchStatus {
+    /// Unix timestamp after which the watcher stops unless renewed
+    pub expires_at: i64,
+    pub exclude: Vec<String>,
+}
+
+struct ActiveWatch {
+    exclude: Vec<String>,
+    /// Pushed back whenever the watch is renewed
+    deadline: Arc<std::sync::Mutex<Instant>>,
+    stop: CancellationToken,
+}
+
+/// The running watchers, at most one per instance
+#[derive(Clone, Default)]
+pub struct FsWatchManager {
+    watches: Arc<DashMap<InstanceUuid, ActiveWatch>>,
+}
+
+impl FsWatchManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts watching `root`, or renews the running watcher if it excludes the same globs.
+    ///
+    /// The watcher stops on its own once `idle_timeout` passes without a renewal
+    pub fn watch(
+        &self,
+        instance_uuid: &InstanceUuid,
+        instance_name: String,
+        root: &Path,
+        exclude: Vec<String>,
+        idle_timeout: Duration,
+        event_broadcaster: EventBroadcaster,
+    ) -> Result<WatchStatus, Error> {
+        let idle_timeout = idle_timeout.min(MAX_IDLE_TIMEOUT);
+        let expires_at = chrono::Utc::now().timestamp() + idle_timeout.as_secs() as i64;
+        if let Some(active) = self.watches.get(instance_uuid) {
+            if active.exclude == exclude && !active.stop.is_cancelled() {
+                *active.deadline.lock().unwrap() = Instant::now() + idle_timeout;
+                return Ok(WatchStatus {
+                    expires_at,
+                    exclude,
+                });
+            }
+        }
+        let exclude_set = exclude_set(&exclude)?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // the receiver is gone once the watch stopped
+            let _ = tx.send(event);
+        })
+        .context("Failed to create file watcher")?;
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .context(format!("Failed to watch {}", root.display()))?;
+
+        let deadline = Arc::new(std::sync::Mutex::new(Instant::now() + idle_timeout));
+        let stop = CancellationToken::new();
+        if let Some(previous) = self.watches.insert(
+            instance_uuid.clone(),
+            ActiveWatch {
+                exclude: exclude.clone(),
+                deadline: deadline.clone(),
+                stop: stop.clone(),
+            },
+        ) {
+            previous.stop.cancel();
+        }
+
+        let watches = self.watches.clone();
+        let instance_uuid = instance_uuid.clone();
+        let root = root.to_owned();
+        tokio::spawn(async move {
+            run_watch(
+                watcher,
+                rx,
+                &root,
+                &exclude_set,
+                &deadline,
+                &stop,
+                |change, path| {
+                    event_broadcaster.send(Event {
+                        event_inner: EventInner::InstanceEvent(InstanceEvent {
+                            instance_uuid: instance_uuid.clone(),
+                            instance_name: instance_name.clone(),
+                            instance_event_inner: InstanceEventInner::FileChanged { change, path },
+                        }),
+                        details: "".to_string(),
+                        snowflake: Snowflake::default(),
+                        caused_by: CausedBy::System,
+                    })
+                },
+            )
+            .await;
+            stop.cancel();
+            // a newer watch may have taken its place
+            watches.remove_if(&instance_uuid, |_, active| active.stop.is_cancelled());
+            debug!("Stopped watching {}", root.display());
+        });
+        Ok(WatchStatus {
+            expires_at,
+            exclude,
+        })
+    }
+
+    pub fn unwatch(&self, instance_uuid: &InstanceUuid) -> bool {
+        match self.watches.remove(instance_uuid) {
+            Some((_, active)) => {
+                active.stop.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn exclude_set(exclude: &[String]) -> Result<globset::GlobSet, Error> {
+    let mut builder = globset::GlobSetBuilder::new();
+    // lodestone's own bookkeeping isn't interesting to a file manager
+    let trash = [TRASH_DIR_NAME.to_string(), format!("{}/**", TRASH_DIR_NAME)];
+    for glob in exclude.iter().chain(trash.iter()) {
+        builder.add(globset::Glob::new(glob).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid glob {}: {}", glob, e),
+        })?);
+    }
+    builder.build().map_err(|e| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Invalid exclude globs: {}", e),
+    })
+}
+
+/// What the paths of a notify event went through, a rename is a removal and a creation
+fn changes(event: notify::Event) -> Vec<(FileChange, PathBuf)> {
+    let change = match event.kind {
+        EventKind::Create(_) => FileChange::Created,
+        EventKind::Remove(_) => FileChange::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileChange::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => FileChange::Created,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            return paths
+                .next()
+                .map(|from| (FileChange::Removed, from))
+                .into_iter()
+                .chain(paths.next().map(|to| (FileChange::Created, to)))
+                .collect();
+        }
+        EventKind::Modify(_) | EventKind::Any => FileChange::Modified,
+        EventKind::Access(_) | EventKind::Other => return Vec::new(),
+    };
+    event.paths.into_iter().map(|path| (change, path)).collect()
+}
+
+/// Folds a later change to the same path into an earlier one, `None` if they cancel out
+fn merge(earlier: FileChange, later: FileChange) -> Option<FileChange> {
+    match (earlier, later) {
+        (FileChange::Created, FileChange::Removed) => None,
+        (FileChange::Created, _) => Some(FileChange::Created),
+        (FileChange::Removed, FileChange::Created) => Some(FileChange::Modified),
+        (_, later) => Some(later),
+    }
+}
+
+async fn run_watch(
+    // dropping it stops the watch
+    _watcher: notify::RecommendedWatcher,
+    mut rx: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    root: &Path,
+    exclude: &globset::GlobSet,
+    deadline: &std::sync::Mutex<Instant>,
+    stop: &CancellationToken,
+    mut emit: impl FnMut(FileChange, PathBuf),
+) {
+    let mut pending: HashMap<PathBuf, FileChange> = HashMap::new();
+    // insertion order, so events go out in the order things happened
+    let mut order: Vec<PathBuf> = Vec::new();
+    let mut flush_at: Option<Instant> = None;
+    loop {
+        let idle_deadline = *deadline.lock().unwrap();
+        tokio::select! {
+            event = rx.recv() => {
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    // errors are usually about a subdirectory deleted mid-walk, the rest of the
+                    // tree is still watched
+                    Some(Err(e)) => {
+                        warn!("File watcher error for {}: {}", root.display(), e);
+                        continue;
+                    }
+                    None => break,
+                };
+                for (change, path) in changes(event) {
+                    let relative = match path.strip_prefix(root) {
+                        Ok(relative) if !relative.as_os_str().is_empty() => relative.to_path_buf(),
+                        _ => continue,
+                    };
+                    if exclude.is_match(&relative) {
+                        continue;
+                    }
+                    match pending.get(&relative).copied() {
+                        Some(earlier) => match merge(earlier, change) {
+                            Some(merged) => {
+                                pending.insert(relative, merged);
+                            }
+                            None => {
+                                pending.remove(&relative);
+                                order.retain(|path| path != &relative);
+                            }
+                        },
+                        None if pending.len() < MAX_PENDING_CHANGES => {
+                            order.push(relative.clone());
+                            pending.insert(relative, change);
+                        }
+                        None => {}
+                    }
+                }
+                if flush_at.is_none() && !pending.is_empty() {
+                    flush_at = Some(Instant::now() + DEBOUNCE);
+                }
+            }
+            _ = tokio::time::sleep_until(flush_at.unwrap_or(idle_deadline)), if flush_at.is_some() => {
+                for path in order.drain(..) {
+                    if let Some(change) = pending.remove(&path) {
+                        emit(change, path);
+                    }
+                }
+                flush_at = None;
+            }
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                // renewed while asleep
+                if *deadline.lock().unwrap() <= Instant::now() {
+                    break;
+                }
+            }
+            _ = stop.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, RemoveKind};
+
+    fn event(kind: EventKind, paths: &[&str]) -> notify::Event {
+        paths.iter().fold(notify::Event::new(kind), |event, path| {
+            event.add_path(PathBuf::from(path))
+        })
+    }
+
+    #[test]
+    fn test_changes() {
+        assert_eq!(
+            changes(event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/i/a.txt", "/i/b.txt"]
+            )),
+            vec![
+                (FileChange::Removed, PathBuf::from("/i/a.txt")),
+                (FileChange::Created, PathBuf::from("/i/b.txt")),
+            ]
+        );
+        assert_eq!(
+            changes(event(EventKind::Create(CreateKind::File), &["/i/a.txt"])),
+            vec![(FileChange::Created, PathBuf::from("/i/a.txt"))]
+        );
+        assert!(changes(event(
+            EventKind::Access(notify::event::AccessKind::Any),
+            &["/i/a.txt"]
+        ))
+        .is_empty());
+        assert_eq!(
+            changes(event(EventKind::Remove(RemoveKind::Folder), &["/i/c"])),
+            vec![(FileChange::Removed, PathBuf::from("/i/c"))]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(
+            merge(FileChange::Created, FileChange::Modified),
+            Some(FileChange::Created)
+        );
+        assert_eq!(merge(FileChange::Created, FileChange::Removed), None);
+        assert_eq!(
+            merge(FileChange::Removed, FileChange::Created),
+            Some(FileChange::Modified)
+        );
+        assert_eq!(
+            merge(FileChange::Modified, FileChange::Removed),
+            Some(FileChange::Removed)
+        );
+    }
+
+    #[test]
+    fn test_exclude_set() {
+        let set = exclude_set(
+            &DEFAULT_EXCLUDES
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        assert!(set.is_match("logs/latest.log"));
+        assert!(set.is_match("world/region/r.0.0.mca"));
+        assert!(set.is_match(".lodestone_trash/1/server.properties"));
+        assert!(!set.is_match("server.properties"));
+        assert!(exclude_set(&["[".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_watch_debounces() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let root = PathBuf::from("/instance");
+        let exclude = exclude_set(&["logs/**".to_string()]).unwrap();
+        let deadline = std::sync::Mutex::new(Instant::now() + Duration::from_secs(60));
+        let stop = CancellationToken::new();
+        for (kind, path) in [
+            (EventKind::Create(CreateKind::File), "/instance/a.txt"),
+            (EventKind::Modify(ModifyKind::Any), "/instance/a.txt"),
+            (
+                EventKind::Modify(ModifyKind::Any),
+                "/instance/logs/latest.log",
+            ),
+            (EventKind::Create(CreateKind::File), "/instance/tmp"),
+            (EventKind::Remove(RemoveKind::File), "/instance/tmp"),
+            (EventKind::Remove(RemoveKind::Folder), "/instance/world"),
+        ] {
+            tx.send(Ok(event(kind, &[path]))).unwrap();
+        }
+        let watcher = notify::recommended_watcher(|_| {}).unwrap();
+        let mut emitted = Vec::new();
+        let stop_after_flush = stop.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(DEBOUNCE * 4).await;
+            stop_after_flush.cancel();
+        });
+        run_watch(
+            watcher,
+            rx,
+            &root,
+            &exclude,
+            &deadline,
+            &stop,
+            |change, path| emitted.push((change, path)),
+        )
+        .await;
+        assert_eq!(
+            emitted,
+            vec![
+                (FileChange::Created, PathBuf::from("a.txt")),
+                (FileChange::Removed, PathBuf::from("world")),
+            ]
+        );
+    }
+}
@@ -1,11 +1,23 @@
-use std::path::PathBuf;
+use std::net::{IpAddr, TcpListener};
+use std::path::{Path, PathBuf};
 
-use color_eyre::eyre::Context;
-use serde::{Deserialize, Serialize};
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::io::AsyncWriteExt;
 use ts_rs::TS;
 
-use crate::{error::Error, event_broadcaster::EventBroadcaster};
+use crate::backups::BackupSchedule;
+use crate::http_config::parse_origin;
+use crate::prelude::lodestone_path;
+use crate::traits::t_configurable::manifest::{SettingValidationError, SettingValidationErrors};
+use crate::trash::DEFAULT_TRASH_RETENTION_DAYS;
+use crate::{
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+};
+
+/// Directory in the data directory the archives of deleted instances go to by default
+pub const GRAVEYARD_DIR_NAME: &str = "graveyard";
 
 #[derive(Serialize, Deserialize, Clone, TS)]
 #[ts(export)]
@@ -15,6 +27,410 @@ pub struct GlobalSettingsData {
     pub domain: Option<String>,
     #[serde(default)]
     pub playit_enabled: bool,
+    /// Longest absolute path, in bytes, the fs endpoints will create
+    #[serde(default = "default_max_path_length")]
+    pub max_path_length: u32,
+    /// Largest request body, in bytes, the multipart upload endpoint accepts
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+    #[serde(default)]
+    pub login_rate_limit: LoginRateLimit,
+    #[serde(default)]
+    pub port_range: PortRange,
+    /// Whether `/openapi.json` and the Swagger UI at `/docs` are served
+    #[serde(default)]
+    pub api_docs_enabled: bool,
+    /// Address the API listens on, every interface when unset. Read at startup
+    #[serde(default)]
+    #[ts(type = "string | null")]
+    pub bind_address: Option<IpAddr>,
+    /// Where instances are stored, `instances` in the data directory when unset. Read at startup
+    #[serde(default)]
+    pub instances_path: Option<PathBuf>,
+    /// Origins allowed to call the API, replacing those of `LODESTONE_CORS_ORIGINS` while set
+    #[serde(default)]
+    pub cors_allowed_origins: Option<Vec<String>>,
+    /// Schedule new instances start with
+    #[serde(default)]
+    pub backup_defaults: BackupSchedule,
+    /// Days deleted instance files stay in the trash, 0 keeps them until purged by hand
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+    /// Where instances deleted with `archive` leave their final export, `graveyard` in the data
+    /// directory when unset
+    #[serde(default)]
+    pub graveyard_path: Option<PathBuf>,
+    #[serde(default)]
+    pub remote_backup_target: Option<RemoteBackupTarget>,
+    /// Secret key of the remote backup target, kept out of [`DaemonSettings`] so it is never
+    /// sent back
+    #[serde(default)]
+    pub remote_backup_secret: Option<String>,
+    #[serde(default)]
+    pub max_running_instances: Option<u32>,
+    /// Sum of the `max_ram` of running instances, in MB
+    #[serde(default)]
+    pub max_total_ram: Option<u32>,
+}
+
+/// The settings of the daemon itself, answered by `GET /system/settings`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct DaemonSettings {
+    #[ts(type = "string | null")]
+    pub bind_address: Option<IpAddr>,
+    pub instances_path: Option<PathBuf>,
+    pub port_range: PortRange,
+    pub max_upload_size: u64,
+    pub cors_allowed_origins: Option<Vec<String>>,
+    pub backup_defaults: BackupSchedule,
+    pub trash_retention_days: u32,
+    pub graveyard_path: Option<PathBuf>,
+    pub remote_backup_target: Option<RemoteBackupTarget>,
+    /// Instances that may run at once, no limit when unset
+    pub max_running_instances: Option<u32>,
+    /// Heap, in MB, running instances may reserve together through their `max_ram`, no limit
+    /// when unset
+    pub max_total_ram: Option<u32>,
+}
+
+impl DaemonSettings {
+    /// Settings only read at startup, a change to them waits for a restart
+    pub const RESTART_REQUIRED: &'static [&'static str] = &["bind_address", "instances_path"];
+
+    /// Names of the settings that differ from `other`
+    pub fn changed(&self, other: &DaemonSettings) -> Vec<String> {
+        let mut changed = Vec::new();
+        if self.bind_address != other.bind_address {
+            changed.push("bind_address");
+        }
+        if self.instances_path != other.instances_path {
+            changed.push("instances_path");
+        }
+        if self.port_range != other.port_range {
+            changed.push("port_range");
+        }
+        if self.max_upload_size != other.max_upload_size {
+            changed.push("max_upload_size");
+        }
+        if self.cors_allowed_origins != other.cors_allowed_origins {
+            changed.push("cors_allowed_origins");
+        }
+        if self.backup_defaults != other.backup_defaults {
+            changed.push("backup_defaults");
+        }
+        if self.trash_retention_days != other.trash_retention_days {
+            changed.push("trash_retention_days");
+        }
+        if self.graveyard_path != other.graveyard_path {
+            changed.push("graveyard_path");
+        }
+        if self.remote_backup_target != other.remote_backup_target {
+            changed.push("remote_backup_target");
+        }
+        if self.max_running_instances != other.max_running_instances {
+            changed.push("max_running_instances");
+        }
+        if self.max_total_ram != other.max_total_ram {
+            changed.push("max_total_ram");
+        }
+        changed.into_iter().map(String::from).collect()
+    }
+}
+
+/// Tells a field set to null, which resets the setting, from one left out
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Why a directory setting can't take `path`
+fn directory_error(path: &Option<PathBuf>) -> Option<&'static str> {
+    match path {
+        Some(path) if !path.is_absolute() => Some("must be an absolute path"),
+        Some(path) if path.exists() && !path.is_dir() => Some("isn't a directory"),
+        _ => None,
+    }
+}
+
+/// A partial update of [`DaemonSettings`], fields left out are kept
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonSettingsPatch {
+    #[serde(default, deserialize_with = "nullable")]
+    pub bind_address: Option<Option<IpAddr>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub instances_path: Option<Option<PathBuf>>,
+    pub port_range: Option<PortRange>,
+    pub max_upload_size: Option<u64>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub cors_allowed_origins: Option<Option<Vec<String>>>,
+    pub backup_defaults: Option<BackupSchedule>,
+    pub trash_retention_days: Option<u32>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub graveyard_path: Option<Option<PathBuf>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub remote_backup_target: Option<Option<RemoteBackupTarget>>,
+    /// Write only, see [`GlobalSettingsData::remote_backup_secret`]
+    #[serde(default, deserialize_with = "nullable")]
+    pub remote_backup_secret: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_running_instances: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_total_ram: Option<Option<u32>>,
+}
+
+impl DaemonSettingsPatch {
+    /// `settings` with the patch applied, every refused field is reported together
+    pub fn apply(self, settings: &DaemonSettings) -> Result<DaemonSettings, Error> {
+        let mut patched = settings.clone();
+        let mut errors = Vec::new();
+        if let Some(bind_address) = self.bind_address {
+            // the address must belong to this host, else the next start can't listen on it
+            match bind_address {
+                Some(ip) if TcpListener::bind((ip, 0)).is_err() => {
+                    errors.push(SettingValidationError::new(
+                        "bind_address",
+                        format!("{} isn't an address of this host", ip),
+                    ))
+                }
+                _ => patched.bind_address = bind_address,
+            }
+        }
+        if let Some(instances_path) = self.instances_path {
+            match directory_error(&instances_path) {
+                Some(error) => errors.push(SettingValidationError::new("instances_path", error)),
+                None => patched.instances_path = instances_path,
+            }
+        }
+        if let Some(port_range) = self.port_range {
+            match port_range.validate() {
+                Ok(()) => patched.port_range = port_range,
+                Err(e) => errors.push(SettingValidationError::new("port_range", e.source)),
+            }
+        }
+        if let Some(max_upload_size) = self.max_upload_size {
+            if max_upload_size == 0 {
+                errors.push(SettingValidationError::new(
+                    "max_upload_size",
+                    "must be at least 1 byte",
+                ));
+            } else {
+                patched.max_upload_size = max_upload_size;
+            }
+        }
+        if let Some(cors_allowed_origins) = self.cors_allowed_origins {
+            match cors_allowed_origins {
+                Some(origins) => match origins
+                    .iter()
+                    .map(|origin| parse_origin(origin.trim()))
+                    .collect::<Result<Vec<_>, _>>()
+                {
+                    // kept the way browsers send them, so they compare equal
+                    Ok(origins) => {
+                        patched.cors_allowed_origins = Some(
+                            origins
+                                .iter()
+                                .filter_map(|origin| origin.to_str().ok())
+                                .map(String::from)
+                                .collect(),
+                        )
+                    }
+                    Err(e) => errors.push(SettingValidationError::new("cors_allowed_origins", e)),
+                },
+                None => patched.cors_allowed_origins = None,
+            }
+        }
+        if let Some(backup_defaults) = self.backup_defaults {
+            match backup_defaults.validate() {
+                Ok(()) => patched.backup_defaults = backup_defaults,
+                Err(e) => errors.push(SettingValidationError::new("backup_defaults", e.source)),
+            }
+        }
+        if let Some(trash_retention_days) = self.trash_retention_days {
+            patched.trash_retention_days = trash_retention_days;
+        }
+        if let Some(graveyard_path) = self.graveyard_path {
+            match directory_error(&graveyard_path) {
+                Some(error) => errors.push(SettingValidationError::new("graveyard_path", error)),
+                None => patched.graveyard_path = graveyard_path,
+            }
+        }
+        if let Some(remote_backup_target) = self.remote_backup_target {
+            match remote_backup_target
+                .as_ref()
+                .map(RemoteBackupTarget::validate)
+            {
+                Some(Err(e)) => errors.push(SettingValidationError::new(
+                    "remote_backup_target",
+                    e.source,
+                )),
+                _ => patched.remote_backup_target = remote_backup_target,
+            }
+        }
+        // a limit of 0 would refuse every start, leaving the setting unset is how it's lifted
+        if let Some(max_running_instances) = self.max_running_instances {
+            if max_running_instances == Some(0) {
+                errors.push(SettingValidationError::new(
+                    "max_running_instances",
+                    "must be at least 1",
+                ));
+            } else {
+                patched.max_running_instances = max_running_instances;
+            }
+        }
+        if let Some(max_total_ram) = self.max_total_ram {
+            if max_total_ram == Some(0) {
+                errors.push(SettingValidationError::new(
+                    "max_total_ram",
+                    "must be at least 1 MB",
+                ));
+            } else {
+                patched.max_total_ram = max_total_ram;
+            }
+        }
+        if !errors.is_empty() {
+            return Err(SettingValidationErrors(errors).into());
+        }
+        Ok(patched)
+    }
+}
+
+/// An S3 compatible bucket backups are uploaded to once taken
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct RemoteBackupTarget {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com` or the address of a MinIO server
+    pub endpoint: String,
+    #[serde(default = "default_remote_backup_region")]
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    /// Put in front of every object key, `lodestone/` keeps backups under that folder
+    #[serde(default)]
+    pub prefix: String,
+    /// Addresses the bucket as `{endpoint}/{bucket}` rather than `{bucket}.{endpoint}`, most
+    /// self hosted servers need it
+    #[serde(default = "default_path_style")]
+    pub path_style: bool,
+}
+
+impl RemoteBackupTarget {
+    pub fn validate(&self) -> Result<(), Error> {
+        let endpoint = url::Url::parse(&self.endpoint).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Endpoint isn't a valid URL: {}", e),
+        })?;
+        if !["http", "https"].contains(&endpoint.scheme()) || endpoint.host_str().is_none() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Endpoint must be an http or https URL"),
+            });
+        }
+        if self.bucket.is_empty() || self.bucket.contains('/') {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Bucket must be a bucket name"),
+            });
+        }
+        if self.access_key_id.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Access key ID is missing"),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn default_remote_backup_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_path_style() -> bool {
+    true
+}
+
+/// Ports lodestone picks from when it allocates or suggests one, both ends included
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct PortRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u32) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.start == 0 || self.start > self.end || self.end > 65535 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Port range must be a non-empty range between 1 and 65535"),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for PortRange {
+    fn default() -> Self {
+        Self {
+            start: 25565,
+            end: 26565,
+        }
+    }
+}
+
+/// Limits on login attempts, counted separately per source IP and per username
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct LoginRateLimit {
+    /// Attempts allowed within the window before logins are answered with 429
+    pub max_attempts: u32,
+    pub window_seconds: u64,
+    /// Consecutive failures after which a username is locked out
+    pub lockout_threshold: u32,
+    pub lockout_seconds: u64,
+}
+
+impl Default for LoginRateLimit {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            window_seconds: 60,
+            lockout_threshold: 5,
+            lockout_seconds: 15 * 60,
+        }
+    }
+}
+
+pub const DEFAULT_MAX_PATH_LENGTH: u32 = 1024;
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+fn default_max_path_length() -> u32 {
+    DEFAULT_MAX_PATH_LENGTH
+}
+
+fn default_max_upload_size() -> u64 {
+    DEFAULT_MAX_UPLOAD_SIZE
+}
+
+fn default_trash_retention_days() -> u32 {
+    DEFAULT_TRASH_RETENTION_DAYS
+}
+
+/// The instances path set through the daemon settings, read before anything else is loaded
+/// since the other paths are set up from it
+pub fn configured_instances_path(path_to_global_settings: &Path) -> Option<PathBuf> {
+    let content = std::fs::read(path_to_global_settings).ok()?;
+    let settings: serde_json::Value = serde_json::from_slice(&content).ok()?;
+    serde_json::from_value(settings.get("instances_path")?.clone()).ok()?
 }
 
 impl Default for GlobalSettingsData {
@@ -24,6 +440,21 @@ impl Default for GlobalSettingsData {
             safe_mode: true,
             domain: None,
             playit_enabled: true,
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            login_rate_limit: LoginRateLimit::default(),
+            port_range: PortRange::default(),
+            api_docs_enabled: false,
+            bind_address: None,
+            instances_path: None,
+            cors_allowed_origins: None,
+            backup_defaults: BackupSchedule::default(),
+            trash_retention_days: DEFAULT_TRASH_RETENTION_DAYS,
+            graveyard_path: None,
+            remote_backup_target: None,
+            remote_backup_secret: None,
+            max_running_instances: None,
+            max_total_ram: None,
         }
     }
 }
@@ -165,6 +596,173 @@ impl GlobalSettings {
     pub fn playit_enabled(&self) -> bool {
         self.global_settings_data.playit_enabled
     }
+
+    pub async fn set_max_path_length(&mut self, max_path_length: u32) -> Result<(), Error> {
+        let old_max_path_length = self.global_settings_data.max_path_length;
+        self.global_settings_data.max_path_length = max_path_length;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.max_path_length = old_max_path_length;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn max_path_length(&self) -> usize {
+        self.global_settings_data.max_path_length as usize
+    }
+
+    pub async fn set_max_upload_size(&mut self, max_upload_size: u64) -> Result<(), Error> {
+        let old_max_upload_size = self.global_settings_data.max_upload_size;
+        self.global_settings_data.max_upload_size = max_upload_size;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.max_upload_size = old_max_upload_size;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn max_upload_size(&self) -> u64 {
+        self.global_settings_data.max_upload_size
+    }
+
+    pub async fn set_login_rate_limit(
+        &mut self,
+        login_rate_limit: LoginRateLimit,
+    ) -> Result<(), Error> {
+        let old_login_rate_limit = self.global_settings_data.login_rate_limit.clone();
+        self.global_settings_data.login_rate_limit = login_rate_limit;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.login_rate_limit = old_login_rate_limit;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn login_rate_limit(&self) -> LoginRateLimit {
+        self.global_settings_data.login_rate_limit.clone()
+    }
+
+    pub async fn set_port_range(&mut self, port_range: PortRange) -> Result<(), Error> {
+        let old_port_range = self.global_settings_data.port_range;
+        self.global_settings_data.port_range = port_range;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.port_range = old_port_range;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn port_range(&self) -> PortRange {
+        self.global_settings_data.port_range
+    }
+
+    pub async fn set_api_docs_enabled(&mut self, api_docs_enabled: bool) -> Result<(), Error> {
+        let old_api_docs_enabled = self.global_settings_data.api_docs_enabled;
+        self.global_settings_data.api_docs_enabled = api_docs_enabled;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.api_docs_enabled = old_api_docs_enabled;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn api_docs_enabled(&self) -> bool {
+        self.global_settings_data.api_docs_enabled
+    }
+
+    pub async fn set_daemon_settings(&mut self, settings: DaemonSettings) -> Result<(), Error> {
+        let old_data = self.global_settings_data.clone();
+        let data = &mut self.global_settings_data;
+        data.bind_address = settings.bind_address;
+        data.instances_path = settings.instances_path;
+        data.port_range = settings.port_range;
+        data.max_upload_size = settings.max_upload_size;
+        data.cors_allowed_origins = settings.cors_allowed_origins;
+        data.backup_defaults = settings.backup_defaults;
+        data.trash_retention_days = settings.trash_retention_days;
+        data.graveyard_path = settings.graveyard_path;
+        data.remote_backup_target = settings.remote_backup_target;
+        data.max_running_instances = settings.max_running_instances;
+        data.max_total_ram = settings.max_total_ram;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data = old_data;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn daemon_settings(&self) -> DaemonSettings {
+        let data = &self.global_settings_data;
+        DaemonSettings {
+            bind_address: data.bind_address,
+            instances_path: data.instances_path.clone(),
+            port_range: data.port_range,
+            max_upload_size: data.max_upload_size,
+            cors_allowed_origins: data.cors_allowed_origins.clone(),
+            backup_defaults: data.backup_defaults,
+            trash_retention_days: data.trash_retention_days,
+            graveyard_path: data.graveyard_path.clone(),
+            remote_backup_target: data.remote_backup_target.clone(),
+            max_running_instances: data.max_running_instances,
+            max_total_ram: data.max_total_ram,
+        }
+    }
+
+    pub async fn set_remote_backup_secret(&mut self, secret: Option<String>) -> Result<(), Error> {
+        let old_secret = self.global_settings_data.remote_backup_secret.clone();
+        self.global_settings_data.remote_backup_secret = secret;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.remote_backup_secret = old_secret;
+                Err(e)
+            }
+        }
+    }
+
+    /// The remote backup target with its secret key, `None` until both are set
+    pub fn remote_backup_credentials(&self) -> Option<(RemoteBackupTarget, String)> {
+        let data = &self.global_settings_data;
+        Some((
+            data.remote_backup_target.clone()?,
+            data.remote_backup_secret.clone()?,
+        ))
+    }
+
+    pub fn bind_address(&self) -> Option<IpAddr> {
+        self.global_settings_data.bind_address
+    }
+
+    pub fn cors_allowed_origins(&self) -> Option<Vec<String>> {
+        self.global_settings_data.cors_allowed_origins.clone()
+    }
+
+    pub fn backup_defaults(&self) -> BackupSchedule {
+        self.global_settings_data.backup_defaults
+    }
+
+    pub fn trash_retention_days(&self) -> u32 {
+        self.global_settings_data.trash_retention_days
+    }
+
+    pub fn graveyard_path(&self) -> PathBuf {
+        self.global_settings_data
+            .graveyard_path
+            .clone()
+            .unwrap_or_else(|| lodestone_path().join(GRAVEYARD_DIR_NAME))
+    }
 }
 
 impl AsRef<GlobalSettingsData> for GlobalSettings {
@@ -232,4 +830,124 @@ mod tests {
 
         assert_eq!(global_settings.core_name(), "test_core_name");
     }
+
+    #[tokio::test]
+    async fn test_daemon_settings_patch() {
+        use super::*;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path_to_global_settings = temp_dir.path().join("global_settings.json");
+        let (event_broadcaster, _) = EventBroadcaster::new(10);
+        let mut global_settings = GlobalSettings::new(
+            path_to_global_settings.clone(),
+            event_broadcaster,
+            GlobalSettingsData::default(),
+        );
+        global_settings.load_from_file().await.unwrap();
+        let settings = global_settings.daemon_settings();
+
+        let patch: DaemonSettingsPatch = serde_json::from_value(serde_json::json!({
+            "instances_path": temp_dir.path().join("servers"),
+            "max_upload_size": 1024,
+            "cors_allowed_origins": ["https://dashboard.example.com/"],
+        }))
+        .unwrap();
+        let patched = patch.apply(&settings).unwrap();
+        assert_eq!(
+            patched.changed(&settings),
+            vec!["instances_path", "max_upload_size", "cors_allowed_origins"]
+        );
+        assert_eq!(
+            patched.cors_allowed_origins,
+            Some(vec!["https://dashboard.example.com".to_string()])
+        );
+        global_settings
+            .set_daemon_settings(patched.clone())
+            .await
+            .unwrap();
+        assert_eq!(global_settings.max_upload_size(), 1024);
+        assert_eq!(
+            configured_instances_path(&path_to_global_settings),
+            Some(temp_dir.path().join("servers"))
+        );
+
+        // null resets a setting, a field left out is kept
+        let patch: DaemonSettingsPatch =
+            serde_json::from_value(serde_json::json!({ "instances_path": null })).unwrap();
+        let reset = patch.apply(&patched).unwrap();
+        assert_eq!(reset.instances_path, None);
+        assert_eq!(reset.max_upload_size, 1024);
+
+        let patch: DaemonSettingsPatch = serde_json::from_value(serde_json::json!({
+            "max_running_instances": 4,
+            "max_total_ram": 16384,
+        }))
+        .unwrap();
+        let limited = patch.apply(&patched).unwrap();
+        assert_eq!(
+            limited.changed(&patched),
+            vec!["max_running_instances", "max_total_ram"]
+        );
+        assert_eq!(limited.max_total_ram, Some(16384));
+
+        // every refused field is reported
+        let mut backup_defaults = serde_json::to_value(BackupSchedule::default()).unwrap();
+        backup_defaults["interval_hours"] = 0.into();
+        let patch: DaemonSettingsPatch = serde_json::from_value(serde_json::json!({
+            "instances_path": "relative/servers",
+            "graveyard_path": "relative/graveyard",
+            "port_range": { "start": 30000, "end": 20000 },
+            "cors_allowed_origins": ["*"],
+            "backup_defaults": backup_defaults,
+            "max_running_instances": 0,
+            "remote_backup_target": {
+                "endpoint": "https://s3.example.com",
+                "bucket": "backups/lodestone",
+                "access_key_id": "key",
+            },
+        }))
+        .unwrap();
+        let error = patch.apply(&patched).unwrap_err();
+        let errors = error
+            .source
+            .downcast_ref::<SettingValidationErrors>()
+            .unwrap();
+        assert_eq!(errors.0.len(), 7);
+
+        // the secret is stored but never part of the settings sent back
+        let patch: DaemonSettingsPatch = serde_json::from_value(serde_json::json!({
+            "remote_backup_target": {
+                "endpoint": "http://127.0.0.1:9000",
+                "bucket": "backups",
+                "access_key_id": "key",
+            },
+            "remote_backup_secret": "hunter2",
+        }))
+        .unwrap();
+        let with_target = patch.apply(&patched).unwrap();
+        assert_eq!(with_target.changed(&patched), vec!["remote_backup_target"]);
+        global_settings
+            .set_daemon_settings(with_target)
+            .await
+            .unwrap();
+        assert!(global_settings.remote_backup_credentials().is_none());
+        global_settings
+            .set_remote_backup_secret(Some("hunter2".to_string()))
+            .await
+            .unwrap();
+        let (target, secret) = global_settings.remote_backup_credentials().unwrap();
+        assert_eq!(target.region, "us-east-1");
+        assert!(target.path_style);
+        assert_eq!(secret, "hunter2");
+        assert!(!serde_json::to_string(&global_settings.daemon_settings())
+            .unwrap()
+            .contains("hunter2"));
+
+        assert!(
+            serde_json::from_value::<DaemonSettingsPatch>(serde_json::json!({
+                "instance_path": "/srv/servers"
+            }))
+            .is_err()
+        );
+    }
 }
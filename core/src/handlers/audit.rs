@@ -0,0 +1,111 @@
+use axum::{
+    extract::{Path, Query},
+    routing::get,
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+
+use crate::{
+    audit::{AuditEntry, DEFAULT_AUDIT_PAGE_SIZE, MAX_AUDIT_PAGE_SIZE},
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    types::{InstanceUuid, Snowflake},
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    /// At most `MAX_AUDIT_PAGE_SIZE`
+    limit: Option<usize>,
+    /// Id of the last entry of the previous page, only older entries are returned
+    before: Option<Snowflake>,
+}
+
+impl AuditQuery {
+    fn limit(&self) -> Result<usize, Error> {
+        match self.limit {
+            Some(limit) if limit == 0 || limit > MAX_AUDIT_PAGE_SIZE => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("limit must be between 1 and {}", MAX_AUDIT_PAGE_SIZE),
+            }),
+            Some(limit) => Ok(limit),
+            None => Ok(DEFAULT_AUDIT_PAGE_SIZE),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/audit",
+    tag = "audit",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_instance_audit(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<AuditQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<AuditEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    if requester
+        .try_action(&UserAction::ViewAudit, safe_mode)
+        .is_err()
+    {
+        requester.try_action(&UserAction::AccessSetting(uuid.clone()), safe_mode)?;
+    }
+    if !state.instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    state
+        .audit_log
+        .read(&uuid, query.limit()?, query.before)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "audit",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_audit(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<AuditQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<AuditEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewAudit,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state
+        .audit_log
+        .read_all(query.limit()?, query.before)
+        .await
+        .map(Json)
+}
+
+pub fn get_audit_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/audit", get(get_instance_audit))
+        .route("/audit", get(get_audit))
+        .with_state(state)
+}
@@ -1,8 +1,21 @@
-use crate::traits::t_configurable::TConfigurable;
 use crate::{port_manager::PortStatus, AppState};
 use axum::{extract::Path, routing::get, Json, Router};
+
+use super::util::instances_named;
+
 /// Check the status of a port
 /// Note: this function is not cheap
+#[utoipa::path(
+    get,
+    path = "/check/port/{port}",
+    tag = "checks",
+    params(
+        ("port" = u32, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
 pub async fn get_port_status(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(port): Path<u32>,
@@ -10,18 +23,24 @@ pub async fn get_port_status(
     Json(state.port_manager.lock().await.port_status(port))
 }
 
-/// Check whether a name is in use
+/// Check whether a name is in use, names that slug the same count as the same name
 /// Note: this function is not cheap
+#[utoipa::path(
+    get,
+    path = "/check/name/{name}",
+    tag = "checks",
+    params(
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success", body = bool),
+    ),
+)]
 pub async fn is_name_in_use(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(name): Path<String>,
 ) -> Json<bool> {
-    for entry in state.instances.iter() {
-        if entry.value().name().await == name {
-            return Json(true);
-        }
-    }
-    Json(false)
+    Json(!instances_named(&state, &name).await.is_empty())
 }
 
 pub fn get_checks_routes(state: AppState) -> Router {
@@ -1,7 +1,14 @@
 use std::env;
+use std::path::PathBuf;
 
-use crate::{prelude::VERSION, AppState};
+use crate::{
+    error::Error,
+    prelude::{path_to_instances, VERSION},
+    startup::StartupTimings,
+    AppState,
+};
 use axum::{routing::get, Json, Router};
+use axum_auth::AuthBearer;
 use serde::{Deserialize, Serialize};
 use sysinfo::{CpuExt, DiskExt, System, SystemExt};
 
@@ -19,8 +26,22 @@ pub struct CoreInfo {
     uuid: String,
     core_name: String,
     up_since: i64,
+    /// Frontends show a banner, the data is seeded and discarded on exit
+    demo_mode: bool,
+    /// Port the API is served on, not necessarily the default when several daemons share a host
+    port: u16,
+    /// How long each phase of startup took, absent until startup finished
+    startup: Option<StartupTimings>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/info",
+    tag = "core_info",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
 pub async fn get_core_info(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Json<CoreInfo> {
@@ -50,11 +71,54 @@ pub async fn get_core_info(
         core_name: state.global_settings.lock().await.core_name(),
         uuid: state.uuid.clone(),
         up_since: state.up_since,
+        demo_mode: state.demo_mode,
+        port: state.http_port,
+        startup: state.startup_timings.lock().await.clone(),
     })
 }
 
+/// What an operator debugging the daemon wants to know, kept out of the unauthenticated
+/// `/info` since it exposes paths on the host
+#[derive(Serialize, Deserialize)]
+pub struct DaemonInfo {
+    version: semver::Version,
+    /// Commit the daemon was built from, absent when built outside a git checkout
+    git_hash: Option<String>,
+    /// Seconds since the daemon started
+    uptime: i64,
+    os: String,
+    arch: String,
+    path_to_instances: PathBuf,
+}
+
+#[utoipa::path(
+    get,
+    path = "/info/daemon",
+    tag = "core_info",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_daemon_info(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DaemonInfo>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(DaemonInfo {
+        version: VERSION.with(|v| v.clone()),
+        git_hash: option_env!("LODESTONE_GIT_HASH").map(String::from),
+        uptime: chrono::Utc::now().timestamp() - state.up_since,
+        os: env::consts::OS.to_string(),
+        arch: env::consts::ARCH.to_string(),
+        path_to_instances: path_to_instances().clone(),
+    }))
+}
+
 pub fn get_core_info_routes(state: AppState) -> Router {
     Router::new()
         .route("/info", get(get_core_info))
+        .route("/info/daemon", get(get_daemon_info))
         .with_state(state)
 }
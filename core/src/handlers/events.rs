@@ -1,42 +1,71 @@
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    extract::{ws::WebSocket, Path, Query, WebSocketUpgrade},
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Path, Query, WebSocketUpgrade,
+    },
     response::Response,
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 
+use async_trait::async_trait;
 use color_eyre::eyre::eyre;
-use futures::{SinkExt, StreamExt};
+use futures::{stream::SplitSink, Sink, SinkExt, Stream, StreamExt};
 use ringbuffer::{AllocRingBuffer, RingBufferExt};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::output_types::ClientEvent;
-use crate::types::InstanceUuid;
+use crate::types::{InstanceUuid, Snowflake, TimeRange};
 use crate::{
-    auth::{user::UsersManager, user_id::UserId},
+    auth::{
+        user::{User, UserAction, UsersManager},
+        user_id::UserId,
+    },
     db::read::search_events,
     error::{Error, ErrorKind},
     events::EventQuery,
 };
 
 use crate::{
-    events::{Event, EventInner, UserEventInner},
+    events::{
+        CausedBy, Event, EventInner, InstanceEventInner, ProgressionEndValue,
+        ProgressionEventInner, UserEventInner,
+    },
+    traits::t_server::{State, TServer},
     AppState,
 };
-use serde::Deserialize;
-use tokio::sync::{broadcast::Receiver, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    RwLock,
+};
 use ts_rs::TS;
 
-use super::util::parse_bearer_token;
+use super::util::{check_console_command, game_instance, parse_bearer_token};
 
 #[derive(Deserialize, Clone, Debug, TS)]
 pub struct EventQueryWrapper {
     filter: String,
 }
 
+#[utoipa::path(
+    get,
+    path = "/events/{uuid}/buffer",
+    tag = "events",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_event_buffer(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -74,6 +103,16 @@ pub async fn get_event_buffer(
 }
 
 // TODO implement me
+#[utoipa::path(
+    get,
+    path = "/events/search",
+    tag = "events",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_event_search(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -99,6 +138,19 @@ pub async fn get_event_search(
     search_events(&state.sqlite_pool, query).await.map(Json)
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/console/buffer",
+    tag = "events",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_console_buffer(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -135,9 +187,35 @@ pub async fn get_console_buffer(
 
 #[derive(Deserialize)]
 pub struct WebsocketQuery {
-    token: String,
+    /// Browsers can't set headers on a websocket handshake, so the token can be passed here.
+    /// If absent, the first text message sent over the socket must be the token
+    token: Option<String>,
+}
+
+/// How long a console stream waits for the token when it isn't in the query
+const CONSOLE_STREAM_AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A console line as sent over the console stream
+#[derive(Serialize)]
+struct ConsoleFrame {
+    #[serde(flatten)]
+    event: ClientEvent,
+    /// Unix timestamp in milliseconds
+    timestamp: i64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/events/{uuid}/stream",
+    tag = "events",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn event_stream(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -208,91 +286,860 @@ async fn event_stream_ws(
     }
 }
 
+#[derive(Deserialize)]
+pub struct LiveEventStreamQuery {
+    /// Same as [`WebsocketQuery::token`]
+    token: Option<String>,
+    /// Replays the events after this one before streaming live ones
+    since: Option<Snowflake>,
+}
+
+/// How often the live event stream pings the client and checks its token is still valid
+const LIVE_EVENT_STREAM_PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Most events replayed to a reconnecting client, the newest are kept
+const MAX_REPLAYED_EVENTS: usize = 1000;
+
+/// Streams every event the user may see, console output excluded.
+///
+/// Events of instances the user can't view are left out, progressions included. A stream that
+/// falls behind is closed with code 1013, reconnecting with `since` set to the last event
+/// received replays what it missed
+#[utoipa::path(
+    get,
+    path = "/events/stream",
+    tag = "events",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn live_event_stream(
+    ws: WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<LiveEventStreamQuery>,
+) -> Result<Response, Error> {
+    let token = match query.token.as_deref() {
+        Some(token) => {
+            let token = parse_bearer_token(token).ok_or_else(|| Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("Token error"),
+            })?;
+            if state.users_manager.read().await.try_auth(&token).is_none() {
+                return Err(Error {
+                    kind: ErrorKind::Unauthorized,
+                    source: eyre!("Token error"),
+                });
+            }
+            Some(token)
+        }
+        None => None,
+    };
+    Ok(ws.on_upgrade(move |socket| live_event_stream_ws(socket, state, token, query.since)))
+}
+
+/// Tracks which progressions a stream hides, only their start says which instance they're about
+#[derive(Default)]
+struct EventVisibility {
+    hidden_progressions: HashSet<Snowflake>,
+}
+
+impl EventVisibility {
+    fn allows(&mut self, user: &User, event: &Event) -> bool {
+        let progression = match &event.event_inner {
+            EventInner::ProgressionEvent(progression) => progression,
+            event_inner => return user.can_view_event_inner(event_inner),
+        };
+        let event_id = progression.event_id();
+        match progression.progression_event_inner() {
+            ProgressionEventInner::ProgressionStart {
+                inner: Some(inner), ..
+            } => match inner.instance_uuid() {
+                Some(instance_uuid)
+                    if !user
+                        .can_perform_action(&UserAction::ViewInstance(instance_uuid.clone())) =>
+                {
+                    self.hidden_progressions.insert(event_id);
+                    false
+                }
+                _ => true,
+            },
+            ProgressionEventInner::ProgressionStart { .. } => true,
+            ProgressionEventInner::ProgressionUpdate { .. } => {
+                !self.hidden_progressions.contains(&event_id)
+            }
+            ProgressionEventInner::ProgressionEnd { .. } => {
+                !self.hidden_progressions.remove(&event_id)
+            }
+        }
+    }
+}
+
+/// Events after `since` oldest first, from the event buffer if it reaches back that far and the
+/// database otherwise
+async fn replay_events(state: &AppState, since: Snowflake) -> Vec<Event> {
+    let buffered: Vec<Event> = state.events_buffer.lock().await.iter().cloned().collect();
+    let mut events: Vec<Event> = if buffered
+        .first()
+        .map_or(false, |oldest| oldest.snowflake <= since)
+    {
+        buffered
+            .into_iter()
+            .filter(|event| event.snowflake > since)
+            .collect()
+    } else {
+        let query = EventQuery {
+            event_levels: None,
+            event_types: None,
+            instance_event_types: None,
+            user_event_types: None,
+            event_user_ids: None,
+            event_instance_ids: None,
+            bearer_token: None,
+            time_range: Some(TimeRange {
+                start: since.timestamp_millis(),
+                end: chrono::Utc::now().timestamp_millis(),
+            }),
+        };
+        match search_events(&state.sqlite_pool, query).await {
+            Ok(client_events) => client_events
+                .into_iter()
+                .map(|client_event| Event {
+                    event_inner: client_event.event_inner,
+                    details: client_event.details,
+                    snowflake: client_event.snowflake,
+                    caused_by: client_event.caused_by,
+                })
+                .filter(|event| event.snowflake > since && !event.is_event_console_message())
+                .collect(),
+            Err(e) => {
+                warn!("Failed to read events to replay: {}", e);
+                Vec::new()
+            }
+        }
+    };
+    events.sort_by_key(|event| event.snowflake);
+    if events.len() > MAX_REPLAYED_EVENTS {
+        events.drain(..events.len() - MAX_REPLAYED_EVENTS);
+    }
+    events
+}
+
+async fn send_event(
+    sender: &mut SplitSink<WebSocket, Message>,
+    event: &Event,
+) -> Result<(), axum::Error> {
+    sender
+        .send(Message::Text(
+            serde_json::to_string(&ClientEvent::from(event)).unwrap(),
+        ))
+        .await
+}
+
+async fn live_event_stream_ws(
+    stream: WebSocket,
+    state: AppState,
+    token: Option<String>,
+    since: Option<Snowflake>,
+) {
+    let (mut sender, mut receiver) = stream.split();
+    let token = match token {
+        Some(token) => token,
+        None => {
+            // first message authentication
+            match tokio::time::timeout(CONSOLE_STREAM_AUTH_TIMEOUT, receiver.next()).await {
+                Ok(Some(Ok(Message::Text(token)))) => parse_bearer_token(&token).unwrap_or(token),
+                _ => {
+                    close_with(&mut sender, close_code::POLICY, "Expected token").await;
+                    return;
+                }
+            }
+        }
+    };
+    let mut user = match state.users_manager.read().await.try_auth(&token) {
+        Some(user) => user,
+        None => {
+            close_with(&mut sender, close_code::POLICY, "Token error").await;
+            return;
+        }
+    };
+    // subscribe before replaying so no event falls between the two
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut visibility = EventVisibility::default();
+    let mut replayed = HashSet::new();
+    if let Some(since) = since {
+        for event in replay_events(&state, since).await {
+            replayed.insert(event.snowflake);
+            if visibility.allows(&user, &event) && send_event(&mut sender, &event).await.is_err() {
+                return;
+            }
+        }
+    }
+    let mut ping = tokio::time::interval_at(
+        tokio::time::Instant::now() + LIVE_EVENT_STREAM_PING_INTERVAL,
+        LIVE_EVENT_STREAM_PING_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            event = event_receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        // carrying on would leave a gap the client never hears of
+                        warn!("Live event stream lagged, skipped {} events", skipped);
+                        close_with(
+                            &mut sender,
+                            close_code::AGAIN,
+                            "Missed events, reconnect with since set to the last event received",
+                        )
+                        .await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => {
+                        close_with(&mut sender, close_code::AWAY, "Shutting down").await;
+                        break;
+                    }
+                };
+                if event.is_event_console_message() || replayed.remove(&event.snowflake) {
+                    continue;
+                }
+                // a logout, deletion or permission change of the user takes effect right away
+                if let EventInner::UserEvent(user_event) = &event.event_inner {
+                    if user_event.user_id == user.uid {
+                        match state.users_manager.read().await.try_auth(&token) {
+                            Some(refreshed) => user = refreshed,
+                            None => {
+                                close_with(&mut sender, close_code::POLICY, "Token revoked").await;
+                                break;
+                            }
+                        }
+                    }
+                }
+                if visibility.allows(&user, &event) && send_event(&mut sender, &event).await.is_err() {
+                    debug!("Websocket disconnected");
+                    break;
+                }
+            }
+            _ = ping.tick() => {
+                if state.users_manager.read().await.try_auth(&token).is_none() {
+                    close_with(&mut sender, close_code::POLICY, "Token revoked").await;
+                    break;
+                }
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    debug!("Websocket disconnected");
+                    break;
+                }
+            }
+            ws_msg = receiver.next() => {
+                match ws_msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("Websocket disconnected");
+                        break;
+                    }
+                    // pings are answered by the websocket implementation, nothing else is expected
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/console/stream",
+    tag = "events",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn console_stream(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
     query: Query<WebsocketQuery>,
     Path(uuid): Path<InstanceUuid>,
 ) -> Result<Response, Error> {
-    let users_manager = state.users_manager.read().await;
-
-    let user = parse_bearer_token(query.token.as_str())
-        .and_then(|token| users_manager.try_auth(&token))
-        .ok_or_else(|| Error {
-            kind: ErrorKind::Unauthorized,
-            source: eyre!("Token error"),
-        })?;
-    drop(users_manager);
-    let event_receiver = state.event_broadcaster.subscribe();
+    let user = match query.token.as_deref() {
+        Some(token) => {
+            let users_manager = state.users_manager.read().await;
+            let user = parse_bearer_token(token)
+                .and_then(|token| users_manager.try_auth(&token))
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::Unauthorized,
+                    source: eyre!("Token error"),
+                })?;
+            drop(users_manager);
+            check_console_access(
+                &user,
+                &uuid,
+                UserAction::ReadConsole,
+                state.global_settings.lock().await.safe_mode(),
+            )?;
+            Some(user)
+        }
+        None => None,
+    };
 
     Ok(ws.on_upgrade(move |socket| {
-        console_stream_ws(socket, event_receiver, user.uid, uuid, state.users_manager)
+        let (sender, receiver) = socket.split();
+        console_stream_ws(sender, receiver, state, user, uuid)
     }))
 }
 
-async fn console_stream_ws(
-    stream: WebSocket,
-    mut event_receiver: Receiver<Event>,
-    uid: UserId,
+/// Watching the stream takes `ReadConsole`, writing to it `AccessConsole`
+fn check_console_access(
+    user: &User,
+    uuid: &InstanceUuid,
+    action: fn(InstanceUuid) -> UserAction,
+    safe_mode: bool,
+) -> Result<(), Error> {
+    // the aggregated stream is filtered per event instead
+    if *uuid == "all" {
+        return Ok(());
+    }
+    user.try_action(&action(uuid.clone()), safe_mode)
+}
+
+/// What the console stream needs from the daemon
+#[async_trait]
+trait ConsoleStreamBackend: Send + Sync {
+    async fn authenticate(&self, token: &str) -> Option<User>;
+    async fn user(&self, uid: &UserId) -> Option<User>;
+    async fn safe_mode(&self) -> bool;
+    fn subscribe(&self) -> Receiver<Event>;
+    /// Writes `command` to the instance's stdin if the user's command policy allows it
+    async fn send_command(
+        &self,
+        user: &User,
+        uuid: &InstanceUuid,
+        command: &str,
+    ) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl ConsoleStreamBackend for AppState {
+    async fn authenticate(&self, token: &str) -> Option<User> {
+        self.users_manager.read().await.try_auth(token)
+    }
+
+    async fn user(&self, uid: &UserId) -> Option<User> {
+        self.users_manager.read().await.get_user(uid)
+    }
+
+    async fn safe_mode(&self) -> bool {
+        self.global_settings.lock().await.safe_mode()
+    }
+
+    fn subscribe(&self) -> Receiver<Event> {
+        self.event_broadcaster.subscribe()
+    }
+
+    async fn send_command(
+        &self,
+        user: &User,
+        uuid: &InstanceUuid,
+        command: &str,
+    ) -> Result<(), Error> {
+        check_console_command(self, user, uuid, command).await?;
+        let instance = game_instance(self, uuid)?;
+        instance
+            .send_command(
+                command,
+                CausedBy::User {
+                    user_id: user.uid.clone(),
+                    user_name: user.username.clone(),
+                },
+            )
+            .await
+    }
+}
+
+async fn close_with<S>(sender: &mut S, code: u16, reason: &str)
+where
+    S: Sink<Message> + Unpin,
+{
+    let _ = sender
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_owned().into(),
+        })))
+        .await;
+}
+
+async fn console_stream_ws<Si, St, E>(
+    mut sender: Si,
+    mut receiver: St,
+    backend: impl ConsoleStreamBackend,
+    user: Option<User>,
     uuid: InstanceUuid,
-    users_manager: Arc<RwLock<UsersManager>>,
-) {
-    let (mut sender, mut receiver) = stream.split();
+) where
+    Si: Sink<Message> + Unpin,
+    Si::Error: std::fmt::Display,
+    St: Stream<Item = Result<Message, E>> + Unpin,
+{
+    let uid = match user {
+        Some(user) => user.uid,
+        None => {
+            // first message authentication
+            let token =
+                match tokio::time::timeout(CONSOLE_STREAM_AUTH_TIMEOUT, receiver.next()).await {
+                    Ok(Some(Ok(Message::Text(token)))) => token,
+                    _ => {
+                        close_with(&mut sender, close_code::POLICY, "Expected token").await;
+                        return;
+                    }
+                };
+            let token = parse_bearer_token(&token).unwrap_or(token);
+            let user = match backend.authenticate(&token).await {
+                Some(user) => user,
+                None => {
+                    close_with(&mut sender, close_code::POLICY, "Token error").await;
+                    return;
+                }
+            };
+            if let Err(e) = check_console_access(
+                &user,
+                &uuid,
+                UserAction::ReadConsole,
+                backend.safe_mode().await,
+            ) {
+                close_with(&mut sender, close_code::POLICY, &e.kind.to_string()).await;
+                return;
+            }
+            user.uid
+        }
+    };
+    let mut event_receiver = backend.subscribe();
     loop {
         tokio::select! {
             Ok(event) = event_receiver.recv() => {
                 match &event.event_inner {
                     EventInner::InstanceEvent(instance_event) => {
-                        let user = match users_manager.read().await.get_user(&uid) {
+                        let user = match backend.user(&uid).await {
                             Some(user) => user,
                             None => break,
                         };
                         if event.is_event_console_message() && (instance_event.instance_uuid == uuid || uuid == "all")
                             && user.can_view_event(&event)
                         {
+                            let frame = ConsoleFrame {
+                                timestamp: event.snowflake.timestamp_millis(),
+                                event: ClientEvent::from(&event),
+                            };
                             if let Err(e) = sender
-                                .send(axum::extract::ws::Message::Text(
-                                    serde_json::to_string(&event).unwrap(),
-                                ))
+                                .send(Message::Text(serde_json::to_string(&frame).unwrap()))
                                 .await
                             {
                                 error!("Failed to send event: {}", e);
                                 break;
                             }
+                        } else if instance_event.instance_uuid == uuid
+                            && instance_event.instance_event_inner
+                                == (InstanceEventInner::StateTransition { to: State::Stopped })
+                        {
+                            close_with(&mut sender, close_code::NORMAL, "Instance stopped").await;
+                            break;
                         }
                     }
                     EventInner::UserEvent(user_event) => {
                         match user_event.user_event_inner {
                             UserEventInner::UserLoggedOut | UserEventInner::UserDeleted => {
                                 if user_event.user_id == uid {
+                                    close_with(&mut sender, close_code::POLICY, "User logged out").await;
                                     break;
                                 }
                             },
                             _ => {}
                         }
                     },
+                    EventInner::ProgressionEvent(progression_event) => {
+                        if let ProgressionEventInner::ProgressionEnd {
+                            success: true,
+                            inner: Some(ProgressionEndValue::InstanceDelete { instance_uuid, .. }),
+                            ..
+                        } = progression_event.progression_event_inner()
+                        {
+                            if *instance_uuid == uuid {
+                                close_with(&mut sender, close_code::AWAY, "Instance deleted").await;
+                                break;
+                            }
+                        }
+                    }
                     EventInner::MacroEvent(_) => continue,
-                    EventInner::ProgressionEvent(_) => continue,
                     EventInner::FSEvent(_) => continue,
                     EventInner::PlayitggRunnerEvent(_) => continue,
+                    EventInner::DaemonSettingsEvent(_) => continue,
                 }
             }
-            Some(Ok(ws_msg)) = receiver.next() => {
-                match sender.send(ws_msg).await {
-                    Ok(_) => debug!("Replied to ping"),
-                    Err(_) => break,
-                };
+            ws_msg = receiver.next() => {
+                match ws_msg {
+                    Some(Ok(Message::Text(command))) => {
+                        if let Err(e) = console_stream_command(&backend, &uid, &uuid, &command).await {
+                            if sender
+                                .send(Message::Text(serde_json::to_string(&e).unwrap()))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                    // pings are answered by the websocket implementation
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => {}
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("Websocket disconnected");
+                        break;
+                    }
+                }
             }
         }
     }
 }
 
+/// Writes a text frame received on the console stream to the instance's stdin
+async fn console_stream_command(
+    backend: &impl ConsoleStreamBackend,
+    uid: &UserId,
+    uuid: &InstanceUuid,
+    command: &str,
+) -> Result<(), Error> {
+    let user = backend.user(uid).await.ok_or_else(|| Error {
+        kind: ErrorKind::Unauthorized,
+        source: eyre!("User not found"),
+    })?;
+    if *uuid == "all" {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Cannot send commands to the aggregated console stream"),
+        });
+    }
+    check_console_access(
+        &user,
+        uuid,
+        UserAction::AccessConsole,
+        backend.safe_mode().await,
+    )?;
+    backend.send_command(&user, uuid, command).await
+}
+
+/// Cancels a long-running operation by the id of its progression event
+#[utoipa::path(
+    post,
+    path = "/progression/{event_id}/cancel",
+    tag = "events",
+    params(
+        ("event_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn cancel_progression(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(event_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &state.cancellation_registry.permission(&event_id)?,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state.cancellation_registry.cancel(&event_id)?;
+    Ok(Json(()))
+}
+
 pub fn get_events_routes(state: AppState) -> Router {
     Router::new()
+        .route("/events/stream", get(live_event_stream))
         .route("/events/:uuid/stream", get(event_stream))
         .route("/events/:uuid/buffer", get(get_event_buffer))
         .route("/events/search", get(get_event_search))
+        .route("/progression/:event_id/cancel", post(cancel_progression))
         .route("/instance/:uuid/console/stream", get(console_stream))
         .route("/instance/:uuid/console/buffer", get(get_console_buffer))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::permission::UserPermission;
+    use crate::event_broadcaster::EventBroadcaster;
+    use crate::events::{ProgressionStartValue, UserEvent};
+    use futures::channel::mpsc;
+
+    /// Signs in the users by token, records the commands instead of sending them
+    struct FakeBackend {
+        users: Vec<(String, User)>,
+        /// Taken by the stream, subscribed up front so no event sent by the test is missed
+        events: std::sync::Mutex<Option<Receiver<Event>>>,
+        commands: Arc<std::sync::Mutex<Vec<(InstanceUuid, String)>>>,
+    }
+
+    impl FakeBackend {
+        fn new(users: Vec<(String, User)>, event_broadcaster: &EventBroadcaster) -> Self {
+            Self {
+                users,
+                events: std::sync::Mutex::new(Some(event_broadcaster.subscribe())),
+                commands: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ConsoleStreamBackend for FakeBackend {
+        async fn authenticate(&self, token: &str) -> Option<User> {
+            self.users
+                .iter()
+                .find(|(user_token, _)| user_token == token)
+                .map(|(_, user)| user.clone())
+        }
+
+        async fn user(&self, uid: &UserId) -> Option<User> {
+            self.users
+                .iter()
+                .find(|(_, user)| user.uid == *uid)
+                .map(|(_, user)| user.clone())
+        }
+
+        async fn safe_mode(&self) -> bool {
+            false
+        }
+
+        fn subscribe(&self) -> Receiver<Event> {
+            self.events.lock().unwrap().take().unwrap()
+        }
+
+        async fn send_command(
+            &self,
+            _user: &User,
+            uuid: &InstanceUuid,
+            command: &str,
+        ) -> Result<(), Error> {
+            self.commands
+                .lock()
+                .unwrap()
+                .push((uuid.clone(), command.to_string()));
+            Ok(())
+        }
+    }
+
+    fn console_user(name: &str, read: &[&InstanceUuid], access: &[&InstanceUuid]) -> User {
+        let mut permissions = UserPermission::new();
+        for uuid in read {
+            permissions.can_view_instance.insert((*uuid).clone());
+            permissions
+                .can_read_instance_console
+                .insert((*uuid).clone());
+        }
+        for uuid in access {
+            permissions.can_view_instance.insert((*uuid).clone());
+            permissions
+                .can_access_instance_console
+                .insert((*uuid).clone());
+        }
+        User::new(name.to_string(), "password", false, false, permissions)
+    }
+
+    /// Runs the stream over channels until it ends, returns what it sent
+    async fn run_console_stream(
+        backend: FakeBackend,
+        user: Option<User>,
+        uuid: &InstanceUuid,
+        incoming: Vec<Message>,
+    ) -> Vec<Message> {
+        let (client_tx, client_rx) = mpsc::unbounded::<Result<Message, axum::Error>>();
+        for message in incoming {
+            client_tx.unbounded_send(Ok(message)).unwrap();
+        }
+        // hanging up ends the stream once the messages are handled
+        drop(client_tx);
+        let (server_tx, server_rx) = mpsc::unbounded();
+        console_stream_ws(server_tx, client_rx, backend, user, uuid.clone()).await;
+        server_rx.collect().await
+    }
+
+    fn close_code(message: &Message) -> Option<u16> {
+        match message {
+            Message::Close(Some(frame)) => Some(frame.code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_console_stream_rejects_bad_tokens() {
+        let uuid = InstanceUuid::default();
+        let reader = console_user("reader", &[&uuid], &[]);
+        let stranger = console_user("stranger", &[], &[]);
+        for (token, expected) in [
+            ("Bearer wrong", "Token error"),
+            ("Bearer stranger", "Permission Denied"),
+        ] {
+            let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+            let backend = FakeBackend::new(
+                vec![
+                    ("reader".to_string(), reader.clone()),
+                    ("stranger".to_string(), stranger.clone()),
+                ],
+                &event_broadcaster,
+            );
+            let sent =
+                run_console_stream(backend, None, &uuid, vec![Message::Text(token.to_string())])
+                    .await;
+            assert_eq!(sent.len(), 1);
+            match &sent[0] {
+                Message::Close(Some(frame)) => {
+                    assert_eq!(frame.code, close_code::POLICY);
+                    assert_eq!(frame.reason, expected);
+                }
+                other => panic!("Expected a close frame, got {other:?}"),
+            }
+        }
+
+        // no token at all
+        let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+        let backend = FakeBackend::new(Vec::new(), &event_broadcaster);
+        let sent = run_console_stream(backend, None, &uuid, vec![Message::Binary(vec![1])]).await;
+        assert_eq!(close_code(&sent[0]), Some(close_code::POLICY));
+    }
+
+    #[tokio::test]
+    async fn test_console_stream_filters_by_permission() {
+        let visible = InstanceUuid::default();
+        let hidden = InstanceUuid::default();
+        let reader = console_user("reader", &[&visible], &[]);
+        let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+        let backend = FakeBackend::new(
+            vec![("reader".to_string(), reader.clone())],
+            &event_broadcaster,
+        );
+        for instance_uuid in [&visible, &hidden] {
+            event_broadcaster.send(Event::new_instance_output(
+                instance_uuid.clone(),
+                "server".to_string(),
+                format!("line of {instance_uuid}"),
+            ));
+        }
+        // ends the stream
+        event_broadcaster.send(Event {
+            event_inner: EventInner::UserEvent(UserEvent {
+                user_id: reader.uid.clone(),
+                user_event_inner: UserEventInner::UserLoggedOut,
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::System,
+        });
+
+        // the client keeps the socket open meanwhile
+        let (client_tx, client_rx) = mpsc::unbounded::<Result<Message, axum::Error>>();
+        let (server_tx, server_rx) = mpsc::unbounded();
+        console_stream_ws(
+            server_tx,
+            client_rx,
+            backend,
+            Some(reader),
+            InstanceUuid::from("all".to_string()),
+        )
+        .await;
+        drop(client_tx);
+        let sent: Vec<Message> = server_rx.collect().await;
+        assert_eq!(sent.len(), 2);
+        match &sent[0] {
+            Message::Text(frame) => {
+                assert!(frame.contains(&format!("line of {visible}")));
+                assert!(!frame.contains(&hidden.to_string()));
+            }
+            other => panic!("Expected a console line, got {other:?}"),
+        }
+        assert_eq!(close_code(&sent[1]), Some(close_code::POLICY));
+    }
+
+    #[tokio::test]
+    async fn test_console_stream_sends_commands() {
+        let uuid = InstanceUuid::default();
+        let operator = console_user("operator", &[], &[&uuid]);
+        let reader = console_user("reader", &[&uuid], &[]);
+        let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+        let backend = FakeBackend::new(
+            vec![
+                ("operator".to_string(), operator.clone()),
+                ("reader".to_string(), reader.clone()),
+            ],
+            &event_broadcaster,
+        );
+        let commands = backend.commands.clone();
+        // the first message signs in, the ones after are commands
+        let sent = run_console_stream(
+            backend,
+            None,
+            &uuid,
+            vec![
+                Message::Text("Bearer operator".to_string()),
+                Message::Text("say hi".to_string()),
+                Message::Ping(Vec::new()),
+                Message::Text("list".to_string()),
+            ],
+        )
+        .await;
+        assert!(sent.is_empty());
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                (uuid.clone(), "say hi".to_string()),
+                (uuid.clone(), "list".to_string())
+            ]
+        );
+
+        // reading the console doesn't allow writing to it, the error comes back as a frame
+        let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+        let backend = FakeBackend::new(
+            vec![("reader".to_string(), reader.clone())],
+            &event_broadcaster,
+        );
+        let commands = backend.commands.clone();
+        let sent = run_console_stream(
+            backend,
+            Some(reader),
+            &uuid,
+            vec![Message::Text("stop".to_string())],
+        )
+        .await;
+        assert!(commands.lock().unwrap().is_empty());
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(&sent[0], Message::Text(error) if error.contains("permission")));
+    }
+
+    #[test]
+    fn test_progressions_of_hidden_instances_are_filtered() {
+        let visible = InstanceUuid::default();
+        let hidden = InstanceUuid::default();
+        let mut permissions = UserPermission::new();
+        permissions.can_view_instance.insert(visible.clone());
+        let user = User::new("viewer".to_string(), "password", false, false, permissions);
+        let mut visibility = EventVisibility::default();
+
+        for (instance_uuid, expected) in [(visible, true), (hidden, false)] {
+            let (start, event_id) = Event::new_progression_event_start(
+                "Backing up",
+                None,
+                Some(ProgressionStartValue::BackupRun {
+                    instance_uuid,
+                    backup_id: Snowflake::default(),
+                    scheduled: false,
+                }),
+                CausedBy::System,
+            );
+            let update = Event::new_progression_event_update(&event_id, "Archiving", 1.0);
+            let end = Event::new_progression_event_end(event_id, true, None::<&str>, None);
+            assert_eq!(visibility.allows(&user, &start), expected);
+            assert_eq!(visibility.allows(&user, &update), expected);
+            assert_eq!(visibility.allows(&user, &end), expected);
+        }
+        assert!(visibility.hidden_progressions.is_empty());
+    }
+}
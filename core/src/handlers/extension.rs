@@ -19,6 +19,14 @@ use crate::{
     AppState,
 };
 
+#[utoipa::path(
+    get,
+    path = "/extension/gitstatus",
+    tag = "extension",
+    responses(
+        (status = 200, description = "Success", body = bool),
+    ),
+)]
 async fn is_git_installed() -> Json<bool> {
     Json(which::which("git").is_ok())
 }
@@ -60,6 +68,15 @@ struct FetchManifestRet {
     is_domain_true: bool,
 }
 
+#[utoipa::path(
+    get,
+    path = "/extension/fetchmanifest",
+    tag = "extension",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 async fn fetch_extension_manifest(
     Json(body): Json<ExtensionRequestBody>,
 ) -> Result<Json<FetchManifestRet>, FetchExtensionManifestError> {
@@ -71,6 +88,16 @@ async fn fetch_extension_manifest(
     }))
 }
 
+#[utoipa::path(
+    put,
+    path = "/extension/install",
+    tag = "extension",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn install_extension(
     axum::extract::State(state): axum::extract::State<AppState>,
     Json(body): Json<ExtensionRequestBody>,
@@ -0,0 +1,80 @@
+use axum::{routing::any, Router};
+
+use crate::{
+    error::Error,
+    features::{enabled_features, Feature},
+};
+
+/// Builds the stub router for every feature not in `enabled`.
+///
+/// Takes the feature set as a parameter so any combination can be tested without recompiling
+pub fn feature_stub_routes(enabled: &[Feature]) -> Router {
+    let mut router = Router::new();
+    for feature in Feature::ALL {
+        if enabled.contains(&feature) {
+            continue;
+        }
+        for route in feature.routes() {
+            router = router.route(
+                route,
+                any(move || async move { Err::<(), Error>(feature.disabled_error()) }),
+            );
+        }
+    }
+    router
+}
+
+pub fn get_feature_stub_routes() -> Router {
+    feature_stub_routes(&enabled_features())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use std::net::{SocketAddr, TcpListener};
+
+    async fn serve(router: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(Router::new().nest("/api/v1", router).into_make_service()),
+        );
+        addr
+    }
+
+    fn concrete_route(route: &str) -> String {
+        route
+            .replace(":uuid", "INSTANCE_test")
+            .replace("*path", "some/path")
+    }
+
+    #[tokio::test]
+    async fn test_feature_stub_matrix() {
+        let mut combinations: Vec<Vec<Feature>> = vec![Vec::new(), Feature::ALL.to_vec()];
+        for feature in Feature::ALL {
+            combinations.push(vec![feature]);
+            combinations.push(Feature::ALL.into_iter().filter(|f| *f != feature).collect());
+        }
+        let client = reqwest::Client::new();
+        for enabled in combinations {
+            let addr = serve(feature_stub_routes(&enabled)).await;
+            for feature in Feature::ALL {
+                for route in feature.routes() {
+                    let url = format!("http://{}/api/v1{}", addr, concrete_route(route));
+                    let response = client.post(&url).send().await.unwrap();
+                    if enabled.contains(&feature) {
+                        // enabled features are served by their own router, not the stubs
+                        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{url}");
+                    } else {
+                        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED, "{url}");
+                        let body: serde_json::Value = response.json().await.unwrap();
+                        assert_eq!(body["kind"], "FeatureDisabled");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -4,10 +4,24 @@ use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 
 use crate::{
+    auth::user::UserAction,
     error::{Error, ErrorKind},
     AppState,
 };
 
+#[utoipa::path(
+    put,
+    path = "/gateway/open_port/{port}",
+    tag = "gateway",
+    params(
+        ("port" = u32, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn open_port(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -22,7 +36,7 @@ pub async fn open_port(
             kind: ErrorKind::Unauthorized,
             source: eyre!("Token error"),
         })?;
-    if !requester.is_owner {
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
         return Err(Error {
             kind: ErrorKind::Unauthorized,
             source: eyre!("Only owners can open ports"),
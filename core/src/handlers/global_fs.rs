@@ -3,7 +3,7 @@ use std::path::PathBuf;
 
 use axum::{
     body::{Bytes, StreamBody},
-    extract::{Multipart, Path},
+    extract::{Multipart, Path, Query},
     http,
     routing::{delete, get, put},
     Json, Router,
@@ -18,12 +18,13 @@ use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 use tokio_util::io::ReaderStream;
 use ts_rs::TS;
+use utoipa::ToSchema;
 
 use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
     events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget},
-    util::{list_dir, rand_alphanumeric, zip_files},
+    util::{check_path_length, extended_length_path, list_dir, rand_alphanumeric, zip_files},
     AppState,
 };
 
@@ -36,14 +37,15 @@ pub enum DownloadableFile {
     ZippedFile((PathBuf, TempDir)),
 }
 
-#[derive(Debug, Serialize, Deserialize, TS)]
+#[derive(Debug, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub enum FileType {
     File,
     Directory,
+    Symlink,
     Unknown,
 }
-#[derive(Debug, Serialize, Deserialize, TS)]
+#[derive(Debug, Serialize, Deserialize, TS, ToSchema)]
 #[serde(rename = "ClientFile")]
 #[ts(export)]
 pub struct FileEntry {
@@ -55,16 +57,24 @@ pub struct FileEntry {
     pub creation_time: Option<u64>,
     pub modification_time: Option<u64>,
     pub file_type: FileType,
+    /// The permissions don't allow writing to it
+    #[serde(default)]
+    pub read_only: bool,
+    /// A symlink whose target is missing or outside of where the listing is confined to, it
+    /// isn't followed
+    #[serde(default)]
+    pub unresolvable: bool,
 }
 
 impl From<&std::path::Path> for FileEntry {
     fn from(path: &std::path::Path) -> Self {
-        let file_type = if path.is_dir() {
-            FileType::Directory
-        } else if path.is_file() {
-            FileType::File
-        } else {
-            FileType::Unknown
+        // a symlink is described by the link itself, never by its target
+        let metadata = path.symlink_metadata().ok();
+        let file_type = match metadata.as_ref().map(|m| m.file_type()) {
+            Some(t) if t.is_symlink() => FileType::Symlink,
+            Some(t) if t.is_dir() => FileType::Directory,
+            Some(t) if t.is_file() => FileType::File,
+            _ => FileType::Unknown,
         };
         Self {
             name: path
@@ -75,10 +85,9 @@ impl From<&std::path::Path> for FileEntry {
                 .file_name()
                 .map(|s| s.to_string_lossy().into_owned())
                 .unwrap_or_default(),
-            size: if path.is_file() {
-                path.metadata().ok().map(|m| m.len())
-            } else {
-                None
+            size: match file_type {
+                FileType::File => metadata.as_ref().map(|m| m.len()),
+                _ => None,
             },
             file_stem: path
                 .file_stem()
@@ -87,25 +96,161 @@ impl From<&std::path::Path> for FileEntry {
             extension: path.extension().map(|s| s.to_string_lossy().into_owned()),
             // unix timestamp
             // if we cant get the time, return none
-            creation_time: path
-                .metadata()
-                .ok()
+            creation_time: metadata
+                .as_ref()
                 .and_then(|m| m.created().ok())
                 .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()),
-            modification_time: path
-                .metadata()
-                .ok()
+            modification_time: metadata
+                .as_ref()
                 .and_then(|m| m.modified().ok())
                 .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()),
-
+            read_only: metadata
+                .as_ref()
+                .map_or(false, |m| m.permissions().readonly()),
+            // a dangling link
+            unresolvable: matches!(file_type, FileType::Symlink) && path.metadata().is_err(),
             file_type,
         }
     }
 }
 
+/// Deepest `depth` the ls endpoints go, the recursive listing is meant to stay shallow
+pub const MAX_LIST_DEPTH: usize = 3;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FileListSort {
+    /// Case insensitive, by the listed path
+    #[default]
+    Name,
+    /// Directories and symlinks have no size
+    Size,
+    /// Modification time
+    Mtime,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FileListQuery {
+    /// Whether dotfiles are listed
+    #[serde(default = "default_true")]
+    pub hidden: bool,
+    #[serde(default)]
+    pub sort: FileListSort,
+    #[serde(default)]
+    pub descending: bool,
+    /// Levels of subdirectories listed along with the directory, capped at `MAX_LIST_DEPTH`
+    #[serde(default)]
+    pub depth: usize,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for FileListQuery {
+    fn default() -> Self {
+        Self {
+            hidden: true,
+            sort: FileListSort::default(),
+            descending: false,
+            depth: 0,
+        }
+    }
+}
+
+impl FileListQuery {
+    pub fn shows(&self, entry: &FileEntry) -> bool {
+        self.hidden || !entry.name.starts_with('.')
+    }
+
+    /// Stable sort with directories first whichever way the rest is sorted
+    pub fn sort(&self, entries: &mut [FileEntry]) {
+        entries.sort_by(|a, b| {
+            let order = match self.sort {
+                FileListSort::Name => a.path.to_lowercase().cmp(&b.path.to_lowercase()),
+                FileListSort::Size => a.size.cmp(&b.size),
+                FileListSort::Mtime => a.modification_time.cmp(&b.modification_time),
+            };
+            let order = if self.descending {
+                order.reverse()
+            } else {
+                order
+            };
+            let is_dir = |entry: &FileEntry| matches!(entry.file_type, FileType::Directory);
+            is_dir(b).cmp(&is_dir(a)).then(order)
+        });
+    }
+}
+
+/// Lists `dir` and `query.depth` levels of its subdirectories, with paths relative to `base`.
+///
+/// Symlinks are listed but never descended into. When `root` is given, those resolving outside
+/// of it are marked unresolvable
+pub(crate) async fn list_entries(
+    dir: &std::path::Path,
+    base: &std::path::Path,
+    root: Option<&std::path::Path>,
+    query: &FileListQuery,
+) -> Result<Vec<FileEntry>, Error> {
+    let root = root.map(|root| root.canonicalize().unwrap_or_else(|_| root.to_owned()));
+    let depth = query.depth.min(MAX_LIST_DEPTH);
+    let mut ret = Vec::new();
+    let mut pending = vec![(dir.to_owned(), 0)];
+    while let Some((dir, level)) = pending.pop() {
+        let paths = match list_dir(&dir, None).await {
+            Ok(paths) => paths,
+            // an unreadable subdirectory shouldn't fail the whole listing
+            Err(_) if level > 0 => continue,
+            Err(e) => return Err(e),
+        };
+        for path in paths {
+            let mut entry: FileEntry = path.as_path().into();
+            if !query.shows(&entry) {
+                continue;
+            }
+            entry.path = match path.strip_prefix(base).ok().and_then(|p| p.to_str()) {
+                Some(relative) => relative.to_owned(),
+                None => continue,
+            };
+            match entry.file_type {
+                FileType::Directory if level < depth => pending.push((path, level + 1)),
+                FileType::Symlink => {
+                    if let Some(root) = &root {
+                        entry.unresolvable |= !path
+                            .canonicalize()
+                            .map_or(false, |target| target.starts_with(root));
+                    }
+                }
+                _ => {}
+            }
+            ret.push(entry);
+        }
+    }
+    query.sort(&mut ret);
+    Ok(ret)
+}
+
+#[utoipa::path(
+    get,
+    path = "/fs/{base64_absolute_path}/ls",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+        ("hidden" = Option<bool>, Query, description = "List dotfiles, true by default"),
+        ("sort" = Option<String>, Query, description = "name, size or mtime, directories always come first"),
+        ("descending" = Option<bool>, Query),
+        ("depth" = Option<usize>, Query, description = "Levels of subdirectories to list along, at most 3"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = [FileEntry]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn list_files(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
+    Query(query): Query<FileListQuery>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<Vec<FileEntry>>, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
@@ -126,14 +271,7 @@ async fn list_files(
         user_id: requester.uid,
         user_name: requester.username,
     };
-    let ret: Vec<FileEntry> = list_dir(&path, None)
-        .await?
-        .iter()
-        .map(|p| {
-            let r: FileEntry = p.as_path().into();
-            r
-        })
-        .collect();
+    let ret = list_entries(&path, &path, None, &query).await?;
     state.event_broadcaster.send(new_fs_event(
         FSOperation::Read,
         FSTarget::Directory(path),
@@ -142,6 +280,19 @@ async fn list_files(
     Ok(Json(ret))
 }
 
+#[utoipa::path(
+    get,
+    path = "/fs/{base64_absolute_path}/read",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn read_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -161,7 +312,7 @@ async fn read_file(
     requester.try_action(&UserAction::ReadGlobalFile, state.global_settings.lock().await.safe_mode())?;
 
     let path = PathBuf::from(absolute_path);
-    let ret = tokio::fs::read_to_string(&path).await.context(
+    let ret = tokio::fs::read_to_string(extended_length_path(&path)).await.context(
         "
         Failed to read file
     ",
@@ -178,6 +329,19 @@ async fn read_file(
     Ok(ret)
 }
 
+#[utoipa::path(
+    put,
+    path = "/fs/{base64_absolute_path}/write",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn write_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -198,8 +362,9 @@ async fn write_file(
     requester.try_action(&UserAction::WriteGlobalFile, state.global_settings.lock().await.safe_mode())?;
 
     let path = PathBuf::from(absolute_path);
+    check_path_length(&path, state.global_settings.lock().await.max_path_length())?;
 
-    tokio::fs::write(&path, body)
+    tokio::fs::write(extended_length_path(&path), body)
         .await
         .context(format!("Failed to write to file {}", path.display()))?;
 
@@ -215,6 +380,19 @@ async fn write_file(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/fs/{base64_absolute_path}/mkdir",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn make_directory(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -234,7 +412,8 @@ async fn make_directory(
     requester.try_action(&UserAction::WriteGlobalFile, state.global_settings.lock().await.safe_mode())?;
 
     let path = PathBuf::from(absolute_path);
-    tokio::fs::create_dir(&path).await.context(format!(
+    check_path_length(&path, state.global_settings.lock().await.max_path_length())?;
+    tokio::fs::create_dir(extended_length_path(&path)).await.context(format!(
         "
         Failed to create directory {}
     ",
@@ -253,6 +432,20 @@ async fn make_directory(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/fs/{base64_absolute_path}/move/{base64_relative_path_dest}",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+        ("base64_relative_path_dest" = String, Path, description = "Destination, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn move_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((base64_absolute_path_source, base64_absolute_path_dest)): Path<(String, String)>,
@@ -291,6 +484,19 @@ async fn move_file(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/fs/{base64_absolute_path}/rm",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn remove_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -310,7 +516,7 @@ async fn remove_file(
 
     let path = PathBuf::from(absolute_path);
 
-    tokio::fs::remove_file(&path)
+    tokio::fs::remove_file(extended_length_path(&path))
         .await
         .context(format!("Failed to remove file {}", path.display()))?;
 
@@ -326,6 +532,19 @@ async fn remove_file(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/fs/{base64_absolute_path}/rmdir",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn remove_dir(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -345,9 +564,7 @@ async fn remove_dir(
 
     let path = PathBuf::from(absolute_path);
 
-    tokio::fs::remove_dir_all(&path)
-        .await
-        .context(format!("Failed to remove directory {}", path.display()))?;
+    crate::util::fs::remove_dir_all(&path).await?;
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -362,6 +579,19 @@ async fn remove_dir(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/fs/{base64_absolute_path}/new",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn new_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -380,8 +610,9 @@ async fn new_file(
     requester.try_action(&UserAction::WriteGlobalFile, state.global_settings.lock().await.safe_mode())?;
 
     let path = PathBuf::from(absolute_path);
+    check_path_length(&path, state.global_settings.lock().await.max_path_length())?;
 
-    tokio::fs::File::create(&path)
+    tokio::fs::File::create(extended_length_path(&path))
         .await
         .context(format!("Failed to create file {}", path.display()))?;
 
@@ -398,6 +629,19 @@ async fn new_file(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    get,
+    path = "/fs/{base64_absolute_path}/download",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn download_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -450,6 +694,19 @@ async fn download_file(
     Ok(key)
 }
 
+#[utoipa::path(
+    put,
+    path = "/fs/{base64_absolute_path}/upload",
+    tag = "global_fs",
+    params(
+        ("base64_absolute_path" = String, Path, description = "Absolute path, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn upload_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -471,8 +728,10 @@ async fn upload_file(
     requester.try_action(&UserAction::WriteGlobalFile, state.global_settings.lock().await.safe_mode())?;
 
     let path_to_dir = PathBuf::from(absolute_path);
+    let max_path_length = state.global_settings.lock().await.max_path_length();
+    check_path_length(&path_to_dir, max_path_length)?;
 
-    tokio::fs::create_dir_all(&path_to_dir)
+    tokio::fs::create_dir_all(extended_length_path(&path_to_dir))
         .await
         .context(format!(
             "Failed to create directory {}",
@@ -524,7 +783,8 @@ async fn upload_file(
         } else {
             path
         };
-        let mut file = tokio::fs::File::create(&path)
+        check_path_length(&path, max_path_length)?;
+        let mut file = tokio::fs::File::create(extended_length_path(&path))
             .await
             .context(format!("Failed to create file {}", path.display()))?;
 
@@ -581,6 +841,18 @@ async fn upload_file(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    get,
+    path = "/file/{key}",
+    tag = "global_fs",
+    params(
+        ("key" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 async fn download(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(key): Path<String>,
@@ -654,3 +926,81 @@ pub fn get_global_fs_routes(state: AppState) -> Router {
         .route("/file/:key", get(download))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_list_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("b.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello world").unwrap();
+        std::fs::write(dir.path().join(".hidden"), "").unwrap();
+        std::fs::create_dir_all(dir.path().join("z/nested/deeper")).unwrap();
+        std::fs::write(dir.path().join("z/inner.txt"), "").unwrap();
+
+        let paths = |entries: Vec<FileEntry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.path).collect()
+        };
+        let listed = list_entries(dir.path(), dir.path(), None, &FileListQuery::default())
+            .await
+            .unwrap();
+        assert!(matches!(listed[0].file_type, FileType::Directory));
+        assert_eq!(listed[3].size, Some(5));
+        assert!(!listed[3].read_only);
+        assert_eq!(paths(listed), vec!["z", ".hidden", "a.txt", "b.txt"]);
+
+        let query = FileListQuery {
+            hidden: false,
+            sort: FileListSort::Size,
+            descending: true,
+            depth: 1,
+        };
+        let listed = list_entries(dir.path(), dir.path(), None, &query)
+            .await
+            .unwrap();
+        let nested = std::path::Path::new("z").join("nested");
+        let inner = std::path::Path::new("z").join("inner.txt");
+        assert_eq!(
+            paths(listed),
+            vec![
+                "z",
+                nested.to_str().unwrap(),
+                "a.txt",
+                "b.txt",
+                inner.to_str().unwrap(),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_list_entries_does_not_follow_symlinks_out_of_root() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("world")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("world"), root.path().join("inside")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("missing"), root.path().join("dangling"))
+            .unwrap();
+
+        let query = FileListQuery {
+            depth: MAX_LIST_DEPTH,
+            ..Default::default()
+        };
+        let listed = list_entries(root.path(), root.path(), Some(root.path()), &query)
+            .await
+            .unwrap();
+        let unresolvable: Vec<(&str, bool)> = listed
+            .iter()
+            .filter(|entry| matches!(entry.file_type, FileType::Symlink))
+            .map(|entry| (entry.path.as_str(), entry.unresolvable))
+            .collect();
+        assert_eq!(
+            unresolvable,
+            vec![("dangling", true), ("escape", true), ("inside", false)]
+        );
+        assert_eq!(listed.len(), 4);
+    }
+}
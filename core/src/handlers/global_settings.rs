@@ -5,8 +5,28 @@ use axum::{
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 
-use crate::{error::ErrorKind, AppState, Error, GlobalSettingsData};
+use crate::{
+    auth::user::UserAction,
+    error::ErrorKind,
+    global_settings::{LoginRateLimit, PortRange},
+    AppState, Error, GlobalSettingsData,
+};
+
+/// Below this even a freshly created instance directory would not fit
+const MIN_MAX_PATH_LENGTH: u32 = 128;
+/// Longest path the Windows extended-length APIs accept
+const MAX_MAX_PATH_LENGTH: u32 = 32767;
 
+#[utoipa::path(
+    get,
+    path = "/global_settings",
+    tag = "global_settings",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_core_settings(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -21,9 +41,22 @@ pub async fn get_core_settings(
             source: eyre!("Token error"),
         })?;
 
-    Ok(Json(state.global_settings.lock().await.as_ref().clone()))
+    let mut settings = state.global_settings.lock().await.as_ref().clone();
+    settings.remote_backup_secret = None;
+    Ok(Json(settings))
 }
 
+#[utoipa::path(
+    put,
+    path = "/global_settings/name",
+    tag = "global_settings",
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn change_core_name(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -31,7 +64,7 @@ pub async fn change_core_name(
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
 
-    if !requester.is_owner {
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
             source: eyre!("Not authorized to change core name"),
@@ -58,6 +91,17 @@ pub async fn change_core_name(
     Ok(())
 }
 
+#[utoipa::path(
+    put,
+    path = "/global_settings/safe_mode",
+    tag = "global_settings",
+    request_body = bool,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn change_core_safe_mode(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -65,7 +109,7 @@ pub async fn change_core_safe_mode(
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
 
-    if !requester.is_owner {
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
             source: eyre!("Not authorized to change core safe mode"),
@@ -80,13 +124,24 @@ pub async fn change_core_safe_mode(
     Ok(())
 }
 
+#[utoipa::path(
+    put,
+    path = "/global_settings/domain",
+    tag = "global_settings",
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn change_domain(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
     Json(new_domain): Json<String>,
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    if !requester.is_owner {
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
             source: eyre!("Not authorized to change core domain"),
@@ -111,6 +166,17 @@ pub async fn change_domain(
     Ok(())
 }
 
+#[utoipa::path(
+    put,
+    path = "/global_settings/playit_enabled",
+    tag = "global_settings",
+    request_body = bool,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn change_core_playit_enabled(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -118,7 +184,7 @@ pub async fn change_core_playit_enabled(
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
 
-    if !requester.is_owner {
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
             source: eyre!("Not authorized to change Playit Enabled status."),
@@ -134,6 +200,198 @@ pub async fn change_core_playit_enabled(
     Ok(())
 }
 
+#[utoipa::path(
+    put,
+    path = "/global_settings/max_path_length",
+    tag = "global_settings",
+    request_body = u32,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn change_max_path_length(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(max_path_length): Json<u32>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the maximum path length"),
+        });
+    }
+    if !(MIN_MAX_PATH_LENGTH..=MAX_MAX_PATH_LENGTH).contains(&max_path_length) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Maximum path length must be between {} and {}",
+                MIN_MAX_PATH_LENGTH,
+                MAX_MAX_PATH_LENGTH
+            ),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_max_path_length(max_path_length)
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    put,
+    path = "/global_settings/max_upload_size",
+    tag = "global_settings",
+    request_body = u64,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn change_max_upload_size(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(max_upload_size): Json<u64>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the maximum upload size"),
+        });
+    }
+    if max_upload_size == 0 {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Maximum upload size must be at least 1 byte"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_max_upload_size(max_upload_size)
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    put,
+    path = "/global_settings/login_rate_limit",
+    tag = "global_settings",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn change_login_rate_limit(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(login_rate_limit): Json<LoginRateLimit>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the login rate limit"),
+        });
+    }
+    if login_rate_limit.max_attempts == 0
+        || login_rate_limit.window_seconds == 0
+        || login_rate_limit.lockout_threshold == 0
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Login limits must allow at least one attempt in a non-empty window"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_login_rate_limit(login_rate_limit)
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    put,
+    path = "/global_settings/port_range",
+    tag = "global_settings",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn change_port_range(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(port_range): Json<PortRange>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the port range"),
+        });
+    }
+    port_range.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_port_range(port_range)
+        .await?;
+    // ports allocated outside the new range are kept until their instance lets go of them
+    state.port_manager.lock().await.set_range(port_range);
+    Ok(())
+}
+
+#[utoipa::path(
+    put,
+    path = "/global_settings/api_docs_enabled",
+    tag = "global_settings",
+    request_body = bool,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn change_api_docs_enabled(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(api_docs_enabled): Json<bool>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change whether the API documentation is served"),
+        });
+    }
+
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_api_docs_enabled(api_docs_enabled)
+        .await?;
+    Ok(())
+}
+
 pub fn get_global_settings_routes(state: AppState) -> Router {
     Router::new()
         .route("/global_settings", get(get_core_settings))
@@ -144,5 +402,22 @@ pub fn get_global_settings_routes(state: AppState) -> Router {
             "/global_settings/playit_enabled",
             put(change_core_playit_enabled),
         )
+        .route(
+            "/global_settings/max_path_length",
+            put(change_max_path_length),
+        )
+        .route(
+            "/global_settings/max_upload_size",
+            put(change_max_upload_size),
+        )
+        .route(
+            "/global_settings/login_rate_limit",
+            put(change_login_rate_limit),
+        )
+        .route("/global_settings/port_range", put(change_port_range))
+        .route(
+            "/global_settings/api_docs_enabled",
+            put(change_api_docs_enabled),
+        )
         .with_state(state)
 }
@@ -0,0 +1,125 @@
+//! Probes for load balancers and service managers, served without authentication
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use dashmap::DashMap;
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::broken_instances::BrokenInstances;
+use crate::prelude::GameInstance;
+use crate::types::InstanceUuid;
+
+/// The part of the app state the probes read, they can be served without the rest
+#[derive(Clone)]
+pub struct HealthState {
+    /// Set once startup finished, never unset
+    pub ready: Arc<AtomicBool>,
+    pub instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    pub broken_instances: BrokenInstances,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct Readiness {
+    pub ready: bool,
+    pub loaded_instances: usize,
+    /// Instance directories that failed to load, see `/instance/broken`
+    pub broken_instances: usize,
+}
+
+/// Answers as soon as the router serves requests
+pub async fn get_health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// 503 until startup finished, 200 after
+pub async fn get_readiness(State(state): State<HealthState>) -> Response {
+    let readiness = Readiness {
+        ready: state.ready.load(Ordering::SeqCst),
+        loaded_instances: state.instances.len(),
+        broken_instances: state.broken_instances.list().await.len(),
+    };
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
+/// Mounted outside the versioned API, probes are configured once and not migrated
+pub fn get_health_routes(state: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(get_health))
+        .route("/readyz", get(get_readiness))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::broken_instances::BrokenInstance;
+
+    fn serve(state: HealthState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(get_health_routes(state).into_make_service()),
+        );
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_probes_need_no_token() {
+        let state = HealthState {
+            ready: Arc::new(AtomicBool::new(false)),
+            instances: Arc::new(DashMap::new()),
+            broken_instances: BrokenInstances::new(vec![BrokenInstance::new(
+                PathBuf::from("instances").join("survival"),
+                "Failed to parse .lodestone_config file",
+            )]),
+        };
+        let addr = serve(state.clone());
+        let client = reqwest::Client::new();
+
+        let health = client
+            .get(format!("http://{}/healthz", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+
+        let readiness = client
+            .get(format!("http://{}/readyz", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(readiness.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.ready.store(true, Ordering::SeqCst);
+        let readiness = client
+            .get(format!("http://{}/readyz", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(readiness.status(), StatusCode::OK);
+        let body: serde_json::Value = readiness.json().await.unwrap();
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["loaded_instances"], 0);
+        assert_eq!(body["broken_instances"], 1);
+    }
+}
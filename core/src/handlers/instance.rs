@@ -1,123 +1,659 @@
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::StreamBody;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{delete, get, post};
 use axum::Router;
-use axum::{extract::Path, Json};
+use axum::{
+    extract::{Multipart, Path, Query},
+    Json,
+};
 use axum_auth::AuthBearer;
 
 use bollard::container::ListContainersOptions;
 use bollard::Docker;
 use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
 use serde::Deserialize;
-use tracing::{error, info};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
 
-use crate::auth::user::UserAction;
-use crate::error::{Error, ErrorKind};
-use crate::events::{CausedBy, Event, ProgressionEndValue, ProgressionStartValue};
+use crate::archive_manifest::{verify_archive, MANIFEST_FILE_NAME};
+use crate::audit::{audit_value, AuditTarget};
+use crate::auth::user::{User, UserAction};
+use crate::broken_instances::BrokenInstance;
+use crate::cancellation::{cancelled_error, checkpoint};
+use crate::creation_status::CreationPoll;
+use crate::disk_usage::{check_space, volume_space};
+use crate::error::{Error, ErrorCode, ErrorKind};
+use crate::events::{
+    CausedBy, Event, ProgressionEndValue, ProgressionStartBuilder, ProgressionStartValue,
+};
 
+use crate::implementations::command::CommandInstance;
 use crate::implementations::generic;
+use crate::instance_archive::{
+    export_entries, read_export_metadata, write_export, ChannelWriter, ExportMetadata,
+    EXPORT_METADATA_FILE_NAME,
+};
+use crate::instance_migration::Migration;
+use crate::request_context::propagate;
 use crate::traits::t_configurable::GameType;
 
 use crate::implementations::minecraft::MinecraftInstance;
-use crate::prelude::{path_to_instances, GameInstance};
-use crate::traits::t_configurable::manifest::SetupValue;
+use crate::prelude::{path_to_instances, path_to_tmp, GameInstance};
+use crate::traits::t_configurable::manifest::{
+    ConfigurableValue, SettingValidationError, SettingValidationErrors, SetupValue,
+};
 use crate::traits::t_configurable::Game::Generic;
-use crate::traits::{t_configurable::TConfigurable, t_server::TServer, InstanceInfo, TInstance};
-use crate::types::{DotLodestoneConfig, InstanceUuid};
+use crate::traits::{
+    t_configurable::TConfigurable, t_server::RconStatus, t_server::TServer, InstanceInfo, TInstance,
+};
+use crate::types::{normalize_tag, DotLodestoneConfig, InstanceUuid, Snowflake};
+use crate::util::{check_archive_entries, extract_archive, format_byte, format_byte_download};
 use crate::{implementations::minecraft, traits::t_server::State, AppState};
 
+use super::instance_fs::{
+    archive_size, read_dot_lodestone_config, upload_too_large, write_dot_lodestone_config,
+    PartialFile,
+};
 use super::instance_setup_configs::HandlerGameType;
+use super::util::{
+    cloned_handles, ensure_name_available, game_instance, instance_not_found, instances_named,
+};
+
+/// The directory of a new instance, the whole UUID in its name keeps it apart from any other
+fn new_instance_dir(name: &str, uuid: &InstanceUuid) -> PathBuf {
+    path_to_instances().join(format!("{}-{}", name, uuid.no_prefix()))
+}
+
+/// How long one instance may take to report its info before the list goes on without it
+const LIST_ENTRY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Fields kept in every entry whatever `fields` selects
+const ALWAYS_LISTED_FIELDS: [&str; 2] = ["uuid", "degraded"];
+
+/// The info of the instance with its tags and maintenance, an unreadable `.lodestone_config`
+/// lists it untagged
+async fn tagged_instance_info(instance: &GameInstance) -> InstanceInfo {
+    let mut info = instance.get_instance_info().await;
+    match read_dot_lodestone_config(&instance.path().await).await {
+        Ok(config) => {
+            info.tags = config.tags().to_vec();
+            info.maintenance = config.maintenance().cloned();
+        }
+        Err(e) => warn!("Failed to read the tags of {}: {}", info.name, e),
+    }
+    info
+}
+
+/// The cached info of the instance, built again once the instance changed since or if `fresh`
+async fn cached_instance_info(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    instance: &GameInstance,
+    fresh: bool,
+) -> InstanceInfo {
+    let snapshot = instance.snapshot();
+    if !fresh {
+        if let Some(info) = state.instance_info.get(uuid, &snapshot) {
+            return info;
+        }
+    }
+    state
+        .instance_info
+        .build(uuid, snapshot, tagged_instance_info(instance))
+        .await
+}
+
+enum ListEntry {
+    Info(InstanceInfo),
+    /// The instance didn't report its info in time, listed by UUID only
+    Degraded(InstanceUuid),
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceListSort {
+    #[default]
+    CreationTime,
+    /// Case insensitive
+    Name,
+    /// Instances that haven't started since the daemon did come first
+    LastStarted,
+    /// Instances without a player count come first
+    PlayerCount,
+}
+
+#[derive(Deserialize)]
+pub struct InstanceListQuery {
+    /// Matched against the normalized tags
+    tag: Option<String>,
+    game_type: Option<GameType>,
+    state: Option<State>,
+    #[serde(default)]
+    sort: InstanceListSort,
+    #[serde(default)]
+    descending: bool,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    /// Comma separated names of the fields to list, all of them if absent
+    fields: Option<String>,
+    /// Builds the info of every instance again instead of listing the cached
+    #[serde(default)]
+    fresh: bool,
+}
+
+impl InstanceListQuery {
+    fn fields(&self) -> Option<Vec<&str>> {
+        self.fields.as_deref().map(|fields| {
+            fields
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .collect()
+        })
+    }
+
+    fn filters(&self) -> bool {
+        self.tag.is_some() || self.game_type.is_some() || self.state.is_some()
+    }
+
+    fn matches(&self, info: &InstanceInfo) -> bool {
+        let tag = self.tag.as_deref().map(normalize_tag);
+        tag.map_or(true, |tag| info.tags.contains(&tag))
+            && self.game_type.map_or(true, |game_type| {
+                GameType::from(&info.game_type) == game_type
+            })
+            && self.state.map_or(true, |state| info.state == state)
+    }
+
+    fn sort(&self, list: &mut [InstanceInfo]) {
+        match self.sort {
+            InstanceListSort::CreationTime => list.sort_by_key(|info| info.creation_time),
+            InstanceListSort::Name => list.sort_by_key(|info| info.name.to_lowercase()),
+            InstanceListSort::LastStarted => list.sort_by_key(|info| info.last_started),
+            InstanceListSort::PlayerCount => list.sort_by_key(|info| info.player_count),
+        }
+        if self.descending {
+            list.reverse();
+        }
+    }
+
+    fn project(&self, info: &InstanceInfo) -> Result<serde_json::Value, Error> {
+        let mut value = serde_json::to_value(info).context("Failed to serialize instance info")?;
+        if let (Some(fields), Some(object)) = (self.fields(), value.as_object_mut()) {
+            object.retain(|key, _| {
+                fields.contains(&key.as_str()) || ALWAYS_LISTED_FIELDS.contains(&key.as_str())
+            });
+        }
+        Ok(value)
+    }
 
+    /// The page of the filtered and sorted entries, and how many entries there are in total.
+    ///
+    /// Degraded entries can't be filtered or sorted, they are left out of filtered lists and
+    /// come last otherwise
+    fn page(&self, entries: Vec<ListEntry>) -> Result<(usize, Vec<serde_json::Value>), Error> {
+        let mut infos = Vec::new();
+        let mut degraded = Vec::new();
+        for entry in entries {
+            match entry {
+                ListEntry::Info(info) => infos.push(info),
+                ListEntry::Degraded(uuid) => degraded.push(uuid),
+            }
+        }
+        infos.retain(|info| self.matches(info));
+        self.sort(&mut infos);
+        if self.filters() {
+            degraded.clear();
+        }
+        let total = infos.len() + degraded.len();
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut page = Vec::new();
+        for info in infos.iter().skip(self.offset).take(limit) {
+            page.push(self.project(info)?);
+        }
+        let listed = page.len();
+        page.extend(
+            degraded
+                .into_iter()
+                .skip(self.offset.saturating_sub(infos.len()))
+                .take(limit - listed)
+                .map(|uuid| serde_json::json!({ "uuid": uuid, "degraded": true })),
+        );
+        Ok((total, page))
+    }
+}
+
+/// The instances the requester can view, with the total before paging in `X-Total-Count`
+#[utoipa::path(
+    get,
+    path = "/instance/list",
+    tag = "instance",
+    responses(
+        (
+            status = 200,
+            description = "The page of instances, with only the requested fields if any",
+            body = [InstanceInfo],
+            headers(("x-total-count" = u64, description = "Matching instances before paging")),
+        ),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_list(
     axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<InstanceListQuery>,
     AuthBearer(token): AuthBearer,
-) -> Result<Json<Vec<InstanceInfo>>, Error> {
+) -> Result<Response, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    let mut list_of_configs: Vec<InstanceInfo> = Vec::new();
+    let state = &state;
+    let fresh = query.fresh;
 
-    for instance in state.instances.iter() {
-        if requester.can_perform_action(&UserAction::ViewInstance(instance.uuid().await)) {
-            list_of_configs.push(instance.get_instance_info().await);
-        }
-    }
-    let docker_bridge = state.docker_bridge.clone();
-    let vec = docker_bridge.list_containers().await.unwrap_or_default();
-
-    list_of_configs.extend(vec);
+    // gathered concurrently, an instance that doesn't answer in time is listed as degraded
+    // instead of holding up the rest
+    let mut entries = futures::future::join_all(
+        cloned_handles(&state.instances)
+            .into_iter()
+            .filter(|(uuid, _)| {
+                requester.can_perform_action(&UserAction::ViewInstance(uuid.clone()))
+            })
+            .map(|(uuid, instance)| async move {
+                let info = cached_instance_info(state, &uuid, &instance, fresh);
+                match tokio::time::timeout(LIST_ENTRY_TIMEOUT, info).await {
+                    Ok(info) => ListEntry::Info(info),
+                    Err(_) => {
+                        warn!(
+                            "Instance {} is listed as degraded, its info timed out",
+                            uuid
+                        );
+                        ListEntry::Degraded(uuid)
+                    }
+                }
+            }),
+    )
+    .await;
+    let containers =
+        tokio::time::timeout(LIST_ENTRY_TIMEOUT, state.docker_bridge.list_containers())
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .unwrap_or_default();
+    entries.extend(containers.into_iter().map(ListEntry::Info));
 
-    list_of_configs.sort_by(|a, b| a.creation_time.cmp(&b.creation_time));
+    let (total, page) = query.page(entries)?;
+    Ok((
+        [(
+            header::HeaderName::from_static("x-total-count"),
+            total.to_string(),
+        )],
+        Json(page),
+    )
+        .into_response())
+}
 
-    Ok(Json(list_of_configs))
+#[derive(Deserialize)]
+pub struct InstanceInfoQuery {
+    /// Builds the info again instead of returning the cached
+    #[serde(default)]
+    fresh: bool,
 }
 
+/// The info of the instance, as cached since it last changed unless `fresh` is set
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/info",
+    tag = "instance",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("fresh" = Option<bool>, Query, description = "Bypass the cache, false if left out"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = InstanceInfo),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_info(
     Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<InstanceInfoQuery>,
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<InstanceInfo>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
 
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
 
     requester.try_action(
         &UserAction::ViewInstance(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    Ok(Json(instance.get_instance_info().await))
+    Ok(Json(
+        cached_instance_info(&state, &uuid, &instance, query.fresh).await,
+    ))
 }
 
-pub async fn create_minecraft_instance(
+#[derive(Deserialize)]
+pub struct LookupQuery {
+    name: String,
+}
+
+/// The UUID of the instance going by `name`, matched by name or slug among the instances the
+/// requester can view
+#[utoipa::path(
+    get,
+    path = "/instance/lookup",
+    tag = "instance",
+    responses(
+        (status = 200, description = "Success", body = InstanceUuid),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn lookup_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<LookupQuery>,
     AuthBearer(token): AuthBearer,
-    Path(game_type): Path<HandlerGameType>,
-    Json(manifest_value): Json<SetupValue>,
 ) -> Result<Json<InstanceUuid>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let mut matches: Vec<InstanceUuid> = instances_named(&state, &query.name)
+        .await
+        .into_iter()
+        .filter(|uuid| requester.can_perform_action(&UserAction::ViewInstance(uuid.clone())))
+        .collect();
+    match matches.len() {
+        0 => Err(Error::coded(
+            ErrorCode::InstanceNotFound,
+            format!("No instance is named {}", query.name),
+        )),
+        1 => Ok(Json(matches.remove(0))),
+        _ => Err(Error::coded(
+            ErrorCode::InstanceNameAmbiguous,
+            format!(
+                "{} instances are named {}: {}",
+                matches.len(),
+                query.name,
+                matches
+                    .iter()
+                    .map(|uuid| uuid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )
+        .with_details(serde_json::json!({ "instances": matches }))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreationStatusQuery {
+    /// Seconds to hold the request open waiting for a change past the `If-None-Match` cursor
+    #[serde(default)]
+    wait: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/creation-status",
+    tag = "instance",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_creation_status(
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<CreationStatusQuery>,
+    headers: HeaderMap,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Response, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(
         &UserAction::CreateInstance,
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let mut perm = requester.permissions;
-
-    let mut instance_uuid = InstanceUuid::default();
+    let cursor = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().trim_matches('"').parse::<u64>().ok());
+    let poll = state
+        .creation_registry
+        .poll(&uuid, cursor, Duration::from_secs(query.wait))
+        .await
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No recent creation of this instance"),
+        })?;
+    Ok(match poll {
+        CreationPoll::Changed(status) => (
+            [(header::ETAG, format!("\"{}\"", status.cursor))],
+            Json(status),
+        )
+            .into_response(),
+        CreationPoll::Unchanged(cursor) => (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, format!("\"{}\"", cursor))],
+        )
+            .into_response(),
+    })
+}
 
-    for entry in state.instances.iter() {
-        if let Some(uuid) = entry.key().as_ref().get(0..8) {
-            if uuid == &instance_uuid.no_prefix()[0..8] {
-                instance_uuid = InstanceUuid::default();
+/// What keeps a setup value from being created, by setting. Empty if it would be accepted
+async fn setup_value_errors(
+    state: &AppState,
+    game_type: HandlerGameType,
+    setup_value: &SetupValue,
+) -> Result<Vec<SettingValidationError>, Error> {
+    let mut errors = match game_type {
+        HandlerGameType::Command => {
+            let mut errors = CommandInstance::setup_manifest().setup_value_errors(setup_value);
+            if errors.is_empty() {
+                if let Err(e) = CommandInstance::construct_setup_config(setup_value.clone()) {
+                    match e.source.downcast_ref::<SettingValidationErrors>() {
+                        Some(refused) => errors.extend(refused.0.iter().cloned()),
+                        None => return Err(e),
+                    }
+                }
+            }
+            if setup_value.template_id.is_some() {
+                errors.push(SettingValidationError::new(
+                    "template_id",
+                    "Templates only apply to Minecraft instances",
+                ));
+            }
+            errors
+        }
+        game_type => {
+            let mut errors =
+                MinecraftInstance::setup_value_errors(setup_value, game_type.try_into()?).await?;
+            if let Some(template_id) = &setup_value.template_id {
+                if let Err(e) = state.templates.get(template_id).await {
+                    errors.push(SettingValidationError::new("template_id", e.source));
+                }
             }
+            errors
         }
+    };
+    if !instances_named(state, &setup_value.name).await.is_empty() {
+        errors.push(SettingValidationError::new(
+            "name",
+            format!("An instance named {} already exists", setup_value.name),
+        ));
     }
+    let value = |setting_id: &str| {
+        setup_value
+            .get_unique_setting(setting_id)
+            .and_then(|setting| setting.get_value())
+    };
+    if let Some(ConfigurableValue::UnsignedInteger(port)) = value("port") {
+        let status = state.port_manager.lock().await.port_status(*port);
+        if status.is_allocated {
+            errors.push(SettingValidationError::new(
+                "port",
+                format!("Port {} is already allocated to another instance", port),
+            ));
+        } else if status.is_in_use {
+            errors.push(SettingValidationError::new(
+                "port",
+                format!("Port {} is already in use by another program", port),
+            ));
+        }
+    }
+    let max_total_ram = state
+        .global_settings
+        .lock()
+        .await
+        .daemon_settings()
+        .max_total_ram;
+    if let (Some(ConfigurableValue::UnsignedInteger(max_ram)), Some(limit)) =
+        (value("max_ram"), max_total_ram)
+    {
+        // the capacity check would refuse every start
+        if *max_ram > limit {
+            errors.push(SettingValidationError::new(
+                "max_ram",
+                format!(
+                    "Maximum RAM ({} MB) is more than running instances may reserve together ({} MB)",
+                    max_ram, limit
+                ),
+            ));
+        }
+    }
+    Ok(errors)
+}
+
+/// Checks a setup value the way creating the instance would, without creating anything. Lists
+/// every problem by setting, an empty list means the creation would be accepted
+#[utoipa::path(
+    post,
+    path = "/instance/validate/{game_type}",
+    tag = "instance",
+    params(
+        ("game_type" = String, Path),
+    ),
+    request_body = SetupValue,
+    responses(
+        (status = 200, description = "The problems found", body = [SettingValidationError]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn validate_setup_value(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(game_type): Path<HandlerGameType>,
+    Json(setup_value): Json<SetupValue>,
+) -> Result<Json<Vec<SettingValidationError>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    setup_value_errors(&state, game_type, &setup_value)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/create/{game_type}",
+    tag = "instance",
+    params(
+        ("game_type" = String, Path),
+        ("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key is answered with the first response instead of creating another instance"),
+    ),
+    request_body = SetupValue,
+    responses(
+        (status = 200, description = "Success", body = InstanceUuid),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_minecraft_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(game_type): Path<HandlerGameType>,
+    Json(manifest_value): Json<SetupValue>,
+) -> Result<Json<InstanceUuid>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if let HandlerGameType::Command = game_type {
+        return create_command_instance(state, requester, manifest_value).await;
+    }
+    let mut perm = requester.permissions.clone();
 
-    let instance_uuid = instance_uuid;
+    let instance_uuid =
+        InstanceUuid::unique_among(state.instances.iter().map(|entry| entry.key().clone()))?;
 
     let flavour = game_type.try_into()?;
 
+    // looked up first so a missing template fails the request rather than the setup
+    let template = match manifest_value.template_id {
+        Some(template_id) => Some(state.templates.get(&template_id).await?),
+        None => None,
+    };
+    ensure_name_available(&state, &manifest_value.name, None).await?;
+    // checked before anything is spawned, so the response carries what's wrong
+    let errors = setup_value_errors(&state, game_type, &manifest_value).await?;
+    if !errors.is_empty() {
+        return Err(SettingValidationErrors(errors).into());
+    }
     let setup_config = MinecraftInstance::construct_setup_config(manifest_value, flavour).await?;
 
-    let setup_path = path_to_instances().join(format!(
-        "{}-{}",
-        setup_config.name,
-        &instance_uuid.no_prefix()[0..8]
-    ));
-
-    tokio::fs::create_dir_all(&setup_path)
+    // claimed before anything is written so a taken port fails the request right away
+    state
+        .port_manager
+        .lock()
         .await
-        .context("Failed to create instance directory")?;
+        .claim(setup_config.port, &instance_uuid)?;
+
+    let setup_path = new_instance_dir(&setup_config.name, &instance_uuid);
 
     let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), game_type.into());
 
-    // write dot lodestone config
+    let prepared = async {
+        tokio::fs::create_dir_all(&setup_path)
+            .await
+            .context("Failed to create instance directory")?;
 
-    tokio::fs::write(
-        setup_path.join(".lodestone_config"),
-        serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
-    )
-    .await
-    .context("Failed to write .lodestone_config file")?;
+        // write dot lodestone config
 
-    tokio::task::spawn({
+        tokio::fs::write(
+            setup_path.join(".lodestone_config"),
+            serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
+        )
+        .await
+        .context("Failed to write .lodestone_config file")?;
+        Ok::<(), Error>(())
+    }
+    .await;
+    if let Err(e) = prepared {
+        state
+            .port_manager
+            .lock()
+            .await
+            .deallocate_instance(&instance_uuid);
+        return Err(e);
+    }
+
+    state.creation_registry.register(instance_uuid.clone());
+    let backup_schedule = state.global_settings.lock().await.backup_defaults();
+    tokio::task::spawn(propagate({
         let uuid = instance_uuid.clone();
         let instance_name = setup_config.name.clone();
         let event_broadcaster = state.event_broadcaster.clone();
@@ -126,30 +662,55 @@ pub async fn create_minecraft_instance(
             user_name: requester.username.clone(),
         };
         async move {
-            let (progression_start_event, event_id) = Event::new_progression_event_start(
+            let (progression_start_event, event_id) = ProgressionStartBuilder::new(
                 format!("Setting up Minecraft server {instance_name}"),
-                Some(10.0),
-                Some(ProgressionStartValue::InstanceCreation {
+                ProgressionStartValue::InstanceCreation {
                     instance_uuid: uuid.clone(),
-                }),
+                },
                 caused_by,
-            );
-            event_broadcaster.send(progression_start_event);
-            let minecraft_instance = match minecraft::MinecraftInstance::new(
-                setup_config.clone(),
-                dot_lodestone_config,
-                setup_path.clone(),
-                &event_id,
-                state.event_broadcaster.clone(),
-                state.macro_executor.clone(),
             )
-            .await
-            {
-                Ok(v) => {
+            .total(10.0)
+            .build();
+            event_broadcaster.send(progression_start_event);
+            let cancellation = state
+                .cancellation_registry
+                .register(&event_id, UserAction::CreateInstance);
+            // dropping the setup stops its download wherever it is
+            let created = tokio::select! {
+                created = async {
+                    let instance = minecraft::MinecraftInstance::new(
+                        setup_config.clone(),
+                        dot_lodestone_config,
+                        setup_path.clone(),
+                        backup_schedule,
+                        &event_id,
+                        state.event_broadcaster.clone(),
+                        state.macro_executor.clone(),
+                    )
+                    .await?;
+                    let warnings = match &template {
+                        Some(template) => {
+                            instance
+                                .apply_template(template, &state.templates, &requester)
+                                .await?
+                        }
+                        None => Vec::new(),
+                    };
+                    Ok::<_, Error>((instance, warnings))
+                } => created,
+                _ = cancellation.cancelled() => Err(cancelled_error()),
+            };
+            let minecraft_instance = match created {
+                Ok((v, warnings)) => {
+                    let message = if warnings.is_empty() {
+                        "Instance created successfully".to_string()
+                    } else {
+                        format!("Instance created, but {}", warnings.join("; "))
+                    };
                     event_broadcaster.send(Event::new_progression_event_end(
                         event_id,
                         true,
-                        Some("Instance created successfully"),
+                        Some(message),
                         Some(ProgressionEndValue::InstanceCreation(
                             v.get_instance_info().await,
                         )),
@@ -157,12 +718,17 @@ pub async fn create_minecraft_instance(
                     v
                 }
                 Err(e) => {
-                    event_broadcaster.send(Event::new_progression_event_end(
-                        event_id,
-                        false,
-                        Some(&format!("Instance creation failed: {e}")),
-                        None,
-                    ));
+                    event_broadcaster.send(if cancellation.is_cancelled() {
+                        Event::new_progression_event_cancelled(event_id, Some(uuid.clone()))
+                    } else {
+                        Event::new_progression_event_end(
+                            event_id,
+                            false,
+                            Some(&format!("Instance creation failed: {e}")),
+                            None,
+                        )
+                    });
+                    state.port_manager.lock().await.deallocate_instance(&uuid);
                     crate::util::fs::remove_dir_all(setup_path)
                         .await
                         .context("Failed to remove directory after instance creation failed")
@@ -170,8 +736,6 @@ pub async fn create_minecraft_instance(
                     return;
                 }
             };
-            let mut port_manager = state.port_manager.lock().await;
-            port_manager.add_port(setup_config.port);
             perm.can_start_instance.insert(uuid.clone());
             perm.can_stop_instance.insert(uuid.clone());
             perm.can_view_instance.insert(uuid.clone());
@@ -192,16 +756,155 @@ pub async fn create_minecraft_instance(
                 .instances
                 .insert(uuid.clone(), minecraft_instance.into());
         }
-    });
+    }));
+    Ok(Json(instance_uuid))
+}
+
+fn reject_template(setup_value: &SetupValue) -> Result<(), Error> {
+    match setup_value.template_id {
+        Some(_) => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Templates only apply to Minecraft instances"),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Nothing is downloaded, so the instance is ready by the time the request returns
+async fn create_command_instance(
+    state: AppState,
+    requester: User,
+    manifest_value: SetupValue,
+) -> Result<Json<InstanceUuid>, Error> {
+    // the command runs with the daemon's privileges
+    if !requester.can_perform_action(&UserAction::AdministerInstance(None)) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only admins can create command instances"),
+        });
+    }
+    let mut perm = requester.permissions;
+    let instance_uuid =
+        InstanceUuid::unique_among(state.instances.iter().map(|entry| entry.key().clone()))?;
+    reject_template(&manifest_value)?;
+    let config = CommandInstance::construct_setup_config(manifest_value)?;
+    ensure_name_available(&state, &config.name, None).await?;
+    state
+        .port_manager
+        .lock()
+        .await
+        .claim(config.port, &instance_uuid)?;
+
+    let setup_path = new_instance_dir(&config.name, &instance_uuid);
+    let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), GameType::Command);
+    state.creation_registry.register(instance_uuid.clone());
+    let (progression_start_event, event_id) = ProgressionStartBuilder::new(
+        format!("Setting up command instance {}", config.name),
+        ProgressionStartValue::InstanceCreation {
+            instance_uuid: instance_uuid.clone(),
+        },
+        CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        },
+    )
+    .total(1.0)
+    .build();
+    state.event_broadcaster.send(progression_start_event);
+    let backup_schedule = state.global_settings.lock().await.backup_defaults();
+    let created = async {
+        tokio::fs::create_dir_all(&setup_path)
+            .await
+            .context("Failed to create instance directory")?;
+        tokio::fs::write(
+            setup_path.join(".lodestone_config"),
+            serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
+        )
+        .await
+        .context("Failed to write .lodestone_config file")?;
+        CommandInstance::new(
+            config,
+            dot_lodestone_config,
+            setup_path.clone(),
+            backup_schedule,
+            state.event_broadcaster.clone(),
+        )
+        .await
+    }
+    .await;
+    let instance = match created {
+        Ok(v) => {
+            state
+                .event_broadcaster
+                .send(Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some("Instance created successfully"),
+                    Some(ProgressionEndValue::InstanceCreation(
+                        v.get_instance_info().await,
+                    )),
+                ));
+            v
+        }
+        Err(e) => {
+            state
+                .event_broadcaster
+                .send(Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(&format!("Instance creation failed: {e}")),
+                    None,
+                ));
+            state
+                .port_manager
+                .lock()
+                .await
+                .deallocate_instance(&instance_uuid);
+            if let Err(e) = crate::util::fs::remove_dir_all(&setup_path).await {
+                error!("Failed to remove directory after instance creation failed: {e}");
+            }
+            return Err(e);
+        }
+    };
+    perm.can_start_instance.insert(instance_uuid.clone());
+    perm.can_stop_instance.insert(instance_uuid.clone());
+    perm.can_view_instance.insert(instance_uuid.clone());
+    perm.can_read_instance_file.insert(instance_uuid.clone());
+    perm.can_write_instance_file.insert(instance_uuid.clone());
+    // ignore errors since we don't care if the permissions update fails
+    let _ = state
+        .users_manager
+        .write()
+        .await
+        .update_permissions(&requester.uid, perm, CausedBy::System)
+        .await
+        .map_err(|e| {
+            error!("Failed to update permissions: {:?}", e);
+            e
+        });
+    state
+        .instances
+        .insert(instance_uuid.clone(), instance.into());
     Ok(Json(instance_uuid))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct GenericSetupConfig {
     url: String,
     setup_value: SetupValue,
 }
 
+#[utoipa::path(
+    post,
+    path = "/instance/create_generic",
+    tag = "instance",
+    request_body = GenericSetupConfig,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn create_generic_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -212,22 +915,12 @@ pub async fn create_generic_instance(
         &UserAction::CreateInstance,
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let mut instance_uuid = InstanceUuid::default();
-    for entry in state.instances.iter() {
-        if let Some(uuid) = entry.key().as_ref().get(0..8) {
-            if uuid == &instance_uuid.no_prefix()[0..8] {
-                instance_uuid = InstanceUuid::default();
-            }
-        }
-    }
+    reject_template(&setup_config.setup_value)?;
+    ensure_name_available(&state, &setup_config.setup_value.name, None).await?;
+    let instance_uuid =
+        InstanceUuid::unique_among(state.instances.iter().map(|entry| entry.key().clone()))?;
 
-    let instance_uuid = instance_uuid;
-
-    let setup_path = path_to_instances().join(format!(
-        "{}-{}",
-        setup_config.setup_value.name,
-        &instance_uuid.no_prefix()[0..8]
-    ));
+    let setup_path = new_instance_dir(&setup_config.setup_value.name, &instance_uuid);
 
     tokio::fs::create_dir_all(&setup_path)
         .await
@@ -235,18 +928,20 @@ pub async fn create_generic_instance(
 
     let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), GameType::Generic);
     let event_broadcaster = state.event_broadcaster.clone();
-    tokio::task::spawn(async move {
-        let (progression_start_event, event_id) = Event::new_progression_event_start(
+    state.creation_registry.register(instance_uuid.clone());
+    tokio::task::spawn(propagate(async move {
+        let (progression_start_event, event_id) = ProgressionStartBuilder::new(
             format!("Setting up instance {}", setup_config.setup_value.name),
-            Some(100.0),
-            Some(ProgressionStartValue::InstanceCreation {
+            ProgressionStartValue::InstanceCreation {
                 instance_uuid: instance_uuid.clone(),
-            }),
+            },
             CausedBy::User {
                 user_id: requester.uid,
                 user_name: requester.username,
             },
-        );
+        )
+        .total(100.0)
+        .build();
         event_broadcaster.send(progression_start_event);
         let instance = match generic::GenericInstance::new(
             setup_config.url.into(),
@@ -301,14 +996,37 @@ pub async fn create_generic_instance(
         state
             .instances
             .insert(instance_uuid.clone(), instance.into());
-    });
+    }));
 
     Ok(Json(()))
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeleteInstanceQuery {
+    /// Export the instance into the graveyard directory before deleting it
+    #[serde(default)]
+    archive: bool,
+}
+
+/// Deletes a stopped instance in the background, its progression event reports how it went
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}",
+    tag = "instance",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("archive" = Option<bool>, Query, description = "Leave a final export in the graveyard directory first"),
+    ),
+    responses(
+        (status = 200, description = "Deletion started"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn delete_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<DeleteInstanceQuery>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
@@ -320,87 +1038,1057 @@ pub async fn delete_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    if let Some((_, instance)) = state.instances.remove(&uuid) {
-        if !(instance.state().await == State::Stopped) {
-            state.instances.insert(uuid.clone(), instance);
-            Err(Error {
-                kind: ErrorKind::BadRequest,
-                source: eyre!("Instance must be stopped before deletion"),
-            })
-        } else {
-            let (progression_event_start, event_id) = Event::new_progression_event_start(
-                format!("Deleting instance {}", instance.name().await),
-                Some(10.0),
-                None,
-                caused_by,
-            );
-            let event_broadcaster = state.event_broadcaster.clone();
-            event_broadcaster.send(progression_event_start);
-            if let Err(e) =
-                tokio::fs::remove_file(instance.path().await.join(".lodestone_config")).await
+    // held while it's taken out, so a migration can't pick it up meanwhile
+    let _migrating = state.migrating.try_begin(&uuid)?;
+    // out of the map, nothing can start it while the deletion runs
+    let (_, instance) = state
+        .instances
+        .remove(&uuid)
+        .ok_or_else(instance_not_found)?;
+    if instance.state().await != State::Stopped {
+        state.instances.insert(uuid.clone(), instance);
+        return Err(Error::coded(
+            ErrorCode::InstanceNotStopped,
+            "Instance must be stopped before deletion",
+        ));
+    }
+    let (progression_event_start, event_id) = ProgressionStartBuilder::new(
+        format!("Deleting instance {}", instance.name().await),
+        ProgressionStartValue::InstanceDelete {
+            instance_uuid: uuid.clone(),
+        },
+        caused_by,
+    )
+    .total(100.0)
+    .build();
+    state.event_broadcaster.send(progression_event_start);
+    tokio::spawn(propagate(async move {
+        let result =
+            delete_stopped_instance(&state, &uuid, instance, query.archive, &event_id).await;
+        state.event_broadcaster.send(match result {
+            Ok(archive_path) => Event::new_progression_event_end(
+                event_id,
+                true,
+                Some("Instance deleted successfully"),
+                Some(ProgressionEndValue::InstanceDelete {
+                    instance_uuid: uuid,
+                    archive_path: archive_path.map(|path| path.display().to_string()),
+                }),
+            ),
+            Err(e) => {
+                error!("Failed to delete instance {}: {}", uuid, e.source);
+                Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(format!("{:#}", e.source)),
+                    None,
+                )
+            }
+        });
+    }));
+    Ok(Json(()))
+}
+
+/// Archives the instance if asked to and deletes it, returning where the archive went.
+///
+/// The instance goes back into the map if it fails before anything is deleted
+async fn delete_stopped_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    instance: GameInstance,
+    archive: bool,
+    event_id: &Snowflake,
+) -> Result<Option<PathBuf>, Error> {
+    let instance_path = instance.path().await;
+    let archived = if archive {
+        let graveyard = state.global_settings.lock().await.graveyard_path();
+        archive_to_graveyard(state, uuid, &instance, &graveyard, event_id)
+            .await
+            .map(Some)
+    } else {
+        Ok(None)
+    };
+    let (instance, archive_path) =
+        unregister_instance(&state.instances, uuid, instance, &instance_path, archived).await?;
+
+    // the instance is gone from here on, whatever happens to its files
+    state.port_manager.lock().await.deallocate_instance(uuid);
+    if let GameInstance::GenericInstance(i) = instance {
+        i.destruct().await;
+    };
+    state.disk_usage.forget(uuid);
+    state.instance_info.invalidate(uuid);
+    state.fs_watchers.unwatch(uuid);
+    if let Err(e) = state.scheduler.remove_instance(uuid).await {
+        error!(
+            "Failed to remove scheduled tasks of deleted instance: {}",
+            e
+        );
+    }
+    if let Err(e) = state.macro_triggers.remove_instance(uuid).await {
+        error!("Failed to remove macro triggers of deleted instance: {}", e);
+    }
+    if let Err(e) = state.instance_locations.set(uuid, None).await {
+        error!("Failed to forget the location of deleted instance: {}", e);
+    }
+    if let Err(e) = state.networks.remove_instance(uuid).await {
+        error!("Failed to remove deleted instance from its network: {}", e);
+    }
+    crate::util::fs::remove_dir_all(&instance_path)
+        .await
+        .map_err(|e| Error {
+            kind: e.kind,
+            source: e
+                .source
+                .wrap_err("Failed to delete some or all of the instance's files"),
+        })?;
+    Ok(archive_path)
+}
+
+/// Removes the `.lodestone_config` that makes `instance_path` an instance once `archived` went
+/// through. Fails before anything is deleted otherwise, with the instance back in `instances`
+async fn unregister_instance<T>(
+    instances: &DashMap<InstanceUuid, T>,
+    uuid: &InstanceUuid,
+    instance: T,
+    instance_path: &std::path::Path,
+    archived: Result<Option<PathBuf>, Error>,
+) -> Result<(T, Option<PathBuf>), Error> {
+    let archive_path = match archived {
+        Ok(archive_path) => archive_path,
+        Err(e) => {
+            instances.insert(uuid.clone(), instance);
+            return Err(Error {
+                kind: e.kind,
+                source: e
+                    .source
+                    .wrap_err("Failed to archive the instance, it was not deleted"),
+            });
+        }
+    };
+    if let Err(e) = tokio::fs::remove_file(instance_path.join(".lodestone_config")).await {
+        instances.insert(uuid.clone(), instance);
+        return Err::<_, std::io::Error>(e)
+            .context("Failed to delete the .lodestone_config file, the instance was not deleted")
+            .map_err(Into::into);
+    }
+    Ok((instance, archive_path))
+}
+
+/// Writes the export of the instance into `graveyard`, named after it, its UUID and the time
+async fn archive_to_graveyard(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    instance: &GameInstance,
+    graveyard: &std::path::Path,
+    event_id: &Snowflake,
+) -> Result<PathBuf, Error> {
+    let metadata = ExportMetadata::new(instance).await?;
+    let path = instance.path().await;
+    let entries = tokio::task::spawn_blocking(move || export_entries(&path))
+        .await
+        .context("Failed to spawn blocking task")??;
+    let total: u64 = entries.iter().filter_map(|entry| entry.size).sum();
+    crate::util::fs::create_dir_all(graveyard).await?;
+    let archive_path = graveyard.join(format!(
+        "{}-{}-{}.tar.gz",
+        sanitize_filename::sanitize(&metadata.name),
+        uuid.no_prefix(),
+        chrono::Utc::now().format("%Y%m%d-%H%M%S")
+    ));
+    let graveyard = graveyard.to_owned();
+    let destination = archive_path.clone();
+    let event_broadcaster = state.event_broadcaster.clone();
+    let event_id = event_id.clone();
+    tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        // a failed export leaves no half written archive behind
+        let file = tempfile::NamedTempFile::new_in(&graveyard)
+            .context("Failed to create the archive in the graveyard")?;
+        let mut reported = 0;
+        write_export(
+            &metadata,
+            &entries,
+            BufWriter::with_capacity(64 * 1024, file.as_file()),
+            &mut |archived| {
+                if archived - reported >= EXPORT_PROGRESS_BYTES {
+                    // archiving is most of the deletion, the other half is removing the files
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        &event_id,
+                        format!("Archiving, {}", format_byte_download(archived, total)),
+                        (archived - reported) as f64 / total.max(1) as f64 * 50.0,
+                    ));
+                    reported = archived;
+                }
+            },
+        )?;
+        file.persist(&destination)
+            .context(format!("Failed to write {}", destination.display()))?;
+        Ok(())
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+    Ok(archive_path)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/broken",
+    tag = "instance",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_broken_instances(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<BrokenInstance>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(Json(state.broken_instances.list().await))
+}
+
+#[derive(Deserialize)]
+pub struct RegeneratedConfig {
+    game_type: GameType,
+    /// A new UUID is generated if none is given
+    #[serde(default)]
+    uuid: Option<InstanceUuid>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct RepairRequest {
+    /// Overwrites `.lodestone_config` before loading, for when it is missing or unreadable
+    #[serde(default)]
+    regenerate_config: Option<RegeneratedConfig>,
+}
+
+/// Tries loading a broken instance directory again, after its files were fixed or with a
+/// regenerated `.lodestone_config`
+#[utoipa::path(
+    post,
+    path = "/instance/broken/{name}/repair",
+    tag = "instance",
+    params(
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success", body = InstanceInfo),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn repair_broken_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(name): Path<String>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<RepairRequest>,
+) -> Result<Json<InstanceInfo>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let broken = state
+        .broken_instances
+        .get(&name)
+        .await
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No broken instance directory named {}", name),
+        })?;
+    if let Some(config) = request.regenerate_config {
+        let dot_lodestone_config =
+            DotLodestoneConfig::new(config.uuid.unwrap_or_default(), config.game_type);
+        write_dot_lodestone_config(&broken.path, &dot_lodestone_config).await?;
+    }
+    let (uuid, instance) = match crate::restore_instance(
+        &broken.path,
+        state.event_broadcaster.clone(),
+        state.macro_executor.clone(),
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            state
+                .broken_instances
+                .insert(BrokenInstance::new(
+                    broken.path.clone(),
+                    format!("{:#}", e.source),
+                ))
+                .await;
+            return Err(e);
+        }
+    };
+    if state.instances.contains_key(&uuid) {
+        return Err(Error::coded(
+            ErrorCode::InstanceUuidTaken,
+            format!("Another instance already has the UUID {}", uuid),
+        ));
+    }
+    {
+        // same as on startup, a port in use shouldn't keep the instance from loading
+        let mut port_manager = state.port_manager.lock().await;
+        if let Err(e) = port_manager.claim(instance.port().await, &uuid) {
+            warn!("Repaired instance {}: {}", name, e.source);
+        }
+        if let Ok(RconStatus {
+            port: Some(rcon_port),
+            ..
+        }) = instance.rcon_status().await
+        {
+            if let Err(e) = port_manager.claim(rcon_port, &uuid) {
+                warn!("Repaired instance {}: {}", name, e.source);
+            }
+        }
+    }
+    info!("Repaired instance directory {}", name);
+    let info = instance.get_instance_info().await;
+    state.instances.insert(uuid, instance);
+    state.broken_instances.remove(&name).await;
+    Ok(Json(info))
+}
+
+/// Archived bytes of an export between two progression updates
+const EXPORT_PROGRESS_BYTES: u64 = 16 * 1024 * 1024;
+/// Written chunks of an export held back while the download is slower than the archiving
+const EXPORT_STREAM_CHUNKS: usize = 16;
+
+/// Streams the instance as a tar.gz that `POST /instance/import_archive` of any host takes
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/export",
+    tag = "instance",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn export_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Response, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let metadata = ExportMetadata::new(&instance).await?;
+    let path = instance.path().await;
+    let entries = tokio::task::spawn_blocking(move || export_entries(&path))
+        .await
+        .context("Failed to spawn blocking task")??;
+    let total: u64 = entries.iter().filter_map(|entry| entry.size).sum();
+    let file_name = format!("{}.tar.gz", sanitize_filename::sanitize(&metadata.name));
+
+    let (progression_start, event_id) = ProgressionStartBuilder::new(
+        format!("Exporting instance {}", metadata.name),
+        ProgressionStartValue::InstanceExport {
+            instance_uuid: uuid.clone(),
+        },
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    )
+    .total(total as f64)
+    .build();
+    state.event_broadcaster.send(progression_start);
+    let (tx, rx) = mpsc::channel(EXPORT_STREAM_CHUNKS);
+    let event_broadcaster = state.event_broadcaster.clone();
+    // a client that went away closes the receiver, which fails the next write
+    tokio::task::spawn_blocking(move || {
+        let mut reported = 0;
+        let result = write_export(
+            &metadata,
+            &entries,
+            BufWriter::with_capacity(64 * 1024, ChannelWriter(tx)),
+            &mut |archived| {
+                if archived - reported >= EXPORT_PROGRESS_BYTES {
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        &event_id,
+                        format!("Exporting, {}", format_byte_download(archived, total)),
+                        (archived - reported) as f64,
+                    ));
+                    reported = archived;
+                }
+            },
+        );
+        event_broadcaster.send(match result {
+            Ok(bytes) => Event::new_progression_event_end(
+                event_id,
+                true,
+                Some("Export complete"),
+                Some(ProgressionEndValue::InstanceExport {
+                    instance_uuid: uuid,
+                    bytes,
+                }),
+            ),
+            Err(e) => {
+                error!("Failed to export instance {}: {}", uuid, e.source);
+                Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(format!("Export failed: {}", e.source)),
+                    None,
+                )
+            }
+        });
+    });
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        StreamBody::new(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct MigrateInstanceBody {
+    /// Absolute path of an existing directory to move the instance directory under
+    destination: String,
+}
+
+/// Moves the instance directory under another directory, e.g. on another disk.
+///
+/// The files are copied and verified before the old directory is removed, the instance stays
+/// where it is if anything fails. It can't be started or deleted meanwhile. Progress is reported
+/// as a progression
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/migrate",
+    tag = "instance",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = MigrateInstanceBody,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn migrate_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(body): Json<MigrateInstanceBody>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can migrate an instance"),
+        });
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let destination_root = PathBuf::from(body.destination);
+    // the instance refuses to start or be deleted while its files are copied
+    let migrating = state.migrating.try_begin(&uuid)?;
+    let instance = game_instance(&state, &uuid)?;
+    if instance.state().await != State::Stopped {
+        return Err(Error::coded(
+            ErrorCode::InstanceNotStopped,
+            "Instance must be stopped before migrating",
+        ));
+    }
+    let source = instance.path().await;
+    let planned = async {
+        let migration = tokio::task::spawn_blocking({
+            let uuid = uuid.clone();
+            let source = source.clone();
+            let destination_root = destination_root.clone();
+            move || Migration::plan(uuid, &source, &destination_root)
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        let free = volume_space(&mut *state.system.lock().await, &destination_root)
+            .map(|space| space.free);
+        check_space(0, None, free, migration.bytes)?;
+        Ok::<_, Error>(migration)
+    }
+    .await;
+    let migration = Arc::new(planned?);
+
+    let (progression_start, event_id) = ProgressionStartBuilder::new(
+        format!("Migrating instance {}", instance.name().await),
+        ProgressionStartValue::InstanceMigration {
+            instance_uuid: uuid.clone(),
+            destination: destination_root,
+        },
+        caused_by.clone(),
+    )
+    .total(migration.bytes as f64)
+    .build();
+    state.event_broadcaster.send(progression_start);
+    tokio::spawn(propagate(async move {
+        let _migrating = migrating;
+        let event_broadcaster = state.event_broadcaster.clone();
+        let copied = tokio::task::spawn_blocking({
+            let migration = migration.clone();
+            let locations = state.instance_locations.clone();
+            let event_broadcaster = event_broadcaster.clone();
+            move || {
+                let total = migration.bytes;
+                let mut copied = 0;
+                let mut reported = 0;
+                let result = migration
+                    .copy(&locations, &mut |bytes| {
+                        copied += bytes;
+                        if copied - reported >= EXPORT_PROGRESS_BYTES {
+                            event_broadcaster.send(Event::new_progression_event_update(
+                                &event_id,
+                                format!("Copying, {}", format_byte_download(copied, total)),
+                                (copied - reported) as f64,
+                            ));
+                            reported = copied;
+                        }
+                    })
+                    .and_then(|_| migration.verify());
+                (result, event_id)
+            }
+        })
+        .await;
+        let (copied, event_id) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                error!("Migration of instance {} panicked: {}", uuid, e);
+                let migration = migration.clone();
+                let locations = state.instance_locations.clone();
+                let _ = tokio::task::spawn_blocking(move || migration.discard(&locations)).await;
+                return;
+            }
+        };
+        // the copy is loaded before the switch, so a copy that doesn't load changes nothing
+        let migrated = match copied {
+            Ok(()) => match crate::restore_instance(
+                &migration.destination,
+                event_broadcaster.clone(),
+                state.macro_executor.clone(),
+            )
+            .await
             {
+                Ok((_, migrated)) => match migration
+                    .commit(&state.instance_locations, path_to_instances())
+                    .await
+                {
+                    Ok(()) => Ok(migrated),
+                    Err(e) => {
+                        if let GameInstance::GenericInstance(i) = migrated {
+                            i.destruct().await;
+                        }
+                        Err(e)
+                    }
+                },
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match migrated {
+            Ok(migrated) => {
+                if let Some(GameInstance::GenericInstance(i)) =
+                    state.instances.insert(uuid.clone(), migrated)
+                {
+                    i.destruct().await;
+                }
+                state.disk_usage.forget(&uuid);
+                state.instance_info.invalidate(&uuid);
+                // the watcher is still on the old directory
+                state.fs_watchers.unwatch(&uuid);
+                state
+                    .audit_log
+                    .record(
+                        &uuid,
+                        caused_by,
+                        AuditTarget::Property {
+                            name: "path".to_string(),
+                        },
+                        audit_value(&source, false),
+                        audit_value(&migration.destination, false),
+                    )
+                    .await;
+                if let Err(e) = migration.finish(&state.instance_locations).await {
+                    warn!(
+                        "Migrated instance {} but failed to remove its old directory {}, remove it by hand: {}",
+                        uuid,
+                        source.display(),
+                        e.source
+                    );
+                }
+                info!(
+                    "Migrated instance {} from {} to {}",
+                    uuid,
+                    source.display(),
+                    migration.destination.display()
+                );
+                event_broadcaster.send(Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some("Migration complete"),
+                    Some(ProgressionEndValue::InstanceMigration {
+                        instance_uuid: uuid,
+                        path: migration.destination.clone(),
+                        bytes: migration.bytes,
+                    }),
+                ));
+            }
+            Err(e) => {
+                error!("Failed to migrate instance {}: {}", uuid, e.source);
+                let discarded = {
+                    let migration = migration.clone();
+                    let locations = state.instance_locations.clone();
+                    tokio::task::spawn_blocking(move || migration.discard(&locations))
+                };
+                let _ = discarded.await;
                 event_broadcaster.send(Event::new_progression_event_end(
                     event_id,
                     false,
-                    Some("Failed to delete .lodestone_config. Instance not deleted"),
+                    Some(format!(
+                        "Migration failed, the instance was left in place: {}",
+                        e.source
+                    )),
                     None,
                 ));
-                state.instances.insert(uuid.clone(), instance);
-                return Err::<Json<()>, std::io::Error>(e)
-                    .context("Failed to delete .lodestone_config file. Instance not deleted")
-                    .map_err(Into::into);
             }
+        }
+    }));
+    Ok(Json(()))
+}
 
-            state
-                .port_manager
-                .lock()
+/// Creates an instance from the archive of `GET /instance/:uuid/export`, uploaded as
+/// `multipart/form-data`. It gets a new UUID, and another port if its own is taken on this host.
+/// Nothing is left behind if the import fails
+#[utoipa::path(
+    post,
+    path = "/instance/import_archive",
+    tag = "instance",
+    responses(
+        (status = 200, description = "Success", body = InstanceInfo),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn import_instance_archive(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    headers: HeaderMap,
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<InstanceInfo>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let (max_path_length, max_upload_size) = {
+        let global_settings = state.global_settings.lock().await;
+        (
+            global_settings.max_path_length(),
+            global_settings.max_upload_size(),
+        )
+    };
+    let total = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if total.map_or(false, |total| total > max_upload_size) {
+        return Err(upload_too_large(max_upload_size));
+    }
+    let instance_uuid =
+        InstanceUuid::unique_among(state.instances.iter().map(|entry| entry.key().clone()))?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+
+    state.creation_registry.register(instance_uuid.clone());
+    let (progression_start, event_id) = ProgressionStartBuilder::new(
+        "Importing instance",
+        ProgressionStartValue::InstanceCreation {
+            instance_uuid: instance_uuid.clone(),
+        },
+        caused_by,
+    )
+    .total(100.0)
+    .build();
+    state.event_broadcaster.send(progression_start);
+    let cancellation = state
+        .cancellation_registry
+        .register(&event_id, UserAction::CreateInstance);
+    // a disconnecting client fails the body stream instead of cancelling the import midway
+    tokio::spawn(propagate(async move {
+        let archive = path_to_tmp().join(format!("import-{}.tar.gz", instance_uuid.no_prefix()));
+        // the upload is removed whether or not the import succeeds
+        let _upload = PartialFile(Some(archive.clone()));
+        let event_broadcaster = state.event_broadcaster.clone();
+        let result = async {
+            let mut field = multipart
+                .next_field()
+                .await
+                .context("Failed to read multipart field")?
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("No archive was uploaded"),
+                })?;
+            let mut file = crate::util::fs::create(&archive).await?;
+            let mut uploaded = 0_u64;
+            let mut reported = 0_u64;
+            while let Some(chunk) = field
+                .chunk()
+                .await
+                .context("Failed to read the uploaded archive")?
+            {
+                checkpoint(&cancellation)?;
+                uploaded += chunk.len() as u64;
+                if uploaded > max_upload_size {
+                    return Err(upload_too_large(max_upload_size));
+                }
+                file.write_all(&chunk)
+                    .await
+                    .context("Failed to write the uploaded archive")?;
+                // the upload is the first half of the import
+                if let Some(total) = total {
+                    let progress = (uploaded * 50 / total.max(1)).min(50);
+                    if progress > reported {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            &event_id,
+                            format!("Uploading, {}", format_byte_download(uploaded, total)),
+                            (progress - reported) as f64,
+                        ));
+                        reported = progress;
+                    }
+                }
+            }
+            file.flush()
                 .await
-                .deallocate(instance.port().await);
-            let instance_path = instance.path().await;
-            // if instance is generic
-            if let GameInstance::GenericInstance(i) = instance {
-                i.destruct().await;
+                .context("Failed to write the uploaded archive")?;
+            drop(file);
+
+            let metadata = tokio::task::spawn_blocking({
+                let archive = archive.clone();
+                move || {
+                    let metadata = read_export_metadata(&archive)?;
+                    verify_archive(&archive)?
+                        .ok_or_else(|| Error {
+                            kind: ErrorKind::BadRequest,
+                            source: eyre!("The export has no checksum manifest"),
+                        })?
+                        .into_result()?;
+                    Ok::<_, Error>(metadata)
+                }
+            })
+            .await
+            .context("Failed to spawn blocking task")??;
+            ensure_name_available(&state, &metadata.name, None).await?;
+            let needed = archive_size(archive.clone()).await?;
+            let free = volume_space(&mut *state.system.lock().await, path_to_instances())
+                .map(|space| space.free);
+            check_space(0, None, free, needed)?;
+
+            let setup_path =
+                new_instance_dir(&sanitize_filename::sanitize(&metadata.name), &instance_uuid);
+            if setup_path.exists() {
+                return Err(Error {
+                    kind: ErrorKind::Conflict,
+                    source: eyre!("{} already exists", setup_path.display()),
+                });
+            }
+            // another port is picked before the files are in place, so only the write of it can
+            // fail once the instance is loaded
+            let port = {
+                let mut port_manager = state.port_manager.lock().await;
+                match port_manager.claim(metadata.port, &instance_uuid) {
+                    Ok(()) => metadata.port,
+                    Err(_) => port_manager.allocate(metadata.port, &instance_uuid)?,
+                }
             };
-            let res = crate::util::fs::remove_dir_all(instance_path).await;
-            match &res {
-                Ok(_) => event_broadcaster.send(Event::new_progression_event_end(
-                    event_id,
-                    true,
-                    Some("Instance deleted successfully"),
-                    Some(ProgressionEndValue::InstanceDelete {
-                        instance_uuid: uuid.clone(),
-                    }),
-                )),
-                Err(e) => {
-                    event_broadcaster.send(Event::new_progression_event_end(
-                        event_id,
-                        false,
-                        Some(&format!(
-                            "Failed to delete some of all of instance's files : {e}"
-                        )),
-                        None,
-                    ));
+            event_broadcaster.send(Event::new_progression_event_update(
+                &event_id,
+                format!("Extracting {}", format_byte(needed)),
+                (50 - reported) as f64,
+            ));
+            let imported = async {
+                tokio::task::spawn_blocking({
+                    let archive = archive.clone();
+                    let setup_path = setup_path.clone();
+                    let cancellation = cancellation.clone();
+                    move || {
+                        check_archive_entries(&archive, &setup_path, max_path_length)?;
+                        extract_archive(&archive, &setup_path, &|_| false, &mut |_| {
+                            checkpoint(&cancellation)
+                        })?;
+                        for name in [EXPORT_METADATA_FILE_NAME, MANIFEST_FILE_NAME] {
+                            std::fs::remove_file(setup_path.join(name))
+                                .context(format!("Failed to remove {}", name))?;
+                        }
+                        Ok::<(), Error>(())
+                    }
+                })
+                .await
+                .context("Failed to spawn blocking task")??;
+                checkpoint(&cancellation)?;
+                event_broadcaster.send(Event::new_progression_event_update(
+                    &event_id,
+                    "Loading the instance",
+                    40.0,
+                ));
+                write_dot_lodestone_config(
+                    &setup_path,
+                    &metadata.dot_lodestone_config(instance_uuid.clone()),
+                )
+                .await?;
+                let (_, instance) = crate::restore_instance(
+                    &setup_path,
+                    state.event_broadcaster.clone(),
+                    state.macro_executor.clone(),
+                )
+                .await?;
+                if port != metadata.port {
+                    if let Err(e) = instance.set_port(port).await {
+                        if let GameInstance::GenericInstance(i) = instance {
+                            i.destruct().await;
+                        }
+                        return Err(e);
+                    }
+                    info!(
+                        "Port {} is taken, the imported instance {} uses {} instead",
+                        metadata.port, metadata.name, port
+                    );
                 }
+                Ok::<_, Error>(instance)
             }
-            res.map(|_| Json(()))
+            .await;
+            let instance = match imported {
+                Ok(instance) => instance,
+                Err(e) => {
+                    state
+                        .port_manager
+                        .lock()
+                        .await
+                        .deallocate_instance(&instance_uuid);
+                    if let Err(cleanup_error) = crate::util::fs::remove_dir_all(&setup_path).await {
+                        error!(
+                            "Failed to remove the partly imported instance: {}",
+                            cleanup_error
+                        );
+                    }
+                    return Err(e);
+                }
+            };
+
+            let mut perm = requester.permissions.clone();
+            perm.can_start_instance.insert(instance_uuid.clone());
+            perm.can_stop_instance.insert(instance_uuid.clone());
+            perm.can_view_instance.insert(instance_uuid.clone());
+            perm.can_read_instance_file.insert(instance_uuid.clone());
+            perm.can_write_instance_file.insert(instance_uuid.clone());
+            // ignore errors since we don't care if the permissions update fails
+            let _ = state
+                .users_manager
+                .write()
+                .await
+                .update_permissions(&requester.uid, perm, CausedBy::System)
+                .await
+                .map_err(|e| {
+                    error!("Failed to update permissions: {:?}", e);
+                    e
+                });
+            let info = instance.get_instance_info().await;
+            state.instances.insert(instance_uuid.clone(), instance);
+            Ok::<_, Error>(info)
         }
-    } else {
-        Err(Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })
-    }
+        .await;
+        event_broadcaster.send(match &result {
+            Ok(info) => Event::new_progression_event_end(
+                event_id,
+                true,
+                Some("Instance imported successfully"),
+                Some(ProgressionEndValue::InstanceCreation(info.clone())),
+            ),
+            Err(_) if cancellation.is_cancelled() => {
+                Event::new_progression_event_cancelled(event_id, Some(instance_uuid.clone()))
+            }
+            Err(e) => Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(format!("Instance import failed: {}", e.source)),
+                None,
+            ),
+        });
+        result.map(Json)
+    }))
+    .await
+    .context("Import task panicked")?
 }
 
 pub fn get_instance_routes(state: AppState) -> Router {
     Router::new()
         .route("/instance/list", get(get_instance_list))
+        .route("/instance/lookup", get(lookup_instance))
         .route(
             "/instance/create/:game_type",
             post(create_minecraft_instance),
         )
+        .route("/instance/validate/:game_type", post(validate_setup_value))
         .route("/instance/create_generic", post(create_generic_instance))
+        .route("/instance/import_archive", post(import_instance_archive))
+        .route("/instance/broken", get(get_broken_instances))
+        .route(
+            "/instance/broken/:name/repair",
+            post(repair_broken_instance),
+        )
         .route("/instance/:uuid", delete(delete_instance))
         .route("/instance/:uuid/info", get(get_instance_info))
+        .route("/instance/:uuid/creation-status", get(get_creation_status))
+        .route("/instance/:uuid/export", get(export_instance))
+        .route("/instance/:uuid/migrate", post(migrate_instance))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::t_configurable::{Game, MinecraftVariant};
+
+    fn instance_info(name: &str, creation_time: i64, state: State) -> InstanceInfo {
+        InstanceInfo {
+            uuid: InstanceUuid::default(),
+            name: name.to_string(),
+            game_type: Game::MinecraftJava {
+                variant: MinecraftVariant::Vanilla,
+            },
+            description: "".to_string(),
+            version: "1.20.1".to_string(),
+            port: 25565,
+            creation_time,
+            path: name.to_string(),
+            auto_start: false,
+            restart_on_crash: false,
+            state,
+            player_count: Some(0),
+            max_player_count: None,
+            player_list: None,
+            launch_command: None,
+            eula_accepted: None,
+            slug: name.to_string(),
+            last_started: None,
+            last_started_at: None,
+            last_stopped_at: None,
+            uptime_seconds: None,
+            last_exit_code: None,
+            last_exit_reason: None,
+            tags: Vec::new(),
+            maintenance: None,
+            degraded: false,
+        }
+    }
+
+    fn query(query: &str) -> InstanceListQuery {
+        let uri = format!("/instance/list?{}", query).parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    fn entries() -> Vec<ListEntry> {
+        vec![
+            ListEntry::Info(instance_info("b", 2, State::Running)),
+            ListEntry::Degraded(InstanceUuid::default()),
+            ListEntry::Info(instance_info("a", 3, State::Stopped)),
+            ListEntry::Info(instance_info("c", 1, State::Running)),
+        ]
+    }
+
+    fn names(page: &[serde_json::Value]) -> Vec<Option<&str>> {
+        page.iter().map(|entry| entry["name"].as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_failed_delete_keeps_instance() {
+        let instances = DashMap::new();
+        let uuid = InstanceUuid::default();
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join(".lodestone_config");
+        std::fs::write(&config, "{}").unwrap();
+        std::fs::write(dir.path().join("server.properties"), "").unwrap();
+
+        // a failed archive stops the deletion before anything is removed
+        let e = unregister_instance(
+            &instances,
+            &uuid,
+            "instance",
+            dir.path(),
+            Err(Error {
+                kind: ErrorKind::InsufficientStorage,
+                source: eyre!("Graveyard is full"),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::InsufficientStorage));
+        assert_eq!(instances.remove(&uuid).unwrap().1, "instance");
+        assert!(config.is_file());
+        assert!(dir.path().join("server.properties").is_file());
+
+        // so does a config that can't be removed
+        let missing = dir.path().join("missing");
+        assert!(
+            unregister_instance(&instances, &uuid, "instance", &missing, Ok(None))
+                .await
+                .is_err()
+        );
+        assert!(instances.contains_key(&uuid));
+        instances.remove(&uuid);
+
+        let archive = dir.path().join("graveyard.tar.gz");
+        let (instance, archive_path) = unregister_instance(
+            &instances,
+            &uuid,
+            "instance",
+            dir.path(),
+            Ok(Some(archive.clone())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(instance, "instance");
+        assert_eq!(archive_path, Some(archive));
+        assert!(!instances.contains_key(&uuid));
+        assert!(!config.exists());
+    }
+
+    #[test]
+    fn test_instance_list_page() {
+        let (total, page) = query("").page(entries()).unwrap();
+        assert_eq!(total, 4);
+        assert_eq!(names(&page), [Some("c"), Some("b"), Some("a"), None]);
+        assert_eq!(page[3]["degraded"], true);
+
+        let (total, page) = query("sort=name&offset=1&limit=2").page(entries()).unwrap();
+        assert_eq!(total, 4);
+        assert_eq!(names(&page), [Some("b"), Some("c")]);
+
+        // degraded entries can't be told to match a filter
+        let (total, page) = query("state=Running&fields=name").page(entries()).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(names(&page), [Some("c"), Some("b")]);
+        let mut fields: Vec<&String> = page[0].as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["name", "uuid"]);
+    }
+}
@@ -0,0 +1,115 @@
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+
+use crate::{
+    announcements::AnnouncementsConfig, auth::user::UserAction, error::Error,
+    traits::t_server::TServer, types::InstanceUuid, AppState,
+};
+
+use super::util::game_instance;
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/announcements",
+    tag = "instance_announcements",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_announcements(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<AnnouncementsConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .announcements()
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/announcements",
+    tag = "instance_announcements",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_announcements(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<AnnouncementsConfig>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .set_announcements(config)
+        .await
+        .map(|_| Json(()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/announcements/{id}/test",
+    tag = "instance_announcements",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn test_announcement(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .test_announcement(&id)
+        .await
+        .map(|_| Json(()))
+}
+
+pub fn get_instance_announcements_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/announcements",
+            get(get_announcements).put(set_announcements),
+        )
+        .route(
+            "/instance/:uuid/announcements/:id/test",
+            post(test_announcement),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,350 @@
+use std::path::PathBuf;
+
+use axum::{
+    extract::{Path, Query},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+    auth::user::UserAction,
+    backups::{Backup, BackupConfig},
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    implementations::{command, generic, minecraft},
+    prelude::GameInstance,
+    traits::{
+        t_configurable::{GameType, TConfigurable},
+        t_server::{State, TServer},
+    },
+    types::{DotLodestoneConfig, InstanceUuid, Snowflake},
+    AppState,
+};
+
+use super::util::game_instance;
+
+#[derive(Deserialize)]
+pub struct RestoreQuery {
+    #[serde(default)]
+    pub(super) skip_verify: bool,
+}
+
+async fn instance_path(state: &AppState, uuid: &InstanceUuid) -> Result<PathBuf, Error> {
+    Ok(game_instance(state, uuid)?.path().await)
+}
+
+/// Loads the instance again from its directory, used once a restore replaced its files
+async fn reload_instance(state: &AppState, path: PathBuf) -> Result<GameInstance, Error> {
+    let dot_lodestone_config: DotLodestoneConfig = serde_json::from_slice(
+        &tokio::fs::read(path.join(".lodestone_config"))
+            .await
+            .context("Failed to read .lodestone_config file")?,
+    )
+    .context("Failed to parse .lodestone_config file")?;
+    Ok(match dot_lodestone_config.game_type() {
+        GameType::MinecraftJava => minecraft::MinecraftInstance::restore(
+            path,
+            dot_lodestone_config,
+            state.event_broadcaster.clone(),
+            state.macro_executor.clone(),
+        )
+        .await?
+        .into(),
+        GameType::Generic => generic::GenericInstance::restore(
+            path,
+            dot_lodestone_config,
+            state.event_broadcaster.clone(),
+            state.macro_executor.clone(),
+        )
+        .await?
+        .into(),
+        GameType::Command => command::CommandInstance::restore(
+            path,
+            dot_lodestone_config,
+            state.event_broadcaster.clone(),
+        )
+        .await?
+        .into(),
+        GameType::MinecraftBedrock => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Bedrock instances are not supported"),
+            })
+        }
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/backups",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Backup>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let path = instance_path(&state, &uuid).await?;
+    state.backup_manager.list(&path).await.map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/backup",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<BackupConfig>,
+) -> Result<Json<Snowflake>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let (path, name) = {
+        let instance = game_instance(&state, &uuid)?;
+        (instance.path().await, instance.name().await)
+    };
+    state.backup_manager.try_begin(&uuid).await?;
+    let id = Snowflake::default();
+    tokio::task::spawn(async move {
+        match state
+            .backup_manager
+            .create(
+                &uuid,
+                &path,
+                &name,
+                id,
+                config,
+                state.event_broadcaster.clone(),
+                caused_by,
+            )
+            .await
+        {
+            #[cfg(feature = "s3-backups")]
+            Ok(backup) => {
+                crate::remote_backups::upload_new_backup(
+                    &state.backup_manager,
+                    &state.global_settings,
+                    &uuid,
+                    &path,
+                    &backup,
+                    &state.event_broadcaster,
+                )
+                .await
+            }
+            #[cfg(not(feature = "s3-backups"))]
+            Ok(_) => {}
+            Err(e) => error!("Failed to back up instance {}: {}", name, e),
+        }
+        state.backup_manager.end(&uuid).await;
+    });
+    Ok(Json(id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/backup/{backup_id}/verify",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("backup_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn verify_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Backup>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let path = instance_path(&state, &uuid).await?;
+    state
+        .backup_manager
+        .verify(&path, &backup_id)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/backup/{backup_id}/restore",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("backup_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn restore_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, Snowflake)>,
+    Query(query): Query<RestoreQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state.backup_manager.try_begin(&uuid).await?;
+    let result = restore_instance(&state, &uuid, &backup_id, !query.skip_verify).await;
+    state.backup_manager.end(&uuid).await;
+    result.map(Json)
+}
+
+/// Restores a backup over a stopped instance and loads the instance again.
+/// The caller holds the backup operation of the instance
+pub(super) async fn restore_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    backup_id: &Snowflake,
+    verify: bool,
+) -> Result<(), Error> {
+    let max_path_length = state.global_settings.lock().await.max_path_length();
+    // the instance is taken out while its files are swapped so it cannot be started meanwhile
+    let instance = match state.instances.remove(uuid) {
+        Some((_, instance)) => instance,
+        None => {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Instance not found"),
+            });
+        }
+    };
+    if instance.state().await != State::Stopped {
+        state.instances.insert(uuid.clone(), instance);
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance must be stopped before restoring a backup"),
+        });
+    }
+    let path = instance.path().await;
+    let result = state
+        .backup_manager
+        .restore(&path, backup_id, max_path_length, verify)
+        .await;
+    match result {
+        // the previous files are back in place, so is the instance
+        Err(e) => {
+            state.instances.insert(uuid.clone(), instance);
+            Err(e)
+        }
+        Ok(_) => {
+            if let GameInstance::GenericInstance(i) = instance {
+                i.destruct().await;
+            }
+            match reload_instance(state, path).await {
+                Ok(instance) => {
+                    state.instances.insert(uuid.clone(), instance);
+                    Ok(())
+                }
+                Err(e) => Err(Error {
+                    kind: e.kind,
+                    source: e
+                        .source
+                        .wrap_err("Backup restored but the instance failed to load, restart the core to retry"),
+                }),
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/backup/{backup_id}",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("backup_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let path = instance_path(&state, &uuid).await?;
+    state.backup_manager.try_begin(&uuid).await?;
+    let result = state.backup_manager.delete(&path, &backup_id).await;
+    state.backup_manager.end(&uuid).await;
+    #[cfg(feature = "s3-backups")]
+    if let Ok(backup) = &result {
+        crate::remote_backups::delete_remote_copies(
+            std::slice::from_ref(backup),
+            &state.global_settings,
+        )
+        .await;
+    }
+    result.map(|_| Json(()))
+}
+
+pub fn get_instance_backups_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/backups", get(get_backups))
+        .route("/instance/:uuid/backup", post(create_backup))
+        .route("/instance/:uuid/backup/:backup_id", delete(delete_backup))
+        .route(
+            "/instance/:uuid/backup/:backup_id/verify",
+            post(verify_backup),
+        )
+        .route(
+            "/instance/:uuid/backup/:backup_id/restore",
+            post(restore_backup),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,111 @@
+use axum::{
+    extract::{Path, Query},
+    routing::get,
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::{
+    auth::user::UserAction,
+    error::Error,
+    events::CausedBy,
+    implementations::minecraft::chat::{ChatMessage, ChatPage},
+    types::InstanceUuid,
+    AppState,
+};
+
+use super::util::minecraft_instance;
+
+#[derive(Deserialize)]
+pub struct ChatQuery {
+    /// `next_seq` of the last page, only the messages after it are returned
+    since: Option<u64>,
+}
+
+/// Recent chat, player messages recognized in the console and those relayed through Lodestone.
+/// Only the last few hundred are kept, and none across daemon restarts
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/chat",
+    tag = "instance_chat",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("since" = Option<u64>, Query, description = "Sequence number to start from, every message kept if left out"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = ChatPage),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_chat(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<ChatQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ChatPage>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    Ok(Json(instance.chat(query.since).await))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SendChatRequest {
+    message: String,
+    /// Shown in front of the message, the requester's name if left out. Lets a bridge pass on who
+    /// wrote the message elsewhere
+    sender: Option<String>,
+}
+
+/// Shows a message to every player, prefixed with the sender's name. Takes `SendChat` rather than
+/// console access
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/chat",
+    tag = "instance_chat",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = SendChatRequest,
+    responses(
+        (status = 200, description = "The message as kept in the chat history", body = ChatMessage),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn send_chat(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<SendChatRequest>,
+) -> Result<Json<ChatMessage>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::SendChat(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let sender = request.sender.unwrap_or_else(|| requester.username.clone());
+    minecraft_instance(&state, &uuid)?
+        .send_chat(
+            &sender,
+            &request.message,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+        )
+        .await
+        .map(Json)
+}
+
+pub fn get_instance_chat_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/chat", get(get_chat).post(send_chat))
+        .with_state(state)
+}
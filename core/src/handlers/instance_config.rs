@@ -1,22 +1,63 @@
 use axum::{
+    body::Bytes,
     extract::Path,
-    routing::{get, put},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
+use indexmap::IndexMap;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::error;
+use ts_rs::TS;
+use utoipa::ToSchema;
 
 use crate::{
+    audit::{audit_value, AuditTarget},
     auth::user::UserAction,
+    backups::{BackupConfig, BackupSchedule},
     error::{Error, ErrorKind},
-    traits::t_configurable::{
-        manifest::{ConfigurableManifest, ConfigurableValue},
-        TConfigurable,
+    events::{
+        new_fs_event, CausedBy, Event, EventInner, FSOperation, FSTarget, InstanceEvent,
+        InstanceEventInner, ProgressionEndValue, ProgressionStartBuilder, ProgressionStartValue,
     },
-    types::InstanceUuid,
+    implementations::{command::COMMAND_SECTION_ID, minecraft::versions::is_downgrade},
+    java::JavaSelection,
+    prelude::GameInstance,
+    restart_policy::RestartPolicy,
+    traits::{
+        t_configurable::{
+            manifest::{ConfigurableManifest, ConfigurableValue, SettingManifest},
+            TConfigurable,
+        },
+        t_server::{State, TServer},
+        TInstance,
+    },
+    types::{AutoStartOrder, InstanceUuid, Maintenance, Snowflake, MAX_MAINTENANCE_REASON_LENGTH},
     AppState,
 };
 
+use super::{
+    instance_fs::{read_dot_lodestone_config, write_dot_lodestone_config},
+    util::{ensure_name_available, game_instance, minecraft_instance},
+};
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/configurable_manifest",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = ConfigurableManifest),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_configurable_manifest(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -27,13 +68,23 @@ pub async fn get_instance_configurable_manifest(
         &UserAction::AccessSetting(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     Ok(Json(instance.configurable_manifest().await))
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/settings",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = ConfigurableManifest),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_settings(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -47,13 +98,26 @@ pub async fn get_instance_settings(
     if uuid.to_string().starts_with("DOCKER-") {
         return Ok(Json(ConfigurableManifest::default()));
     }
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     Ok(Json(instance.configurable_manifest().await))
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/settings/{section_id}/{setting_id}",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("section_id" = String, Path),
+        ("setting_id" = String, Path),
+    ),
+    request_body = ConfigurableValue,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn set_instance_setting(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, section_id, setting_id)): Path<(InstanceUuid, String, String)>,
@@ -65,18 +129,68 @@ pub async fn set_instance_setting(
         &UserAction::AccessSetting(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or(Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
+    if let GameInstance::MinecraftInstance(minecraft) = &instance {
+        minecraft
+            .check_setting_update(&requester, &section_id, &setting_id, &value)
+            .await?;
+    }
+    // only admins may change what a command instance runs
+    if matches!(instance, GameInstance::CommandInstance(_))
+        && section_id == COMMAND_SECTION_ID
+        && !requester.can_perform_action(&UserAction::AdministerInstance(Some(uuid.clone())))
+    {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only admins may change what a command instance runs"),
+        });
+    }
 
+    let old_setting = instance
+        .configurable_manifest()
+        .await
+        .get_setting(&section_id, &setting_id)
+        .cloned();
+    let is_secret = old_setting.as_ref().map_or(false, |s| s.is_secret());
     instance
-        .update_configurable(&section_id, &setting_id, value)
+        .update_configurable(&section_id, &setting_id, value.clone())
         .await?;
-
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Setting {
+                section_id,
+                setting_id,
+            },
+            old_setting
+                .as_ref()
+                .and_then(|s| s.get_value())
+                .and_then(|v| audit_value(v, is_secret)),
+            audit_value(value, is_secret),
+        )
+        .await;
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/name",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn set_instance_name(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -88,18 +202,42 @@ pub async fn set_instance_name(
         &UserAction::AccessSetting(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
+    let instance = game_instance(&state, &uuid)?;
+    ensure_name_available(&state, &new_name, Some(&uuid)).await?;
+    let old_value = instance.name().await;
+    instance.set_name(new_name.clone()).await?;
     state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .set_name(new_name)
-        .await?;
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "name".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(new_name, false),
+        )
+        .await;
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/description",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn set_instance_description(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -111,18 +249,41 @@ pub async fn set_instance_description(
         &UserAction::AccessSetting(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
+    let instance = game_instance(&state, &uuid)?;
+    let old_value = instance.description().await;
+    instance.set_description(new_description.clone()).await?;
     state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .set_description(new_description)
-        .await?;
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "description".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(new_description, false),
+        )
+        .await;
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/version/{new_version}",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("new_version" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn change_version(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, new_version)): Path<(InstanceUuid, String)>,
@@ -133,15 +294,1087 @@ pub async fn change_version(
         &UserAction::AccessSetting(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
+    let instance = game_instance(&state, &uuid)?;
+    let old_value = instance.version().await;
+    instance.change_version(new_version.clone()).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "version".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(new_version, false),
+        )
+        .await;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/restart_policy",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_restart_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<RestartPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    Ok(Json(instance.restart_policy().await))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/restart_policy",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_restart_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(restart_policy): Json<RestartPolicy>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let old_value = instance.restart_policy().await;
+    instance.set_restart_policy(restart_policy).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "restart_policy".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(restart_policy, false),
+        )
+        .await;
+    Ok(Json(()))
+}
+
+/// Whether the instance starts with Lodestone and where it goes in the start order
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct AutoStartSettings {
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub order: AutoStartOrder,
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/auto_start",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_auto_start(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<AutoStartSettings>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    Ok(Json(AutoStartSettings {
+        enabled: instance.auto_start().await,
+        order: read_dot_lodestone_config(&instance.path().await)
+            .await?
+            .auto_start_order(),
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/auto_start",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_auto_start(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(settings): Json<AutoStartSettings>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let root = instance.path().await;
+    let mut config = read_dot_lodestone_config(&root).await?;
+    let old_value = AutoStartSettings {
+        enabled: instance.auto_start().await,
+        order: config.auto_start_order(),
+    };
+    instance.set_auto_start(settings.enabled).await?;
+    config.set_auto_start_order(settings.order);
+    write_dot_lodestone_config(&root, &config).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "auto_start".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(settings, false),
+        )
+        .await;
+    Ok(Json(()))
+}
+
+/// Replaces the instance's tags, adding and removing tags is done by sending the new list
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/tags",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = [String],
+    responses(
+        (status = 200, description = "Success", body = [String]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_instance_tags(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(tags): Json<Vec<String>>,
+) -> Result<Json<Vec<String>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let root = instance.path().await;
+    let mut config = read_dot_lodestone_config(&root).await?;
+    let old_value = config.tags().to_vec();
+    config.set_tags(tags)?;
+    write_dot_lodestone_config(&root, &config).await?;
+    let new_value = config.tags().to_vec();
+    // the cache drops it on the event too, this way the requester's next read already has them
+    state.instance_info.invalidate(&uuid);
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    state.event_broadcaster.send(Event {
+        event_inner: EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid: uuid.clone(),
+            instance_name: instance.name().await,
+            instance_event_inner: InstanceEventInner::TagsChanged {
+                tags: new_value.clone(),
+            },
+        }),
+        details: "".to_string(),
+        snowflake: Snowflake::default(),
+        caused_by: caused_by.clone(),
+    });
+    state
+        .audit_log
+        .record(
+            &uuid,
+            caused_by,
+            AuditTarget::Property {
+                name: "tags".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(&new_value, false),
+        )
+        .await;
+    Ok(Json(new_value))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MaintenanceRequest {
+    enabled: bool,
+    /// Shown to whoever tries to start the instance, ignored when disabling
+    reason: Option<String>,
+}
+
+/// Puts the instance under maintenance or takes it out of it. While under maintenance it can
+/// only be started by the user who set it and those allowed to bypass maintenance, schedules and
+/// crash restarts leave it stopped
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/maintenance",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = MaintenanceRequest,
+    responses(
+        (status = 200, description = "The maintenance set, null once disabled", body = Maintenance),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_instance_maintenance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<MaintenanceRequest>,
+) -> Result<Json<Option<Maintenance>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let reason = request
+        .reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    if reason.as_ref().map_or(false, |reason| {
+        reason.chars().count() > MAX_MAINTENANCE_REASON_LENGTH
+    }) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "The reason can be at most {} characters long",
+                MAX_MAINTENANCE_REASON_LENGTH
+            ),
+        });
+    }
+    let instance = game_instance(&state, &uuid)?;
+    let root = instance.path().await;
+    let mut config = read_dot_lodestone_config(&root).await?;
+    let old_value = config.maintenance().cloned();
+    let new_value = request.enabled.then(|| Maintenance {
+        reason,
+        set_by: requester.uid.clone(),
+        set_by_name: requester.username.clone(),
+        since: chrono::Utc::now().timestamp(),
+    });
+    config.set_maintenance(new_value.clone());
+    write_dot_lodestone_config(&root, &config).await?;
+    state.instance_info.invalidate(&uuid);
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    // dashboards refresh the banner and start button on it
+    state.event_broadcaster.send(Event {
+        event_inner: EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid: uuid.clone(),
+            instance_name: instance.name().await,
+            instance_event_inner: InstanceEventInner::MaintenanceChanged {
+                maintenance: new_value.clone(),
+            },
+        }),
+        details: "".to_string(),
+        snowflake: Snowflake::default(),
+        caused_by: caused_by.clone(),
+    });
+    state
+        .audit_log
+        .record(
+            &uuid,
+            caused_by,
+            AuditTarget::Property {
+                name: "maintenance".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(&new_value, false),
+        )
+        .await;
+    Ok(Json(new_value))
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/backup_schedule",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_backup_schedule(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BackupSchedule>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    instance.backup_schedule().await.map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/backup_schedule",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_backup_schedule(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(backup_schedule): Json<BackupSchedule>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let old_value = instance.backup_schedule().await.ok();
+    instance.set_backup_schedule(backup_schedule).await?;
     state
-        .instances
-        .get(&uuid)
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "backup_schedule".to_string(),
+            },
+            audit_value(old_value, false),
+            audit_value(backup_schedule, false),
+        )
+        .await;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/game/settings",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_game_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<IndexMap<String, SettingManifest>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    instance.game_settings().await.map(Json)
+}
+
+/// Merges the given values into the game's settings file, a new `server-port` must be free.
+///
+/// Nothing is written if any value is invalid, the error lists every invalid setting
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/game/settings",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_game_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(settings): Json<IndexMap<String, ConfigurableValue>>,
+) -> Result<Json<IndexMap<String, SettingManifest>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let old_port = instance.port().await;
+    // a malformed port is reported with the other invalid settings
+    let new_port = settings
+        .get("server-port")
+        .and_then(|value| value.try_as_unsigned_integer().ok())
+        .filter(|port| *port <= u16::MAX as u32 && *port != old_port);
+    let old_settings = instance.game_settings().await?;
+    if let Some(port) = new_port {
+        state.port_manager.lock().await.claim(port, &uuid)?;
+    }
+    if let Err(e) = instance.set_game_settings(settings.clone()).await {
+        if let Some(port) = new_port {
+            state.port_manager.lock().await.deallocate(port);
+        }
+        return Err(e);
+    }
+    if new_port.is_some() {
+        state.port_manager.lock().await.deallocate(old_port);
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    for (key, value) in settings {
+        let old_setting = old_settings.get(&key);
+        let is_secret = old_setting.map_or(false, |s| s.is_secret());
+        state
+            .audit_log
+            .record(
+                &uuid,
+                caused_by.clone(),
+                AuditTarget::GameSetting { key },
+                old_setting
+                    .and_then(|s| s.get_value())
+                    .and_then(|v| audit_value(v, is_secret)),
+                audit_value(value, is_secret),
+            )
+            .await;
+    }
+    instance.game_settings().await.map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/java",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_java(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<JavaSelection>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    instance.java_selection().await.map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct JavaPin {
+    /// A Java executable, e.g. one listed by `GET /system/java`
+    path: PathBuf,
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/java",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_java(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(JavaPin { path }): Json<JavaPin>,
+) -> Result<Json<JavaSelection>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let old_path = instance.java_selection().await.ok().map(|java| java.path);
+    let selection = instance.set_java(path).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "java".to_string(),
+            },
+            audit_value(old_path, false),
+            audit_value(&selection.path, false),
+        )
+        .await;
+    Ok(Json(selection))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/java/download",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn download_java(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    instance
+        .download_java(CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        })
+        .await
+        .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct GameVersionChange {
+    version: String,
+    /// Worlds opened by a newer version may not load in an older one
+    #[serde(default)]
+    allow_downgrade: bool,
+}
+
+/// Refuses a change to an older version, or one whose order is unknown
+fn refuse_downgrade(
+    old_version: &str,
+    new_version: &str,
+    is_downgrade: Option<bool>,
+) -> Result<(), Error> {
+    match is_downgrade {
+        Some(false) => Ok(()),
+        Some(true) => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "{} is older than {}, worlds don't downgrade safely. Set allow_downgrade to do it anyway",
+                new_version,
+                old_version
+            ),
+        }),
+        None => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Can't tell whether {} is older than {}. Set allow_downgrade to change anyway",
+                new_version,
+                old_version
+            ),
+        }),
+    }
+}
+
+/// Installs another game version into a stopped Minecraft instance after backing it up.
+///
+/// The work continues in the background and is reported through a version upgrade
+/// progression event, the instance is taken out meanwhile so it cannot be started
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/game/version",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn change_game_version(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(change): Json<GameVersionChange>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    if !matches!(instance, GameInstance::MinecraftInstance(_)) {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Only Minecraft instances can change their game version"),
+        });
+    }
+    let old_version = instance.version().await;
+    if old_version == change.version {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance is already on version {}", change.version),
+        });
+    }
+    if !change.allow_downgrade {
+        refuse_downgrade(
+            &old_version,
+            &change.version,
+            is_downgrade(&old_version, &change.version).await?,
+        )?;
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    // the backup manager's lock keeps backups and restores off the instance until it's done
+    state.backup_manager.try_begin(&uuid).await?;
+    let instance = match state.instances.remove(&uuid) {
+        Some((_, instance)) => instance,
+        None => {
+            state.backup_manager.end(&uuid).await;
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Instance not found"),
+            });
+        }
+    };
+    if instance.state().await != State::Stopped {
+        state.instances.insert(uuid.clone(), instance);
+        state.backup_manager.end(&uuid).await;
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance must be stopped before changing its version"),
+        });
+    }
+    tokio::spawn(async move {
+        let name = instance.name().await;
+        let (progression_start, event_id) = ProgressionStartBuilder::new(
+            format!("Changing {} to {}", name, change.version),
+            ProgressionStartValue::VersionUpgrade {
+                instance_uuid: uuid.clone(),
+                from: old_version.clone(),
+                to: change.version.clone(),
+            },
+            caused_by.clone(),
+        )
+        .total(3.0)
+        .build();
+        state.event_broadcaster.send(progression_start);
+        state
+            .event_broadcaster
+            .send(Event::new_progression_event_update(
+                &event_id,
+                "1/3: Backing up",
+                0.0,
+            ));
+        let result = async {
+            state
+                .backup_manager
+                .create(
+                    &uuid,
+                    &instance.path().await,
+                    &name,
+                    Snowflake::default(),
+                    BackupConfig {
+                        name: Some(format!("Before changing to {}", change.version)),
+                        ..Default::default()
+                    },
+                    state.event_broadcaster.clone(),
+                    caused_by.clone(),
+                )
+                .await
+                .map_err(|e| Error {
+                    kind: e.kind,
+                    source: e
+                        .source
+                        .wrap_err("Safety backup failed, version not changed"),
+                })?;
+            state
+                .event_broadcaster
+                .send(Event::new_progression_event_update(
+                    &event_id,
+                    "2/3: Downloading",
+                    1.0,
+                ));
+            match &instance {
+                GameInstance::MinecraftInstance(minecraft) => {
+                    minecraft
+                        .upgrade_version(change.version.clone(), &event_id)
+                        .await
+                }
+                GameInstance::GenericInstance(_) | GameInstance::CommandInstance(_) => Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!("Only Minecraft instances can change their game version"),
+                }),
+            }
+        }
+        .await;
+        state.instances.insert(uuid.clone(), instance);
+        state.backup_manager.end(&uuid).await;
+        match result {
+            Ok(()) => {
+                state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        true,
+                        Some(format!("Changed to {}", change.version)),
+                        Some(ProgressionEndValue::VersionUpgrade {
+                            instance_uuid: uuid.clone(),
+                            version: change.version.clone(),
+                        }),
+                    ));
+                state
+                    .audit_log
+                    .record(
+                        &uuid,
+                        caused_by,
+                        AuditTarget::Property {
+                            name: "version".to_string(),
+                        },
+                        audit_value(old_version, false),
+                        audit_value(change.version, false),
+                    )
+                    .await;
+            }
+            Err(e) => {
+                error!("Failed to change the version of {}: {}", name, e);
+                state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        false,
+                        Some(format!("Version change failed: {}", e.source)),
+                        None,
+                    ));
+            }
+        }
+    });
+    Ok(Json(()))
+}
+
+/// The instance's `server-icon.png`
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/game/icon",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_server_icon(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Response, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let icon = minecraft_instance(&state, &uuid)?
+        .server_icon()
+        .await?
         .ok_or_else(|| Error {
             kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .change_version(new_version)
+            source: eyre!("Instance has no server icon"),
+        })?;
+    Ok(([(CONTENT_TYPE, "image/png")], icon).into_response())
+}
+
+/// Turns an uploaded PNG or JPEG into the instance's 64x64 `server-icon.png`
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/game/icon",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_server_icon(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    body: Bytes,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let path = minecraft_instance(&state, &uuid)?
+        .set_server_icon(body.to_vec())
         .await?;
+    state.disk_usage.invalidate(&uuid);
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Write,
+        FSTarget::File(path),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/game/icon",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn remove_server_icon(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    if !instance.remove_server_icon().await? {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance has no server icon"),
+        });
+    }
+    state.disk_usage.invalidate(&uuid);
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Delete,
+        FSTarget::File(instance.path().await.join("server-icon.png")),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(()))
+}
+
+/// The MOTD as shown in the server list, with formatting codes but without escapes
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/game/motd",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = String),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_motd(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<String>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(Json(minecraft_instance(&state, &uuid)?.motd().await))
+}
+
+/// Sets the MOTD, `§` formatting codes, a line break and unicode are escaped for server.properties
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/game/motd",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_motd(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(motd): Json<String>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let old_motd = instance.motd().await;
+    instance.set_motd(&motd).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::GameSetting {
+                key: "motd".to_string(),
+            },
+            audit_value(old_motd, false),
+            audit_value(motd, false),
+        )
+        .await;
+    Ok(Json(()))
+}
+
+/// Accepts the Minecraft EULA and starts the server again if it stopped waiting for it
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/game/accept_eula",
+    tag = "instance_config",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn accept_game_eula(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester
+        .try_action(&UserAction::AccessSetting(uuid.clone()), safe_mode)
+        .and_then(|_| requester.try_action(&UserAction::StartInstance(uuid.clone()), safe_mode))?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let was_accepted = instance.snapshot().eula_accepted;
+    instance.accept_eula().await?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    state
+        .audit_log
+        .record(
+            &uuid,
+            caused_by.clone(),
+            AuditTarget::GameSetting {
+                key: "eula".to_string(),
+            },
+            audit_value(was_accepted, false),
+            audit_value(true, false),
+        )
+        .await;
+    if was_accepted == Some(false) && instance.state().await == State::Stopped {
+        state
+            .admission
+            .start(&GameInstance::from(instance), caused_by, false)
+            .await?;
+    }
     Ok(Json(()))
 }
 
@@ -152,6 +1385,7 @@ pub fn get_instance_config_routes(state: AppState) -> Router {
             get(get_instance_configurable_manifest),
         )
         .route("/instance/:uuid/version/:new_version", put(change_version))
+        .route("/instance/:uuid/game/version", post(change_game_version))
         .route("/instance/:uuid/settings", get(get_instance_settings))
         .route(
             "/instance/:uuid/settings/:section_id/:setting_id",
@@ -159,5 +1393,49 @@ pub fn get_instance_config_routes(state: AppState) -> Router {
         )
         .route("/instance/:uuid/name", put(set_instance_name))
         .route("/instance/:uuid/description", put(set_instance_description))
+        .route("/instance/:uuid/tags", put(set_instance_tags))
+        .route("/instance/:uuid/maintenance", put(set_instance_maintenance))
+        .route(
+            "/instance/:uuid/restart_policy",
+            get(get_restart_policy).put(set_restart_policy),
+        )
+        .route(
+            "/instance/:uuid/auto_start",
+            get(get_auto_start).put(set_auto_start),
+        )
+        .route(
+            "/instance/:uuid/backup_schedule",
+            get(get_backup_schedule).put(set_backup_schedule),
+        )
+        .route(
+            "/instance/:uuid/game/settings",
+            get(get_game_settings).put(set_game_settings),
+        )
+        .route(
+            "/instance/:uuid/game/icon",
+            get(get_server_icon)
+                .put(set_server_icon)
+                .delete(remove_server_icon),
+        )
+        .route("/instance/:uuid/game/motd", get(get_motd).put(set_motd))
+        .route("/instance/:uuid/game/accept_eula", post(accept_game_eula))
+        .route("/instance/:uuid/java", get(get_java).put(set_java))
+        .route("/instance/:uuid/java/download", post(download_java))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refuse_downgrade() {
+        assert!(refuse_downgrade("1.20.1", "1.20.2", Some(false)).is_ok());
+        let e = refuse_downgrade("1.20.2", "1.20.1", Some(true)).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+        assert!(e.source.to_string().contains("allow_downgrade"));
+        let e = refuse_downgrade("1.20.2", "1.21-custom", None).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+        assert!(e.source.to_string().contains("Can't tell"));
+    }
+}
@@ -0,0 +1,241 @@
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Query},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use headers::HeaderMap;
+use reqwest::header::CONTENT_LENGTH;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    events::{new_fs_event, CausedBy, FSOperation, FSTarget},
+    implementations::minecraft::datapacks::{DatapackChange, InstalledDatapack},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+use super::{
+    instance_fs::{ensure_space, upload_too_large, PartialFile},
+    util::minecraft_instance,
+};
+
+/// Data packs of the active world, disabled ones included
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/datapacks",
+    tag = "instance_datapacks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_datapacks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<InstalledDatapack>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .list_datapacks()
+        .await
+        .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct DatapackUploadQuery {
+    /// Install a pack made for another version of the game
+    #[serde(default)]
+    force: bool,
+}
+
+/// Installs a zipped data pack from a `multipart/form-data` body into the active world.
+///
+/// The zip must hold `pack.mcmeta` and `data` at its top and be made for the server's version
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/datapacks/upload",
+    tag = "instance_datapacks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("force" = Option<bool>, Query, description = "Install a pack made for another version"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn upload_datapack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<DatapackUploadQuery>,
+    headers: HeaderMap,
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<DatapackChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let root = instance.path().await;
+    let max_upload_size = state.global_settings.lock().await.max_upload_size();
+    let total = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if total.map_or(false, |total| total > max_upload_size) {
+        return Err(upload_too_large(max_upload_size));
+    }
+    ensure_space(&state, &uuid, &root, total.unwrap_or(0)).await?;
+
+    let mut field = multipart
+        .next_field()
+        .await
+        .context("Failed to read multipart field")?
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Missing data pack"),
+        })?;
+    let name = field.file_name().unwrap_or_default().to_string();
+    // kept in the instance so the checked pack is only renamed into place
+    let upload = root.join(format!(".datapack_upload_{}.zip", rand_alphanumeric(8)));
+    let partial_file = PartialFile(Some(upload.clone()));
+    let mut file = crate::util::fs::create(&upload).await?;
+    let mut uploaded = 0_u64;
+    while let Some(chunk) = field.chunk().await.context("Failed to read data pack")? {
+        uploaded += chunk.len() as u64;
+        if uploaded > max_upload_size {
+            return Err(upload_too_large(max_upload_size));
+        }
+        file.write_all(&chunk)
+            .await
+            .context("Failed to write data pack")?;
+    }
+    file.flush().await.context("Failed to write data pack")?;
+    drop(file);
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    let change = instance
+        .install_datapack(&upload, &name, query.force, &caused_by)
+        .await?;
+    drop(partial_file);
+    state.disk_usage.add(&uuid, uploaded);
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Upload,
+        FSTarget::File(instance.datapack_path(&name).await),
+        caused_by,
+    ));
+    Ok(Json(change))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/datapack/{name}/enabled",
+    tag = "instance_datapacks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    request_body = bool,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_datapack_enabled(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(enabled): Json<bool>,
+) -> Result<Json<DatapackChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .set_datapack_enabled(
+            &name,
+            enabled,
+            &CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+        )
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/datapack/{name}",
+    tag = "instance_datapacks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn remove_datapack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DatapackChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    let (path, change) = minecraft_instance(&state, &uuid)?
+        .remove_datapack(&name, &caused_by)
+        .await?;
+    state.disk_usage.invalidate(&uuid);
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Delete,
+        FSTarget::File(path),
+        caused_by,
+    ));
+    Ok(Json(change))
+}
+
+pub fn get_instance_datapacks_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/datapacks/upload", post(upload_datapack))
+        .layer(DefaultBodyLimit::disable())
+        .route("/instance/:uuid/datapacks", get(get_datapacks))
+        .route(
+            "/instance/:uuid/datapack/:name/enabled",
+            put(set_datapack_enabled),
+        )
+        .route("/instance/:uuid/datapack/:name", delete(remove_datapack))
+        .with_state(state)
+}
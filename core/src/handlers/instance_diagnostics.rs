@@ -0,0 +1,267 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use serde::{Deserialize, Serialize};
+use sysinfo::SystemExt;
+use ts_rs::TS;
+
+use crate::{
+    auth::user::UserAction,
+    diagnostics::{
+        run_diagnostics, run_preflight, Automation, DiagnosticsContext, DiagnosticsReport,
+        FindingId, RemediationAction, StartDiagnosis,
+    },
+    error::Error,
+    implementations::minecraft::launch_failure::LaunchFailure,
+    prelude::GameInstance,
+    traits::{
+        t_configurable::TConfigurable,
+        t_server::{State, TServer},
+    },
+    types::InstanceUuid,
+    AppState,
+};
+
+use super::util::{game_instance, minecraft_instance};
+
+#[derive(Deserialize)]
+pub struct FixQuery {
+    /// Also move the server to a free port if its own is taken
+    #[serde(default)]
+    reassign_port: bool,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct FixReport {
+    applied: Vec<FindingId>,
+    /// The suite run again once the fixes were applied
+    report: DiagnosticsReport,
+}
+
+fn get_instance(state: &AppState, uuid: &InstanceUuid) -> Result<GameInstance, Error> {
+    game_instance(state, uuid)
+}
+
+async fn diagnose(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    instance: &GameInstance,
+) -> Result<DiagnosticsReport, Error> {
+    let target = instance.preflight_target().await?;
+    let available_memory_mb = {
+        let mut system = state.system.lock().await;
+        system.refresh_memory();
+        system.available_memory() / 1024 / 1024
+    };
+    let context = DiagnosticsContext {
+        available_memory_mb,
+        instance_running: instance.state().await != State::Stopped,
+    };
+    Ok(run_diagnostics(uuid, &target, context).await)
+}
+
+/// Adds the findings that explain a failed start to its error, when there are any
+pub async fn diagnose_start_failure(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    instance: &GameInstance,
+    error: Error,
+) -> Error {
+    let finding_ids = match diagnose(state, uuid, instance).await {
+        Ok(report) => report.blocking_ids(),
+        Err(_) => return error,
+    };
+    if finding_ids.is_empty() {
+        return error;
+    }
+    Error {
+        kind: error.kind,
+        source: error.source.wrap_err(StartDiagnosis(finding_ids)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/diagnostics",
+    tag = "instance_diagnostics",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_diagnostics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DiagnosticsReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = get_instance(&state, &uuid)?;
+    diagnose(&state, &uuid, &instance).await.map(Json)
+}
+
+/// The checks a start runs before spawning the server: port, Java runtime, server jar and free disk
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/preflight",
+    tag = "instance_diagnostics",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success, a start fails unless there are no error findings"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_preflight(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DiagnosticsReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = get_instance(&state, &uuid)?;
+    let target = instance.preflight_target().await?;
+    let instance_running = instance.state().await != State::Stopped;
+    Ok(Json(run_preflight(&uuid, &target, instance_running).await))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/diagnostics/fix",
+    tag = "instance_diagnostics",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn fix_diagnostics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<FixQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<FixReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = get_instance(&state, &uuid)?;
+    let report = diagnose(&state, &uuid, &instance).await?;
+    let mut applied = Vec::new();
+    for finding in &report.findings {
+        let remediation = match &finding.remediation {
+            Some(remediation) => remediation,
+            None => continue,
+        };
+        let wanted = match remediation.automation {
+            Automation::Safe => true,
+            Automation::OptIn => query.reassign_port,
+            Automation::Manual => false,
+        };
+        if !wanted {
+            continue;
+        }
+        match remediation.action {
+            RemediationAction::AcceptEula => instance.accept_eula().await?,
+            RemediationAction::ReassignPort => {
+                let old_port = instance.port().await;
+                let new_port = {
+                    let mut port_manager = state.port_manager.lock().await;
+                    let new_port = port_manager.allocate(old_port + 1, &uuid)?;
+                    port_manager.deallocate(old_port);
+                    new_port
+                };
+                instance.set_port(new_port).await?;
+            }
+            RemediationAction::SetJavaCmd | RemediationAction::LowerMaxRam => continue,
+        }
+        applied.push(finding.id);
+    }
+    let report = diagnose(&state, &uuid, &instance).await?;
+    Ok(Json(FixReport { applied, report }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/eula",
+    tag = "instance_diagnostics",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn accept_instance_eula(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    get_instance(&state, &uuid)?.accept_eula().await?;
+    Ok(Json(()))
+}
+
+/// Why the server last exited before it finished starting, `null` if it never has
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/last_failure",
+    tag = "instance_diagnostics",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_last_launch_failure(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<LaunchFailure>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    Ok(Json(instance.last_launch_failure().await))
+}
+
+pub fn get_instance_diagnostics_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/diagnostics", get(get_diagnostics))
+        .route("/instance/:uuid/diagnostics/fix", post(fix_diagnostics))
+        .route("/instance/:uuid/preflight", get(get_preflight))
+        .route("/instance/:uuid/eula", put(accept_instance_eula))
+        .route("/instance/:uuid/last_failure", get(get_last_launch_failure))
+        .with_state(state)
+}
@@ -1,114 +1,245 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use axum::http::{HeaderValue, StatusCode};
 use axum::{
-    body::Bytes,
-    extract::{DefaultBodyLimit, Multipart, Path},
-    routing::{delete, get, put},
+    body::{Bytes, StreamBody},
+    extract::{DefaultBodyLimit, Multipart, Path, Query},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 use color_eyre::eyre::{eyre, Context};
-use fs_extra::TransitProcess;
+use futures::{AsyncWriteExt as _, StreamExt};
 use headers::HeaderMap;
-use reqwest::header::CONTENT_LENGTH;
-use serde::Deserialize;
-use tokio::io::AsyncWriteExt;
-use tracing::error;
+use reqwest::header::{
+    ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_MATCH, RANGE,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{error, warn};
 use ts_rs::TS;
-use walkdir::WalkDir;
 
 use crate::{
-    auth::user::UserAction,
-    error::{Error, ErrorKind},
-    events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue},
+    archive_manifest::{verify_archive, MANIFEST_FILE_NAME},
+    auth::user::{User, UserAction},
+    cancellation::checkpoint,
+    disk_usage::{check_space, volume_space, DirSize},
+    error::{Error, ErrorCode, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{
+        new_fs_event, CausedBy, Event, FSOperation, FSTarget, FsOperationKind, ProgressionEndValue,
+        ProgressionStartBuilder, ProgressionStartValue,
+    },
+    fs_search::{search_dir, SearchQuery, SearchResult},
+    fs_watch::{WatchStatus, DEFAULT_EXCLUDES, DEFAULT_IDLE_TIMEOUT},
     prelude::path_to_tmp,
-    traits::t_configurable::TConfigurable,
-    types::InstanceUuid,
+    traits::{t_configurable::TConfigurable, GameInstance},
+    trash::{
+        get_trash_entry, list_trash, move_to_trash, purge_trash_entry, restore_from_trash,
+        trash_dir, TrashEntry,
+    },
+    types::{DotLodestoneConfig, InstanceUuid, ProtectedFilesPolicy},
     util::{
-        format_byte, format_byte_download, list_dir, rand_alphanumeric, resolve_path_conflict,
-        scoped_join_win_safe, unzip_file_async, zip_files, zip_files_async, UnzipOption,
+        archive_top_level_names, archive_uncompressed_size, check_archive_entries,
+        check_path_length, extended_length_path, extract_archive, format_byte,
+        format_byte_download, rand_alphanumeric, resolve_path_conflict, scoped_join_win_safe,
+        strip_extended_length_prefix, walk_dir, zip_files, zip_files_async, ExtractSummary,
+        UnzipOption, MAX_TRAVERSAL_DEPTH,
     },
     AppState,
 };
 
-// list of protected file extension that cannot be modified
-static PROTECTED_EXTENSIONS: [&str; 10] = [
-    "jar",
-    "lua",
-    "sh",
-    "exe",
-    "bat",
-    "cmd",
-    "msi",
-    "lodestone_config",
-    "out",
-    "inf",
-];
-
-static PROTECTED_DIR_NAME: [&str; 1] = ["mods"];
-
-fn is_path_protected(path: impl AsRef<std::path::Path>) -> bool {
-    let path = path.as_ref();
-    if path.is_dir() {
-        path.file_name()
-            .and_then(|s| s.to_str().map(|s| PROTECTED_DIR_NAME.contains(&s)))
-            .unwrap_or(true)
-    } else if let Some(ext) = path.extension() {
-        ext.to_str()
-            .map(|s| PROTECTED_EXTENSIONS.contains(&s))
-            .unwrap_or(true)
-    } else {
-        true
+/// The protected files policy of the instance at `root`, the default if its config is unreadable
+async fn protected_files_policy(root: &std::path::Path) -> ProtectedFilesPolicy {
+    match read_dot_lodestone_config(root).await {
+        Ok(config) => config.protected_files().clone(),
+        Err(e) => {
+            warn!("Using the default protected files policy: {}", e);
+            ProtectedFilesPolicy::default()
+        }
+    }
+}
+
+pub(crate) async fn read_dot_lodestone_config(
+    root: &std::path::Path,
+) -> Result<DotLodestoneConfig, Error> {
+    let content = tokio::fs::read(root.join(".lodestone_config"))
+        .await
+        .context("Failed to read .lodestone_config file")?;
+    Ok(serde_json::from_slice(&content).context("Failed to parse .lodestone_config file")?)
+}
+
+pub(crate) async fn write_dot_lodestone_config(
+    root: &std::path::Path,
+    config: &DotLodestoneConfig,
+) -> Result<(), Error> {
+    tokio::fs::write(
+        root.join(".lodestone_config"),
+        serde_json::to_string_pretty(config).context("Failed to serialize config")?,
+    )
+    .await
+    .context("Failed to write .lodestone_config file")?;
+    Ok(())
+}
+
+/// Whether the requester may write files the instance's policy protects
+fn can_write_protected(requester: &User, uuid: &InstanceUuid) -> bool {
+    requester.can_perform_action(&UserAction::WriteGlobalFile)
+        || requester.can_perform_action(&UserAction::ManageProtectedFiles(uuid.clone()))
+}
+
+/// Authenticates the request and checks the requester may take `required_action`
+async fn authorize(
+    state: &AppState,
+    token: &str,
+    required_action: &UserAction,
+) -> Result<User, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(token)?;
+    requester.try_action(
+        required_action,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(requester)
+}
+
+/// A path inside an instance that a request is allowed to act on
+struct ResolvedPath {
+    requester: User,
+    /// The instance directory
+    root: PathBuf,
+    /// The requested path joined onto `root`, it can't lead outside of it
+    path: PathBuf,
+}
+
+/// Refuses writes to the world of a suspended server, it would resume with stale data in memory
+async fn ensure_world_writable(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    path: &std::path::Path,
+) -> Result<(), Error> {
+    match cloned_handle(&state.instances, uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => {
+            instance.ensure_world_writable(path).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What the fs routes start with: authentication, the permission check, the instance lookup and
+/// the scoped join of the requested path
+async fn resolve_instance_fs(
+    state: &AppState,
+    token: &str,
+    uuid: &InstanceUuid,
+    relative_path: impl AsRef<std::path::Path>,
+    required_action: UserAction,
+) -> Result<ResolvedPath, Error> {
+    let requester = authorize(state, token, &required_action).await?;
+    let root = instance_root(state, uuid).await?;
+    let path = scoped_join_win_safe(&root, relative_path)?;
+    if let UserAction::WriteInstanceFile(_) = required_action {
+        ensure_world_writable(state, uuid, &path).await?;
     }
+    Ok(ResolvedPath {
+        requester,
+        root,
+        path,
+    })
+}
+
+/// Fails with how much space is needed versus available if `needed` more bytes don't fit under
+/// the instance's quota or on the disk holding it
+pub(crate) async fn ensure_space(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    root: &std::path::Path,
+    needed: u64,
+) -> Result<(), Error> {
+    let quota = match read_dot_lodestone_config(root).await {
+        Ok(config) => config.disk_quota(),
+        Err(e) => {
+            warn!("Not enforcing a disk quota: {}", e);
+            None
+        }
+    };
+    // only walk the instance when there is a quota to compare against
+    let used = match quota {
+        Some(_) => state.disk_usage.size(uuid, root).await?.bytes,
+        None => 0,
+    };
+    let free = volume_space(&mut *state.system.lock().await, root).map(|space| space.free);
+    check_space(used, quota, free, needed)
+}
+
+/// The uncompressed size of an archive, read without blocking the runtime
+pub(crate) async fn archive_size(archive: PathBuf) -> Result<u64, Error> {
+    tokio::task::spawn_blocking(move || archive_uncompressed_size(archive))
+        .await
+        .context("Failed to spawn blocking task")?
 }
 
 use super::{
-    global_fs::{DownloadableFile, FileEntry},
-    util::decode_base64,
+    global_fs::{list_entries, DownloadableFile, FileEntry, FileListQuery},
+    util::{cloned_handle, decode_base64, game_instance},
 };
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/ls",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+        ("hidden" = Option<bool>, Query, description = "List dotfiles, true by default"),
+        ("sort" = Option<String>, Query, description = "name, size or mtime, directories always come first"),
+        ("descending" = Option<bool>, Query),
+        ("depth" = Option<usize>, Query, description = "Levels of subdirectories to list along, at most 3"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = [FileEntry]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn list_instance_files(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<FileListQuery>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<Vec<FileEntry>>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-
-    requester.try_action(
-        &UserAction::ReadInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
     if uuid.to_string().starts_with("DOCKER-") {
-        let files = state
+        authorize(&state, &token, &UserAction::ListInstanceFiles(uuid.clone())).await?;
+        // mounts aren't walked, only the listed directory is sorted and filtered
+        let mut files = state
             .docker_bridge
             .list_files(&uuid, relative_path.into())
             .await?;
+        files.retain(|file| query.shows(file));
+        query.sort(&mut files);
         return Ok(Json(files));
     }
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(&root, relative_path)?;
-
-    let ret: Vec<FileEntry> = list_dir(&path, None)
-        .await?
-        .iter()
-        .filter_map(move |p| -> Option<FileEntry> {
-            // remove the root path from the file path
-            let mut r: FileEntry = p.as_path().into();
-            r.path = p
-                .strip_prefix(&root)
-                .ok()
-                .and_then(|p| p.to_str())
-                .map(|s| s.to_owned())?;
-            Some(r)
-        })
-        .collect();
+    let ResolvedPath {
+        requester,
+        root,
+        path,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::ListInstanceFiles(uuid.clone()),
+    )
+    .await?;
+
+    let ret = list_entries(&path, &root, Some(&root), &query).await?;
     let caused_by = CausedBy::User {
         user_id: requester.uid,
         user_name: requester.username,
@@ -121,35 +252,188 @@ async fn list_instance_files(
     Ok(Json(ret))
 }
 
+/// How much `tail=true` returns when no length is given
+const DEFAULT_TAIL_LENGTH: u64 = 64 * 1024;
+/// Largest window a single read returns, larger requests are cut short
+const MAX_READ_WINDOW: u64 = 16 * 1024 * 1024;
+/// Size of the whole file, so a client reading a window knows how far it can page
+const FILE_SIZE_HEADER: &str = "x-file-size";
+
+#[derive(Deserialize, Default)]
+struct ReadFileQuery {
+    offset: Option<u64>,
+    length: Option<u64>,
+    /// Read the last `length` bytes
+    #[serde(default)]
+    tail: bool,
+}
+
+/// Parses a single `bytes=` range into a half-open window
+fn parse_range_header(range: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    // several ranges would need a multipart response
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", suffix) => Some((size.saturating_sub(suffix.parse().ok()?), size)),
+        (start, "") => Some((start.parse().ok()?, size)),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if start > end {
+                return None;
+            }
+            Some((start, end.saturating_add(1).min(size)))
+        }
+    }
+}
+
+/// The window to read, `None` for the whole file. Query parameters win over a `Range` header
+fn resolve_read_window(
+    query: &ReadFileQuery,
+    range: Option<&str>,
+    size: u64,
+) -> Result<Option<(u64, u64)>, Error> {
+    let window = if query.tail {
+        let length = query.length.unwrap_or(DEFAULT_TAIL_LENGTH);
+        Some((size.saturating_sub(length), size))
+    } else if query.offset.is_some() || query.length.is_some() {
+        let start = query.offset.unwrap_or(0);
+        let end = query
+            .length
+            .map_or(size, |length| start.saturating_add(length));
+        Some((start, end.min(size)))
+    } else if let Some(range) = range {
+        Some(parse_range_header(range, size).ok_or_else(|| {
+            Error::coded(
+                ErrorCode::InvalidRange,
+                format!("Unsupported range {}", range),
+            )
+        })?)
+    } else {
+        None
+    };
+    match window {
+        Some((start, _)) if start > size => Err(Error::coded(
+            ErrorCode::InvalidRange,
+            format!("Offset {} is past the end of the {} byte file", start, size),
+        )),
+        Some((start, end)) => Ok(Some((start, end.min(start + MAX_READ_WINDOW)))),
+        None => Ok(None),
+    }
+}
+
+/// Identifies a version of a file by its content and modification time, so an edit is told
+/// apart even if it restored the same content
+fn file_etag(content: &[u8], modified: Option<std::time::SystemTime>) -> String {
+    let modified = modified
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis());
+    format!("\"{}-{}\"", hex::encode(Sha256::digest(content)), modified)
+}
+
+/// Whether an `If-Match` header value lists `etag`, weak tags are compared like strong ones
+fn if_match(header: &str, etag: Option<&str>) -> bool {
+    header.split(',').map(str::trim).any(|tag| match etag {
+        Some(_) if tag == "*" => true,
+        Some(etag) => tag.trim_start_matches("W/") == etag,
+        None => false,
+    })
+}
+
+/// Reads a text file, or a window of it with `offset`/`length`, `tail` or a `Range` header.
+///
+/// Invalid UTF-8 is replaced rather than failing the read, a window may cut a character in half.
+/// A whole file comes with an `ETag` that writes can send back as `If-Match`
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/read",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn read_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<ReadFileQuery>,
+    headers: HeaderMap,
     AuthBearer(token): AuthBearer,
-) -> Result<String, Error> {
+) -> Result<Response, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::ReadInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
     if uuid.to_string().starts_with("DOCKER-") {
+        authorize(&state, &token, &UserAction::ReadInstanceFile(uuid.clone())).await?;
         let file = state
             .docker_bridge
             .read_container_file(&uuid, relative_path.into())
             .await?;
-        return Ok(file);
+        return Ok(file.into_response());
     }
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
-
-    let ret = tokio::fs::read_to_string(&path)
+    let ResolvedPath {
+        requester, path, ..
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::ReadInstanceFile(uuid.clone()),
+    )
+    .await?;
+
+    let mut file = tokio::fs::File::open(extended_length_path(&path))
         .await
         .context("Failed to read file")?;
+    let metadata = file
+        .metadata()
+        .await
+        .context("Failed to read file metadata")?;
+    let size = metadata.len();
+    let range = headers.get(RANGE).and_then(|v| v.to_str().ok());
+    let window = resolve_read_window(&query, range, size)?;
+    let mut buf = Vec::new();
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(FILE_SIZE_HEADER, size.into());
+    response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let status = match window {
+        None => {
+            file.read_to_end(&mut buf)
+                .await
+                .context("Failed to read file")?;
+            response_headers.insert(
+                ETAG,
+                HeaderValue::from_str(&file_etag(&buf, metadata.modified().ok()))
+                    .context("Invalid ETag")?,
+            );
+            StatusCode::OK
+        }
+        Some((start, end)) => {
+            file.seek(std::io::SeekFrom::Start(start))
+                .await
+                .context("Failed to seek file")?;
+            // a log rotated since the size was taken just yields less
+            file.take(end - start)
+                .read_to_end(&mut buf)
+                .await
+                .context("Failed to read file")?;
+            let content_range = if buf.is_empty() {
+                format!("bytes */{}", size)
+            } else {
+                format!("bytes {}-{}/{}", start, start + buf.len() as u64 - 1, size)
+            };
+            response_headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).context("Invalid content range")?,
+            );
+            StatusCode::PARTIAL_CONTENT
+        }
+    };
     let caused_by = CausedBy::User {
         user_id: requester.uid,
         user_name: requester.username,
@@ -159,48 +443,272 @@ async fn read_instance_file(
         FSTarget::File(path),
         caused_by,
     ));
-    Ok(ret)
+    Ok((
+        status,
+        response_headers,
+        String::from_utf8_lossy(&buf).into_owned(),
+    )
+        .into_response())
+}
+
+/// Files hashed in one batch request
+const MAX_HASH_BATCH: usize = 1000;
+const HASH_CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, TS)]
+#[ts(export)]
+pub struct FileHash {
+    /// Hex encoded SHA-256 of the content
+    pub sha256: String,
+    pub size: u64,
+    /// Unix timestamp in seconds, absent if the platform doesn't record it
+    pub modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[serde(untagged)]
+#[ts(export)]
+pub enum FileHashResult {
+    Hash(FileHash),
+    Error { error: String },
+}
+
+/// Hashes a file in fixed size chunks so its size doesn't matter, blocks while doing so
+fn hash_file(path: &std::path::Path) -> Result<FileHash, Error> {
+    let path = extended_length_path(path);
+    // checked before opening, opening a directory succeeds on some platforms
+    let metadata = std::fs::metadata(&path).context("Failed to read file metadata")?;
+    if metadata.is_dir() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Cannot hash a directory"),
+        });
+    }
+    let mut file = std::fs::File::open(&path).context("Failed to open file")?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; HASH_CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = std::io::Read::read(&mut file, &mut buf).context("Failed to read file")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+    Ok(FileHash {
+        sha256: hex::encode(hasher.finalize()),
+        size,
+        modified: metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64),
+    })
+}
+
+/// Hashes a file of the instance, refusing symlinks that resolve outside of it
+async fn hash_instance_file(root: PathBuf, relative_path: String) -> Result<FileHash, Error> {
+    tokio::task::spawn_blocking(move || {
+        let path = scoped_join_win_safe(&root, &relative_path)?;
+        let canonical_root =
+            std::fs::canonicalize(&root).context("Failed to resolve instance path")?;
+        let canonical_path = std::fs::canonicalize(extended_length_path(&path))
+            .context(format!("Failed to resolve {}", relative_path))?;
+        if !strip_extended_length_prefix(&canonical_path)
+            .starts_with(strip_extended_length_prefix(&canonical_root))
+        {
+            return Err(Error::coded(
+                ErrorCode::PathOutsideInstance,
+                "Path leads outside the instance",
+            ));
+        }
+        hash_file(&canonical_path)
+    })
+    .await
+    .context("Failed to join hashing task")?
+}
+
+async fn instance_root_for_hashing(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    token: &str,
+) -> Result<PathBuf, Error> {
+    authorize(state, token, &UserAction::ReadInstanceFile(uuid.clone())).await?;
+    instance_root(state, uuid).await
+}
+
+/// SHA-256, size and modification time of a file, so sync tools can compare without downloading
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/hash/{relative_path}",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("relative_path" = String, Path, description = "Path relative to the instance directory, may span several segments"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn get_instance_file_hash(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, relative_path)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<FileHash>, Error> {
+    let root = instance_root_for_hashing(&state, &uuid, &token).await?;
+    Ok(Json(hash_instance_file(root, relative_path).await?))
+}
+
+#[derive(Deserialize)]
+struct HashInstanceFilesRequest {
+    relative_paths: Vec<String>,
+}
+
+/// Hashes several files, a path that can't be hashed gets an error entry instead of failing the
+/// whole batch
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/fs/hash",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn get_instance_file_hashes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<HashInstanceFilesRequest>,
+) -> Result<Json<BTreeMap<String, FileHashResult>>, Error> {
+    if request.relative_paths.len() > MAX_HASH_BATCH {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("At most {} files can be hashed at once", MAX_HASH_BATCH),
+        });
+    }
+    let root = instance_root_for_hashing(&state, &uuid, &token).await?;
+    let mut hashes = BTreeMap::new();
+    for relative_path in request.relative_paths {
+        let result = match hash_instance_file(root.clone(), relative_path.clone()).await {
+            Ok(hash) => FileHashResult::Hash(hash),
+            Err(e) => FileHashResult::Error {
+                error: e.source.to_string(),
+            },
+        };
+        hashes.insert(relative_path, result);
+    }
+    Ok(Json(hashes))
+}
+
+/// Held from checking a write's `If-Match` until the write is in place, so two conditional
+/// writes can't both pass the check
+static WRITE_LOCK: once_cell::sync::Lazy<tokio::sync::Mutex<()>> =
+    once_cell::sync::Lazy::new(|| tokio::sync::Mutex::new(()));
+
+/// The current ETag of a file, `None` if it doesn't exist
+async fn current_etag(path: &std::path::Path) -> Result<Option<String>, Error> {
+    let content = match tokio::fs::read(extended_length_path(path)).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        result => result.context("Failed to read file")?,
+    };
+    let modified = tokio::fs::metadata(extended_length_path(path))
+        .await
+        .context("Failed to read file metadata")?
+        .modified()
+        .ok();
+    Ok(Some(file_etag(&content, modified)))
 }
 
+/// Replaces the file in one step, a crash mid-write leaves the old content.
+///
+/// With `If-Match` set to the `ETag` the file was read with, the write is refused with a 412 if
+/// the file changed since, the error's details carry the current `etag`. Without it, the last
+/// write wins
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/write",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+        ("If-Match" = Option<String>, Header, description = "ETag the file was read with"),
+    ),
+    responses(
+        (status = 200, description = "Success, the new content's ETag is in the ETag header"),
+        (status = 412, description = "The file changed since it was read", body = Error),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn write_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    headers: HeaderMap,
     AuthBearer(token): AuthBearer,
     body: Bytes,
-) -> Result<Json<()>, Error> {
+) -> Result<Response, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
     if uuid.to_string().starts_with("DOCKER-") {
+        authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
         state
             .docker_bridge
             .write_container_file(&uuid, relative_path.into(), &body)
             .await?;
-        return Ok(Json(()));
-    }
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
-    // if target has a protected extension, or no extension, deny
-    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
-        return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("You don't have permission to write to this file"),
-        });
+        return Ok(Json(()).into_response());
     }
-    let mut file = tokio::fs::File::create(&path)
-        .await
-        .context("Failed to create file")?;
-    file.write_all(&body)
-        .await
-        .context("Failed to write to file")?;
+    let ResolvedPath {
+        requester,
+        root,
+        path,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    let protected_files = protected_files_policy(&root).await;
+    // deny if the instance policy protects the target
+    if !can_write_protected(&requester, &uuid) && protected_files.is_protected(&path) {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "You don't have permission to write to this file",
+        ));
+    }
+    check_path_length(&path, state.global_settings.lock().await.max_path_length())?;
+    ensure_space(&state, &uuid, &root, body.len() as u64).await?;
+    let _guard = WRITE_LOCK.lock().await;
+    if let Some(expected) = headers.get(IF_MATCH) {
+        let expected = expected.to_str().map_err(|_| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid If-Match header"),
+        })?;
+        let current = current_etag(&path).await?;
+        if !if_match(expected, current.as_deref()) {
+            return Err(
+                Error::coded(ErrorCode::FileChanged, "The file changed since it was read")
+                    .with_details(serde_json::json!({ "etag": current })),
+            );
+        }
+    }
+    crate::util::fs::write_atomic(&path, &body).await?;
+    let etag = file_etag(
+        &body,
+        tokio::fs::metadata(extended_length_path(&path))
+            .await
+            .ok()
+            .and_then(|metadata| metadata.modified().ok()),
+    );
+    state.disk_usage.add(&uuid, body.len() as u64);
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -211,27 +719,44 @@ async fn write_instance_file(
         FSTarget::File(path),
         caused_by,
     ));
-    Ok(Json(()))
+    Ok((
+        [(ETAG, HeaderValue::from_str(&etag).context("Invalid ETag")?)],
+        Json(()),
+    )
+        .into_response())
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/mkdir",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn make_instance_directory(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<()>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
+    let ResolvedPath {
+        requester, path, ..
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    check_path_length(&path, state.global_settings.lock().await.max_path_length())?;
     // create the file if it doesn't exist
     crate::util::fs::create_dir_all(&path).await?;
 
@@ -254,6 +779,21 @@ struct CopyInstanceFileRequest {
     relative_path_dest: PathBuf,
 }
 
+/// Copies each source into the destination directory, the way `PUT /instance/{uuid}/fs/copy`
+/// does. Stops at the first source that fails, the ones before it stay copied
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/cpr",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn copy_instance_files(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -263,243 +803,542 @@ async fn copy_instance_files(
         relative_path_dest,
     }): Json<CopyInstanceFileRequest>,
 ) -> Result<Json<()>, Error> {
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    // join each path to the root
-    let paths_source = relative_paths_source
-        .iter()
-        .map(|p| scoped_join_win_safe(root.clone(), p))
-        .collect::<Result<Vec<_>, _>>()?;
-
-    let path_dest = scoped_join_win_safe(root, &relative_path_dest)?;
-
-    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path_dest)
-    {
-        return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("You don't have permission to write to this file"),
-        });
-    }
-
-    // if the destination path is a subdirectory of any of the source paths, deny
-    if paths_source.iter().any(|p| path_dest.starts_with(p)) {
-        return Err(Error {
-            kind: ErrorKind::BadRequest,
-            source: eyre!("You can't copy a directory to a subdirectory of itself"),
-        });
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    for source in relative_paths_source {
+        let file_name = source
+            .file_name()
+            .ok_or_else(|| Error::coded(ErrorCode::MissingFileName, "Missing file name"))?;
+        let destination = relative_path_dest.join(file_name);
+        copy_path(
+            &state,
+            &uuid,
+            &requester,
+            TransferInstanceFileRequest {
+                source,
+                destination,
+            },
+        )
+        .await?;
     }
+    Ok(Json(()))
+}
 
-    let event_broadcaster = state.event_broadcaster.clone();
-
-    tokio::task::spawn_blocking(move || {
-        let mut first = true;
-        let mut threshold = 500000_u64;
-        let mut elapsed_bytes = 0_u64;
-        let mut last_progression = 0_u64;
-        let mut progression_event_id = None;
-
-        let handle = |process_info: TransitProcess| {
-            if first {
-                threshold = process_info.total_bytes / 100;
-                let (progression_event_start, _progression_event_id) =
-                    Event::new_progression_event_start(
-                        "Copying files(s)",
-                        Some(process_info.total_bytes as f64),
-                        None,
-                        CausedBy::User {
-                            user_id: requester.uid.clone(),
-                            user_name: requester.username.clone(),
-                        },
-                    );
-                event_broadcaster.send(progression_event_start);
-                progression_event_id = Some(_progression_event_id);
-                first = false;
-                elapsed_bytes = process_info.copied_bytes;
-            } else {
-                elapsed_bytes = process_info.copied_bytes;
-                let progression = elapsed_bytes / threshold;
-                if progression > last_progression {
-                    last_progression = progression;
-                    event_broadcaster.send(Event::new_progression_event_update(
-                        progression_event_id.as_ref().unwrap(),
-                        format!(
-                            "Copying file {}, {}",
-                            process_info.file_name,
-                            format_byte_download(
-                                process_info.copied_bytes,
-                                process_info.total_bytes
-                            )
-                        ),
-                        threshold as f64,
-                    ));
-                }
-            }
-            fs_extra::dir::TransitProcessResult::SkipAll
-        };
-
-        let inner = || -> Result<(), Error> {
-            let tmp_dir =
-                tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary file")?;
-            let temp_dir_path = tmp_dir.path().to_owned();
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct TransferInstanceFileRequest {
+    source: PathBuf,
+    destination: PathBuf,
+}
 
-            fs_extra::copy_items_with_progress(
-                &paths_source,
-                &temp_dir_path,
-                &fs_extra::dir::CopyOptions::new(),
-                handle,
-            )
-            .context("Failed to copy file(s)")?;
+/// Every entry of a move or copy, resolved and checked before anything is touched
+struct TransferPlan {
+    source: PathBuf,
+    destination: PathBuf,
+    /// (from, to, is_dir), parents before their children
+    entries: Vec<(PathBuf, PathBuf, bool)>,
+    total_bytes: u64,
+}
 
-            for temp_path in std::fs::read_dir(temp_dir_path)
-                .context("Failed to read tmp directory")?
-                .filter_map(|entry| entry.ok().map(|v| v.path()))
-            {
-                let dest_path =
-                    resolve_path_conflict(path_dest.join(temp_path.file_name().unwrap()), None);
-                std::fs::rename(temp_path, dest_path).context("Failed to move file")?;
-            }
-            Ok(())
+fn plan_transfer(
+    source: PathBuf,
+    destination: PathBuf,
+    max_path_length: usize,
+    protected_files: &ProtectedFilesPolicy,
+    allow_protected: bool,
+) -> Result<TransferPlan, Error> {
+    let mut entries = Vec::new();
+    let mut total_bytes = 0;
+    for entry in walk_dir(&source, MAX_TRAVERSAL_DEPTH) {
+        let entry = entry?;
+        let file_type = entry.file_type();
+        // symlinks are left behind rather than followed
+        if !file_type.is_file() && !file_type.is_dir() {
+            continue;
+        }
+        let from = strip_extended_length_prefix(entry.path());
+        let relative = from
+            .strip_prefix(&source)
+            .context("Error stripping prefix")?;
+        let to = if relative.as_os_str().is_empty() {
+            destination.clone()
+        } else {
+            destination.join(relative)
         };
-
-        if let Err(e) = inner() {
-            error!("Error copying file(s): {}", e);
-            event_broadcaster.send(Event::new_progression_event_end(
-                progression_event_id.unwrap(),
-                false,
-                Some(&format!("Error copying file(s): {}", e)),
-                Some(ProgressionEndValue::FSOperationCompleted {
-                    instance_uuid: uuid,
-                    success: false,
-                    message: format!("Error copying file(s): {}", e),
-                }),
-            ));
+        check_path_length(&to, max_path_length)?;
+        let protected = if file_type.is_dir() {
+            protected_files.is_protected_dir(&from) || protected_files.is_protected_dir(&to)
         } else {
-            event_broadcaster.send(Event::new_progression_event_end(
-                progression_event_id.unwrap(),
-                true,
-                None::<&str>,
-                Some(ProgressionEndValue::FSOperationCompleted {
-                    instance_uuid: uuid,
-                    success: true,
-                    message: "File(s) copied successfully".to_string(),
-                }),
+            protected_files.is_protected_file(&from) || protected_files.is_protected_file(&to)
+        };
+        if protected && !allow_protected {
+            return Err(Error::coded(
+                ErrorCode::ProtectedFile,
+                format!(
+                    "You don't have permission to move or copy {}",
+                    from.display()
+                ),
             ));
         }
-    });
-    Ok(Json(()))
-}
-
-async fn move_instance_file(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Path((uuid, base64_relative_path_source, base64_relative_path_dest)): Path<(
-        InstanceUuid,
-        String,
-        String,
-    )>,
-    AuthBearer(token): AuthBearer,
-) -> Result<Json<()>, Error> {
-    let relative_path_source = decode_base64(&base64_relative_path_source)?;
-    let relative_path_dest = decode_base64(&base64_relative_path_dest)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path_source = scoped_join_win_safe(&root, relative_path_source)?;
-    let path_dest = scoped_join_win_safe(&root, relative_path_dest)?;
-
-    let relative_path_source = path_source
-        .strip_prefix(&root)
-        .context("Error stripping prefix")?;
-    let relative_path_dest = path_dest
-        .strip_prefix(&root)
-        .context("Error stripping prefix")?;
-
-    if !requester.can_perform_action(&UserAction::WriteInstanceFile(uuid.clone()))
-        && (is_path_protected(&path_source) || is_path_protected(&path_dest))
-    {
-        return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("You don't have permission to write to this file"),
-        });
+        if file_type.is_file() {
+            total_bytes += entry.metadata().map_or(0, |metadata| metadata.len());
+        }
+        entries.push((from, to, file_type.is_dir()));
     }
+    Ok(TransferPlan {
+        source,
+        destination,
+        entries,
+        total_bytes,
+    })
+}
 
-    // if the destination is a subdirectory of the source, we reject the request
-    if path_dest.starts_with(&path_source) {
+/// Resolves both ends of a move or copy inside `root` and plans it. A path can't lead out of
+/// `root`, whatever `..` or symlinks it goes through
+fn plan_transfer_in(
+    root: &std::path::Path,
+    request: &TransferInstanceFileRequest,
+    max_path_length: usize,
+    protected_files: &ProtectedFilesPolicy,
+    allow_protected: bool,
+) -> Result<TransferPlan, Error> {
+    let source = scoped_join_win_safe(root, &request.source)?;
+    let destination = scoped_join_win_safe(root, &request.destination)?;
+    if source == root || destination == root {
         return Err(Error {
             kind: ErrorKind::BadRequest,
-            source: eyre!("Destination is a subdirectory of the source"),
+            source: eyre!("The instance directory itself can't be moved or copied"),
         });
     }
+    if !extended_length_path(&source).exists() {
+        return Err(Error::coded(
+            ErrorCode::FileNotFound,
+            format!("{} does not exist", request.source.display()),
+        )
+        .with_details(serde_json::json!({ "path": request.source })));
+    }
+    if destination.starts_with(&source) {
+        return Err(Error::coded(
+            ErrorCode::DestinationInsideSource,
+            "Destination is inside the source",
+        ));
+    }
+    let destination = resolve_path_conflict(destination, None);
+    plan_transfer(
+        source,
+        destination,
+        max_path_length,
+        protected_files,
+        allow_protected,
+    )
+}
 
-    let path_dest = resolve_path_conflict(path_dest.to_owned(), None);
-
-    tokio::fs::rename(&path_source, &path_dest)
-        .await
-        .context(format!(
-            "Error moving file from {} to {}",
-            relative_path_source.display(),
-            relative_path_dest.display()
-        ))?;
+/// Plans a move or copy and checks the requester may touch everything involved
+async fn prepare_transfer(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    allow_protected: bool,
+    request: TransferInstanceFileRequest,
+) -> Result<TransferPlan, Error> {
+    let root = instance_root(state, uuid).await?;
+    let max_path_length = state.global_settings.lock().await.max_path_length();
+    let protected_files = protected_files_policy(&root).await;
+    let plan = tokio::task::spawn_blocking(move || {
+        plan_transfer_in(
+            &root,
+            &request,
+            max_path_length,
+            &protected_files,
+            allow_protected,
+        )
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+    ensure_world_writable(state, uuid, &plan.destination).await?;
+    Ok(plan)
+}
 
-    let caused_by = CausedBy::User {
-        user_id: requester.uid,
-        user_name: requester.username,
-    };
+fn copy_planned(plan: &TransferPlan, on_progress: &mut dyn FnMut(u64)) -> Result<(), Error> {
+    let mut buf = vec![0; 64 * 1024];
+    for (from, to, is_dir) in &plan.entries {
+        if *is_dir {
+            fs::create_dir_all(extended_length_path(to))
+                .context(format!("Failed to create directory {}", to.display()))?;
+            continue;
+        }
+        let mut reader = fs::File::open(extended_length_path(from))
+            .context(format!("Failed to open {}", from.display()))?;
+        let mut writer = fs::File::create(extended_length_path(to))
+            .context(format!("Failed to create {}", to.display()))?;
+        loop {
+            let read = std::io::Read::read(&mut reader, &mut buf)
+                .context(format!("Failed to read {}", from.display()))?;
+            if read == 0 {
+                break;
+            }
+            std::io::Write::write_all(&mut writer, &buf[..read])
+                .context(format!("Failed to write {}", to.display()))?;
+            on_progress(read as u64);
+        }
+    }
+    Ok(())
+}
 
-    state.event_broadcaster.send(new_fs_event(
-        FSOperation::Move {
-            source: path_source.clone(),
+/// Copies a planned tree, reporting progress as a progression event. A failed copy leaves
+/// nothing behind at the destination
+async fn copy_with_progress(
+    event_broadcaster: EventBroadcaster,
+    uuid: InstanceUuid,
+    kind: FsOperationKind,
+    plan: TransferPlan,
+    caused_by: CausedBy,
+) -> Result<TransferPlan, Error> {
+    let (progression_start, event_id) = ProgressionStartBuilder::new(
+        format!(
+            "Copying {}",
+            plan.source
+                .file_name()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        ),
+        ProgressionStartValue::FsOperation {
+            instance_uuid: Some(uuid.clone()),
+            kind,
+            paths: vec![plan.source.clone()],
         },
-        FSTarget::File(path_source),
         caused_by,
-    ));
+    )
+    .total(plan.total_bytes as f64)
+    .build();
+    event_broadcaster.send(progression_start);
+    let (plan, event_id, result) = tokio::task::spawn_blocking({
+        let event_broadcaster = event_broadcaster.clone();
+        move || {
+            let threshold = (plan.total_bytes / 100).max(1);
+            let mut copied = 0;
+            let mut reported = 0;
+            let result = copy_planned(&plan, &mut |bytes| {
+                copied += bytes;
+                if copied - reported >= threshold {
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        &event_id,
+                        format!("Copied {}", format_byte_download(copied, plan.total_bytes)),
+                        (copied - reported) as f64,
+                    ));
+                    reported = copied;
+                }
+            });
+            if result.is_err() {
+                let _ = if extended_length_path(&plan.destination).is_dir() {
+                    fs::remove_dir_all(extended_length_path(&plan.destination))
+                } else {
+                    fs::remove_file(extended_length_path(&plan.destination))
+                };
+            }
+            (plan, event_id, result)
+        }
+    })
+    .await
+    .context("Failed to spawn blocking task")?;
+    event_broadcaster.send(match &result {
+        Ok(()) => Event::new_progression_event_end(
+            event_id,
+            true,
+            Some("Copy complete"),
+            Some(ProgressionEndValue::FsOperation {
+                instance_uuid: Some(uuid),
+                kind,
+                paths: vec![plan.destination.clone()],
+                bytes: plan.total_bytes,
+            }),
+        ),
+        Err(e) => Event::new_progression_event_end(
+            event_id,
+            false,
+            Some(format!("Copy failed: {}", e.source)),
+            None,
+        ),
+    });
+    result.map(|_| plan)
+}
 
-    Ok(Json(()))
+/// `rename` can't cross filesystems, e.g. into a directory that is a separate mount
+fn is_cross_device(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    const EXDEV: i32 = libc::EXDEV;
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(windows)]
+    const EXDEV: i32 = 17;
+    e.raw_os_error() == Some(EXDEV)
 }
 
-async fn remove_instance_file(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
-    AuthBearer(token): AuthBearer,
-) -> Result<Json<()>, Error> {
-    let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
-    // if target has a protected extension, or no extension, deny
-    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
+fn fs_target(path: PathBuf) -> FSTarget {
+    if extended_length_path(&path).is_dir() {
+        FSTarget::Directory(path)
+    } else {
+        FSTarget::File(path)
+    }
+}
+
+/// Moves what `plan` covers, renaming where it can and copying across filesystems
+async fn move_planned(
+    event_broadcaster: EventBroadcaster,
+    uuid: InstanceUuid,
+    plan: TransferPlan,
+    caused_by: CausedBy,
+) -> Result<TransferPlan, Error> {
+    match tokio::fs::rename(
+        extended_length_path(&plan.source),
+        extended_length_path(&plan.destination),
+    )
+    .await
+    {
+        Ok(()) => Ok(plan),
+        Err(e) if is_cross_device(&e) => {
+            let plan = copy_with_progress(
+                event_broadcaster,
+                uuid,
+                FsOperationKind::Move,
+                plan,
+                caused_by,
+            )
+            .await?;
+            if extended_length_path(&plan.source).is_dir() {
+                crate::util::fs::remove_dir_all(&plan.source).await?;
+            } else {
+                crate::util::fs::remove_file(&plan.source).await?;
+            }
+            Ok(plan)
+        }
+        Err(e) => Err(e)
+            .context(format!(
+                "Error moving {} to {}",
+                plan.source.display(),
+                plan.destination.display()
+            ))
+            .map_err(Into::into),
+    }
+}
+
+/// Moves a file or directory of the instance, returns where it ended up
+async fn move_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    requester: &User,
+    request: TransferInstanceFileRequest,
+) -> Result<PathBuf, Error> {
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let allow_protected = can_write_protected(requester, uuid);
+    let plan = prepare_transfer(state, uuid, allow_protected, request).await?;
+    ensure_world_writable(state, uuid, &plan.source).await?;
+    let plan = move_planned(
+        state.event_broadcaster.clone(),
+        uuid.clone(),
+        plan,
+        caused_by.clone(),
+    )
+    .await?;
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Move {
+            source: plan.source,
+        },
+        fs_target(plan.destination.clone()),
+        caused_by,
+    ));
+    Ok(plan.destination)
+}
+
+/// Copies a file or directory tree of the instance, returns where the copy is
+async fn copy_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    requester: &User,
+    request: TransferInstanceFileRequest,
+) -> Result<PathBuf, Error> {
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let allow_protected = can_write_protected(requester, uuid);
+    let plan = prepare_transfer(state, uuid, allow_protected, request).await?;
+    let plan = copy_with_progress(
+        state.event_broadcaster.clone(),
+        uuid.clone(),
+        FsOperationKind::Copy,
+        plan,
+        caused_by.clone(),
+    )
+    .await?;
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Create,
+        fs_target(plan.destination.clone()),
+        caused_by,
+    ));
+    Ok(plan.destination)
+}
+
+/// Moves or renames a file or directory
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/move",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = String),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn move_instance_path(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<TransferInstanceFileRequest>,
+) -> Result<Json<PathBuf>, Error> {
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    Ok(Json(move_path(&state, &uuid, &requester, request).await?))
+}
+
+/// Copies a file or a whole directory tree
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/copy",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = String),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn copy_instance_path(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<TransferInstanceFileRequest>,
+) -> Result<Json<PathBuf>, Error> {
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    Ok(Json(copy_path(&state, &uuid, &requester, request).await?))
+}
+
+/// Same as `PUT /instance/{uuid}/fs/move`, with both paths in the URL
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/move/{base64_relative_path_dest}",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+        ("base64_relative_path_dest" = String, Path, description = "Destination relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn move_instance_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path_source, base64_relative_path_dest)): Path<(
+        InstanceUuid,
+        String,
+        String,
+    )>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let request = TransferInstanceFileRequest {
+        source: decode_base64(&base64_relative_path_source)?.into(),
+        destination: decode_base64(&base64_relative_path_dest)?.into(),
+    };
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    move_path(&state, &uuid, &requester, request).await?;
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct RemoveQuery {
+    /// Delete right away instead of moving to the trash
+    #[serde(default)]
+    permanent: bool,
+}
+
+/// Moves `path` into the instance's trash, unless it's asked to be deleted for good or already
+/// is in the trash
+async fn remove_path(
+    root: &std::path::Path,
+    path: &std::path::Path,
+    permanent: bool,
+) -> Result<Option<TrashEntry>, Error> {
+    if !permanent && !path.starts_with(trash_dir(root)) {
+        return move_to_trash(root, path).await.map(Some);
+    }
+    if extended_length_path(path).is_dir() {
+        crate::util::fs::remove_dir_all(path).await?;
+    } else {
+        crate::util::fs::remove_file(path).await?;
+    }
+    Ok(None)
+}
+
+/// Moves the file into the instance's trash, the trash entry is returned unless `permanent` is
+/// set
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/rm",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+        ("permanent" = Option<bool>, Query, description = "Delete right away instead of moving to the trash"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn remove_instance_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<RemoveQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<TrashEntry>>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let ResolvedPath {
+        requester,
+        root,
+        path,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    let protected_files = protected_files_policy(&root).await;
+    // deny if the instance policy protects the target
+    if !can_write_protected(&requester, &uuid) && protected_files.is_protected(&path) {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "File extension is protected",
+        ));
+    }
+
+    if extended_length_path(&path).is_dir() {
         return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("File extension is protected"),
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is a directory", path.display()),
         });
     }
-
-    crate::util::fs::remove_file(&path).await?;
+    let trash_entry = remove_path(&root, &path, query.permanent).await?;
+    state.disk_usage.invalidate(&uuid);
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -510,61 +1349,80 @@ async fn remove_instance_file(
         FSTarget::File(path),
         caused_by,
     ));
-    Ok(Json(()))
+    Ok(Json(trash_entry))
 }
 
+/// Moves the directory into the instance's trash, the trash entry is returned unless
+/// `permanent` is set
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/rmdir",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+        ("permanent" = Option<bool>, Query, description = "Delete right away instead of moving to the trash"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn remove_instance_dir(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<RemoveQuery>,
     AuthBearer(token): AuthBearer,
-) -> Result<Json<()>, Error> {
+) -> Result<Json<Option<TrashEntry>>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(&root, relative_path)?;
+    let ResolvedPath {
+        requester,
+        root,
+        path,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    let protected_files = protected_files_policy(&root).await;
     if path == root {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
             source: eyre!("Cannot delete instance root"),
         });
     }
-    // if target has a protected extension, or no extension, deny
-    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
-        return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("File extension is protected"),
-        });
+    // deny if the instance policy protects the target
+    if !can_write_protected(&requester, &uuid) && protected_files.is_protected(&path) {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "File extension is protected",
+        ));
     }
 
-    if requester.can_perform_action(&UserAction::WriteGlobalFile) {
-        tokio::fs::remove_dir_all(&path)
-            .await
-            .context("Failed to remove directory")?;
-    } else {
-        // recursively access all files in the directory and check if they are protected
-        for entry in WalkDir::new(path.clone()) {
-            let entry =
-                entry.context("Failed to walk directory while scanning for protected files")?;
-            if entry.file_type().is_file() && is_path_protected(entry.path()) {
-                return Err(Error {
-                    kind: ErrorKind::PermissionDenied,
-                    source: eyre!("Directory contains protected files"),
-                });
+    if !can_write_protected(&requester, &uuid) {
+        // access all files in the directory and check if they are protected
+        for entry in walk_dir(&path, MAX_TRAVERSAL_DEPTH) {
+            let entry = entry?;
+            if entry.file_type().is_file() && protected_files.is_protected_file(entry.path()) {
+                return Err(Error::coded(
+                    ErrorCode::ProtectedFile,
+                    "Directory contains protected files",
+                ));
             }
         }
-        tokio::fs::remove_dir_all(&path)
-            .await
-            .context("Failed to remove directory")?;
     }
+    if !extended_length_path(&path).is_dir() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is not a directory", path.display()),
+        });
+    }
+    let trash_entry = remove_path(&root, &path, query.permanent).await?;
+    state.disk_usage.invalidate(&uuid);
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -575,34 +1433,175 @@ async fn remove_instance_dir(
         FSTarget::Directory(path),
         caused_by,
     ));
+    Ok(Json(trash_entry))
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/trash",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Trashed files and directories, most recently deleted first"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn list_instance_trash(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<TrashEntry>>, Error> {
+    authorize(&state, &token, &UserAction::ListInstanceFiles(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    Ok(Json(list_trash(&root).await?))
+}
+
+/// Puts a trashed file or directory back where it was deleted from
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/fs/trash/{id}/restore",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("id" = String, Path, description = "ID of the trash entry"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code, a conflict if the original path is taken", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn restore_instance_trash(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<TrashEntry>, Error> {
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    let entry = get_trash_entry(&root, &id).await?;
+    let original = root.join(&entry.path);
+    ensure_world_writable(&state, &uuid, &original).await?;
+    if !can_write_protected(&requester, &uuid)
+        && protected_files_policy(&root).await.is_protected(&original)
+    {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "File extension is protected",
+        ));
+    }
+    let entry = restore_from_trash(&root, &id).await?;
+
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Create,
+        fs_target(original),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(entry))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/fs/trash/{id}",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("id" = String, Path, description = "ID of the trash entry"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn purge_instance_trash_entry(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    let entry = get_trash_entry(&root, &id).await?;
+    purge_trash_entry(&root, &entry.id).await?;
+    state.disk_usage.invalidate(&uuid);
     Ok(Json(()))
 }
 
+/// Empties the trash, returning how many entries were in it
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/fs/trash",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = usize),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn purge_instance_trash(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<usize>, Error> {
+    authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    let entries = list_trash(&root).await?;
+    for entry in &entries {
+        purge_trash_entry(&root, &entry.id).await?;
+    }
+    state.disk_usage.invalidate(&uuid);
+    Ok(Json(entries.len()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/new",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn new_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<()>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(root, relative_path)?;
-    // if target has a protected extension, or no extension, deny
-    if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
-        return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("File extension is protected"),
-        });
+    let ResolvedPath {
+        requester,
+        root,
+        path,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    let protected_files = protected_files_policy(&root).await;
+    // deny if the instance policy protects the target
+    if !can_write_protected(&requester, &uuid) && protected_files.is_protected(&path) {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "File extension is protected",
+        ));
     }
+    check_path_length(&path, state.global_settings.lock().await.max_path_length())?;
 
     crate::util::fs::create(&path).await?;
 
@@ -618,30 +1617,39 @@ async fn new_instance_file(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/url",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn get_instance_file_url(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
     AuthBearer(token): AuthBearer,
 ) -> Result<String, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::ReadInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path = scoped_join_win_safe(&root, &relative_path)?;
+    let ResolvedPath {
+        requester, path, ..
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        &relative_path,
+        UserAction::ReadInstanceFile(uuid.clone()),
+    )
+    .await?;
 
     let downloadable_file = if fs::metadata(&path)
-        .map_err(|_| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Could not read file metadata"),
-        })?
+        .context("Could not read file metadata")?
         .is_dir()
     {
         let (start_event, id) = Event::new_progression_event_start(
@@ -701,6 +1709,234 @@ async fn get_instance_file_url(
     Ok(key)
 }
 
+/// Buffer between the zip writer and the response body, the archive is never held in memory
+const ZIP_STREAM_BUFFER_SIZE: usize = 256 * 1024;
+
+fn content_type(path: &std::path::Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("txt" | "log" | "properties" | "cfg" | "conf" | "ini" | "toml" | "yml" | "yaml") => {
+            "text/plain; charset=utf-8"
+        }
+        Some("json" | "mcmeta") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("zip") => "application/zip",
+        Some("jar") => "application/java-archive",
+        Some("gz" | "tgz") => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn attachment(file_name: &str) -> String {
+    format!(
+        "attachment; filename=\"{}\"",
+        file_name.replace(['"', '\\'], "_")
+    )
+}
+
+/// Writes `dir` as a zip into `writer` one entry at a time. Symlinks are skipped so nothing
+/// outside the instance ends up in the archive
+async fn stream_zip(dir: PathBuf, writer: tokio::io::DuplexStream) -> Result<(), Error> {
+    let entries = tokio::task::spawn_blocking(move || {
+        let base = dir.parent().unwrap_or(&dir).to_owned();
+        let mut entries = Vec::new();
+        for entry in walk_dir(&dir, MAX_TRAVERSAL_DEPTH) {
+            let entry = entry?;
+            let file_type = entry.file_type();
+            if !file_type.is_file() && !file_type.is_dir() {
+                continue;
+            }
+            let path = strip_extended_length_prefix(entry.path());
+            let name = match path.strip_prefix(&base) {
+                Ok(relative) => relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                Err(_) => continue,
+            };
+            entries.push((path, name, file_type.is_dir()));
+        }
+        Ok::<_, Error>(entries)
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut buf = vec![0; 64 * 1024];
+    for (path, name, is_dir) in entries {
+        if is_dir {
+            zip.write_entry_whole(
+                ZipEntryBuilder::new(format!("{name}/").into(), Compression::Stored),
+                &[],
+            )
+            .await
+            .context(format!("Failed to write {name} to the archive"))?;
+            continue;
+        }
+        let mut file = tokio::fs::File::open(extended_length_path(&path))
+            .await
+            .context(format!("Failed to open {}", path.display()))?;
+        let mut entry = zip
+            .write_entry_stream(ZipEntryBuilder::new(
+                name.clone().into(),
+                Compression::Deflate,
+            ))
+            .await
+            .context(format!("Failed to write {name} to the archive"))?;
+        loop {
+            let read = file
+                .read(&mut buf)
+                .await
+                .context(format!("Failed to read {}", path.display()))?;
+            if read == 0 {
+                break;
+            }
+            entry
+                .write_all(&buf[..read])
+                .await
+                .context(format!("Failed to write {name} to the archive"))?;
+        }
+        entry
+            .close()
+            .await
+            .context(format!("Failed to write {name} to the archive"))?;
+    }
+    zip.close().await.context("Failed to finish the archive")?;
+    Ok(())
+}
+
+/// Resolves the symlinks of `path`, refusing one that points outside the instance at `root`
+async fn canonicalize_in_instance(
+    root: &std::path::Path,
+    path: &std::path::Path,
+) -> Result<PathBuf, Error> {
+    let canonical_root = tokio::fs::canonicalize(root)
+        .await
+        .context("Failed to resolve instance path")?;
+    let canonical_path = tokio::fs::canonicalize(extended_length_path(path))
+        .await
+        .context("Failed to resolve the file")?;
+    if !strip_extended_length_prefix(&canonical_path)
+        .starts_with(strip_extended_length_prefix(&canonical_root))
+    {
+        return Err(Error::coded(
+            ErrorCode::PathOutsideInstance,
+            "Path leads outside the instance",
+        ));
+    }
+    Ok(canonical_path)
+}
+
+/// Streams a file as is, or a directory as a zip built on the fly
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/download/{relative_path}",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("relative_path" = String, Path, description = "Path relative to the instance directory, may span several segments"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn download_instance_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, relative_path)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Response, Error> {
+    let ResolvedPath {
+        requester,
+        root,
+        path,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        &relative_path,
+        UserAction::ReadInstanceFile(uuid.clone()),
+    )
+    .await?;
+    let canonical_path = canonicalize_in_instance(&root, &path).await?;
+    let file_name = path
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "download".to_string());
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+
+    if canonical_path.is_dir() {
+        let (writer, reader) = tokio::io::duplex(ZIP_STREAM_BUFFER_SIZE);
+        tokio::spawn({
+            let path = path.clone();
+            async move {
+                // a client that went away closes the reader, which fails the next write
+                if let Err(e) = stream_zip(canonical_path, writer).await {
+                    error!("Failed to stream {} as zip: {}", path.display(), e);
+                }
+            }
+        });
+        state.event_broadcaster.send(new_fs_event(
+            FSOperation::Download,
+            FSTarget::Directory(path),
+            caused_by,
+        ));
+        return Ok((
+            [
+                (CONTENT_TYPE, "application/zip".to_string()),
+                (CONTENT_DISPOSITION, attachment(&format!("{file_name}.zip"))),
+            ],
+            StreamBody::new(ReaderStream::new(reader)),
+        )
+            .into_response());
+    }
+
+    let file = tokio::fs::File::open(&canonical_path)
+        .await
+        .context(format!("Failed to open file {}", path.display()))?;
+    let len = file
+        .metadata()
+        .await
+        .context(format!("Failed to read metadata of {}", path.display()))?
+        .len();
+    let headers = [
+        (CONTENT_TYPE, content_type(&path).to_string()),
+        (CONTENT_DISPOSITION, attachment(&file_name)),
+        (CONTENT_LENGTH, len.to_string()),
+    ];
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Download,
+        FSTarget::File(path),
+        caused_by,
+    ));
+    Ok((headers, StreamBody::new(ReaderStream::new(file))).into_response())
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/upload",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn upload_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
@@ -709,116 +1945,108 @@ async fn upload_instance_file(
     mut multipart: Multipart,
 ) -> Result<Json<()>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
+    let ResolvedPath {
+        requester,
+        root,
+        path: path_to_dir,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path_to_dir = scoped_join_win_safe(&root, relative_path)?;
+    let protected_files = protected_files_policy(&root).await;
+    let (max_path_length, max_upload_size) = {
+        let global_settings = state.global_settings.lock().await;
+        (
+            global_settings.max_path_length(),
+            global_settings.max_upload_size(),
+        )
+    };
+    check_path_length(&path_to_dir, max_path_length)?;
     crate::util::fs::create_dir_all(&path_to_dir).await?;
 
     let total = headers
         .get(CONTENT_LENGTH)
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.parse::<f64>().ok());
+    if total.map_or(false, |total| total > max_upload_size as f64) {
+        return Err(upload_too_large(max_upload_size));
+    }
+    ensure_space(&state, &uuid, &root, total.unwrap_or(0.0) as u64).await?;
     let (progression_start_event, event_id) =
         Event::new_progression_event_start("Uploading files", total, None, caused_by.clone());
     state.event_broadcaster.send(progression_start_event);
-    while let Ok(Some(mut field)) = multipart.next_field().await {
-        let name = field.file_name().ok_or_else(|| Error {
-            kind: ErrorKind::BadRequest,
-            source: eyre!("Missing file name"),
-        })?;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field
+            .file_name()
+            .ok_or_else(|| Error::coded(ErrorCode::MissingFileName, "Missing file name"))?;
         let name = sanitize_filename::sanitize(name);
         let path = resolve_path_conflict(scoped_join_win_safe(&path_to_dir, &name)?, None);
-        // if the file has a protected extension, or no extension, deny
-        if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(&path) {
-            return Err(Error {
-                kind: ErrorKind::PermissionDenied,
-                source: eyre!("File extension is protected"),
-            });
+        // deny if the instance policy protects the file
+        if !can_write_protected(&requester, &uuid) && protected_files.is_protected(&path) {
+            return Err(Error::coded(
+                ErrorCode::ProtectedFile,
+                "File extension is protected",
+            ));
         }
         let path = resolve_path_conflict(path, None);
-
-        let mut file = crate::util::fs::create(&path).await?;
+        check_path_length(&path, max_path_length)?;
 
         let threshold = total.unwrap_or(500000.0) / 100.0;
 
         let mut elapsed_bytes = 0_u64;
         let mut last_progression = 0_u64;
 
-        while let Some(chunk) = match field.chunk().await {
-            Ok(v) => v,
-            Err(e) => {
-                tokio::fs::remove_file(&path).await.ok();
-                state
-                    .event_broadcaster
-                    .send(Event::new_progression_event_end(
-                        event_id,
-                        false,
-                        Some(&e.to_string()),
-                        Some(ProgressionEndValue::FSOperationCompleted {
-                            instance_uuid: uuid.clone(),
-                            success: false,
-                            message: format!("Failed to upload file {name}, {e}"),
-                        }),
-                    ));
-                return Err::<Json<()>, axum::extract::multipart::MultipartError>(e)
-                    .context("Failed to read chunk")
-                    .map_err(Error::from);
-            }
-        } {
-            elapsed_bytes += chunk.len() as u64;
-            let progression = (elapsed_bytes as f64 / threshold).floor() as u64;
-            if progression > last_progression {
-                last_progression = progression;
-                state
-                    .event_broadcaster
-                    .send(Event::new_progression_event_update(
-                        &event_id,
-                        if let Some(total) = total {
-                            format!(
-                                "Uploading {name}, {}",
-                                format_byte_download(elapsed_bytes, total as u64)
-                            )
-                        } else {
-                            format!("Uploading {name}, {} uploaded", format_byte(elapsed_bytes))
-                        },
-                        threshold,
-                    ));
-            }
-            match file.write_all(&chunk).await {
-                Ok(v) => v,
-                Err(e) => {
-                    tokio::fs::remove_file(&path).await.ok();
+        let written = write_upload(
+            &path,
+            field,
+            &mut elapsed_bytes,
+            max_upload_size,
+            |elapsed_bytes| {
+                let progression = (elapsed_bytes as f64 / threshold).floor() as u64;
+                if progression > last_progression {
+                    last_progression = progression;
                     state
                         .event_broadcaster
-                        .send(Event::new_progression_event_end(
-                            event_id,
-                            false,
-                            Some(&e.to_string()),
-                            Some(ProgressionEndValue::FSOperationCompleted {
-                                instance_uuid: uuid.clone(),
-                                success: false,
-                                message: format!("Failed to upload file {name}, {e}"),
-                            }),
+                        .send(Event::new_progression_event_update(
+                            &event_id,
+                            if let Some(total) = total {
+                                format!(
+                                    "Uploading {name}, {}",
+                                    format_byte_download(elapsed_bytes, total as u64)
+                                )
+                            } else {
+                                format!("Uploading {name}, {} uploaded", format_byte(elapsed_bytes))
+                            },
+                            threshold,
                         ));
-                    return Err::<Json<()>, std::io::Error>(e)
-                        .context("Failed to write chunk")
-                        .map_err(Error::from);
                 }
-            };
+            },
+        )
+        .await;
+        if let Err(e) = written {
+            state
+                .event_broadcaster
+                .send(Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(&e.source.to_string()),
+                    Some(ProgressionEndValue::FSOperationCompleted {
+                        instance_uuid: uuid.clone(),
+                        success: false,
+                        message: format!("Failed to upload file {name}, {}", e.source),
+                    }),
+                ));
+            return Err(e);
         }
+        state.disk_usage.add(&uuid, elapsed_bytes);
 
         state.event_broadcaster.send(new_fs_event(
             FSOperation::Upload,
@@ -841,74 +2069,341 @@ async fn upload_instance_file(
     Ok(Json(()))
 }
 
-pub async fn unzip_instance_file(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
-    AuthBearer(token): AuthBearer,
-    Json(unzip_option): Json<UnzipOption>,
-) -> Result<Json<()>, Error> {
-    let relative_path = decode_base64(&base64_relative_path)?;
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let path_to_zip_file = scoped_join_win_safe(root, &relative_path)?;
-
-    if let UnzipOption::ToDir(ref dir) = unzip_option {
-        if !requester.can_perform_action(&UserAction::WriteGlobalFile) && is_path_protected(dir) {
-            return Err(Error {
-                kind: ErrorKind::PermissionDenied,
-                source: eyre!("Destination is protected"),
-            });
-        }
-    }
-    let event_broadcaster = state.event_broadcaster.clone();
-    tokio::spawn(async move {
-        let (progression_event_start, event_id) = Event::new_progression_event_start(
-            format!("Unzipping {relative_path}"),
-            None,
-            None,
-            CausedBy::User {
-                user_id: requester.uid.clone(),
-                user_name: requester.username.clone(),
-            },
-        );
+/// Removes a file that was not completely written, including when the upload is abandoned
+pub(crate) struct PartialFile(pub(crate) Option<PathBuf>);
 
-        event_broadcaster.send(progression_event_start);
+impl PartialFile {
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
 
-        if let Err(e) = unzip_file_async(path_to_zip_file, unzip_option).await {
-            event_broadcaster.send(Event::new_progression_event_end(
-                event_id,
-                false,
-                Some(&format!("Unzip failed: {}", e)),
-                Some(ProgressionEndValue::FSOperationCompleted {
-                    instance_uuid: uuid,
-                    success: false,
-                    message: format!("Unzip {} failed : {e}", relative_path),
-                }),
-            ));
-        } else {
-            event_broadcaster.send(Event::new_progression_event_end(
-                event_id,
-                true,
-                Some("Unzip complete"),
-                Some(ProgressionEndValue::FSOperationCompleted {
-                    instance_uuid: uuid,
-                    success: true,
-                    message: format!("Unzipped {relative_path}"),
-                }),
-            ));
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = fs::remove_file(path);
         }
-    });
+    }
+}
 
-    Ok(Json(()))
+/// Streams `chunks` into a new file at `path`, adding their size to `uploaded`. The file is
+/// removed unless every chunk made it in, including when the upload is dropped midway
+async fn write_upload<S, E>(
+    path: &std::path::Path,
+    chunks: S,
+    uploaded: &mut u64,
+    max_upload_size: u64,
+    mut on_chunk: impl FnMut(u64),
+) -> Result<(), Error>
+where
+    S: futures::Stream<Item = Result<axum::body::Bytes, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    // declared first so the file is closed before the guard removes it
+    let partial_file = PartialFile(Some(path.to_path_buf()));
+    let mut file = crate::util::fs::create(path).await?;
+    let copied = {
+        let reader = StreamReader::new(chunks.map(|chunk| {
+            let chunk = chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            *uploaded += chunk.len() as u64;
+            if *uploaded > max_upload_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Maximum upload size exceeded",
+                ));
+            }
+            on_chunk(*uploaded);
+            Ok(chunk)
+        }));
+        tokio::pin!(reader);
+        tokio::io::copy(&mut reader, &mut file).await
+    };
+    if *uploaded > max_upload_size {
+        return Err(upload_too_large(max_upload_size));
+    }
+    copied.context(format!(
+        "Failed to upload file {}",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ))?;
+    partial_file.keep();
+    Ok(())
+}
+
+pub(crate) fn upload_too_large(max_upload_size: u64) -> Error {
+    Error::coded(
+        ErrorCode::UploadTooLarge,
+        format!(
+            "Upload exceeds the maximum upload size of {}",
+            format_byte(max_upload_size)
+        ),
+    )
+    .with_details(serde_json::json!({ "max_upload_size": max_upload_size }))
+}
+
+/// Streams every file of a `multipart/form-data` body into `relative_path`, only a chunk at a
+/// time is held in memory
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/upload/{relative_path}",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("relative_path" = String, Path, description = "Path relative to the instance directory, may span several segments"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = [String]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn upload_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, relative_path)): Path<(InstanceUuid, String)>,
+    headers: HeaderMap,
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<PathBuf>>, Error> {
+    let ResolvedPath {
+        requester,
+        root,
+        path: path_to_dir,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let protected_files = protected_files_policy(&root).await;
+    let (max_path_length, max_upload_size) = {
+        let global_settings = state.global_settings.lock().await;
+        (
+            global_settings.max_path_length(),
+            global_settings.max_upload_size(),
+        )
+    };
+    check_path_length(&path_to_dir, max_path_length)?;
+    let total = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if total.map_or(false, |total| total > max_upload_size) {
+        return Err(upload_too_large(max_upload_size));
+    }
+    ensure_space(&state, &uuid, &root, total.unwrap_or(0)).await?;
+    crate::util::fs::create_dir_all(&path_to_dir).await?;
+
+    let mut progression_start = ProgressionStartBuilder::new(
+        "Uploading files",
+        ProgressionStartValue::FsOperation {
+            instance_uuid: Some(uuid.clone()),
+            kind: FsOperationKind::Upload,
+            paths: vec![path_to_dir.clone()],
+        },
+        caused_by.clone(),
+    );
+    if let Some(total) = total {
+        progression_start = progression_start.total(total as f64);
+    }
+    let (progression_start_event, event_id) = progression_start.build();
+    state.event_broadcaster.send(progression_start_event);
+    let can_write_protected = can_write_protected(&requester, &uuid);
+    let event_broadcaster = state.event_broadcaster.clone();
+    let disk_usage = state.disk_usage.clone();
+    // a disconnecting client fails the body stream instead of cancelling the upload midway
+    tokio::spawn(async move {
+        let report_threshold = (total.unwrap_or(500000) / 100).max(1);
+        let mut uploaded = 0_u64;
+        let mut reported = 0_u64;
+        let mut paths = Vec::new();
+        let result = async {
+            while let Some(field) = multipart
+                .next_field()
+                .await
+                .context("Failed to read multipart field")?
+            {
+                let name = sanitize_filename::sanitize(field.file_name().ok_or_else(|| {
+                    Error::coded(ErrorCode::MissingFileName, "Missing file name")
+                })?);
+                let path = resolve_path_conflict(scoped_join_win_safe(&path_to_dir, &name)?, None);
+                if !can_write_protected && protected_files.is_protected(&path) {
+                    return Err(Error::coded(
+                        ErrorCode::ProtectedFile,
+                        format!("File extension of {} is protected", name),
+                    ));
+                }
+                check_path_length(&path, max_path_length)?;
+                write_upload(&path, field, &mut uploaded, max_upload_size, |uploaded| {
+                    if uploaded - reported >= report_threshold {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            &event_id,
+                            match total {
+                                Some(total) => format!(
+                                    "Uploading {name}, {}",
+                                    format_byte_download(uploaded, total)
+                                ),
+                                None => {
+                                    format!("Uploading {name}, {} uploaded", format_byte(uploaded))
+                                }
+                            },
+                            (uploaded - reported) as f64,
+                        ));
+                        reported = uploaded;
+                    }
+                })
+                .await?;
+                paths.push(path.clone());
+                event_broadcaster.send(new_fs_event(
+                    FSOperation::Upload,
+                    FSTarget::File(path),
+                    caused_by.clone(),
+                ));
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+        match result {
+            Ok(()) => disk_usage.add(&uuid, uploaded),
+            Err(_) => disk_usage.invalidate(&uuid),
+        }
+        event_broadcaster.send(match &result {
+            Ok(()) => Event::new_progression_event_end(
+                event_id,
+                true,
+                Some("Upload complete"),
+                Some(ProgressionEndValue::FsOperation {
+                    instance_uuid: Some(uuid),
+                    kind: FsOperationKind::Upload,
+                    paths: paths.clone(),
+                    bytes: uploaded,
+                }),
+            ),
+            Err(e) => Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(format!("Upload failed: {}", e.source)),
+                None,
+            ),
+        });
+        result.map(|_| Json(paths))
+    })
+    .await
+    .context("Upload task panicked")?
+}
+
+#[derive(Deserialize)]
+pub struct UnzipQuery {
+    /// Extract even if the files don't match the archive's checksum manifest
+    #[serde(default)]
+    skip_verify: bool,
+}
+
+/// Extracts an archive the way `POST /instance/{uuid}/fs/extract/{relative_path}` does, after
+/// checking it against its checksum manifest
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/{base64_relative_path}/unzip",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("base64_relative_path" = String, Path, description = "Path relative to the instance directory, base64 encoded"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn unzip_instance_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<UnzipQuery>,
+    AuthBearer(token): AuthBearer,
+    Json(unzip_option): Json<UnzipOption>,
+) -> Result<Json<()>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let ResolvedPath {
+        requester,
+        root,
+        path: archive,
+    } = resolve_instance_fs(
+        &state,
+        &token,
+        &uuid,
+        &relative_path,
+        UserAction::WriteInstanceFile(uuid.clone()),
+    )
+    .await?;
+    if !query.skip_verify {
+        let report = tokio::task::spawn_blocking({
+            let archive = archive.clone();
+            move || verify_archive(&archive)
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        match report {
+            Some(report) => report.into_result()?,
+            None => warn!(
+                "{} has no checksum manifest, extracting without verification",
+                relative_path
+            ),
+        }
+    }
+    let destination = unzip_destination(&root, &archive, unzip_option).await?;
+    // the manifest was only there for the check above
+    let manifest = destination.join(MANIFEST_FILE_NAME);
+    let had_manifest = extended_length_path(&manifest).exists();
+    extract_into(
+        &state,
+        &uuid,
+        &requester,
+        &root,
+        archive,
+        destination,
+        false,
+    )
+    .await?;
+    if !had_manifest {
+        let _ = tokio::fs::remove_file(extended_length_path(&manifest)).await;
+    }
+    Ok(Json(()))
+}
+
+/// Where `unzip_option` puts the files of `archive`
+async fn unzip_destination(
+    root: &std::path::Path,
+    archive: &std::path::Path,
+    unzip_option: UnzipOption,
+) -> Result<PathBuf, Error> {
+    let parent = archive.parent().unwrap_or(root).to_path_buf();
+    let stem = archive
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().trim_end_matches(".tar").to_string())
+        .unwrap_or_default();
+    Ok(match unzip_option {
+        UnzipOption::Normal => parent,
+        // avoids spilling an archive of many files next to it
+        UnzipOption::Smart => {
+            let top_level = tokio::task::spawn_blocking({
+                let archive = archive.to_path_buf();
+                move || archive_top_level_names(archive)
+            })
+            .await
+            .context("Failed to spawn blocking task")??;
+            if top_level.len() > 1 {
+                resolve_path_conflict(parent.join(stem), None)
+            } else {
+                parent
+            }
+        }
+        UnzipOption::ToDirectoryWithFileName => resolve_path_conflict(parent.join(stem), None),
+        UnzipOption::ToDir(dir) => scoped_join_win_safe(root, dir)?,
+    })
 }
 
 #[derive(Deserialize, TS)]
@@ -918,98 +2413,684 @@ struct ZipRequest {
     destination_relative_path: PathBuf,
 }
 
+/// Same as `POST /instance/{uuid}/fs/archive`
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/zip",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 async fn zip_instance_files(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
     AuthBearer(token): AuthBearer,
     Json(zip_request): Json<ZipRequest>,
 ) -> Result<Json<()>, Error> {
-    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(
-        &UserAction::WriteInstanceFile(uuid.clone()),
-        state.global_settings.lock().await.safe_mode(),
-    )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
-    let root = instance.path().await;
-    drop(instance);
-    let ZipRequest {
-        mut target_relative_paths,
-        mut destination_relative_path,
-    } = zip_request;
-
-    // apply scoped_join_win_safe to all paths
-    for path in &mut target_relative_paths {
-        *path = scoped_join_win_safe(&root, &*path)?;
-    }
-    destination_relative_path = scoped_join_win_safe(&root, &destination_relative_path)?;
-
-    if !requester.can_perform_action(&UserAction::ReadGlobalFile)
-        && is_path_protected(&destination_relative_path)
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    archive_paths(
+        &state,
+        &uuid,
+        &requester,
+        &zip_request.target_relative_paths,
+        &zip_request.destination_relative_path,
+    )
+    .await?;
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct ExtractQuery {
+    /// Directory to extract into relative to the instance, the archive's directory by default
+    destination: Option<PathBuf>,
+    /// Also replace existing files with a protected extension
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Unpacks an archive already in the instance, e.g. an uploaded modpack
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/fs/extract/{relative_path}",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("relative_path" = String, Path, description = "Path relative to the instance directory, may span several segments"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn extract_instance_archive(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<ExtractQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ExtractSummary>, Error> {
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    let archive = scoped_join_win_safe(&root, &relative_path)?;
+    let destination = match query.destination {
+        Some(ref destination) => scoped_join_win_safe(&root, destination)?,
+        None => archive.parent().unwrap_or(&root).to_path_buf(),
+    };
+    let summary = extract_into(
+        &state,
+        &uuid,
+        &requester,
+        &root,
+        archive,
+        destination,
+        query.overwrite,
+    )
+    .await?;
+    Ok(Json(summary))
+}
+
+/// Extracts `archive` into `destination`, both already joined onto the instance `root`.
+///
+/// Every entry is checked against the destination before anything is written, and can't land
+/// outside of it. The progression it reports can be cancelled
+async fn extract_into(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    requester: &User,
+    root: &std::path::Path,
+    archive: PathBuf,
+    destination: PathBuf,
+    overwrite: bool,
+) -> Result<ExtractSummary, Error> {
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let can_write_protected = can_write_protected(requester, uuid);
+    if overwrite && !can_write_protected {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "You don't have permission to overwrite protected files",
+        ));
+    }
+    if !extended_length_path(&archive).is_file() {
+        let relative_path = archive.strip_prefix(root).unwrap_or(&archive);
+        return Err(Error::coded(
+            ErrorCode::FileNotFound,
+            format!("{} does not exist", relative_path.display()),
+        )
+        .with_details(serde_json::json!({ "path": relative_path })));
+    }
+    let protected_files = protected_files_policy(root).await;
+    ensure_world_writable(state, uuid, &destination).await?;
+    if !can_write_protected
+        && extended_length_path(&destination).is_dir()
+        && protected_files.is_protected(&destination)
     {
-        return Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("Destination is protected"),
-        });
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "Destination is protected",
+        ));
     }
+    let max_path_length = state.global_settings.lock().await.max_path_length();
+    let needed = archive_size(archive.clone()).await?;
+    ensure_space(state, uuid, root, needed).await?;
 
     let event_broadcaster = state.event_broadcaster.clone();
+    let cancellation_registry = state.cancellation_registry.clone();
+    let summary = tokio::task::spawn_blocking({
+        let uuid = uuid.clone();
+        let destination = destination.clone();
+        let caused_by = caused_by.clone();
+        move || {
+            let total = check_archive_entries(&archive, &destination, max_path_length)?;
+            let (progression_start, event_id) = ProgressionStartBuilder::new(
+                format!(
+                    "Extracting {}",
+                    archive
+                        .file_name()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                ),
+                ProgressionStartValue::FsOperation {
+                    instance_uuid: Some(uuid.clone()),
+                    kind: FsOperationKind::Unzip,
+                    paths: vec![archive.clone()],
+                },
+                caused_by,
+            )
+            .total(total as f64)
+            .build();
+            event_broadcaster.send(progression_start);
+            let cancellation = cancellation_registry
+                .register(&event_id, UserAction::WriteInstanceFile(uuid.clone()));
+            let threshold = (total / 100).max(1);
+            let mut reported = 0;
+            let result = extract_archive(
+                &archive,
+                &destination,
+                &|path| !overwrite && protected_files.is_protected(path),
+                &mut |done| {
+                    if done - reported >= threshold || done == total {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            &event_id,
+                            format!("Extracted {done} of {total} entries"),
+                            (done - reported) as f64,
+                        ));
+                        reported = done;
+                    }
+                    checkpoint(&cancellation)
+                },
+            );
+            event_broadcaster.send(match &result {
+                Ok(summary) => Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some(format!(
+                        "Extracted {} files, skipped {} protected files",
+                        summary.extracted,
+                        summary.skipped.len()
+                    )),
+                    Some(ProgressionEndValue::FsOperation {
+                        instance_uuid: Some(uuid),
+                        kind: FsOperationKind::Unzip,
+                        paths: vec![destination],
+                        bytes: summary.bytes,
+                    }),
+                ),
+                Err(_) if cancellation.is_cancelled() => {
+                    Event::new_progression_event_cancelled(event_id, Some(uuid))
+                }
+                Err(e) => Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(format!("Extraction failed: {}", e.source)),
+                    None,
+                ),
+            });
+            result
+        }
+    })
+    .await
+    .context("Failed to spawn blocking task")?;
+    // a failed extraction may have left some files behind
+    let summary = match summary {
+        Ok(summary) => {
+            state.disk_usage.add(uuid, summary.bytes);
+            summary
+        }
+        Err(e) => {
+            state.disk_usage.invalidate(uuid);
+            return Err(e);
+        }
+    };
 
-    tokio::spawn(async move {
-        let aggregate_name = {
-            let combined_file_name = target_relative_paths
-                .iter()
-                .map(|p| p.file_name().unwrap().to_string_lossy())
-                .collect::<Vec<_>>()
-                .join(", ");
-            if combined_file_name.len() < 100 {
-                combined_file_name
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Create,
+        fs_target(destination),
+        caused_by,
+    ));
+    Ok(summary)
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct ArchiveInstanceFilesRequest {
+    paths: Vec<PathBuf>,
+    /// Where the zip goes, a numbered name is picked if it is taken
+    destination: PathBuf,
+}
+
+/// Zips a selection of paths into a new archive inside the instance
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/fs/archive",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = String),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn archive_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<ArchiveInstanceFilesRequest>,
+) -> Result<Json<PathBuf>, Error> {
+    let requester = authorize(&state, &token, &UserAction::WriteInstanceFile(uuid.clone())).await?;
+    let archive = archive_paths(
+        &state,
+        &uuid,
+        &requester,
+        &request.paths,
+        &request.destination,
+    )
+    .await?;
+    Ok(Json(archive))
+}
+
+/// Zips `paths` of the instance into a new archive at `destination`, returns where it went
+async fn archive_paths(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    requester: &User,
+    paths: &[PathBuf],
+    destination: &std::path::Path,
+) -> Result<PathBuf, Error> {
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let root = instance_root(state, uuid).await?;
+    if paths.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Nothing to archive"),
+        });
+    }
+    let mut joined_paths = Vec::with_capacity(paths.len());
+    for path in paths {
+        let joined = scoped_join_win_safe(&root, path)?;
+        if joined == root || !extended_length_path(&joined).exists() {
+            return Err(Error::coded(
+                ErrorCode::FileNotFound,
+                format!("{} does not exist", path.display()),
+            )
+            .with_details(serde_json::json!({ "path": path })));
+        }
+        joined_paths.push(joined);
+    }
+    let relative_destination = destination;
+    let destination = scoped_join_win_safe(&root, relative_destination)?;
+    ensure_world_writable(state, uuid, &destination).await?;
+    if destination.extension() != Some(std::ffi::OsStr::new("zip")) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Destination must be a .zip file"),
+        });
+    }
+    if !can_write_protected(requester, uuid)
+        && protected_files_policy(&root)
+            .await
+            .is_protected(&destination)
+    {
+        return Err(Error::coded(
+            ErrorCode::ProtectedFile,
+            "Destination is protected",
+        ));
+    }
+    check_path_length(
+        &destination,
+        state.global_settings.lock().await.max_path_length(),
+    )?;
+
+    let (progression_start, event_id) = ProgressionStartBuilder::new(
+        format!(
+            "Archiving {} into {}",
+            if paths.len() == 1 {
+                paths[0].display().to_string()
             } else {
-                format!("{} files", target_relative_paths.len())
-            }
-        };
-        let (progression_start_event, event_id) = Event::new_progression_event_start(
-            format!("Zipping {aggregate_name}"),
-            None,
-            None,
-            CausedBy::User {
-                user_id: requester.uid.clone(),
-                user_name: requester.username.clone(),
+                format!("{} files", paths.len())
             },
-        );
-        event_broadcaster.send(progression_start_event);
+            relative_destination.display()
+        ),
+        ProgressionStartValue::FsOperation {
+            instance_uuid: Some(uuid.clone()),
+            kind: FsOperationKind::Zip,
+            paths: joined_paths.clone(),
+        },
+        caused_by.clone(),
+    )
+    .build();
+    state.event_broadcaster.send(progression_start);
+    let result = zip_files_async(&joined_paths, destination, false).await;
+    state.event_broadcaster.send(match &result {
+        Ok(archive) => Event::new_progression_event_end(
+            event_id,
+            true,
+            Some("Archive created"),
+            Some(ProgressionEndValue::FsOperation {
+                instance_uuid: Some(uuid.clone()),
+                kind: FsOperationKind::Zip,
+                paths: vec![archive.clone()],
+                bytes: fs::metadata(archive).map_or(0, |metadata| metadata.len()),
+            }),
+        ),
+        Err(e) => Event::new_progression_event_end(
+            event_id,
+            false,
+            Some(format!("Archiving failed: {}", e.source)),
+            None,
+        ),
+    });
+    let archive = result?;
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Create,
+        fs_target(archive.clone()),
+        caused_by,
+    ));
+    Ok(archive)
+}
 
-        if let Err(e) =
-            zip_files_async(&target_relative_paths, destination_relative_path, false).await
-        {
-            event_broadcaster.send(Event::new_progression_event_end(
-                event_id,
-                false,
-                Some(&format!("Zipping failed: {e}")),
-                Some(ProgressionEndValue::FSOperationCompleted {
-                    instance_uuid: uuid,
-                    success: false,
-                    message: format!("Zipping {aggregate_name} failed : {e}"),
-                }),
-            ));
-        } else {
-            event_broadcaster.send(Event::new_progression_event_end(
-                event_id,
-                true,
-                Some("Zip complete"),
-                Some(ProgressionEndValue::FSOperationCompleted {
-                    instance_uuid: uuid,
-                    success: true,
-                    message: format!("Zipped {aggregate_name}"),
-                }),
-            ));
+/// Searches that take longer than this can be cancelled through their progression
+const SEARCH_PROGRESSION_AFTER: std::time::Duration = std::time::Duration::from_secs(2);
+const SEARCH_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Searches the text files of the instance line by line, skipping binary files.
+///
+/// A search still running after a few seconds gets a progression it can be cancelled through
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/fs/search",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Matches as relative path, line number and line, with why the search stopped early if it did"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn search_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(query): Json<SearchQuery>,
+) -> Result<Json<SearchResult>, Error> {
+    let requester = authorize(&state, &token, &UserAction::ReadInstanceFile(uuid.clone())).await?;
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Searching files is not supported for Docker instances"),
+        });
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    let root = instance_root(&state, &uuid).await?;
+    let event_broadcaster = state.event_broadcaster.clone();
+    let cancellation_registry = state.cancellation_registry.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut progression = None;
+        let mut last_update = std::time::Instant::now();
+        let result = search_dir(&root, &query, &mut |elapsed, so_far| {
+            if progression.is_none() && elapsed >= SEARCH_PROGRESSION_AFTER {
+                let (progression_start, event_id) = ProgressionStartBuilder::new(
+                    format!("Searching files for \"{}\"", query.query),
+                    ProgressionStartValue::FsOperation {
+                        instance_uuid: Some(uuid.clone()),
+                        kind: FsOperationKind::Search,
+                        paths: vec![root.clone()],
+                    },
+                    caused_by.clone(),
+                )
+                .build();
+                event_broadcaster.send(progression_start);
+                let cancellation = cancellation_registry
+                    .register(&event_id, UserAction::ReadInstanceFile(uuid.clone()));
+                progression = Some((event_id, cancellation));
+            }
+            match &progression {
+                Some((event_id, cancellation)) => {
+                    if last_update.elapsed() >= SEARCH_UPDATE_INTERVAL {
+                        last_update = std::time::Instant::now();
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            event_id,
+                            format!(
+                                "Searched {} files, {} matches so far",
+                                so_far.files_scanned,
+                                so_far.matches.len()
+                            ),
+                            0.0,
+                        ));
+                    }
+                    checkpoint(cancellation)
+                }
+                None => Ok(()),
+            }
+        });
+        if let Some((event_id, cancellation)) = progression {
+            event_broadcaster.send(match &result {
+                Ok(result) => Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some(format!(
+                        "Found {} matches in {} files",
+                        result.matches.len(),
+                        result.files_scanned
+                    )),
+                    Some(ProgressionEndValue::FsOperation {
+                        instance_uuid: Some(uuid.clone()),
+                        kind: FsOperationKind::Search,
+                        paths: Vec::new(),
+                        bytes: result.bytes_scanned,
+                    }),
+                ),
+                Err(_) if cancellation.is_cancelled() => {
+                    Event::new_progression_event_cancelled(event_id, Some(uuid))
+                }
+                Err(e) => Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(format!("Search failed: {}", e.source)),
+                    None,
+                ),
+            });
         }
-    });
+        result
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+    Ok(Json(result))
+}
 
-    // remove root from path
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct WatchInstanceFilesRequest {
+    /// Stop the watcher instead of starting or renewing it
+    #[serde(default)]
+    stop: bool,
+    /// Globs of relative paths to leave out, defaults to logs and region files
+    exclude: Option<Vec<String>>,
+    /// How long the watcher runs without being renewed, 10 minutes by default and an hour at most
+    idle_timeout_secs: Option<u64>,
+}
 
+/// Starts, renews or stops the instance's file watcher.
+///
+/// While it runs, changes to the instance directory are sent as `FileChanged` instance events,
+/// a client keeps the watcher alive by renewing it before it expires
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/fs/watch",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "When the watcher expires and what it excludes, nothing if it was stopped"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn watch_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<WatchInstanceFilesRequest>,
+) -> Result<Json<Option<WatchStatus>>, Error> {
+    authorize(&state, &token, &UserAction::ListInstanceFiles(uuid.clone())).await?;
+    if request.stop {
+        state.fs_watchers.unwatch(&uuid);
+        return Ok(Json(None));
+    }
+    if uuid.to_string().starts_with("DOCKER-") {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Watching files is not supported for Docker instances"),
+        });
+    }
+    let instance = game_instance(&state, &uuid)?;
+    let status = state.fs_watchers.watch(
+        &uuid,
+        instance.name().await,
+        &instance.path().await,
+        request.exclude.unwrap_or_else(|| {
+            DEFAULT_EXCLUDES
+                .iter()
+                .map(|glob| glob.to_string())
+                .collect()
+        }),
+        request
+            .idle_timeout_secs
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT),
+        state.event_broadcaster.clone(),
+    )?;
+    Ok(Json(Some(status)))
+}
+
+async fn instance_root(state: &AppState, uuid: &InstanceUuid) -> Result<PathBuf, Error> {
+    let instance = game_instance(state, uuid)?;
+    Ok(instance.path().await)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/fs/protected",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn get_protected_files_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ProtectedFilesPolicy>, Error> {
+    authorize(&state, &token, &UserAction::ReadInstanceFile(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    Ok(Json(
+        read_dot_lodestone_config(&root)
+            .await?
+            .protected_files()
+            .clone(),
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/fs/protected",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn set_protected_files_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(policy): Json<ProtectedFilesPolicy>,
+) -> Result<Json<()>, Error> {
+    let requester = authorize(
+        &state,
+        &token,
+        &UserAction::ManageProtectedFiles(uuid.clone()),
+    )
+    .await?;
+    let root = instance_root(&state, &uuid).await?;
+    let mut config = read_dot_lodestone_config(&root).await?;
+    config.set_protected_files(policy);
+    write_dot_lodestone_config(&root, &config).await?;
+    Ok(Json(()))
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+struct InstanceDiskUsage {
+    used: DirSize,
+    /// Bytes the instance may use, unlimited when unset
+    quota: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/disk",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn get_instance_disk_usage(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<InstanceDiskUsage>, Error> {
+    authorize(&state, &token, &UserAction::ListInstanceFiles(uuid.clone())).await?;
+    let root = instance_root(&state, &uuid).await?;
+    Ok(Json(InstanceDiskUsage {
+        used: state.disk_usage.size(&uuid, &root).await?,
+        quota: read_dot_lodestone_config(&root).await?.disk_quota(),
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/disk/quota",
+    tag = "instance_fs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+async fn set_instance_disk_quota(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(quota): Json<Option<u64>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.can_perform_action(&UserAction::AdministerInstance(Some(uuid.clone()))) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner and admins can change disk quotas"),
+        });
+    }
+    let root = instance_root(&state, &uuid).await?;
+    let mut config = read_dot_lodestone_config(&root).await?;
+    config.set_disk_quota(quota);
+    write_dot_lodestone_config(&root, &config).await?;
     Ok(Json(()))
 }
 
@@ -1032,6 +3113,8 @@ pub fn get_instance_fs_routes(state: AppState) -> Router {
             put(make_instance_directory),
         )
         .route("/instance/:uuid/fs/cpr", put(copy_instance_files))
+        .route("/instance/:uuid/fs/move", put(move_instance_path))
+        .route("/instance/:uuid/fs/copy", put(copy_instance_path))
         .route(
             "/instance/:uuid/fs/:base64_relative_path/move/:base64_relative_path_dest",
             put(move_instance_file),
@@ -1056,11 +3139,405 @@ pub fn get_instance_fs_routes(state: AppState) -> Router {
             "/instance/:uuid/fs/:base64_relative_path/upload",
             put(upload_instance_file),
         )
+        .route(
+            "/instance/:uuid/fs/download/*relative_path",
+            get(download_instance_file),
+        )
+        .route(
+            "/instance/:uuid/fs/upload/*relative_path",
+            put(upload_instance_files),
+        )
         .layer(DefaultBodyLimit::disable())
         .route(
             "/instance/:uuid/fs/:base64_relative_path/unzip",
             put(unzip_instance_file),
         )
         .route("/instance/:uuid/fs/zip", put(zip_instance_files))
+        .route(
+            "/instance/:uuid/fs/extract/*relative_path",
+            post(extract_instance_archive),
+        )
+        .route("/instance/:uuid/fs/archive", post(archive_instance_files))
+        .route("/instance/:uuid/fs/search", post(search_instance_files))
+        .route("/instance/:uuid/fs/watch", post(watch_instance_files))
+        .route(
+            "/instance/:uuid/fs/trash",
+            get(list_instance_trash).delete(purge_instance_trash),
+        )
+        .route(
+            "/instance/:uuid/fs/trash/:id",
+            delete(purge_instance_trash_entry),
+        )
+        .route(
+            "/instance/:uuid/fs/trash/:id/restore",
+            post(restore_instance_trash),
+        )
+        .route(
+            "/instance/:uuid/fs/hash/*relative_path",
+            get(get_instance_file_hash),
+        )
+        .route("/instance/:uuid/fs/hash", post(get_instance_file_hashes))
+        .route(
+            "/instance/:uuid/fs/protected",
+            get(get_protected_files_policy).put(set_protected_files_policy),
+        )
+        .route("/instance/:uuid/disk", get(get_instance_disk_usage))
+        .route("/instance/:uuid/disk/quota", put(set_instance_disk_quota))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.properties");
+        assert_eq!(current_etag(&path).await.unwrap(), None);
+        std::fs::write(&path, "motd=hi").unwrap();
+        let etag = current_etag(&path).await.unwrap().unwrap();
+        assert!(etag.starts_with(&format!("\"{}-", hex::encode(Sha256::digest("motd=hi")))));
+
+        assert!(if_match(&etag, Some(&etag)));
+        assert!(if_match(&format!("\"other\", W/{}", etag), Some(&etag)));
+        assert!(if_match("*", Some(&etag)));
+        assert!(!if_match("*", None));
+        assert!(!if_match("\"other\"", Some(&etag)));
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.properties");
+        // larger than one chunk so the chunks are stitched together
+        let content = b"hello world".repeat(HASH_CHUNK_SIZE / 4);
+        std::fs::write(&path, &content).unwrap();
+        let hash = hash_file(&path).unwrap();
+        assert_eq!(hash.sha256, hex::encode(Sha256::digest(&content)));
+        assert_eq!(hash.size, content.len() as u64);
+        assert!(hash.modified.is_some());
+
+        std::fs::write(&path, b"hello world").unwrap();
+        assert_eq!(
+            hash_file(&path).unwrap().sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert!(matches!(
+            hash_file(dir.path()).unwrap_err().kind,
+            ErrorKind::BadRequest
+        ));
+        assert!(matches!(
+            hash_file(&dir.path().join("missing")).unwrap_err().kind,
+            ErrorKind::NotFound
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hash_instance_file_stays_in_instance() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), b"a").unwrap();
+        std::fs::write(outside.path().join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.txt"),
+            root.path().join("link.txt"),
+        )
+        .unwrap();
+        let root = root.path().to_path_buf();
+        assert!(hash_instance_file(root.clone(), "a.txt".to_string())
+            .await
+            .is_ok());
+        assert!(matches!(
+            hash_instance_file(root.clone(), "missing.txt".to_string())
+                .await
+                .unwrap_err()
+                .kind,
+            ErrorKind::NotFound
+        ));
+        assert!(matches!(
+            hash_instance_file(root, "link.txt".to_string())
+                .await
+                .unwrap_err()
+                .kind,
+            ErrorKind::PermissionDenied
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_download_stays_in_instance() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), b"a").unwrap();
+        std::fs::write(outside.path().join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.txt"),
+            root.path().join("link.txt"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("linked_dir")).unwrap();
+        let root = root.path();
+
+        let path = scoped_join_win_safe(root, "a.txt").unwrap();
+        assert!(canonicalize_in_instance(root, &path).await.is_ok());
+        // a traversal is clamped to the root before it gets here
+        let path = scoped_join_win_safe(root, "../../a.txt").unwrap();
+        assert_eq!(path, root.join("a.txt"));
+        for escaping in ["link.txt", "linked_dir", "linked_dir/secret.txt"] {
+            assert!(scoped_join_win_safe(root, escaping)
+                .unwrap()
+                .starts_with(root));
+            // a link that got past the join is still caught once resolved
+            let e = canonicalize_in_instance(root, &root.join(escaping))
+                .await
+                .unwrap_err();
+            assert_eq!(e.code(), ErrorCode::PathOutsideInstance);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_zip_skips_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), b"secret").unwrap();
+        let world = root.path().join("world");
+        std::fs::create_dir_all(world.join("region")).unwrap();
+        std::fs::write(world.join("level.dat"), b"level data").unwrap();
+        std::fs::write(world.join("region/r.0.0.mca"), b"region").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret.txt"), world.join("secret.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(outside.path(), world.join("outside")).unwrap();
+
+        let (writer, mut reader) = tokio::io::duplex(ZIP_STREAM_BUFFER_SIZE);
+        let streaming = tokio::spawn(stream_zip(world, writer));
+        let mut zipped = Vec::new();
+        reader.read_to_end(&mut zipped).await.unwrap();
+        streaming.await.unwrap().unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zipped)).unwrap();
+        let mut names = archive.file_names().map(str::to_string).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "world/",
+                "world/level.dat",
+                "world/region/",
+                "world/region/r.0.0.mca"
+            ]
+        );
+        let mut level = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name("world/level.dat").unwrap(), &mut level)
+            .unwrap();
+        assert_eq!(level, "level data");
+    }
+
+    fn chunks(
+        chunks: &[&'static str],
+    ) -> impl futures::Stream<Item = Result<axum::body::Bytes, std::io::Error>> {
+        futures::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(axum::body::Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_write_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("modpack.zip");
+        let mut uploaded = 0;
+        let mut progress = Vec::new();
+        write_upload(
+            &path,
+            chunks(&["hello ", "world"]),
+            &mut uploaded,
+            11,
+            |done| progress.push(done),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello world");
+        assert_eq!(uploaded, 11);
+        assert_eq!(progress, vec![6, 11]);
+
+        // the limit covers every file of the request, not just this one
+        let path = dir.path().join("second.zip");
+        let e = write_upload(&path, chunks(&["!"]), &mut uploaded, 11, |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ErrorCode::UploadTooLarge);
+        assert!(!path.exists());
+
+        // a body that fails midway
+        let failing = chunks(&["hello "]).chain(futures::stream::iter([Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "reset",
+        ))]));
+        assert!(write_upload(&path, failing, &mut 0, 11, |_| {})
+            .await
+            .is_err());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_upload_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("modpack.zip");
+        // a client that stops sending after the first chunk
+        let stalled = chunks(&["hello "]).chain(futures::stream::pending());
+        let upload = tokio::spawn({
+            let path = path.clone();
+            async move { write_upload(&path, stalled, &mut 0, u64::MAX, |_| {}).await }
+        });
+        while std::fs::metadata(&path).map_or(true, |metadata| metadata.len() == 0) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        upload.abort();
+        assert!(upload.await.unwrap_err().is_cancelled());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_resolve_read_window() {
+        let none = ReadFileQuery::default();
+        assert_eq!(resolve_read_window(&none, None, 1000).unwrap(), None);
+
+        let window = ReadFileQuery {
+            offset: Some(100),
+            length: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_read_window(&window, Some("bytes=0-9"), 1000).unwrap(),
+            Some((100, 150))
+        );
+        let past_end = ReadFileQuery {
+            offset: Some(900),
+            length: Some(500),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_read_window(&past_end, None, 1000).unwrap(),
+            Some((900, 1000))
+        );
+        let tail = ReadFileQuery {
+            tail: true,
+            length: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_read_window(&tail, None, 1000).unwrap(),
+            Some((990, 1000))
+        );
+        let huge = ReadFileQuery {
+            offset: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_read_window(&huge, None, u64::MAX).unwrap(),
+            Some((0, MAX_READ_WINDOW))
+        );
+        let beyond = ReadFileQuery {
+            offset: Some(1001),
+            ..Default::default()
+        };
+        assert!(resolve_read_window(&beyond, None, 1000).is_err());
+    }
+
+    fn transfer(source: &str, destination: &str) -> TransferInstanceFileRequest {
+        TransferInstanceFileRequest {
+            source: source.into(),
+            destination: destination.into(),
+        }
+    }
+
+    fn plan(
+        root: &std::path::Path,
+        request: TransferInstanceFileRequest,
+    ) -> Result<TransferPlan, Error> {
+        plan_transfer_in(
+            root,
+            &request,
+            4096,
+            &ProtectedFilesPolicy::default(),
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_transfer_stays_in_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("instance");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), b"secret").unwrap();
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+
+        // `..` can't reach the file next to the instance
+        let error = plan(&root, transfer("../secret.txt", "stolen.txt"))
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), ErrorCode::FileNotFound);
+        let error = plan(&root, transfer("/../../secret.txt", "stolen.txt"))
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), ErrorCode::FileNotFound);
+
+        // nor can a destination put anything next to it
+        let moving = plan(&root, transfer("a.txt", "../../moved.txt")).unwrap();
+        assert_eq!(moving.destination, root.join("moved.txt"));
+        let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+        move_planned(
+            event_broadcaster,
+            InstanceUuid::default(),
+            moving,
+            CausedBy::System,
+        )
+        .await
+        .unwrap();
+        assert!(root.join("moved.txt").is_file());
+        assert!(!root.join("a.txt").exists());
+        assert!(!dir.path().join("moved.txt").exists());
+        assert_eq!(
+            std::fs::read(dir.path().join("secret.txt")).unwrap(),
+            b"secret"
+        );
+
+        let error = plan(&root, transfer("moved.txt", "..")).err().unwrap();
+        assert!(matches!(error.kind, ErrorKind::BadRequest));
+    }
+
+    #[test]
+    fn test_directory_into_itself() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("world").join("region")).unwrap();
+        for destination in ["world/region/world", "world/copy", "world"] {
+            let error = plan(root.path(), transfer("world", destination))
+                .err()
+                .unwrap();
+            assert_eq!(error.code(), ErrorCode::DestinationInsideSource);
+        }
+        // a sibling whose name merely starts the same is fine
+        let copying = plan(root.path(), transfer("world", "world_nether")).unwrap();
+        assert_eq!(copying.destination, root.path().join("world_nether"));
+        assert_eq!(copying.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_range_header() {
+        assert_eq!(parse_range_header("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range_header("bytes=500-", 1000), Some((500, 1000)));
+        assert_eq!(parse_range_header("bytes=-200", 1000), Some((800, 1000)));
+        assert_eq!(
+            parse_range_header("bytes=900-2000", 1000),
+            Some((900, 1000))
+        );
+        assert_eq!(parse_range_header("bytes=10-5", 1000), None);
+        assert_eq!(parse_range_header("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range_header("items=0-1", 1000), None);
+    }
+}
@@ -0,0 +1,112 @@
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
+
+use crate::{
+    audit::{audit_value, AuditTarget},
+    auth::user::UserAction,
+    error::Error,
+    events::CausedBy,
+    implementations::minecraft::game_update::{GameUpdateOutcome, GameUpdates},
+    types::InstanceUuid,
+    AppState,
+};
+
+use super::util::minecraft_instance;
+
+/// Stable Paper builds newer than the installed one, with their changelogs
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/game/updates",
+    tag = "instance_game",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Installed build, the build waiting for the next start and the newer builds"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_game_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<GameUpdates>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .game_updates()
+        .await
+        .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct GameUpdateRequest {
+    /// The newest stable build when unset
+    #[serde(default)]
+    build: Option<i64>,
+}
+
+/// Downloads a Paper build and checks it against PaperMC's sha256.
+///
+/// A stopped server gets it right away, a running one on its next start
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/game/update",
+    tag = "instance_game",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "The build and whether it is installed already"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_game(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    request: Option<Json<GameUpdateRequest>>,
+) -> Result<Json<GameUpdateOutcome>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let build = request.and_then(|Json(request)| request.build);
+    let instance = minecraft_instance(&state, &uuid)?;
+    let old_build = instance.paper_build().await;
+    let outcome = instance.update_game(build).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "paper_build".to_string(),
+            },
+            audit_value(old_build, false),
+            audit_value(outcome.build, false),
+        )
+        .await;
+    Ok(Json(outcome))
+}
+
+pub fn get_instance_game_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/game/updates", get(get_game_updates))
+        .route("/instance/:uuid/game/update", post(update_game))
+        .with_state(state)
+}
@@ -0,0 +1,345 @@
+use std::io::{BufWriter, Write};
+
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{audit_value, AuditTarget},
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    implementations::minecraft::{
+        log_rules::{LogClassification, LogRule, LogRules},
+        logs::{
+            list_crash_reports, list_logs, open_log, tail_log, CrashReport, LogFile,
+            DEFAULT_TAIL_LINES,
+        },
+    },
+    instance_archive::ChannelWriter,
+    types::InstanceUuid,
+    AppState,
+};
+
+use super::util::minecraft_instance;
+
+/// Chunks of a log read ahead of a slow client
+const LOG_STREAM_CHUNKS: usize = 16;
+/// Most lines one log rule test classifies
+const MAX_LOG_PARSE_TEST_LINES: usize = 1000;
+
+async fn authorize(state: &AppState, token: &str, uuid: &InstanceUuid) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )
+}
+
+/// The server's logs, `latest.log` first and the rotated ones newest first
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/logs",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Name, size on disk, modification time and whether it's gzipped"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_logs(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<LogFile>>, Error> {
+    authorize(&state, &token, &uuid).await?;
+    let dir = minecraft_instance(&state, &uuid)?.path_to_logs();
+    tokio::task::spawn_blocking(move || list_logs(&dir))
+        .await
+        .context("Failed to spawn blocking task")?
+        .map(Json)
+}
+
+/// Streams the text of a log, decompressing a gzipped one on the way
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/logs/{name}",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path, description = "File name of the log, e.g. latest.log"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn read_log(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Response, Error> {
+    authorize(&state, &token, &uuid).await?;
+    let dir = minecraft_instance(&state, &uuid)?.path_to_logs();
+    let mut log = open_log(&dir, &name)?;
+    let (tx, rx) = mpsc::channel(LOG_STREAM_CHUNKS);
+    // a client that went away closes the receiver, which fails the next write
+    tokio::task::spawn_blocking(move || {
+        let mut writer = BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        if let Err(e) = std::io::copy(&mut log, &mut writer).and_then(|_| writer.flush()) {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        StreamBody::new(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct TailQuery {
+    lines: Option<usize>,
+}
+
+/// The last lines of a log, 500 by default and 10000 at most
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/logs/{name}/tail",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path, description = "File name of the log, e.g. latest.log"),
+        ("lines" = Option<usize>, Query, description = "How many lines to return"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_log_tail(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    Query(query): Query<TailQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<String>>, Error> {
+    authorize(&state, &token, &uuid).await?;
+    let dir = minecraft_instance(&state, &uuid)?.path_to_logs();
+    let lines = query.lines.unwrap_or(DEFAULT_TAIL_LINES);
+    tokio::task::spawn_blocking(move || tail_log(&dir, &name, lines))
+        .await
+        .context("Failed to spawn blocking task")?
+        .map(Json)
+}
+
+/// The crash reports newest first, each with what crashed taken from its header
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/crash-reports",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_crash_reports(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CrashReport>>, Error> {
+    authorize(&state, &token, &uuid).await?;
+    let dir = minecraft_instance(&state, &uuid)?.path_to_crash_reports();
+    tokio::task::spawn_blocking(move || list_crash_reports(&dir))
+        .await
+        .context("Failed to spawn blocking task")?
+        .map(Json)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LogRulesResponse {
+    /// False while the instance uses the defaults of its flavour
+    custom: bool,
+    /// Tried in order, the first match classifies a line
+    rules: Vec<LogRule>,
+}
+
+/// The rules telling player joins, leaves and chat apart in the console
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/logparse/rules",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = LogRulesResponse),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_log_rules(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<LogRulesResponse>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let (custom, rules) = minecraft_instance(&state, &uuid)?.log_rules().await;
+    Ok(Json(LogRulesResponse { custom, rules }))
+}
+
+/// Replaces the instance's log rules, null goes back to the defaults of its flavour. The server
+/// picks them up on its next start
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/logparse/rules",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = Vec<LogRule>,
+    responses(
+        (status = 200, description = "The rules now in effect", body = LogRulesResponse),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_log_rules(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(rules): Json<Option<Vec<LogRule>>>,
+) -> Result<Json<LogRulesResponse>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let (was_custom, old_rules) = instance.log_rules().await;
+    instance.set_log_rules(rules.clone()).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::Property {
+                name: "log_rules".to_string(),
+            },
+            audit_value(was_custom.then_some(old_rules), false),
+            audit_value(&rules, false),
+        )
+        .await;
+    let (custom, rules) = instance.log_rules().await;
+    Ok(Json(LogRulesResponse { custom, rules }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LogParseTestRequest {
+    lines: Vec<String>,
+    /// Rules to try before saving them, the instance's own if left out
+    rules: Option<Vec<LogRule>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LogParseTestResult {
+    line: String,
+    /// Null if no rule matched
+    classification: Option<LogClassification>,
+}
+
+/// Classifies console lines without touching the server, to check rules before saving them
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/logparse/test",
+    tag = "instance_logs",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = LogParseTestRequest,
+    responses(
+        (status = 200, description = "Each line with what it was taken as", body = [LogParseTestResult]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn test_log_rules(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<LogParseTestRequest>,
+) -> Result<Json<Vec<LogParseTestResult>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if request.lines.len() > MAX_LOG_PARSE_TEST_LINES {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "At most {} lines can be tested at once",
+                MAX_LOG_PARSE_TEST_LINES
+            ),
+        });
+    }
+    let rules = match request.rules {
+        Some(rules) => rules,
+        None => minecraft_instance(&state, &uuid)?.log_rules().await.1,
+    };
+    let lines = request.lines;
+    // matching is bounded per line but still blocking work
+    tokio::task::spawn_blocking(move || -> Result<Vec<LogParseTestResult>, Error> {
+        let rules = LogRules::compile(&rules)?;
+        Ok(lines
+            .into_iter()
+            .map(|line| LogParseTestResult {
+                classification: rules.classify(&line),
+                line,
+            })
+            .collect())
+    })
+    .await
+    .context("Failed to spawn blocking task")?
+    .map(Json)
+}
+
+pub fn get_instance_logs_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/logs", get(get_logs))
+        .route("/instance/:uuid/logs/:name", get(read_log))
+        .route("/instance/:uuid/logs/:name/tail", get(get_log_tail))
+        .route("/instance/:uuid/crash-reports", get(get_crash_reports))
+        .route(
+            "/instance/:uuid/logparse/rules",
+            get(get_log_rules).put(set_log_rules),
+        )
+        .route("/instance/:uuid/logparse/test", post(test_log_rules))
+        .with_state(state)
+}
@@ -8,6 +8,7 @@ use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use ts_rs::TS;
 
 use crate::traits::t_configurable::manifest::SettingManifest;
@@ -15,12 +16,15 @@ use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
     events::CausedBy,
-    macro_executor::MacroPID,
+    macro_executor::{to_macro_args, MacroPID},
+    prelude::GameInstance,
     traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
     types::InstanceUuid,
     AppState,
 };
 
+use super::util::game_instance;
+
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
 pub struct GetConfigResponse {
@@ -29,6 +33,19 @@ pub struct GetConfigResponse {
     pub error: Option<ErrorKind>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/task/list",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_task_list(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -39,14 +56,24 @@ pub async fn get_instance_task_list(
         &UserAction::AccessMacro(Some(uuid.clone())),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     let tasks = instance.get_task_list().await?;
     Ok(Json(tasks))
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/macro/list",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_macro_list(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -57,14 +84,24 @@ pub async fn get_instance_macro_list(
         &UserAction::AccessMacro(Some(uuid.clone())),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     let macros = instance.get_macro_list().await?;
     Ok(Json(macros))
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/macro/history",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_history_list(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -75,14 +112,26 @@ pub async fn get_instance_history_list(
         &UserAction::AccessMacro(Some(uuid.clone())),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     let history = instance.get_history_list().await?;
     Ok(Json(history))
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/macro/run/{macro_name}",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("macro_name" = String, Path),
+    ),
+    request_body = [String],
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn run_macro(
     Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -94,31 +143,36 @@ pub async fn run_macro(
         &UserAction::AccessMacro(Some(uuid.clone())),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
+    start_macro(
+        &instance,
+        &macro_name,
+        args,
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    )
+    .await?;
+    Ok(Json(()))
+}
 
-    if let Ok(valid_config) = instance.validate_local_config(&macro_name, None).await {
+/// Runs a macro with its locally stored config
+async fn start_macro(
+    instance: &GameInstance,
+    macro_name: &str,
+    args: Vec<String>,
+    caused_by: CausedBy,
+) -> Result<TaskEntry, Error> {
+    if let Ok(valid_config) = instance.validate_local_config(macro_name, None).await {
         let valid_config = if valid_config.is_empty() {
             None
         } else {
             Some(valid_config)
         };
-
         instance
-            .run_macro(
-                &macro_name,
-                args,
-                valid_config,
-                CausedBy::User {
-                    user_id: requester.uid,
-                    user_name: requester.username,
-                },
-            )
-            .await?;
-
-        Ok(Json(()))
+            .run_macro(macro_name, args, valid_config, caused_by)
+            .await
     } else {
         Err(Error {
             kind: ErrorKind::Internal,
@@ -127,6 +181,70 @@ pub async fn run_macro(
     }
 }
 
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct MacroRunRequest {
+    pub name: String,
+    #[serde(default)]
+    #[ts(type = "Record<string, unknown>")]
+    pub args: Map<String, Value>,
+}
+
+/// Starts a macro, or queues it behind a running one if the macro asks for that.
+/// The returned pid identifies the run for aborting it and in the history
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/macro/run",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn start_macro_run(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<MacroRunRequest>,
+) -> Result<Json<TaskEntry>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let args = to_macro_args(request.args)?;
+    let instance = game_instance(&state, &uuid)?;
+    start_macro(
+        &instance,
+        &request.name,
+        args,
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    )
+    .await
+    .map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/macro/kill/{pid}",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("pid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn kill_macro(
     Path((uuid, pid)): Path<(InstanceUuid, MacroPID)>,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -137,14 +255,25 @@ pub async fn kill_macro(
         &UserAction::AccessMacro(Some(uuid.clone())),
         state.global_settings.lock().await.safe_mode(),
     )?;
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     instance.kill_macro(pid).await?;
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/macro/config/get/{macro_name}",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("macro_name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_macro_configs(
     Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -154,10 +283,7 @@ pub async fn get_macro_configs(
     let safe_mode = state.global_settings.lock().await.safe_mode();
     requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
 
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
 
     let mut config = instance.get_macro_config(&macro_name).await?;
 
@@ -195,6 +321,20 @@ pub async fn get_macro_configs(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/macro/config/store/{macro_name}",
+    tag = "instance_macro",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("macro_name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn store_config_to_local(
     Path((uuid, macro_name)): Path<(InstanceUuid, String)>,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -206,10 +346,7 @@ pub async fn store_config_to_local(
 
     requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())), safe_mode)?;
 
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
 
     instance
         .store_macro_config_to_local(&macro_name, &config_to_store)
@@ -222,6 +359,13 @@ pub fn get_instance_macro_routes(state: AppState) -> Router {
         .route("/instance/:uuid/macro/run/:macro_name", put(run_macro))
         .route("/instance/:uuid/macro/kill/:pid", put(kill_macro))
         .route("/instance/:uuid/macro/list", get(get_instance_macro_list))
+        .route("/instance/:uuid/macro", get(get_instance_macro_list))
+        .route("/instance/:uuid/macro/run", post(start_macro_run))
+        .route("/instance/:uuid/macro/abort/:pid", post(kill_macro))
+        .route(
+            "/instance/:uuid/macro/history",
+            get(get_instance_history_list),
+        )
         .route(
             "/instance/:uuid/macro/config/get/:macro_name",
             get(get_macro_configs),
@@ -0,0 +1,217 @@
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    macro_triggers::{MacroTrigger, MacroTriggerConfig, TriggerMatch},
+    types::{InstanceUuid, Snowflake},
+    AppState,
+};
+
+fn ensure_instance_exists(state: &AppState, uuid: &InstanceUuid) -> Result<(), Error> {
+    if state.instances.contains_key(uuid) {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/macro/triggers",
+    tag = "instance_macro_triggers",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_triggers(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<MacroTrigger>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    ensure_instance_exists(&state, &uuid)?;
+    Ok(Json(state.macro_triggers.list(&uuid).await))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/macro/triggers",
+    tag = "instance_macro_triggers",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<MacroTriggerConfig>,
+) -> Result<Json<MacroTrigger>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    ensure_instance_exists(&state, &uuid)?;
+    state.macro_triggers.create(&uuid, config).await.map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/macro/trigger/{trigger_id}",
+    tag = "instance_macro_triggers",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("trigger_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, trigger_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MacroTrigger>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state.macro_triggers.get(&uuid, &trigger_id).await.map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/macro/trigger/{trigger_id}",
+    tag = "instance_macro_triggers",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("trigger_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, trigger_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<MacroTriggerConfig>,
+) -> Result<Json<MacroTrigger>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state
+        .macro_triggers
+        .update(&uuid, &trigger_id, config)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/macro/trigger/{trigger_id}",
+    tag = "instance_macro_triggers",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("trigger_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, trigger_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state
+        .macro_triggers
+        .delete(&uuid, &trigger_id)
+        .await
+        .map(|_| Json(()))
+}
+
+/// Which of the instance's recent events a trigger would have fired on, nothing is run
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/macro/triggers/dry_run",
+    tag = "instance_macro_triggers",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn dry_run_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<MacroTriggerConfig>,
+) -> Result<Json<Vec<TriggerMatch>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessMacro(Some(uuid.clone())),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    ensure_instance_exists(&state, &uuid)?;
+    state.macro_triggers.dry_run(&uuid, &config).await.map(Json)
+}
+
+pub fn get_instance_macro_triggers_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/macro/triggers",
+            get(get_triggers).post(create_trigger),
+        )
+        .route(
+            "/instance/:uuid/macro/triggers/dry_run",
+            post(dry_run_trigger),
+        )
+        .route(
+            "/instance/:uuid/macro/trigger/:trigger_id",
+            get(get_trigger).put(update_trigger).delete(delete_trigger),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,289 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+
+use crate::{
+    auth::user::UserAction,
+    error::Error,
+    events::{new_fs_event, CausedBy, FSOperation, FSTarget},
+    implementations::minecraft::mods::InstalledMod,
+    types::InstanceUuid,
+    AppState,
+};
+
+use super::util::minecraft_instance;
+
+#[cfg(feature = "mod-providers")]
+use crate::{
+    implementations::minecraft::mods::{ModUpdate, ModrinthSearchHit},
+    traits::t_configurable::TConfigurable,
+};
+#[cfg(feature = "mod-providers")]
+use axum::{extract::Query, routing::post};
+#[cfg(feature = "mod-providers")]
+use serde::Deserialize;
+
+/// Most results a single Modrinth search returns
+#[cfg(feature = "mod-providers")]
+const MAX_SEARCH_RESULTS: u32 = 50;
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/mods",
+    tag = "instance_mods",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_mods(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<InstalledMod>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .list_mods()
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/mod/{file_name}/enabled",
+    tag = "instance_mods",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("file_name" = String, Path),
+    ),
+    request_body = bool,
+    responses(
+        (status = 200, description = "Success", body = String),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_mod_enabled(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, file_name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(enabled): Json<bool>,
+) -> Result<Json<String>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let new_name = instance.set_mod_enabled(&file_name, enabled).await?;
+    if new_name != file_name {
+        let dir = instance.mods_dir().await?;
+        state.event_broadcaster.send(new_fs_event(
+            FSOperation::Move {
+                source: dir.join(&file_name),
+            },
+            FSTarget::File(dir.join(&new_name)),
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+        ));
+    }
+    Ok(Json(new_name))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/mod/{file_name}",
+    tag = "instance_mods",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("file_name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn remove_mod(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, file_name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let path = minecraft_instance(&state, &uuid)?
+        .remove_mod(&file_name)
+        .await?;
+    state.disk_usage.invalidate(&uuid);
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Delete,
+        FSTarget::File(path),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(()))
+}
+
+#[cfg(feature = "mod-providers")]
+#[derive(Deserialize)]
+pub struct ModSearchQuery {
+    query: String,
+    #[serde(default = "default_search_limit")]
+    limit: u32,
+}
+
+#[cfg(feature = "mod-providers")]
+fn default_search_limit() -> u32 {
+    20
+}
+
+/// Modrinth projects for the instance's loader and game version
+#[cfg(feature = "mod-providers")]
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/mods/search",
+    tag = "instance_mods",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn search_mods(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<ModSearchQuery>,
+) -> Result<Json<Vec<ModrinthSearchHit>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .search_mods(&query.query, query.limit.clamp(1, MAX_SEARCH_RESULTS))
+        .await
+        .map(Json)
+}
+
+#[cfg(feature = "mod-providers")]
+#[derive(Deserialize)]
+pub struct ModInstallRequest {
+    project_id: String,
+    /// Installs the newest version compatible with the instance when unset
+    #[serde(default)]
+    version_id: Option<String>,
+}
+
+/// Downloads a Modrinth project into the mods or plugins directory
+#[cfg(feature = "mod-providers")]
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/mods/install",
+    tag = "instance_mods",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn install_mod(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<ModInstallRequest>,
+) -> Result<Json<InstalledMod>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let download = instance
+        .resolve_mod(&request.project_id, request.version_id.as_deref())
+        .await?;
+    super::instance_fs::ensure_space(&state, &uuid, &instance.path().await, download.size).await?;
+    let installed = instance.install_mod(&download).await?;
+    state.disk_usage.add(&uuid, installed.size);
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Write,
+        FSTarget::File(instance.mods_dir().await?.join(&installed.file_name)),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(Json(installed))
+}
+
+/// Installed jars Modrinth knows, with the newest version compatible with the instance
+#[cfg(feature = "mod-providers")]
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/mods/updates",
+    tag = "instance_mods",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_mod_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ModUpdate>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .check_mod_updates()
+        .await
+        .map(Json)
+}
+
+pub fn get_instance_mods_routes(state: AppState) -> Router {
+    let router = Router::new()
+        .route("/instance/:uuid/mods", get(get_mods))
+        .route("/instance/:uuid/mod/:file_name", delete(remove_mod))
+        .route(
+            "/instance/:uuid/mod/:file_name/enabled",
+            put(set_mod_enabled),
+        );
+    // without the feature these are answered by the feature stubs
+    #[cfg(feature = "mod-providers")]
+    let router = router
+        .route("/instance/:uuid/mods/search", get(search_mods))
+        .route("/instance/:uuid/mods/install", post(install_mod))
+        .route("/instance/:uuid/mods/updates", get(get_mod_updates));
+    router.with_state(state)
+}
@@ -1,80 +1,322 @@
-use std::collections::HashSet;
-
-use axum::{extract::Path, routing::get, Json, Router};
-use color_eyre::eyre::eyre;
+use axum::{
+    extract::{Path, Query},
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
 
 use crate::{
-    error::{Error, ErrorKind},
-    traits::t_player::{Player, TPlayerManagement},
+    auth::user::UserAction,
+    error::Error,
+    events::CausedBy,
+    traits::t_player::{
+        OnlinePlayer, PlayerListChange, PlayerListEntry, PlayerListKind, PlayerSession,
+        TPlayerManagement,
+    },
     types::InstanceUuid,
     AppState,
 };
 
+use super::util::game_instance;
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/players/count",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = u32),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn get_player_count(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
 ) -> Result<Json<u32>, Error> {
-    state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
+    game_instance(&state, &uuid)?
         .get_player_count()
         .await
         .map(Json)
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/players/max",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success", body = u32),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn get_max_player_count(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
 ) -> Result<Json<u32>, Error> {
-    state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
+    game_instance(&state, &uuid)?
         .get_max_player_count()
         .await
         .map(Json)
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/players/max",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = u32,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn set_max_player_count(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
     Json(count): Json<u32>,
 ) -> Result<Json<()>, Error> {
-    state
-        .instances
-        .get_mut(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
+    game_instance(&state, &uuid)?
         .set_max_player_count(count)
         .await
         .map(Json)
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/players",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn get_player_list(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
-) -> Result<Json<HashSet<Player>>, Error> {
-    state
-        .instances
-        .get_mut(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .get_player_list()
+) -> Result<Json<Vec<OnlinePlayer>>, Error> {
+    game_instance(&state, &uuid)?
+        .get_online_players()
         .await
         .map(Json)
 }
 
+#[derive(Deserialize)]
+pub struct PlayerHistoryQuery {
+    /// Unix timestamp in seconds, sessions that ended before it are left out
+    since: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/players/history",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_player_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<PlayerHistoryQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PlayerSession>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    instance.get_player_sessions(query.since).await.map(Json)
+}
+
+async fn get_player_list_entries(
+    state: AppState,
+    uuid: InstanceUuid,
+    token: String,
+    list: PlayerListKind,
+) -> Result<Json<Vec<PlayerListEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    instance.get_player_list_entries(list).await.map(Json)
+}
+
+async fn change_player_list(
+    state: AppState,
+    uuid: InstanceUuid,
+    name: String,
+    token: String,
+    list: PlayerListKind,
+    add: bool,
+) -> Result<Json<PlayerListChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let instance = game_instance(&state, &uuid)?;
+    let change = if add {
+        instance.add_to_player_list(list, &name, caused_by).await
+    } else {
+        instance
+            .remove_from_player_list(list, &name, caused_by)
+            .await
+    };
+    change.map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/players/whitelist",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_whitelist(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PlayerListEntry>>, Error> {
+    get_player_list_entries(state, uuid, token, PlayerListKind::Whitelist).await
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/players/whitelist/{name}",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_to_whitelist(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PlayerListChange>, Error> {
+    change_player_list(state, uuid, name, token, PlayerListKind::Whitelist, true).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/players/whitelist/{name}",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn remove_from_whitelist(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PlayerListChange>, Error> {
+    change_player_list(state, uuid, name, token, PlayerListKind::Whitelist, false).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/players/ops",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_ops(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PlayerListEntry>>, Error> {
+    get_player_list_entries(state, uuid, token, PlayerListKind::Ops).await
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/players/ops/{name}",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_op(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PlayerListChange>, Error> {
+    change_player_list(state, uuid, name, token, PlayerListKind::Ops, true).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/players/ops/{name}",
+    tag = "instance_players",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn remove_op(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PlayerListChange>, Error> {
+    change_player_list(state, uuid, name, token, PlayerListKind::Ops, false).await
+}
+
 pub fn get_instance_players_routes(state: AppState) -> Router {
     Router::new()
         .route("/instance/:uuid/players/count", get(get_player_count))
@@ -83,5 +325,16 @@ pub fn get_instance_players_routes(state: AppState) -> Router {
             get(get_max_player_count).put(set_max_player_count),
         )
         .route("/instance/:uuid/players", get(get_player_list))
+        .route("/instance/:uuid/players/history", get(get_player_history))
+        .route("/instance/:uuid/players/whitelist", get(get_whitelist))
+        .route(
+            "/instance/:uuid/players/whitelist/:name",
+            put(add_to_whitelist).delete(remove_from_whitelist),
+        )
+        .route("/instance/:uuid/players/ops", get(get_ops))
+        .route(
+            "/instance/:uuid/players/ops/:name",
+            put(add_op).delete(remove_op),
+        )
         .with_state(state)
 }
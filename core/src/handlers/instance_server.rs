@@ -1,5 +1,7 @@
+use std::time::Duration;
+
 use axum::{
-    extract::Path,
+    extract::{Path, Query},
     routing::{get, post, put},
     Router,
 };
@@ -8,23 +10,63 @@ use axum::Json;
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::eyre;
+use serde::Deserialize;
 use serde_json::{json, Value};
+use tracing::warn;
 
 use crate::{
+    audit::{audit_value, AuditTarget},
     auth::user::UserAction,
+    command_queue::{CommandQueueConfig, CommandQueueStatus},
+    console_capture::{CommandOutput, DEFAULT_CAPTURE_TIMEOUT, MAX_CAPTURE_TIMEOUT},
+    console_history::ConsoleHistoryPage,
     error::{Error, ErrorKind},
     events::CausedBy,
+    implementations::minecraft::DEFAULT_RCON_PORT,
     types::InstanceUuid,
 };
 
+use super::instance_diagnostics::diagnose_start_failure;
+use super::util::{check_console_command, game_instance};
+
 use crate::{
-    traits::{t_configurable::TConfigurable, t_server::TServer},
+    traits::{
+        t_configurable::TConfigurable,
+        t_server::{RconStatus, State, TServer},
+    },
     AppState,
 };
 
+const DEFAULT_CONSOLE_HISTORY_PAGE_SIZE: usize = 200;
+
+#[derive(Deserialize)]
+pub struct StartQuery {
+    /// Start even if it goes over the capacity limits of the daemon settings. Admins only, the
+    /// override is audited
+    #[serde(default)]
+    override_capacity: bool,
+}
+
+/// Refused with `CAPACITY_EXCEEDED` if the daemon is at its limit of running instances or
+/// reserved heap, `GET /system/capacity` tells beforehand
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/start",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("override_capacity" = Option<bool>, Query, description = "Admins only, start over the capacity limits"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn start_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
+    Query(StartQuery { override_capacity }): Query<StartQuery>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
@@ -32,6 +74,14 @@ pub async fn start_instance(
         &UserAction::StartInstance(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
+    if override_capacity
+        && !requester.can_perform_action(&UserAction::AdministerInstance(Some(uuid.clone())))
+    {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only admins can start an instance over the capacity limits"),
+        });
+    }
     if uuid.to_string().starts_with("DOCKER-") {
         let docker_bridge = state.docker_bridge.clone();
         docker_bridge.start_container(&uuid).await?;
@@ -41,27 +91,68 @@ pub async fn start_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
     let port = instance.port().await;
+    // held until the start returns, so a concurrent start can't take the same room
+    let ticket = state.admission.admit(&instance, override_capacity).await?;
+    if let Some(overridden) = &ticket.overridden {
+        warn!(
+            "{} started {} over the capacity limits: {}",
+            requester.username, uuid, overridden.reason
+        );
+        state
+            .audit_log
+            .record(
+                &uuid,
+                caused_by.clone(),
+                AuditTarget::CapacityOverride,
+                None,
+                audit_value(overridden, false),
+            )
+            .await;
+    }
 
-    // check if port is already in use
-    if state.port_manager.lock().await.port_status(port).is_in_use {
-        return Err(Error {
+    // check if port is already in use, a running instance holds its own port
+    let result = if instance.state().await == State::Stopped
+        && state.port_manager.lock().await.port_status(port).is_in_use
+    {
+        Err(Error {
             kind: ErrorKind::Internal,
             source: eyre!("Port {} is in use", port),
-        });
+        })
+    } else {
+        instance.start(caused_by, false).await
+    };
+    drop(ticket);
+    if let Err(e) = result {
+        return Err(diagnose_start_failure(&state, &uuid, &instance, e).await);
     }
-
-    instance.start(caused_by, false).await?;
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+pub struct StopQuery {
+    /// Warn players for this many seconds before stopping
+    graceful_seconds: Option<u32>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/stop",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn stop_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
+    Query(StopQuery { graceful_seconds }): Query<StopQuery>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
@@ -78,18 +169,27 @@ pub async fn stop_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .stop(caused_by, false)
-        .await?;
+    let instance = game_instance(&state, &uuid)?;
+    match graceful_seconds {
+        Some(seconds) => instance.stop_gracefully(caused_by, seconds).await?,
+        None => instance.stop(caused_by, false).await?,
+    }
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/restart",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn restart_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -110,25 +210,173 @@ pub async fn restart_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
-        kind: ErrorKind::NotFound,
-        source: eyre!("Instance not found"),
-    })?;
+    let instance = game_instance(&state, &uuid)?;
 
     instance.restart(caused_by, false).await?;
     Ok(Json(()))
 }
 
+/// Freezes the server's processes, they keep their memory but use no CPU until resumed. Unix only
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/suspend",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn suspend_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::StopInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    game_instance(&state, &uuid)?.suspend(caused_by).await?;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/resume",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn resume_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::StartInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    game_instance(&state, &uuid)?.resume(caused_by).await?;
+    Ok(Json(()))
+}
+
+/// Minutes without players before the server is suspended, `null` if it never is
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/auto_suspend",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_auto_suspend(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<u32>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?.auto_suspend().await.map(Json)
+}
+
+/// Sets the minutes without players before the server is suspended, `null` turns it off. A
+/// server suspended this way is resumed when a client connects to it, on Linux
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/auto_suspend",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body(content = u32, description = "Minutes, or null"),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_auto_suspend(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(minutes): Json<Option<u32>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .set_auto_suspend(minutes)
+        .await
+        .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct KillRequest {
+    /// Must be true, killing skips saving the world
+    confirm: bool,
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/kill",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn kill_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
     AuthBearer(token): AuthBearer,
+    body: Option<Json<KillRequest>>,
 ) -> Result<Json<Value>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(
         &UserAction::StopInstance(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
+    if !body.map(|Json(body)| body.confirm).unwrap_or(false) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Killing an instance may lose unsaved world data, send {{\"confirm\": true}} to proceed"
+            ),
+        });
+    }
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
@@ -138,18 +386,24 @@ pub async fn kill_instance(
         docker_bridge.kill_container(&uuid).await?;
         return Ok(Json(json!("ok")));
     }
-    state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .kill(caused_by)
-        .await?;
+    game_instance(&state, &uuid)?.kill(caused_by).await?;
     Ok(Json(json!("ok")))
 }
 
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/console",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn send_command(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -161,22 +415,291 @@ pub async fn send_command(
         &UserAction::AccessConsole(uuid.clone()),
         state.global_settings.lock().await.safe_mode(),
     )?;
+    check_console_command(&state, &requester, &uuid, &command).await?;
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
+    game_instance(&state, &uuid)?
         .send_command(&command, caused_by)
         .await
         .map(|_| Json(()))
 }
 
+#[derive(Deserialize)]
+pub struct CapturedCommand {
+    command: String,
+    /// How long to wait for output, 2 seconds by default and at most 10
+    timeout_ms: Option<u64>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/console/command",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn send_command_with_output(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(CapturedCommand {
+        command,
+        timeout_ms,
+    }): Json<CapturedCommand>,
+) -> Result<Json<CommandOutput>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessConsole(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let timeout = timeout_ms.map_or(DEFAULT_CAPTURE_TIMEOUT, Duration::from_millis);
+    if timeout > MAX_CAPTURE_TIMEOUT {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "timeout_ms can be at most {}",
+                MAX_CAPTURE_TIMEOUT.as_millis()
+            ),
+        });
+    }
+    check_console_command(&state, &requester, &uuid, &command).await?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let instance = game_instance(&state, &uuid)?;
+    instance
+        .send_command_with_output(&command, caused_by, timeout)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/rcon",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_rcon_status(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<RconStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?.rcon_status().await.map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct SetRcon {
+    enabled: bool,
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/rcon",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_rcon(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(SetRcon { enabled }): Json<SetRcon>,
+) -> Result<Json<RconStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let status = instance.rcon_status().await?;
+    if status.enabled == enabled {
+        return Ok(Json(status));
+    }
+    if enabled {
+        let port = state
+            .port_manager
+            .lock()
+            .await
+            .allocate(DEFAULT_RCON_PORT, &uuid)?;
+        let status = instance.enable_rcon(port).await;
+        if status.is_err() {
+            state.port_manager.lock().await.deallocate(port);
+        }
+        status.map(Json)
+    } else {
+        if let Some(port) = instance.disable_rcon().await? {
+            state.port_manager.lock().await.deallocate(port);
+        }
+        instance.rcon_status().await.map(Json)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/console/queue",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_command_queue_status(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<CommandQueueStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadConsole(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .command_queue_status()
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/console/queue",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_command_queue_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<CommandQueueConfig>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .set_command_queue_config(config)
+        .await
+        .map(|_| Json(()))
+}
+
+#[derive(Deserialize)]
+pub struct ConsoleHistoryQuery {
+    offset: Option<u64>,
+    count: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/console/history",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_console_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<ConsoleHistoryQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ConsoleHistoryPage>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadConsole(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .console_history(
+            query.offset,
+            query.count.unwrap_or(DEFAULT_CONSOLE_HISTORY_PAGE_SIZE),
+        )
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/console/history",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn clear_console_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessConsole(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?
+        .clear_console_history()
+        .await
+        .map(|_| Json(()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/state",
+    tag = "instance_server",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_instance_state(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -194,26 +717,41 @@ pub async fn get_instance_state(
         let state = docker_bridge.get_container_state(&uuid).await?;
         return Ok(Json(json!(state)));
     }
-    Ok(Json(json!(
-        state
-            .instances
-            .get(&uuid)
-            .ok_or_else(|| Error {
-                kind: ErrorKind::NotFound,
-                source: eyre!("Instance not found"),
-            })?
-            .state()
-            .await
-    )))
+    Ok(Json(json!(game_instance(&state, &uuid)?.state().await)))
 }
 
 pub fn get_instance_server_routes(state: AppState) -> Router {
     Router::new()
         .route("/instance/:uuid/start", put(start_instance))
-        .route("/instance/:uuid/stop", put(stop_instance))
+        .route(
+            "/instance/:uuid/stop",
+            put(stop_instance).post(stop_instance),
+        )
         .route("/instance/:uuid/restart", put(restart_instance))
-        .route("/instance/:uuid/kill", put(kill_instance))
+        .route(
+            "/instance/:uuid/kill",
+            put(kill_instance).post(kill_instance),
+        )
         .route("/instance/:uuid/console", post(send_command))
+        .route(
+            "/instance/:uuid/console/command",
+            post(send_command_with_output),
+        )
+        .route(
+            "/instance/:uuid/console/queue",
+            get(get_command_queue_status).put(set_command_queue_config),
+        )
+        .route(
+            "/instance/:uuid/console/history",
+            get(get_console_history).delete(clear_console_history),
+        )
+        .route("/instance/:uuid/rcon", get(get_rcon_status).put(set_rcon))
         .route("/instance/:uuid/state", get(get_instance_state))
+        .route("/instance/:uuid/suspend", post(suspend_instance))
+        .route("/instance/:uuid/resume", post(resume_instance))
+        .route(
+            "/instance/:uuid/auto_suspend",
+            get(get_auto_suspend).put(set_auto_suspend),
+        )
         .with_state(state)
 }
@@ -1,12 +1,19 @@
 use crate::error::Error;
 use crate::error::ErrorKind;
+use crate::implementations::command;
 use crate::implementations::generic;
 use crate::implementations::minecraft;
+use crate::implementations::minecraft::versions::{
+    get_fabric_setup_versions, get_forge_setup_versions, get_loader_versions,
+    get_paper_setup_versions, get_quilt_setup_versions, get_vanilla_setup_versions,
+    FlavourVersions, LoaderVersion,
+};
 use crate::minecraft::FlavourKind;
 use crate::traits::t_configurable::manifest::SetupManifest;
 use crate::traits::t_configurable::GameType;
 use crate::AppState;
 use axum::extract::Path;
+use axum::extract::Query;
 use axum::routing::get;
 use axum::routing::put;
 use axum::Json;
@@ -15,16 +22,20 @@ use color_eyre::eyre::eyre;
 use serde::Deserialize;
 use serde::Serialize;
 use ts_rs::TS;
+use utoipa::ToSchema;
 
 #[allow(clippy::enum_variant_names)]
-#[derive(Serialize, Deserialize, TS, Clone, Copy)]
+#[derive(Serialize, Deserialize, TS, Clone, Copy, ToSchema)]
 #[ts(export)]
 pub enum HandlerGameType {
     MinecraftJavaVanilla,
     MinecraftFabric,
+    MinecraftQuilt,
     MinecraftForge,
     MinecraftPaper,
     MinecraftBedrock,
+    /// Any server, started with a user supplied command
+    Command,
 }
 
 impl From<HandlerGameType> for GameType {
@@ -32,9 +43,11 @@ impl From<HandlerGameType> for GameType {
         match value {
             HandlerGameType::MinecraftJavaVanilla => Self::MinecraftJava,
             HandlerGameType::MinecraftFabric => Self::MinecraftJava,
+            HandlerGameType::MinecraftQuilt => Self::MinecraftJava,
             HandlerGameType::MinecraftForge => Self::MinecraftJava,
             HandlerGameType::MinecraftPaper => Self::MinecraftJava,
             HandlerGameType::MinecraftBedrock => Self::MinecraftBedrock,
+            HandlerGameType::Command => Self::Command,
         }
     }
 }
@@ -46,6 +59,7 @@ impl TryFrom<HandlerGameType> for FlavourKind {
         Ok(match value {
             HandlerGameType::MinecraftJavaVanilla => Self::Vanilla,
             HandlerGameType::MinecraftFabric => Self::Fabric,
+            HandlerGameType::MinecraftQuilt => Self::Quilt,
             HandlerGameType::MinecraftForge => Self::Forge,
             HandlerGameType::MinecraftPaper => Self::Paper,
             HandlerGameType::MinecraftBedrock => {
@@ -54,32 +68,135 @@ impl TryFrom<HandlerGameType> for FlavourKind {
                     source: eyre!("Programmer error: tried to convert HandlerGameType::MinecraftBedrock to FlavourKind"),
                 })
             }
+            HandlerGameType::Command => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Command instances have no Minecraft flavour"),
+                })
+            }
         })
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/games",
+    tag = "instance_setup_configs",
+    responses(
+        (status = 200, description = "Success", body = [HandlerGameType]),
+    ),
+)]
 pub async fn get_available_games() -> Json<Vec<HandlerGameType>> {
     Json(vec![
         HandlerGameType::MinecraftJavaVanilla,
         HandlerGameType::MinecraftFabric,
+        HandlerGameType::MinecraftQuilt,
         HandlerGameType::MinecraftForge,
         HandlerGameType::MinecraftPaper,
+        HandlerGameType::Command,
     ])
 }
 
+#[utoipa::path(
+    get,
+    path = "/setup_manifest/{game_type}",
+    tag = "instance_setup_configs",
+    params(
+        ("game_type" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success", body = SetupManifest),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn get_setup_manifest(
     Path(game_type): Path<HandlerGameType>,
 ) -> Result<Json<SetupManifest>, Error> {
+    if let HandlerGameType::Command = game_type {
+        return Ok(Json(command::CommandInstance::setup_manifest()));
+    }
     minecraft::MinecraftInstance::setup_manifest(&game_type.try_into()?)
         .await
         .map(Json)
 }
 
+/// Versions the flavour can be set up with, served from a cache of the upstream version lists
+#[utoipa::path(
+    get,
+    path = "/instance_setup/{game_type}/versions",
+    tag = "instance_setup_configs",
+    params(
+        ("game_type" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn get_setup_versions(
+    Path(game_type): Path<HandlerGameType>,
+) -> Result<Json<FlavourVersions>, Error> {
+    match FlavourKind::try_from(game_type)? {
+        FlavourKind::Vanilla => get_vanilla_setup_versions().await,
+        FlavourKind::Fabric => get_fabric_setup_versions().await,
+        FlavourKind::Quilt => get_quilt_setup_versions().await,
+        FlavourKind::Paper => get_paper_setup_versions().await,
+        FlavourKind::Forge => get_forge_setup_versions().await,
+        FlavourKind::Spigot => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Spigot servers can't be set up yet"),
+        }),
+    }
+    .map(Json)
+}
+
 #[derive(Deserialize)]
+pub struct LoaderVersionsQuery {
+    /// Only list the loaders that can run this version of minecraft
+    minecraft_version: Option<String>,
+}
+
+/// Loader versions of a Fabric or Quilt setup, newest first
+#[utoipa::path(
+    get,
+    path = "/instance_setup/{game_type}/loader_versions",
+    tag = "instance_setup_configs",
+    params(
+        ("game_type" = String, Path),
+        ("minecraft_version" = Option<String>, Query),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn get_setup_loader_versions(
+    Path(game_type): Path<HandlerGameType>,
+    Query(query): Query<LoaderVersionsQuery>,
+) -> Result<Json<Vec<LoaderVersion>>, Error> {
+    get_loader_versions(
+        FlavourKind::try_from(game_type)?,
+        query.minecraft_version.as_deref(),
+    )
+    .await
+    .map(Json)
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct GenericSetupManifestBody {
     pub url: String,
 }
 
+#[utoipa::path(
+    put,
+    path = "/generic_setup_manifest",
+    tag = "instance_setup_configs",
+    request_body = GenericSetupManifestBody,
+    responses(
+        (status = 200, description = "Success", body = SetupManifest),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn get_generic_setup_manifest(
     axum::extract::State(state): axum::extract::State<AppState>,
     Json(body): Json<GenericSetupManifestBody>,
@@ -97,6 +214,14 @@ pub fn get_instance_setup_config_routes(appstate: AppState) -> Router {
     Router::new()
         .route("/games", get(get_available_games))
         .route("/setup_manifest/:game_type", get(get_setup_manifest))
+        .route(
+            "/instance_setup/:game_type/versions",
+            get(get_setup_versions),
+        )
+        .route(
+            "/instance_setup/:game_type/loader_versions",
+            get(get_setup_loader_versions),
+        )
         .route("/generic_setup_manifest", put(get_generic_setup_manifest))
         .with_state(appstate)
 }
@@ -0,0 +1,196 @@
+use axum::{extract::Path, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::{User, UserAction},
+    error::{Error, ErrorKind},
+    scheduler::{ScheduledTask, ScheduledTaskConfig},
+    types::{InstanceUuid, Snowflake},
+    AppState,
+};
+
+/// Saving a task takes its settings, and running it takes whatever the task does
+fn check_task_access(
+    requester: &User,
+    uuid: &InstanceUuid,
+    config: &ScheduledTaskConfig,
+    safe_mode: bool,
+) -> Result<(), Error> {
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()), safe_mode)?;
+    config.action.check_allowed(requester, uuid, safe_mode)
+}
+
+fn ensure_instance_exists(state: &AppState, uuid: &InstanceUuid) -> Result<(), Error> {
+    if state.instances.contains_key(uuid) {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/tasks",
+    tag = "instance_tasks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_tasks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ScheduledTask>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    ensure_instance_exists(&state, &uuid)?;
+    Ok(Json(state.scheduler.list(&uuid).await))
+}
+
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/tasks",
+    tag = "instance_tasks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<ScheduledTaskConfig>,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    check_task_access(
+        &requester,
+        &uuid,
+        &config,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    ensure_instance_exists(&state, &uuid)?;
+    state
+        .scheduler
+        .create(&uuid, config, requester.uid)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/tasks/{task_id}",
+    tag = "instance_tasks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("task_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, task_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state.scheduler.get(&uuid, &task_id).await.map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/tasks/{task_id}",
+    tag = "instance_tasks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("task_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, task_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<ScheduledTaskConfig>,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    check_task_access(
+        &requester,
+        &uuid,
+        &config,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state
+        .scheduler
+        .update(&uuid, &task_id, config, requester.uid)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/instance/{uuid}/tasks/{task_id}",
+    tag = "instance_tasks",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("task_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, task_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state
+        .scheduler
+        .delete(&uuid, &task_id)
+        .await
+        .map(|_| Json(()))
+}
+
+pub fn get_instance_tasks_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/tasks", get(get_tasks).post(create_task))
+        .route(
+            "/instance/:uuid/tasks/:task_id",
+            get(get_task).put(update_task).delete(delete_task),
+        )
+        .with_state(state)
+}
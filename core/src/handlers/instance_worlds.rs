@@ -0,0 +1,300 @@
+use std::path::PathBuf;
+
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Query},
+    routing::{get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use headers::HeaderMap;
+use reqwest::header::CONTENT_LENGTH;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    audit::{audit_value, AuditTarget},
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    events::{new_fs_event, CausedBy, FSOperation, FSTarget},
+    implementations::minecraft::worlds::{install_world_archive, WorldInfo},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+use super::{
+    instance_fs::{archive_size, ensure_space, upload_too_large, PartialFile},
+    util::minecraft_instance,
+};
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/worlds",
+    tag = "instance_worlds",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_worlds(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<WorldInfo>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    minecraft_instance(&state, &uuid)?
+        .list_worlds()
+        .await
+        .map(Json)
+}
+
+/// Points `level-name` at another world, only while the instance is stopped
+#[utoipa::path(
+    put,
+    path = "/instance/{uuid}/worlds/active",
+    tag = "instance_worlds",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_active_world(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(name): Json<String>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let old_name = instance.active_world().await;
+    instance.switch_world(&name).await?;
+    state
+        .audit_log
+        .record(
+            &uuid,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+            AuditTarget::GameSetting {
+                key: "level-name".to_string(),
+            },
+            audit_value(old_name, false),
+            audit_value(name, false),
+        )
+        .await;
+    Ok(Json(()))
+}
+
+/// Moves a world and its dimensions aside so the server generates a new one, only while the
+/// instance is stopped. Returns where the world was moved to
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/world/{name}/reset",
+    tag = "instance_worlds",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("name" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success", body = String),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn reset_world(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PathBuf>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let moved = minecraft_instance(&state, &uuid)?
+        .reset_world(&name)
+        .await?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    for (from, to) in &moved {
+        state.event_broadcaster.send(new_fs_event(
+            FSOperation::Move {
+                source: from.clone(),
+            },
+            FSTarget::Directory(to.clone()),
+            caused_by.clone(),
+        ));
+    }
+    // the world itself is always moved first
+    Ok(Json(
+        moved
+            .into_iter()
+            .next()
+            .map(|(_, to)| to)
+            .unwrap_or_default(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct WorldUploadQuery {
+    /// Directory the world is extracted to, must not exist yet
+    name: String,
+}
+
+/// Adds a world from a `.zip`, `.tar.gz` or `.tgz` archive in a `multipart/form-data` body.
+///
+/// The archive may hold the world's files at its top or in a directory, Bukkit style
+/// dimension directories next to it are renamed after the new world
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/worlds/upload",
+    tag = "instance_worlds",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn upload_world(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<WorldUploadQuery>,
+    headers: HeaderMap,
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<WorldInfo>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteResource(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    let root = instance.path().await;
+    let (max_path_length, max_upload_size) = {
+        let global_settings = state.global_settings.lock().await;
+        (
+            global_settings.max_path_length(),
+            global_settings.max_upload_size(),
+        )
+    };
+    let total = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if total.map_or(false, |total| total > max_upload_size) {
+        return Err(upload_too_large(max_upload_size));
+    }
+    ensure_space(&state, &uuid, &root, total.unwrap_or(0)).await?;
+
+    let mut field = multipart
+        .next_field()
+        .await
+        .context("Failed to read multipart field")?
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Missing world archive"),
+        })?;
+    let file_name = field.file_name().unwrap_or_default().to_lowercase();
+    let extension = [".tar.gz", ".tgz", ".zip"]
+        .into_iter()
+        .find(|extension| file_name.ends_with(extension))
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Expected a .zip, .tar.gz or .tgz world archive"),
+        })?;
+    // kept next to the instance so the extracted world is only renamed into place
+    let archive = root.join(format!(
+        ".world_upload_{}{}",
+        rand_alphanumeric(8),
+        extension
+    ));
+    let partial_file = PartialFile(Some(archive.clone()));
+    let mut file = crate::util::fs::create(&archive).await?;
+    let mut uploaded = 0_u64;
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .context("Failed to read world archive")?
+    {
+        uploaded += chunk.len() as u64;
+        if uploaded > max_upload_size {
+            return Err(upload_too_large(max_upload_size));
+        }
+        file.write_all(&chunk)
+            .await
+            .context("Failed to write world archive")?;
+    }
+    file.flush()
+        .await
+        .context("Failed to write world archive")?;
+    drop(file);
+
+    let uncompressed = archive_size(archive.clone()).await?;
+    ensure_space(&state, &uuid, &root, uncompressed).await?;
+    let created = tokio::task::spawn_blocking({
+        let root = root.clone();
+        let name = query.name.clone();
+        move || install_world_archive(&archive, &root, &name, max_path_length)
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+    drop(partial_file);
+    state.disk_usage.add(&uuid, uncompressed);
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    for dir in created {
+        state.event_broadcaster.send(new_fs_event(
+            FSOperation::Upload,
+            FSTarget::Directory(dir),
+            caused_by.clone(),
+        ));
+    }
+    instance
+        .list_worlds()
+        .await?
+        .into_iter()
+        .find(|world| world.name == query.name)
+        .map(Json)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::Internal,
+            source: eyre!("Uploaded world {} went missing", query.name),
+        })
+}
+
+pub fn get_instance_worlds_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/worlds/upload", post(upload_world))
+        .layer(DefaultBodyLimit::disable())
+        .route("/instance/:uuid/worlds", get(get_worlds))
+        .route("/instance/:uuid/worlds/active", put(set_active_world))
+        .route("/instance/:uuid/world/:name/reset", post(reset_world))
+        .with_state(state)
+}
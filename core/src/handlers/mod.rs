@@ -1,23 +1,44 @@
 // pub mod jar;
 // pub mod instance;
 // pub mod users;
+pub mod audit;
 pub mod checks;
 pub mod core_info;
 pub mod events;
+pub mod feature_stubs;
 pub mod gateway;
 pub mod global_fs;
 pub mod global_settings;
+pub mod health;
 pub mod instance;
+pub mod instance_announcements;
+pub mod instance_backups;
+pub mod instance_chat;
 pub mod instance_config;
+pub mod instance_datapacks;
+pub mod instance_diagnostics;
 pub mod instance_fs;
+pub mod instance_game;
+pub mod instance_logs;
 pub mod instance_macro;
+pub mod instance_macro_triggers;
+pub mod instance_mods;
 pub mod instance_players;
 pub mod instance_server;
 pub mod instance_setup_configs;
+pub mod instance_tasks;
+pub mod instance_worlds;
 pub mod monitor;
+pub mod networks;
+pub mod notifications;
+pub mod openapi;
 pub mod playitgg;
+pub mod ports;
+#[cfg(feature = "s3-backups")]
+pub mod remote_backups;
 pub mod setup;
 pub mod system;
+pub mod templates;
 pub mod users;
-mod util;
+pub mod util;
 pub mod extension;
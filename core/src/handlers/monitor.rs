@@ -4,39 +4,105 @@ use axum::{
     extract::{ws::WebSocket, Path, WebSocketUpgrade},
     response::Response,
     routing::get,
-    Router,
+    Json, Router,
 };
-use color_eyre::eyre::eyre;
+use axum_auth::AuthBearer;
 use futures::{SinkExt, StreamExt};
 use ringbuffer::{AllocRingBuffer, RingBufferExt};
 use tokio::sync::Mutex;
 use tracing::error;
 
 use crate::{
+    auth::user::UserAction,
     error::Error,
+    implementations::minecraft::server_list_ping::PingStatus,
     prelude::GameInstance,
-    traits::{t_server::MonitorReport, t_server::TServer},
+    traits::{t_server::MonitorReport, t_server::State, t_server::TServer},
     types::InstanceUuid,
     AppState,
 };
 
+use super::util::{game_instance, minecraft_instance};
+
+#[utoipa::path(
+    get,
+    path = "/monitor/{uuid}",
+    tag = "monitor",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn monitor(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
 ) -> Result<Response, Error> {
-    let instance = state
-        .instances
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: crate::error::ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
-        .to_owned();
+    let instance = game_instance(&state, &uuid)?;
     Ok(ws
         .on_upgrade(move |stream| monitor_ws(stream, state.monitor_buffer.clone(), instance, uuid)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/metrics",
+    tag = "monitor",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_instance_metrics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MonitorReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    if instance.state().await == State::Stopped {
+        return Ok(Json(MonitorReport::zeroed()));
+    }
+    Ok(Json(instance.monitor().await))
+}
+
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/ping",
+    tag = "monitor",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Result of the last server list pings, reset while the server is stopped"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_instance_ping(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PingStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ViewInstance(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = minecraft_instance(&state, &uuid)?;
+    Ok(Json(instance.ping_status().await))
+}
+
 async fn monitor_ws(
     stream: WebSocket,
     monitor_buffer: Arc<Mutex<HashMap<InstanceUuid, AllocRingBuffer<MonitorReport>>>>,
@@ -84,5 +150,7 @@ async fn monitor_ws(
 pub fn get_monitor_routes(state: AppState) -> Router {
     Router::new()
         .route("/monitor/:uuid", get(monitor))
+        .route("/instance/:uuid/metrics", get(get_instance_metrics))
+        .route("/instance/:uuid/ping", get(get_instance_ping))
         .with_state(state)
 }
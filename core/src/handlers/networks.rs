@@ -0,0 +1,254 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::{
+    auth::user::{User, UserAction},
+    error::Error,
+    networks::{
+        leave_network, sync_network, Network, NetworkConfig, NetworkMemberConfig, NetworkSyncReport,
+    },
+    traits::t_configurable::TConfigurable,
+    types::{InstanceUuid, Snowflake},
+    AppState,
+};
+
+use super::util::game_instance;
+
+/// A network with what the sync that came with the change did
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct NetworkChange {
+    pub network: Network,
+    pub sync: NetworkSyncReport,
+}
+
+/// Changing a network rewrites the files of the proxy and every member, so it takes access to
+/// all of their settings
+async fn authorize_network(
+    state: &AppState,
+    requester: &User,
+    network: &Network,
+) -> Result<(), Error> {
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::AccessSetting(network.proxy.clone()), safe_mode)?;
+    for member in &network.members {
+        requester.try_action(
+            &UserAction::AccessSetting(member.instance_uuid.clone()),
+            safe_mode,
+        )?;
+    }
+    Ok(())
+}
+
+/// Networks whose proxy the requester can see
+#[utoipa::path(
+    get,
+    path = "/networks",
+    tag = "networks",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_networks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Network>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    Ok(Json(
+        state
+            .networks
+            .list()
+            .await
+            .into_iter()
+            .filter(|network| {
+                requester
+                    .try_action(&UserAction::ViewInstance(network.proxy.clone()), safe_mode)
+                    .is_ok()
+            })
+            .map(Network::redacted)
+            .collect(),
+    ))
+}
+
+/// Creates a network around a proxy and writes its forwarding settings
+#[utoipa::path(
+    post,
+    path = "/networks",
+    tag = "networks",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_network(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<NetworkConfig>,
+) -> Result<Json<NetworkChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::AccessSetting(config.proxy.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &config.proxy)?;
+    let network = state.networks.create(config).await?;
+    let sync = sync_network(&network, &state.instances).await;
+    Ok(Json(NetworkChange {
+        network: network.redacted(),
+        sync,
+    }))
+}
+
+/// Deletes a network, its members get `online-mode` back and stop taking forwarded players.
+/// The proxy's files are left as they are
+#[utoipa::path(
+    delete,
+    path = "/networks/{network_id}",
+    tag = "networks",
+    params(
+        ("network_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_network(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(network_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<NetworkSyncReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    authorize_network(&state, &requester, &state.networks.get(&network_id).await?).await?;
+    let network = state.networks.delete(&network_id).await?;
+    let mut report = NetworkSyncReport::default();
+    for member in &network.members {
+        leave_network(
+            &network,
+            &member.instance_uuid,
+            &state.instances,
+            &mut report,
+        )
+        .await;
+    }
+    Ok(Json(report))
+}
+
+/// Adds an instance to a network, turning on forwarding on it and listing it in the proxy
+#[utoipa::path(
+    put,
+    path = "/networks/{network_id}/members/{uuid}",
+    tag = "networks",
+    params(
+        ("network_id" = String, Path),
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn add_network_member(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((network_id, uuid)): Path<(Snowflake, InstanceUuid)>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<NetworkMemberConfig>,
+) -> Result<Json<NetworkChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    authorize_network(&state, &requester, &state.networks.get(&network_id).await?).await?;
+    requester.try_action(
+        &UserAction::AccessSetting(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let instance = game_instance(&state, &uuid)?;
+    let network = state
+        .networks
+        .add_member(&network_id, &uuid, &instance.name().await, config)
+        .await?;
+    let sync = sync_network(&network, &state.instances).await;
+    Ok(Json(NetworkChange {
+        network: network.redacted(),
+        sync,
+    }))
+}
+
+/// Takes an instance out of a network and its proxy's server list
+#[utoipa::path(
+    delete,
+    path = "/networks/{network_id}/members/{uuid}",
+    tag = "networks",
+    params(
+        ("network_id" = String, Path),
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn remove_network_member(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((network_id, uuid)): Path<(Snowflake, InstanceUuid)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<NetworkChange>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    authorize_network(&state, &requester, &state.networks.get(&network_id).await?).await?;
+    let network = state.networks.remove_member(&network_id, &uuid).await?;
+    let mut sync = sync_network(&network, &state.instances).await;
+    leave_network(&network, &uuid, &state.instances, &mut sync).await;
+    Ok(Json(NetworkChange {
+        network: network.redacted(),
+        sync,
+    }))
+}
+
+/// Rewrites the proxy's server list and the members' forwarding settings. Running instances
+/// are left alone and reported, sync again once they're stopped
+#[utoipa::path(
+    post,
+    path = "/networks/{network_id}/sync",
+    tag = "networks",
+    params(
+        ("network_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn sync_network_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(network_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<NetworkSyncReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let network = state.networks.get(&network_id).await?;
+    authorize_network(&state, &requester, &network).await?;
+    Ok(Json(sync_network(&network, &state.instances).await))
+}
+
+pub fn get_network_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/networks", get(get_networks).post(create_network))
+        .route("/networks/:network_id", delete(delete_network))
+        .route(
+            "/networks/:network_id/members/:uuid",
+            put(add_network_member).delete(remove_network_member),
+        )
+        .route("/networks/:network_id/sync", post(sync_network_config))
+        .with_state(state)
+}
@@ -0,0 +1,196 @@
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+
+use crate::{
+    auth::user::UserAction,
+    error::Error,
+    events::CausedBy,
+    notifications::{NotificationTarget, NotificationTargetConfig},
+    types::Snowflake,
+    AppState,
+};
+
+async fn authorize(state: &AppState, token: &str) -> Result<CausedBy, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(token)?;
+    requester.try_action(
+        &UserAction::ManageNotifications,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/notifications/targets",
+    tag = "notifications",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_targets(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<NotificationTarget>>, Error> {
+    authorize(&state, &token).await?;
+    Ok(Json(
+        state
+            .notification_manager
+            .list()
+            .await
+            .into_iter()
+            .map(NotificationTarget::redacted)
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/notifications/targets",
+    tag = "notifications",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_target(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<NotificationTargetConfig>,
+) -> Result<Json<NotificationTarget>, Error> {
+    authorize(&state, &token).await?;
+    state
+        .notification_manager
+        .create(config)
+        .await
+        .map(|target| Json(target.redacted()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notifications/targets/{target_id}",
+    tag = "notifications",
+    params(
+        ("target_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_target(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(target_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<NotificationTarget>, Error> {
+    authorize(&state, &token).await?;
+    state
+        .notification_manager
+        .get(&target_id)
+        .await
+        .map(|target| Json(target.redacted()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/notifications/targets/{target_id}",
+    tag = "notifications",
+    params(
+        ("target_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn update_target(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(target_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<NotificationTargetConfig>,
+) -> Result<Json<NotificationTarget>, Error> {
+    authorize(&state, &token).await?;
+    state
+        .notification_manager
+        .update(&target_id, config)
+        .await
+        .map(|target| Json(target.redacted()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notifications/targets/{target_id}",
+    tag = "notifications",
+    params(
+        ("target_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_target(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(target_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    authorize(&state, &token).await?;
+    state
+        .notification_manager
+        .delete(&target_id)
+        .await
+        .map(Json)
+}
+
+/// Sends a test notification right away, a failed delivery is returned instead of retried
+#[utoipa::path(
+    post,
+    path = "/notifications/targets/{target_id}/test",
+    tag = "notifications",
+    params(
+        ("target_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn test_target(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(target_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let caused_by = authorize(&state, &token).await?;
+    state
+        .notification_manager
+        .send_test(&target_id, caused_by)
+        .await
+        .map(Json)
+}
+
+pub fn get_notification_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/notifications/targets",
+            get(get_targets).post(create_target),
+        )
+        .route(
+            "/notifications/targets/:target_id",
+            get(get_target).put(update_target).delete(delete_target),
+        )
+        .route("/notifications/targets/:target_id/test", post(test_target))
+        .with_state(state)
+}
@@ -0,0 +1,711 @@
+//! The OpenAPI document of the HTTP API, generated from the `utoipa::path` annotations on the
+//! handlers, and the Swagger UI reading it
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Json, Router,
+};
+use color_eyre::eyre::eyre;
+use once_cell::sync::Lazy;
+use utoipa::openapi::path::{PathItem, PathItemType};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use super::instance::{GenericSetupConfig, MigrateInstanceBody};
+use super::instance_setup_configs::{GenericSetupManifestBody, HandlerGameType};
+#[cfg(feature = "s3-backups")]
+use super::remote_backups;
+use super::{
+    audit, checks, core_info, events, extension, gateway, global_fs, global_settings, instance,
+    instance_announcements, instance_backups, instance_chat, instance_config, instance_datapacks,
+    instance_diagnostics, instance_fs, instance_game, instance_logs, instance_macro,
+    instance_macro_triggers, instance_mods, instance_players, instance_server,
+    instance_setup_configs, instance_tasks, instance_worlds, monitor, networks, notifications,
+    ports, setup, system, templates, users,
+};
+use crate::error::{Error, ErrorCode, ErrorKind};
+use crate::handlers::global_fs::{FileEntry, FileType};
+use crate::playitgg;
+use crate::run_record::ExitReason;
+use crate::traits::t_configurable::manifest::{
+    ConfigurableManifest, ConfigurableValue, ConfigurableValueType, SectionManifest,
+    SectionManifestValue, SettingManifest, SettingManifestValue, SettingValidationError,
+    SetupManifest, SetupValue,
+};
+use crate::traits::t_configurable::{Game, GameType, MinecraftVariant};
+use crate::traits::t_server::State as InstanceState;
+use crate::traits::InstanceInfo;
+use crate::types::{InstanceUuid, Maintenance};
+use crate::AppState;
+
+/// Where the Swagger UI fetches the document from, relative to `/docs/`
+const DOCUMENT_URL: &str = "../openapi.json";
+
+/// Routes served by the handler of another route, documented as a copy of its operation.
+///
+/// `utoipa::path` takes a single method and path, these are the extra ones
+const ROUTE_ALIASES: &[(PathItemType, &str, PathItemType, &str)] = &[
+    (
+        PathItemType::Get,
+        "/instance/{uuid}/macro",
+        PathItemType::Get,
+        "/instance/{uuid}/macro/list",
+    ),
+    (
+        PathItemType::Post,
+        "/instance/{uuid}/macro/abort/{pid}",
+        PathItemType::Put,
+        "/instance/{uuid}/macro/kill/{pid}",
+    ),
+    (
+        PathItemType::Get,
+        "/instance/{uuid}/history/list",
+        PathItemType::Get,
+        "/instance/{uuid}/macro/history",
+    ),
+    (
+        PathItemType::Post,
+        "/instance/{uuid}/stop",
+        PathItemType::Put,
+        "/instance/{uuid}/stop",
+    ),
+    (
+        PathItemType::Post,
+        "/instance/{uuid}/kill",
+        PathItemType::Put,
+        "/instance/{uuid}/kill",
+    ),
+    (PathItemType::Get, "/users", PathItemType::Get, "/user/list"),
+    (PathItemType::Post, "/users", PathItemType::Post, "/user"),
+    (
+        PathItemType::Post,
+        "/users/{uid}/logout_all",
+        PathItemType::Post,
+        "/user/logout/{uid}",
+    ),
+    (
+        PathItemType::Get,
+        "/docs/",
+        PathItemType::Get,
+        "/docs/{path}",
+    ),
+];
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Lodestone Core"),
+    servers((url = "/api/v1")),
+    paths(
+        audit::get_instance_audit,
+        audit::get_audit,
+        checks::get_port_status,
+        checks::is_name_in_use,
+        core_info::get_core_info,
+        core_info::get_daemon_info,
+        events::live_event_stream,
+        events::event_stream,
+        events::get_event_buffer,
+        events::get_event_search,
+        events::cancel_progression,
+        events::console_stream,
+        events::get_console_buffer,
+        extension::is_git_installed,
+        extension::fetch_extension_manifest,
+        extension::install_extension,
+        gateway::open_port,
+        global_fs::list_files,
+        global_fs::read_file,
+        global_fs::write_file,
+        global_fs::make_directory,
+        global_fs::move_file,
+        global_fs::remove_file,
+        global_fs::remove_dir,
+        global_fs::new_file,
+        global_fs::download_file,
+        global_fs::upload_file,
+        global_fs::download,
+        global_settings::get_core_settings,
+        global_settings::change_core_name,
+        global_settings::change_core_safe_mode,
+        global_settings::change_domain,
+        global_settings::change_core_playit_enabled,
+        global_settings::change_max_path_length,
+        global_settings::change_max_upload_size,
+        global_settings::change_login_rate_limit,
+        global_settings::change_port_range,
+        global_settings::change_api_docs_enabled,
+        instance::get_instance_list,
+        instance::lookup_instance,
+        instance::validate_setup_value,
+        instance::create_minecraft_instance,
+        instance::create_generic_instance,
+        instance::import_instance_archive,
+        instance::get_broken_instances,
+        instance::repair_broken_instance,
+        instance::delete_instance,
+        instance::get_instance_info,
+        instance::get_creation_status,
+        instance::export_instance,
+        instance::migrate_instance,
+        instance_announcements::get_announcements,
+        instance_announcements::set_announcements,
+        instance_announcements::test_announcement,
+        instance_backups::get_backups,
+        instance_backups::create_backup,
+        instance_backups::delete_backup,
+        instance_backups::verify_backup,
+        instance_backups::restore_backup,
+        instance_config::get_instance_configurable_manifest,
+        instance_config::change_version,
+        instance_config::change_game_version,
+        instance_config::get_instance_settings,
+        instance_config::set_instance_setting,
+        instance_config::set_instance_name,
+        instance_config::set_instance_description,
+        instance_config::set_instance_tags,
+        instance_config::set_instance_maintenance,
+        instance_config::get_restart_policy,
+        instance_config::set_restart_policy,
+        instance_config::get_auto_start,
+        instance_config::set_auto_start,
+        instance_config::get_backup_schedule,
+        instance_config::set_backup_schedule,
+        instance_config::get_game_settings,
+        instance_config::set_game_settings,
+        instance_config::get_server_icon,
+        instance_config::set_server_icon,
+        instance_config::remove_server_icon,
+        instance_config::get_motd,
+        instance_config::set_motd,
+        instance_config::accept_game_eula,
+        instance_config::get_java,
+        instance_config::set_java,
+        instance_config::download_java,
+        instance_diagnostics::get_diagnostics,
+        instance_diagnostics::get_preflight,
+        instance_diagnostics::fix_diagnostics,
+        instance_diagnostics::accept_instance_eula,
+        instance_diagnostics::get_last_launch_failure,
+        instance_fs::list_instance_files,
+        instance_fs::read_instance_file,
+        instance_fs::write_instance_file,
+        instance_fs::make_instance_directory,
+        instance_fs::copy_instance_files,
+        instance_fs::move_instance_path,
+        instance_fs::copy_instance_path,
+        instance_fs::move_instance_file,
+        instance_fs::remove_instance_file,
+        instance_fs::remove_instance_dir,
+        instance_fs::new_instance_file,
+        instance_fs::get_instance_file_url,
+        instance_fs::upload_instance_file,
+        instance_fs::download_instance_file,
+        instance_fs::upload_instance_files,
+        instance_fs::unzip_instance_file,
+        instance_fs::zip_instance_files,
+        instance_fs::extract_instance_archive,
+        instance_fs::archive_instance_files,
+        instance_fs::get_instance_file_hash,
+        instance_fs::get_instance_file_hashes,
+        instance_fs::get_protected_files_policy,
+        instance_fs::set_protected_files_policy,
+        instance_fs::get_instance_disk_usage,
+        instance_fs::set_instance_disk_quota,
+        instance_fs::search_instance_files,
+        instance_fs::watch_instance_files,
+        instance_fs::list_instance_trash,
+        instance_fs::restore_instance_trash,
+        instance_fs::purge_instance_trash_entry,
+        instance_fs::purge_instance_trash,
+        instance_macro::run_macro,
+        instance_macro::kill_macro,
+        instance_macro::get_instance_macro_list,
+        instance_macro::start_macro_run,
+        instance_macro::get_instance_history_list,
+        instance_macro::get_macro_configs,
+        instance_macro::store_config_to_local,
+        instance_macro::get_instance_task_list,
+        instance_macro_triggers::get_triggers,
+        instance_macro_triggers::create_trigger,
+        instance_macro_triggers::dry_run_trigger,
+        instance_macro_triggers::get_trigger,
+        instance_macro_triggers::update_trigger,
+        instance_macro_triggers::delete_trigger,
+        instance_mods::get_mods,
+        instance_mods::remove_mod,
+        instance_mods::set_mod_enabled,
+        instance_players::get_player_count,
+        instance_players::get_max_player_count,
+        instance_players::set_max_player_count,
+        instance_players::get_player_list,
+        instance_players::get_player_history,
+        instance_players::get_whitelist,
+        instance_players::add_to_whitelist,
+        instance_players::remove_from_whitelist,
+        instance_players::get_ops,
+        instance_players::add_op,
+        instance_players::remove_op,
+        instance_chat::get_chat,
+        instance_chat::send_chat,
+        instance_server::start_instance,
+        instance_server::stop_instance,
+        instance_server::restart_instance,
+        instance_server::kill_instance,
+        instance_server::suspend_instance,
+        instance_server::resume_instance,
+        instance_server::get_auto_suspend,
+        instance_server::set_auto_suspend,
+        instance_server::send_command,
+        instance_server::send_command_with_output,
+        instance_server::get_command_queue_status,
+        instance_server::set_command_queue_config,
+        instance_server::get_console_history,
+        instance_server::clear_console_history,
+        instance_server::get_rcon_status,
+        instance_server::set_rcon,
+        instance_server::get_instance_state,
+        instance_setup_configs::get_available_games,
+        instance_setup_configs::get_setup_manifest,
+        instance_setup_configs::get_setup_versions,
+        instance_setup_configs::get_setup_loader_versions,
+        instance_setup_configs::get_generic_setup_manifest,
+        instance_tasks::get_tasks,
+        instance_tasks::create_task,
+        instance_tasks::get_task,
+        instance_tasks::update_task,
+        instance_tasks::delete_task,
+        instance_worlds::upload_world,
+        instance_game::get_game_updates,
+        instance_game::update_game,
+        instance_logs::get_logs,
+        instance_logs::read_log,
+        instance_logs::get_log_tail,
+        instance_logs::get_crash_reports,
+        instance_logs::get_log_rules,
+        instance_logs::set_log_rules,
+        instance_logs::test_log_rules,
+        instance_worlds::get_worlds,
+        instance_worlds::set_active_world,
+        instance_worlds::reset_world,
+        instance_datapacks::get_datapacks,
+        instance_datapacks::upload_datapack,
+        instance_datapacks::set_datapack_enabled,
+        instance_datapacks::remove_datapack,
+        monitor::monitor,
+        monitor::get_instance_metrics,
+        monitor::get_instance_ping,
+        networks::get_networks,
+        networks::create_network,
+        networks::delete_network,
+        networks::add_network_member,
+        networks::remove_network_member,
+        networks::sync_network_config,
+        notifications::get_targets,
+        notifications::create_target,
+        notifications::get_target,
+        notifications::update_target,
+        notifications::delete_target,
+        notifications::test_target,
+        playitgg::generate_signup_link,
+        playitgg::start_cli,
+        playitgg::stop_cli,
+        playitgg::verify_key,
+        playitgg::cli_is_running,
+        playitgg::get_tunnels,
+        ports::get_allocations,
+        ports::suggest_ports,
+        setup::get_setup_status,
+        setup::setup_owner,
+        setup::setup_owner_with_key,
+        system::get_ram,
+        system::get_disk,
+        system::get_instances_disk,
+        system::get_cpu_info,
+        system::get_features,
+        system::get_java_runtimes,
+        system::shutdown,
+        system::get_daemon_settings,
+        system::patch_daemon_settings,
+        system::get_capacity,
+        system::get_daemon_metrics,
+        templates::get_templates,
+        templates::create_template,
+        templates::delete_template,
+        users::get_all_users,
+        users::new_user,
+        users::get_user_info,
+        users::delete_user,
+        users::update_permissions,
+        users::get_self_info,
+        users::rename_user,
+        users::change_password,
+        users::set_user_role,
+        users::list_access_tokens,
+        users::create_access_token,
+        users::revoke_access_token,
+        users::get_permissions,
+        users::patch_instance_permissions,
+        users::apply_permission_preset,
+        users::set_user_disabled,
+        users::require_password_reset,
+        users::login,
+        users::unlock_user,
+        users::logout,
+        users::logout_self,
+        get_openapi_json,
+        get_docs_index,
+        get_docs_file,
+    ),
+    components(schemas(
+        ConfigurableManifest,
+        ConfigurableValue,
+        ConfigurableValueType,
+        Error,
+        ErrorCode,
+        ErrorKind,
+        ExitReason,
+        FileEntry,
+        FileType,
+        Game,
+        GameType,
+        GenericSetupConfig,
+        GenericSetupManifestBody,
+        HandlerGameType,
+        InstanceInfo,
+        InstanceState,
+        InstanceUuid,
+        Maintenance,
+        MigrateInstanceBody,
+        MinecraftVariant,
+        SectionManifest,
+        SectionManifestValue,
+        SettingManifest,
+        SettingManifestValue,
+        SettingValidationError,
+        SetupManifest,
+        SetupValue,
+    )),
+    modifiers(&BearerAuth, &RouteAliases)
+)]
+struct ApiDoc;
+
+/// The routes only compiled in with the feature, without it the feature stubs answer them
+#[cfg(feature = "mod-providers")]
+#[derive(OpenApi)]
+#[openapi(paths(
+    instance_mods::search_mods,
+    instance_mods::install_mod,
+    instance_mods::get_mod_updates,
+))]
+struct ModProvidersApiDoc;
+
+#[cfg(feature = "s3-backups")]
+#[derive(OpenApi)]
+#[openapi(paths(
+    remote_backups::get_remote_backups,
+    remote_backups::upload_backup,
+    remote_backups::restore_remote_backup,
+    remote_backups::test_backup_target,
+))]
+struct S3BackupsApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+struct RouteAliases;
+
+impl Modify for RouteAliases {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for (method, path, canonical_method, canonical_path) in ROUTE_ALIASES {
+            let operation = openapi
+                .paths
+                .paths
+                .get(*canonical_path)
+                .and_then(|item| item.operations.get(canonical_method))
+                .cloned();
+            let mut operation = match operation {
+                Some(operation) => operation,
+                None => continue,
+            };
+            // operation ids have to be unique
+            operation.operation_id = None;
+            openapi
+                .paths
+                .paths
+                .entry(path.to_string())
+                .or_insert_with(PathItem::default)
+                .operations
+                .insert(method.clone(), operation);
+        }
+    }
+}
+
+/// The document of every route this build serves
+pub fn api_doc() -> utoipa::openapi::OpenApi {
+    #[allow(unused_mut)]
+    let mut doc = ApiDoc::openapi();
+    #[cfg(feature = "mod-providers")]
+    doc.merge(ModProvidersApiDoc::openapi());
+    #[cfg(feature = "s3-backups")]
+    doc.merge(S3BackupsApiDoc::openapi());
+    doc
+}
+
+static API_DOC: Lazy<utoipa::openapi::OpenApi> = Lazy::new(api_doc);
+
+async fn ensure_docs_enabled(state: &AppState) -> Result<(), Error> {
+    if state.global_settings.lock().await.api_docs_enabled() {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::FeatureDisabled,
+            source: eyre!(
+                "The API documentation is disabled, the owner can enable it in the global settings"
+            ),
+        })
+    }
+}
+
+/// The OpenAPI document of this API
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "openapi",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn get_openapi_json(
+    State(state): State<AppState>,
+) -> Result<Json<&'static utoipa::openapi::OpenApi>, Error> {
+    ensure_docs_enabled(&state).await?;
+    Ok(Json(&API_DOC))
+}
+
+/// Redirects to the Swagger UI, its assets are linked relative to `/docs/`
+#[utoipa::path(
+    get,
+    path = "/docs",
+    tag = "openapi",
+    responses(
+        (status = 303, description = "Redirect to the Swagger UI"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn get_docs_index(State(state): State<AppState>) -> Result<Redirect, Error> {
+    ensure_docs_enabled(&state).await?;
+    Ok(Redirect::to("docs/"))
+}
+
+/// A file of the Swagger UI, `index.html` for an empty path
+#[utoipa::path(
+    get,
+    path = "/docs/{path}",
+    tag = "openapi",
+    params(("path" = String, Path, description = "File of the Swagger UI, may be empty")),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn get_docs_file(
+    State(state): State<AppState>,
+    path: Option<Path<String>>,
+) -> Result<Response, Error> {
+    ensure_docs_enabled(&state).await?;
+    let path = path.map(|Path(path)| path).unwrap_or_default();
+    let config = Arc::new(utoipa_swagger_ui::Config::from(DOCUMENT_URL));
+    match utoipa_swagger_ui::serve(&path, config) {
+        Ok(Some(file)) => Ok((
+            [(header::CONTENT_TYPE, file.content_type)],
+            file.bytes.to_vec(),
+        )
+            .into_response()),
+        Ok(None) => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("The Swagger UI has no file {}", path),
+        }),
+        Err(e) => Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!("Failed to serve the Swagger UI: {}", e),
+        }),
+    }
+}
+
+pub fn get_openapi_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/openapi.json", get(get_openapi_json))
+        .route("/docs", get(get_docs_index))
+        .route("/docs/", get(get_docs_file))
+        .route("/docs/*path", get(get_docs_file))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::Feature;
+
+    /// The sources holding the routers merged into the API
+    const ROUTER_SOURCES: &[&str] = &[
+        include_str!("audit.rs"),
+        include_str!("checks.rs"),
+        include_str!("core_info.rs"),
+        include_str!("events.rs"),
+        include_str!("extension.rs"),
+        include_str!("gateway.rs"),
+        include_str!("global_fs.rs"),
+        include_str!("global_settings.rs"),
+        include_str!("instance.rs"),
+        include_str!("instance_announcements.rs"),
+        include_str!("instance_backups.rs"),
+        include_str!("instance_chat.rs"),
+        include_str!("instance_config.rs"),
+        include_str!("instance_datapacks.rs"),
+        include_str!("instance_diagnostics.rs"),
+        include_str!("instance_fs.rs"),
+        include_str!("instance_game.rs"),
+        include_str!("instance_logs.rs"),
+        include_str!("instance_macro.rs"),
+        include_str!("instance_macro_triggers.rs"),
+        include_str!("instance_mods.rs"),
+        include_str!("instance_players.rs"),
+        include_str!("instance_server.rs"),
+        include_str!("instance_setup_configs.rs"),
+        include_str!("instance_tasks.rs"),
+        include_str!("instance_worlds.rs"),
+        include_str!("monitor.rs"),
+        include_str!("networks.rs"),
+        include_str!("notifications.rs"),
+        include_str!("openapi.rs"),
+        include_str!("playitgg.rs"),
+        include_str!("ports.rs"),
+        include_str!("remote_backups.rs"),
+        include_str!("setup.rs"),
+        include_str!("system.rs"),
+        include_str!("templates.rs"),
+        include_str!("users.rs"),
+    ];
+
+    const METHODS: &[&str] = &["get", "post", "put", "delete", "patch"];
+
+    /// The `(method, axum path)` pairs of the `.route(..)` calls in `source`
+    fn routes(source: &str) -> Vec<(&'static str, String)> {
+        let source = source
+            .split("#[cfg(test)]\nmod tests")
+            .next()
+            .unwrap_or_default();
+        let mut routes = Vec::new();
+        for call in source.split(".route(").skip(1) {
+            let path = match call.split('"').nth(1) {
+                Some(path) => path,
+                None => continue,
+            };
+            // the arguments of the call end where its parenthesis closes
+            let mut depth = 0;
+            let end = call
+                .char_indices()
+                .find(|(_, c)| {
+                    match c {
+                        '(' => depth += 1,
+                        ')' if depth == 0 => return true,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    false
+                })
+                .map_or(call.len(), |(i, _)| i);
+            let arguments = &call[..end];
+            for method in METHODS {
+                let called = arguments
+                    .match_indices(&format!("{}(", method))
+                    .any(|(i, _)| {
+                        !arguments[..i].ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+                    });
+                if called {
+                    routes.push((*method, path.to_string()));
+                }
+            }
+        }
+        routes
+    }
+
+    /// `/instance/:uuid/fs/*path` as `/instance/{uuid}/fs/{path}`
+    fn openapi_path(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.strip_prefix([':', '*']) {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn test_every_route_is_documented() {
+        let doc = serde_json::to_value(api_doc()).unwrap();
+        let compiled_out: Vec<&str> = Feature::ALL
+            .iter()
+            .filter(|feature| !feature.is_enabled())
+            .flat_map(|feature| feature.routes().iter().copied())
+            .collect();
+        let mut checked = 0;
+        for source in ROUTER_SOURCES {
+            for (method, path) in routes(source) {
+                if compiled_out.contains(&path.as_str()) {
+                    continue;
+                }
+                let documented = openapi_path(&path);
+                assert!(
+                    doc["paths"][&documented][method].is_object(),
+                    "{} {} isn't in the OpenAPI document",
+                    method.to_uppercase(),
+                    documented
+                );
+                checked += 1;
+            }
+        }
+        assert!(checked > 200, "only {} routes were found", checked);
+
+        assert_eq!(
+            doc["components"]["securitySchemes"]["bearer"]["scheme"],
+            "bearer"
+        );
+        for schema in [
+            "InstanceInfo",
+            "ConfigurableValue",
+            "Error",
+            "FileEntry",
+            "SetupValue",
+            "SetupManifest",
+        ] {
+            assert!(
+                doc["components"]["schemas"][schema].is_object(),
+                "{} has no schema",
+                schema
+            );
+        }
+        let parameters =
+            &doc["paths"]["/instance/{uuid}/fs/download/{relative_path}"]["get"]["parameters"];
+        assert!(parameters
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|parameter| parameter["name"] == "relative_path"));
+    }
+}
@@ -0,0 +1,77 @@
+use axum::{extract::Query, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
+
+use crate::{auth::user::UserAction, error::Error, port_manager::PortAllocation, AppState};
+
+/// Most ports a single suggestion returns, every candidate costs a bind attempt
+const MAX_SUGGESTIONS: usize = 32;
+
+#[derive(Deserialize)]
+pub struct SuggestQuery {
+    #[serde(default = "default_count")]
+    count: usize,
+}
+
+fn default_count() -> usize {
+    1
+}
+
+/// Ports of the configured range that are neither allocated nor bound by another program,
+/// they aren't reserved until an instance is created or changed to use them
+#[utoipa::path(
+    get,
+    path = "/ports/suggest",
+    tag = "ports",
+    responses(
+        (status = 200, description = "Success", body = [u32]),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn suggest_ports(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<SuggestQuery>,
+) -> Result<Json<Vec<u32>>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    let port_manager = state.port_manager.lock().await;
+    Ok(Json(
+        port_manager.suggest(query.count.clamp(1, MAX_SUGGESTIONS)),
+    ))
+}
+
+/// Allocated ports of the instances the requester can view
+#[utoipa::path(
+    get,
+    path = "/ports",
+    tag = "ports",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_allocations(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<PortAllocation>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let allocations = state.port_manager.lock().await.allocations();
+    Ok(Json(
+        allocations
+            .into_iter()
+            .filter(|allocation| {
+                requester
+                    .can_perform_action(&UserAction::ViewInstance(allocation.instance_uuid.clone()))
+            })
+            .collect(),
+    ))
+}
+
+pub fn get_ports_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/ports", get(get_allocations))
+        .route("/ports/suggest", get(suggest_ports))
+        .with_state(state)
+}
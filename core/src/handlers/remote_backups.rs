@@ -0,0 +1,221 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use tracing::error;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    remote_backups::{RemoteObject, S3Client},
+    traits::{
+        t_configurable::TConfigurable,
+        t_server::{State, TServer},
+    },
+    types::{InstanceUuid, Snowflake},
+    AppState,
+};
+
+use super::{
+    instance_backups::{restore_instance, RestoreQuery},
+    util::game_instance,
+};
+
+async fn client(state: &AppState) -> Result<S3Client, Error> {
+    S3Client::from_settings(&state.global_settings)
+        .await
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "No remote backup target is set, the owner can set one in the daemon settings"
+            ),
+        })
+}
+
+/// Objects under the instance's prefix on the remote backup target
+#[utoipa::path(
+    get,
+    path = "/instance/{uuid}/backups/s3",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_remote_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<RemoteObject>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    game_instance(&state, &uuid)?;
+    let client = client(&state).await?;
+    client.list(&client.instance_prefix(&uuid)).await.map(Json)
+}
+
+/// Uploads a backup to the remote backup target, progress is reported through a progression
+/// event
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/backups/s3/{backup_id}/upload",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("backup_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn upload_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let path = game_instance(&state, &uuid)?.path().await;
+    let client = client(&state).await?;
+    state.backup_manager.get(&path, &backup_id).await?;
+    state.backup_manager.try_begin(&uuid).await?;
+    tokio::task::spawn(async move {
+        if let Err(e) = state
+            .backup_manager
+            .upload(
+                &client,
+                &uuid,
+                &path,
+                &backup_id,
+                &state.event_broadcaster,
+                caused_by,
+            )
+            .await
+        {
+            error!("Failed to upload backup of {}: {}", uuid, e);
+        }
+        state.backup_manager.end(&uuid).await;
+    });
+    Ok(Json(()))
+}
+
+/// Downloads a backup from the remote backup target if it isn't on disk, then restores it like
+/// a local one. A backup missing from the index is added back to it
+#[utoipa::path(
+    post,
+    path = "/instance/{uuid}/backups/s3/{backup_id}/restore",
+    tag = "instance_backups",
+    params(
+        ("uuid" = String, Path, description = "UUID of the instance"),
+        ("backup_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn restore_remote_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, Snowflake)>,
+    Query(query): Query<RestoreQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::WriteInstanceFile(uuid.clone()),
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let instance = game_instance(&state, &uuid)?;
+    // checked again before the files are swapped, this only saves a pointless download
+    if instance.state().await != State::Stopped {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance must be stopped before restoring a backup"),
+        });
+    }
+    let path = instance.path().await;
+    let client = client(&state).await?;
+    state.backup_manager.try_begin(&uuid).await?;
+    let result = match state
+        .backup_manager
+        .fetch(
+            &client,
+            &uuid,
+            &path,
+            &backup_id,
+            &state.event_broadcaster,
+            caused_by,
+        )
+        .await
+    {
+        Ok(_) => restore_instance(&state, &uuid, &backup_id, !query.skip_verify).await,
+        Err(e) => Err(e),
+    };
+    state.backup_manager.end(&uuid).await;
+    result.map(Json)
+}
+
+/// Lists the bucket of the remote backup target with the saved credentials
+#[utoipa::path(
+    post,
+    path = "/system/backup_target/test",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn test_backup_target(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can test the remote backup target"),
+        });
+    }
+    client(&state).await?.test().await.map(Json)
+}
+
+pub fn get_remote_backups_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/backups/s3", get(get_remote_backups))
+        .route(
+            "/instance/:uuid/backups/s3/:backup_id/upload",
+            post(upload_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/s3/:backup_id/restore",
+            post(restore_remote_backup),
+        )
+        .route("/system/backup_target/test", post(test_backup_target))
+        .with_state(state)
+}
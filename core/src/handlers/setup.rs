@@ -1,8 +1,13 @@
-use axum::{extract::Path, Json, Router};
+use axum::{extract::Path, routing::get, Json, Router};
 use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
 
 use crate::{
-    auth::{permission::UserPermission, user::User},
+    auth::{
+        permission::UserPermission,
+        user::{User, UsersManager},
+    },
     error::{Error, ErrorKind},
     events::CausedBy,
     AppState,
@@ -10,52 +15,213 @@ use crate::{
 
 use super::users::LoginReply;
 
-#[derive(serde::Deserialize)]
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct SetupStatus {
+    pub owner_exists: bool,
+}
+
+#[derive(Deserialize)]
 pub struct OwnerSetup {
     username: String,
     password: String,
+    /// Printed in the daemon log or set through `LODESTONE_SETUP_KEY`
+    #[serde(default)]
+    setup_key: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/setup/status",
+    tag = "setup",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
+pub async fn get_setup_status(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Json<SetupStatus> {
+    let users_manager = state.users_manager.read().await;
+    Json(SetupStatus {
+        owner_exists: users_manager.as_ref().values().any(|user| user.is_owner),
+    })
+}
+
+/// Creates the owner account with the setup key, which is used up by it. Whoever reaches a fresh
+/// install first would otherwise own it
+async fn create_owner_with(
+    users_manager: &mut UsersManager,
+    setup_key: &mut Option<String>,
+    owner_setup: OwnerSetup,
+) -> Result<LoginReply, Error> {
+    if users_manager.as_ref().values().any(|user| user.is_owner) {
+        return Err(Error {
+            kind: ErrorKind::Conflict,
+            source: eyre!("An owner already exists"),
+        });
+    }
+    match (setup_key.as_ref(), owner_setup.setup_key.as_ref()) {
+        (Some(expected), Some(key)) if expected == key => {}
+        (None, _) => {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("The setup key was already used, restart the daemon for a new one"),
+            })
+        }
+        (Some(_), Some(_)) => {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Invalid setup key"),
+            })
+        }
+        (Some(_), None) => {
+            return Err(Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("A setup key is required, it is printed in the daemon log"),
+            })
+        }
+    }
+    let owner = User::new(
+        owner_setup.username,
+        &owner_setup.password,
+        true,
+        false,
+        UserPermission::default(),
+    );
+    users_manager
+        .add_user(owner.clone(), CausedBy::System)
+        .await?;
+    // the key is single use, a new one is only generated on the next start
+    setup_key.take();
+    Ok(LoginReply {
+        token: owner.create_jwt()?,
+        user: owner.into(),
+    })
+}
+
+/// Holds the users lock throughout so two requests can't both succeed
+async fn create_owner(
+    state: &AppState,
+    owner_setup: OwnerSetup,
+) -> Result<Json<LoginReply>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let mut setup_key = state.first_time_setup_key.lock().await;
+    create_owner_with(&mut users_manager, &mut setup_key, owner_setup)
+        .await
+        .map(Json)
 }
 
+#[utoipa::path(
+    post,
+    path = "/setup/owner",
+    tag = "setup",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn setup_owner(
     axum::extract::State(state): axum::extract::State<AppState>,
-    Path(key): Path<String>,
     Json(owner_setup): Json<OwnerSetup>,
 ) -> Result<Json<LoginReply>, Error> {
-    let mut setup_key_lock = state.first_time_setup_key.lock().await;
-    match setup_key_lock.clone() {
-        Some(k) if k == key => {
-            setup_key_lock.take();
-            let owner = User::new(
-                owner_setup.username,
-                &owner_setup.password,
-                true,
-                false,
-                UserPermission::default(),
-            );
-            state
-                .users_manager
-                .write()
-                .await
-                .add_user(owner.clone(), CausedBy::System)
-                .await?;
-            Ok(Json(LoginReply {
-                token: owner.create_jwt()?,
-                user: owner.into(),
-            }))
-        }
-        None => Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("Setup key already used."),
-        }),
-        Some(_) => Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("Invalid setup key."),
-        }),
-    }
+    create_owner(&state, owner_setup).await
+}
+
+/// Older clients pass the setup key in the path
+#[utoipa::path(
+    post,
+    path = "/setup/{key}",
+    tag = "setup",
+    params(
+        ("key" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn setup_owner_with_key(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(key): Path<String>,
+    Json(mut owner_setup): Json<OwnerSetup>,
+) -> Result<Json<LoginReply>, Error> {
+    owner_setup.setup_key = Some(key);
+    create_owner(&state, owner_setup).await
 }
 
 pub fn get_setup_route(state: AppState) -> Router {
     Router::new()
-        .route("/setup/:key", axum::routing::post(setup_owner))
+        .route("/setup/status", get(get_setup_status))
+        .route("/setup/owner", axum::routing::post(setup_owner))
+        .route("/setup/:key", axum::routing::post(setup_owner_with_key))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::event_broadcaster::EventBroadcaster;
+
+    fn owner_setup(setup_key: Option<&str>) -> OwnerSetup {
+        OwnerSetup {
+            username: "owner".to_string(),
+            password: "hunter22".to_string(),
+            setup_key: setup_key.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_owner_needs_the_setup_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx, HashMap::new(), dir.path().join("users.json"));
+        let mut setup_key = Some("key".to_string());
+
+        // no users yet, still not without the key
+        let error = create_owner_with(&mut users_manager, &mut setup_key, owner_setup(None))
+            .await
+            .unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::Unauthorized));
+        let error = create_owner_with(
+            &mut users_manager,
+            &mut setup_key,
+            owner_setup(Some("wrong")),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::PermissionDenied));
+        assert!(users_manager.as_ref().is_empty());
+
+        let reply = create_owner_with(&mut users_manager, &mut setup_key, owner_setup(Some("key")))
+            .await
+            .unwrap();
+        assert!(reply.user.is_owner);
+        assert_eq!(setup_key, None);
+
+        // whatever the key, once there is an owner
+        for key in [Some("key"), Some("wrong"), None] {
+            let error = create_owner_with(&mut users_manager, &mut setup_key, owner_setup(key))
+                .await
+                .unwrap_err();
+            assert!(matches!(error.kind, ErrorKind::Conflict));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_used_setup_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut users_manager =
+            UsersManager::new(tx, HashMap::new(), dir.path().join("users.json"));
+        // taken by the desktop app creating the owner, which was deleted since
+        let mut setup_key = None;
+        let error = create_owner_with(&mut users_manager, &mut setup_key, owner_setup(Some("key")))
+            .await
+            .unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::PermissionDenied));
+        assert!(users_manager.as_ref().is_empty());
+    }
+}
@@ -1,9 +1,26 @@
-use axum::{routing::get, Json, Router};
+use axum::{
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
 use sysinfo::{CpuExt, CpuRefreshKind, DiskExt, SystemExt};
+use ts_rs::TS;
+use utoipa::ToSchema;
 
 use tokio::time::sleep;
 
+use crate::auth::user::UserAction;
+use crate::capacity::Capacity;
+use crate::disk_usage::{volume_space, VolumeSpace};
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event};
+use crate::features::{enabled_features, Feature};
+use crate::global_settings::{DaemonSettings, DaemonSettingsPatch};
+use crate::java::{detect_runtimes, path_to_java_runtimes, JavaRuntime};
+use crate::prelude::path_to_instances;
 use crate::AppState;
 
 // Since MemInfo is not serializable, we need to create a new struct that is serializable.
@@ -13,6 +30,14 @@ pub struct MemInfo {
     free: u64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/system/ram",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
 pub async fn get_ram(axum::extract::State(state): axum::extract::State<AppState>) -> Json<MemInfo> {
     let mut sys = state.system.lock().await;
     sys.refresh_memory();
@@ -29,6 +54,14 @@ pub struct DiskInfo {
     free: u64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/system/disk",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
 pub async fn get_disk(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Json<DiskInfo> {
@@ -41,12 +74,42 @@ pub async fn get_disk(
     })
 }
 
+/// Space of the volume instances are stored on, which is what fills up as worlds grow
+#[utoipa::path(
+    get,
+    path = "/system/disk/instances",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
+pub async fn get_instances_disk(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<VolumeSpace>, Error> {
+    let mut sys = state.system.lock().await;
+    volume_space(&mut sys, path_to_instances())
+        .map(Json)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No mounted disk holds the instances directory"),
+        })
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CPUInfo {
     pub cpu_speed: u64,
     pub cpu_load: f32,
 }
 
+#[utoipa::path(
+    get,
+    path = "/system/cpu",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
 pub async fn get_cpu_info(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Json<CPUInfo> {
@@ -63,10 +126,254 @@ pub async fn get_cpu_info(
     })
 }
 
+#[utoipa::path(
+    get,
+    path = "/system/features",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+    ),
+)]
+pub async fn get_features() -> Json<Vec<Feature>> {
+    Json(enabled_features())
+}
+
+/// Java runtimes installed on the host, including those lodestone downloaded
+#[utoipa::path(
+    get,
+    path = "/system/java",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_java_runtimes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<JavaRuntime>>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(detect_runtimes(&path_to_java_runtimes()).await))
+}
+
+/// Shuts the daemon down like SIGTERM does, answered before the shutdown starts
+#[utoipa::path(
+    post,
+    path = "/system/shutdown",
+    tag = "system",
+    responses(
+        (status = 202, description = "The shutdown started"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn shutdown(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<StatusCode, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can shut the daemon down"),
+        });
+    }
+    // after a moment so the answer reaches the requester before the server stops taking requests
+    tokio::spawn(async move {
+        sleep(tokio::time::Duration::from_millis(100)).await;
+        state.shutdown.cancel();
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    get,
+    path = "/system/settings",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_daemon_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DaemonSettings>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can view the daemon settings"),
+        });
+    }
+    Ok(Json(state.global_settings.lock().await.daemon_settings()))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DaemonSettingsUpdate {
+    pub settings: DaemonSettings,
+    /// Settings the update changed that only apply once the daemon restarts
+    pub restart_required: Vec<String>,
+}
+
+/// Changes the settings given, the others are kept. A field set to null goes back to its default.
+///
+/// `remote_backup_secret` can only be written, it is never part of the settings answered
+#[utoipa::path(
+    patch,
+    path = "/system/settings",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn patch_daemon_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(mut patch): Json<DaemonSettingsPatch>,
+) -> Result<Json<DaemonSettingsUpdate>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.can_perform_action(&UserAction::ManageDaemon) {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can change the daemon settings"),
+        });
+    }
+    let remote_backup_secret = patch.remote_backup_secret.take();
+    let (old_settings, settings) = {
+        let mut global_settings = state.global_settings.lock().await;
+        let old_settings = global_settings.daemon_settings();
+        let settings = patch.apply(&old_settings)?;
+        global_settings
+            .set_daemon_settings(settings.clone())
+            .await?;
+        if let Some(secret) = remote_backup_secret.clone() {
+            global_settings.set_remote_backup_secret(secret).await?;
+        }
+        (old_settings, settings)
+    };
+    let mut changed = settings.changed(&old_settings);
+    // audited by name only, the value is never recorded
+    if remote_backup_secret.is_some() {
+        changed.push("remote_backup_secret".to_string());
+    }
+    // the upload limit is read on each upload and the backup defaults on each new instance, the
+    // others are applied here
+    if changed.iter().any(|name| name == "port_range") {
+        // ports allocated outside the new range are kept until their instance lets go of them
+        state
+            .port_manager
+            .lock()
+            .await
+            .set_range(settings.port_range);
+    }
+    state
+        .cors_origins
+        .set(settings.cors_allowed_origins.clone());
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let old_values = serde_json::to_value(&old_settings).unwrap_or_default();
+    let new_values = serde_json::to_value(&settings).unwrap_or_default();
+    for name in &changed {
+        state
+            .audit_log
+            .record_daemon_setting(
+                caused_by.clone(),
+                name,
+                old_values.get(name).cloned(),
+                new_values.get(name).cloned(),
+            )
+            .await;
+    }
+    if !changed.is_empty() {
+        state
+            .event_broadcaster
+            .send(Event::new_daemon_settings_event(
+                settings.clone(),
+                changed.clone(),
+                caused_by,
+            ));
+    }
+    Ok(Json(DaemonSettingsUpdate {
+        settings,
+        restart_required: changed
+            .into_iter()
+            .filter(|name| DaemonSettings::RESTART_REQUIRED.contains(&name.as_str()))
+            .collect(),
+    }))
+}
+
+/// Counters about the daemon as a whole, the metrics of an instance are under
+/// `/instance/{uuid}/metrics`
+#[derive(Serialize, TS, ToSchema)]
+#[ts(export)]
+pub struct DaemonMetrics {
+    /// Requests served on the deprecated unprefixed paths since the core started, see
+    /// `/api/versions`
+    pub legacy_api_requests: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/system/metrics",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success", body = DaemonMetrics),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_daemon_metrics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DaemonMetrics>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(DaemonMetrics {
+        legacy_api_requests: state.legacy_traffic.count(),
+    }))
+}
+
+/// Running instances and their reserved heap against the limits of the daemon settings, a start
+/// past either is refused
+#[utoipa::path(
+    get,
+    path = "/system/capacity",
+    tag = "system",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_capacity(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Capacity>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(state.admission.capacity().await))
+}
+
 pub fn get_system_routes(state: AppState) -> Router {
     Router::new()
         .route("/system/ram", get(get_ram))
         .route("/system/disk", get(get_disk))
+        .route("/system/disk/instances", get(get_instances_disk))
         .route("/system/cpu", get(get_cpu_info))
+        .route("/system/features", get(get_features))
+        .route("/system/java", get(get_java_runtimes))
+        .route("/system/capacity", get(get_capacity))
+        .route("/system/metrics", get(get_daemon_metrics))
+        .route("/system/shutdown", post(shutdown))
+        .route(
+            "/system/settings",
+            get(get_daemon_settings).patch(patch_daemon_settings),
+        )
         .with_state(state)
 }
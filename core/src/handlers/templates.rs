@@ -0,0 +1,126 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    templates::{Template, TemplateConfig},
+    traits::{
+        t_configurable::TConfigurable,
+        t_server::{State, TServer},
+    },
+    types::Snowflake,
+    AppState,
+};
+
+use super::util::minecraft_instance;
+
+/// Templates new Minecraft instances can be created from
+#[utoipa::path(
+    get,
+    path = "/templates",
+    tag = "templates",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_templates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Template>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    Ok(Json(state.templates.list().await))
+}
+
+/// Captures the settings and the chosen files of a stopped Minecraft instance into a template
+#[utoipa::path(
+    post,
+    path = "/templates",
+    tag = "templates",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_template(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<TemplateConfig>,
+) -> Result<Json<Template>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let safe_mode = state.global_settings.lock().await.safe_mode();
+    requester.try_action(&UserAction::CreateInstance, safe_mode)?;
+    requester.try_action(
+        &UserAction::AccessSetting(config.instance_uuid.clone()),
+        safe_mode,
+    )?;
+    requester.try_action(
+        &UserAction::ReadInstanceFile(config.instance_uuid.clone()),
+        safe_mode,
+    )?;
+    let instance = minecraft_instance(&state, &config.instance_uuid)?;
+    // the files and settings of a running server can change while they're copied
+    if instance.state().await != State::Stopped {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance must be stopped before it's captured into a template"),
+        });
+    }
+    let settings = instance.template_settings().await?;
+    state
+        .templates
+        .create(
+            config,
+            instance.flavour_kind().await,
+            instance.version().await,
+            settings,
+            &instance.path().await,
+        )
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/templates/{template_id}",
+    tag = "templates",
+    params(
+        ("template_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_template(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(template_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Template>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::CreateInstance,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    state.templates.delete(&template_id).await.map(Json)
+}
+
+pub fn get_templates_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/templates", get(get_templates).post(create_template))
+        .route("/templates/:template_id", delete(delete_template))
+        .with_state(state)
+}
@@ -1,17 +1,25 @@
 use crate::{
     auth::{
+        access_token::{AccessTokenInfo, TokenScopes},
         jwt_token::JwtToken,
-        permission::UserPermission,
-        user::{PublicUser, User, UserAction},
+        login_limiter::LoginRejection,
+        permission::{InstanceCapability, PermissionPreset, UserPermission},
+        user::{PublicUser, User, UserAction, UserRole, UsersManager},
         user_id::UserId,
     },
     error::{Error, ErrorKind},
-    events::CausedBy,
+    events::{CausedBy, Event, EventInner, UserEvent, UserEventInner},
+    http_config::ClientAddr,
+    types::{InstanceUuid, Snowflake},
     AppState,
 };
 
+use std::time::Duration;
+
 use axum::{
     extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Json, Router,
 };
@@ -26,8 +34,44 @@ use ts_rs::TS;
 pub struct NewUser {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub role: Option<UserRole>,
+}
+
+/// Rejects changes to the owner, and to admins unless the requester is the owner
+fn try_manage(requester: &User, target: &User) -> Result<(), Error> {
+    if target.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("The owner account is protected"),
+        });
+    }
+    if target.is_admin && !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage admins"),
+        });
+    }
+    Ok(())
+}
+
+fn get_target(users_manager: &UsersManager, uid: &UserId) -> Result<User, Error> {
+    users_manager.get_user(uid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("User not found"),
+    })
 }
 
+#[utoipa::path(
+    post,
+    path = "/user",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn new_user(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -39,11 +83,13 @@ pub async fn new_user(
         &UserAction::ManageUser,
         state.global_settings.lock().await.safe_mode(),
     )?;
+    let role = config.role.unwrap_or(UserRole::User);
+    requester.can_assign_role(None, role)?;
     let user = User::new(
         config.username,
         config.password,
         false,
-        false,
+        role == UserRole::Admin,
         UserPermission::default(),
     );
     let caused_by = CausedBy::User {
@@ -59,6 +105,19 @@ pub async fn new_user(
     }))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/user/{uid}",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn delete_user(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -77,7 +136,9 @@ pub async fn delete_user(
             source: eyre!("You cannot delete yourself"),
         });
     }
+    try_manage(&requester, &get_target(&users_manager, &uid)?)?;
 
+    // the user's permissions and token secret go with them, so their tokens stop working
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
@@ -88,6 +149,22 @@ pub async fn delete_user(
     Ok(Json(json!("ok")))
 }
 
+/// Invalidates every session token of the user by rotating their token secret.
+///
+/// Access tokens aren't signed with the secret and stay valid until revoked
+#[utoipa::path(
+    post,
+    path = "/user/logout/{uid}",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn logout(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -103,6 +180,9 @@ pub async fn logout(
             source: eyre!("You are not authorized to logout other users"),
         });
     }
+    if requester.uid != uid {
+        try_manage(&requester, &get_target(&users_manager, &uid)?)?;
+    }
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username,
@@ -113,6 +193,43 @@ pub async fn logout(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    post,
+    path = "/users/self/logout_all",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn logout_self(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username,
+    };
+    users_manager.logout_user(requester.uid, caused_by).await?;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/user/{uid}/update_perm",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn update_permissions(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -136,6 +253,437 @@ pub async fn update_permissions(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    get,
+    path = "/users/{uid}/permissions",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn get_permissions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<UserPermission>, Error> {
+    let users_manager = state.users_manager.read().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    if requester.uid != uid {
+        requester.try_action(
+            &UserAction::ManagePermission,
+            state.global_settings.lock().await.safe_mode(),
+        )?;
+    }
+    Ok(Json(get_target(&users_manager, &uid)?.permissions))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct InstancePermissionChange {
+    pub capability: InstanceCapability,
+    pub instance_uuid: InstanceUuid,
+    pub granted: bool,
+}
+
+/// Grants or revokes per-instance capabilities one instance at a time.
+///
+/// Only granting checks the instance exists, so leftovers of deleted instances can be revoked
+#[utoipa::path(
+    put,
+    path = "/users/{uid}/permissions",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn patch_instance_permissions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(changes): Json<Vec<InstancePermissionChange>>,
+) -> Result<Json<UserPermission>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let target = get_target(&users_manager, &uid)?;
+    let new_permissions = apply_instance_permission_changes(&requester, target, changes, |uuid| {
+        state.instances.contains_key(uuid)
+    })?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    // sessions of the user re-check their permissions on the resulting event
+    users_manager
+        .update_permissions(&uid, new_permissions.clone(), caused_by)
+        .await?;
+    Ok(Json(new_permissions))
+}
+
+/// The permissions `target` ends up with after `changes`, refused if `requester` can't make them
+fn apply_instance_permission_changes(
+    requester: &User,
+    mut target: User,
+    changes: Vec<InstancePermissionChange>,
+    instance_exists: impl Fn(&InstanceUuid) -> bool,
+) -> Result<UserPermission, Error> {
+    if requester.uid == target.uid {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You cannot change your own permissions"),
+        });
+    }
+    let mut new_permissions = target.permissions.clone();
+    for change in changes {
+        let instances = new_permissions.instances_mut(change.capability);
+        if change.granted {
+            if !instance_exists(&change.instance_uuid) {
+                return Err(Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("Instance {} not found", change.instance_uuid),
+                });
+            }
+            instances.insert(change.instance_uuid);
+        } else {
+            instances.remove(&change.instance_uuid);
+        }
+    }
+    // checks the requester outranks the target and may grant what's being granted
+    requester.update_permission(&mut target, new_permissions)?;
+    Ok(target.permissions)
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct ApplyPermissionPreset {
+    pub preset: PermissionPreset,
+    pub instances: Vec<InstanceUuid>,
+}
+
+/// Replaces a user's permissions with a preset scoped to `instances`
+#[utoipa::path(
+    put,
+    path = "/users/{uid}/permissions/preset",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn apply_permission_preset(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<ApplyPermissionPreset>,
+) -> Result<Json<UserPermission>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManagePermission,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if requester.uid == uid {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("You cannot change your own permissions"),
+        });
+    }
+    if let Some(missing) = request
+        .instances
+        .iter()
+        .find(|instance_uuid| !state.instances.contains_key(*instance_uuid))
+    {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance {} not found", missing),
+        });
+    }
+    let mut target = get_target(&users_manager, &uid)?;
+    requester.update_permission(&mut target, request.preset.permissions(request.instances))?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager
+        .update_permissions(&uid, target.permissions.clone(), caused_by)
+        .await?;
+    Ok(Json(target.permissions))
+}
+
+/// Access tokens can't manage access tokens, or a scoped token could mint an unscoped one
+fn try_session_auth(users_manager: &UsersManager, token: &str) -> Result<User, Error> {
+    let requester = users_manager.try_auth_or_err(token)?;
+    if requester.token_scopes.is_some() {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Access tokens must be managed from a login session"),
+        });
+    }
+    Ok(requester)
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export)]
+pub struct NewAccessToken {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: TokenScopes,
+    /// Unix timestamp in seconds, the token never expires when unset
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct NewAccessTokenReply {
+    /// The full token, it can't be retrieved again
+    pub token: String,
+    pub info: AccessTokenInfo,
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/self/tokens",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_access_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<NewAccessToken>,
+) -> Result<Json<NewAccessTokenReply>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = try_session_auth(&users_manager, &token)?;
+    if config.name.trim().is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Access token name cannot be empty"),
+        });
+    }
+    if config.expires_at.map_or(false, |expires_at| {
+        expires_at <= chrono::Utc::now().timestamp()
+    }) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Expiry must be in the future"),
+        });
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let (access_token, token) = users_manager
+        .create_access_token(
+            &requester.uid,
+            config.name,
+            config.scopes,
+            config.expires_at,
+            caused_by,
+        )
+        .await?;
+    Ok(Json(NewAccessTokenReply {
+        token,
+        info: (&access_token).into(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/self/tokens",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn list_access_tokens(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<AccessTokenInfo>>, Error> {
+    let users_manager = state.users_manager.read().await;
+    let requester = try_session_auth(&users_manager, &token)?;
+    Ok(Json(
+        requester
+            .access_tokens
+            .iter()
+            .map(AccessTokenInfo::from)
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/self/tokens/{token_id}",
+    tag = "users",
+    params(
+        ("token_id" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn revoke_access_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(token_id): Path<Snowflake>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = try_session_auth(&users_manager, &token)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager
+        .revoke_access_token(&requester.uid, token_id, caused_by)
+        .await?;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/user/{uid}/role",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_user_role(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(new_role): Json<UserRole>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManageUser,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let target = get_target(&users_manager, &uid)?;
+    requester.can_assign_role(Some(&target), new_role)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager.set_role(uid, new_role, caused_by).await?;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/user/{uid}/disabled",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    request_body = bool,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn set_user_disabled(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(is_disabled): Json<bool>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManageUser,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    if uid == requester.uid {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("You cannot disable yourself"),
+        });
+    }
+    try_manage(&requester, &get_target(&users_manager, &uid)?)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager
+        .set_disabled(uid, is_disabled, caused_by)
+        .await?;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/{uid}/password_reset",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn require_password_reset(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManageUser,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    try_manage(&requester, &get_target(&users_manager, &uid)?)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager.require_password_reset(uid, caused_by).await?;
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/user/info",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_self_info(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -150,6 +698,19 @@ pub async fn get_self_info(
     ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/user/{uid}",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_user_info(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -175,6 +736,20 @@ pub async fn get_user_info(
     ))
 }
 
+#[utoipa::path(
+    put,
+    path = "/user/{uid}/rename",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    request_body = String,
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn rename_user(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -191,6 +766,9 @@ pub async fn rename_user(
             source: eyre!("You are not authorized to rename other users"),
         });
     }
+    if requester.uid != uid {
+        try_manage(&requester, &get_target(&users_manager, &uid)?)?;
+    }
 
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
@@ -207,11 +785,28 @@ pub struct ChangePasswordConfig {
     new_password: String,
 }
 
+/// Changes a password, which also logs the user out everywhere.
+///
+/// Users changing their own password from a session get a fresh token back so they stay
+/// logged in here
+#[utoipa::path(
+    put,
+    path = "/user/{uid}/password",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn change_password(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
     Json(config): Json<ChangePasswordConfig>,
-) -> Result<Json<()>, Error> {
+) -> Result<Json<Option<JwtToken>>, Error> {
     let mut users_manager = state.users_manager.write().await;
 
     let requester = users_manager.try_auth_or_err(&token)?;
@@ -222,7 +817,12 @@ pub async fn change_password(
             source: eyre!("You are not authorized to change other users password"),
         });
     }
+    if requester.uid != config.uid {
+        try_manage(&requester, &get_target(&users_manager, &config.uid)?)?;
+    }
 
+    // an access token mustn't trade itself for an unscoped session token
+    let is_self_change = requester.uid == config.uid && requester.token_scopes.is_none();
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username,
@@ -243,7 +843,12 @@ pub async fn change_password(
         )
         .await?;
 
-    Ok(Json(()))
+    if !is_self_change {
+        return Ok(Json(None));
+    }
+    Ok(Json(Some(
+        get_target(&users_manager, &config.uid)?.create_jwt()?,
+    )))
 }
 
 #[derive(Serialize, TS)]
@@ -253,31 +858,174 @@ pub struct LoginReply {
     pub user: PublicUser,
 }
 
+/// Emits a failed or locked out login, under the user it targeted if there is one
+fn send_login_event(
+    state: &AppState,
+    users_manager: &UsersManager,
+    username: &str,
+    user_event_inner: UserEventInner,
+) {
+    state.event_broadcaster.send(Event {
+        event_inner: EventInner::UserEvent(UserEvent {
+            user_id: users_manager
+                .get_user_by_username(username)
+                .map(|user| user.uid)
+                .unwrap_or_else(|| UserId::from(String::new())),
+            user_event_inner,
+        }),
+        details: "".to_string(),
+        snowflake: Snowflake::default(),
+        caused_by: CausedBy::System,
+    });
+}
+
+fn too_many_requests(retry_after: Duration, message: &str) -> Response {
+    (
+        [(
+            header::RETRY_AFTER,
+            retry_after.as_secs().max(1).to_string(),
+        )],
+        Error {
+            kind: ErrorKind::RateLimited,
+            source: eyre!("{}", message),
+        },
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/login",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn login(
     axum::extract::State(state): axum::extract::State<AppState>,
+    client: ClientAddr,
     AuthBasic((username, password)): AuthBasic,
-) -> Result<Json<LoginReply>, Error> {
-    if let Some(password) = password {
-        let users_manager = state.users_manager.read().await;
-
-        Ok(Json(LoginReply {
-            token: users_manager.login(&username, &password)?,
-            user: users_manager
-                .get_user_by_username(&username)
-                .ok_or_else(|| Error {
-                    kind: ErrorKind::NotFound,
-                    source: eyre!("User not found"),
-                })?
-                .into(),
-        }))
-    } else {
-        Err(Error {
+) -> Result<Json<LoginReply>, Response> {
+    let password = password.ok_or_else(|| {
+        Error {
             kind: ErrorKind::BadRequest,
             source: eyre!("You must provide a password"),
-        })
+        }
+        .into_response()
+    })?;
+    let ip = client.ip;
+    let source_ip = ip.map(|ip| ip.to_string());
+    let limits = state.global_settings.lock().await.login_rate_limit();
+    let users_manager = state.users_manager.read().await;
+
+    match state.login_limiter.check(ip, &username, &limits).await {
+        Ok(()) => {}
+        Err(LoginRejection::RateLimited { retry_after }) => {
+            return Err(too_many_requests(
+                retry_after,
+                "Too many login attempts, try again later",
+            ))
+        }
+        Err(LoginRejection::LockedOut { retry_after }) => {
+            send_login_event(
+                &state,
+                &users_manager,
+                &username,
+                UserEventInner::LoginLockedOut {
+                    username: username.clone(),
+                    source_ip,
+                    retry_after: retry_after.as_secs(),
+                },
+            );
+            return Err(too_many_requests(
+                retry_after,
+                "This account is temporarily locked after too many failed logins",
+            ));
+        }
     }
+
+    let token = match users_manager.login(&username, &password) {
+        Ok(token) => token,
+        Err(e) => {
+            send_login_event(
+                &state,
+                &users_manager,
+                &username,
+                UserEventInner::LoginFailed {
+                    username: username.clone(),
+                    source_ip: source_ip.clone(),
+                },
+            );
+            if let Some(lockout) = state.login_limiter.record_failure(&username, &limits).await {
+                send_login_event(
+                    &state,
+                    &users_manager,
+                    &username,
+                    UserEventInner::LoginLockedOut {
+                        username: username.clone(),
+                        source_ip,
+                        retry_after: lockout.as_secs(),
+                    },
+                );
+            }
+            return Err(e.into_response());
+        }
+    };
+    state.login_limiter.record_success(&username).await;
+    Ok(Json(LoginReply {
+        token,
+        user: users_manager
+            .get_user_by_username(&username)
+            .ok_or_else(|| {
+                Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("User not found"),
+                }
+                .into_response()
+            })?
+            .into(),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/{uid}/unlock",
+    tag = "users",
+    params(
+        ("uid" = String, Path),
+    ),
+    responses(
+        (status = 200, description = "Success", body = bool),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn unlock_user(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<bool>, Error> {
+    let users_manager = state.users_manager.read().await;
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(
+        &UserAction::ManageUser,
+        state.global_settings.lock().await.safe_mode(),
+    )?;
+    let target = get_target(&users_manager, &uid)?;
+    Ok(Json(state.login_limiter.unlock(&target.username).await))
 }
 
+#[utoipa::path(
+    get,
+    path = "/user/list",
+    tag = "users",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+    security(("bearer" = [])),
+)]
 pub async fn get_all_users(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -305,13 +1053,122 @@ pub fn get_user_routes(state: AppState) -> Router {
     Router::new()
         .route("/user/list", get(get_all_users))
         .route("/user", post(new_user))
+        .route("/users", get(get_all_users).post(new_user))
         .route("/user/:uid", get(get_user_info))
         .route("/user/:uid", delete(delete_user))
         .route("/user/:uid/update_perm", put(update_permissions))
         .route("/user/info", get(get_self_info))
         .route("/user/:uid/rename", put(rename_user))
         .route("/user/:uid/password", put(change_password))
+        .route("/user/:uid/role", put(set_user_role))
+        .route(
+            "/users/self/tokens",
+            get(list_access_tokens).post(create_access_token),
+        )
+        .route("/users/self/tokens/:token_id", delete(revoke_access_token))
+        .route(
+            "/users/:uid/permissions",
+            get(get_permissions).put(patch_instance_permissions),
+        )
+        .route(
+            "/users/:uid/permissions/preset",
+            put(apply_permission_preset),
+        )
+        .route("/user/:uid/disabled", put(set_user_disabled))
+        .route("/user/:uid/password_reset", post(require_password_reset))
         .route("/user/login", post(login))
+        .route("/user/:uid/unlock", post(unlock_user))
         .route("/user/logout/:uid", post(logout))
+        .route("/users/self/logout_all", post(logout_self))
+        .route("/users/:uid/logout_all", post(logout))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(
+        capability: InstanceCapability,
+        instance_uuid: &InstanceUuid,
+        granted: bool,
+    ) -> InstancePermissionChange {
+        InstancePermissionChange {
+            capability,
+            instance_uuid: instance_uuid.clone(),
+            granted,
+        }
+    }
+
+    #[test]
+    fn test_instance_permission_changes() {
+        let existing = InstanceUuid::default();
+        let deleted = InstanceUuid::default();
+        let exists = |uuid: &InstanceUuid| *uuid == existing;
+        let admin = User::new(
+            "admin".to_string(),
+            "password",
+            false,
+            true,
+            UserPermission::default(),
+        );
+        let mut permissions = UserPermission::default();
+        permissions.can_view_instance.insert(deleted.clone());
+        let user = User::new("user".to_string(), "password", false, false, permissions);
+
+        let granted = apply_instance_permission_changes(
+            &admin,
+            user.clone(),
+            vec![
+                change(InstanceCapability::Start, &existing, true),
+                // a leftover of a deleted instance can still be revoked
+                change(InstanceCapability::View, &deleted, false),
+            ],
+            exists,
+        )
+        .unwrap();
+        assert!(granted.can_start_instance.contains(&existing));
+        assert!(granted.can_view_instance.is_empty());
+
+        let e = apply_instance_permission_changes(
+            &admin,
+            user.clone(),
+            vec![change(InstanceCapability::Start, &deleted, true)],
+            exists,
+        )
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::NotFound));
+
+        // unsafe capabilities are the owner's to grant
+        let e = apply_instance_permission_changes(
+            &admin,
+            user.clone(),
+            vec![change(InstanceCapability::WriteFile, &existing, true)],
+            exists,
+        )
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::PermissionDenied));
+
+        // nobody escalates themselves or someone of the same rank
+        let mut other_admin = admin.clone();
+        other_admin.uid = UserId::default();
+        for target in [admin.clone(), other_admin] {
+            let e = apply_instance_permission_changes(
+                &admin,
+                target,
+                vec![change(InstanceCapability::Start, &existing, true)],
+                exists,
+            )
+            .unwrap_err();
+            assert!(matches!(e.kind, ErrorKind::PermissionDenied));
+        }
+        let e = apply_instance_permission_changes(
+            &user,
+            admin.clone(),
+            vec![change(InstanceCapability::View, &existing, true)],
+            exists,
+        )
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::PermissionDenied));
+    }
+}
@@ -1,6 +1,45 @@
 use color_eyre::eyre::Context;
+use dashmap::DashMap;
 
-use crate::error::Error;
+use crate::{
+    audit::{audit_value, AuditTarget},
+    auth::user::User,
+    error::{Error, ErrorCode},
+    events::CausedBy,
+    implementations::minecraft::MinecraftInstance,
+    prelude::GameInstance,
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::slugify,
+    AppState,
+};
+
+/// Checks a console command against the user's command policy, a refused one is audited
+pub async fn check_console_command(
+    state: &AppState,
+    user: &User,
+    uuid: &InstanceUuid,
+    command: &str,
+) -> Result<(), Error> {
+    let refused = match user.check_console_command(uuid, command) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    state
+        .audit_log
+        .record(
+            uuid,
+            CausedBy::User {
+                user_id: user.uid.clone(),
+                user_name: user.username.clone(),
+            },
+            AuditTarget::RefusedCommand,
+            None,
+            audit_value(command, false),
+        )
+        .await;
+    Err(refused)
+}
 
 pub fn parse_bearer_token(token: &str) -> Option<String> {
     let mut split = token.split_ascii_whitespace();
@@ -23,3 +62,233 @@ pub fn decode_base64(input: &str) -> Result<String, Error> {
     )
     .context("Invalid UTF-8")?)
 }
+
+/// The instance as a Minecraft instance, for endpoints other games have no equivalent of
+pub fn minecraft_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<MinecraftInstance, Error> {
+    match state.instances.get(uuid).as_deref() {
+        Some(GameInstance::MinecraftInstance(instance)) => Ok(instance.clone()),
+        Some(_) => Err(Error::coded(
+            ErrorCode::UnsupportedGameType,
+            "Only Minecraft instances support this",
+        )),
+        None => Err(instance_not_found()),
+    }
+}
+
+/// Clones the handle out of the map, so the map's shard isn't locked while it is awaited on.
+///
+/// A guard from `DashMap::get` held across an await stalls every write to its shard, e.g. an
+/// instance being created or deleted, until the instance answers
+pub fn cloned_handle<V: Clone>(map: &DashMap<InstanceUuid, V>, uuid: &InstanceUuid) -> Option<V> {
+    map.get(uuid).map(|entry| entry.value().clone())
+}
+
+/// Clones every handle out of the map, for handlers going over all instances
+pub fn cloned_handles<V: Clone>(map: &DashMap<InstanceUuid, V>) -> Vec<(InstanceUuid, V)> {
+    map.iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect()
+}
+
+/// The instance, cloned out of the instance map
+pub fn game_instance(state: &AppState, uuid: &InstanceUuid) -> Result<GameInstance, Error> {
+    cloned_handle(&state.instances, uuid).ok_or_else(instance_not_found)
+}
+
+pub fn instance_not_found() -> Error {
+    Error::coded(ErrorCode::InstanceNotFound, "Instance not found")
+}
+
+/// Whether two instance names would be told apart by their slugs. Names without any
+/// alphanumeric slug to the same empty string, those are only compared as is
+fn same_name(a: &str, b: &str) -> bool {
+    let slug = slugify(a);
+    if slug.is_empty() {
+        a == b
+    } else {
+        slug == slugify(b)
+    }
+}
+
+/// The instances whose name or slug matches `name`, in no particular order
+pub async fn instances_named(state: &AppState, name: &str) -> Vec<InstanceUuid> {
+    let mut matches = Vec::new();
+    for (uuid, instance) in cloned_handles(&state.instances) {
+        if same_name(&instance.name().await, name) {
+            matches.push(uuid);
+        }
+    }
+    matches
+}
+
+/// Fails with a conflict if another instance than `except` already goes by `name`
+pub async fn ensure_name_available(
+    state: &AppState,
+    name: &str,
+    except: Option<&InstanceUuid>,
+) -> Result<(), Error> {
+    let taken = instances_named(state, name)
+        .await
+        .into_iter()
+        .any(|uuid| Some(&uuid) != except);
+    if taken {
+        return Err(Error::coded(
+            ErrorCode::InstanceNameTaken,
+            format!("An instance named {} already exists", name),
+        )
+        .with_details(serde_json::json!({ "name": name })));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hung_instance_does_not_block_the_map() {
+        let map: Arc<DashMap<InstanceUuid, Arc<Mutex<u32>>>> = Arc::new(DashMap::new());
+        let hung = InstanceUuid::default();
+        map.insert(hung.clone(), Arc::new(Mutex::new(0)));
+        for _ in 0..16 {
+            map.insert(InstanceUuid::default(), Arc::new(Mutex::new(0)));
+        }
+
+        // a start in flight, holding its instance for longer than the test waits
+        let starting = cloned_handle(&map, &hung).unwrap();
+        let guard = starting.lock_owned().await;
+
+        let mut tasks = Vec::new();
+        for i in 0..64 {
+            let map = map.clone();
+            let hung = hung.clone();
+            tasks.push(tokio::spawn(async move {
+                match i % 3 {
+                    // list, skipping the instance that is busy
+                    0 => {
+                        for (uuid, instance) in cloned_handles(&map) {
+                            if uuid != hung {
+                                *instance.lock().await += 1;
+                            }
+                        }
+                    }
+                    // info and fs of other instances
+                    1 => {
+                        let uuid = map
+                            .iter()
+                            .map(|entry| entry.key().clone())
+                            .find(|uuid| *uuid != hung)
+                            .unwrap();
+                        *cloned_handle(&map, &uuid).unwrap().lock().await += 1;
+                    }
+                    // instances created and deleted meanwhile
+                    _ => {
+                        let uuid = InstanceUuid::default();
+                        map.insert(uuid.clone(), Arc::new(Mutex::new(0)));
+                        map.remove(&uuid);
+                    }
+                }
+            }));
+        }
+        tokio::time::timeout(Duration::from_secs(5), futures::future::join_all(tasks))
+            .await
+            .expect("a handler blocked on another instance's long operation");
+        assert_eq!(*guard, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_map_access() {
+        let map: Arc<DashMap<InstanceUuid, Arc<Mutex<u32>>>> = Arc::new(DashMap::new());
+        let busy = InstanceUuid::default();
+        map.insert(busy.clone(), Arc::new(Mutex::new(0)));
+        for _ in 0..32 {
+            map.insert(InstanceUuid::default(), Arc::new(Mutex::new(0)));
+        }
+        let operation = cloned_handle(&map, &busy).unwrap().lock_owned().await;
+
+        // every task ends up waiting on the busy instance, the way a handler waits on an
+        // instance in the middle of a start
+        let mut tasks = Vec::new();
+        for i in 0..256 {
+            let map = map.clone();
+            let busy = busy.clone();
+            tasks.push(tokio::spawn(async move {
+                match i % 4 {
+                    // list
+                    0 => {
+                        for (_, instance) in cloned_handles(&map) {
+                            *instance.lock().await += 1;
+                        }
+                    }
+                    // get
+                    1 => {
+                        *cloned_handle(&map, &busy).unwrap().lock().await += 1;
+                    }
+                    // create
+                    2 => {
+                        map.insert(InstanceUuid::default(), Arc::new(Mutex::new(0)));
+                        *cloned_handle(&map, &busy).unwrap().lock().await += 1;
+                    }
+                    // delete one of the instances created meanwhile
+                    _ => {
+                        let created = map
+                            .iter()
+                            .map(|entry| entry.key().clone())
+                            .find(|uuid| *uuid != busy);
+                        if let Some(created) = created {
+                            map.remove(&created);
+                        }
+                        *cloned_handle(&map, &busy).unwrap().lock().await += 1;
+                    }
+                }
+            }));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // a guard held across any of those awaits would keep the writes below waiting on the
+        // shard it locks, for as long as the busy instance stays busy
+        let writes = tokio::task::spawn_blocking({
+            let map = map.clone();
+            let busy = busy.clone();
+            move || {
+                for (uuid, _) in cloned_handles(&map) {
+                    if uuid == busy {
+                        continue;
+                    }
+                    if let Some((uuid, instance)) = map.remove(&uuid) {
+                        map.insert(uuid, instance);
+                    }
+                }
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), writes)
+            .await
+            .expect("a map guard was held across an await")
+            .unwrap();
+
+        drop(operation);
+        tokio::time::timeout(Duration::from_secs(5), futures::future::join_all(tasks))
+            .await
+            .expect("the map deadlocked")
+            .into_iter()
+            .for_each(|task| task.unwrap());
+        assert_eq!(*cloned_handle(&map, &busy).unwrap().lock().await, 256);
+    }
+
+    #[test]
+    fn test_same_name() {
+        assert!(same_name("My Server", "my-server"));
+        assert!(same_name("My Server", "my server!"));
+        assert!(!same_name("My Server", "My Server 2"));
+        assert!(same_name("???", "???"));
+        assert!(!same_name("???", "!!!"));
+    }
+}
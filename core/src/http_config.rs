@@ -0,0 +1,509 @@
+//! How the router answers browsers on other origins and which reverse proxies it believes about
+//! the client's address, read from the environment at startup. The allowed origins can also be
+//! changed at runtime through the daemon settings
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        request::Parts,
+        Method,
+    },
+};
+use color_eyre::eyre::eyre;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::error::{Error, ErrorKind};
+
+/// Comma separated origins allowed to call the API, `*` for any
+pub const CORS_ORIGINS_VAR: &str = "LODESTONE_CORS_ORIGINS";
+/// Whether browsers may send cookies and authorization headers cross origin
+pub const CORS_CREDENTIALS_VAR: &str = "LODESTONE_CORS_CREDENTIALS";
+/// Comma separated response headers scripts on other origins may read, besides the request id
+pub const CORS_EXPOSE_HEADERS_VAR: &str = "LODESTONE_CORS_EXPOSE_HEADERS";
+/// Comma separated addresses of the reverse proxies whose forwarding headers are believed
+pub const TRUSTED_PROXIES_VAR: &str = "LODESTONE_TRUSTED_PROXIES";
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsConfig {
+    pub allowed_origins: AllowedOrigins,
+    pub allow_credentials: bool,
+    pub expose_headers: Vec<HeaderName>,
+}
+
+impl Default for CorsConfig {
+    /// Any origin without credentials, the dashboard authenticates with a bearer token
+    fn default() -> Self {
+        Self {
+            allowed_origins: AllowedOrigins::Any,
+            allow_credentials: false,
+            expose_headers: Vec::new(),
+        }
+    }
+}
+
+/// Origins set through the daemon settings, they replace the configured ones while set
+#[derive(Debug, Clone, Default)]
+pub struct OriginOverride(Arc<RwLock<Option<Vec<HeaderValue>>>>);
+
+impl OriginOverride {
+    /// Takes effect from the next request, `origins` are already validated
+    pub fn set(&self, origins: Option<Vec<String>>) {
+        let origins = origins.map(|origins| {
+            origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok())
+                .collect()
+        });
+        *self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = origins;
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> Option<bool> {
+        self.0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+            .map(|origins| origins.contains(origin))
+    }
+}
+
+impl CorsConfig {
+    /// The layer answering preflights, `extra_headers` are allowed in requests and exposed in
+    /// responses.
+    ///
+    /// Origins are checked per request so `origin_override` applies without a restart, an
+    /// allowed origin is echoed back instead of answering `*`
+    pub fn layer(
+        &self,
+        extra_headers: &[HeaderName],
+        origin_override: OriginOverride,
+    ) -> CorsLayer {
+        let layer = CorsLayer::new()
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PATCH,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            .allow_headers(
+                [header::ORIGIN, header::CONTENT_TYPE, header::AUTHORIZATION]
+                    .into_iter()
+                    .chain(extra_headers.iter().cloned())
+                    .collect::<Vec<_>>(),
+            )
+            .expose_headers(
+                extra_headers
+                    .iter()
+                    .chain(self.expose_headers.iter())
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            )
+            .allow_credentials(self.allow_credentials);
+        let allowed_origins = self.allowed_origins.clone();
+        layer.allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origin_override
+                .allows(origin)
+                .unwrap_or_else(|| match &allowed_origins {
+                    AllowedOrigins::Any => true,
+                    AllowedOrigins::List(origins) => origins.contains(origin),
+                })
+        }))
+    }
+}
+
+/// The reverse proxies in front of the daemon, only they can tell the client's address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpAddr>) -> Self {
+        Self(proxies)
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+
+    /// The address the request came from, read from `X-Forwarded-For` when `peer` is trusted.
+    ///
+    /// Each proxy appends the address it got the request from, so the list is walked from the
+    /// end and the first untrusted address is the client, anything before it could be forged
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for forwarded in headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .rev()
+            .filter_map(|value| value.to_str().ok())
+        {
+            for ip in forwarded.rsplit(',') {
+                match ip.trim().parse::<IpAddr>() {
+                    Ok(ip) => {
+                        client = ip;
+                        if !self.trusts(&ip) {
+                            return client;
+                        }
+                    }
+                    // a hop that can't be read ends what can be believed
+                    Err(_) => return client,
+                }
+            }
+        }
+        client
+    }
+
+    /// Whether the client reached the first proxy over HTTPS, as told by a trusted `peer`
+    pub fn forwarded_https(&self, peer: IpAddr, headers: &HeaderMap) -> Option<bool> {
+        if !self.trusts(&peer) {
+            return None;
+        }
+        let proto = headers.get(X_FORWARDED_PROTO)?.to_str().ok()?;
+        match proto.split(',').next()?.trim() {
+            proto if proto.eq_ignore_ascii_case("https") => Some(true),
+            proto if proto.eq_ignore_ascii_case("http") => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpConfig {
+    pub cors: CorsConfig,
+    pub trusted_proxies: TrustedProxies,
+}
+
+fn config_error(var: &str, message: impl std::fmt::Display) -> Error {
+    Error {
+        kind: ErrorKind::Internal,
+        source: eyre!("Invalid {}: {}", var, message),
+    }
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+impl HttpConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_vars(|var| std::env::var(var).ok())
+    }
+
+    /// Rejects anything it can't honor exactly, a daemon silently more open than configured is
+    /// worse than one that doesn't start
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let allowed_origins = match var(CORS_ORIGINS_VAR) {
+            None => AllowedOrigins::Any,
+            Some(origins) if origins.trim() == "*" => AllowedOrigins::Any,
+            Some(origins) => AllowedOrigins::List(
+                list(&origins)
+                    .map(|origin| {
+                        parse_origin(origin).map_err(|e| config_error(CORS_ORIGINS_VAR, e))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+        let allow_credentials = match var(CORS_CREDENTIALS_VAR) {
+            None => false,
+            Some(value) => value.trim().parse::<bool>().map_err(|_| {
+                config_error(
+                    CORS_CREDENTIALS_VAR,
+                    format!("expected true or false, got {}", value),
+                )
+            })?,
+        };
+        if allow_credentials && allowed_origins == AllowedOrigins::Any {
+            return Err(config_error(
+                CORS_CREDENTIALS_VAR,
+                format!(
+                    "credentials can't be allowed for any origin, list the allowed origins in {}",
+                    CORS_ORIGINS_VAR
+                ),
+            ));
+        }
+        let expose_headers = match var(CORS_EXPOSE_HEADERS_VAR) {
+            None => Vec::new(),
+            Some(headers) => list(&headers)
+                .map(|name| {
+                    HeaderName::try_from(name).map_err(|_| {
+                        config_error(
+                            CORS_EXPOSE_HEADERS_VAR,
+                            format!("{} isn't a header name", name),
+                        )
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        let trusted_proxies = match var(TRUSTED_PROXIES_VAR) {
+            None => Vec::new(),
+            Some(proxies) => list(&proxies)
+                .map(|ip| {
+                    ip.parse::<IpAddr>().map_err(|_| {
+                        config_error(TRUSTED_PROXIES_VAR, format!("{} isn't an IP address", ip))
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self {
+            cors: CorsConfig {
+                allowed_origins,
+                allow_credentials,
+                expose_headers,
+            },
+            trusted_proxies: TrustedProxies::new(trusted_proxies),
+        })
+    }
+}
+
+/// An origin is a scheme, host and port, browsers send nothing else
+pub(crate) fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    if origin == "*" {
+        return Err("* can't be listed with other origins".to_string());
+    }
+    let url = url::Url::parse(origin).map_err(|e| format!("{} isn't an origin: {}", origin, e))?;
+    let serialized = url.origin().ascii_serialization();
+    if !matches!(url.scheme(), "http" | "https") || serialized != origin.trim_end_matches('/') {
+        return Err(format!(
+            "{} isn't an origin, expected e.g. https://dashboard.example.com",
+            origin
+        ));
+    }
+    HeaderValue::from_str(&serialized).map_err(|e| format!("{} isn't an origin: {}", origin, e))
+}
+
+/// Where a request came from, behind trusted proxies the client they forwarded it for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientAddr {
+    /// Unknown when the router isn't served with connect info, e.g. in tests
+    pub ip: Option<IpAddr>,
+    /// Whether a trusted proxy said the client used HTTPS
+    pub forwarded_https: Option<bool>,
+}
+
+impl ClientAddr {
+    pub fn of(extensions: &axum::http::Extensions, headers: &HeaderMap) -> Self {
+        let peer = match extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => addr.ip(),
+            None => {
+                return Self {
+                    ip: None,
+                    forwarded_https: None,
+                }
+            }
+        };
+        let default_proxies = TrustedProxies::default();
+        let proxies = extensions
+            .get::<TrustedProxies>()
+            .unwrap_or(&default_proxies);
+        Self {
+            ip: Some(proxies.client_ip(peer, headers)),
+            forwarded_https: proxies.forwarded_https(peer, headers),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddr {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::of(&parts.extensions, &parts.headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    use axum::{routing::get, Extension, Json, Router};
+
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<HttpConfig, Error> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect();
+        HttpConfig::from_vars(|var| vars.get(var).cloned())
+    }
+
+    #[test]
+    fn test_config_from_vars() {
+        assert_eq!(from_vars(&[]).unwrap(), HttpConfig::default());
+
+        let config = from_vars(&[
+            (
+                CORS_ORIGINS_VAR,
+                "https://dashboard.example.com, http://localhost:3000/",
+            ),
+            (CORS_CREDENTIALS_VAR, "true"),
+            (CORS_EXPOSE_HEADERS_VAR, "x-total-count"),
+            (TRUSTED_PROXIES_VAR, "10.0.0.1,::1"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.cors.allowed_origins,
+            AllowedOrigins::List(vec![
+                HeaderValue::from_static("https://dashboard.example.com"),
+                HeaderValue::from_static("http://localhost:3000"),
+            ])
+        );
+        assert!(config.cors.allow_credentials);
+        assert_eq!(
+            config.cors.expose_headers,
+            vec![HeaderName::from_static("x-total-count")]
+        );
+        assert!(config.trusted_proxies.trusts(&"::1".parse().unwrap()));
+
+        // credentials for any origin would let every site act as the user
+        assert!(from_vars(&[(CORS_CREDENTIALS_VAR, "true")]).is_err());
+        assert!(from_vars(&[(CORS_ORIGINS_VAR, "*"), (CORS_CREDENTIALS_VAR, "true")]).is_err());
+        assert!(from_vars(&[(CORS_ORIGINS_VAR, "https://a.example.com,*")]).is_err());
+        assert!(from_vars(&[(CORS_ORIGINS_VAR, "https://a.example.com/dashboard")]).is_err());
+        assert!(from_vars(&[(CORS_CREDENTIALS_VAR, "yes")]).is_err());
+        assert!(from_vars(&[(TRUSTED_PROXIES_VAR, "10.0.0.0/8")]).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = TrustedProxies::new(vec![
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+        ]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("6.6.6.6, 203.0.113.7, 10.0.0.2"),
+        );
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+
+        let client = proxies.client_ip("10.0.0.1".parse().unwrap(), &headers);
+        assert_eq!(client, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            proxies.forwarded_https("10.0.0.1".parse().unwrap(), &headers),
+            Some(true)
+        );
+
+        // anyone else could have written the headers themselves
+        let peer: IpAddr = "198.51.100.4".parse().unwrap();
+        assert_eq!(proxies.client_ip(peer, &headers), peer);
+        assert_eq!(proxies.forwarded_https(peer, &headers), None);
+    }
+
+    async fn client(client: ClientAddr) -> Json<Option<String>> {
+        Json(client.ip.map(|ip| ip.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_cors_and_forwarded_client() {
+        let config = from_vars(&[
+            (CORS_ORIGINS_VAR, "https://dashboard.example.com"),
+            (CORS_CREDENTIALS_VAR, "true"),
+            (TRUSTED_PROXIES_VAR, "127.0.0.1"),
+        ])
+        .unwrap();
+        let origin_override = OriginOverride::default();
+        let app = Router::new()
+            .route("/client", get(client))
+            .layer(Extension(config.trusted_proxies.clone()))
+            .layer(config.cors.layer(&[], origin_override.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+        let http = reqwest::Client::new();
+
+        let preflight = http
+            .request(Method::OPTIONS, format!("http://{}/client", addr))
+            .header(header::ORIGIN, "https://dashboard.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await
+            .unwrap();
+        assert!(preflight.status().is_success());
+        assert_eq!(
+            preflight.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dashboard.example.com"
+        );
+        assert_eq!(
+            preflight.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS],
+            "true"
+        );
+
+        let other = http
+            .request(Method::OPTIONS, format!("http://{}/client", addr))
+            .header(header::ORIGIN, "https://evil.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await
+            .unwrap();
+        assert!(other
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        // origins set through the daemon settings apply to the next request
+        origin_override.set(Some(vec!["https://other.example.com".to_string()]));
+        let overridden = http
+            .request(Method::OPTIONS, format!("http://{}/client", addr))
+            .header(header::ORIGIN, "https://other.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            overridden.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://other.example.com"
+        );
+        origin_override.set(None);
+        let reverted = http
+            .request(Method::OPTIONS, format!("http://{}/client", addr))
+            .header(header::ORIGIN, "https://other.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await
+            .unwrap();
+        assert!(reverted
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let client: Option<String> = http
+            .get(format!("http://{}/client", addr))
+            .header(X_FORWARDED_FOR, "203.0.113.7")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(client.as_deref(), Some("203.0.113.7"));
+    }
+}
@@ -0,0 +1,357 @@
+//! Answers a mutating request retried with the same `Idempotency-Key` header with the response
+//! of the first one instead of running it again, e.g. a timed out instance creation
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{boxed, Body, Bytes, Full, HttpBody},
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use color_eyre::eyre::eyre;
+use indexmap::IndexMap;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::error::{Error, ErrorCode, ErrorKind};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on a response replayed from the first request with the key
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+/// Requests and responses are buffered to be compared and replayed, larger ones aren't
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Keys kept at most, the least recently used go first
+const MAX_KEYS: usize = 1000;
+
+type Digested = [u8; 32];
+
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    fn replay(&self) -> Response {
+        let mut response = Response::new(boxed(Full::from(self.body.clone())));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// The response of the first request, `None` while it runs. Closed without one if the first
+/// request was dropped or its response can't be replayed
+type Outcome = watch::Receiver<Option<Arc<StoredResponse>>>;
+
+struct Entry {
+    /// Of the method, URI and body, a retry must send the same request
+    fingerprint: Digested,
+    created: Instant,
+    outcome: Outcome,
+}
+
+enum Claim {
+    /// First with the key, it runs the request and answers the retries
+    First(watch::Sender<Option<Arc<StoredResponse>>>),
+    Pending(Outcome),
+    Done(Arc<StoredResponse>),
+    /// The key was used for another request
+    Mismatch,
+}
+
+/// Keys seen recently with the response to their first request
+#[derive(Clone, Default)]
+pub struct IdempotencyStore {
+    entries: Arc<Mutex<IndexMap<Digested, Entry>>>,
+}
+
+impl IdempotencyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn claim(&self, key: Digested, fingerprint: Digested, now: Instant) -> Claim {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| now.duration_since(entry.created) < KEY_TTL);
+        if let Some(entry) = entries.shift_remove(&key) {
+            if entry.fingerprint != fingerprint {
+                entries.insert(key, entry);
+                return Claim::Mismatch;
+            }
+            let response = entry.outcome.borrow().clone();
+            let abandoned = entry.outcome.has_changed().is_err();
+            match response {
+                Some(response) => {
+                    entries.insert(key, entry);
+                    return Claim::Done(response);
+                }
+                None if !abandoned => {
+                    let outcome = entry.outcome.clone();
+                    entries.insert(key, entry);
+                    return Claim::Pending(outcome);
+                }
+                // taken over by this request
+                None => {}
+            }
+        }
+        let (sender, outcome) = watch::channel(None);
+        entries.insert(
+            key,
+            Entry {
+                fingerprint,
+                created: now,
+                outcome,
+            },
+        );
+        while entries.len() > MAX_KEYS {
+            entries.shift_remove_index(0);
+        }
+        Claim::First(sender)
+    }
+
+    /// Forgets the key so a retry runs again
+    fn release(&self, key: &Digested) {
+        self.entries.lock().unwrap().shift_remove(key);
+    }
+
+    /// Keeps the response for retries. Server errors and responses too large to buffer aren't
+    /// kept, retries of them run again
+    async fn finish(
+        &self,
+        key: Digested,
+        sender: watch::Sender<Option<Arc<StoredResponse>>>,
+        response: Response,
+    ) -> Response {
+        let (parts, body) = response.into_parts();
+        let too_large = body
+            .size_hint()
+            .upper()
+            .map_or(true, |size| size > MAX_BODY_SIZE as u64);
+        if parts.status.is_server_error() || too_large {
+            self.release(&key);
+            return Response::from_parts(parts, body);
+        }
+        let body = match read_limited(body).await {
+            Some(body) => body,
+            None => {
+                self.release(&key);
+                return Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!("Failed to buffer the response"),
+                }
+                .into_response();
+            }
+        };
+        let _ = sender.send(Some(Arc::new(StoredResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        })));
+        Response::from_parts(parts, boxed(Full::from(body)))
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn digest(parts: &[&[u8]]) -> Digested {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // length prefixed, so moving bytes from one part to the next changes the digest
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// `None` if the body is larger than [`MAX_BODY_SIZE`] or fails to arrive
+async fn read_limited<B>(mut body: B) -> Option<Bytes>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+{
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.ok()?;
+        if buffer.len() + chunk.len() > MAX_BODY_SIZE {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Some(Bytes::from(buffer))
+}
+
+/// Runs a POST, PUT, PATCH or DELETE request with an `Idempotency-Key` header once per key.
+///
+/// Keys are scoped to the credentials the request was sent with, so nobody is answered with
+/// another user's response. A retry while the first request still runs waits for its response,
+/// one with the same key but a different method, URI or body is refused with 409
+pub async fn replay_idempotent(
+    State(store): State<IdempotencyStore>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if ![Method::POST, Method::PUT, Method::PATCH, Method::DELETE].contains(request.method()) {
+        return next.run(request).await;
+    }
+    let key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => key.to_str().ok().filter(|key| is_valid_key(key)),
+        None => return next.run(request).await,
+    };
+    let key = match key {
+        Some(key) => key.to_string(),
+        None => {
+            return Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Idempotency-Key must be 1 to {} printable ASCII characters",
+                    MAX_KEY_LENGTH
+                ),
+            }
+            .into_response()
+        }
+    };
+    let (parts, body) = request.into_parts();
+    let body = match read_limited(body).await {
+        Some(body) => body,
+        None => {
+            return Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Idempotency-Key is only supported for request bodies up to {} bytes",
+                    MAX_BODY_SIZE
+                ),
+            }
+            .into_response()
+        }
+    };
+    let credentials = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .map(HeaderValue::as_bytes)
+        .unwrap_or_default();
+    let scoped_key = digest(&[credentials, key.as_bytes()]);
+    let fingerprint = digest(&[
+        parts.method.as_str().as_bytes(),
+        parts.uri.to_string().as_bytes(),
+        &body,
+    ]);
+    let request = Request::from_parts(parts, Body::from(body));
+    loop {
+        match store.claim(scoped_key, fingerprint, Instant::now()) {
+            Claim::First(sender) => {
+                let response = next.run(request).await;
+                return store.finish(scoped_key, sender, response).await;
+            }
+            Claim::Done(response) => return response.replay(),
+            Claim::Pending(mut outcome) => {
+                let response = loop {
+                    let response = outcome.borrow().clone();
+                    if response.is_some() || outcome.changed().await.is_err() {
+                        break response;
+                    }
+                };
+                // closed without a response, the next claim takes the key over
+                if let Some(response) = response {
+                    return response.replay();
+                }
+            }
+            Claim::Mismatch => {
+                return Error::coded(
+                    ErrorCode::IdempotencyKeyReused,
+                    "Idempotency-Key was already used for a different request",
+                )
+                .into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest_of(value: &str) -> Digested {
+        digest(&[value.as_bytes()])
+    }
+
+    fn stored(status: StatusCode) -> Arc<StoredResponse> {
+        Arc::new(StoredResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"{}"),
+        })
+    }
+
+    #[test]
+    fn test_claim() {
+        let store = IdempotencyStore::new();
+        let key = digest_of("create-1");
+        let request = digest_of("POST /api/v1/instance/create");
+        let now = Instant::now();
+
+        let sender = match store.claim(key, request, now) {
+            Claim::First(sender) => sender,
+            _ => panic!("the first request with a key runs"),
+        };
+        assert!(matches!(store.claim(key, request, now), Claim::Pending(_)));
+        let other = digest_of("DELETE /api/v1/instance/x");
+        assert!(matches!(store.claim(key, other, now), Claim::Mismatch));
+
+        sender.send(Some(stored(StatusCode::OK))).unwrap();
+        drop(sender);
+        match store.claim(key, request, now) {
+            Claim::Done(response) => assert_eq!(response.status, StatusCode::OK),
+            _ => panic!("a finished request is replayed"),
+        }
+        assert!(matches!(
+            store.claim(key, request, now + KEY_TTL),
+            Claim::First(_)
+        ));
+    }
+
+    #[test]
+    fn test_abandoned_claim_is_taken_over() {
+        let store = IdempotencyStore::new();
+        let key = digest_of("retry");
+        let request = digest_of("POST /api/v1/instance/x/backups");
+        let now = Instant::now();
+        match store.claim(key, request, now) {
+            Claim::First(sender) => drop(sender),
+            _ => panic!("the first request with a key runs"),
+        }
+        assert!(matches!(store.claim(key, request, now), Claim::First(_)));
+    }
+
+    #[test]
+    fn test_least_recently_used_keys_are_evicted() {
+        let store = IdempotencyStore::new();
+        let request = digest_of("POST /api/v1/instance/x/start");
+        let now = Instant::now();
+        let mut senders = Vec::new();
+        for i in 0..=MAX_KEYS {
+            if let Claim::First(sender) = store.claim(digest_of(&i.to_string()), request, now) {
+                senders.push(sender);
+            }
+        }
+        assert_eq!(store.entries.lock().unwrap().len(), MAX_KEYS);
+        assert!(!store.entries.lock().unwrap().contains_key(&digest_of("0")));
+    }
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(is_valid_key("6f1c2b4e-provision-42"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("has space"));
+        assert!(!is_valid_key(&"k".repeat(MAX_KEY_LENGTH + 1)));
+    }
+}
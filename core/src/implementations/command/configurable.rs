@@ -0,0 +1,139 @@
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+
+use crate::backups::BackupSchedule;
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_configurable::manifest::{ConfigurableManifest, ConfigurableValue};
+use crate::traits::t_configurable::{Game, TConfigurable};
+use crate::types::InstanceUuid;
+
+use super::{CommandInstance, COMMAND_SECTION_ID, LODESTONE_SECTION_ID};
+
+#[async_trait]
+impl TConfigurable for CommandInstance {
+    async fn uuid(&self) -> InstanceUuid {
+        self.uuid.clone()
+    }
+
+    async fn name(&self) -> String {
+        self.snapshot.load().name.clone()
+    }
+
+    async fn game_type(&self) -> Game {
+        Game::Command
+    }
+
+    async fn version(&self) -> String {
+        self.snapshot.load().version.clone()
+    }
+
+    async fn description(&self) -> String {
+        self.snapshot.load().description.clone()
+    }
+
+    async fn port(&self) -> u32 {
+        self.snapshot.load().port
+    }
+
+    async fn creation_time(&self) -> i64 {
+        self.creation_time
+    }
+
+    async fn path(&self) -> std::path::PathBuf {
+        self.path_to_instance.clone()
+    }
+
+    async fn auto_start(&self) -> bool {
+        self.snapshot.load().auto_start
+    }
+
+    async fn restart_on_crash(&self) -> bool {
+        false
+    }
+
+    async fn set_name(&self, name: String) -> Result<(), Error> {
+        if name.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Name cannot be empty"),
+            });
+        }
+        if name.len() > 100 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Name cannot be longer than 100 characters"),
+            });
+        }
+        self.config.lock().await.name = name;
+        self.write_config_to_file().await
+    }
+
+    async fn set_description(&self, description: String) -> Result<(), Error> {
+        self.config.lock().await.description = description;
+        self.write_config_to_file().await
+    }
+
+    async fn set_port(&self, port: u32) -> Result<(), Error> {
+        if port == 0 || port > 65535 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Port must be between 1 and 65535"),
+            });
+        }
+        self.config.lock().await.port = port;
+        self.write_config_to_file().await
+    }
+
+    async fn set_auto_start(&self, auto_start: bool) -> Result<(), Error> {
+        self.config.lock().await.auto_start = auto_start;
+        self.write_config_to_file().await
+    }
+
+    async fn backup_schedule(&self) -> Result<BackupSchedule, Error> {
+        Ok(self.config.lock().await.backup_schedule)
+    }
+
+    async fn set_backup_schedule(&self, backup_schedule: BackupSchedule) -> Result<(), Error> {
+        backup_schedule.validate()?;
+        self.config.lock().await.backup_schedule = backup_schedule;
+        self.write_config_to_file().await
+    }
+
+    async fn configurable_manifest(&self) -> ConfigurableManifest {
+        Self::build_configurable_manifest(&*self.config.lock().await)
+    }
+
+    async fn update_configurable(
+        &self,
+        section_id: &str,
+        setting_id: &str,
+        value: ConfigurableValue,
+    ) -> Result<(), Error> {
+        let mut config = self.config.lock().await;
+        // checks the setting exists and the value fits its type
+        let mut manifest = Self::build_configurable_manifest(&config);
+        manifest.update_setting_value(section_id, setting_id, value.clone())?;
+        match section_id {
+            LODESTONE_SECTION_ID => config.auto_start = value.try_as_boolean()?,
+            COMMAND_SECTION_ID => {
+                // settings depend on each other, e.g. a stop mode on its input
+                let mut updated = config.clone();
+                Self::apply_command_settings(&mut updated, |id| {
+                    manifest
+                        .get_setting(COMMAND_SECTION_ID, id)
+                        .and_then(|setting| setting.get_value())
+                        .cloned()
+                })?;
+                *config = updated;
+            }
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("Setting not found"),
+                })
+            }
+        }
+        drop(config);
+        self.write_config_to_file().await
+    }
+}
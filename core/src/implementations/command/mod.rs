@@ -0,0 +1,812 @@
+pub mod configurable;
+pub mod server;
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use color_eyre::eyre::{eyre, Context};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+use tokio::process::Child;
+use tokio::sync::Mutex;
+
+use crate::backups::BackupSchedule;
+use crate::command_queue::{CommandQueue, CommandQueueConfig};
+use crate::console_history::{ConsoleHistory, DEFAULT_CONSOLE_HISTORY_SIZE};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::CausedBy;
+use crate::process_tree::{new_process_system, ProcessTreeTracker};
+use crate::run_record::RunRecord;
+use crate::snapshot::{watch_instance_events, InstanceSnapshot, Snapshot};
+use crate::traits::t_configurable::manifest::{
+    ConfigurableManifest, ConfigurableValue, ConfigurableValueType, SectionManifest,
+    SettingLocalCache, SettingManifest, SettingValidationError, SettingValidationErrors,
+    SetupManifest, SetupValue,
+};
+use crate::traits::t_configurable::Game;
+use crate::traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry};
+use crate::traits::t_player::TPlayerManagement;
+use crate::traits::t_server::{MonitorReport, State};
+use crate::traits::TInstance;
+use crate::types::{DotLodestoneConfig, InstanceUuid};
+
+pub const DEFAULT_STOP_TIMEOUT_SECS: u32 = 30;
+
+pub(crate) const COMMAND_SECTION_ID: &str = "command_section";
+pub(crate) const LODESTONE_SECTION_ID: &str = "lodestone_section";
+
+/// How a command instance is asked to stop
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StopBehavior {
+    /// Writes `input` to the process's stdin, e.g. `stop` or `quit`
+    Stdin { input: String },
+    /// SIGTERM to the process group, a plain kill on Windows
+    #[default]
+    Sigterm,
+    /// Runs `command` in the working directory, e.g. a control script
+    Command { command: String },
+}
+
+impl StopBehavior {
+    fn mode(&self) -> &'static str {
+        match self {
+            StopBehavior::Stdin { .. } => "stdin",
+            StopBehavior::Sigterm => "sigterm",
+            StopBehavior::Command { .. } => "command",
+        }
+    }
+
+    fn input(&self) -> Option<&String> {
+        match self {
+            StopBehavior::Stdin { input } => Some(input),
+            StopBehavior::Sigterm => None,
+            StopBehavior::Command { command } => Some(command),
+        }
+    }
+
+    fn from_mode(mode: &str, input: Option<String>) -> Result<Self, String> {
+        match (mode, input.filter(|input| !input.trim().is_empty())) {
+            ("sigterm", _) => Ok(StopBehavior::Sigterm),
+            ("stdin", Some(input)) => Ok(StopBehavior::Stdin { input }),
+            ("command", Some(command)) => {
+                split_command(&command).map_err(|e| e.source.to_string())?;
+                Ok(StopBehavior::Command { command })
+            }
+            ("stdin" | "command", None) => Err(format!("Stop mode {} needs a stop input", mode)),
+            _ => Err(format!("Unknown stop mode {}", mode)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandConfig {
+    pub name: String,
+    pub description: String,
+    /// Program and arguments, split with `split_command` on every start
+    pub command: String,
+    /// Relative to the instance directory, the instance directory itself if unset
+    #[serde(default)]
+    pub working_directory: Option<String>,
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+    pub port: u32,
+    #[serde(default)]
+    pub stop: StopBehavior,
+    /// The instance counts as running once a stdout line matches, right after launch if unset
+    #[serde(default)]
+    pub running_pattern: Option<String>,
+    pub auto_start: bool,
+    /// How long a stop may take before the kill route is suggested
+    #[serde(default = "default_stop_timeout_secs")]
+    pub stop_timeout_secs: u32,
+    #[serde(default)]
+    pub command_queue: CommandQueueConfig,
+    #[serde(default = "default_console_history_size")]
+    pub console_history_size: u32,
+    #[serde(default)]
+    pub backup_schedule: BackupSchedule,
+}
+
+fn default_console_history_size() -> u32 {
+    DEFAULT_CONSOLE_HISTORY_SIZE
+}
+
+fn default_stop_timeout_secs() -> u32 {
+    DEFAULT_STOP_TIMEOUT_SECS
+}
+
+/// An instance running a user supplied command, for servers Lodestone has no integration for
+#[derive(Clone)]
+pub struct CommandInstance {
+    config: Arc<Mutex<CommandConfig>>,
+    uuid: InstanceUuid,
+    creation_time: i64,
+    state: Arc<Mutex<State>>,
+    event_broadcaster: EventBroadcaster,
+    path_to_instance: PathBuf,
+    path_to_config: PathBuf,
+    process: Arc<Mutex<Option<Child>>>,
+    /// Reads the process's output and cleans up once it exits
+    output_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    command_queue: CommandQueue,
+    console_history: Arc<Mutex<ConsoleHistory>>,
+    /// Set when a stop or kill is requested so the exit is reported as such
+    stop_requested: Arc<AtomicBool>,
+    system: Arc<Mutex<sysinfo::System>>,
+    last_monitor_report: Arc<Mutex<Option<(Instant, MonitorReport)>>>,
+    process_tree: Arc<Mutex<ProcessTreeTracker>>,
+    /// Informational fields served to readers without touching the locks above
+    snapshot: Snapshot<InstanceSnapshot>,
+}
+
+/// Splits a command line into its program and arguments
+///
+/// Arguments are separated by whitespace, single quotes keep their content as is and double
+/// quotes allow `\"` and `\\` escapes. No shell is involved, so pipes and variables are not
+/// expanded
+pub fn split_command(command: &str) -> Result<Vec<String>, Error> {
+    let unterminated = || Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Unterminated quote in {}", command),
+    };
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(unterminated()),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(unterminated()),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err(unterminated()),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Quotes `arg` so `split_command` reads it back as one argument
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'')
+    {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Parses `KEY=VALUE` pairs separated by whitespace, quoted like command arguments
+fn parse_env(env: &str) -> Result<IndexMap<String, String>, String> {
+    let mut vars = IndexMap::new();
+    for pair in split_command(env).map_err(|e| e.source.to_string())? {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not in the form KEY=VALUE", pair))?;
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("{} is not a valid variable name", key));
+        }
+        vars.insert(key.to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+fn format_env(env: &IndexMap<String, String>) -> String {
+    env.iter()
+        .map(|(key, value)| quote_arg(&format!("{}={}", key, value)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The working directory has to stay inside the instance so fs permissions still cover it
+fn validate_working_directory(dir: &str) -> Result<(), String> {
+    if Path::new(dir)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(())
+    } else {
+        Err("The working directory must be a relative path inside the instance".to_string())
+    }
+}
+
+fn validate_running_pattern(pattern: &str) -> Result<(), String> {
+    fancy_regex::Regex::new(pattern)
+        .map(|_| ())
+        .map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+fn non_empty(value: Option<&ConfigurableValue>) -> Option<String> {
+    value
+        .and_then(|v| v.try_as_string().ok())
+        .filter(|v| !v.trim().is_empty())
+        .cloned()
+}
+
+impl CommandInstance {
+    pub fn setup_manifest() -> SetupManifest {
+        let mut command_section = Self::command_settings(None);
+        let port_setting = SettingManifest::new_value_with_type(
+            "port".to_string(),
+            "Port".to_string(),
+            "The port the server listens on, reserved for this instance".to_string(),
+            None,
+            ConfigurableValueType::UnsignedInteger {
+                min: Some(1),
+                max: Some(65535),
+            },
+            None,
+            false,
+            true,
+        );
+        command_section.insert("port".to_string(), port_setting);
+        let mut sections = IndexMap::new();
+        sections.insert(
+            COMMAND_SECTION_ID.to_string(),
+            SectionManifest::new(
+                COMMAND_SECTION_ID.to_string(),
+                "Command Settings".to_string(),
+                "What the instance runs and how it is stopped".to_string(),
+                command_section,
+            ),
+        );
+        SetupManifest {
+            setting_sections: sections,
+        }
+    }
+
+    /// Settings of the command section, holding the values of `config` if given
+    fn command_settings(config: Option<&CommandConfig>) -> IndexMap<String, SettingManifest> {
+        let string_type = ConfigurableValueType::String { regex: None };
+        let string = |value: Option<&String>| value.cloned().map(ConfigurableValue::String);
+        let mut settings = IndexMap::new();
+        settings.insert(
+            "command".to_string(),
+            SettingManifest::new_value_with_type(
+                "command".to_string(),
+                "Start Command".to_string(),
+                "The program and its arguments, run without a shell".to_string(),
+                config.map(|c| ConfigurableValue::String(c.command.clone())),
+                string_type.clone(),
+                None,
+                false,
+                true,
+            )
+            .with_restart_required(),
+        );
+        settings.insert(
+            "working_directory".to_string(),
+            SettingManifest::new_optional_value(
+                "working_directory".to_string(),
+                "Working Directory".to_string(),
+                "Relative to the instance directory, the instance directory if empty".to_string(),
+                config.and_then(|c| string(c.working_directory.as_ref())),
+                string_type.clone(),
+                None,
+                false,
+                true,
+            )
+            .with_restart_required(),
+        );
+        settings.insert(
+            "env".to_string(),
+            SettingManifest::new_optional_value(
+                "env".to_string(),
+                "Environment Variables".to_string(),
+                "KEY=VALUE pairs separated by spaces, quote values containing spaces".to_string(),
+                config
+                    .filter(|c| !c.env.is_empty())
+                    .map(|c| ConfigurableValue::String(format_env(&c.env))),
+                string_type.clone(),
+                None,
+                false,
+                true,
+            )
+            .with_restart_required(),
+        );
+        settings.insert(
+            "stop_mode".to_string(),
+            SettingManifest::new_value_with_type(
+                "stop_mode".to_string(),
+                "Stop Mode".to_string(),
+                "Write the stop input to stdin, send SIGTERM, or run the stop input as a command"
+                    .to_string(),
+                Some(ConfigurableValue::Enum(
+                    config
+                        .map(|c| c.stop.mode())
+                        .unwrap_or("sigterm")
+                        .to_string(),
+                )),
+                ConfigurableValueType::Enum {
+                    options: vec![
+                        "stdin".to_string(),
+                        "sigterm".to_string(),
+                        "command".to_string(),
+                    ],
+                },
+                Some(ConfigurableValue::Enum("sigterm".to_string())),
+                false,
+                true,
+            ),
+        );
+        settings.insert(
+            "stop_input".to_string(),
+            SettingManifest::new_optional_value(
+                "stop_input".to_string(),
+                "Stop Input".to_string(),
+                "What is written to stdin or run to stop the server, unused with SIGTERM"
+                    .to_string(),
+                config.and_then(|c| string(c.stop.input())),
+                string_type.clone(),
+                None,
+                false,
+                true,
+            ),
+        );
+        settings.insert(
+            "running_pattern".to_string(),
+            SettingManifest::new_optional_value(
+                "running_pattern".to_string(),
+                "Running Pattern".to_string(),
+                "Regex matched against stdout, the server counts as running once a line matches. \
+                 Running right after launch if empty"
+                    .to_string(),
+                config.and_then(|c| string(c.running_pattern.as_ref())),
+                string_type,
+                None,
+                false,
+                true,
+            )
+            .with_restart_required(),
+        );
+        settings
+    }
+
+    /// Checks the command section of a setup or an update, every refused setting is reported
+    fn validate_command_settings(
+        get: &impl Fn(&str) -> Option<ConfigurableValue>,
+    ) -> Result<(), Error> {
+        let mut errors = Vec::new();
+        match non_empty(get("command").as_ref()).map(|c| split_command(&c)) {
+            Some(Ok(args)) if !args.is_empty() => {}
+            Some(Err(e)) => errors.push(SettingValidationError::new("command", e.source)),
+            _ => errors.push(SettingValidationError::new(
+                "command",
+                "The start command cannot be empty",
+            )),
+        }
+        if let Some(Err(e)) =
+            non_empty(get("working_directory").as_ref()).map(|d| validate_working_directory(&d))
+        {
+            errors.push(SettingValidationError::new("working_directory", e));
+        }
+        if let Some(Err(e)) = non_empty(get("env").as_ref()).map(|e| parse_env(&e)) {
+            errors.push(SettingValidationError::new("env", e));
+        }
+        if let Some(mode) = get("stop_mode") {
+            if let Err(e) =
+                StopBehavior::from_mode(mode.try_as_enum()?, non_empty(get("stop_input").as_ref()))
+            {
+                errors.push(SettingValidationError::new("stop_input", e));
+            }
+        }
+        if let Some(Err(e)) =
+            non_empty(get("running_pattern").as_ref()).map(|p| validate_running_pattern(&p))
+        {
+            errors.push(SettingValidationError::new("running_pattern", e));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingValidationErrors(errors).into())
+        }
+    }
+
+    /// Validates the command section as a whole and applies it to `config`
+    fn apply_command_settings(
+        config: &mut CommandConfig,
+        get: impl Fn(&str) -> Option<ConfigurableValue>,
+    ) -> Result<(), Error> {
+        Self::validate_command_settings(&get)?;
+        let string = |id: &str| non_empty(get(id).as_ref());
+        let stop_mode = get("stop_mode");
+        let stop_mode = match &stop_mode {
+            Some(mode) => mode.try_as_enum()?.as_str(),
+            None => "sigterm",
+        };
+        config.stop = StopBehavior::from_mode(stop_mode, string("stop_input")).map_err(|e| {
+            SettingValidationErrors(vec![SettingValidationError::new("stop_input", e)])
+        })?;
+        // the rest was validated above
+        config.command = string("command").unwrap_or_default();
+        config.working_directory = string("working_directory");
+        config.env = string("env")
+            .map(|env| parse_env(&env).unwrap_or_default())
+            .unwrap_or_default();
+        config.running_pattern = string("running_pattern");
+        Ok(())
+    }
+
+    pub fn construct_setup_config(setup_value: SetupValue) -> Result<CommandConfig, Error> {
+        Self::setup_manifest().validate_setup_value(&setup_value)?;
+        let get = |id: &str| {
+            setup_value
+                .get_unique_setting(id)
+                .and_then(|setting| setting.get_value())
+                .cloned()
+        };
+        let port = get("port")
+            .ok_or_else(|| {
+                SettingValidationErrors(vec![SettingValidationError::new(
+                    "port",
+                    "A port is required",
+                )])
+            })?
+            .try_as_unsigned_integer()?;
+        let mut config = CommandConfig {
+            name: setup_value.name.clone(),
+            description: setup_value.description.clone().unwrap_or_default(),
+            command: String::new(),
+            working_directory: None,
+            env: IndexMap::new(),
+            port,
+            stop: StopBehavior::default(),
+            running_pattern: None,
+            auto_start: setup_value.auto_start,
+            stop_timeout_secs: DEFAULT_STOP_TIMEOUT_SECS,
+            command_queue: CommandQueueConfig::default(),
+            console_history_size: DEFAULT_CONSOLE_HISTORY_SIZE,
+            backup_schedule: BackupSchedule::default(),
+        };
+        Self::apply_command_settings(&mut config, get)?;
+        Ok(config)
+    }
+
+    fn build_configurable_manifest(config: &CommandConfig) -> ConfigurableManifest {
+        let auto_start = SettingManifest::new_required_value(
+            "auto_start".to_string(),
+            "Auto Start".to_string(),
+            "Start the server when Lodestone starts".to_string(),
+            ConfigurableValue::Boolean(config.auto_start),
+            Some(ConfigurableValue::Boolean(false)),
+            false,
+            true,
+        );
+        let mut sections = IndexMap::new();
+        sections.insert(
+            LODESTONE_SECTION_ID.to_string(),
+            SectionManifest::new(
+                LODESTONE_SECTION_ID.to_string(),
+                "Lodestone Settings".to_string(),
+                "How Lodestone manages the server".to_string(),
+                IndexMap::from([("auto_start".to_string(), auto_start)]),
+            ),
+        );
+        sections.insert(
+            COMMAND_SECTION_ID.to_string(),
+            SectionManifest::new(
+                COMMAND_SECTION_ID.to_string(),
+                "Command Settings".to_string(),
+                "What the instance runs and how it is stopped".to_string(),
+                Self::command_settings(Some(config)),
+            ),
+        );
+        ConfigurableManifest::new(false, false, sections)
+    }
+
+    pub async fn new(
+        config: CommandConfig,
+        dot_lodestone_config: DotLodestoneConfig,
+        path_to_instance: PathBuf,
+        backup_schedule: BackupSchedule,
+        event_broadcaster: EventBroadcaster,
+    ) -> Result<CommandInstance, Error> {
+        let config = CommandConfig {
+            backup_schedule,
+            ..config
+        };
+        let working_directory = match &config.working_directory {
+            Some(dir) => path_to_instance.join(dir),
+            None => path_to_instance.clone(),
+        };
+        tokio::fs::create_dir_all(&working_directory)
+            .await
+            .context("Could not create the instance directory")?;
+        tokio::fs::write(
+            path_to_instance.join(".lodestone_command_config.json"),
+            to_string_pretty(&config)
+                .context("Failed to serialize config to string, this is a bug, please report it")?,
+        )
+        .await
+        .context("Failed to write config file")?;
+        CommandInstance::restore(path_to_instance, dot_lodestone_config, event_broadcaster).await
+    }
+
+    pub async fn restore(
+        path_to_instance: PathBuf,
+        dot_lodestone_config: DotLodestoneConfig,
+        event_broadcaster: EventBroadcaster,
+    ) -> Result<CommandInstance, Error> {
+        let path_to_config = path_to_instance.join(".lodestone_command_config.json");
+        let config: CommandConfig =
+            serde_json::from_reader(std::fs::File::open(&path_to_config).context(format!(
+                "Failed to open config file at {}",
+                &path_to_config.display()
+            ))?)
+            .context(
+                "Failed to deserialize config from string. Was the config file modified manually?",
+            )?;
+        let snapshot = Snapshot::new(InstanceSnapshot {
+            name: config.name.clone(),
+            game_type: Game::Command,
+            description: config.description.clone(),
+            version: String::new(),
+            port: config.port,
+            auto_start: config.auto_start,
+            restart_on_crash: false,
+            state: State::Stopped,
+            player_count: None,
+            max_player_count: None,
+            player_list: None,
+            launch_command: None,
+            eula_accepted: None,
+            last_started: None,
+            last_run: RunRecord::default(),
+            degraded: false,
+        });
+        watch_instance_events(
+            &snapshot,
+            dot_lodestone_config.uuid().clone(),
+            &event_broadcaster,
+        );
+        Ok(CommandInstance {
+            uuid: dot_lodestone_config.uuid().clone(),
+            creation_time: dot_lodestone_config.creation_time(),
+            state: Arc::new(Mutex::new(State::Stopped)),
+            event_broadcaster,
+            path_to_instance,
+            path_to_config,
+            process: Arc::new(Mutex::new(None)),
+            output_task: Arc::new(Mutex::new(None)),
+            command_queue: CommandQueue::new(config.command_queue),
+            console_history: Arc::new(Mutex::new(ConsoleHistory::new(config.console_history_size))),
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            system: Arc::new(Mutex::new(new_process_system())),
+            last_monitor_report: Arc::new(Mutex::new(None)),
+            process_tree: Arc::new(Mutex::new(ProcessTreeTracker::new())),
+            config: Arc::new(Mutex::new(config)),
+            snapshot,
+        })
+    }
+
+    /// Where the command runs, checked again here since the config file can be edited by hand
+    fn working_directory(&self, config: &CommandConfig) -> Result<PathBuf, Error> {
+        match &config.working_directory {
+            Some(dir) => {
+                validate_working_directory(dir).map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(e),
+                })?;
+                Ok(self.path_to_instance.join(dir))
+            }
+            None => Ok(self.path_to_instance.clone()),
+        }
+    }
+
+    async fn write_config_to_file(&self) -> Result<(), Error> {
+        tokio::fs::write(
+            &self.path_to_config,
+            to_string_pretty(&*self.config.lock().await)
+                .context("Failed to serialize config to string, this is a bug, please report it")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write config to file at {}",
+            &self.path_to_config.display()
+        ))?;
+        self.refresh_snapshot().await;
+        Ok(())
+    }
+
+    /// Republishes the informational fields after the config changed
+    async fn refresh_snapshot(&self) {
+        let config = self.config.lock().await;
+        self.snapshot.update(|snapshot| {
+            snapshot.name = config.name.clone();
+            snapshot.description = config.description.clone();
+            snapshot.port = config.port;
+            snapshot.auto_start = config.auto_start;
+        });
+    }
+}
+
+impl TInstance for CommandInstance {
+    fn snapshot(&self) -> Arc<InstanceSnapshot> {
+        self.snapshot.load()
+    }
+}
+
+#[async_trait]
+impl TMacro for CommandInstance {
+    async fn get_macro_list(&self) -> Result<Vec<MacroEntry>, Error> {
+        Ok(Vec::new())
+    }
+    async fn get_task_list(&self) -> Result<Vec<TaskEntry>, Error> {
+        Ok(Vec::new())
+    }
+    async fn get_history_list(&self) -> Result<Vec<HistoryEntry>, Error> {
+        Ok(Vec::new())
+    }
+    async fn delete_macro(&self, _name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn create_macro(&self, _name: &str, _content: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn run_macro(
+        &self,
+        _name: &str,
+        _args: Vec<String>,
+        _configs: Option<IndexMap<String, SettingLocalCache>>,
+        _caused_by: CausedBy,
+    ) -> Result<TaskEntry, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Command instances do not support macros"),
+        })
+    }
+}
+
+#[async_trait]
+impl TPlayerManagement for CommandInstance {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(
+            split_command("java -Xmx2G -jar server.jar").unwrap(),
+            vec!["java", "-Xmx2G", "-jar", "server.jar"]
+        );
+        assert_eq!(
+            split_command(r#"./run.sh "a b" 'c "d"' e\f "g\"h""#).unwrap(),
+            vec!["./run.sh", "a b", r#"c "d""#, r"e\f", r#"g"h"#]
+        );
+        assert_eq!(split_command(r#"x "" y"#).unwrap(), vec!["x", "", "y"]);
+        assert!(split_command("  ").unwrap().is_empty());
+        assert!(split_command("echo \"oops").is_err());
+        assert!(split_command("echo 'oops").is_err());
+    }
+
+    #[test]
+    fn test_env_round_trip() {
+        let env = parse_env(r#"FOO=bar GREETING="hello world" EMPTY= PATHS=C:\x"#).unwrap();
+        assert_eq!(env.get("GREETING").unwrap(), "hello world");
+        assert_eq!(env.get("EMPTY").unwrap(), "");
+        assert_eq!(env.get("PATHS").unwrap(), r"C:\x");
+        assert_eq!(parse_env(&format_env(&env)).unwrap(), env);
+        assert!(parse_env("NOVALUE").is_err());
+        assert!(parse_env("=x").is_err());
+        assert!(parse_env("BAD-NAME=x").is_err());
+    }
+
+    #[test]
+    fn test_working_directory_stays_inside() {
+        assert!(validate_working_directory("server").is_ok());
+        assert!(validate_working_directory("./server/bin").is_ok());
+        assert!(validate_working_directory("../elsewhere").is_err());
+        assert!(validate_working_directory("server/../../x").is_err());
+        assert!(validate_working_directory("/etc").is_err());
+    }
+
+    #[test]
+    fn test_stop_behavior() {
+        assert_eq!(
+            StopBehavior::from_mode("stdin", Some("quit".to_string())),
+            Ok(StopBehavior::Stdin {
+                input: "quit".to_string()
+            })
+        );
+        assert_eq!(
+            StopBehavior::from_mode("sigterm", Some("ignored".to_string())),
+            Ok(StopBehavior::Sigterm)
+        );
+        assert!(StopBehavior::from_mode("stdin", Some(" ".to_string())).is_err());
+        assert!(StopBehavior::from_mode("command", None).is_err());
+        assert!(StopBehavior::from_mode("command", Some("./stop \"x".to_string())).is_err());
+        let stop: StopBehavior =
+            serde_json::from_str(r#"{"type":"command","command":"./stop.sh"}"#).unwrap();
+        assert_eq!(stop.mode(), "command");
+        assert_eq!(stop.input().unwrap(), "./stop.sh");
+    }
+
+    #[test]
+    fn test_construct_setup_config() {
+        let setup_value: SetupValue = serde_json::from_value(serde_json::json!({
+            "name": "proxy",
+            "description": null,
+            "auto_start": true,
+            "restart_on_crash": false,
+            "setting_sections": {
+                "command_section": {
+                    "settings": {
+                        "command": { "value": { "type": "String", "value": "./velocity --port 25577" } },
+                        "port": { "value": { "type": "UnsignedInteger", "value": 25577 } },
+                        "env": { "value": { "type": "String", "value": "JAVA_OPTS=\"-Xmx1G -Xms1G\"" } },
+                        "stop_mode": { "value": { "type": "Enum", "value": "stdin" } },
+                        "stop_input": { "value": { "type": "String", "value": "end" } },
+                        "running_pattern": { "value": { "type": "String", "value": "Done \\(\\d+" } }
+                    }
+                }
+            }
+        }))
+        .unwrap();
+        let config = CommandInstance::construct_setup_config(setup_value.clone()).unwrap();
+        assert_eq!(config.port, 25577);
+        assert_eq!(config.env.get("JAVA_OPTS").unwrap(), "-Xmx1G -Xms1G");
+        assert_eq!(
+            config.stop,
+            StopBehavior::Stdin {
+                input: "end".to_string()
+            }
+        );
+        assert!(config.auto_start);
+        assert_eq!(config.working_directory, None);
+
+        let mut bad = serde_json::to_value(&setup_value).unwrap();
+        bad["setting_sections"]["command_section"]["settings"]["running_pattern"]["value"]
+            ["value"] = serde_json::json!("(unclosed");
+        bad["setting_sections"]["command_section"]["settings"]["command"]["value"]["value"] =
+            serde_json::json!("  ");
+        let err = CommandInstance::construct_setup_config(serde_json::from_value(bad).unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("command"), "{}", err);
+        assert!(err.contains("running_pattern"), "{}", err);
+    }
+}
@@ -0,0 +1,648 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Context};
+use sysinfo::SystemExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+use crate::command_queue::{CommandPriority, CommandQueueConfig, CommandQueueStatus};
+use crate::console_history::ConsoleHistoryPage;
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::maintenance;
+use crate::prelude::app_state;
+use crate::process_tree::kill_tree;
+use crate::traits::t_server::{MonitorReport, State, StateAction, TServer};
+use crate::types::Snowflake;
+use crate::util::dont_spawn_terminal;
+
+use super::{split_command, CommandConfig, CommandInstance, StopBehavior};
+
+/// How long a sampled `MonitorReport` is reused before /proc is read again
+const MONITOR_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for the exit status once the process closed its output
+const PROCESS_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a forced kill waits for the output task to notice the exit before cleaning up itself
+const KILL_CLEANUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl CommandInstance {
+    fn instance_event(
+        &self,
+        name: &str,
+        instance_event_inner: InstanceEventInner,
+        details: &str,
+        caused_by: CausedBy,
+    ) -> Event {
+        Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_name: name.to_string(),
+                instance_uuid: self.uuid.clone(),
+                instance_event_inner,
+            }),
+            snowflake: Snowflake::default(),
+            details: details.to_string(),
+            caused_by,
+        }
+    }
+
+    /// Moves the state machine and announces the new state
+    async fn transition(
+        &self,
+        action: StateAction,
+        name: &str,
+        details: &str,
+        caused_by: &CausedBy,
+    ) -> Result<(), Error> {
+        self.state.lock().await.try_transition(
+            action,
+            Some(&|state| {
+                self.event_broadcaster.send(self.instance_event(
+                    name,
+                    InstanceEventInner::StateTransition { to: state },
+                    details,
+                    caused_by.clone(),
+                ));
+            }),
+        )
+    }
+
+    fn stop_timeout_message(&self, stop_timeout_secs: u32) -> String {
+        format!(
+            "The server did not stop within {} seconds, it can be force stopped with \
+             POST /api/v1/instance/{}/kill and {{\"confirm\": true}}",
+            stop_timeout_secs, self.uuid
+        )
+    }
+
+    /// Builds `command_line` to run in the working directory with the configured environment
+    fn build_command(&self, config: &CommandConfig, command_line: &str) -> Result<Command, Error> {
+        let args = split_command(command_line)?;
+        let (program, args) = args.split_first().ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The command is empty"),
+        })?;
+        let working_directory = self.working_directory(config)?;
+        // a program given as a path, e.g. ./start.sh, is relative to the working directory on
+        // every platform, a bare name is looked up in PATH
+        let program = if Path::new(program).components().count() > 1 {
+            working_directory.join(program)
+        } else {
+            PathBuf::from(program)
+        };
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .current_dir(working_directory)
+            .envs(&config.env);
+        Ok(command)
+    }
+
+    /// Asks the process to exit the configured way
+    async fn deliver_stop(
+        &self,
+        config: &CommandConfig,
+        caused_by: &CausedBy,
+    ) -> Result<(), Error> {
+        match &config.stop {
+            StopBehavior::Stdin { input } => {
+                self.command_queue
+                    .push_with_priority(input, caused_by.clone(), CommandPriority::Control)
+                    .await
+            }
+            StopBehavior::Sigterm => {
+                let mut process = self.process.lock().await;
+                let process = process
+                    .as_mut()
+                    .ok_or_else(|| eyre!("The server process is not running"))?;
+                #[cfg(unix)]
+                if let Some(pid) = process.id() {
+                    // SAFETY: killpg only sends a signal. The server leads its own group since it
+                    // was spawned with process_group(0)
+                    if unsafe { libc::killpg(pid as libc::pid_t, libc::SIGTERM) } == 0 {
+                        return Ok(());
+                    }
+                }
+                process
+                    .start_kill()
+                    .context("Failed to signal the server process")?;
+                Ok(())
+            }
+            StopBehavior::Command { command } => {
+                let mut stop_command = self.build_command(config, command)?;
+                let mut child = dont_spawn_terminal(&mut stop_command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .context(format!("Failed to run the stop command {}", command))?;
+                // the server exiting is what ends the stop, the command is only reaped here
+                let name = config.name.clone();
+                tokio::task::spawn(async move {
+                    match child.wait().await {
+                        Ok(status) if !status.success() => {
+                            warn!("[{}] The stop command exited with {}", name, status)
+                        }
+                        Err(e) => warn!("[{}] Failed to wait for the stop command: {}", name, e),
+                        _ => {}
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TServer for CommandInstance {
+    async fn start(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        app_state().migrating.check(&self.uuid)?;
+        maintenance::check_start(&self.path_to_instance, &self.uuid, &caused_by).await?;
+        let config = self.config.lock().await.clone();
+        let running_pattern = config
+            .running_pattern
+            .as_deref()
+            .map(fancy_regex::Regex::new)
+            .transpose()
+            .map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid running pattern: {}", e),
+            })?;
+        let mut command = self.build_command(&config, &config.command)?;
+        self.transition(
+            StateAction::UserStart,
+            &config.name,
+            "Starting server",
+            &caused_by,
+        )
+        .await?;
+        self.stop_requested.store(false, Ordering::SeqCst);
+
+        if !port_scanner::local_port_available(config.port as u16) {
+            self.transition(
+                StateAction::InstanceStop,
+                &config.name,
+                "Port already in use",
+                &caused_by,
+            )
+            .await?;
+            return Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!("Port {} is already in use", config.port),
+            });
+        }
+
+        // a group of its own lets SIGTERM and a forced kill reach everything the command spawned
+        #[cfg(unix)]
+        command.process_group(0);
+        let launch_command = std::iter::once(command.as_std().get_program())
+            .chain(command.as_std().get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        self.snapshot
+            .update(|snapshot| snapshot.launch_command = Some(launch_command));
+
+        let mut proc = match dont_spawn_terminal(&mut command)
+            .stdout(Stdio::piped())
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(proc) => proc,
+            Err(e) => {
+                error!("[{}] Failed to start server, {}", config.name, e);
+                self.transition(
+                    StateAction::InstanceStop,
+                    &config.name,
+                    "Failed to start server",
+                    &caused_by,
+                )
+                .await?;
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Failed to run {}: {}", config.command, e),
+                });
+            }
+        };
+        let stdin = proc
+            .stdin
+            .take()
+            .ok_or_else(|| eyre!("Failed to take stdin during startup"))?;
+        let stdout = proc
+            .stdout
+            .take()
+            .ok_or_else(|| eyre!("Failed to take stdout during startup"))?;
+        let stderr = proc
+            .stderr
+            .take()
+            .ok_or_else(|| eyre!("Failed to take stderr during startup"))?;
+        *self.process.lock().await = Some(proc);
+        self.command_queue.open().await;
+        self.command_queue.spawn_writer(stdin, {
+            let __self = self.clone();
+            let name = config.name.clone();
+            move |cmd| {
+                let details = if cmd.count > 1 {
+                    format!("Coalesced {} identical commands", cmd.count)
+                } else {
+                    "".to_string()
+                };
+                __self.event_broadcaster.send(__self.instance_event(
+                    &name,
+                    InstanceEventInner::InstanceInput {
+                        message: cmd.command.clone(),
+                    },
+                    &details,
+                    cmd.caused_by.clone(),
+                ));
+            }
+        });
+        // without a pattern there is nothing to wait for
+        if running_pattern.is_none() {
+            self.transition(
+                StateAction::InstanceStart,
+                &config.name,
+                "Server started",
+                &caused_by,
+            )
+            .await?;
+        }
+
+        let output_task = tokio::task::spawn({
+            let __self = self.clone();
+            let name = config.name.clone();
+            async move {
+                let mut did_start = running_pattern.is_none();
+                let mut stdout_reader = BufReader::new(stdout);
+                let mut stderr_reader = BufReader::new(stderr);
+                // kept across iterations, a read cancelled by the other stream resumes into them
+                let mut stdout_line = Vec::new();
+                let mut stderr_line = Vec::new();
+                let (mut stdout_open, mut stderr_open) = (true, true);
+
+                while stdout_open || stderr_open {
+                    let (read, is_stdout) = tokio::select! {
+                        read = stdout_reader.read_until(b'\n', &mut stdout_line), if stdout_open => {
+                            (read, true)
+                        }
+                        read = stderr_reader.read_until(b'\n', &mut stderr_line), if stderr_open => {
+                            (read, false)
+                        }
+                    };
+                    match read {
+                        Ok(0) => {}
+                        Ok(_) => {
+                            let buf = if is_stdout {
+                                &mut stdout_line
+                            } else {
+                                &mut stderr_line
+                            };
+                            let line = String::from_utf8_lossy(buf).to_string();
+                            buf.clear();
+                            if !is_stdout {
+                                warn!("[{}] {}", name, line);
+                            }
+                            let snowflake = Snowflake::default();
+                            __self
+                                .console_history
+                                .lock()
+                                .await
+                                .push(line.clone(), snowflake);
+                            __self.event_broadcaster.send(Event {
+                                snowflake,
+                                ..__self.instance_event(
+                                    &name,
+                                    InstanceEventInner::InstanceOutput {
+                                        message: line.clone(),
+                                    },
+                                    "",
+                                    CausedBy::System,
+                                )
+                            });
+                            let matched = is_stdout
+                                && !did_start
+                                && running_pattern.as_ref().map_or(false, |pattern| {
+                                    pattern
+                                        .is_match(line.trim_end_matches(['\r', '\n']))
+                                        .unwrap_or(false)
+                                });
+                            if matched {
+                                did_start = true;
+                                // a stop may already be underway, leave it alone then
+                                if __self.state().await == State::Starting {
+                                    let _ = __self
+                                        .transition(
+                                            StateAction::InstanceStart,
+                                            &name,
+                                            "Server started",
+                                            &CausedBy::System,
+                                        )
+                                        .await;
+                                    info!("[{}] Instance started", name);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("[{}] Failed to read from stdout/stderr: {}", name, e);
+                        }
+                    }
+                    // end of file or an unreadable stream
+                    if is_stdout {
+                        stdout_open = false;
+                    } else {
+                        stderr_open = false;
+                    }
+                }
+                info!("Instance {} process shutdown", name);
+                let status = match __self.process.lock().await.as_mut() {
+                    Some(process) => tokio::time::timeout(PROCESS_EXIT_TIMEOUT, process.wait())
+                        .await
+                        .ok()
+                        .and_then(|status| status.ok()),
+                    None => None,
+                };
+                let requested = __self.stop_requested.load(Ordering::SeqCst)
+                    || __self.state().await == State::Stopping;
+                let details = match (requested, status) {
+                    (true, _) => "Server stopped".to_string(),
+                    (false, Some(status)) => format!("Server process exited with {}", status),
+                    (false, None) => "Server process closed its output".to_string(),
+                };
+                let _ = __self
+                    .transition(
+                        StateAction::InstanceStop,
+                        &name,
+                        &details,
+                        &CausedBy::System,
+                    )
+                    .await;
+                __self.command_queue.close().await;
+                __self.process.lock().await.take();
+            }
+        });
+        *self.output_task.lock().await = Some(output_task);
+
+        if block {
+            let mut rx = self.event_broadcaster.subscribe();
+            // the state may have moved on before the subscription
+            match self.state().await {
+                State::Running => return Ok(()),
+                State::Stopped => {
+                    return Err(eyre!("Instance exited unexpectedly before starting").into())
+                }
+                _ => {}
+            }
+            while let Ok(event) = rx.recv().await {
+                if let EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid,
+                    instance_event_inner: InstanceEventInner::StateTransition { to },
+                    ..
+                }) = event.event_inner
+                {
+                    if instance_uuid == self.uuid {
+                        if to == State::Running {
+                            return Ok(());
+                        } else if to == State::Stopped {
+                            return Err(
+                                eyre!("Instance exited unexpectedly before starting").into()
+                            );
+                        }
+                    }
+                }
+            }
+            Err(eyre!("Sender shutdown").into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn stop(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        let config = self.config.lock().await.clone();
+        self.transition(
+            StateAction::UserStop,
+            &config.name,
+            "Stopping server",
+            &caused_by,
+        )
+        .await?;
+        self.stop_requested.store(true, Ordering::SeqCst);
+        let mut rx = self.event_broadcaster.subscribe();
+        if let Err(e) = self.deliver_stop(&config, &caused_by).await {
+            error!("[{}] Failed to stop instance: {}", config.name, e);
+            // the process never got the request and keeps running
+            self.stop_requested.store(false, Ordering::SeqCst);
+            if self.state().await == State::Stopping {
+                let _ = self
+                    .transition(
+                        StateAction::InstanceStart,
+                        &config.name,
+                        "Failed to stop server",
+                        &caused_by,
+                    )
+                    .await;
+            }
+            return Err(e);
+        }
+        let stop_timeout = Duration::from_secs(config.stop_timeout_secs as u64);
+
+        if block {
+            let stopped = tokio::time::timeout(stop_timeout, async {
+                // the process may have exited before the subscription
+                if self.state().await == State::Stopped {
+                    return Ok(());
+                }
+                while let Ok(event) = rx.recv().await {
+                    if let EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid,
+                        instance_event_inner: InstanceEventInner::StateTransition { to },
+                        ..
+                    }) = event.event_inner
+                    {
+                        if instance_uuid == self.uuid && to == State::Stopped {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(eyre!("Sender shutdown").into())
+            })
+            .await;
+            match stopped {
+                Ok(result) => result,
+                Err(_) => Err(Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!(self.stop_timeout_message(config.stop_timeout_secs)),
+                }),
+            }
+        } else {
+            let __self = self.clone();
+            tokio::task::spawn(async move {
+                tokio::time::sleep(stop_timeout).await;
+                if __self.state().await == State::Stopping {
+                    let message = __self.stop_timeout_message(config.stop_timeout_secs);
+                    warn!("[{}] {}", config.name, message);
+                    __self.event_broadcaster.send(__self.instance_event(
+                        &config.name,
+                        InstanceEventInner::InstanceWarning { message },
+                        "",
+                        caused_by,
+                    ));
+                }
+            });
+            Ok(())
+        }
+    }
+
+    async fn restart(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        // refused before stopping, so the server isn't left down
+        maintenance::check_start(&self.path_to_instance, &self.uuid, &caused_by).await?;
+        if block {
+            self.stop(caused_by.clone(), block).await?;
+            self.start(caused_by, block).await
+        } else {
+            self.state
+                .lock()
+                .await
+                .try_new_state(StateAction::UserStop, None)?;
+
+            let __self = self.clone();
+            tokio::task::spawn(async move {
+                if let Err(e) = __self.stop(caused_by.clone(), true).await {
+                    error!("Failed to stop instance for restart: {}", e);
+                    return;
+                }
+                if let Err(e) = __self.start(caused_by, false).await {
+                    error!("Failed to start instance for restart: {}", e);
+                }
+            });
+            Ok(())
+        }
+    }
+
+    async fn kill(&self, caused_by: CausedBy) -> Result<(), Error> {
+        let config = self.config.lock().await.clone();
+
+        if self.state().await == State::Stopped {
+            warn!("[{}] Instance is already stopped", config.name);
+            return Err(eyre!("Instance is already stopped").into());
+        }
+        self.stop_requested.store(true, Ordering::SeqCst);
+        let pid = self.process.lock().await.as_ref().and_then(|p| p.id());
+        let killed = match pid {
+            Some(pid) => kill_tree(&mut self.system.lock().await, pid),
+            None => 0,
+        };
+        if let Some(process) = self.process.lock().await.as_mut() {
+            let _ = process.start_kill();
+        }
+        warn!(
+            "[{}] Force killed the server, {} process(es) signalled",
+            config.name, killed
+        );
+        self.event_broadcaster.send(self.instance_event(
+            &config.name,
+            InstanceEventInner::InstanceWarning {
+                message: format!("Server force killed, {} process(es) signalled", killed),
+            },
+            "Instance was force killed",
+            caused_by.clone(),
+        ));
+
+        // the output task normally sees the pipes close and does the cleanup
+        let cleaned_up = tokio::time::timeout(KILL_CLEANUP_TIMEOUT, async {
+            while self.state().await != State::Stopped {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok();
+        if !cleaned_up {
+            warn!(
+                "[{}] Server output did not close after the kill, cleaning up",
+                config.name
+            );
+            if let Some(output_task) = self.output_task.lock().await.take() {
+                output_task.abort();
+            }
+            self.command_queue.close().await;
+            self.process.lock().await.take();
+            *self.state.lock().await = State::Stopped;
+            self.event_broadcaster.send(self.instance_event(
+                &config.name,
+                InstanceEventInner::StateTransition { to: State::Stopped },
+                "Instance was force killed",
+                caused_by,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn state(&self) -> State {
+        *self.state.lock().await
+    }
+
+    async fn send_command(&self, command: &str, caused_by: CausedBy) -> Result<(), Error> {
+        if self.state().await == State::Stopped {
+            return Err(eyre!("Instance is stopped").into());
+        }
+        self.command_queue.push(command, caused_by).await
+    }
+
+    async fn pid(&self) -> Option<u32> {
+        self.process.lock().await.as_ref().and_then(|p| p.id())
+    }
+
+    async fn monitor(&self) -> MonitorReport {
+        let mut last_report = self.last_monitor_report.lock().await;
+        if let Some((sampled_at, report)) = last_report.as_ref() {
+            if sampled_at.elapsed() < MONITOR_SAMPLE_INTERVAL {
+                return report.clone();
+            }
+        }
+        let mut sys = self.system.lock().await;
+        sys.refresh_memory();
+        let report = match self.process.lock().await.as_ref().and_then(|p| p.id()) {
+            Some(pid) => self.process_tree.lock().await.sample(&mut sys, pid).map_or(
+                MonitorReport::default(),
+                |tree| MonitorReport {
+                    memory_usage: Some(tree.memory_usage),
+                    disk_usage: Some(tree.disk_usage),
+                    cpu_usage: Some(tree.cpu_usage),
+                    start_time: Some(tree.start_time),
+                    uptime: Some(tree.uptime),
+                    process_count: Some(tree.process_count),
+                    top_children: Some(tree.top_children),
+                },
+            ),
+            None => MonitorReport::default(),
+        };
+        last_report.replace((Instant::now(), report.clone()));
+        report
+    }
+
+    async fn command_queue_status(&self) -> Result<CommandQueueStatus, Error> {
+        Ok(self.command_queue.status().await)
+    }
+
+    async fn set_command_queue_config(&self, config: CommandQueueConfig) -> Result<(), Error> {
+        self.command_queue.set_config(config).await?;
+        self.config.lock().await.command_queue = config;
+        self.write_config_to_file().await
+    }
+
+    async fn console_history(
+        &self,
+        offset: Option<u64>,
+        count: usize,
+    ) -> Result<ConsoleHistoryPage, Error> {
+        Ok(self.console_history.lock().await.page(offset, count))
+    }
+
+    async fn clear_console_history(&self) -> Result<(), Error> {
+        self.console_history.lock().await.clear();
+        Ok(())
+    }
+}
@@ -6,11 +6,8 @@ use indexmap::IndexMap;
 
 use super::GenericInstance;
 use crate::error::{Error, ErrorKind};
-use crate::implementations::generic::bridge::procedure_call::{
-    ProcedureCallInner, ProcedureCallResultInner,
-};
+use crate::implementations::generic::bridge::procedure_call::ProcedureCallInner;
 use crate::traits::t_configurable::manifest::{ConfigurableManifest, ConfigurableValue};
-use crate::traits::t_configurable::GameType;
 use crate::traits::t_configurable::{Game, TConfigurable};
 use crate::InstanceUuid;
 
@@ -21,54 +18,20 @@ impl TConfigurable for GenericInstance {
         self.dot_lodestone_config.uuid().clone()
     }
     async fn name(&self) -> String {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetName)
-            .await
-            .unwrap_or(ProcedureCallResultInner::String("Unknown".to_string()))
-            .try_into()
-            .unwrap_or("Unknown".to_string())
+        self.snapshot.load().name.clone()
     }
 
     async fn game_type(&self) -> Game {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetGame)
-            .await
-            .map_or(
-                Game::Generic {
-                    game_name: GameType::Generic,
-                    game_display_name: "Unknown".to_string(),
-                },
-                |r| {
-                    r.try_into().unwrap_or(Game::Generic {
-                        game_name: GameType::Generic,
-                        game_display_name: "Unknown".to_string(),
-                    })
-                },
-            )
+        self.snapshot.load().game_type.clone()
     }
     async fn version(&self) -> String {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetVersion)
-            .await
-            .unwrap_or(ProcedureCallResultInner::String("Unknown".to_string()))
-            .try_into()
-            .unwrap_or("Unknown".to_string())
+        self.snapshot.load().version.clone()
     }
     async fn description(&self) -> String {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetDescription)
-            .await
-            .unwrap_or(ProcedureCallResultInner::String("Unknown".to_string()))
-            .try_into()
-            .unwrap_or("Unknown".to_string())
+        self.snapshot.load().description.clone()
     }
     async fn port(&self) -> u32 {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetPort)
-            .await
-            .unwrap_or(ProcedureCallResultInner::Num(0))
-            .try_into()
-            .unwrap_or(0)
+        self.snapshot.load().port
     }
     async fn creation_time(&self) -> i64 {
         self.dot_lodestone_config.creation_time()
@@ -78,40 +41,36 @@ impl TConfigurable for GenericInstance {
     }
     /// does start when lodestone starts
     async fn auto_start(&self) -> bool {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetAutoStart)
-            .await
-            .unwrap_or(ProcedureCallResultInner::Bool(false))
-            .try_into()
-            .unwrap_or(false)
+        self.snapshot.load().auto_start
     }
     async fn restart_on_crash(&self) -> bool {
-        self.procedure_bridge
-            .call(ProcedureCallInner::GetRestartOnCrash)
-            .await
-            .unwrap_or(ProcedureCallResultInner::Bool(false))
-            .try_into()
-            .unwrap_or(false)
+        self.snapshot.load().restart_on_crash
     }
     // setters
     async fn set_name(&self, name: String) -> Result<(), Error> {
         self.procedure_bridge
-            .call(ProcedureCallInner::SetName { new_name: name })
+            .call(ProcedureCallInner::SetName {
+                new_name: name.clone(),
+            })
             .await?;
+        self.snapshot.update(|snapshot| snapshot.name = name);
         Ok(())
     }
     async fn set_description(&self, description: String) -> Result<(), Error> {
         self.procedure_bridge
             .call(ProcedureCallInner::SetDescription {
-                new_description: description,
+                new_description: description.clone(),
             })
             .await?;
+        self.snapshot
+            .update(|snapshot| snapshot.description = description);
         Ok(())
     }
     async fn set_port(&self, port: u32) -> Result<(), Error> {
         self.procedure_bridge
             .call(ProcedureCallInner::SetPort { new_port: port })
             .await?;
+        self.snapshot.update(|snapshot| snapshot.port = port);
         Ok(())
     }
     async fn set_auto_start(&self, auto_start: bool) -> Result<(), Error> {
@@ -120,6 +79,8 @@ impl TConfigurable for GenericInstance {
                 new_auto_start: auto_start,
             })
             .await?;
+        self.snapshot
+            .update(|snapshot| snapshot.auto_start = auto_start);
         Ok(())
     }
     async fn set_restart_on_crash(&self, restart_on_crash: bool) -> Result<(), Error> {
@@ -128,6 +89,8 @@ impl TConfigurable for GenericInstance {
                 new_restart_on_crash: restart_on_crash,
             })
             .await?;
+        self.snapshot
+            .update(|snapshot| snapshot.restart_on_crash = restart_on_crash);
         Ok(())
     }
     async fn set_backup_period(&self, _backup_period: Option<u32>) -> Result<(), Error> {
@@ -170,6 +133,8 @@ impl TConfigurable for GenericInstance {
                 new_value: value,
             })
             .await?;
+        // a setting may back any of the informational fields
+        self.refresh_snapshot().await;
         Ok(())
     }
 }
@@ -5,7 +5,10 @@ use color_eyre::eyre::Context;
 use tracing::{debug, error};
 
 use self::{
-    bridge::procedure_call::{emit_result, next_procedure, proc_bridge_ready, ProcedureCallInner},
+    bridge::procedure_call::{
+        emit_result, next_procedure, proc_bridge_ready, ProcedureBridge, ProcedureCallInner,
+        ProcedureCallResultInner,
+    },
     r#macro::GenericMainWorkerGenerator,
 };
 use crate::{
@@ -13,14 +16,15 @@ use crate::{
     event_broadcaster::EventBroadcaster,
     events::{CausedBy, ProgressionEventID},
     macro_executor::{self, MacroExecutor, MacroPID, SpawnResult, WorkerOptionGenerator},
+    run_record::RunRecord,
+    snapshot::{watch_instance_events, InstanceSnapshot, Snapshot},
     traits::{
         t_configurable::{
             manifest::{SetupManifest, SetupValue},
-            TConfigurable,
+            Game, GameType,
         },
-        t_player::TPlayerManagement,
-        t_server::TServer,
-        InstanceInfo, TInstance,
+        t_server::State,
+        TInstance,
     },
     types::DotLodestoneConfig,
 };
@@ -42,6 +46,8 @@ pub struct GenericInstance {
     path: PathBuf,
     core_macro_pid: MacroPID,
     drop_guard: Arc<GenericDropGuard>,
+    /// Informational fields, so reads don't queue behind the procedure bridge
+    snapshot: Snapshot<InstanceSnapshot>,
 }
 
 /// RAII guard for dropping a generic instance
@@ -149,6 +155,12 @@ impl GenericInstance {
                 path: path.clone(),
             })
             .await?;
+        let snapshot = Snapshot::new(fetch_snapshot(&procedure_bridge).await);
+        watch_instance_events(
+            &snapshot,
+            dot_lodestone_config.uuid().clone(),
+            &event_broadcaster,
+        );
         Ok(GenericInstance {
             dot_lodestone_config,
             procedure_bridge,
@@ -160,6 +172,7 @@ impl GenericInstance {
                 core_macro_pid,
                 macro_executor: core_macro_executor,
             }),
+            snapshot,
         })
     }
 
@@ -201,6 +214,12 @@ impl GenericInstance {
                 path: path_to_instance.clone(),
             })
             .await?;
+        let snapshot = Snapshot::new(fetch_snapshot(&procedure_bridge).await);
+        watch_instance_events(
+            &snapshot,
+            dot_lodestone_config.uuid().clone(),
+            &event_broadcaster,
+        );
         Ok(GenericInstance {
             dot_lodestone_config,
             procedure_bridge,
@@ -212,6 +231,7 @@ impl GenericInstance {
                 core_macro_pid,
                 macro_executor: core_macro_executor,
             }),
+            snapshot,
         })
     }
 
@@ -262,6 +282,14 @@ impl GenericInstance {
         ret
     }
 
+    /// Fetches the informational fields again, for when the runtime may have changed several
+    async fn refresh_snapshot(&self) {
+        let mut snapshot = fetch_snapshot(&self.procedure_bridge).await;
+        // the runtime doesn't know when the server last started, only the events tell
+        snapshot.last_started = self.snapshot.load().last_started;
+        self.snapshot.store(snapshot);
+    }
+
     /// Will notify the typescript side that the instance is being destructed
     pub async fn destruct(self) {
         let _ = self
@@ -274,25 +302,63 @@ impl GenericInstance {
     }
 }
 
+/// Calls into the runtime, `None` if the call or the conversion fails
+async fn try_call<T: TryFrom<ProcedureCallResultInner>>(
+    procedure_bridge: &ProcedureBridge,
+    procedure: ProcedureCallInner,
+) -> Option<T> {
+    procedure_bridge
+        .call(procedure)
+        .await
+        .ok()
+        .and_then(|r| r.try_into().ok())
+}
+
+async fn fetch_snapshot(procedure_bridge: &ProcedureBridge) -> InstanceSnapshot {
+    let unknown = || "Unknown".to_string();
+    InstanceSnapshot {
+        name: try_call(procedure_bridge, ProcedureCallInner::GetName)
+            .await
+            .unwrap_or_else(unknown),
+        game_type: try_call(procedure_bridge, ProcedureCallInner::GetGame)
+            .await
+            .unwrap_or_else(|| Game::Generic {
+                game_name: GameType::Generic,
+                game_display_name: unknown(),
+            }),
+        description: try_call(procedure_bridge, ProcedureCallInner::GetDescription)
+            .await
+            .unwrap_or_else(unknown),
+        version: try_call(procedure_bridge, ProcedureCallInner::GetVersion)
+            .await
+            .unwrap_or_else(unknown),
+        port: try_call(procedure_bridge, ProcedureCallInner::GetPort)
+            .await
+            .unwrap_or(0),
+        auto_start: try_call(procedure_bridge, ProcedureCallInner::GetAutoStart)
+            .await
+            .unwrap_or(false),
+        restart_on_crash: try_call(procedure_bridge, ProcedureCallInner::GetRestartOnCrash)
+            .await
+            .unwrap_or(false),
+        state: try_call(procedure_bridge, ProcedureCallInner::GetState)
+            .await
+            .unwrap_or(State::Stopped),
+        player_count: try_call(procedure_bridge, ProcedureCallInner::GetPlayerCount).await,
+        max_player_count: try_call(procedure_bridge, ProcedureCallInner::GetMaxPlayerCount).await,
+        player_list: try_call(procedure_bridge, ProcedureCallInner::GetPlayerList).await,
+        launch_command: None,
+        eula_accepted: None,
+        last_started: None,
+        last_run: RunRecord::default(),
+        degraded: false,
+    }
+}
+
 #[async_trait]
 impl TInstance for GenericInstance {
-    async fn get_instance_info(&self) -> InstanceInfo {
-        InstanceInfo {
-            uuid: self.uuid().await,
-            name: self.name().await,
-            game_type: self.game_type().await,
-            description: self.description().await,
-            version: self.version().await,
-            port: self.port().await,
-            creation_time: self.creation_time().await,
-            path: self.path().await.display().to_string(),
-            auto_start: self.auto_start().await,
-            restart_on_crash: self.restart_on_crash().await,
-            state: self.state().await,
-            player_count: self.get_player_count().await.ok(),
-            max_player_count: self.get_max_player_count().await.ok(),
-            player_list: self.get_player_list().await.ok(),
-        }
+    fn snapshot(&self) -> Arc<InstanceSnapshot> {
+        self.snapshot.load()
     }
 }
 
@@ -3,7 +3,12 @@ use tracing::error;
 use crate::{
     error::Error,
     events::CausedBy,
-    traits::t_server::{MonitorReport, State, TServer},
+    maintenance,
+    prelude::app_state,
+    traits::{
+        t_configurable::TConfigurable,
+        t_server::{MonitorReport, State, TServer},
+    },
 };
 
 use super::{bridge::procedure_call::ProcedureCallInner, GenericInstance};
@@ -11,6 +16,8 @@ use super::{bridge::procedure_call::ProcedureCallInner, GenericInstance};
 #[async_trait::async_trait]
 impl TServer for GenericInstance {
     async fn start(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        app_state().migrating.check(&self.uuid().await)?;
+        maintenance::check_start(&self.path().await, &self.uuid().await, &caused_by).await?;
         self.procedure_bridge
             .call(ProcedureCallInner::StartInstance { caused_by, block })
             .await?;
@@ -23,6 +30,7 @@ impl TServer for GenericInstance {
         Ok(())
     }
     async fn restart(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        maintenance::check_start(&self.path().await, &self.uuid().await, &caused_by).await?;
         self.procedure_bridge
             .call(ProcedureCallInner::RestartInstance { caused_by, block })
             .await?;
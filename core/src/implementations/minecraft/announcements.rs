@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tracing::{info, warn};
+
+use crate::announcements::{Announcement, AnnouncementContext, AnnouncementScheduler};
+use crate::error::Error;
+use crate::events::CausedBy;
+use crate::traits::t_player::TPlayerManagement;
+use crate::traits::t_server::{State, TServer};
+
+use super::MinecraftInstance;
+
+const ANNOUNCEMENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+impl MinecraftInstance {
+    /// Sends due announcements until the server process exits.
+    ///
+    /// Changes to the announcement config are picked up on the next poll
+    pub(super) async fn spawn_announcements_task(&self) {
+        let __self = self.clone();
+        let handle = tokio::task::spawn(async move {
+            let mut config = __self.config.lock().await.announcements.clone();
+            let mut scheduler = AnnouncementScheduler::new(&config, Utc::now());
+            let mut interval = tokio::time::interval(ANNOUNCEMENT_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                match __self.state().await {
+                    State::Running => {}
+                    State::Stopped | State::Error => break,
+                    _ => continue,
+                }
+                let current = __self.config.lock().await.announcements.clone();
+                if current != config {
+                    config = current;
+                    scheduler = AnnouncementScheduler::new(&config, Utc::now());
+                }
+                let due = scheduler.due(Utc::now());
+                if due.is_empty()
+                    || (config.require_players_online
+                        && __self.players_manager.lock().await.count() == 0)
+                {
+                    continue;
+                }
+                for announcement in due.iter().filter_map(|id| config.get(id)) {
+                    if let Err(e) = __self.send_announcement(announcement).await {
+                        warn!(
+                            "[{}] Failed to send announcement \"{}\": {}",
+                            __self.config.lock().await.name,
+                            announcement.id,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+        // a quick restart can outpace the previous task noticing the instance stopped
+        if let Some(previous) = self.announcements_task.lock().await.replace(handle) {
+            previous.abort();
+        }
+    }
+
+    pub(super) async fn send_announcement(&self, announcement: &Announcement) -> Result<(), Error> {
+        let context = AnnouncementContext {
+            instance_name: self.config.lock().await.name.clone(),
+            online_players: self.players_manager.lock().await.count(),
+            max_players: self.get_max_player_count().await.unwrap_or(20),
+        };
+        self.command_queue
+            .push(announcement.command(&context), CausedBy::System)
+            .await?;
+        info!(
+            "[{}] Sent announcement \"{}\"",
+            context.instance_name, announcement.id
+        );
+        Ok(())
+    }
+}
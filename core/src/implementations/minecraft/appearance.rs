@@ -0,0 +1,182 @@
+#[cfg(feature = "thumbnails")]
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, Context};
+#[cfg(feature = "thumbnails")]
+use image::imageops::FilterType;
+#[cfg(feature = "thumbnails")]
+use image::io::Limits;
+#[cfg(feature = "thumbnails")]
+use image::{ImageFormat, ImageOutputFormat};
+use indexmap::IndexMap;
+
+use crate::error::{Error, ErrorKind};
+#[cfg(not(feature = "thumbnails"))]
+use crate::features::Feature;
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+use crate::traits::t_configurable::TConfigurable;
+use crate::util::extended_length_path;
+
+use super::configurable::ServerPropertySetting;
+use super::util::{escape_property_value, unescape_property_value};
+use super::MinecraftInstance;
+
+const ICON_FILE_NAME: &str = "server-icon.png";
+/// The only size the server loads an icon at
+#[cfg(feature = "thumbnails")]
+const ICON_SIZE: u32 = 64;
+/// Larger uploads aren't worth decoding for a 64x64 icon
+#[cfg(feature = "thumbnails")]
+const MAX_SOURCE_DIMENSION: u32 = 4096;
+/// The icon is sent base64 encoded in the status response, a string the client reads at most
+/// 32767 characters of, which the MOTD and player list share
+#[cfg(feature = "thumbnails")]
+const MAX_ICON_BYTES: usize = 20 * 1024;
+/// The server list shows two lines of MOTD
+const MAX_MOTD_LINES: usize = 2;
+
+#[cfg(feature = "thumbnails")]
+fn bad_image(e: impl std::fmt::Display) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Not a usable PNG or JPEG image: {}", e),
+    }
+}
+
+/// Scales and center crops a PNG or JPEG to the 64x64 PNG the server expects
+#[cfg(feature = "thumbnails")]
+pub fn make_server_icon(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut reader = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(bad_image)?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg)) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The server icon must be a PNG or JPEG image"),
+        });
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    reader.limits(limits);
+    let icon = reader.decode().map_err(bad_image)?.resize_to_fill(
+        ICON_SIZE,
+        ICON_SIZE,
+        FilterType::Lanczos3,
+    );
+    let mut png = Vec::new();
+    icon.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .context("Failed to encode the server icon")?;
+    if png.len() > MAX_ICON_BYTES {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "The resized icon is {} bytes, more than the {} Minecraft accepts",
+                png.len(),
+                MAX_ICON_BYTES
+            ),
+        });
+    }
+    Ok(png)
+}
+
+/// Existing icons are still served and removed, only converting an upload needs the image codecs
+#[cfg(not(feature = "thumbnails"))]
+pub fn make_server_icon(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Feature::Thumbnails.disabled_error())
+}
+
+impl MinecraftInstance {
+    fn path_to_icon(&self) -> PathBuf {
+        self.path_to_instance.join(ICON_FILE_NAME)
+    }
+
+    /// The PNG bytes of `server-icon.png`, None if the instance has no icon
+    pub async fn server_icon(&self) -> Result<Option<Vec<u8>>, Error> {
+        match tokio::fs::read(extended_length_path(self.path_to_icon())).await {
+            Ok(icon) => Ok(Some(icon)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(eyre!("Failed to read server-icon.png: {}", e).into()),
+        }
+    }
+
+    /// Converts an uploaded image and writes it as `server-icon.png`, returning its path.
+    /// The server reads the icon when it starts
+    pub async fn set_server_icon(&self, data: Vec<u8>) -> Result<PathBuf, Error> {
+        let icon = tokio::task::spawn_blocking(move || make_server_icon(&data))
+            .await
+            .context("Failed to spawn blocking task")??;
+        let path = self.path_to_icon();
+        crate::util::fs::write_all(&path, icon).await?;
+        Ok(path)
+    }
+
+    /// Returns false if the instance had no icon
+    pub async fn remove_server_icon(&self) -> Result<bool, Error> {
+        let path = self.path_to_icon();
+        if !extended_length_path(&path).is_file() {
+            return Ok(false);
+        }
+        crate::util::fs::remove_file(&path).await?;
+        Ok(true)
+    }
+
+    /// The MOTD with the escapes of server.properties resolved
+    pub async fn motd(&self) -> String {
+        self.configurable_manifest
+            .lock()
+            .await
+            .get_unique_setting_key(&ServerPropertySetting::Motd(String::new()).get_identifier())
+            .and_then(|setting| setting.get_value().map(|v| v.try_as_string().cloned()))
+            .and_then(Result::ok)
+            .map(|motd| unescape_property_value(&motd))
+            .unwrap_or_default()
+    }
+
+    /// Writes an MOTD, `§` formatting codes, line breaks and any unicode included, escaped the
+    /// way the server reads server.properties
+    pub async fn set_motd(&self, motd: &str) -> Result<(), Error> {
+        let motd = motd.replace("\r\n", "\n");
+        if motd.lines().count() > MAX_MOTD_LINES {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "The server list only shows {} lines of MOTD",
+                    MAX_MOTD_LINES
+                ),
+            });
+        }
+        self.set_game_settings(IndexMap::from([(
+            ServerPropertySetting::Motd(String::new()).get_identifier(),
+            ConfigurableValue::String(escape_property_value(&motd)),
+        )]))
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "thumbnails"))]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn test_make_server_icon() {
+        let source = DynamicImage::ImageRgba8(ImageBuffer::from_fn(300, 120, |x, y| {
+            Rgba([(x % 256) as u8, (y % 256) as u8, 128, 255])
+        }));
+        let mut jpeg = Vec::new();
+        source
+            .to_rgb8()
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let icon = make_server_icon(&jpeg).unwrap();
+        assert!(icon.len() <= MAX_ICON_BYTES);
+        let decoded = image::load_from_memory_with_format(&icon, ImageFormat::Png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (ICON_SIZE, ICON_SIZE));
+
+        assert!(make_server_icon(b"GIF89a not really").is_err());
+        assert!(make_server_icon(b"definitely not an image").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Chat kept apart from the rest of the console, so bridges to other chats can read and write it
+//! without scraping the console
+
+use std::collections::VecDeque;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ts_rs::TS;
+use utoipa::ToSchema;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_server::TServer;
+use crate::types::Snowflake;
+
+use super::MinecraftInstance;
+
+/// Messages kept per instance, the oldest are dropped first
+pub const CHAT_HISTORY_SIZE: usize = 500;
+/// Most characters in a message sent to the server, what the game allows in its own chat
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 256;
+/// Most characters in the name a message is sent under
+pub const MAX_CHAT_SENDER_LENGTH: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, TS, ToSchema)]
+#[ts(export)]
+pub struct ChatMessage {
+    /// Increases by one per message, never reused within a daemon run
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp: i64,
+    /// For relayed messages, the name they were sent under
+    pub player: String,
+    pub message: String,
+    /// Sent through Lodestone rather than typed in game
+    pub relayed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, TS, ToSchema)]
+#[ts(export)]
+pub struct ChatPage {
+    /// Oldest first
+    pub messages: Vec<ChatMessage>,
+    /// Pass as `since` to get only the messages after these
+    pub next_seq: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ChatHistory {
+    messages: VecDeque<ChatMessage>,
+    next_seq: u64,
+}
+
+impl ChatHistory {
+    pub fn push(&mut self, player: String, message: String, relayed: bool) -> ChatMessage {
+        let message = ChatMessage {
+            seq: self.next_seq,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            player,
+            message,
+            relayed,
+        };
+        self.next_seq += 1;
+        if self.messages.len() == CHAT_HISTORY_SIZE {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message.clone());
+        message
+    }
+
+    /// The messages from sequence number `since` on, every message kept without it
+    pub fn since(&self, since: Option<u64>) -> ChatPage {
+        let since = since.unwrap_or(0);
+        ChatPage {
+            messages: self
+                .messages
+                .iter()
+                .filter(|message| message.seq >= since)
+                .cloned()
+                .collect(),
+            next_seq: self.next_seq,
+        }
+    }
+}
+
+/// Drops control characters, a line break would end the command and start one of the sender's
+/// choosing, and the section sign the game takes for formatting codes
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() && *c != '§')
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn validate(text: &str, what: &str, max_length: usize) -> Result<String, Error> {
+    let text = sanitize(text);
+    if text.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The {} can't be empty", what),
+        });
+    }
+    if text.chars().count() > max_length {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The {} can be at most {} characters long", what, max_length),
+        });
+    }
+    Ok(text)
+}
+
+/// Builds the command showing `message` to every player. Both parts go in plain `text`
+/// components serialized by serde, so quotes in them can't open components of their own, and
+/// the brackets keep the message from passing for a player's `<name>` chat
+fn tellraw_command(sender: &str, message: &str) -> String {
+    let components = json!([
+        "",
+        { "text": format!("[{}] ", sender), "color": "aqua" },
+        { "text": message },
+    ]);
+    format!("tellraw @a {}", components)
+}
+
+impl MinecraftInstance {
+    pub async fn chat(&self, since: Option<u64>) -> ChatPage {
+        self.chat_history.lock().await.since(since)
+    }
+
+    /// Shows the message in game with the sender's name in front of it
+    pub async fn send_chat(
+        &self,
+        sender: &str,
+        message: &str,
+        caused_by: CausedBy,
+    ) -> Result<ChatMessage, Error> {
+        let sender = validate(sender, "sender", MAX_CHAT_SENDER_LENGTH)?;
+        let message = validate(message, "message", MAX_CHAT_MESSAGE_LENGTH)?;
+        self.send_command(&tellraw_command(&sender, &message), caused_by.clone())
+            .await?;
+        let sent = self
+            .chat_history
+            .lock()
+            .await
+            .push(sender.clone(), message.clone(), true);
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid: self.uuid.clone(),
+                instance_name: self.config.lock().await.name.clone(),
+                instance_event_inner: InstanceEventInner::ChatRelayed { sender, message },
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by,
+        });
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tellraw_command_escapes_input() {
+        let sender = validate("Alex\n", "sender", MAX_CHAT_SENDER_LENGTH).unwrap();
+        let message = validate(
+            "\"},{\"text\":\"\",\"clickEvent\":{\"action\":\"run_command\",\"value\":\"/op Alex\"}}\nop Alex §k",
+            "message",
+            MAX_CHAT_MESSAGE_LENGTH,
+        )
+        .unwrap();
+        let command = tellraw_command(&sender, &message);
+        assert_eq!(command.lines().count(), 1);
+        let components: serde_json::Value =
+            serde_json::from_str(command.strip_prefix("tellraw @a ").unwrap()).unwrap();
+        let components = components.as_array().unwrap();
+        assert_eq!(components.len(), 3);
+        assert_eq!(components[1]["text"], "[Alex] ");
+        assert_eq!(
+            components[2],
+            json!({ "text": "\"},{\"text\":\"\",\"clickEvent\":{\"action\":\"run_command\",\"value\":\"/op Alex\"}}op Alex k" })
+        );
+
+        assert!(validate(" \n", "message", MAX_CHAT_MESSAGE_LENGTH).is_err());
+        assert!(validate(&"a".repeat(257), "message", MAX_CHAT_MESSAGE_LENGTH).is_err());
+    }
+
+    #[test]
+    fn test_chat_history() {
+        let mut history = ChatHistory::default();
+        for i in 0..CHAT_HISTORY_SIZE + 2 {
+            history.push("Steve".to_string(), format!("message {i}"), false);
+        }
+        let page = history.since(None);
+        assert_eq!(page.messages.len(), CHAT_HISTORY_SIZE);
+        assert_eq!(page.messages[0].seq, 2);
+        assert_eq!(page.next_seq, CHAT_HISTORY_SIZE as u64 + 2);
+
+        let sent = history.push("Alex".to_string(), "hi".to_string(), true);
+        let page = history.since(Some(page.next_seq));
+        assert_eq!(page.messages, vec![sent]);
+        assert!(history.since(Some(page.next_seq)).messages.is_empty());
+    }
+}
@@ -3,19 +3,36 @@ use std::sync::atomic;
 
 use async_trait::async_trait;
 use color_eyre::eyre::{eyre, Context, ContextCompat};
+use indexmap::IndexMap;
 
+use crate::backups::BackupSchedule;
+use crate::diagnostics::{accept_eula, PreflightTarget};
 use crate::error::{Error, ErrorKind};
+use crate::events::{
+    CausedBy, Event, ProgressionEndValue, ProgressionStartBuilder, ProgressionStartValue,
+};
+use crate::java::{install_runtime, probe, JavaRequirement, JavaSelection, JavaSource};
 use crate::prelude::path_to_tmp;
+use crate::restart_policy::{RestartMode, RestartPolicy};
 use crate::traits::t_configurable::manifest::{
     ConfigurableManifest, ConfigurableValue, ConfigurableValueType, SettingManifest,
+    SettingValidationError, SettingValidationErrors,
 };
 use crate::traits::t_configurable::{Game, TConfigurable};
 use crate::traits::t_server::State;
 
+use crate::downloads::download_jar;
 use crate::types::InstanceUuid;
-use crate::util::download_file;
 
-use super::util::{get_fabric_jar_url, get_paper_jar_url, get_vanilla_jar_url};
+use super::forge::ForgeLaunchTarget;
+use super::hooks::{format_env, parse_env};
+use super::jvm_args::split_args;
+use super::server_launchers::QUILT_LAUNCHER;
+use super::server_list_ping::DEFAULT_PING_INTERVAL_SECS;
+use super::util::{
+    get_fabric_jar_url, get_jre_url, get_paper_jar_url, get_server_jar_checksum,
+    get_vanilla_jar_url, merge_properties,
+};
 use super::MinecraftInstance;
 
 #[async_trait]
@@ -25,23 +42,23 @@ impl TConfigurable for MinecraftInstance {
     }
 
     async fn name(&self) -> String {
-        self.config.lock().await.name.clone()
+        self.snapshot.load().name.clone()
     }
 
     async fn game_type(&self) -> Game {
-        self.config.lock().await.flavour.clone().into()
+        self.snapshot.load().game_type.clone()
     }
 
     async fn version(&self) -> String {
-        self.config.lock().await.version.clone()
+        self.snapshot.load().version.clone()
     }
 
     async fn description(&self) -> String {
-        self.config.lock().await.description.clone()
+        self.snapshot.load().description.clone()
     }
 
     async fn port(&self) -> u32 {
-        self.config.lock().await.port
+        self.snapshot.load().port
     }
 
     async fn creation_time(&self) -> i64 {
@@ -53,11 +70,15 @@ impl TConfigurable for MinecraftInstance {
     }
 
     async fn auto_start(&self) -> bool {
-        self.config.lock().await.auto_start
+        self.snapshot.load().auto_start
     }
 
     async fn restart_on_crash(&self) -> bool {
-        self.config.lock().await.restart_on_crash
+        self.snapshot.load().restart_on_crash
+    }
+
+    async fn restart_policy(&self) -> RestartPolicy {
+        self.config.lock().await.restart_policy()
     }
 
     async fn set_name(&self, name: String) -> Result<(), Error> {
@@ -99,16 +120,189 @@ impl TConfigurable for MinecraftInstance {
     async fn set_auto_start(&self, auto_start: bool) -> Result<(), Error> {
         self.config.lock().await.auto_start = auto_start;
         self.auto_start.store(auto_start, atomic::Ordering::Relaxed);
+        let setting = LodestoneSetting::AutoStart(auto_start);
+        self.configurable_manifest
+            .lock()
+            .await
+            .update_setting_value(
+                LodestoneSetting::get_section_id(),
+                setting.get_identifier(),
+                ConfigurableValue::Boolean(auto_start),
+            )?;
         self.write_config_to_file().await
     }
 
     async fn set_restart_on_crash(&self, restart_on_crash: bool) -> Result<(), Error> {
-        self.config.lock().await.restart_on_crash = restart_on_crash;
-        self.auto_start
+        let mut restart_policy = self.config.lock().await.restart_policy();
+        restart_policy.mode = match (restart_on_crash, restart_policy.mode) {
+            (false, _) => RestartMode::Never,
+            (true, RestartMode::Never) => RestartMode::OnCrash,
+            (true, mode) => mode,
+        };
+        self.set_restart_policy(restart_policy).await
+    }
+
+    async fn set_restart_policy(&self, restart_policy: RestartPolicy) -> Result<(), Error> {
+        restart_policy.validate()?;
+        let restart_on_crash = restart_policy.mode != RestartMode::Never;
+        {
+            let mut config = self.config.lock().await;
+            config.restart_on_crash = restart_on_crash;
+            config.restart_policy = Some(restart_policy);
+        }
+        self.restart_on_crash
             .store(restart_on_crash, atomic::Ordering::Relaxed);
         self.write_config_to_file().await
     }
 
+    async fn backup_schedule(&self) -> Result<BackupSchedule, Error> {
+        Ok(self.config.lock().await.backup_schedule)
+    }
+
+    async fn set_backup_schedule(&self, backup_schedule: BackupSchedule) -> Result<(), Error> {
+        backup_schedule.validate()?;
+        self.config.lock().await.backup_schedule = backup_schedule;
+        self.write_config_to_file().await
+    }
+
+    async fn preflight_target(&self) -> Result<PreflightTarget, Error> {
+        let config = self.config.lock().await;
+        Ok(PreflightTarget {
+            path: self.path_to_instance.clone(),
+            port: config.port,
+            java: self.java_path(&config),
+            // modern Forge launches from an args file, older ones from a versioned jar
+            server_jar: match &config.flavour {
+                super::Flavour::Forge { build_version } => match ForgeLaunchTarget::detect(
+                    &self.path_to_instance,
+                    &config.version,
+                    build_version.as_ref(),
+                )
+                .await
+                {
+                    Ok(ForgeLaunchTarget::Jar(jar)) => Some(jar),
+                    _ => None,
+                },
+                super::Flavour::Quilt { .. } => Some(self.path_to_instance.join(QUILT_LAUNCHER)),
+                _ => Some(self.path_to_instance.join("server.jar")),
+            },
+            max_ram_mb: config.max_ram,
+        })
+    }
+
+    async fn accept_eula(&self) -> Result<(), Error> {
+        accept_eula(&self.path_to_instance).await?;
+        self.snapshot
+            .update(|snapshot| snapshot.eula_accepted = Some(true));
+        Ok(())
+    }
+
+    async fn java_selection(&self) -> Result<JavaSelection, Error> {
+        let config = self.config.lock().await.clone();
+        Ok(self.resolve_java(&config).await)
+    }
+
+    async fn set_java(&self, path: std::path::PathBuf) -> Result<JavaSelection, Error> {
+        let runtime = probe(&path, JavaSource::Custom)
+            .await
+            .ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("{} is not a working Java executable", path.display()),
+            })?;
+        let config = self.config.lock().await.clone();
+        if let Some(requirement) = JavaRequirement::for_minecraft(&config.version, &config.flavour)
+        {
+            if !requirement.is_met_by(runtime.major_version) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Minecraft {} needs {}, but {} is Java {}",
+                        config.version,
+                        requirement,
+                        path.display(),
+                        runtime.major_version
+                    ),
+                });
+            }
+        }
+        self.pin_java(&path).await?;
+        self.java_selection().await
+    }
+
+    async fn download_java(&self, caused_by: CausedBy) -> Result<(), Error> {
+        let config = self.config.lock().await.clone();
+        let major_version = match JavaRequirement::for_minecraft(&config.version, &config.flavour) {
+            Some(requirement) => requirement.preferred_major(),
+            // snapshots aren't covered by the table, Mojang's manifest knows them
+            None => get_jre_url(&config.version)
+                .await
+                .map(|(_, major_version)| major_version as u32)
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Could not tell which Java Minecraft {} needs",
+                        config.version
+                    ),
+                })?,
+        };
+        let (progression_start, event_id) = ProgressionStartBuilder::new(
+            format!("Downloading Java {} for {}", major_version, config.name),
+            ProgressionStartValue::JavaDownload {
+                instance_uuid: self.uuid.clone(),
+                major_version,
+            },
+            caused_by,
+        )
+        .total(100.0)
+        .build();
+        self.event_broadcaster.send(progression_start);
+
+        let __self = self.clone();
+        tokio::task::spawn(async move {
+            let event_broadcaster = __self.event_broadcaster.clone();
+            let installed = install_runtime(
+                major_version,
+                &__self.path_to_runtimes.join("java"),
+                &|dl| {
+                    if let Some(total) = dl.total {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            &event_id,
+                            format!("Downloading Java {}", major_version),
+                            (dl.step as f64 / total as f64) * 100.0,
+                        ));
+                    }
+                },
+            )
+            .await;
+            let pinned = match installed {
+                Ok(path) => __self.pin_java(&path).await.map(|_| path),
+                Err(e) => Err(e),
+            };
+            event_broadcaster.send(match pinned {
+                Ok(path) => Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some(format!("Java {} is ready", major_version)),
+                    Some(ProgressionEndValue::JavaDownload {
+                        instance_uuid: __self.uuid.clone(),
+                        major_version,
+                        path,
+                    }),
+                ),
+                Err(e) => Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(format!(
+                        "Failed to download Java {}: {}",
+                        major_version, e.source
+                    )),
+                    None,
+                ),
+            });
+        });
+        Ok(())
+    }
+
     async fn change_version(&self, version: String) -> Result<(), Error> {
         if *self.state.lock().await != State::Stopped {
             return Err(Error {
@@ -119,7 +313,9 @@ impl TConfigurable for MinecraftInstance {
         if version == self.config.lock().await.version {
             return Ok(());
         }
-        let (url, _) = match self.config.lock().await.flavour {
+        // the config lock must not be held across the download below
+        let flavour = self.config.lock().await.flavour.clone();
+        let (url, jar_flavour) = match flavour {
             super::Flavour::Vanilla => get_vanilla_jar_url(&version).await.ok_or_else(|| {
                 let error_msg =
                     format!("Cannot get the vanilla jar version for version {}", version);
@@ -155,18 +351,18 @@ impl TConfigurable for MinecraftInstance {
                     source: eyre!("Changing versions is unsupported for forge servers"),
                 })
             }
+            super::Flavour::Quilt { .. } => {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!("Changing versions is unsupported for quilt servers"),
+                })
+            }
         };
         let lodestone_tmp = path_to_tmp().clone();
         let temp_dir = tempfile::tempdir_in(lodestone_tmp).context("Failed to create temp dir")?;
-        download_file(
-            &url,
-            temp_dir.path(),
-            Some("server.jar"),
-            &Box::new(|_| {}),
-            true,
-        )
-        .await?;
+        let checksum = get_server_jar_checksum(&version, &jar_flavour).await;
         let jar_path = temp_dir.path().join("server.jar");
+        download_jar(&url, checksum.as_ref(), &jar_path, &|_| {}).await?;
         crate::util::fs::rename(jar_path, self.path().await.join("server.jar")).await?;
         self.config.lock().await.version = version;
         self.write_config_to_file().await
@@ -181,12 +377,118 @@ impl TConfigurable for MinecraftInstance {
         self.configurable_manifest.lock().await.clone()
     }
 
+    async fn game_settings(&self) -> Result<IndexMap<String, SettingManifest>, Error> {
+        Ok(self
+            .configurable_manifest()
+            .await
+            .get_section(ServerPropertySetting::get_section_id())
+            .map(|section| section.all_settings().clone())
+            .unwrap_or_default())
+    }
+
+    async fn set_game_settings(
+        &self,
+        settings: IndexMap<String, ConfigurableValue>,
+    ) -> Result<(), Error> {
+        let current = self.game_settings().await?;
+        let mut updates = IndexMap::new();
+        let mut validated = Vec::new();
+        let mut errors = Vec::new();
+        for (key, value) in settings {
+            let line_value = value.to_string();
+            // also catches what the manifest types don't bound
+            let parsed = match ServerPropertySetting::from_key_val(&key, &line_value) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    errors.push(SettingValidationError::new(key, e.source));
+                    continue;
+                }
+            };
+            let mut setting: SettingManifest = match current.get(&key) {
+                Some(setting) => setting.clone(),
+                None if matches!(parsed, ServerPropertySetting::Unknown(..)) => {
+                    errors.push(SettingValidationError::new(key, "Unknown setting"));
+                    continue;
+                }
+                None => parsed.into(),
+            };
+            if let Err(e) = setting.set_value(value) {
+                errors.push(SettingValidationError::new(key, e.source));
+                continue;
+            }
+            updates.insert(key, line_value);
+            validated.push(setting);
+        }
+        // nothing is written unless every value is valid
+        if !errors.is_empty() {
+            return Err(SettingValidationErrors(errors).into());
+        }
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let content = match tokio::fs::read_to_string(&self.path_to_properties).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => Err(e).context("Failed to read server.properties")?,
+        };
+        tokio::fs::write(
+            &self.path_to_properties,
+            merge_properties(&content, &updates),
+        )
+        .await
+        .context("Failed to write server.properties")?;
+        {
+            let mut manifest = self.configurable_manifest.lock().await;
+            for setting in validated {
+                manifest.set_setting(ServerPropertySetting::get_section_id(), setting)?;
+            }
+        }
+        self.sync_configurable_to_restore_config().await;
+        self.write_config_to_file().await?;
+        self.refresh_snapshot().await;
+        Ok(())
+    }
+
     async fn update_configurable(
         &self,
         section_id: &str,
         setting_id: &str,
         value: ConfigurableValue,
     ) -> Result<(), Error> {
+        if section_id == LodestoneSetting::get_section_id() {
+            // parsed first, a malformed environment leaves the setting as it was
+            let env = if setting_id == LodestoneSetting::Env(IndexMap::new()).get_identifier() {
+                Some(parse_env(value.try_as_string()?)?)
+            } else {
+                None
+            };
+            // checks the setting exists and the value's type before acting on it
+            self.configurable_manifest
+                .lock()
+                .await
+                .update_setting_value(section_id, setting_id, value.clone())?;
+            if setting_id == LodestoneSetting::PingInterval(0).get_identifier() {
+                // the ping task reads the interval before every ping
+                self.config.lock().await.ping_interval_secs = value.try_as_unsigned_integer()?;
+                return self.write_config_to_file().await;
+            }
+            if let Some(env) = env {
+                self.config.lock().await.env = env;
+                return self.write_config_to_file().await;
+            }
+            if setting_id == LodestoneSetting::PreStartHook(None).get_identifier() {
+                self.config.lock().await.pre_start_hook = hook_command(&value)?;
+                return self.write_config_to_file().await;
+            }
+            if setting_id == LodestoneSetting::PostStopHook(None).get_identifier() {
+                self.config.lock().await.post_stop_hook = hook_command(&value)?;
+                return self.write_config_to_file().await;
+            }
+            return self.set_auto_start(value.try_as_boolean()?).await;
+        }
+        if section_id == CmdArgSetting::get_section_id() {
+            self.validate_cmd_arg_update(setting_id, &value).await?;
+        }
         let _ = self.read_properties().await;
         self.configurable_manifest
             .lock()
@@ -198,6 +500,12 @@ impl TConfigurable for MinecraftInstance {
     }
 }
 
+/// An empty hook runs nothing
+fn hook_command(value: &ConfigurableValue) -> Result<Option<String>, Error> {
+    let command = value.try_as_string()?.trim();
+    Ok((!command.is_empty()).then(|| command.to_string()))
+}
+
 pub(super) enum InstanceSetting {
     CmdArg(CmdArgSetting),
     ServerProperty(ServerPropertySetting),
@@ -277,6 +585,7 @@ pub(super) enum CmdArgSetting {
     MaxRam(u32),
     JavaCmd(String),
     Args(Vec<String>),
+    ExtraJvmArgs(Vec<String>),
 }
 
 impl CmdArgSetting {
@@ -289,6 +598,7 @@ impl CmdArgSetting {
             CmdArgSetting::MaxRam(_) => "max_ram",
             CmdArgSetting::JavaCmd(_) => "java_cmd",
             CmdArgSetting::Args(_) => "cmd_args",
+            CmdArgSetting::ExtraJvmArgs(_) => "extra_jvm_args",
         }
     }
     pub fn get_name(&self) -> &'static str {
@@ -297,6 +607,7 @@ impl CmdArgSetting {
             CmdArgSetting::MaxRam(_) => "Maximum RAM",
             CmdArgSetting::JavaCmd(_) => "Java command",
             CmdArgSetting::Args(_) => "Command line arguments",
+            CmdArgSetting::ExtraJvmArgs(_) => "Extra JVM arguments",
         }
     }
     pub fn get_description(&self) -> &'static str {
@@ -309,6 +620,9 @@ impl CmdArgSetting {
             }
             CmdArgSetting::JavaCmd(_) => "The command to use to run the java executable",
             CmdArgSetting::Args(_) => "The command line arguments to pass to the server",
+            CmdArgSetting::ExtraJvmArgs(_) => {
+                "Flags passed to Java before the server jar, e.g. garbage collector tuning"
+            }
         }
     }
     pub fn from_key_val(key: &str, val: &str) -> Result<Self, Error> {
@@ -323,6 +637,7 @@ impl CmdArgSetting {
             "cmd_args" => Ok(CmdArgSetting::Args(
                 val.split(' ').map(|s| s.to_string()).collect(),
             )),
+            "extra_jvm_args" => Ok(CmdArgSetting::ExtraJvmArgs(split_args(val))),
             _ => Err(Error {
                 kind: ErrorKind::BadRequest,
                 source: eyre!("Invalid key"),
@@ -330,13 +645,25 @@ impl CmdArgSetting {
         }
     }
     pub fn is_key_valid(key: &str) -> bool {
-        matches!(key, "min_ram" | "max_ram" | "java_cmd" | "cmd_args")
+        matches!(
+            key,
+            "min_ram" | "max_ram" | "java_cmd" | "cmd_args" | "extra_jvm_args"
+        )
     }
 }
 
 impl From<CmdArgSetting> for SettingManifest {
     fn from(value: CmdArgSetting) -> Self {
-        match value {
+        let default_value = match value {
+            CmdArgSetting::MinRam(_) => Some(ConfigurableValue::UnsignedInteger(1024)),
+            CmdArgSetting::MaxRam(_) => Some(ConfigurableValue::UnsignedInteger(2048)),
+            // found on the system when left unset
+            CmdArgSetting::JavaCmd(_) => None,
+            CmdArgSetting::Args(_) | CmdArgSetting::ExtraJvmArgs(_) => {
+                Some(ConfigurableValue::String(String::new()))
+            }
+        };
+        let setting = match value {
             CmdArgSetting::MinRam(min_ram) => SettingManifest::new_optional_value(
                 value.get_identifier().to_owned(),
                 value.get_name().to_owned(),
@@ -383,7 +710,21 @@ impl From<CmdArgSetting> for SettingManifest {
                 false,
                 true,
             ),
-        }
+            CmdArgSetting::ExtraJvmArgs(ref args) => SettingManifest::new_optional_value(
+                value.get_identifier().to_owned(),
+                value.get_name().to_owned(),
+                value.get_description().to_owned(),
+                Some(ConfigurableValue::String(args.join(" "))),
+                ConfigurableValueType::String { regex: None },
+                None,
+                false,
+                true,
+            ),
+        };
+        // all of them are part of the command the server is launched with
+        setting
+            .with_default_value(default_value)
+            .with_restart_required()
     }
 }
 
@@ -420,6 +761,12 @@ impl TryFrom<SettingManifest> for CmdArgSetting {
                     .map(|s| s.to_string())
                     .collect(),
             )),
+            "extra_jvm_args" => Ok(CmdArgSetting::ExtraJvmArgs(split_args(
+                value
+                    .get_value()
+                    .context("Expected a value")?
+                    .try_as_string()?,
+            ))),
             _ => Err(Error {
                 kind: ErrorKind::BadRequest,
                 source: eyre!("Invalid key"),
@@ -428,6 +775,105 @@ impl TryFrom<SettingManifest> for CmdArgSetting {
     }
 }
 
+/// Settings Lodestone acts on itself instead of handing them to the server
+#[derive(Debug)]
+pub(super) enum LodestoneSetting {
+    AutoStart(bool),
+    PingInterval(u32),
+    Env(IndexMap<String, String>),
+    PreStartHook(Option<String>),
+    PostStopHook(Option<String>),
+}
+
+impl LodestoneSetting {
+    pub fn get_section_id() -> &'static str {
+        "lodestone_section"
+    }
+    pub fn get_identifier(&self) -> &'static str {
+        match self {
+            LodestoneSetting::AutoStart(_) => "auto_start",
+            LodestoneSetting::PingInterval(_) => "ping_interval_secs",
+            LodestoneSetting::Env(_) => "env",
+            LodestoneSetting::PreStartHook(_) => "pre_start_hook",
+            LodestoneSetting::PostStopHook(_) => "post_stop_hook",
+        }
+    }
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            LodestoneSetting::AutoStart(_) => "Auto start",
+            LodestoneSetting::PingInterval(_) => "Ping interval",
+            LodestoneSetting::Env(_) => "Environment variables",
+            LodestoneSetting::PreStartHook(_) => "Pre-start hook",
+            LodestoneSetting::PostStopHook(_) => "Post-stop hook",
+        }
+    }
+    pub fn get_description(&self) -> &'static str {
+        match self {
+            LodestoneSetting::AutoStart(_) => "Start the server when Lodestone starts",
+            LodestoneSetting::PingInterval(_) => {
+                "Seconds between server list pings that check the running server responds, 0 disables them"
+            }
+            LodestoneSetting::Env(_) => {
+                "One KEY=VALUE per line, set for the server and its hooks, e.g. JAVA_TOOL_OPTIONS"
+            }
+            LodestoneSetting::PreStartHook(_) => {
+                "Shell command run in the instance's directory before the server starts, a failure aborts the start"
+            }
+            LodestoneSetting::PostStopHook(_) => {
+                "Shell command run in the instance's directory after the server stops, e.g. to sync the world elsewhere"
+            }
+        }
+    }
+}
+
+impl From<LodestoneSetting> for SettingManifest {
+    fn from(value: LodestoneSetting) -> Self {
+        match value {
+            LodestoneSetting::AutoStart(auto_start) => SettingManifest::new_required_value(
+                value.get_identifier().to_owned(),
+                value.get_name().to_owned(),
+                value.get_description().to_owned(),
+                ConfigurableValue::Boolean(auto_start),
+                Some(ConfigurableValue::Boolean(false)),
+                false,
+                true,
+            ),
+            LodestoneSetting::PingInterval(interval) => SettingManifest::new_required_value(
+                value.get_identifier().to_owned(),
+                value.get_name().to_owned(),
+                value.get_description().to_owned(),
+                ConfigurableValue::UnsignedInteger(interval),
+                Some(ConfigurableValue::UnsignedInteger(
+                    DEFAULT_PING_INTERVAL_SECS,
+                )),
+                false,
+                true,
+            ),
+            // values are often license keys and the like
+            LodestoneSetting::Env(ref env) => SettingManifest::new_required_value(
+                value.get_identifier().to_owned(),
+                value.get_name().to_owned(),
+                value.get_description().to_owned(),
+                ConfigurableValue::String(format_env(env)),
+                Some(ConfigurableValue::String(String::new())),
+                true,
+                true,
+            )
+            .with_restart_required(),
+            LodestoneSetting::PreStartHook(ref command)
+            | LodestoneSetting::PostStopHook(ref command) => SettingManifest::new_required_value(
+                value.get_identifier().to_owned(),
+                value.get_name().to_owned(),
+                value.get_description().to_owned(),
+                ConfigurableValue::String(command.clone().unwrap_or_default()),
+                Some(ConfigurableValue::String(String::new())),
+                false,
+                true,
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub(super) enum Gamemode {
     #[default]
@@ -503,6 +949,74 @@ impl FromStr for Difficulty {
     }
 }
 
+/// What a vanilla server writes to a fresh server.properties
+const VANILLA_PROPERTIES: &[(&str, &str)] = &[
+    ("enable-jmx-monitoring", "false"),
+    ("rcon.port", "25575"),
+    ("level-seed", ""),
+    ("gamemode", "survival"),
+    ("enable-command-block", "false"),
+    ("enable-query", "false"),
+    ("generator-settings", "{}"),
+    ("enforce-secure-profile", "true"),
+    ("level-name", "world"),
+    ("motd", "A Minecraft Server"),
+    ("query.port", "25565"),
+    ("pvp", "true"),
+    ("generate-structures", "true"),
+    ("max-chained-neighbor-updates", "1000000"),
+    ("difficulty", "easy"),
+    ("network-compression-threshold", "256"),
+    ("require-resource-pack", "false"),
+    ("max-tick-time", "60000"),
+    ("max-players", "20"),
+    ("use-native-transport", "true"),
+    ("online-mode", "true"),
+    ("enable-status", "true"),
+    ("allow-flight", "false"),
+    ("initial-disabled-packs", ""),
+    ("broadcast-rcon-to-ops", "true"),
+    ("view-distance", "10"),
+    ("resource-pack-prompt", ""),
+    ("server-ip", ""),
+    ("allow-nether", "true"),
+    ("server-port", "25565"),
+    ("enable-rcon", "false"),
+    ("sync-chunk-writes", "true"),
+    ("op-permission-level", "4"),
+    ("prevent-proxy-connections", "false"),
+    ("hide-online-players", "false"),
+    ("resource-pack", ""),
+    ("entity-broadcast-range-percentage", "100"),
+    ("simulation-distance", "10"),
+    ("rcon.password", ""),
+    ("player-idle-timeout", "0"),
+    ("force-gamemode", "false"),
+    ("rate-limit", "0"),
+    ("hardcore", "false"),
+    ("white-list", "false"),
+    ("broadcast-console-to-ops", "true"),
+    ("previews-chat", "false"),
+    ("spawn-npcs", "true"),
+    ("spawn-animals", "true"),
+    ("function-permission-level", "2"),
+    ("initial-enabled-packs", "vanilla"),
+    ("level-type", "minecraft\\:normal"),
+    ("text-filtering-config", ""),
+    ("spawn-monsters", "true"),
+    ("enforce-whitelist", "false"),
+    ("spawn-protection", "16"),
+    ("resource-pack-sha1", ""),
+    ("max-world-size", "29999984"),
+];
+
+fn vanilla_default(key: &str) -> Option<&'static str> {
+    VANILLA_PROPERTIES
+        .iter()
+        .find(|(property, _)| *property == key)
+        .map(|(_, value)| *value)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(super) enum ServerPropertySetting {
     EnableJmxMonitoring(bool),
@@ -568,7 +1082,7 @@ pub(super) enum ServerPropertySetting {
 
 impl From<ServerPropertySetting> for SettingManifest {
     fn from(value: ServerPropertySetting) -> Self {
-        match value {
+        let setting = match value {
             ServerPropertySetting::EnableJmxMonitoring(inner_val) => Self::new_required_value(
                 value.get_identifier(),
                 value.get_name(),
@@ -921,11 +1435,15 @@ impl From<ServerPropertySetting> for SettingManifest {
                 false,
                 true,
             ),
-            ServerPropertySetting::ServerPort(inner_val) => Self::new_required_value(
+            ServerPropertySetting::ServerPort(inner_val) => Self::new_value_with_type(
                 value.get_identifier(),
                 value.get_name(),
                 value.get_description(),
-                ConfigurableValue::UnsignedInteger(inner_val as u32),
+                Some(ConfigurableValue::UnsignedInteger(inner_val as u32)),
+                ConfigurableValueType::UnsignedInteger {
+                    min: Some(0),
+                    max: Some(65535),
+                },
                 None,
                 false,
                 true,
@@ -1130,7 +1648,13 @@ impl From<ServerPropertySetting> for SettingManifest {
                 false,
                 true,
             ),
-        }
+        };
+        let default_value = vanilla_default(setting.get_identifier())
+            .and_then(|raw| setting.get_value_type().parse_value(raw).ok());
+        setting
+            .with_default_value(default_value)
+            // the server only reads server.properties when it starts
+            .with_restart_required()
     }
 }
 
@@ -1993,6 +2517,52 @@ mod test {
         assert_eq!(res[3], ServerPropertySetting::Difficulty(Difficulty::Easy));
     }
 
+    #[test]
+    fn test_property_descriptors() {
+        let setting: SettingManifest = ServerPropertySetting::ViewDistance(16).into();
+        assert!(setting.requires_restart());
+        assert_eq!(
+            setting.get_value(),
+            Some(&ConfigurableValue::UnsignedInteger(16))
+        );
+        assert!(matches!(
+            setting.validate_setting(&Some(ConfigurableValue::String("far".to_string()))),
+            Err(Error {
+                kind: ErrorKind::BadRequest,
+                ..
+            })
+        ));
+
+        let setting: SettingManifest = ServerPropertySetting::Difficulty(Difficulty::Hard).into();
+        assert!(setting
+            .validate_setting(&Some(ConfigurableValue::Enum("medium".to_string())))
+            .is_err());
+        let default: SettingManifest = ServerPropertySetting::from_key_val(
+            "difficulty",
+            vanilla_default("difficulty").unwrap(),
+        )
+        .unwrap()
+        .into();
+        assert_eq!(
+            default.get_value(),
+            Some(&ConfigurableValue::Enum("easy".to_string()))
+        );
+
+        // every vanilla default has to fit the type the property is read as
+        for (key, value) in VANILLA_PROPERTIES {
+            let property = ServerPropertySetting::from_key_val(key, value).unwrap();
+            assert!(
+                !matches!(property, ServerPropertySetting::Unknown(..)),
+                "{key}"
+            );
+            let setting: SettingManifest = property.into();
+            assert!(setting.get_value_type().parse_value(value).is_ok(), "{key}");
+        }
+
+        let setting: SettingManifest = LodestoneSetting::AutoStart(true).into();
+        assert!(!setting.requires_restart());
+    }
+
     #[test]
     fn test_exhausiveness() {
         let properties_file = std::io::BufReader::new(
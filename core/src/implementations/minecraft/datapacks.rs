@@ -0,0 +1,584 @@
+//! Data packs of the active world. A running server picks changes up through the `datapack`
+//! and `reload` commands, a stopped one on its next start
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorCode, ErrorKind};
+use crate::events::CausedBy;
+use crate::traits::t_server::{State, TServer};
+use crate::util::extended_length_path;
+
+use super::MinecraftInstance;
+
+const DATAPACKS_DIR: &str = "datapacks";
+/// The server loads everything in `datapacks`, disabled packs wait next to it
+const DISABLED_DATAPACKS_DIR: &str = ".lodestone_disabled_datapacks";
+const PACK_METADATA_FILE: &str = "pack.mcmeta";
+
+/// A data pack of the active world, a `.zip` or a directory
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct InstalledDatapack {
+    pub name: String,
+    pub enabled: bool,
+    /// Plain text of the description in `pack.mcmeta`
+    pub description: Option<String>,
+    pub pack_format: Option<u32>,
+    /// Whether the pack is made for the server's version, `None` if either is unknown
+    pub compatible: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct DatapackChange {
+    /// `None` once removed
+    pub datapack: Option<InstalledDatapack>,
+    /// The running server took the change through console commands
+    pub applied_live: bool,
+    /// The server is running but couldn't take the change, it applies on the next start
+    pub restart_required: bool,
+}
+
+/// Data pack formats, each from the release in front of it on
+const DATA_PACK_FORMATS: [((u32, u32), u32); 18] = [
+    ((13, 0), 4),
+    ((15, 0), 5),
+    ((16, 2), 6),
+    ((17, 0), 7),
+    ((18, 0), 8),
+    ((18, 2), 9),
+    ((19, 0), 10),
+    ((19, 4), 12),
+    ((20, 0), 15),
+    ((20, 2), 18),
+    ((20, 3), 26),
+    ((20, 5), 41),
+    ((21, 0), 48),
+    ((21, 2), 57),
+    ((21, 4), 61),
+    ((21, 5), 71),
+    ((21, 6), 80),
+    ((21, 7), 81),
+];
+/// Newest release the table above knows
+const NEWEST_KNOWN_RELEASE: (u32, u32) = (21, 8);
+
+/// Minor and patch of a `1.x.y` release, `None` for snapshots and anything else
+fn release(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    if parts.next()? != "1" {
+        return None;
+    }
+    let minor = parts.next()?.parse().ok()?;
+    let patch = match parts.next() {
+        Some(patch) => patch.parse().ok()?,
+        None => 0,
+    };
+    Some((minor, patch))
+}
+
+/// The data pack format of a release, `None` for snapshots and releases newer than lodestone
+pub fn data_pack_format(version: &str) -> Option<u32> {
+    let release = release(version)?;
+    if release > NEWEST_KNOWN_RELEASE {
+        return None;
+    }
+    DATA_PACK_FORMATS
+        .iter()
+        .rev()
+        .find(|(since, _)| release >= *since)
+        .map(|(_, format)| *format)
+}
+
+/// What lodestone reads from `pack.mcmeta`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PackMetadata {
+    pack_format: Option<u32>,
+    /// Formats the pack declares it works with, `supported_formats` of 1.20.2 on
+    supported_formats: Option<(u32, u32)>,
+    description: Option<String>,
+}
+
+impl PackMetadata {
+    fn parse(content: &[u8]) -> Result<Self, Error> {
+        let invalid = |message: &str| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid {}: {}", PACK_METADATA_FILE, message),
+        };
+        let value: Value = serde_json::from_slice(content).map_err(|e| invalid(&e.to_string()))?;
+        let pack = value
+            .get("pack")
+            .ok_or_else(|| invalid("missing the pack object"))?;
+        let format = |value: &Value| value.as_u64().map(|format| format as u32);
+        let supported_formats = pack
+            .get("supported_formats")
+            .and_then(|formats| match formats {
+                Value::Array(range) if range.len() == 2 => {
+                    Some((format(&range[0])?, format(&range[1])?))
+                }
+                Value::Object(range) => Some((
+                    format(range.get("min_inclusive")?)?,
+                    format(range.get("max_inclusive")?)?,
+                )),
+                single => format(single).map(|format| (format, format)),
+            });
+        Ok(Self {
+            pack_format: pack.get("pack_format").and_then(format),
+            supported_formats,
+            description: pack.get("description").map(text_component),
+        })
+    }
+
+    fn is_compatible(&self, format: u32) -> Option<bool> {
+        match (self.supported_formats, self.pack_format) {
+            (Some((min, max)), _) => Some((min..=max).contains(&format)),
+            (None, Some(pack_format)) => Some(pack_format == format),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Plain text of a JSON text component
+fn text_component(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().map(text_component).collect(),
+        Value::Object(component) => {
+            let mut text = component
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            if let Some(Value::Array(extra)) = component.get("extra") {
+                text.extend(extra.iter().map(text_component));
+            }
+            text
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Whether `name` is a bare file or directory name, so it can't leave the data packs directory
+fn check_datapack_name(name: &str) -> Result<(), Error> {
+    let is_bare = Path::new(name).file_name().and_then(|name| name.to_str()) == Some(name)
+        && !name.starts_with('.');
+    if !is_bare {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is not a data pack name", name),
+        });
+    }
+    Ok(())
+}
+
+/// Reads `pack.mcmeta` of a zipped pack, checking the server would find the pack in it
+fn read_zipped_pack(path: &Path) -> Result<PackMetadata, Error> {
+    let file = std::fs::File::open(extended_length_path(path))
+        .context(format!("Failed to open {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Not a zip archive: {}", e),
+    })?;
+    let has_data = archive.file_names().any(|name| name.starts_with("data/"));
+    let mut content = Vec::new();
+    match archive.by_name(PACK_METADATA_FILE) {
+        Ok(mut metadata) => {
+            metadata
+                .read_to_end(&mut content)
+                .context(format!("Failed to read {}", PACK_METADATA_FILE))?;
+        }
+        Err(_) => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "{} must be at the top of the zip, not in a directory",
+                    PACK_METADATA_FILE
+                ),
+            })
+        }
+    }
+    let metadata = PackMetadata::parse(&content)?;
+    if !has_data {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The zip has no data directory, it's not a data pack"),
+        });
+    }
+    Ok(metadata)
+}
+
+fn read_pack(path: &Path) -> Option<PackMetadata> {
+    if path.is_dir() {
+        let content = std::fs::read(extended_length_path(&path.join(PACK_METADATA_FILE))).ok()?;
+        PackMetadata::parse(&content).ok()
+    } else {
+        read_zipped_pack(path).ok()
+    }
+}
+
+fn is_pack(path: &Path) -> bool {
+    if path.is_dir() {
+        path.join(PACK_METADATA_FILE).is_file()
+    } else {
+        path.extension()
+            .map_or(false, |extension| extension == "zip")
+    }
+}
+
+fn list_packs(dir: &Path, enabled: bool, format: Option<u32>) -> Vec<InstalledDatapack> {
+    let entries = match std::fs::read_dir(extended_length_path(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_pack(&entry.path()))
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            Some(describe_pack(&entry.path(), name, enabled, format))
+        })
+        .collect()
+}
+
+fn describe_pack(
+    path: &Path,
+    name: String,
+    enabled: bool,
+    format: Option<u32>,
+) -> InstalledDatapack {
+    let metadata = read_pack(path);
+    InstalledDatapack {
+        name,
+        enabled,
+        description: metadata.as_ref().and_then(|m| m.description.clone()),
+        pack_format: metadata.as_ref().and_then(|m| m.pack_format),
+        compatible: metadata
+            .as_ref()
+            .zip(format)
+            .and_then(|(metadata, format)| metadata.is_compatible(format)),
+    }
+}
+
+/// Id the game knows a pack in the world's data packs directory by
+fn pack_id(name: &str) -> String {
+    format!("\"file/{}\"", name)
+}
+
+impl MinecraftInstance {
+    /// Enabled and disabled data pack directories of the active world
+    async fn datapack_dirs(&self) -> (PathBuf, PathBuf) {
+        let world = self.path_to_instance.join(self.active_world().await);
+        (
+            world.join(DATAPACKS_DIR),
+            world.join(DISABLED_DATAPACKS_DIR),
+        )
+    }
+
+    /// Where an enabled pack of the active world is
+    pub async fn datapack_path(&self, name: &str) -> PathBuf {
+        self.datapack_dirs().await.0.join(name)
+    }
+
+    async fn data_pack_format(&self) -> Result<Option<u32>, Error> {
+        let version = self.config.lock().await.version.clone();
+        match release(&version) {
+            Some((minor, _)) if minor < 13 => Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Data packs need Minecraft 1.13 or newer"),
+            }),
+            _ => Ok(data_pack_format(&version)),
+        }
+    }
+
+    /// Sends the commands if the server is running, returning whether it took all of them
+    async fn apply_live(&self, commands: &[String], caused_by: &CausedBy) -> bool {
+        if self.state().await != State::Running {
+            return false;
+        }
+        for command in commands {
+            if self.send_command(command, caused_by.clone()).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn datapack_change(
+        &self,
+        datapack: Option<InstalledDatapack>,
+        commands: &[String],
+        caused_by: &CausedBy,
+    ) -> DatapackChange {
+        let applied_live = self.apply_live(commands, caused_by).await;
+        DatapackChange {
+            datapack,
+            applied_live,
+            restart_required: !applied_live && self.state().await != State::Stopped,
+        }
+    }
+
+    pub async fn list_datapacks(&self) -> Result<Vec<InstalledDatapack>, Error> {
+        let format = self.data_pack_format().await?;
+        let (enabled_dir, disabled_dir) = self.datapack_dirs().await;
+        tokio::task::spawn_blocking(move || {
+            let mut packs = list_packs(&enabled_dir, true, format);
+            packs.extend(list_packs(&disabled_dir, false, format));
+            packs.sort_by(|a, b| a.name.cmp(&b.name));
+            packs
+        })
+        .await
+        .context("Failed to spawn blocking task")
+        .map_err(Into::into)
+    }
+
+    /// Where a pack is, and whether it's enabled
+    async fn find_datapack(&self, name: &str) -> Result<(PathBuf, bool), Error> {
+        check_datapack_name(name)?;
+        let (enabled_dir, disabled_dir) = self.datapack_dirs().await;
+        if enabled_dir.join(name).exists() {
+            Ok((enabled_dir.join(name), true))
+        } else if disabled_dir.join(name).exists() {
+            Ok((disabled_dir.join(name), false))
+        } else {
+            Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} is not installed", name),
+            })
+        }
+    }
+
+    /// Moves an uploaded zip into the data packs directory once it's checked to be a data pack
+    /// for the server's version, `force` takes one made for another version
+    pub async fn install_datapack(
+        &self,
+        upload: &Path,
+        name: &str,
+        force: bool,
+        caused_by: &CausedBy,
+    ) -> Result<DatapackChange, Error> {
+        check_datapack_name(name)?;
+        self.ensure_world_writable(&self.datapack_path(name).await)
+            .await?;
+        if !name.to_lowercase().ends_with(".zip") {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Expected a .zip data pack"),
+            });
+        }
+        let format = self.data_pack_format().await?;
+        let metadata = tokio::task::spawn_blocking({
+            let upload = upload.to_owned();
+            move || read_zipped_pack(&upload)
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        if let Some(format) = format {
+            if metadata.is_compatible(format) == Some(false) && !force {
+                return Err(Error::coded(
+                    ErrorCode::IncompatibleDatapack,
+                    format!(
+                        "The data pack is made for pack format {}, the server needs {}",
+                        metadata
+                            .pack_format
+                            .map_or_else(|| "unknown".to_string(), |f| f.to_string()),
+                        format
+                    ),
+                )
+                .with_details(serde_json::json!({
+                    "pack_format": metadata.pack_format,
+                    "server_pack_format": format,
+                })));
+            }
+        }
+        if self.find_datapack(name).await.is_ok() {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("{} is already installed", name),
+            });
+        }
+        let (enabled_dir, _) = self.datapack_dirs().await;
+        crate::util::fs::create_dir_all(&enabled_dir).await?;
+        let path = enabled_dir.join(name);
+        crate::util::fs::rename(upload, &path).await?;
+        let datapack = describe_pack(&path, name.to_string(), true, format);
+        // a pack the world disabled before stays disabled through a reload
+        let commands = [
+            "reload".to_string(),
+            format!("datapack enable {}", pack_id(name)),
+        ];
+        Ok(self
+            .datapack_change(Some(datapack), &commands, caused_by)
+            .await)
+    }
+
+    pub async fn set_datapack_enabled(
+        &self,
+        name: &str,
+        enabled: bool,
+        caused_by: &CausedBy,
+    ) -> Result<DatapackChange, Error> {
+        let (path, was_enabled) = self.find_datapack(name).await?;
+        self.ensure_world_writable(&path).await?;
+        let format = self.data_pack_format().await?;
+        if was_enabled == enabled {
+            let datapack = describe_pack(&path, name.to_string(), enabled, format);
+            return Ok(DatapackChange {
+                datapack: Some(datapack),
+                applied_live: false,
+                restart_required: false,
+            });
+        }
+        let (enabled_dir, disabled_dir) = self.datapack_dirs().await;
+        let (to_dir, commands) = if enabled {
+            (
+                enabled_dir,
+                vec![
+                    "reload".to_string(),
+                    format!("datapack enable {}", pack_id(name)),
+                ],
+            )
+        } else {
+            (
+                disabled_dir,
+                vec![format!("datapack disable {}", pack_id(name))],
+            )
+        };
+        // the server has to let go of a pack before it leaves the directory
+        let applied_live = !enabled && self.apply_live(&commands, caused_by).await;
+        crate::util::fs::create_dir_all(&to_dir).await?;
+        let to = to_dir.join(name);
+        crate::util::fs::rename(&path, &to).await?;
+        let datapack = describe_pack(&to, name.to_string(), enabled, format);
+        if enabled {
+            return Ok(self
+                .datapack_change(Some(datapack), &commands, caused_by)
+                .await);
+        }
+        Ok(DatapackChange {
+            datapack: Some(datapack),
+            applied_live,
+            restart_required: !applied_live && self.state().await != State::Stopped,
+        })
+    }
+
+    /// Deletes a pack, returning where it was
+    pub async fn remove_datapack(
+        &self,
+        name: &str,
+        caused_by: &CausedBy,
+    ) -> Result<(PathBuf, DatapackChange), Error> {
+        let (path, enabled) = self.find_datapack(name).await?;
+        self.ensure_world_writable(&path).await?;
+        let change = if enabled {
+            self.datapack_change(
+                None,
+                &[format!("datapack disable {}", pack_id(name))],
+                caused_by,
+            )
+            .await
+        } else {
+            DatapackChange {
+                datapack: None,
+                applied_live: false,
+                restart_required: false,
+            }
+        };
+        if path.is_dir() {
+            crate::util::fs::remove_dir_all(&path).await?;
+        } else {
+            crate::util::fs::remove_file(&path).await?;
+        }
+        Ok((path, change))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn write_zip(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        for (name, content) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_data_pack_format() {
+        assert_eq!(data_pack_format("1.13"), Some(4));
+        assert_eq!(data_pack_format("1.16.5"), Some(6));
+        assert_eq!(data_pack_format("1.19.4"), Some(12));
+        assert_eq!(data_pack_format("1.20.1"), Some(15));
+        assert_eq!(data_pack_format("1.20.4"), Some(26));
+        assert_eq!(data_pack_format("1.12.2"), None);
+        assert_eq!(data_pack_format("23w31a"), None);
+        assert_eq!(data_pack_format("1.30"), None);
+    }
+
+    #[test]
+    fn test_pack_metadata() {
+        let metadata = PackMetadata::parse(
+            br#"{"pack": {"pack_format": 15, "description": {"text": "Terrain ", "extra": [{"text": "tweaks"}]}}}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.description.as_deref(), Some("Terrain tweaks"));
+        assert_eq!(metadata.is_compatible(15), Some(true));
+        assert_eq!(metadata.is_compatible(18), Some(false));
+
+        let metadata = PackMetadata::parse(
+            br#"{"pack": {"pack_format": 18, "supported_formats": {"min_inclusive": 15, "max_inclusive": 26}, "description": "Ranged"}}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.is_compatible(15), Some(true));
+        assert_eq!(metadata.is_compatible(41), Some(false));
+        assert!(PackMetadata::parse(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_read_zipped_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let mcmeta = r#"{"pack": {"pack_format": 15, "description": "Test"}}"#;
+
+        let pack = dir.path().join("pack.zip");
+        write_zip(
+            &pack,
+            &[
+                ("pack.mcmeta", mcmeta),
+                ("data/test/functions/hello.mcfunction", "say hello"),
+            ],
+        );
+        assert_eq!(read_zipped_pack(&pack).unwrap().pack_format, Some(15));
+
+        // zipping the pack's directory instead of its contents
+        let nested = dir.path().join("nested.zip");
+        write_zip(
+            &nested,
+            &[
+                ("pack/pack.mcmeta", mcmeta),
+                ("pack/data/test/functions/hello.mcfunction", "say hello"),
+            ],
+        );
+        assert!(read_zipped_pack(&nested).is_err());
+
+        let resource_pack = dir.path().join("resources.zip");
+        write_zip(
+            &resource_pack,
+            &[
+                ("pack.mcmeta", mcmeta),
+                ("assets/test/lang/en_us.json", "{}"),
+            ],
+        );
+        assert!(read_zipped_pack(&resource_pack).is_err());
+    }
+}
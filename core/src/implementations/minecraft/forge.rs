@@ -1,8 +1,18 @@
-use color_eyre::eyre::Context;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
 use indexmap::IndexMap;
 use serde_json::Value;
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::error::{Error, ErrorKind};
 
-use crate::error::Error;
+use super::util::run_installer;
+use super::ForgeBuildVersion;
+
+/// Name the installer is downloaded under, in the instance directory
+pub(super) const FORGE_INSTALLER: &str = "forge-installer.jar";
 
 pub async fn get_forge_minecraft_versions() -> Result<Vec<String>, Error> {
     let http = reqwest::Client::new();
@@ -21,10 +31,208 @@ pub async fn get_forge_minecraft_versions() -> Result<Vec<String>, Error> {
     Ok(response.into_iter().map(|(k, _)| k).rev().collect())
 }
 
+/// Runs the downloaded installer headless in the instance directory, `on_output` sees every line
+/// it prints. The installer and its logs are removed once it succeeded
+pub(super) async fn run_forge_installer(
+    jre: &Path,
+    path_to_instance: &Path,
+    on_output: &(dyn Fn(&str) + Send + Sync),
+) -> Result<(), Error> {
+    run_installer(
+        Command::new(jre)
+            .arg("-jar")
+            .arg(path_to_instance.join(FORGE_INSTALLER))
+            .arg("--installServer")
+            .arg(path_to_instance)
+            .current_dir(path_to_instance),
+        FORGE_INSTALLER,
+        on_output,
+    )
+    .await?;
+    for leftover in [
+        FORGE_INSTALLER.to_string(),
+        format!("{}.log", FORGE_INSTALLER),
+        "installer.log".to_string(),
+    ] {
+        let path = path_to_instance.join(leftover);
+        if path.exists() {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What a Forge server is launched from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum ForgeLaunchTarget {
+    /// 1.17 and later, the installer generates an args file passed to Java as `@file`
+    ArgsFile(PathBuf),
+    /// Older versions run the jar the installer left in the instance directory
+    Jar(PathBuf),
+}
+
+fn args_file_name() -> &'static str {
+    match std::env::consts::OS {
+        "windows" => "win_args.txt",
+        _ => "unix_args.txt",
+    }
+}
+
+impl ForgeLaunchTarget {
+    /// Finds what the installer generated for `version` in the instance directory
+    pub(super) async fn detect(
+        path_to_instance: &Path,
+        version: &str,
+        build_version: Option<&ForgeBuildVersion>,
+    ) -> Result<Self, Error> {
+        let forge_libraries = path_to_instance
+            .join("libraries")
+            .join("net")
+            .join("minecraftforge")
+            .join("forge");
+        if let Some(ForgeBuildVersion(build_version)) = build_version {
+            let args_file = forge_libraries.join(build_version).join(args_file_name());
+            if args_file.is_file() {
+                return Ok(ForgeLaunchTarget::ArgsFile(args_file));
+            }
+        }
+        // the build isn't recorded for servers imported from elsewhere, and upgrades leave the
+        // builds of other versions behind
+        let build_prefix = format!("{}-", version);
+        let mut args_files = Vec::new();
+        if let Ok(mut builds) = tokio::fs::read_dir(&forge_libraries).await {
+            while let Ok(Some(build)) = builds.next_entry().await {
+                let args_file = build.path().join(args_file_name());
+                if build
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(&build_prefix)
+                    && args_file.is_file()
+                {
+                    args_files.push(args_file);
+                }
+            }
+        }
+        args_files.sort();
+        if let Some(args_file) = args_files.pop() {
+            return Ok(ForgeLaunchTarget::ArgsFile(args_file));
+        }
+
+        // forge-<version>-<build>[-universal].jar, or minecraftforge-*.jar before 1.6
+        let mut versioned_jars = Vec::new();
+        let mut old_jars = Vec::new();
+        let mut entries = tokio::fs::read_dir(path_to_instance)
+            .await
+            .context("Failed to read the instance directory")?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Failed to read the instance directory")?
+        {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".jar")
+                || name.ends_with("-installer.jar")
+                || name == FORGE_INSTALLER
+            {
+                continue;
+            }
+            if name.starts_with(&format!("forge-{}", build_prefix)) {
+                versioned_jars.push(entry.path());
+            } else if name.starts_with("minecraftforge") {
+                old_jars.push(entry.path());
+            }
+        }
+        versioned_jars.sort();
+        old_jars.sort();
+        versioned_jars
+            .pop()
+            .or_else(|| old_jars.pop())
+            .map(ForgeLaunchTarget::Jar)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!(
+                    "Forge {} is not installed, neither its {} nor its server jar was found",
+                    version,
+                    args_file_name()
+                ),
+            })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    async fn touch(path: &Path) {
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(path, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_detect_modern_args_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let forge = dir.path().join("libraries/net/minecraftforge/forge");
+        touch(&forge.join("1.19.2-43.2.0").join(args_file_name())).await;
+        touch(&forge.join("1.20.1-47.1.0").join(args_file_name())).await;
+        touch(&dir.path().join(FORGE_INSTALLER)).await;
+
+        let build = ForgeBuildVersion("1.20.1-47.1.0".to_string());
+        assert_eq!(
+            ForgeLaunchTarget::detect(dir.path(), "1.20.1", Some(&build))
+                .await
+                .unwrap(),
+            ForgeLaunchTarget::ArgsFile(forge.join("1.20.1-47.1.0").join(args_file_name()))
+        );
+        // found by the game version when the build is unknown
+        assert_eq!(
+            ForgeLaunchTarget::detect(dir.path(), "1.19.2", None)
+                .await
+                .unwrap(),
+            ForgeLaunchTarget::ArgsFile(forge.join("1.19.2-43.2.0").join(args_file_name()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detect_legacy_jar() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(&dir.path().join(FORGE_INSTALLER)).await;
+        touch(
+            &dir.path()
+                .join("forge-1.7.10-10.13.4.1614-1.7.10-installer.jar"),
+        )
+        .await;
+        touch(&dir.path().join("minecraft_server.1.7.10.jar")).await;
+        assert!(ForgeLaunchTarget::detect(dir.path(), "1.7.10", None)
+            .await
+            .is_err());
+
+        let universal = dir
+            .path()
+            .join("forge-1.7.10-10.13.4.1614-1.7.10-universal.jar");
+        touch(&universal).await;
+        assert_eq!(
+            ForgeLaunchTarget::detect(dir.path(), "1.7.10", None)
+                .await
+                .unwrap(),
+            ForgeLaunchTarget::Jar(universal)
+        );
+
+        let old = dir
+            .path()
+            .join("minecraftforge-universal-1.5.2-7.8.1.738.jar");
+        touch(&old).await;
+        assert_eq!(
+            ForgeLaunchTarget::detect(dir.path(), "1.5.2", None)
+                .await
+                .unwrap(),
+            ForgeLaunchTarget::Jar(old)
+        );
+    }
+
     #[tokio::test]
     async fn test_get_forge_minecraft_versions() {
         let versions = get_forge_minecraft_versions().await.unwrap();
@@ -0,0 +1,213 @@
+//! Keeps Paper servers on the newest stable build of their Minecraft version
+
+use color_eyre::eyre::{eyre, Context};
+use serde::Serialize;
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::downloads::{download_jar, Checksum};
+use crate::error::{Error, ErrorKind};
+use crate::prelude::path_to_tmp;
+use crate::traits::t_server::{State, TServer};
+
+use super::paper::{get_paper_builds, newer_builds, PaperBuild};
+use super::{Flavour, MinecraftInstance, PaperBuildVersion};
+
+/// A build downloaded while the server runs waits here for the next start
+pub const PENDING_JAR_NAME: &str = ".lodestone_pending_server.jar";
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct GameUpdates {
+    pub version: String,
+    /// `None` when the instance doesn't know which build it runs
+    pub installed_build: Option<i64>,
+    /// Downloaded and installed on the next start
+    pub pending_build: Option<i64>,
+    /// Stable builds newer than the installed one, newest first
+    pub available: Vec<PaperBuild>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct GameUpdateOutcome {
+    pub build: i64,
+    /// False when the server is running, the build is then installed on its next start
+    pub applied: bool,
+}
+
+fn installed_paper_build(flavour: &Flavour) -> Result<Option<i64>, Error> {
+    match flavour {
+        Flavour::Paper { build_version } => Ok(build_version
+            .as_ref()
+            .map(|PaperBuildVersion(build)| *build)),
+        _ => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Build updates are only available for Paper servers"),
+        }),
+    }
+}
+
+impl MinecraftInstance {
+    /// `None` for other flavours and Paper servers that don't know their build
+    pub async fn paper_build(&self) -> Option<i64> {
+        installed_paper_build(&self.config.lock().await.flavour)
+            .ok()
+            .flatten()
+    }
+
+    pub async fn game_updates(&self) -> Result<GameUpdates, Error> {
+        let (version, flavour, pending_build) = {
+            let config = self.config.lock().await;
+            (
+                config.version.clone(),
+                config.flavour.clone(),
+                config.pending_build,
+            )
+        };
+        let installed_build = installed_paper_build(&flavour)?;
+        let builds = get_paper_builds(&version).await?;
+        Ok(GameUpdates {
+            version,
+            installed_build,
+            pending_build,
+            available: newer_builds(builds, installed_build),
+        })
+    }
+
+    /// Downloads `build`, or the newest stable build when `None`, and verifies it against the
+    /// checksum PaperMC publishes.
+    ///
+    /// A stopped server gets the jar right away, a running one on its next start
+    pub async fn update_game(&self, build: Option<i64>) -> Result<GameUpdateOutcome, Error> {
+        let (version, flavour) = {
+            let config = self.config.lock().await;
+            (config.version.clone(), config.flavour.clone())
+        };
+        let installed_build = installed_paper_build(&flavour)?;
+        let builds = get_paper_builds(&version).await?;
+        let target = match build {
+            Some(build) => builds
+                .into_iter()
+                .find(|candidate| candidate.build == build)
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("Paper has no build {} for {}", build, version),
+                })?,
+            None => newer_builds(builds, installed_build)
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("The newest stable build of Paper {} is installed", version),
+                })?,
+        };
+        if installed_build == Some(target.build) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Build {} is already installed", target.build),
+            });
+        }
+
+        let temp_dir = tempfile::tempdir_in(path_to_tmp()).context("Failed to create temp dir")?;
+        let temp_jar = temp_dir.path().join("server.jar");
+        download_jar(
+            &target.download_url(&version),
+            Some(&Checksum::sha256(&target.sha256)),
+            &temp_jar,
+            &|_| {},
+        )
+        .await?;
+
+        // a start in between would launch a half swapped jar
+        let _transition = self.transition_lock.acquire().await;
+        let applied = self.state().await == State::Stopped;
+        let jar_name = if applied {
+            "server.jar"
+        } else {
+            PENDING_JAR_NAME
+        };
+        crate::util::fs::rename(&temp_jar, self.path_to_instance.join(jar_name)).await?;
+        let mut config = self.config.lock().await;
+        if applied {
+            config.flavour = Flavour::Paper {
+                build_version: Some(PaperBuildVersion(target.build)),
+            };
+            // an older download still waiting would undo this one
+            if config.pending_build.take().is_some() {
+                let _ = tokio::fs::remove_file(self.path_to_instance.join(PENDING_JAR_NAME)).await;
+            }
+        } else {
+            config.pending_build = Some(target.build);
+        }
+        let name = config.name.clone();
+        drop(config);
+        self.write_config_to_file().await?;
+        info!(
+            "[{}] Paper build {} {}",
+            name,
+            target.build,
+            if applied {
+                "installed"
+            } else {
+                "will be installed on the next start"
+            }
+        );
+        Ok(GameUpdateOutcome {
+            build: target.build,
+            applied,
+        })
+    }
+
+    /// Installs the build downloaded while the server ran, the caller holds the transition lock
+    pub(super) async fn apply_pending_update(&self) -> Result<(), Error> {
+        let build = match self.config.lock().await.pending_build {
+            Some(build) => build,
+            None => return Ok(()),
+        };
+        let pending_jar = self.path_to_instance.join(PENDING_JAR_NAME);
+        let mut config = self.config.lock().await;
+        if pending_jar.exists() {
+            crate::util::fs::rename(&pending_jar, self.path_to_instance.join("server.jar")).await?;
+            config.flavour = Flavour::Paper {
+                build_version: Some(PaperBuildVersion(build)),
+            };
+            info!("[{}] Installed Paper build {}", config.name, build);
+        } else {
+            warn!(
+                "[{}] The downloaded Paper build {} is gone, keeping the installed one",
+                config.name, build
+            );
+        }
+        config.pending_build = None;
+        drop(config);
+        self.write_config_to_file().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_installed_paper_build() {
+        assert_eq!(
+            installed_paper_build(&Flavour::Paper {
+                build_version: Some(PaperBuildVersion(308)),
+            })
+            .unwrap(),
+            Some(308)
+        );
+        assert_eq!(
+            installed_paper_build(&Flavour::Paper {
+                build_version: None
+            })
+            .unwrap(),
+            None
+        );
+        assert!(matches!(
+            installed_paper_build(&Flavour::Vanilla).unwrap_err().kind,
+            ErrorKind::UnsupportedOperation
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{
+    CausedBy, Event, ProgressionEndValue, ProgressionEventID, ProgressionStartBuilder,
+    ProgressionStartValue,
+};
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+use super::MinecraftInstance;
+
+/// Longest countdown a graceful stop accepts
+const MAX_GRACEFUL_STOP_SECONDS: u32 = 600;
+/// Seconds before the stop at which players are warned, on top of every whole minute
+const WARNING_MARKS: [u32; 7] = [30, 10, 5, 4, 3, 2, 1];
+
+/// Seconds left at each warning of a countdown of `seconds`, highest first
+fn warning_marks(seconds: u32) -> Vec<u32> {
+    let mut marks: Vec<u32> = (1..=seconds / 60)
+        .rev()
+        .map(|minutes| minutes * 60)
+        .chain(WARNING_MARKS)
+        .chain(std::iter::once(seconds))
+        .filter(|mark| *mark <= seconds)
+        .collect();
+    marks.sort_unstable_by(|a, b| b.cmp(a));
+    marks.dedup();
+    marks
+}
+
+fn warning_message(seconds: u32) -> String {
+    let (amount, unit) = if seconds >= 60 && seconds % 60 == 0 {
+        (seconds / 60, "minute")
+    } else {
+        (seconds, "second")
+    };
+    format!(
+        "say Server stopping in {} {}{}",
+        amount,
+        unit,
+        if amount == 1 { "" } else { "s" }
+    )
+}
+
+fn end_event(
+    event_id: ProgressionEventID,
+    instance_uuid: InstanceUuid,
+    stopped: bool,
+    message: &str,
+) -> Event {
+    Event::new_progression_event_end(
+        event_id,
+        stopped,
+        Some(message),
+        Some(ProgressionEndValue::GracefulStop {
+            instance_uuid,
+            stopped,
+        }),
+    )
+}
+
+impl MinecraftInstance {
+    /// Cancels a pending graceful stop, returns false if there was none.
+    ///
+    /// `stopping_now` tells the countdown an immediate stop replaces it, so players aren't told
+    /// the stop was called off
+    pub(super) async fn cancel_graceful_stop(&self, stopping_now: bool) -> bool {
+        match self.graceful_stop.lock().await.take() {
+            Some(cancel) => cancel.send(stopping_now).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Warns players for `seconds`, then saves and stops the server.
+    ///
+    /// Stops right away when nobody is online. Requesting a graceful stop while one is pending
+    /// cancels it instead
+    pub(super) async fn start_graceful_stop(
+        &self,
+        caused_by: CausedBy,
+        seconds: u32,
+    ) -> Result<(), Error> {
+        if seconds > MAX_GRACEFUL_STOP_SECONDS {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "A graceful stop can wait at most {} seconds",
+                    MAX_GRACEFUL_STOP_SECONDS
+                ),
+            });
+        }
+        if self.cancel_graceful_stop(false).await {
+            return Ok(());
+        }
+        if self.state().await != State::Running {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is not running"),
+            });
+        }
+        if seconds == 0 || self.players_manager.lock().await.count() == 0 {
+            return self.stop(caused_by, false).await;
+        }
+
+        let (cancel, mut cancelled) = oneshot::channel::<bool>();
+        {
+            let mut graceful_stop = self.graceful_stop.lock().await;
+            if graceful_stop.is_some() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("A graceful stop is already pending"),
+                });
+            }
+            *graceful_stop = Some(cancel);
+        }
+        let (progression_start, event_id) = ProgressionStartBuilder::new(
+            format!("Stopping {}", self.config.lock().await.name),
+            ProgressionStartValue::GracefulStop {
+                instance_uuid: self.uuid.clone(),
+                seconds,
+            },
+            caused_by.clone(),
+        )
+        .total(seconds as f64)
+        .build();
+        self.event_broadcaster.send(progression_start);
+
+        let __self = self.clone();
+        tokio::task::spawn(async move {
+            let marks = warning_marks(seconds);
+            let name = __self.config.lock().await.name.clone();
+            let instance_uuid = __self.uuid.clone();
+            let mut cancellation = None;
+            for remaining in (1..=seconds).rev() {
+                if __self.state().await != State::Running {
+                    __self.graceful_stop.lock().await.take();
+                    __self.event_broadcaster.send(end_event(
+                        event_id,
+                        instance_uuid,
+                        false,
+                        "The server stopped before the countdown ended",
+                    ));
+                    return;
+                }
+                if marks.contains(&remaining) {
+                    if let Err(e) = __self
+                        .send_command(&warning_message(remaining), caused_by.clone())
+                        .await
+                    {
+                        warn!("[{}] Failed to send stop warning: {}", name, e);
+                    }
+                }
+                __self
+                    .event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        format!("Stopping in {}s", remaining),
+                        1.0,
+                    ));
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                    stopping_now = &mut cancelled => {
+                        cancellation = Some(stopping_now.unwrap_or(false));
+                        break;
+                    }
+                }
+            }
+            if cancellation.is_none() {
+                // whoever cancels takes the sender under this lock, so checking here is race free
+                let mut graceful_stop = __self.graceful_stop.lock().await;
+                match cancelled.try_recv() {
+                    Err(oneshot::error::TryRecvError::Empty) => {
+                        graceful_stop.take();
+                    }
+                    stopping_now => cancellation = Some(stopping_now.unwrap_or(false)),
+                }
+            }
+            match cancellation {
+                Some(true) => {
+                    __self.event_broadcaster.send(end_event(
+                        event_id,
+                        instance_uuid,
+                        false,
+                        "Superseded by an immediate stop",
+                    ));
+                }
+                Some(false) => {
+                    info!("[{}] Graceful stop cancelled", name);
+                    let _ = __self
+                        .send_command("say Server stop cancelled", caused_by)
+                        .await;
+                    __self.event_broadcaster.send(end_event(
+                        event_id,
+                        instance_uuid,
+                        false,
+                        "Stop cancelled",
+                    ));
+                }
+                None => {
+                    if let Err(e) = __self.send_command("save-all", caused_by.clone()).await {
+                        warn!("[{}] Failed to save before stopping: {}", name, e);
+                    }
+                    let event = match __self.stop(caused_by, false).await {
+                        Ok(()) => end_event(event_id, instance_uuid, true, "Stopping server"),
+                        Err(e) => {
+                            warn!("[{}] Graceful stop failed: {}", name, e);
+                            end_event(
+                                event_id,
+                                instance_uuid,
+                                false,
+                                &format!("Failed to stop: {}", e),
+                            )
+                        }
+                    };
+                    __self.event_broadcaster.send(event);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warning_marks() {
+        assert_eq!(warning_marks(60), vec![60, 30, 10, 5, 4, 3, 2, 1]);
+        assert_eq!(
+            warning_marks(150),
+            vec![150, 120, 60, 30, 10, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(warning_marks(7), vec![7, 5, 4, 3, 2, 1]);
+        assert_eq!(warning_marks(1), vec![1]);
+    }
+
+    #[test]
+    fn test_warning_message() {
+        assert_eq!(warning_message(120), "say Server stopping in 2 minutes");
+        assert_eq!(warning_message(60), "say Server stopping in 1 minute");
+        assert_eq!(warning_message(90), "say Server stopping in 90 seconds");
+        assert_eq!(warning_message(1), "say Server stopping in 1 second");
+    }
+}
@@ -0,0 +1,352 @@
+//! Commands run on the host before the server starts and after it stops, and the environment
+//! they and the server get
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use indexmap::IndexMap;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use crate::error::{Error, ErrorCode, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::process_tree::kill_tree;
+use crate::types::Snowflake;
+use crate::util::dont_spawn_terminal;
+
+use super::MinecraftInstance;
+
+/// A hook still running after this long is killed and counts as failed
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Lines of output a failed hook's error carries
+const HOOK_ERROR_OUTPUT_LINES: usize = 50;
+/// How long the output of a hook is still read once it exited. A background process it started
+/// may hold the pipes open for as long as it runs
+const HOOK_OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Run commands on the host or reach into the JVM, so only admins may change them
+pub const ADMIN_ONLY_SETTINGS: [&str; 3] = ["env", "pre_start_hook", "post_stop_hook"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreStart,
+    PostStop,
+}
+
+impl Hook {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Hook::PreStart => "pre_start",
+            Hook::PostStop => "post_stop",
+        }
+    }
+}
+
+fn is_valid_env_key(key: &str) -> bool {
+    key.chars().next().map_or(false, |c| !c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses one `KEY=VALUE` per line, blank lines are skipped
+pub fn parse_env(text: &str) -> Result<IndexMap<String, String>, Error> {
+    let mut env = IndexMap::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if is_valid_env_key(key.trim()) => {
+                env.insert(key.trim().to_string(), value.to_string());
+            }
+            // the line isn't echoed back, values are often secrets
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Line {} of the environment is not KEY=VALUE with a KEY of letters, \
+                         digits and underscores",
+                        number + 1
+                    ),
+                })
+            }
+        }
+    }
+    Ok(env)
+}
+
+pub fn format_env(env: &IndexMap<String, String>) -> String {
+    env.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn forward_lines(reader: impl AsyncRead + Unpin + Send + 'static, tx: mpsc::Sender<String>) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if tx.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn keep_line(output: &mut VecDeque<String>, on_line: &mut impl FnMut(&str), line: String) {
+    on_line(&line);
+    if output.len() == HOOK_ERROR_OUTPUT_LINES {
+        output.pop_front();
+    }
+    output.push_back(line);
+}
+
+/// Runs `command` through the shell in `dir`, handing every line it prints to `on_line`.
+///
+/// Fails with the exit code and the last lines of output if it fails or outlives `timeout`. The
+/// hook is done once the shell exits, whatever it left running in the background
+pub async fn run_hook(
+    hook: Hook,
+    command: &str,
+    dir: &Path,
+    env: &IndexMap<String, String>,
+    timeout: Duration,
+    mut on_line: impl FnMut(&str),
+) -> Result<(), Error> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell
+        .arg(command)
+        .current_dir(dir)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // a group of its own lets a timeout reach everything the hook spawned
+    #[cfg(unix)]
+    shell.process_group(0);
+    let mut child = dont_spawn_terminal(&mut shell)
+        .spawn()
+        .context(format!("Failed to run the {} hook", hook.as_str()))?;
+    let (tx, mut rx) = mpsc::channel(64);
+    if let Some(stdout) = child.stdout.take() {
+        forward_lines(stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, tx);
+    }
+
+    let mut output = VecDeque::new();
+    let run = async {
+        let mut pipes_open = true;
+        let status = loop {
+            tokio::select! {
+                line = rx.recv(), if pipes_open => match line {
+                    Some(line) => keep_line(&mut output, &mut on_line, line),
+                    None => pipes_open = false,
+                },
+                status = child.wait() => break status,
+            }
+        };
+        // what the shell printed right before exiting may still be on its way
+        let drain = async {
+            while let Some(line) = rx.recv().await {
+                keep_line(&mut output, &mut on_line, line);
+            }
+        };
+        let _ = tokio::time::timeout(HOOK_OUTPUT_DRAIN_TIMEOUT, drain).await;
+        status
+    };
+    let result = tokio::time::timeout(timeout, run).await;
+    let (message, exit_code) = match result {
+        Ok(status) => {
+            let status =
+                status.context(format!("Failed to wait for the {} hook", hook.as_str()))?;
+            match status.code() {
+                _ if status.success() => return Ok(()),
+                Some(code) => (
+                    format!("The {} hook exited with code {}", hook.as_str(), code),
+                    Some(code),
+                ),
+                None => (
+                    format!("The {} hook was terminated by a signal", hook.as_str()),
+                    None,
+                ),
+            }
+        }
+        Err(_) => {
+            if let Some(pid) = child.id() {
+                kill_tree(&mut sysinfo::System::new(), pid);
+            }
+            let _ = child.start_kill();
+            (
+                format!(
+                    "The {} hook did not finish within {} seconds",
+                    hook.as_str(),
+                    timeout.as_secs()
+                ),
+                None,
+            )
+        }
+    };
+    Err(
+        Error::coded(ErrorCode::HookFailed, message).with_details(json!({
+            "hook": hook.as_str(),
+            "exit_code": exit_code,
+            "output": output,
+        })),
+    )
+}
+
+impl MinecraftInstance {
+    /// Runs the hook with the instance's environment, its output goes to the console
+    pub(super) async fn run_instance_hook(
+        &self,
+        hook: Hook,
+        command: &str,
+        name: &str,
+    ) -> Result<(), Error> {
+        let env = self.config.lock().await.env.clone();
+        // the console is written to asynchronously, the callback only queues the lines
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let forward = async {
+            while let Some(line) = rx.recv().await {
+                self.send_hook_output(hook, name, line).await;
+            }
+        };
+        let run = async {
+            let result = run_hook(
+                hook,
+                command,
+                &self.path_to_instance,
+                &env,
+                HOOK_TIMEOUT,
+                |line| {
+                    let _ = tx.send(line.to_string());
+                },
+            )
+            .await;
+            drop(tx);
+            result
+        };
+        let (result, _) = tokio::join!(run, forward);
+        result
+    }
+
+    async fn send_hook_output(&self, hook: Hook, name: &str, line: String) {
+        // tagged so the console tells it apart from the server's own output
+        let message = format!("[{} hook] {}\n", hook.as_str(), line);
+        let snowflake = Snowflake::default();
+        self.console_history
+            .lock()
+            .await
+            .push(message.clone(), snowflake);
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid: self.uuid.clone(),
+                instance_event_inner: InstanceEventInner::InstanceOutput { message },
+                instance_name: name.to_string(),
+            }),
+            details: format!("Output of the {} hook", hook.as_str()),
+            snowflake,
+            caused_by: CausedBy::System,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env() {
+        let env =
+            parse_env("JAVA_TOOL_OPTIONS=-Dfile.encoding=UTF-8\n\nLICENSE_KEY= abc \n").unwrap();
+        assert_eq!(env["JAVA_TOOL_OPTIONS"], "-Dfile.encoding=UTF-8");
+        assert_eq!(env["LICENSE_KEY"], " abc ");
+        assert_eq!(parse_env(&format_env(&env)).unwrap(), env);
+        assert!(parse_env("NO_VALUE").is_err());
+        assert!(parse_env("1KEY=a").is_err());
+        assert!(parse_env("MY KEY=a").is_err());
+        assert!(parse_env("").unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = IndexMap::from([("GREETING".to_string(), "hello".to_string())]);
+        let mut lines = Vec::new();
+        run_hook(
+            Hook::PreStart,
+            "echo $GREETING; touch mapped",
+            dir.path(),
+            &env,
+            HOOK_TIMEOUT,
+            |line| lines.push(line.to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(lines, vec!["hello"]);
+        assert!(dir.path().join("mapped").exists());
+
+        let error = run_hook(
+            Hook::PreStart,
+            "echo no ramdisk >&2; exit 3",
+            dir.path(),
+            &env,
+            HOOK_TIMEOUT,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::HookFailed);
+        let details = &serde_json::to_value(&error).unwrap()["details"];
+        assert_eq!(details["exit_code"], 3);
+        assert_eq!(details["output"], json!(["no ramdisk"]));
+
+        let error = run_hook(
+            Hook::PostStop,
+            "sleep 5",
+            dir.path(),
+            &env,
+            Duration::from_millis(100),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::HookFailed);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hook_leaving_a_background_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut lines = Vec::new();
+        let started = std::time::Instant::now();
+        // the sleep inherits the pipes and keeps them open long after the shell exited
+        run_hook(
+            Hook::PreStart,
+            "sleep 5 & echo started",
+            dir.path(),
+            &IndexMap::new(),
+            HOOK_TIMEOUT,
+            |line| lines.push(line.to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(lines, vec!["started"]);
+    }
+}
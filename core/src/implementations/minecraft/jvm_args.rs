@@ -0,0 +1,178 @@
+use color_eyre::eyre::eyre;
+
+use crate::auth::user::{User, UserAction};
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+
+use super::configurable::{CmdArgSetting, LodestoneSetting};
+use super::hooks::ADMIN_ONLY_SETTINGS;
+use super::{MinecraftInstance, RestoreConfig};
+
+/// Heap sizes come from min_ram and max_ram, a second flag would silently override them
+const HEAP_FLAGS: &[&str] = &[
+    "-Xmx",
+    "-Xms",
+    "-XX:MaxHeapSize",
+    "-XX:InitialHeapSize",
+    "-XX:MinHeapSize",
+];
+/// Load arbitrary code into the server process
+const AGENT_FLAGS: &[&str] = &["-javaagent", "-agentpath", "-agentlib"];
+
+/// True for arguments only admins may add
+pub fn is_agent_arg(arg: &str) -> bool {
+    AGENT_FLAGS.iter().any(|flag| arg.starts_with(flag))
+}
+
+pub(super) fn split_args(args: &str) -> Vec<String> {
+    args.split_whitespace().map(str::to_string).collect()
+}
+
+/// Rejects arguments that would fight lodestone over the launch command
+pub(super) fn validate_jvm_args(args: &[String]) -> Result<(), Error> {
+    for arg in args {
+        let reason = if HEAP_FLAGS.iter().any(|flag| arg.starts_with(flag)) {
+            "the heap size is set through min_ram and max_ram"
+        } else if arg == "-jar" || arg.starts_with('@') {
+            "lodestone picks the server jar itself"
+        } else {
+            continue;
+        };
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is not allowed, {}", arg, reason),
+        });
+    }
+    Ok(())
+}
+
+pub(super) fn validate_ram(min_ram: u32, max_ram: u32, total_memory_mb: u64) -> Result<(), Error> {
+    if max_ram < min_ram {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Maximum RAM ({} MB) cannot be less than minimum RAM ({} MB)",
+                max_ram,
+                min_ram
+            ),
+        });
+    }
+    if max_ram as u64 > total_memory_mb {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Maximum RAM ({} MB) is more than this machine has ({} MB)",
+                max_ram,
+                total_memory_mb
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Everything passed to Java before the server jar
+pub(super) fn jvm_args(config: &RestoreConfig) -> Vec<String> {
+    [
+        format!("-Xmx{}M", config.max_ram),
+        format!("-Xms{}M", config.min_ram),
+    ]
+    .into_iter()
+    .chain(config.extra_jvm_args.iter().cloned())
+    .chain(config.cmd_args.iter().cloned())
+    .filter(|arg| !arg.is_empty())
+    .collect()
+}
+
+impl MinecraftInstance {
+    /// Heap the server may take, in MB
+    pub async fn max_ram(&self) -> u32 {
+        self.config.lock().await.max_ram
+    }
+
+    /// Checks a command line setting against the rest of the config before it's written.
+    ///
+    /// Values of the wrong type are left to the manifest to reject
+    pub(super) async fn validate_cmd_arg_update(
+        &self,
+        setting_id: &str,
+        value: &ConfigurableValue,
+    ) -> Result<(), Error> {
+        let (mut min_ram, mut max_ram) = {
+            let config = self.config.lock().await;
+            (config.min_ram, config.max_ram)
+        };
+        match (setting_id, value) {
+            ("min_ram", ConfigurableValue::UnsignedInteger(value)) => min_ram = *value,
+            ("max_ram", ConfigurableValue::UnsignedInteger(value)) => max_ram = *value,
+            ("cmd_args" | "extra_jvm_args", ConfigurableValue::String(args)) => {
+                return validate_jvm_args(&split_args(args))
+            }
+            _ => return Ok(()),
+        }
+        let total_memory_mb = {
+            let mut system = self.system.lock().await;
+            system.refresh_memory();
+            system.total_memory() / 1024 / 1024
+        };
+        validate_ram(min_ram, max_ram, total_memory_mb)
+    }
+
+    /// Checks a setting write by `requester`, whether it comes from the settings endpoint or a
+    /// template
+    pub async fn check_setting_update(
+        &self,
+        requester: &User,
+        section_id: &str,
+        setting_id: &str,
+        value: &ConfigurableValue,
+    ) -> Result<(), Error> {
+        let is_admin =
+            requester.can_perform_action(&UserAction::AdministerInstance(Some(self.uuid.clone())));
+        if section_id == CmdArgSetting::get_section_id() {
+            // agents run arbitrary code inside the server, so only admins may add them
+            if let ConfigurableValue::String(args) = value {
+                if let Some(agent) = args.split_whitespace().find(|arg| is_agent_arg(arg)) {
+                    if !is_admin {
+                        return Err(Error {
+                            kind: ErrorKind::PermissionDenied,
+                            source: eyre!("Only admins may add {}", agent),
+                        });
+                    }
+                }
+            }
+            return self.validate_cmd_arg_update(setting_id, value).await;
+        }
+        if section_id == LodestoneSetting::get_section_id()
+            && ADMIN_ONLY_SETTINGS.contains(&setting_id)
+            && !is_admin
+        {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Only admins may change the hooks and environment of an instance"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_jvm_args() {
+        assert!(validate_jvm_args(&split_args("-XX:+UseG1GC -XX:MaxGCPauseMillis=200")).is_ok());
+        assert!(validate_jvm_args(&split_args("-XX:+UseG1GC -Xmx8G")).is_err());
+        assert!(validate_jvm_args(&split_args("-XX:MaxHeapSize=8g")).is_err());
+        assert!(validate_jvm_args(&split_args("-jar other.jar")).is_err());
+        assert!(is_agent_arg("-javaagent:/tmp/agent.jar"));
+        assert!(!is_agent_arg("-Dlog4j2.formatMsgNoLookups=true"));
+    }
+
+    #[test]
+    fn test_validate_ram() {
+        assert!(validate_ram(2048, 4096, 16384).is_ok());
+        assert!(validate_ram(4096, 2048, 16384).is_err());
+        assert!(validate_ram(2048, 32768, 16384).is_err());
+    }
+}
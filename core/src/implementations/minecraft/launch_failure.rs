@@ -0,0 +1,236 @@
+//! Explaining why a server exited before it finished starting, from its output and crash report
+
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::types::Snowflake;
+
+use super::MinecraftInstance;
+
+/// Lines of output kept from a start, the signatures are looked for in those
+pub(super) const STARTUP_OUTPUT_LINES: usize = 500;
+/// Lines of output around the matched one kept in the excerpt
+const EXCERPT_CONTEXT: usize = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchFailureReason {
+    UnsupportedJavaVersion,
+    PortInUse,
+    OutOfMemory,
+    MissingDependency,
+    /// None of the known signatures matched
+    Unknown,
+}
+
+/// A known way for a server to fail its start, any of `needles` in a line identifies it
+struct Signature {
+    reason: LaunchFailureReason,
+    needles: &'static [&'static str],
+    remediation: &'static str,
+}
+
+/// Checked in order, the first signature found wins
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        reason: LaunchFailureReason::UnsupportedJavaVersion,
+        needles: &[
+            "UnsupportedClassVersionError",
+            "has been compiled by a more recent version of the Java Runtime",
+        ],
+        remediation: "The server needs a newer Java than the one it was launched with, pick or download a fitting runtime in the instance's Java settings",
+    },
+    Signature {
+        reason: LaunchFailureReason::PortInUse,
+        needles: &["Address already in use", "FAILED TO BIND TO PORT"],
+        remediation: "Another program is using the server's port, stop it or move the server to a free port",
+    },
+    Signature {
+        reason: LaunchFailureReason::OutOfMemory,
+        needles: &[
+            "java.lang.OutOfMemoryError",
+            "Could not reserve enough space for object heap",
+            "There is insufficient memory for the Java Runtime Environment",
+        ],
+        remediation: "The server ran out of memory, raise its maximum RAM or free memory on the host",
+    },
+    Signature {
+        reason: LaunchFailureReason::MissingDependency,
+        needles: &[
+            // Fabric
+            "Incompatible mods found!",
+            "Incompatible mod set!",
+            "which is missing!",
+            // Forge
+            "Missing or unsupported mandatory dependencies",
+            "MissingModsException",
+        ],
+        remediation: "A mod requires another mod or a version of one that isn't installed, add the dependency or remove the mod that needs it",
+    },
+];
+
+const UNKNOWN_REMEDIATION: &str =
+    "The cause isn't a known one, the excerpt holds the last output of the server";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct LaunchFailure {
+    pub reason: LaunchFailureReason,
+    /// The lines the reason was recognized from, the last lines of output for an unknown one
+    pub excerpt: Vec<String>,
+    pub remediation: String,
+    pub exit_status: Option<String>,
+    /// The crash report the server wrote during the failed start, if any
+    pub crash_report: Option<PathBuf>,
+    pub occurred_at: i64,
+}
+
+/// The matched line of `lines` with a little context, and the signature it matched
+fn find_signature(lines: &[String]) -> Option<(&'static Signature, Vec<String>)> {
+    SIGNATURES.iter().find_map(|signature| {
+        let index = lines
+            .iter()
+            .position(|line| signature.needles.iter().any(|needle| line.contains(needle)))?;
+        let start = index.saturating_sub(EXCERPT_CONTEXT);
+        let end = (index + EXCERPT_CONTEXT + 1).min(lines.len());
+        Some((signature, lines[start..end].to_vec()))
+    })
+}
+
+impl LaunchFailure {
+    /// Matches the output of the failed start first, then the crash report
+    pub fn diagnose(
+        output: &[String],
+        crash_report: Option<(PathBuf, String)>,
+        exit_status: Option<String>,
+    ) -> Self {
+        let (crash_report, crash_report_lines) = match crash_report {
+            Some((path, content)) => (
+                Some(path),
+                content.lines().map(|line| line.to_string()).collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+        let (reason, excerpt, remediation) =
+            match find_signature(output).or_else(|| find_signature(&crash_report_lines)) {
+                Some((signature, excerpt)) => {
+                    (signature.reason, excerpt, signature.remediation.to_string())
+                }
+                None => (
+                    LaunchFailureReason::Unknown,
+                    output[output.len().saturating_sub(EXCERPT_CONTEXT * 2 + 1)..].to_vec(),
+                    UNKNOWN_REMEDIATION.to_string(),
+                ),
+            };
+        Self {
+            reason,
+            excerpt,
+            remediation,
+            exit_status,
+            crash_report,
+            occurred_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/// The newest crash report of the instance written after `since`
+pub(super) async fn crash_report_since(
+    path_to_instance: &Path,
+    since: SystemTime,
+) -> Option<(PathBuf, String)> {
+    let mut entries = tokio::fs::read_dir(path_to_instance.join("crash-reports"))
+        .await
+        .ok()?;
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let modified = match entry.metadata().await.and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if modified >= since && newest.as_ref().map_or(true, |(time, _)| modified > *time) {
+            newest = Some((modified, entry.path()));
+        }
+    }
+    let (_, path) = newest?;
+    let content = tokio::fs::read_to_string(&path).await.ok()?;
+    Some((path, content))
+}
+
+impl MinecraftInstance {
+    /// Diagnoses a start that ended before the server was up, keeps the result and announces it
+    pub(super) async fn report_launch_failure(
+        &self,
+        output: Vec<String>,
+        launched_at: SystemTime,
+        status: Option<ExitStatus>,
+    ) {
+        let crash_report = crash_report_since(&self.path_to_instance, launched_at).await;
+        let failure = LaunchFailure::diagnose(
+            &output,
+            crash_report,
+            status.map(|status| status.to_string()),
+        );
+        let name = self.config.lock().await.name.clone();
+        warn!("[{}] Server failed to start: {:?}", name, failure.reason);
+        *self.last_launch_failure.lock().await = Some(failure.clone());
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid: self.uuid.clone(),
+                instance_name: name,
+                instance_event_inner: InstanceEventInner::LaunchFailed { failure },
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::System,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let output = lines(&[
+            "Starting minecraft server version 1.20.4",
+            "Loading properties",
+            "**** FAILED TO BIND TO PORT!",
+            "The exception was: java.net.BindException: Address already in use",
+            "Perhaps a server is already running on that port?",
+            "Stopping server",
+        ]);
+        let failure = LaunchFailure::diagnose(&output, None, Some("exit status: 1".into()));
+        assert_eq!(failure.reason, LaunchFailureReason::PortInUse);
+        assert_eq!(failure.excerpt, output[0..5].to_vec());
+
+        let failure = LaunchFailure::diagnose(
+            &lines(&["Error: LinkageError occurred while loading main class"]),
+            Some((
+                PathBuf::from("crash-reports/crash.txt"),
+                "java.lang.UnsupportedClassVersionError: net/minecraft/Main".to_string(),
+            )),
+            None,
+        );
+        assert_eq!(failure.reason, LaunchFailureReason::UnsupportedJavaVersion);
+        assert_eq!(
+            failure.crash_report,
+            Some(PathBuf::from("crash-reports/crash.txt"))
+        );
+
+        let failure = LaunchFailure::diagnose(&lines(&["a", "b"]), None, None);
+        assert_eq!(failure.reason, LaunchFailureReason::Unknown);
+        assert_eq!(failure.excerpt, lines(&["a", "b"]));
+    }
+}
@@ -0,0 +1,321 @@
+//! Tells player joins, leaves and chat apart in the console output through a table of regexes,
+//! so servers whose mods or locale print them differently can be taught to recognize them
+
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::eyre;
+use fancy_regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ts_rs::TS;
+use utoipa::ToSchema;
+
+use crate::error::{Error, ErrorKind};
+
+use super::{Flavour, MinecraftInstance, RestoreConfig};
+
+/// Most rules one instance can have
+pub const MAX_LOG_RULES: usize = 32;
+/// Most characters in the pattern of one rule
+pub const MAX_PATTERN_LENGTH: usize = 1024;
+/// fancy-regex backtracks, a line that takes more steps than this doesn't match the rule
+const REGEX_BACKTRACK_LIMIT: usize = 100_000;
+/// Bytes the compiled form of a pattern may take
+const REGEX_SIZE_LIMIT: usize = 1024 * 1024;
+/// Longer console lines are cut before matching
+const MAX_MATCHED_LINE_LENGTH: usize = 4096;
+/// Time one line may take through the rules, the rules left are skipped once it's spent
+const LINE_MATCH_BUDGET: Duration = Duration::from_millis(5);
+
+const CHAT: &str = r"\]: (?:\[Not Secure\] )?<(?P<player>[^>\s]+)> (?P<message>.*)$";
+/// Chat mods put ranks and other decorations in front of the name
+const DECORATED_CHAT: &str =
+    r"\]: (?:\[Not Secure\] )?<(?:\[[^\]]*\] ?)*(?P<player>[^>\s\[\]]+)> (?P<message>.*)$";
+const PLAYER_JOINED: &str =
+    r"\]: (?P<player>[^\s<\[]+)(?: \(formerly known as \S+\))? joined the game$";
+const PLAYER_LEFT: &str = r"\]: (?P<player>[^\s<\[]+) left the game$";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum LogLineKind {
+    PlayerJoined,
+    PlayerLeft,
+    Chat,
+}
+
+/// A line matching `pattern` is taken as `kind`. The pattern names the player with a `player`
+/// group, and the chat message with a `message` group
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, TS, ToSchema)]
+#[ts(export)]
+pub struct LogRule {
+    pub kind: LogLineKind,
+    pub pattern: String,
+}
+
+/// What a console line was taken as
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, TS, ToSchema)]
+#[ts(export)]
+pub struct LogClassification {
+    pub kind: LogLineKind,
+    pub player: String,
+    /// Only for chat
+    pub message: Option<String>,
+}
+
+/// The rules a server of the flavour starts out with
+pub fn default_rules(flavour: &Flavour) -> Vec<LogRule> {
+    let chat = match flavour {
+        Flavour::Forge { .. } | Flavour::Fabric { .. } | Flavour::Quilt { .. } => DECORATED_CHAT,
+        Flavour::Vanilla | Flavour::Paper { .. } | Flavour::Spigot => CHAT,
+    };
+    // chat comes first, so a player can't pass a message off as someone joining
+    vec![
+        LogRule {
+            kind: LogLineKind::Chat,
+            pattern: chat.to_string(),
+        },
+        LogRule {
+            kind: LogLineKind::PlayerJoined,
+            pattern: PLAYER_JOINED.to_string(),
+        },
+        LogRule {
+            kind: LogLineKind::PlayerLeft,
+            pattern: PLAYER_LEFT.to_string(),
+        },
+    ]
+}
+
+/// Rules compiled for matching, the first one a line matches classifies it
+pub struct LogRules {
+    rules: Vec<(LogLineKind, Regex)>,
+}
+
+impl LogRules {
+    pub fn compile(rules: &[LogRule]) -> Result<Self, Error> {
+        if rules.len() > MAX_LOG_RULES {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("An instance can have at most {} log rules", MAX_LOG_RULES),
+            });
+        }
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules {
+            if rule.pattern.chars().count() > MAX_PATTERN_LENGTH {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Log rule patterns can be at most {} characters long",
+                        MAX_PATTERN_LENGTH
+                    ),
+                });
+            }
+            let regex = RegexBuilder::new(&rule.pattern)
+                .backtrack_limit(REGEX_BACKTRACK_LIMIT)
+                .delegate_size_limit(REGEX_SIZE_LIMIT)
+                .delegate_dfa_size_limit(REGEX_SIZE_LIMIT)
+                .build()
+                .map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid regex \"{}\": {}", rule.pattern, e),
+                })?;
+            let groups: Vec<&str> = regex.capture_names().flatten().collect();
+            let required: &[&str] = match rule.kind {
+                LogLineKind::Chat => &["player", "message"],
+                LogLineKind::PlayerJoined | LogLineKind::PlayerLeft => &["player"],
+            };
+            if let Some(missing) = required.iter().find(|group| !groups.contains(group)) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "The pattern \"{}\" needs a group named {}, e.g. (?P<{}>.+)",
+                        rule.pattern,
+                        missing,
+                        missing
+                    ),
+                });
+            }
+            compiled.push((rule.kind, regex));
+        }
+        Ok(Self { rules: compiled })
+    }
+
+    pub fn classify(&self, line: &str) -> Option<LogClassification> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut end = line.len().min(MAX_MATCHED_LINE_LENGTH);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        let line = &line[..end];
+        let started = Instant::now();
+        for (kind, regex) in &self.rules {
+            if started.elapsed() > LINE_MATCH_BUDGET {
+                warn!("Stopped classifying a console line, the log rules took too long on it");
+                return None;
+            }
+            // an error means the backtrack limit was hit
+            let captures = match regex.captures(line) {
+                Ok(Some(captures)) => captures,
+                _ => continue,
+            };
+            let player = match captures.name("player") {
+                Some(player) if !player.as_str().is_empty() => player.as_str().to_string(),
+                _ => continue,
+            };
+            return Some(LogClassification {
+                kind: *kind,
+                player,
+                message: captures
+                    .name("message")
+                    .filter(|_| *kind == LogLineKind::Chat)
+                    .map(|message| message.as_str().to_string()),
+            });
+        }
+        None
+    }
+}
+
+impl RestoreConfig {
+    /// The instance's own rules, or the defaults of its flavour
+    pub fn log_rules(&self) -> Vec<LogRule> {
+        self.log_rules
+            .clone()
+            .unwrap_or_else(|| default_rules(&self.flavour))
+    }
+
+    /// Rules saved before a limit got stricter fall back to the defaults instead of leaving the
+    /// server unparsed
+    pub(super) fn compiled_log_rules(&self) -> LogRules {
+        LogRules::compile(&self.log_rules()).unwrap_or_else(|e| {
+            warn!(
+                "[{}] Using the default log rules, the instance's don't compile: {}",
+                self.name, e
+            );
+            LogRules::compile(&default_rules(&self.flavour)).expect("the default log rules compile")
+        })
+    }
+}
+
+impl MinecraftInstance {
+    /// Whether the rules are the instance's own, and the rules in effect
+    pub async fn log_rules(&self) -> (bool, Vec<LogRule>) {
+        let config = self.config.lock().await;
+        (config.log_rules.is_some(), config.log_rules())
+    }
+
+    /// Replaces the instance's rules, `None` goes back to the defaults of its flavour. Taken up
+    /// the next time the server starts
+    pub async fn set_log_rules(&self, rules: Option<Vec<LogRule>>) -> Result<(), Error> {
+        if let Some(rules) = &rules {
+            LogRules::compile(rules)?;
+        }
+        self.config.lock().await.log_rules = rules;
+        self.write_config_to_file().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(flavour: &Flavour, line: &str) -> Option<LogClassification> {
+        LogRules::compile(&default_rules(flavour))
+            .unwrap()
+            .classify(line)
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let vanilla = Flavour::Vanilla;
+        assert_eq!(
+            classify(
+                &vanilla,
+                "[12:00:00] [Server thread/INFO]: Steve joined the game\n"
+            ),
+            Some(LogClassification {
+                kind: LogLineKind::PlayerJoined,
+                player: "Steve".to_string(),
+                message: None,
+            })
+        );
+        assert_eq!(
+            classify(
+                &vanilla,
+                "[12:00:00] [Server thread/INFO]: Steve (formerly known as Bob) joined the game"
+            )
+            .unwrap()
+            .player,
+            "Steve"
+        );
+        assert_eq!(
+            classify(
+                &vanilla,
+                "[12:00:01] [Server thread/INFO]: Alex left the game"
+            )
+            .unwrap()
+            .kind,
+            LogLineKind::PlayerLeft
+        );
+        assert_eq!(
+            classify(
+                &vanilla,
+                "[12:00:02] [Server thread/INFO]: <Steve> Alex joined the game"
+            ),
+            Some(LogClassification {
+                kind: LogLineKind::Chat,
+                player: "Steve".to_string(),
+                message: Some("Alex joined the game".to_string()),
+            })
+        );
+        assert_eq!(
+            classify(&vanilla, "[12:00:03] [Server thread/INFO]: Done (3.2s)!"),
+            None
+        );
+
+        let forge = Flavour::Forge {
+            build_version: None,
+        };
+        let decorated =
+            "[12:00:04] [Server thread/INFO] [minecraft/MinecraftServer]: <[Admin] Steve> hi";
+        assert_eq!(classify(&forge, decorated).unwrap().player, "Steve");
+        assert_eq!(classify(&vanilla, decorated), None);
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let german = LogRules::compile(&[LogRule {
+            kind: LogLineKind::PlayerJoined,
+            pattern: r"\]: (?P<player>\w+) hat das Spiel betreten$".to_string(),
+        }])
+        .unwrap();
+        assert_eq!(
+            german
+                .classify("[12:00:00] [Server thread/INFO]: Steve hat das Spiel betreten")
+                .unwrap()
+                .player,
+            "Steve"
+        );
+
+        let unnamed = LogRules::compile(&[LogRule {
+            kind: LogLineKind::Chat,
+            pattern: r"<(?P<player>\w+)> (.*)".to_string(),
+        }]);
+        assert!(unnamed.is_err());
+        let invalid = LogRules::compile(&[LogRule {
+            kind: LogLineKind::PlayerLeft,
+            pattern: r"(?P<player>\w+".to_string(),
+        }]);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_catastrophic_backtracking_does_not_match() {
+        // a backreference makes fancy-regex backtrack instead of delegating to the linear engine
+        let rules = LogRules::compile(&[LogRule {
+            kind: LogLineKind::PlayerJoined,
+            pattern: r"^(?P<player>(a+)+)\2b$".to_string(),
+        }])
+        .unwrap();
+        assert_eq!(rules.classify(&"a".repeat(64)), None);
+    }
+}
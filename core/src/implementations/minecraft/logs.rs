@@ -0,0 +1,360 @@
+//! The server's logs and crash reports, read without loading a whole multi-gigabyte log into
+//! memory. Rotated logs are gzipped by the server and decompressed as they are read
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use color_eyre::eyre::Context;
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorCode};
+use crate::util::extended_length_path;
+
+use super::MinecraftInstance;
+
+pub const LOGS_DIR: &str = "logs";
+pub const CRASH_REPORTS_DIR: &str = "crash-reports";
+pub const LATEST_LOG: &str = "latest.log";
+pub const DEFAULT_TAIL_LINES: usize = 500;
+pub const MAX_TAIL_LINES: usize = 10_000;
+/// How much of the end of a log is read at a time while looking for enough lines
+const TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+/// A tail stops growing past this, in case of a log of a few very long lines
+const MAX_TAIL_BYTES: u64 = 16 * 1024 * 1024;
+/// The summary of a crash report is in its first few lines, the stack trace below is skipped
+const CRASH_REPORT_HEAD_BYTES: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct LogFile {
+    pub name: String,
+    /// On disk, so compressed for a gzipped log
+    pub size: u64,
+    /// Unix timestamp in seconds
+    pub modified: Option<i64>,
+    pub compressed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct CrashReport {
+    pub name: String,
+    pub size: u64,
+    /// Unix timestamp in seconds
+    pub modified: Option<i64>,
+    /// As written in the report
+    pub time: Option<String>,
+    /// e.g. `Exception in server tick loop`
+    pub description: Option<String>,
+    /// The first line of the exception, e.g. `java.lang.NullPointerException: ...`
+    pub exception: Option<String>,
+}
+
+fn unix_timestamp(time: std::io::Result<SystemTime>) -> Option<i64> {
+    time.ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+}
+
+fn is_log(name: &str) -> bool {
+    name.ends_with(".log") || name.ends_with(".log.gz")
+}
+
+/// The file `name` in `dir`, which must be a plain file name and not a path
+fn file_in(dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    let not_found = || {
+        Error::coded(
+            ErrorCode::FileNotFound,
+            format!("There is no file named {}", name),
+        )
+    };
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(not_found());
+    }
+    let path = dir.join(name);
+    if !extended_length_path(&path).is_file() {
+        return Err(not_found());
+    }
+    Ok(path)
+}
+
+fn log_in(dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    if !is_log(name) {
+        return Err(Error::coded(
+            ErrorCode::FileNotFound,
+            format!("{} is not a log", name),
+        ));
+    }
+    file_in(dir, name)
+}
+
+/// The logs in `dir`, `latest.log` first and the rest newest first
+pub fn list_logs(dir: &Path) -> Result<Vec<LogFile>, Error> {
+    let entries = match std::fs::read_dir(extended_length_path(dir)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result.context(format!("Failed to read {}", dir.display()))?,
+    };
+    let mut logs: Vec<LogFile> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+            is_log(&name).then(|| LogFile {
+                compressed: name.ends_with(".gz"),
+                name,
+                size: metadata.len(),
+                modified: unix_timestamp(metadata.modified()),
+            })
+        })
+        .collect();
+    logs.sort_by(|a, b| {
+        (b.name == LATEST_LOG)
+            .cmp(&(a.name == LATEST_LOG))
+            .then(b.modified.cmp(&a.modified))
+            .then(b.name.cmp(&a.name))
+    });
+    Ok(logs)
+}
+
+/// The last `lines` lines of a plain text file, read backwards from its end in chunks
+fn tail_plain(file: &mut File, lines: usize) -> std::io::Result<Vec<String>> {
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let mut tail: Vec<u8> = Vec::new();
+    while position > 0 && (tail.len() as u64) < MAX_TAIL_BYTES {
+        let read = TAIL_CHUNK_SIZE.min(position);
+        position -= read;
+        file.seek(SeekFrom::Start(position))?;
+        let mut chunk = vec![0; read as usize];
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        // one more line break than lines wanted, since the first line may be cut
+        let breaks = tail.iter().filter(|byte| **byte == b'\n').count();
+        let trailing = tail.last() == Some(&b'\n');
+        if breaks - trailing as usize > lines {
+            break;
+        }
+    }
+    let text = String::from_utf8_lossy(&tail);
+    let mut all: Vec<&str> = text.lines().collect();
+    if position > 0 && !all.is_empty() {
+        all.remove(0);
+    }
+    let skip = all.len().saturating_sub(lines);
+    Ok(all[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+/// The last `lines` lines of a gzipped file, which can only be found by decompressing all of it
+fn tail_gzipped(file: File, lines: usize) -> std::io::Result<Vec<String>> {
+    let mut reader = BufReader::new(GzDecoder::new(BufReader::new(file)));
+    let mut tail = VecDeque::with_capacity(lines);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if tail.len() == lines {
+            tail.pop_front();
+        }
+        let text = String::from_utf8_lossy(&line);
+        tail.push_back(text.trim_end_matches(['\r', '\n']).to_string());
+    }
+    Ok(tail.into())
+}
+
+/// The last `lines` lines of the log `name` in `dir`, blocks while reading it
+pub fn tail_log(dir: &Path, name: &str, lines: usize) -> Result<Vec<String>, Error> {
+    let path = log_in(dir, name)?;
+    let lines = lines.clamp(1, MAX_TAIL_LINES);
+    let mut file = File::open(extended_length_path(&path))
+        .context(format!("Failed to open {}", path.display()))?;
+    let tail = if name.ends_with(".gz") {
+        tail_gzipped(file, lines)
+    } else {
+        tail_plain(&mut file, lines)
+    };
+    Ok(tail.context(format!("Failed to read {}", path.display()))?)
+}
+
+/// Opens the log `name` in `dir` for reading its text, decompressing it if it's gzipped
+pub fn open_log(dir: &Path, name: &str) -> Result<Box<dyn Read + Send>, Error> {
+    let path = log_in(dir, name)?;
+    let file = File::open(extended_length_path(&path))
+        .context(format!("Failed to open {}", path.display()))?;
+    Ok(if name.ends_with(".gz") {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Picks the time, description and exception out of the header of a crash report:
+///
+/// ```text
+/// ---- Minecraft Crash Report ----
+/// // Witty comment
+///
+/// Time: 2023-06-01 12:00:00
+/// Description: Exception in server tick loop
+///
+/// java.lang.NullPointerException: Cannot invoke "Object.toString()"
+/// ```
+fn parse_crash_report_head(head: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let mut time = None;
+    let mut description = None;
+    let mut lines = head.lines().map(str::trim);
+    for line in lines.by_ref() {
+        if let Some(value) = line.strip_prefix("Time:") {
+            time = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("Description:") {
+            description = Some(value.trim().to_string());
+            break;
+        }
+    }
+    let exception = description
+        .as_ref()
+        .and_then(|_| lines.find(|line| !line.is_empty()))
+        .map(str::to_string);
+    (time, description, exception)
+}
+
+/// The crash reports in `dir` newest first, each summarised from its first few lines
+pub fn list_crash_reports(dir: &Path) -> Result<Vec<CrashReport>, Error> {
+    let entries = match std::fs::read_dir(extended_length_path(dir)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result.context(format!("Failed to read {}", dir.display()))?,
+    };
+    let mut reports = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_file() && name.ends_with(".txt") => metadata,
+            _ => continue,
+        };
+        let mut head = String::new();
+        // an unreadable report is still listed, just without a summary
+        let _ = File::open(entry.path())
+            .map(|file| file.take(CRASH_REPORT_HEAD_BYTES))
+            .and_then(|mut file| {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                head = String::from_utf8_lossy(&buf).into_owned();
+                Ok(())
+            });
+        let (time, description, exception) = parse_crash_report_head(&head);
+        reports.push(CrashReport {
+            name,
+            size: metadata.len(),
+            modified: unix_timestamp(metadata.modified()),
+            time,
+            description,
+            exception,
+        });
+    }
+    reports.sort_by(|a, b| b.modified.cmp(&a.modified).then(b.name.cmp(&a.name)));
+    Ok(reports)
+}
+
+impl MinecraftInstance {
+    pub fn path_to_logs(&self) -> PathBuf {
+        self.path_to_instance.join(LOGS_DIR)
+    }
+
+    pub fn path_to_crash_reports(&self) -> PathBuf {
+        self.path_to_instance.join(CRASH_REPORTS_DIR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn numbered_lines(count: usize) -> String {
+        (1..=count).map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn test_tail_log() {
+        let dir = tempfile::tempdir().unwrap();
+        // spans several chunks
+        let content = numbered_lines(20_000);
+        std::fs::write(dir.path().join(LATEST_LOG), &content).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(dir.path().join("2023-06-01-1.log.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        for name in [LATEST_LOG, "2023-06-01-1.log.gz"] {
+            let tail = tail_log(dir.path(), name, 3).unwrap();
+            assert_eq!(tail, vec!["line 19998", "line 19999", "line 20000"]);
+        }
+        assert_eq!(
+            tail_log(dir.path(), LATEST_LOG, 100_000).unwrap().len(),
+            10_000
+        );
+
+        std::fs::write(dir.path().join("short.log"), "only\r\nlines").unwrap();
+        assert_eq!(
+            tail_log(dir.path(), "short.log", 10).unwrap(),
+            vec!["only", "lines"]
+        );
+        assert!(tail_log(dir.path(), "../latest.log", 10).is_err());
+        assert!(tail_log(dir.path(), "missing.log", 10).is_err());
+    }
+
+    #[test]
+    fn test_list_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(list_logs(&dir.path().join(LOGS_DIR)).unwrap().is_empty());
+        std::fs::write(dir.path().join("2023-06-01-1.log.gz"), "").unwrap();
+        std::fs::write(dir.path().join(LATEST_LOG), "").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        let logs = list_logs(dir.path()).unwrap();
+        assert_eq!(
+            logs.iter().map(|log| log.name.as_str()).collect::<Vec<_>>(),
+            vec![LATEST_LOG, "2023-06-01-1.log.gz"]
+        );
+        assert!(logs[1].compressed);
+    }
+
+    #[test]
+    fn test_list_crash_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("crash-2023-06-01_12.00.00-server.txt"),
+            "---- Minecraft Crash Report ----\n\
+             // Why did you do that?\n\
+             \n\
+             Time: 2023-06-01 12:00:00\n\
+             Description: Exception in server tick loop\n\
+             \n\
+             java.lang.NullPointerException: Cannot invoke \"Object.toString()\"\n\
+             \tat net.minecraft.server.MinecraftServer.tick(MinecraftServer.java:1)\n",
+        )
+        .unwrap();
+        let reports = list_crash_reports(dir.path()).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].time.as_deref(), Some("2023-06-01 12:00:00"));
+        assert_eq!(
+            reports[0].description.as_deref(),
+            Some("Exception in server tick loop")
+        );
+        assert_eq!(
+            reports[0].exception.as_deref(),
+            Some("java.lang.NullPointerException: Cannot invoke \"Object.toString()\"")
+        );
+        assert_eq!(
+            parse_crash_report_head("not a crash report"),
+            (None, None, None)
+        );
+    }
+}
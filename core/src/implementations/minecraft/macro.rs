@@ -4,16 +4,20 @@ use async_trait::async_trait;
 use color_eyre::eyre::{eyre, Context};
 use indexmap::IndexMap;
 
+use tracing::warn;
+
 use crate::error::ErrorKind;
-use crate::macro_executor::MacroExecutor;
+use crate::macro_executor::{config_manifest_from_code, parse_macro_metadata, MacroExecutor};
 use crate::traits::t_configurable::manifest::{
     ConfigurableValue, SettingLocalCache, SettingManifest,
 };
 use crate::{
     error::Error,
-    events::CausedBy,
+    events::{
+        CausedBy, Event, ProgressionEndValue, ProgressionStartBuilder, ProgressionStartValue,
+    },
     macro_executor::{DefaultWorkerOptionGenerator, MacroPID, SpawnResult},
-    traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
+    traits::t_macro::{ExitStatus, HistoryEntry, MacroEntry, MacroOverlap, TMacro, TaskEntry},
 };
 
 use super::MinecraftInstance;
@@ -44,25 +48,54 @@ pub fn resolve_macro_invocation(path_to_macro: &Path, macro_name: &str) -> Optio
 #[async_trait]
 impl TMacro for MinecraftInstance {
     async fn get_macro_list(&self) -> Result<Vec<MacroEntry>, Error> {
-        let mut ret = Vec::new();
+        let mut ret: Vec<MacroEntry> = Vec::new();
         for entry in
             (std::fs::read_dir(&self.path_to_macros).context("Failed to read macro dir")?).flatten()
         {
-            // if the entry is a file, check if it has the .ts or .js extension
+            // a directory with an index.ts or index.js, or a single .ts or .js file
             let path = entry.path();
-            let name = entry.file_name().to_string_lossy().to_string();
-            if path.is_dir() {
-                // check if index.ts or index.js exists
-                let index_ts = path.join("index.ts");
-                let index_js = path.join("index.js");
-                if index_ts.exists() || index_js.exists() {
-                    ret.push(MacroEntry {
-                        last_run: self.macro_name_to_last_run.lock().await.get(&name).cloned(),
-                        name,
-                        path,
-                    })
+            let name = if path.is_dir() {
+                entry.file_name().to_string_lossy().to_string()
+            } else if matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("ts" | "js")
+            ) {
+                match path.file_stem() {
+                    Some(stem) => stem.to_string_lossy().to_string(),
+                    None => continue,
                 }
+            } else {
+                continue;
+            };
+            if ret.iter().any(|entry| entry.name == name) {
+                continue;
             }
+            let main_module = match resolve_macro_invocation(&self.path_to_macros, &name) {
+                Some(main_module) => main_module,
+                None => continue,
+            };
+            let code = match crate::util::fs::read_to_string(&main_module).await {
+                Ok(code) => code,
+                Err(e) => {
+                    warn!("Skipping macro {}: {}", name, e);
+                    continue;
+                }
+            };
+            let metadata = parse_macro_metadata(&code);
+            let (config, config_error) = match config_manifest_from_code(&code) {
+                Ok(config) => (config, None),
+                Err(e) => (IndexMap::new(), Some(e.source.to_string())),
+            };
+            ret.push(MacroEntry {
+                last_run: self.macro_name_to_last_run.lock().await.get(&name).cloned(),
+                name,
+                path,
+                display_name: metadata.name,
+                description: metadata.description,
+                overlap: metadata.overlap,
+                config,
+                config_error,
+            })
         }
         ret.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(ret)
@@ -112,6 +145,8 @@ impl TMacro for MinecraftInstance {
     ) -> Result<TaskEntry, Error> {
         let path_to_macro = resolve_macro_invocation(&self.path_to_macros, name)
             .ok_or_else(|| eyre!("Failed to resolve macro invocation for {}", name))?;
+        let metadata =
+            parse_macro_metadata(&crate::util::fs::read_to_string(&path_to_macro).await?);
 
         // compose config injection code
         let config_code = match configs {
@@ -152,38 +187,135 @@ impl TMacro for MinecraftInstance {
             None => None,
         };
 
-        let SpawnResult { macro_pid: pid, .. } = self
-            .macro_executor
-            .spawn(
-                path_to_macro,
-                args,
-                caused_by,
-                Box::new(DefaultWorkerOptionGenerator),
-                config_code,
-                None,
-                Some(self.uuid.clone()),
-            )
-            .await?;
+        // held across the check and the insert so two requests can't both pass the check
+        let mut pid_to_task_entry = self.pid_to_task_entry.lock().await;
+        if metadata.overlap == MacroOverlap::Reject {
+            for (pid, entry) in pid_to_task_entry.iter() {
+                if entry.name == name && self.macro_executor.get_macro_status(*pid).await.is_none()
+                {
+                    return Err(Error {
+                        kind: ErrorKind::Conflict,
+                        source: eyre!("Macro {} is already running", name),
+                    });
+                }
+            }
+        }
+        let pid = self.macro_executor.reserve_pid();
         let entry = TaskEntry {
             pid,
             name: name.to_string(),
             creation_time: chrono::Utc::now().timestamp(),
+            caused_by: caused_by.clone(),
         };
-        self.pid_to_task_entry
-            .lock()
-            .await
-            .insert(pid, entry.clone());
+        pid_to_task_entry.insert(pid, entry.clone());
+        drop(pid_to_task_entry);
         self.macro_name_to_last_run
             .lock()
             .await
             .insert(name.to_string(), chrono::Utc::now().timestamp());
 
+        let run_lock = self
+            .macro_run_locks
+            .lock()
+            .await
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        tokio::spawn({
+            let macro_executor = self.macro_executor.clone();
+            let event_broadcaster = self.event_broadcaster.clone();
+            let uuid = self.uuid.clone();
+            let name = name.to_string();
+            async move {
+                // runs of the same macro take turns, in the order they were requested
+                let _run_guard = run_lock.lock_owned().await;
+                if macro_executor.get_macro_status(pid).await.is_some() {
+                    // aborted while queued
+                    return;
+                }
+                let (progression_start, event_id) = ProgressionStartBuilder::new(
+                    format!("Running macro {}", name),
+                    ProgressionStartValue::MacroRun {
+                        instance_uuid: uuid.clone(),
+                        macro_name: name.clone(),
+                        pid,
+                    },
+                    caused_by.clone(),
+                )
+                .build();
+                event_broadcaster.send(progression_start);
+                let spawned = macro_executor
+                    .spawn_with_pid(
+                        pid,
+                        path_to_macro,
+                        args,
+                        caused_by,
+                        Box::new(DefaultWorkerOptionGenerator),
+                        config_code,
+                        None,
+                        Some(uuid.clone()),
+                    )
+                    .await;
+                let exit_status = match spawned {
+                    Ok(SpawnResult { exit_future, .. }) => {
+                        exit_future.await.unwrap_or_else(|e| ExitStatus::Error {
+                            time: chrono::Utc::now().timestamp(),
+                            error_msg: e.source.to_string(),
+                        })
+                    }
+                    Err(e) => {
+                        let exit_status = ExitStatus::Error {
+                            time: chrono::Utc::now().timestamp(),
+                            error_msg: e.source.to_string(),
+                        };
+                        macro_executor.record_exit(pid, exit_status.clone(), Some(uuid.clone()));
+                        exit_status
+                    }
+                };
+                let message = match &exit_status {
+                    ExitStatus::Success { .. } => format!("Macro {} finished", name),
+                    ExitStatus::Killed { .. } => format!("Macro {} was aborted", name),
+                    ExitStatus::Error { error_msg, .. } => {
+                        format!("Macro {} failed: {}", name, error_msg)
+                    }
+                };
+                event_broadcaster.send(Event::new_progression_event_end(
+                    event_id,
+                    exit_status.is_success(),
+                    Some(message),
+                    Some(ProgressionEndValue::MacroRun {
+                        instance_uuid: uuid,
+                        macro_name: name,
+                        pid,
+                        exit_status,
+                    }),
+                ));
+            }
+        });
+
         Ok(entry)
     }
 
     async fn kill_macro(&self, pid: MacroPID) -> Result<(), Error> {
-        self.macro_executor.abort_macro(pid)?;
-        Ok(())
+        match self.macro_executor.abort_macro(pid) {
+            Err(e) if matches!(e.kind, ErrorKind::NotFound) => {
+                // a queued run has no isolate to terminate yet
+                let queued = self.pid_to_task_entry.lock().await.contains_key(&pid)
+                    && self.macro_executor.get_macro_status(pid).await.is_none();
+                if !queued {
+                    return Err(e);
+                }
+                self.macro_executor.record_exit(
+                    pid,
+                    ExitStatus::Killed {
+                        time: chrono::Utc::now().timestamp(),
+                    },
+                    Some(self.uuid.clone()),
+                );
+                Ok(())
+            }
+            result => result,
+        }
     }
 
     async fn get_macro_config(
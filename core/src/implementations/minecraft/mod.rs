@@ -1,27 +1,51 @@
+mod announcements;
+mod appearance;
+pub mod chat;
 pub mod configurable;
+pub mod datapacks;
 pub mod fabric;
 mod forge;
+pub mod game_update;
+mod graceful_stop;
+pub mod hooks;
+pub mod jvm_args;
+pub mod launch_failure;
 mod line_parser;
+pub mod log_rules;
+pub mod logs;
 pub mod r#macro;
-mod paper;
+pub mod mods;
+pub mod paper;
 pub mod player;
+mod player_lists;
 mod players_manager;
+mod quilt;
+mod rcon;
+mod restart;
 pub mod server;
+mod server_launchers;
+mod setup_validation;
+pub mod suspend;
+pub mod templates;
+mod transition;
+pub mod server_list_ping;
 pub mod util;
 mod vanilla;
+mod version_upgrade;
 pub mod versions;
+pub mod worlds;
 
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 use enum_kinds::EnumKind;
 use indexmap::IndexMap;
 
-use std::collections::HashMap;
-use std::process::Stdio;
-use std::sync::atomic::AtomicBool;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::Arc;
+use std::time::Instant;
 use sysinfo::SystemExt;
 use tokio::io::AsyncWriteExt;
-use tokio::process::{Child, Command};
+use tokio::process::Child;
 
 use tokio::sync::Mutex;
 
@@ -33,11 +57,26 @@ use tracing::error;
 use tokio;
 use ts_rs::TS;
 
-use crate::error::Error;
+use crate::announcements::AnnouncementsConfig;
+use crate::backups::BackupSchedule;
+use crate::command_queue::{CommandQueue, CommandQueueConfig};
+use crate::console_capture::ConsoleCapture;
+use crate::console_history::{ConsoleHistory, DEFAULT_CONSOLE_HISTORY_SIZE};
+use crate::diagnostics::{accept_eula, eula_accepted};
+use crate::downloads::download_jar;
+use crate::error::{Error, ErrorKind};
 use crate::event_broadcaster::EventBroadcaster;
 use crate::events::{Event, ProgressionEventID};
+use crate::java::{
+    downloaded_runtime_dir, install_runtime, probe, runtime_executable, JavaRequirement,
+    JavaSelection, JavaSource,
+};
 use crate::macro_executor::{MacroExecutor, MacroPID};
 use crate::prelude::path_to_binaries;
+use crate::process_tree::{new_process_system, ProcessTreeTracker};
+use crate::restart_policy::{RestartMode, RestartPolicy};
+use crate::run_record::read_run_record;
+use crate::snapshot::{watch_instance_events, InstanceSnapshot, Snapshot};
 use crate::traits::t_configurable::PathBuf;
 
 use crate::traits::t_configurable::manifest::{
@@ -46,21 +85,31 @@ use crate::traits::t_configurable::manifest::{
 };
 
 use crate::traits::t_macro::TaskEntry;
-use crate::traits::t_server::State;
+use crate::traits::t_player::TPlayerManagement;
+use crate::traits::t_server::{MonitorReport, State};
 use crate::traits::TInstance;
 use crate::types::{DotLodestoneConfig, InstanceUuid};
-use crate::util::{
-    dont_spawn_terminal, download_file, format_byte, format_byte_download, unzip_file_async,
-    UnzipOption,
-};
+use crate::util::{format_byte, format_byte_download, DownloadProgress};
 
-use self::configurable::{CmdArgSetting, ServerPropertySetting};
+use self::chat::ChatHistory;
+use self::configurable::{CmdArgSetting, LodestoneSetting, ServerPropertySetting};
 use self::fabric::get_fabric_minecraft_versions;
-use self::forge::get_forge_minecraft_versions;
+use self::forge::{get_forge_minecraft_versions, run_forge_installer, FORGE_INSTALLER};
+use self::jvm_args::split_args;
+use self::launch_failure::LaunchFailure;
+use self::log_rules::LogRule;
 use self::paper::get_paper_minecraft_versions;
 use self::players_manager::PlayersManager;
-use self::util::{get_jre_url, get_server_jar_url, read_properties_from_path};
+use self::quilt::get_quilt_minecraft_versions;
+pub use self::rcon::DEFAULT_RCON_PORT;
+use self::server_launchers::{install_server_launcher, QUILT_INSTALLER};
+use self::server_list_ping::{PingStatus, DEFAULT_PING_INTERVAL_SECS};
+use self::transition::TransitionLock;
+use self::util::{
+    get_jre_url, get_server_jar_checksum, get_server_jar_url, read_properties_from_path,
+};
 use self::vanilla::get_vanilla_minecraft_versions;
+use self::versions::{check_loader_version, get_loader_versions};
 
 #[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
 #[ts(export)]
@@ -70,6 +119,12 @@ pub struct FabricLoaderVersion(String);
 pub struct FabricInstallerVersion(String);
 #[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
 #[ts(export)]
+pub struct QuiltLoaderVersion(String);
+#[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
+#[ts(export)]
+pub struct QuiltInstallerVersion(String);
+#[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
+#[ts(export)]
 pub struct PaperBuildVersion(i64);
 #[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
 #[ts(export)]
@@ -85,6 +140,10 @@ pub enum Flavour {
         loader_version: Option<FabricLoaderVersion>,
         installer_version: Option<FabricInstallerVersion>,
     },
+    Quilt {
+        loader_version: Option<QuiltLoaderVersion>,
+        installer_version: Option<QuiltInstallerVersion>,
+    },
     Paper {
         build_version: Option<PaperBuildVersion>,
     },
@@ -102,6 +161,10 @@ impl From<FlavourKind> for Flavour {
                 loader_version: None,
                 installer_version: None,
             },
+            FlavourKind::Quilt => Flavour::Quilt {
+                loader_version: None,
+                installer_version: None,
+            },
             FlavourKind::Paper => Flavour::Paper {
                 build_version: None,
             },
@@ -118,6 +181,7 @@ impl ToString for Flavour {
         match self {
             Flavour::Vanilla => "vanilla".to_string(),
             Flavour::Fabric { .. } => "fabric".to_string(),
+            Flavour::Quilt { .. } => "quilt".to_string(),
             Flavour::Paper { .. } => "paper".to_string(),
             Flavour::Spigot => "spigot".to_string(),
             Flavour::Forge { .. } => "forge".to_string(),
@@ -130,6 +194,7 @@ impl ToString for FlavourKind {
         match self {
             FlavourKind::Vanilla => "vanilla".to_string(),
             FlavourKind::Fabric => "fabric".to_string(),
+            FlavourKind::Quilt => "quilt".to_string(),
             FlavourKind::Paper => "paper".to_string(),
             FlavourKind::Spigot => "spigot".to_string(),
             FlavourKind::Forge => "forge".to_string(),
@@ -150,7 +215,13 @@ pub struct SetupConfig {
     pub auto_start: Option<bool>,
     pub restart_on_crash: Option<bool>,
     pub backup_period: Option<u32>,
+    /// Writes `eula=true` before the first launch, otherwise the server stops on its first
+    /// start until the EULA is accepted
+    #[serde(default)]
+    pub accept_eula: bool,
 }
+pub const DEFAULT_STOP_TIMEOUT_SECS: u32 = 120;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RestoreConfig {
     pub name: String,
@@ -167,6 +238,66 @@ pub struct RestoreConfig {
     pub backup_period: Option<u32>,
     pub jre_major_version: u64,
     pub has_started: bool,
+    #[serde(default)]
+    pub command_queue: CommandQueueConfig,
+    #[serde(default = "default_console_history_size")]
+    pub console_history_size: u32,
+    #[serde(default)]
+    pub announcements: AnnouncementsConfig,
+    /// Falls back to `restart_on_crash` for configs written before restart policies existed
+    #[serde(default)]
+    pub restart_policy: Option<RestartPolicy>,
+    #[serde(default)]
+    pub backup_schedule: BackupSchedule,
+    /// Send commands over RCON instead of stdin while a connection is up
+    #[serde(default)]
+    pub use_rcon: bool,
+    /// How long a stop may take before the kill route is suggested
+    #[serde(default = "default_stop_timeout_secs")]
+    pub stop_timeout_secs: u32,
+    /// Passed to Java after the heap flags, e.g. garbage collector tuning
+    #[serde(default)]
+    pub extra_jvm_args: Vec<String>,
+    /// Seconds between server list pings while the server runs, 0 turns them off
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u32,
+    /// Set for the server and its hooks on top of Lodestone's own environment
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+    /// Shell command run before the server is spawned, a failure aborts the start
+    #[serde(default)]
+    pub pre_start_hook: Option<String>,
+    /// Shell command run once the server exited
+    #[serde(default)]
+    pub post_stop_hook: Option<String>,
+    /// Paper build downloaded while the server ran, installed on the next start
+    #[serde(default)]
+    pub pending_build: Option<i64>,
+    /// Minutes without players before the server is suspended, `None` if it never is
+    #[serde(default)]
+    pub auto_suspend_minutes: Option<u32>,
+    /// Recognizes joins, leaves and chat in the console, `None` for the defaults of the flavour
+    #[serde(default)]
+    pub log_rules: Option<Vec<LogRule>>,
+}
+
+impl RestoreConfig {
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.restart_policy
+            .unwrap_or_else(|| RestartPolicy::from_restart_on_crash(self.restart_on_crash))
+    }
+}
+
+fn default_console_history_size() -> u32 {
+    DEFAULT_CONSOLE_HISTORY_SIZE
+}
+
+fn default_stop_timeout_secs() -> u32 {
+    DEFAULT_STOP_TIMEOUT_SECS
+}
+
+fn default_ping_interval_secs() -> u32 {
+    DEFAULT_PING_INTERVAL_SECS
 }
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -190,14 +321,43 @@ pub struct MinecraftInstance {
     restart_on_crash: Arc<AtomicBool>,
     backup_period: Option<u32>,
     process: Arc<Mutex<Option<Child>>>,
-    stdin: Arc<Mutex<Option<tokio::process::ChildStdin>>>,
+    /// Reads the server's output and cleans up once it exits
+    output_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    command_queue: CommandQueue,
+    console_capture: ConsoleCapture,
+    console_history: Arc<Mutex<ConsoleHistory>>,
+    /// Chat recognized in the console and messages relayed to it
+    chat_history: Arc<Mutex<ChatHistory>>,
+    announcements_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    ping_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    ping_status: Arc<Mutex<PingStatus>>,
+    /// Suspends the server once it has been empty long enough and resumes it on a connection
+    idle_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    /// Cancels the pending graceful stop countdown, true if an immediate stop replaces it
+    graceful_stop: Arc<Mutex<Option<tokio::sync::oneshot::Sender<bool>>>>,
+    /// Set when a stop or kill is requested so the exit is not mistaken for a crash
+    stop_requested: Arc<AtomicBool>,
+    /// Set by a kill, the exit it causes is recorded as killed rather than stopped
+    killed: Arc<AtomicBool>,
+    /// Held by a start, stop or restart while it runs, so they never interleave
+    transition_lock: TransitionLock,
+    /// Consecutive automatic restarts since the server last stayed up
+    restart_attempts: Arc<AtomicU32>,
+    /// Why the server last exited before it finished starting
+    last_launch_failure: Arc<Mutex<Option<LaunchFailure>>>,
     system: Arc<Mutex<sysinfo::System>>,
+    last_monitor_report: Arc<Mutex<Option<(Instant, MonitorReport)>>>,
+    process_tree: Arc<Mutex<ProcessTreeTracker>>,
     players_manager: Arc<Mutex<PlayersManager>>,
     configurable_manifest: Arc<Mutex<ConfigurableManifest>>,
     macro_executor: MacroExecutor,
     rcon_conn: Arc<Mutex<Option<rcon::Connection<tokio::net::TcpStream>>>>,
     macro_name_to_last_run: Arc<Mutex<HashMap<String, i64>>>,
     pid_to_task_entry: Arc<Mutex<IndexMap<MacroPID, TaskEntry>>>,
+    /// Held by a macro's run while it executes, queued runs wait on it
+    macro_run_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    /// Informational fields served to readers without touching the locks above
+    snapshot: Snapshot<InstanceSnapshot>,
 }
 
 #[tokio::test]
@@ -214,6 +374,7 @@ impl MinecraftInstance {
         let versions = match flavour {
             FlavourKind::Vanilla => get_vanilla_minecraft_versions().await,
             FlavourKind::Fabric => get_fabric_minecraft_versions().await,
+            FlavourKind::Quilt => get_quilt_minecraft_versions().await,
             FlavourKind::Paper => get_paper_minecraft_versions().await,
             FlavourKind::Spigot => todo!(),
             FlavourKind::Forge => get_forge_minecraft_versions().await,
@@ -276,10 +437,48 @@ impl MinecraftInstance {
             true,
         );
 
+        let accept_eula_setting = SettingManifest::new_optional_value(
+            "accept_eula".to_string(),
+            "Accept the Minecraft EULA".to_string(),
+            "Agree to the Minecraft EULA (https://aka.ms/MinecraftEULA) for this server, it won't start otherwise".to_string(),
+            None,
+            ConfigurableValueType::Boolean,
+            Some(ConfigurableValue::Boolean(false)),
+            false,
+            true,
+        );
+
         let mut section_1_map = IndexMap::new();
 
         section_1_map.insert("version".to_string(), version_setting);
+        if let FlavourKind::Fabric | FlavourKind::Quilt = flavour {
+            let loaders = get_loader_versions(*flavour, None)
+                .await
+                .context("Failed to get loader versions")?;
+            let latest_stable = loaders
+                .iter()
+                .find(|loader| loader.stable)
+                .or_else(|| loaders.first())
+                .map(|loader| ConfigurableValue::Enum(loader.version.clone()));
+            section_1_map.insert(
+                "loader_version".to_string(),
+                SettingManifest::new_optional_value(
+                    "loader_version".to_string(),
+                    "Loader Version".to_string(),
+                    "The version of the mod loader, it must support the version of minecraft"
+                        .to_string(),
+                    latest_stable.clone(),
+                    ConfigurableValueType::Enum {
+                        options: loaders.into_iter().map(|loader| loader.version).collect(),
+                    },
+                    latest_stable,
+                    false,
+                    true,
+                ),
+            );
+        }
         section_1_map.insert("port".to_string(), port_setting);
+        section_1_map.insert("accept_eula".to_string(), accept_eula_setting);
 
         let mut section_2_map = IndexMap::new();
 
@@ -368,6 +567,32 @@ impl MinecraftInstance {
             .map(|s| s.to_string())
             .collect();
 
+        let accept_eula = setup_value
+            .get_unique_setting("accept_eula")
+            .and_then(|setting| setting.get_value())
+            .map(|v| v.try_as_boolean().unwrap())
+            .unwrap_or(false);
+
+        let loader_version = setup_value
+            .get_unique_setting("loader_version")
+            .and_then(|setting| setting.get_value())
+            .map(|v| v.try_as_enum().unwrap().clone());
+        // checked here since nothing has been created for the instance yet
+        if let Some(loader_version) = &loader_version {
+            check_loader_version(flavour, version, loader_version).await?;
+        }
+        let flavour = match (Flavour::from(flavour), loader_version) {
+            (Flavour::Fabric { .. }, Some(loader_version)) => Flavour::Fabric {
+                loader_version: Some(FabricLoaderVersion(loader_version)),
+                installer_version: None,
+            },
+            (Flavour::Quilt { .. }, Some(loader_version)) => Flavour::Quilt {
+                loader_version: Some(QuiltLoaderVersion(loader_version)),
+                installer_version: None,
+            },
+            (flavour, _) => flavour,
+        };
+
         Ok(SetupConfig {
             name,
             description,
@@ -376,10 +601,11 @@ impl MinecraftInstance {
             min_ram: Some(min_ram),
             max_ram: Some(max_ram),
             cmd_args,
-            flavour: flavour.into(),
+            flavour,
             auto_start: Some(setup_value.auto_start),
             restart_on_crash: Some(setup_value.restart_on_crash),
             backup_period: None,
+            accept_eula,
         })
     }
 
@@ -396,6 +622,11 @@ impl MinecraftInstance {
         cmd_args_config_map.insert(max_ram.get_identifier().to_owned(), max_ram.into());
         let java_cmd = CmdArgSetting::JavaCmd(java_cmd);
         cmd_args_config_map.insert(java_cmd.get_identifier().to_owned(), java_cmd.into());
+        let extra_jvm_args = CmdArgSetting::ExtraJvmArgs(restore_config.extra_jvm_args.clone());
+        cmd_args_config_map.insert(
+            extra_jvm_args.get_identifier().to_owned(),
+            extra_jvm_args.into(),
+        );
 
         let cmd_line_section_manifest = SectionManifest::new(
             CmdArgSetting::get_section_id().to_string(),
@@ -411,8 +642,40 @@ impl MinecraftInstance {
             IndexMap::new(),
         );
 
+        let auto_start = LodestoneSetting::AutoStart(restore_config.auto_start);
+        let ping_interval = LodestoneSetting::PingInterval(restore_config.ping_interval_secs);
+        let env = LodestoneSetting::Env(restore_config.env.clone());
+        let pre_start_hook = LodestoneSetting::PreStartHook(restore_config.pre_start_hook.clone());
+        let post_stop_hook = LodestoneSetting::PostStopHook(restore_config.post_stop_hook.clone());
+        let lodestone_section_manifest = SectionManifest::new(
+            LodestoneSetting::get_section_id().to_string(),
+            "Lodestone Settings".to_string(),
+            "How Lodestone manages the server".to_string(),
+            IndexMap::from([
+                (auto_start.get_identifier().to_owned(), auto_start.into()),
+                (
+                    ping_interval.get_identifier().to_owned(),
+                    ping_interval.into(),
+                ),
+                (env.get_identifier().to_owned(), env.into()),
+                (
+                    pre_start_hook.get_identifier().to_owned(),
+                    pre_start_hook.into(),
+                ),
+                (
+                    post_stop_hook.get_identifier().to_owned(),
+                    post_stop_hook.into(),
+                ),
+            ]),
+        );
+
         let mut setting_sections = IndexMap::new();
 
+        setting_sections.insert(
+            LodestoneSetting::get_section_id().to_string(),
+            lodestone_section_manifest,
+        );
+
         setting_sections.insert(
             CmdArgSetting::get_section_id().to_string(),
             cmd_line_section_manifest,
@@ -430,12 +693,12 @@ impl MinecraftInstance {
         config: SetupConfig,
         dot_lodestone_config: DotLodestoneConfig,
         path_to_instance: PathBuf,
+        backup_schedule: BackupSchedule,
         progression_event_id: &ProgressionEventID,
         event_broadcaster: EventBroadcaster,
         macro_executor: MacroExecutor,
     ) -> Result<MinecraftInstance, Error> {
         let path_to_config = path_to_instance.join(".lodestone_minecraft_config.json");
-        let path_to_eula = path_to_instance.join("eula.txt");
         let path_to_macros = path_to_instance.join("macros");
         let path_to_resources = path_to_instance.join("resources");
         let path_to_properties = path_to_instance.join("server.properties");
@@ -453,7 +716,6 @@ impl MinecraftInstance {
             .and(tokio::fs::create_dir_all(&path_to_resources.join("mods")).await)
             .and(tokio::fs::create_dir_all(&path_to_resources.join("worlds")).await)
             .and(tokio::fs::create_dir_all(&path_to_resources.join("defaults")).await)
-            .and(tokio::fs::write(&path_to_eula, "#generated by Lodestone\neula=true").await)
             .and(
                 tokio::fs::write(&path_to_properties, format!("server-port={}", config.port)).await,
             )
@@ -462,68 +724,32 @@ impl MinecraftInstance {
                 error!("{e}");
                 e
             })?;
+        if config.accept_eula {
+            accept_eula(&path_to_instance).await?;
+        }
 
         // Step 2: Download JRE
-        let (url, jre_major_version) = get_jre_url(config.version.as_str())
+        let (_, jre_major_version) = get_jre_url(config.version.as_str())
             .await
             .context("Could not get JRE URL")?;
-        if !path_to_runtimes
-            .join("java")
-            .join(format!("jre{}", jre_major_version))
-            .exists()
-        {
-            let downloaded = download_file(
-                &url,
-                &path_to_runtimes.join("java"),
-                None,
-                {
-                    let event_broadcaster = event_broadcaster.clone();
-                    &move |dl| {
-                        if let Some(total) = dl.total {
-                            event_broadcaster.send(Event::new_progression_event_update(
-                                progression_event_id,
-                                format!(
-                                    "2/4: Downloading JRE {}",
-                                    format_byte_download(dl.downloaded, total)
-                                ),
-                                (dl.step as f64 / total as f64) * 4.0,
-                            ));
-                        }
+        let path_to_java_runtimes = path_to_runtimes.join("java");
+        if !downloaded_runtime_dir(&path_to_java_runtimes, jre_major_version as u32).exists() {
+            install_runtime(jre_major_version as u32, &path_to_java_runtimes, {
+                let event_broadcaster = event_broadcaster.clone();
+                &move |dl| {
+                    if let Some(total) = dl.total {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            progression_event_id,
+                            format!(
+                                "2/4: Downloading JRE {}",
+                                format_byte_download(dl.downloaded, total)
+                            ),
+                            (dl.step as f64 / total as f64) * 4.0,
+                        ));
                     }
-                },
-                true,
-            )
-            .await?;
-
-            let unzipped_content = unzip_file_async(
-                &downloaded,
-                UnzipOption::ToDir(path_to_runtimes.join("java")),
-            )
+                }
+            })
             .await?;
-            if unzipped_content.len() != 1 {
-                return Err(eyre!(
-                    "Expected only one file in the JRE archive, got {}",
-                    unzipped_content.len()
-                )
-                .into());
-            }
-
-            tokio::fs::remove_file(&downloaded).await.context(format!(
-                "Could not remove downloaded JRE file {}",
-                downloaded.display()
-            ))?;
-
-            tokio::fs::rename(
-                unzipped_content.iter().last().unwrap(),
-                path_to_runtimes
-                    .join("java")
-                    .join(format!("jre{}", jre_major_version)),
-            )
-            .await
-            .context(format!(
-                "Could not rename JRE directory {}",
-                unzipped_content.iter().last().unwrap().display()
-            ))?;
         } else {
             event_broadcaster.send(Event::new_progression_event_update(
                 progression_event_id,
@@ -546,54 +772,84 @@ impl MinecraftInstance {
                 }
             })?;
         let jar_name = match flavour {
-            Flavour::Forge { .. } => "forge-installer.jar",
+            Flavour::Forge { .. } => FORGE_INSTALLER,
+            Flavour::Quilt { .. } => QUILT_INSTALLER,
             _ => "server.jar",
         };
+        let jre = runtime_executable(&downloaded_runtime_dir(
+            &path_to_java_runtimes,
+            jre_major_version as u32,
+        ));
 
-        download_file(
-            jar_url.as_str(),
-            &path_to_instance,
-            Some(jar_name),
-            {
-                let event_broadcaster = event_broadcaster.clone();
-                &move |dl| {
-                    if let Some(total) = dl.total {
-                        event_broadcaster.send(Event::new_progression_event_update(
-                            progression_event_id,
-                            format!(
-                                "3/4: Downloading {} {} {}",
-                                flavour_name,
-                                jar_name,
-                                format_byte_download(dl.downloaded, total),
-                            ),
-                            (dl.step as f64 / total as f64) * 3.0,
-                        ));
-                    } else {
-                        event_broadcaster.send(Event::new_progression_event_update(
-                            progression_event_id,
-                            format!(
-                                "3/4: Downloading {} {} {}",
-                                flavour_name,
-                                jar_name,
-                                format_byte(dl.downloaded),
-                            ),
-                            0.0,
-                        ));
-                    }
+        let on_download = {
+            let event_broadcaster = event_broadcaster.clone();
+            let flavour_name = flavour_name.clone();
+            move |dl: DownloadProgress| {
+                let speed = dl
+                    .speed
+                    .map(|speed| format!(" ({}/s)", format_byte(speed)))
+                    .unwrap_or_default();
+                if let Some(total) = dl.total {
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        progression_event_id,
+                        format!(
+                            "3/4: Downloading {} {} {}{}",
+                            flavour_name,
+                            jar_name,
+                            format_byte_download(dl.downloaded, total),
+                            speed,
+                        ),
+                        (dl.step as f64 / total as f64) * 3.0,
+                    ));
+                } else {
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        progression_event_id,
+                        format!(
+                            "3/4: Downloading {} {} {}{}",
+                            flavour_name,
+                            jar_name,
+                            format_byte(dl.downloaded),
+                            speed,
+                        ),
+                        0.0,
+                    ));
                 }
-            },
-            true,
-        )
-        .await?;
-        let jre = path_to_runtimes
-            .join("java")
-            .join(format!("jre{}", jre_major_version))
-            .join(if std::env::consts::OS == "macos" {
-                "Contents/Home/bin"
-            } else {
-                "bin"
-            })
-            .join("java");
+            }
+        };
+        if let Flavour::Fabric { .. } | Flavour::Quilt { .. } = flavour {
+            let downloaded = install_server_launcher(
+                &flavour,
+                &config.version,
+                &jar_url,
+                &jre,
+                &path_to_instance,
+                &on_download,
+                &|line| {
+                    event_broadcaster.send(Event::new_progression_event_update(
+                        progression_event_id,
+                        format!("3/4: Installing {} server: {}", flavour_name, line),
+                        0.0,
+                    ));
+                },
+            )
+            .await?;
+            if !downloaded {
+                event_broadcaster.send(Event::new_progression_event_update(
+                    progression_event_id,
+                    format!("3/4: {} server launcher already downloaded", flavour_name),
+                    3.0,
+                ));
+            }
+        } else {
+            let checksum = get_server_jar_checksum(&config.version, &flavour).await;
+            download_jar(
+                &jar_url,
+                checksum.as_ref(),
+                &path_to_instance.join(jar_name),
+                &on_download,
+            )
+            .await?;
+        }
         // Step 3 (part 2): Forge Setup
         if let Flavour::Forge { .. } = flavour.clone() {
             event_broadcaster.send(Event::new_progression_event_update(
@@ -602,26 +858,14 @@ impl MinecraftInstance {
                 1.0,
             ));
 
-            if !dont_spawn_terminal(
-                Command::new(&jre)
-                    .arg("-jar")
-                    .arg(&path_to_instance.join("forge-installer.jar"))
-                    .arg("--installServer")
-                    .arg(&path_to_instance)
-                    .current_dir(&path_to_instance),
-            )
-            .stderr(Stdio::null())
-            .stdout(Stdio::null())
-            .stdin(Stdio::null())
-            .spawn()
-            .context("Failed to start forge-installer.jar")?
-            .wait()
-            .await
-            .context("forge-installer.jar failed")?
-            .success()
-            {
-                return Err(eyre!("Failed to install forge server").into());
-            }
+            run_forge_installer(&jre, &path_to_instance, &|line| {
+                event_broadcaster.send(Event::new_progression_event_update(
+                    progression_event_id,
+                    format!("3/4: Installing Forge Server: {}", line),
+                    0.0,
+                ));
+            })
+            .await?;
 
             tokio::fs::write(
                 &path_to_instance.join("user_jvm_args.txt"),
@@ -653,6 +897,23 @@ impl MinecraftInstance {
             jre_major_version,
             has_started: false,
             java_cmd: Some(jre.to_string_lossy().to_string()),
+            command_queue: CommandQueueConfig::default(),
+            console_history_size: DEFAULT_CONSOLE_HISTORY_SIZE,
+            announcements: AnnouncementsConfig::default(),
+            restart_policy: Some(RestartPolicy::from_restart_on_crash(
+                config.restart_on_crash.unwrap_or(false),
+            )),
+            backup_schedule,
+            use_rcon: false,
+            stop_timeout_secs: DEFAULT_STOP_TIMEOUT_SECS,
+            extra_jvm_args: Vec::new(),
+            ping_interval_secs: DEFAULT_PING_INTERVAL_SECS,
+            env: IndexMap::new(),
+            pre_start_hook: None,
+            post_stop_hook: None,
+            pending_build: None,
+            auto_suspend_minutes: None,
+            log_rules: None,
         };
         // create config file
         tokio::fs::write(
@@ -700,7 +961,7 @@ impl MinecraftInstance {
                 format!("server-port={}", restore_config.port),
             )
             .await
-            .expect("failed to write to server.properties");
+            .context("Failed to write to server.properties")?;
         };
         let java_path = path_to_runtimes
             .join("java")
@@ -717,6 +978,29 @@ impl MinecraftInstance {
             java_path.to_string_lossy().to_string(),
         )));
 
+        let snapshot = Snapshot::new(InstanceSnapshot {
+            name: restore_config.name.clone(),
+            game_type: restore_config.flavour.clone().into(),
+            description: restore_config.description.clone(),
+            version: restore_config.version.clone(),
+            port: restore_config.port,
+            auto_start: restore_config.auto_start,
+            restart_on_crash: restore_config.restart_policy().mode != RestartMode::Never,
+            state: State::Stopped,
+            player_count: Some(0),
+            max_player_count: None,
+            player_list: Some(HashSet::new()),
+            launch_command: None,
+            eula_accepted: Some(eula_accepted(&path_to_instance).await),
+            last_started: None,
+            last_run: read_run_record(&path_to_instance).await,
+            degraded: false,
+        });
+        watch_instance_events(
+            &snapshot,
+            dot_lodestone_config.uuid().clone(),
+            &event_broadcaster,
+        );
         let instance = MinecraftInstance {
             state: Arc::new(Mutex::new(State::Stopped)),
             uuid: dot_lodestone_config.uuid().clone(),
@@ -728,6 +1012,22 @@ impl MinecraftInstance {
                 event_broadcaster.clone(),
                 dot_lodestone_config.uuid().clone(),
             ))),
+            command_queue: CommandQueue::new(restore_config.command_queue),
+            console_capture: ConsoleCapture::default(),
+            console_history: Arc::new(Mutex::new(ConsoleHistory::new(
+                restore_config.console_history_size,
+            ))),
+            chat_history: Arc::new(Mutex::new(ChatHistory::default())),
+            announcements_task: Arc::new(Mutex::new(None)),
+            ping_task: Arc::new(Mutex::new(None)),
+            ping_status: Arc::new(Mutex::new(PingStatus::default())),
+            idle_task: Arc::new(Mutex::new(None)),
+            graceful_stop: Arc::new(Mutex::new(None)),
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            killed: Arc::new(AtomicBool::new(false)),
+            transition_lock: TransitionLock::default(),
+            restart_attempts: Arc::new(AtomicU32::new(0)),
+            last_launch_failure: Arc::new(Mutex::new(None)),
             config: Arc::new(Mutex::new(restore_config)),
             path_to_instance,
             path_to_config,
@@ -737,12 +1037,16 @@ impl MinecraftInstance {
             event_broadcaster,
             path_to_runtimes,
             process: Arc::new(Mutex::new(None)),
-            system: Arc::new(Mutex::new(sysinfo::System::new_all())),
-            stdin: Arc::new(Mutex::new(None)),
+            output_task: Arc::new(Mutex::new(None)),
+            system: Arc::new(Mutex::new(new_process_system())),
+            last_monitor_report: Arc::new(Mutex::new(None)),
+            process_tree: Arc::new(Mutex::new(ProcessTreeTracker::new())),
             rcon_conn: Arc::new(Mutex::new(None)),
             configurable_manifest,
             macro_name_to_last_run: Arc::new(Mutex::new(HashMap::new())),
             pid_to_task_entry: Arc::new(Mutex::new(IndexMap::new())),
+            macro_run_locks: Arc::new(Mutex::new(HashMap::new())),
+            snapshot,
         };
         instance
             .read_properties()
@@ -751,6 +1055,83 @@ impl MinecraftInstance {
         Ok(instance)
     }
 
+    /// The Java the server is launched with, the configured command or the bundled runtime
+    fn java_path(&self, config: &RestoreConfig) -> PathBuf {
+        match &config.java_cmd {
+            Some(jre) => PathBuf::from(jre),
+            None => runtime_executable(&downloaded_runtime_dir(
+                &self.path_to_runtimes.join("java"),
+                config.jre_major_version as u32,
+            )),
+        }
+    }
+
+    async fn resolve_java(&self, config: &RestoreConfig) -> JavaSelection {
+        let path = self.java_path(config);
+        let source = if path.starts_with(self.path_to_runtimes.join("java")) {
+            JavaSource::Downloaded
+        } else {
+            JavaSource::Custom
+        };
+        JavaSelection {
+            runtime: probe(&path, source).await,
+            requirement: JavaRequirement::for_minecraft(&config.version, &config.flavour),
+            path,
+        }
+    }
+
+    /// Refuses to launch with a runtime that is gone or doesn't fit the game version
+    async fn validate_java(&self, config: &RestoreConfig) -> Result<(), Error> {
+        let selection = self.resolve_java(config).await;
+        let runtime = match selection.runtime {
+            Some(runtime) => runtime,
+            None => {
+                let problem = if selection.path.components().count() > 1 && !selection.path.exists()
+                {
+                    "no longer exists"
+                } else {
+                    "could not be run"
+                };
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "The Java runtime at {} {}, pin another one with PUT \
+                         /api/v1/instance/{}/java or download one with POST \
+                         /api/v1/instance/{}/java/download",
+                        selection.path.display(),
+                        problem,
+                        self.uuid,
+                        self.uuid
+                    ),
+                });
+            }
+        };
+        match selection.requirement {
+            Some(requirement) if !requirement.is_met_by(runtime.major_version) => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "{} {} needs {}, but {} is Java {}",
+                    config.flavour.to_string(),
+                    config.version,
+                    requirement,
+                    runtime.path.display(),
+                    runtime.major_version
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Points the java_cmd setting at `java`
+    async fn pin_java(&self, java: &std::path::Path) -> Result<(), Error> {
+        self.update_configurable(
+            CmdArgSetting::get_section_id(),
+            CmdArgSetting::JavaCmd(String::new()).get_identifier(),
+            ConfigurableValue::String(java.to_string_lossy().to_string()),
+        )
+        .await
+    }
+
     async fn write_config_to_file(&self) -> Result<(), Error> {
         tokio::fs::write(
             &self.path_to_config,
@@ -762,9 +1143,26 @@ impl MinecraftInstance {
             "Failed to write config to file at {}",
             &self.path_to_config.display()
         ))?;
+        self.refresh_snapshot().await;
         Ok(())
     }
 
+    /// Republishes the informational fields after the config or properties changed
+    async fn refresh_snapshot(&self) {
+        let max_player_count = self.get_max_player_count().await.ok();
+        let config = self.config.lock().await;
+        self.snapshot.update(|snapshot| {
+            snapshot.name = config.name.clone();
+            snapshot.game_type = config.flavour.clone().into();
+            snapshot.description = config.description.clone();
+            snapshot.version = config.version.clone();
+            snapshot.port = config.port;
+            snapshot.auto_start = config.auto_start;
+            snapshot.restart_on_crash = config.restart_policy().mode != RestartMode::Never;
+            snapshot.max_player_count = max_player_count;
+        });
+    }
+
     async fn read_properties(&self) -> Result<(), Error> {
         let properties = read_properties_from_path(&self.path_to_properties).await?;
         let mut lock = self.configurable_manifest.lock().await;
@@ -787,6 +1185,8 @@ impl MinecraftInstance {
                     error!("Failed to set property {} to {}: {}", key, value, e);
                 });
         }
+        drop(lock);
+        self.refresh_snapshot().await;
         Ok(())
     }
 
@@ -825,6 +1225,7 @@ impl MinecraftInstance {
                 "Failed to write properties to file at {}",
                 &self.path_to_properties.display()
             ))?;
+        self.refresh_snapshot().await;
         Ok(())
     }
 
@@ -884,6 +1285,17 @@ impl MinecraftInstance {
                 .expect("Programming error, value is not a string")
                 .to_owned(),
         );
+
+        config_lock.extra_jvm_args = configurable_map
+            .get(CmdArgSetting::ExtraJvmArgs(Default::default()).get_identifier())
+            .and_then(|setting| setting.get_value())
+            .and_then(|value| value.try_as_string().ok())
+            .map(|args| split_args(args))
+            .unwrap_or_default();
+    }
+
+    pub async fn last_launch_failure(&self) -> Option<LaunchFailure> {
+        self.last_launch_failure.lock().await.clone()
     }
 
     pub fn get_rcon(&self) -> Arc<Mutex<Option<rcon::Connection<tokio::net::TcpStream>>>> {
@@ -906,4 +1318,8 @@ impl MinecraftInstance {
     }
 }
 
-impl TInstance for MinecraftInstance {}
+impl TInstance for MinecraftInstance {
+    fn snapshot(&self) -> Arc<InstanceSnapshot> {
+        self.snapshot.load()
+    }
+}
@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::util::extended_length_path;
+
+use super::{Flavour, MinecraftInstance};
+
+const MODRINTH_API: &str = "https://api.modrinth.com/v2";
+/// Suffix lodestone appends to a jar to keep the server from loading it
+const DISABLED_SUFFIX: &str = ".disabled";
+
+/// Name, version and id read from a jar's own metadata
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct JarMetadata {
+    id: Option<String>,
+    name: Option<String>,
+    version: Option<String>,
+}
+
+/// A mod or plugin jar in the instance's mods or plugins directory
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct InstalledMod {
+    pub file_name: String,
+    pub enabled: bool,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct ModrinthSearchHit {
+    pub project_id: String,
+    pub slug: String,
+    pub title: String,
+    pub description: String,
+    pub author: String,
+    pub downloads: u64,
+    pub icon_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModrinthSearchResponse {
+    hits: Vec<ModrinthSearchHit>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ModrinthHashes {
+    sha512: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ModrinthFile {
+    url: String,
+    filename: String,
+    primary: bool,
+    size: u64,
+    hashes: ModrinthHashes,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ModrinthVersion {
+    id: String,
+    project_id: String,
+    version_number: String,
+    game_versions: Vec<String>,
+    loaders: Vec<String>,
+    files: Vec<ModrinthFile>,
+}
+
+impl ModrinthVersion {
+    /// The file Modrinth marks as primary, or the only file of the version
+    fn primary_file(&self) -> Option<&ModrinthFile> {
+        self.files
+            .iter()
+            .find(|file| file.primary)
+            .or_else(|| self.files.first())
+    }
+}
+
+/// A Modrinth file picked for installation, download it with [`MinecraftInstance::install_mod`]
+#[derive(Clone, Debug)]
+pub struct ModDownload {
+    pub project_id: String,
+    pub version_id: String,
+    pub file_name: String,
+    pub size: u64,
+    url: String,
+    sha512: String,
+}
+
+/// An installed jar Modrinth knows, with the newest version compatible with the instance
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct ModUpdate {
+    pub file_name: String,
+    pub project_id: String,
+    /// Version read from the jar, if it has one
+    pub installed_version: Option<String>,
+    pub latest_version_id: String,
+    pub latest_version: String,
+    pub update_available: bool,
+}
+
+/// Where an instance keeps its mods and which Modrinth loaders fit it
+struct ModTarget {
+    dir: PathBuf,
+    loaders: &'static [&'static str],
+    game_version: String,
+}
+
+fn modrinth_client() -> Result<reqwest::Client, Error> {
+    // Modrinth asks API clients to identify themselves
+    Ok(reqwest::Client::builder()
+        .user_agent(concat!(
+            "Lodestone-Team/lodestone_core/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .context("Failed to build http client")?)
+}
+
+fn parse_fabric_mod_json(content: &str) -> Option<JarMetadata> {
+    #[derive(Deserialize)]
+    struct FabricModJson {
+        id: String,
+        name: Option<String>,
+        version: Option<String>,
+    }
+    let parsed: FabricModJson = serde_json::from_str(content).ok()?;
+    Some(JarMetadata {
+        id: Some(parsed.id),
+        name: parsed.name,
+        version: parsed.version,
+    })
+}
+
+/// Reads the first mod of a Forge `mods.toml`, `implementation_version` stands in for the
+/// `${file.jarVersion}` placeholder
+fn parse_mods_toml(content: &str, implementation_version: Option<String>) -> Option<JarMetadata> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ModsTomlEntry {
+        mod_id: String,
+        display_name: Option<String>,
+        version: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct ModsToml {
+        mods: Vec<ModsTomlEntry>,
+    }
+    let parsed: ModsToml = toml::from_str(content).ok()?;
+    let entry = parsed.mods.into_iter().next()?;
+    let version = match entry.version {
+        Some(version) if version.starts_with("${") => implementation_version,
+        version => version,
+    };
+    Some(JarMetadata {
+        id: Some(entry.mod_id),
+        name: entry.display_name,
+        version,
+    })
+}
+
+/// Reads the top level `name` and `version` of a Bukkit style `plugin.yml`
+fn parse_plugin_yml(content: &str) -> Option<JarMetadata> {
+    let mut metadata = JarMetadata::default();
+    for line in content.lines() {
+        // nested keys are indented, only top level ones matter
+        if line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let value = value
+            .trim()
+            .trim_matches(|c| c == '"' || c == '\'')
+            .to_string();
+        if value.is_empty() {
+            continue;
+        }
+        match key.trim() {
+            "name" => metadata.name = Some(value),
+            "version" => metadata.version = Some(value),
+            _ => {}
+        }
+    }
+    metadata.name.as_ref()?;
+    metadata.id = metadata.name.clone();
+    Some(metadata)
+}
+
+/// Reads the `Implementation-Version` of a jar manifest
+fn manifest_version(content: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        line.strip_prefix("Implementation-Version:")
+            .map(|version| version.trim().to_string())
+    })
+}
+
+fn read_jar_metadata(path: &Path) -> Option<JarMetadata> {
+    let file = std::fs::File::open(extended_length_path(path)).ok()?;
+    let mut archive = zip::ZipArchive::new(file).ok()?;
+    let mut read_entry = |name: &str| -> Option<String> {
+        let mut content = String::new();
+        archive
+            .by_name(name)
+            .ok()?
+            .read_to_string(&mut content)
+            .ok()?;
+        Some(content)
+    };
+    if let Some(metadata) = read_entry("fabric.mod.json").and_then(|c| parse_fabric_mod_json(&c)) {
+        return Some(metadata);
+    }
+    for mods_toml in ["META-INF/mods.toml", "META-INF/neoforge.mods.toml"] {
+        if let Some(content) = read_entry(mods_toml) {
+            let implementation_version =
+                read_entry("META-INF/MANIFEST.MF").and_then(|c| manifest_version(&c));
+            if let Some(metadata) = parse_mods_toml(&content, implementation_version) {
+                return Some(metadata);
+            }
+        }
+    }
+    ["paper-plugin.yml", "plugin.yml"]
+        .into_iter()
+        .find_map(|name| read_entry(name).and_then(|c| parse_plugin_yml(&c)))
+}
+
+/// Whether `file_name` is a bare jar name, enabled or not, so it can't leave the mods directory
+fn check_mod_file_name(file_name: &str) -> Result<(), Error> {
+    let is_bare = Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        == Some(file_name)
+        && !file_name.starts_with('.');
+    let is_jar = file_name.ends_with(".jar") || file_name.ends_with(".jar.disabled");
+    if !is_bare || !is_jar {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is not a mod file name", file_name),
+        });
+    }
+    Ok(())
+}
+
+fn list_jars(dir: &Path) -> Result<Vec<InstalledMod>, Error> {
+    let entries = match std::fs::read_dir(extended_length_path(dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!("Failed to read {}: {}", dir.display(), e),
+            })
+        }
+    };
+    let mut mods: Vec<InstalledMod> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map_or(false, |t| t.is_file()))
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_str()?.to_string();
+            let enabled = file_name.ends_with(".jar");
+            if !enabled && !file_name.ends_with(".jar.disabled") {
+                return None;
+            }
+            let metadata = read_jar_metadata(&entry.path()).unwrap_or_default();
+            Some(InstalledMod {
+                size: entry.metadata().map_or(0, |m| m.len()),
+                file_name,
+                enabled,
+                id: metadata.id,
+                name: metadata.name,
+                version: metadata.version,
+            })
+        })
+        .collect();
+    mods.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(mods)
+}
+
+fn sha512_file(path: &Path) -> Result<String, Error> {
+    let mut file = std::fs::File::open(extended_length_path(path))
+        .context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha512::new();
+    std::io::copy(&mut file, &mut hasher).context(format!("Failed to read {}", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+impl MinecraftInstance {
+    async fn mod_target(&self) -> Result<ModTarget, Error> {
+        let config = self.config.lock().await;
+        let (dir, loaders): (&str, &'static [&'static str]) = match config.flavour {
+            Flavour::Fabric { .. } => ("mods", &["fabric"]),
+            // Quilt loads Fabric mods too
+            Flavour::Quilt { .. } => ("mods", &["quilt", "fabric"]),
+            Flavour::Forge { .. } => ("mods", &["forge"]),
+            Flavour::Paper { .. } => ("plugins", &["paper", "spigot", "bukkit"]),
+            Flavour::Spigot => ("plugins", &["spigot", "bukkit"]),
+            Flavour::Vanilla => {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!("Vanilla servers can't load mods or plugins"),
+                })
+            }
+        };
+        Ok(ModTarget {
+            dir: self.path_to_instance.join(dir),
+            loaders,
+            game_version: config.version.clone(),
+        })
+    }
+
+    /// Directory the instance loads mods or plugins from
+    pub async fn mods_dir(&self) -> Result<PathBuf, Error> {
+        Ok(self.mod_target().await?.dir)
+    }
+
+    /// Jars in the mods or plugins directory, disabled ones included
+    pub async fn list_mods(&self) -> Result<Vec<InstalledMod>, Error> {
+        let dir = self.mod_target().await?.dir;
+        tokio::task::spawn_blocking(move || list_jars(&dir))
+            .await
+            .context("Failed to spawn blocking task")?
+    }
+
+    /// Renames a jar to or from `.disabled`, returning its new file name
+    pub async fn set_mod_enabled(&self, file_name: &str, enabled: bool) -> Result<String, Error> {
+        check_mod_file_name(file_name)?;
+        let dir = self.mod_target().await?.dir;
+        let base_name = file_name.trim_end_matches(DISABLED_SUFFIX);
+        let new_name = if enabled {
+            base_name.to_string()
+        } else {
+            format!("{}{}", base_name, DISABLED_SUFFIX)
+        };
+        if new_name == file_name {
+            return Ok(new_name);
+        }
+        let from = dir.join(file_name);
+        if !from.is_file() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} is not installed", file_name),
+            });
+        }
+        let to = dir.join(&new_name);
+        if to.exists() {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("{} already exists", new_name),
+            });
+        }
+        crate::util::fs::rename(&from, &to).await?;
+        Ok(new_name)
+    }
+
+    /// Deletes a jar from the mods or plugins directory, returning its path
+    pub async fn remove_mod(&self, file_name: &str) -> Result<PathBuf, Error> {
+        check_mod_file_name(file_name)?;
+        let path = self.mod_target().await?.dir.join(file_name);
+        if !path.is_file() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} is not installed", file_name),
+            });
+        }
+        crate::util::fs::remove_file(&path).await?;
+        Ok(path)
+    }
+
+    /// Searches Modrinth for projects that run on the instance's loader and game version
+    pub async fn search_mods(
+        &self,
+        query: &str,
+        limit: u32,
+    ) -> Result<Vec<ModrinthSearchHit>, Error> {
+        let target = self.mod_target().await?;
+        let loaders: Vec<String> = target
+            .loaders
+            .iter()
+            .map(|loader| format!("categories:{}", loader))
+            .collect();
+        let facets = serde_json::json!([
+            loaders,
+            [format!("versions:{}", target.game_version)],
+            ["project_type:mod"]
+        ]);
+        let response: ModrinthSearchResponse = modrinth_client()?
+            .get(format!("{}/search", MODRINTH_API))
+            .query(&[
+                ("query", query.to_string()),
+                ("limit", limit.to_string()),
+                ("facets", facets.to_string()),
+            ])
+            .send()
+            .await
+            .context("Failed to search Modrinth")?
+            .error_for_status()
+            .context("Failed to search Modrinth")?
+            .json()
+            .await
+            .context("Modrinth returned an invalid search response")?;
+        Ok(response.hits)
+    }
+
+    /// Picks the file to install from a Modrinth project, the newest compatible version unless
+    /// `version_id` names one
+    pub async fn resolve_mod(
+        &self,
+        project_id: &str,
+        version_id: Option<&str>,
+    ) -> Result<ModDownload, Error> {
+        let target = self.mod_target().await?;
+        let client = modrinth_client()?;
+        let version: ModrinthVersion = match version_id {
+            Some(version_id) => {
+                let version: ModrinthVersion = client
+                    .get(format!("{}/version/{}", MODRINTH_API, version_id))
+                    .send()
+                    .await
+                    .context("Failed to fetch the Modrinth version")?
+                    .error_for_status()
+                    .map_err(|e| Error {
+                        kind: ErrorKind::NotFound,
+                        source: eyre!("Modrinth version {} not found: {}", version_id, e),
+                    })?
+                    .json()
+                    .await
+                    .context("Modrinth returned an invalid version")?;
+                let compatible = version.game_versions.contains(&target.game_version)
+                    && version
+                        .loaders
+                        .iter()
+                        .any(|loader| target.loaders.contains(&loader.as_str()));
+                if version.project_id != project_id || !compatible {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!(
+                            "Version {} of {} does not support {} {}",
+                            version_id,
+                            project_id,
+                            target.loaders[0],
+                            target.game_version
+                        ),
+                    });
+                }
+                version
+            }
+            None => {
+                let versions: Vec<ModrinthVersion> = client
+                    .get(format!("{}/project/{}/version", MODRINTH_API, project_id))
+                    .query(&[
+                        ("loaders", serde_json::to_string(target.loaders).unwrap()),
+                        (
+                            "game_versions",
+                            serde_json::to_string(&[&target.game_version]).unwrap(),
+                        ),
+                    ])
+                    .send()
+                    .await
+                    .context("Failed to fetch Modrinth versions")?
+                    .error_for_status()
+                    .map_err(|e| Error {
+                        kind: ErrorKind::NotFound,
+                        source: eyre!("Modrinth project {} not found: {}", project_id, e),
+                    })?
+                    .json()
+                    .await
+                    .context("Modrinth returned invalid versions")?;
+                // Modrinth lists the newest version first
+                versions.into_iter().next().ok_or_else(|| Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!(
+                        "{} has no version for {} {}",
+                        project_id,
+                        target.loaders[0],
+                        target.game_version
+                    ),
+                })?
+            }
+        };
+        let file = version.primary_file().ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Modrinth version {} has no files", version.id),
+        })?;
+        let file_name = sanitize_filename::sanitize(&file.filename);
+        check_mod_file_name(&file_name)?;
+        Ok(ModDownload {
+            project_id: version.project_id.clone(),
+            version_id: version.id.clone(),
+            file_name,
+            size: file.size,
+            url: file.url.clone(),
+            sha512: file.hashes.sha512.to_lowercase(),
+        })
+    }
+
+    /// Downloads a resolved file into the mods or plugins directory, it is only written once
+    /// its sha512 matches what Modrinth published
+    pub async fn install_mod(&self, download: &ModDownload) -> Result<InstalledMod, Error> {
+        let dir = self.mod_target().await?.dir;
+        let path = dir.join(&download.file_name);
+        let disabled_path = dir.join(format!("{}{}", download.file_name, DISABLED_SUFFIX));
+        if path.exists() || disabled_path.exists() {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("{} is already installed", download.file_name),
+            });
+        }
+        let bytes = modrinth_client()?
+            .get(&download.url)
+            .send()
+            .await
+            .context(format!("Failed to download {}", download.file_name))?
+            .error_for_status()
+            .context(format!("Failed to download {}", download.file_name))?
+            .bytes()
+            .await
+            .context(format!("Failed to download {}", download.file_name))?;
+        let sha512 = hex::encode(Sha512::digest(&bytes));
+        if sha512 != download.sha512 {
+            return Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!(
+                    "{} does not match the sha512 Modrinth published, not installing it",
+                    download.file_name
+                ),
+            });
+        }
+        crate::util::fs::create_dir_all(&dir).await?;
+        // written under another name first so the server never sees half a jar
+        let partial_path = dir.join(format!(".{}.partial", download.file_name));
+        crate::util::fs::write_all(&partial_path, &bytes).await?;
+        if let Err(e) = crate::util::fs::rename(&partial_path, &path).await {
+            crate::util::fs::remove_file(&partial_path).await.ok();
+            return Err(e);
+        }
+        let metadata = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || read_jar_metadata(&path)
+        })
+        .await
+        .context("Failed to spawn blocking task")?
+        .unwrap_or_default();
+        Ok(InstalledMod {
+            file_name: download.file_name.clone(),
+            enabled: true,
+            id: metadata.id,
+            name: metadata.name,
+            version: metadata.version,
+            size: bytes.len() as u64,
+        })
+    }
+
+    /// Looks up every installed jar on Modrinth by its sha512 and compares it with the newest
+    /// version compatible with the instance. Jars Modrinth doesn't know are left out
+    pub async fn check_mod_updates(&self) -> Result<Vec<ModUpdate>, Error> {
+        let target = self.mod_target().await?;
+        let hashed = tokio::task::spawn_blocking({
+            let dir = target.dir.clone();
+            move || -> Result<Vec<(InstalledMod, String)>, Error> {
+                list_jars(&dir)?
+                    .into_iter()
+                    .map(|installed| {
+                        let sha512 = sha512_file(&dir.join(&installed.file_name))?;
+                        Ok((installed, sha512))
+                    })
+                    .collect()
+            }
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        if hashed.is_empty() {
+            return Ok(Vec::new());
+        }
+        let latest: HashMap<String, ModrinthVersion> = modrinth_client()?
+            .post(format!("{}/version_files/update", MODRINTH_API))
+            .json(&serde_json::json!({
+                "hashes": hashed.iter().map(|(_, sha512)| sha512).collect::<Vec<_>>(),
+                "algorithm": "sha512",
+                "loaders": target.loaders,
+                "game_versions": [target.game_version],
+            }))
+            .send()
+            .await
+            .context("Failed to check Modrinth for updates")?
+            .error_for_status()
+            .context("Failed to check Modrinth for updates")?
+            .json()
+            .await
+            .context("Modrinth returned an invalid update response")?;
+        Ok(hashed
+            .into_iter()
+            .filter_map(|(installed, sha512)| {
+                let version = latest.get(&sha512)?;
+                Some(ModUpdate {
+                    update_available: !version
+                        .files
+                        .iter()
+                        .any(|file| file.hashes.sha512.eq_ignore_ascii_case(&sha512)),
+                    file_name: installed.file_name,
+                    project_id: version.project_id.clone(),
+                    installed_version: installed.version,
+                    latest_version_id: version.id.clone(),
+                    latest_version: version.version_number.clone(),
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        assert_eq!(
+            parse_fabric_mod_json(r#"{"schemaVersion": 1, "id": "lithium", "version": "0.11.2"}"#),
+            Some(JarMetadata {
+                id: Some("lithium".to_string()),
+                name: None,
+                version: Some("0.11.2".to_string()),
+            })
+        );
+        let mods_toml = r#"
+modLoader="javafml"
+loaderVersion="[47,)"
+[[mods]]
+modId="jei"
+version="${file.jarVersion}"
+displayName="Just Enough Items"
+[[dependencies.jei]]
+modId="forge"
+"#;
+        assert_eq!(
+            parse_mods_toml(mods_toml, Some("15.2.0".to_string())),
+            Some(JarMetadata {
+                id: Some("jei".to_string()),
+                name: Some("Just Enough Items".to_string()),
+                version: Some("15.2.0".to_string()),
+            })
+        );
+        let plugin_yml = "name: EssentialsX\nversion: '2.20.1'\nmain: com.earth2me.essentials.Essentials\ncommands:\n  version: {}\n";
+        assert_eq!(
+            parse_plugin_yml(plugin_yml),
+            Some(JarMetadata {
+                id: Some("EssentialsX".to_string()),
+                name: Some("EssentialsX".to_string()),
+                version: Some("2.20.1".to_string()),
+            })
+        );
+        assert_eq!(parse_plugin_yml("main: a.b.C\n"), None);
+    }
+
+    #[test]
+    fn test_check_mod_file_name() {
+        assert!(check_mod_file_name("sodium-0.5.3.jar").is_ok());
+        assert!(check_mod_file_name("sodium-0.5.3.jar.disabled").is_ok());
+        assert!(check_mod_file_name("../server.jar").is_err());
+        assert!(check_mod_file_name("config/sodium.json").is_err());
+        assert!(check_mod_file_name(".sodium.jar.partial").is_err());
+    }
+}
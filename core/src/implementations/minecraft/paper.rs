@@ -1,8 +1,13 @@
 use color_eyre::eyre::{eyre, Context, ContextCompat};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use ts_rs::TS;
 
 use crate::error::Error;
 
+/// Builds of the other channels are experimental, updates only offer stable ones
+pub const PAPER_STABLE_CHANNEL: &str = "default";
+
 pub async fn get_paper_minecraft_versions() -> Result<Vec<String>, Error> {
     let http = reqwest::Client::new();
 
@@ -39,10 +44,157 @@ pub async fn get_paper_minecraft_versions() -> Result<Vec<String>, Error> {
     Ok(versions)
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct PaperChange {
+    pub commit: String,
+    pub summary: String,
+}
+
+#[derive(Deserialize)]
+struct PaperDownload {
+    name: String,
+    sha256: String,
+}
+
+#[derive(Deserialize)]
+struct PaperDownloads {
+    application: PaperDownload,
+}
+
+#[derive(Deserialize)]
+struct PaperBuildResponse {
+    build: i64,
+    time: String,
+    channel: String,
+    #[serde(default)]
+    changes: Vec<PaperChange>,
+    downloads: PaperDownloads,
+}
+
+#[derive(Deserialize)]
+struct PaperBuildsResponse {
+    builds: Vec<PaperBuildResponse>,
+}
+
+/// One build of Paper for a Minecraft version, as the PaperMC API describes it
+#[derive(Debug, Clone, PartialEq, Serialize, TS)]
+#[ts(export)]
+pub struct PaperBuild {
+    pub build: i64,
+    /// RFC 3339 timestamp of when the build was published
+    pub time: String,
+    pub channel: String,
+    /// What changed since the previous build
+    pub changes: Vec<PaperChange>,
+    pub file_name: String,
+    pub sha256: String,
+}
+
+impl From<PaperBuildResponse> for PaperBuild {
+    fn from(build: PaperBuildResponse) -> Self {
+        PaperBuild {
+            build: build.build,
+            time: build.time,
+            channel: build.channel,
+            changes: build.changes,
+            file_name: build.downloads.application.name,
+            sha256: build.downloads.application.sha256,
+        }
+    }
+}
+
+impl PaperBuild {
+    pub fn download_url(&self, version: &str) -> String {
+        format!(
+            "https://api.papermc.io/v2/projects/paper/versions/{}/builds/{}/downloads/{}",
+            version, self.build, self.file_name
+        )
+    }
+}
+
+/// Every build of Paper for `version`, oldest first
+pub async fn get_paper_builds(version: &str) -> Result<Vec<PaperBuild>, Error> {
+    let response: PaperBuildsResponse = reqwest::Client::new()
+        .get(format!(
+            "https://api.papermc.io/v2/projects/paper/versions/{}/builds",
+            version
+        ))
+        .send()
+        .await
+        .context("Failed to get paper builds")?
+        .error_for_status()
+        .context(format!("Failed to get paper builds for {}", version))?
+        .json()
+        .await
+        .context("Failed to get paper builds, response is not valid json")?;
+    Ok(response.builds.into_iter().map(PaperBuild::from).collect())
+}
+
+/// The stable builds newer than `installed`, newest first.
+///
+/// Every stable build counts as newer when the installed one isn't known
+pub fn newer_builds(builds: Vec<PaperBuild>, installed: Option<i64>) -> Vec<PaperBuild> {
+    let mut newer: Vec<_> = builds
+        .into_iter()
+        .filter(|build| build.channel == PAPER_STABLE_CHANNEL)
+        .filter(|build| installed.map_or(true, |installed| build.build > installed))
+        .collect();
+    newer.sort_by(|a, b| b.build.cmp(&a.build));
+    newer
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_newer_builds() {
+        let response: PaperBuildsResponse = serde_json::from_value(serde_json::json!({
+            "builds": [
+                {
+                    "build": 300,
+                    "time": "2023-01-01T00:00:00.000Z",
+                    "channel": "default",
+                    "promoted": false,
+                    "changes": [],
+                    "downloads": { "application": { "name": "paper-1.19.3-300.jar", "sha256": "aa" } }
+                },
+                {
+                    "build": 307,
+                    "time": "2023-01-05T00:00:00.000Z",
+                    "channel": "experimental",
+                    "promoted": false,
+                    "changes": [],
+                    "downloads": { "application": { "name": "paper-1.19.3-307.jar", "sha256": "bb" } }
+                },
+                {
+                    "build": 308,
+                    "time": "2023-01-06T00:00:00.000Z",
+                    "channel": "default",
+                    "promoted": false,
+                    "changes": [{ "commit": "abc", "summary": "Fix chunk loading", "message": "" }],
+                    "downloads": { "application": { "name": "paper-1.19.3-308.jar", "sha256": "cc" } }
+                }
+            ]
+        }))
+        .unwrap();
+        let builds: Vec<PaperBuild> = response.builds.into_iter().map(PaperBuild::from).collect();
+
+        let newer = newer_builds(builds.clone(), Some(300));
+        assert_eq!(
+            newer.iter().map(|build| build.build).collect::<Vec<_>>(),
+            vec![308]
+        );
+        assert_eq!(newer[0].changes[0].summary, "Fix chunk loading");
+        assert_eq!(
+            newer[0].download_url("1.19.3"),
+            "https://api.papermc.io/v2/projects/paper/versions/1.19.3/builds/308/downloads/paper-1.19.3-308.jar"
+        );
+        assert!(newer_builds(builds.clone(), Some(308)).is_empty());
+        assert_eq!(newer_builds(builds, None).len(), 2);
+    }
+
     #[tokio::test]
     async fn test_get_paper_minecraft_versions() {
         let versions = get_paper_minecraft_versions().await.unwrap();
@@ -1,13 +1,24 @@
 use async_trait::async_trait;
 
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
+use crate::error::ErrorKind;
+use crate::events::CausedBy;
 use crate::traits::t_player::Player;
-use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::traits::t_player::{
+    OnlinePlayer, PlayerListChange, PlayerListChangeTarget, PlayerListEntry, PlayerListKind,
+    PlayerSession, TPlayer, TPlayerManagement,
+};
+use crate::traits::t_server::{State, TServer};
 use crate::Error;
 
 use super::configurable::ServerPropertySetting;
+use super::player_lists::{
+    add_command, dedup, file_name, read_list, remove_command, resolve_profile, validate_username,
+    write_list, ListFileEntry, DEFAULT_OP_LEVEL,
+};
 use super::MinecraftInstance;
 
 #[derive(Eq, Debug, Clone, Serialize, Deserialize, TS)]
@@ -69,4 +80,138 @@ impl TPlayerManagement for MinecraftInstance {
     async fn get_player_list(&self) -> Result<HashSet<Player>, Error> {
         Ok(self.players_manager.lock().await.clone().into())
     }
+
+    async fn get_online_players(&self) -> Result<Vec<OnlinePlayer>, Error> {
+        Ok(self.players_manager.lock().await.online_players())
+    }
+
+    async fn get_player_sessions(&self, since: Option<i64>) -> Result<Vec<PlayerSession>, Error> {
+        Ok(self.players_manager.lock().await.sessions_since(since))
+    }
+
+    async fn get_player_list_entries(
+        &self,
+        list: PlayerListKind,
+    ) -> Result<Vec<PlayerListEntry>, Error> {
+        let entries = read_list(&self.path_to_instance.join(file_name(list))).await?;
+        Ok(dedup(entries).iter().map(ListFileEntry::to_entry).collect())
+    }
+
+    async fn add_to_player_list(
+        &self,
+        list: PlayerListKind,
+        name: &str,
+        caused_by: CausedBy,
+    ) -> Result<PlayerListChange, Error> {
+        validate_username(name)?;
+        let applied_to = self.player_list_change_target().await?;
+        let path = self.path_to_instance.join(file_name(list));
+        let mut entries = read_list(&path).await?;
+        let unchanged = |entry: &ListFileEntry| PlayerListChange {
+            applied_to,
+            changed: false,
+            entry: Some(entry.to_entry()),
+        };
+        if let Some(existing) = entries.iter().find(|entry| entry.matches(name)) {
+            return Ok(unchanged(existing));
+        }
+        let profile = resolve_profile(name, self.online_mode().await).await?;
+        // the account may have been renamed since it was added
+        if let Some(existing) = entries
+            .iter()
+            .find(|entry| entry.uuid.eq_ignore_ascii_case(&profile.uuid))
+        {
+            return Ok(unchanged(existing));
+        }
+        let entry = ListFileEntry::new(list, profile, self.op_permission_level().await);
+        let change = PlayerListChange {
+            applied_to,
+            changed: true,
+            entry: Some(entry.to_entry()),
+        };
+        match applied_to {
+            PlayerListChangeTarget::Live => {
+                self.send_command(&add_command(list, &entry.name), caused_by)
+                    .await?
+            }
+            PlayerListChangeTarget::File => {
+                entries.push(entry);
+                write_list(&path, entries).await?;
+            }
+        }
+        Ok(change)
+    }
+
+    async fn remove_from_player_list(
+        &self,
+        list: PlayerListKind,
+        name: &str,
+        caused_by: CausedBy,
+    ) -> Result<PlayerListChange, Error> {
+        validate_username(name)?;
+        let applied_to = self.player_list_change_target().await?;
+        let path = self.path_to_instance.join(file_name(list));
+        let mut entries = read_list(&path).await?;
+        let removed = match entries.iter().find(|entry| entry.matches(name)) {
+            Some(entry) => entry.to_entry(),
+            None => {
+                return Ok(PlayerListChange {
+                    applied_to,
+                    changed: false,
+                    entry: None,
+                })
+            }
+        };
+        match applied_to {
+            PlayerListChangeTarget::Live => {
+                self.send_command(&remove_command(list, &removed.name), caused_by)
+                    .await?
+            }
+            PlayerListChangeTarget::File => {
+                entries.retain(|entry| !entry.matches(name));
+                write_list(&path, entries).await?;
+            }
+        }
+        Ok(PlayerListChange {
+            applied_to,
+            changed: true,
+            entry: Some(removed),
+        })
+    }
+}
+
+impl MinecraftInstance {
+    /// A running server overwrites its list files, so changes go through the console then
+    async fn player_list_change_target(&self) -> Result<PlayerListChangeTarget, Error> {
+        match *self.state.lock().await {
+            State::Running => Ok(PlayerListChangeTarget::Live),
+            State::Stopped => Ok(PlayerListChangeTarget::File),
+            _ => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "The server is starting or stopping, try again once it is running or stopped"
+                ),
+            }),
+        }
+    }
+
+    async fn online_mode(&self) -> bool {
+        self.configurable_manifest
+            .lock()
+            .await
+            .get_unique_setting_key(&ServerPropertySetting::OnlineMode(true).get_identifier())
+            .and_then(|v| v.get_value().map(|v| v.try_as_boolean()))
+            .and_then(Result::ok)
+            .unwrap_or(true)
+    }
+
+    async fn op_permission_level(&self) -> u32 {
+        self.configurable_manifest
+            .lock()
+            .await
+            .get_unique_setting_key(&ServerPropertySetting::OpPermissionLevel(0).get_identifier())
+            .and_then(|v| v.get_value().map(|v| v.try_as_unsigned_integer()))
+            .and_then(Result::ok)
+            .unwrap_or(DEFAULT_OP_LEVEL)
+    }
 }
@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Context};
+use md5::{Digest, Md5};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_player::{PlayerListEntry, PlayerListKind};
+
+/// How long a username resolved through the Mojang API is trusted
+const PROFILE_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const PROFILE_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Used for new operators when server.properties doesn't say otherwise
+pub(super) const DEFAULT_OP_LEVEL: u32 = 4;
+
+/// Lowercased username to the canonical name and UUID, shared by every instance
+static PROFILE_CACHE: Lazy<std::sync::Mutex<HashMap<String, (Profile, Instant)>>> =
+    Lazy::new(Default::default);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Profile {
+    pub name: String,
+    pub uuid: String,
+}
+
+/// An entry of whitelist.json or ops.json, fields lodestone doesn't manage are kept as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct ListFileEntry {
+    pub uuid: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ListFileEntry {
+    pub fn new(list: PlayerListKind, profile: Profile, op_level: u32) -> Self {
+        let mut extra = serde_json::Map::new();
+        if list == PlayerListKind::Ops {
+            extra.insert("level".to_string(), op_level.into());
+            extra.insert("bypassesPlayerLimit".to_string(), false.into());
+        }
+        Self {
+            uuid: profile.uuid,
+            name: profile.name,
+            extra,
+        }
+    }
+
+    pub fn to_entry(&self) -> PlayerListEntry {
+        PlayerListEntry {
+            name: self.name.clone(),
+            uuid: self.uuid.clone(),
+            level: self
+                .extra
+                .get("level")
+                .and_then(|level| level.as_u64())
+                .map(|level| level as u32),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+pub(super) fn file_name(list: PlayerListKind) -> &'static str {
+    match list {
+        PlayerListKind::Whitelist => "whitelist.json",
+        PlayerListKind::Ops => "ops.json",
+    }
+}
+
+pub(super) fn add_command(list: PlayerListKind, name: &str) -> String {
+    match list {
+        PlayerListKind::Whitelist => format!("whitelist add {}", name),
+        PlayerListKind::Ops => format!("op {}", name),
+    }
+}
+
+pub(super) fn remove_command(list: PlayerListKind, name: &str) -> String {
+    match list {
+        PlayerListKind::Whitelist => format!("whitelist remove {}", name),
+        PlayerListKind::Ops => format!("deop {}", name),
+    }
+}
+
+/// Names end up in console commands, so anything Minecraft wouldn't accept is refused
+pub(super) fn validate_username(name: &str) -> Result<(), Error> {
+    if (1..=16).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is not a valid Minecraft username", name),
+        })
+    }
+}
+
+/// The UUID an offline mode server gives a player, derived from the name alone
+pub(super) fn offline_uuid(name: &str) -> String {
+    let mut hash = Md5::digest(format!("OfflinePlayer:{}", name));
+    // version 3, RFC 4122 variant
+    hash[6] = (hash[6] & 0x0f) | 0x30;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    hyphenate(&hex::encode(hash))
+}
+
+fn hyphenate(id: &str) -> String {
+    format!(
+        "{}-{}-{}-{}-{}",
+        &id[0..8],
+        &id[8..12],
+        &id[12..16],
+        &id[16..20],
+        &id[20..32]
+    )
+}
+
+/// Resolves a username the way the server would, through Mojang in online mode
+pub(super) async fn resolve_profile(name: &str, online_mode: bool) -> Result<Profile, Error> {
+    validate_username(name)?;
+    if !online_mode {
+        return Ok(Profile {
+            name: name.to_string(),
+            uuid: offline_uuid(name),
+        });
+    }
+    let key = name.to_ascii_lowercase();
+    if let Some((profile, resolved_at)) = PROFILE_CACHE.lock().unwrap().get(&key) {
+        if resolved_at.elapsed() < PROFILE_CACHE_TTL {
+            return Ok(profile.clone());
+        }
+    }
+    let response = reqwest::Client::new()
+        .get(format!(
+            "https://api.mojang.com/users/profiles/minecraft/{}",
+            name
+        ))
+        .timeout(PROFILE_LOOKUP_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| Error {
+            kind: ErrorKind::External,
+            source: eyre!("Failed to reach the Mojang profile API: {}", e),
+        })?;
+    if response.status() == reqwest::StatusCode::NO_CONTENT
+        || response.status() == reqwest::StatusCode::NOT_FOUND
+    {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No Minecraft account is named {}", name),
+        });
+    }
+    let response = response.error_for_status().map_err(|e| Error {
+        kind: ErrorKind::External,
+        source: eyre!("The Mojang profile API failed: {}", e),
+    })?;
+    let body: serde_json::Value = response.json().await.map_err(|e| Error {
+        kind: ErrorKind::External,
+        source: eyre!("Invalid response from the Mojang profile API: {}", e),
+    })?;
+    let profile = match (body["id"].as_str(), body["name"].as_str()) {
+        (Some(id), Some(canonical_name)) if id.len() == 32 => Profile {
+            name: canonical_name.to_string(),
+            uuid: hyphenate(id),
+        },
+        _ => {
+            return Err(Error {
+                kind: ErrorKind::External,
+                source: eyre!("Invalid response from the Mojang profile API"),
+            })
+        }
+    };
+    PROFILE_CACHE
+        .lock()
+        .unwrap()
+        .insert(key, (profile.clone(), Instant::now()));
+    Ok(profile)
+}
+
+/// Reads a list file, a missing one is an empty list
+pub(super) async fn read_list(path: &Path) -> Result<Vec<ListFileEntry>, Error> {
+    match tokio::fs::read(path).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)
+            .context(format!("Failed to parse {}", path.display()))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e)
+            .context(format!("Failed to read {}", path.display()))
+            .map_err(Error::from),
+    }
+}
+
+/// Writes a list file, keeping only the first entry of each player
+pub(super) async fn write_list(path: &Path, entries: Vec<ListFileEntry>) -> Result<(), Error> {
+    let entries = dedup(entries);
+    tokio::fs::write(
+        path,
+        serde_json::to_string_pretty(&entries).context("Failed to serialize player list")?,
+    )
+    .await
+    .context(format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+pub(super) fn dedup(entries: Vec<ListFileEntry>) -> Vec<ListFileEntry> {
+    let mut seen = HashSet::new();
+    entries
+        .into_iter()
+        .filter(|entry| seen.insert(entry.uuid.to_ascii_lowercase()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_uuid() {
+        assert_eq!(
+            offline_uuid("Notch"),
+            "b50ad385-829d-3141-a216-7e7d7539ba7f"
+        );
+    }
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("Notch").is_ok());
+        assert!(validate_username("a_b_1").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("seventeen_chars__").is_err());
+        assert!(validate_username("Notch\nstop").is_err());
+        assert!(validate_username("Notch stop").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_file_round_trip_dedups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(file_name(PlayerListKind::Ops));
+        assert!(read_list(&path).await.unwrap().is_empty());
+
+        let notch = Profile {
+            name: "Notch".to_string(),
+            uuid: offline_uuid("Notch"),
+        };
+        let mut entries = vec![
+            ListFileEntry::new(PlayerListKind::Ops, notch.clone(), 3),
+            ListFileEntry::new(PlayerListKind::Ops, notch, 4),
+        ];
+        entries[0]
+            .extra
+            .insert("customField".to_string(), "kept".into());
+        write_list(&path, entries).await.unwrap();
+
+        let entries = read_list(&path).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].to_entry().level, Some(3));
+        assert_eq!(entries[0].extra["customField"], "kept");
+        assert!(entries[0].matches("notch"));
+    }
+}
@@ -1,17 +1,22 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 
 use crate::{
     event_broadcaster::EventBroadcaster,
     events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner},
-    traits::t_player::Player,
+    traits::t_player::{OnlinePlayer, Player, PlayerSession},
     types::{InstanceUuid, Snowflake},
 };
 
 use super::player::MinecraftPlayer;
 
+/// Sessions kept in memory, the oldest closed ones are dropped first
+const MAX_SESSION_HISTORY: usize = 1000;
+
 #[derive(Clone)]
 pub struct PlayersManager {
     players: HashSet<MinecraftPlayer>,
+    /// Oldest first, sessions of online players have no left_at
+    sessions: VecDeque<PlayerSession>,
     event_broadcaster: EventBroadcaster,
     instance_uuid: InstanceUuid,
 }
@@ -20,13 +25,16 @@ impl PlayersManager {
     pub fn new(event_broadcaster: EventBroadcaster, instance_uuid: InstanceUuid) -> Self {
         Self {
             players: HashSet::new(),
+            sessions: VecDeque::new(),
             event_broadcaster,
             instance_uuid,
         }
     }
 
     pub fn add_player(&mut self, player: MinecraftPlayer, instance_name: String) {
-        self.players.insert(player.clone());
+        if self.players.insert(player.clone()) {
+            self.open_session(player.clone());
+        }
         self.event_broadcaster.send(Event {
             event_inner: EventInner::InstanceEvent(InstanceEvent {
                 instance_uuid: self.instance_uuid.clone(),
@@ -47,6 +55,7 @@ impl PlayersManager {
 
     pub fn remove_player(&mut self, player: MinecraftPlayer, instance_name: String) {
         if self.players.remove(&player) {
+            self.close_session(&player, chrono::Utc::now().timestamp());
             self.event_broadcaster.send(Event {
                 event_inner: EventInner::InstanceEvent(InstanceEvent {
                     instance_uuid: self.instance_uuid.clone(),
@@ -81,7 +90,12 @@ impl PlayersManager {
         self.players.len() as u32
     }
 
+    /// Called when the server stops, every open session ends now
     pub fn clear(&mut self, instance_name: String) {
+        let now = chrono::Utc::now().timestamp();
+        for session in self.sessions.iter_mut() {
+            session.left_at.get_or_insert(now);
+        }
         self.event_broadcaster.send(Event {
             event_inner: EventInner::InstanceEvent(InstanceEvent {
                 instance_uuid: self.instance_uuid.clone(),
@@ -100,6 +114,59 @@ impl PlayersManager {
         });
         self.players.clear();
     }
+
+    pub fn online_players(&self) -> Vec<OnlinePlayer> {
+        self.sessions
+            .iter()
+            .filter(|session| session.left_at.is_none())
+            .map(|session| OnlinePlayer {
+                player: session.player.clone(),
+                joined_at: Some(session.joined_at),
+            })
+            .collect()
+    }
+
+    /// Sessions that were still going at or after `since`, most recent first
+    pub fn sessions_since(&self, since: Option<i64>) -> Vec<PlayerSession> {
+        self.sessions
+            .iter()
+            .rev()
+            .filter(|session| match (since, session.left_at) {
+                (Some(since), Some(left_at)) => left_at >= since,
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn open_session(&mut self, player: MinecraftPlayer) {
+        self.sessions.push_back(PlayerSession {
+            player: player.into(),
+            joined_at: chrono::Utc::now().timestamp(),
+            left_at: None,
+        });
+        if self.sessions.len() > MAX_SESSION_HISTORY {
+            if let Some(oldest_closed) = self
+                .sessions
+                .iter()
+                .position(|session| session.left_at.is_some())
+            {
+                self.sessions.remove(oldest_closed);
+            }
+        }
+    }
+
+    fn close_session(&mut self, player: &MinecraftPlayer, left_at: i64) {
+        let player: Player = player.clone().into();
+        if let Some(session) = self
+            .sessions
+            .iter_mut()
+            .rev()
+            .find(|session| session.left_at.is_none() && session.player == player)
+        {
+            session.left_at = Some(left_at);
+        }
+    }
 }
 
 impl AsRef<HashSet<MinecraftPlayer>> for PlayersManager {
@@ -124,6 +191,48 @@ mod tests {
 
     use crate::event_broadcaster::EventBroadcaster;
 
+    #[tokio::test]
+    async fn test_player_sessions() {
+        use crate::traits::t_player::Player;
+        use crate::types::InstanceUuid;
+
+        let (tx, _rx) = EventBroadcaster::new(10);
+        let mut players_manager = super::PlayersManager::new(tx, InstanceUuid::default());
+        let player = |name: &str| super::MinecraftPlayer {
+            name: name.to_string(),
+            uuid: Some(format!("uuid-{}", name)),
+        };
+
+        players_manager.add_player(player("player1"), "mock_instance".to_string());
+        players_manager.add_player(player("player2"), "mock_instance".to_string());
+        // a duplicate join line doesn't open a second session
+        players_manager.add_player(player("player2"), "mock_instance".to_string());
+        players_manager.remove_by_name("player1", "mock_instance".to_string());
+
+        let online = players_manager.online_players();
+        assert_eq!(online.len(), 1);
+        assert_eq!(online[0].player, Player::from(player("player2")));
+        assert!(online[0].joined_at.is_some());
+
+        let sessions = players_manager.sessions_since(None);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].player, Player::from(player("player2")));
+        assert!(sessions[0].left_at.is_none());
+        assert!(sessions[1].left_at.is_some());
+        assert!(players_manager
+            .sessions_since(Some(sessions[1].left_at.unwrap() + 1))
+            .iter()
+            .all(|session| session.left_at.is_none()));
+
+        // the server stopping ends every open session
+        players_manager.clear("mock_instance".to_string());
+        assert!(players_manager.online_players().is_empty());
+        assert!(players_manager
+            .sessions_since(None)
+            .iter()
+            .all(|session| session.left_at.is_some()));
+    }
+
     #[tokio::test]
     async fn test_players_manager() {
         use crate::types::InstanceUuid;
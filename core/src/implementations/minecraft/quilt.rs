@@ -0,0 +1,46 @@
+use color_eyre::eyre::{eyre, Context};
+use serde_json::Value;
+
+use crate::error::Error;
+
+pub async fn get_quilt_minecraft_versions() -> Result<Vec<String>, Error> {
+    let http = reqwest::Client::new();
+
+    let response: Value = serde_json::from_str(
+        http.get("https://meta.quiltmc.org/v3/versions/game")
+            .send()
+            .await
+            .context("Failed to get quilt versions")?
+            .text()
+            .await
+            .context("Failed to get quilt versions")?
+            .as_str(),
+    )
+    .context("Failed to get quilt versions")?;
+
+    response
+        .as_array()
+        .ok_or_else(|| eyre!("Failed to get quilt versions. Response is not an array"))?
+        .iter()
+        .map(|item| {
+            item["version"]
+                .as_str()
+                .ok_or_else(|| {
+                    eyre!("Failed to get quilt versions. Version string is not a string").into()
+                })
+                .map(|version| version.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_quilt_minecraft_versions() {
+        let versions = get_quilt_minecraft_versions().await.unwrap();
+        assert!(versions.contains(&"1.20.1".to_string()));
+        assert!(versions.contains(&"1.18.2".to_string()));
+    }
+}
@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use indexmap::IndexMap;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::command_queue::{CommandTransport, QueuedCommand, Stdin};
+use crate::console_history::ConsoleHistory;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::RconStatus;
+use crate::types::{InstanceUuid, Snowflake};
+use crate::util::rand_alphanumeric;
+
+use super::configurable::ServerPropertySetting;
+use super::MinecraftInstance;
+
+/// Where the search for a free RCON port starts, the vanilla default
+pub const DEFAULT_RCON_PORT: u32 = 25575;
+const RCON_PASSWORD_LENGTH: usize = 32;
+
+/// The server.properties values turning RCON on, with a fresh password
+fn rcon_properties(port: u32) -> IndexMap<String, ConfigurableValue> {
+    IndexMap::from([
+        (
+            ServerPropertySetting::EnableRcon(true).get_identifier(),
+            ConfigurableValue::Boolean(true),
+        ),
+        (
+            ServerPropertySetting::RconPort(0).get_identifier(),
+            ConfigurableValue::UnsignedInteger(port),
+        ),
+        (
+            ServerPropertySetting::RconPassword(String::new()).get_identifier(),
+            ConfigurableValue::String(rand_alphanumeric(RCON_PASSWORD_LENGTH)),
+        ),
+    ])
+}
+
+impl MinecraftInstance {
+    pub(super) async fn rcon_port(&self) -> Option<u32> {
+        self.configurable_manifest
+            .lock()
+            .await
+            .get_unique_setting_key(&ServerPropertySetting::RconPort(0).get_identifier())
+            .and_then(|v| v.get_value().map(|v| v.try_as_unsigned_integer()))
+            .and_then(Result::ok)
+    }
+
+    pub(super) async fn get_rcon_status(&self) -> RconStatus {
+        let enabled = self.config.lock().await.use_rcon;
+        RconStatus {
+            enabled,
+            connected: self.rcon_conn.lock().await.is_some(),
+            port: if enabled {
+                self.rcon_port().await
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Writes RCON settings with a fresh password into server.properties,
+    /// the server picks them up on its next start
+    pub(super) async fn setup_rcon(&self, port: u32) -> Result<RconStatus, Error> {
+        if self.config.lock().await.use_rcon {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("RCON is already enabled"),
+            });
+        }
+        self.set_game_settings(rcon_properties(port)).await?;
+        self.config.lock().await.use_rcon = true;
+        self.write_config_to_file().await?;
+        info!("[{}] RCON enabled on port {}", self.name().await, port);
+        Ok(self.get_rcon_status().await)
+    }
+
+    /// Turns RCON back off and returns the port it was using
+    pub(super) async fn teardown_rcon(&self) -> Result<Option<u32>, Error> {
+        if !self.config.lock().await.use_rcon {
+            return Ok(None);
+        }
+        let port = self.rcon_port().await;
+        self.set_game_settings(IndexMap::from([(
+            ServerPropertySetting::EnableRcon(false).get_identifier(),
+            ConfigurableValue::Boolean(false),
+        )]))
+        .await?;
+        self.config.lock().await.use_rcon = false;
+        self.write_config_to_file().await?;
+        self.rcon_conn.lock().await.take();
+        Ok(port)
+    }
+}
+
+/// The part of an RCON connection the command writer uses
+#[async_trait]
+pub(super) trait RconClient: Send + 'static {
+    async fn cmd(&mut self, command: &str) -> Result<String, Error>;
+}
+
+#[async_trait]
+impl RconClient for rcon::Connection<tokio::net::TcpStream> {
+    async fn cmd(&mut self, command: &str) -> Result<String, Error> {
+        rcon::Connection::cmd(self, command)
+            .await
+            .map_err(|e| eyre!("RCON command failed: {}", e).into())
+    }
+}
+
+/// Sends the queued commands of a server over RCON while it's connected, and to stdin otherwise.
+///
+/// `stop` always goes to stdin, the server drops the RCON connection before answering it
+pub(super) struct RconTransport<C, W> {
+    /// None when RCON isn't enabled for this run
+    pub rcon: Option<Arc<Mutex<Option<C>>>>,
+    pub stdin: Stdin<W>,
+    pub console_history: Arc<Mutex<ConsoleHistory>>,
+    pub event_broadcaster: EventBroadcaster,
+    pub instance_uuid: InstanceUuid,
+    pub instance_name: String,
+}
+
+impl<C, W> RconTransport<C, W>
+where
+    C: RconClient,
+{
+    /// The response to `cmd`, None if it has to go to stdin instead. A failed connection is
+    /// dropped so the next command doesn't retry it
+    async fn try_rcon(&self, cmd: &QueuedCommand) -> Option<String> {
+        if cmd.command == "stop" {
+            return None;
+        }
+        let mut rcon_conn = self.rcon.as_ref()?.lock().await;
+        match rcon_conn.as_mut()?.cmd(&cmd.command).await {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!(
+                    "[{}] RCON command failed, falling back to stdin: {}",
+                    self.instance_name, e.source
+                );
+                rcon_conn.take();
+                None
+            }
+        }
+    }
+
+    /// The server doesn't print RCON responses, so they are surfaced as console output here
+    async fn surface(&self, cmd: &QueuedCommand, response: &str) {
+        for line in response.lines().filter(|line| !line.trim().is_empty()) {
+            let snowflake = Snowflake::default();
+            self.console_history
+                .lock()
+                .await
+                .push(line.to_string(), snowflake);
+            self.event_broadcaster.send(Event {
+                event_inner: EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid: self.instance_uuid.clone(),
+                    instance_event_inner: InstanceEventInner::InstanceOutput {
+                        message: line.to_string(),
+                    },
+                    instance_name: self.instance_name.clone(),
+                }),
+                details: "".to_string(),
+                snowflake,
+                caused_by: cmd.caused_by.clone(),
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl<C, W> CommandTransport for RconTransport<C, W>
+where
+    C: RconClient,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    async fn send(&mut self, cmd: &QueuedCommand) -> std::io::Result<Option<String>> {
+        match self.try_rcon(cmd).await {
+            Some(response) => {
+                self.surface(cmd, &response).await;
+                Ok(Some(response))
+            }
+            None => self.stdin.send(cmd).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream};
+
+    use super::*;
+    use crate::command_queue::{CommandQueue, CommandQueueConfig};
+    use crate::events::CausedBy;
+
+    /// Records what it's sent, and fails every command once `reachable` is false
+    struct FakeRcon {
+        sent: Arc<std::sync::Mutex<Vec<String>>>,
+        reachable: bool,
+    }
+
+    #[async_trait]
+    impl RconClient for FakeRcon {
+        async fn cmd(&mut self, command: &str) -> Result<String, Error> {
+            if !self.reachable {
+                return Err(eyre!("Connection refused").into());
+            }
+            self.sent.lock().unwrap().push(command.to_string());
+            Ok(format!("ran {}", command))
+        }
+    }
+
+    fn user() -> CausedBy {
+        CausedBy::User {
+            user_id: "user".to_string().into(),
+            user_name: "user".to_string(),
+        }
+    }
+
+    /// A queue writing to a server whose RCON is `rcon`, and the server's stdin
+    async fn serve(
+        rcon: Option<Arc<Mutex<Option<FakeRcon>>>>,
+    ) -> (CommandQueue, tokio::io::Lines<BufReader<DuplexStream>>) {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(16);
+        let (stdin, server_side) = tokio::io::duplex(4096);
+        let queue = CommandQueue::new(CommandQueueConfig::default());
+        queue.open().await;
+        queue.spawn_transport(
+            RconTransport {
+                rcon,
+                stdin: Stdin(stdin),
+                console_history: Arc::new(Mutex::new(ConsoleHistory::new(16))),
+                event_broadcaster,
+                instance_uuid: InstanceUuid::default(),
+                instance_name: "test".to_string(),
+            },
+            |_| {},
+        );
+        (queue, BufReader::new(server_side).lines())
+    }
+
+    fn fake_rcon(
+        reachable: bool,
+    ) -> (
+        Arc<Mutex<Option<FakeRcon>>>,
+        Arc<std::sync::Mutex<Vec<String>>>,
+    ) {
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let rcon = Arc::new(Mutex::new(Some(FakeRcon {
+            sent: sent.clone(),
+            reachable,
+        })));
+        (rcon, sent)
+    }
+
+    #[test]
+    fn test_rcon_properties() {
+        let properties = rcon_properties(25580);
+        assert!(matches!(
+            properties.get("enable-rcon"),
+            Some(ConfigurableValue::Boolean(true))
+        ));
+        assert!(matches!(
+            properties.get("rcon.port"),
+            Some(ConfigurableValue::UnsignedInteger(25580))
+        ));
+        let password = match properties.get("rcon.password") {
+            Some(ConfigurableValue::String(password)) => password.clone(),
+            other => panic!("unexpected rcon.password {:?}", other),
+        };
+        assert_eq!(password.len(), RCON_PASSWORD_LENGTH);
+        assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+        // a fresh one every time
+        assert!(!matches!(
+            rcon_properties(25580).get("rcon.password"),
+            Some(ConfigurableValue::String(other)) if *other == password
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_command_goes_over_rcon() {
+        let (rcon, sent) = fake_rcon(true);
+        let (queue, _stdin) = serve(Some(rcon)).await;
+        let response = queue.push_for_response("list", user()).await.unwrap();
+        assert_eq!(response.await.unwrap().as_deref(), Some("ran list"));
+        assert_eq!(*sent.lock().unwrap(), vec!["list".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_rcon_falls_back_to_stdin() {
+        let (rcon, sent) = fake_rcon(false);
+        let (queue, mut stdin) = serve(Some(rcon.clone())).await;
+        let response = queue.push_for_response("list", user()).await.unwrap();
+        assert_eq!(response.await.unwrap(), None);
+        assert_eq!(stdin.next_line().await.unwrap().unwrap(), "list");
+        assert!(sent.lock().unwrap().is_empty());
+        // dropped, so the next command doesn't try it again
+        assert!(rcon.lock().await.is_none());
+
+        // RCON not enabled for the run at all
+        let (queue, mut stdin) = serve(None).await;
+        queue.push("list", user()).await.unwrap();
+        assert_eq!(stdin.next_line().await.unwrap().unwrap(), "list");
+    }
+
+    #[tokio::test]
+    async fn test_stop_goes_to_stdin() {
+        let (rcon, sent) = fake_rcon(true);
+        let (queue, mut stdin) = serve(Some(rcon.clone())).await;
+        queue.push("stop", user()).await.unwrap();
+        let line = tokio::time::timeout(Duration::from_secs(1), stdin.next_line())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.as_deref(), Some("stop"));
+        assert!(sent.lock().unwrap().is_empty());
+        assert!(rcon.lock().await.is_some());
+    }
+}
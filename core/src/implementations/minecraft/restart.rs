@@ -0,0 +1,181 @@
+use std::process::ExitStatus;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::maintenance::maintenance;
+use crate::restart_policy::{ExitKind, RestartPolicy, RESTART_ATTEMPTS_RESET_AFTER};
+use crate::run_record::{write_run_record, ExitReason, RunRecord};
+use crate::shutdown::is_shutting_down;
+use crate::traits::t_server::{State, TServer};
+use crate::types::Snowflake;
+
+use super::MinecraftInstance;
+
+/// Classifies a server exit, `status` is `None` when it could not be collected
+pub(super) fn exit_kind(stop_requested: bool, status: Option<ExitStatus>) -> ExitKind {
+    if stop_requested {
+        ExitKind::Requested
+    } else if status.map_or(false, |status| status.success()) {
+        ExitKind::Clean
+    } else {
+        ExitKind::Crashed
+    }
+}
+
+impl MinecraftInstance {
+    /// Updates the run record and saves it, failing to save only loses the record
+    pub(super) async fn record_run(&self, update: impl FnOnce(&mut RunRecord)) {
+        self.snapshot
+            .update(|snapshot| update(&mut snapshot.last_run));
+        let record = self.snapshot.load().last_run.clone();
+        if let Err(e) = write_run_record(&self.path_to_instance, &record).await {
+            warn!(
+                "Failed to save the run record of {}: {}",
+                self.path_to_instance.display(),
+                e
+            );
+        }
+    }
+
+    pub(super) async fn record_exit(&self, exit_kind: ExitKind, status: Option<ExitStatus>) {
+        let reason = ExitReason::classify(
+            exit_kind,
+            self.killed.load(Ordering::SeqCst),
+            is_shutting_down(),
+        );
+        self.record_run(|run| {
+            run.last_stopped_at = Some(chrono::Utc::now().timestamp());
+            run.last_exit_code = status.and_then(|status| status.code());
+            run.last_exit_reason = Some(reason);
+        })
+        .await;
+    }
+
+    fn send_restart_event(&self, name: &str, instance_event_inner: InstanceEventInner) {
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid: self.uuid.clone(),
+                instance_name: name.to_string(),
+                instance_event_inner,
+            }),
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::System,
+        });
+    }
+
+    /// Counts a restart attempt and announces it.
+    ///
+    /// Returns the delay before the attempt, or `None` once the retry budget is spent
+    fn next_restart_attempt(&self, name: &str, policy: &RestartPolicy) -> Option<Duration> {
+        let attempt = self.restart_attempts.fetch_add(1, Ordering::SeqCst) + 1;
+        if attempt > policy.max_retries {
+            // a later manual start gets a fresh budget
+            self.restart_attempts.store(0, Ordering::SeqCst);
+            error!(
+                "[{}] Giving up on restarting after {} attempts",
+                name, policy.max_retries
+            );
+            self.send_restart_event(
+                name,
+                InstanceEventInner::InstanceError {
+                    message: format!(
+                        "Gave up restarting the server after {} attempts",
+                        policy.max_retries
+                    ),
+                },
+            );
+            return None;
+        }
+        let delay = policy.backoff(attempt);
+        info!(
+            "[{}] Restarting in {}s, attempt {}/{}",
+            name,
+            delay.as_secs(),
+            attempt,
+            policy.max_retries
+        );
+        self.send_restart_event(
+            name,
+            InstanceEventInner::InstanceWarning {
+                message: format!(
+                    "Restarting server in {} seconds (attempt {}/{})",
+                    delay.as_secs(),
+                    attempt,
+                    policy.max_retries
+                ),
+            },
+        );
+        Some(delay)
+    }
+
+    /// Applies the restart policy once the server process is gone and the instance is stopped
+    pub(super) async fn handle_process_exit(
+        &self,
+        exit_kind: ExitKind,
+        status: Option<ExitStatus>,
+        uptime: Duration,
+    ) {
+        let (name, policy) = {
+            let config = self.config.lock().await;
+            (config.name.clone(), config.restart_policy())
+        };
+        if exit_kind == ExitKind::Requested || uptime >= RESTART_ATTEMPTS_RESET_AFTER {
+            self.restart_attempts.store(0, Ordering::SeqCst);
+        }
+        if exit_kind == ExitKind::Crashed {
+            let reason =
+                status.map_or_else(|| "unknown exit status".to_string(), |s| s.to_string());
+            warn!("[{}] Server crashed ({})", name, reason);
+            self.send_restart_event(
+                &name,
+                InstanceEventInner::InstanceError {
+                    message: format!("Server crashed ({})", reason),
+                },
+            );
+        }
+        if !policy.should_restart(exit_kind) {
+            return;
+        }
+        let mut delay = match self.next_restart_attempt(&name, &policy) {
+            Some(delay) => delay,
+            None => return,
+        };
+        let __self = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(delay).await;
+                // the instance may have been deleted, started by hand, had its policy changed or
+                // been put under maintenance meanwhile
+                let policy = __self.config.lock().await.restart_policy();
+                if !__self.path_to_instance.join(".lodestone_config").exists()
+                    || __self.state().await != State::Stopped
+                    || !policy.should_restart(exit_kind)
+                {
+                    return;
+                }
+                if let Ok(Some(maintenance)) = maintenance(&__self.path_to_instance).await {
+                    info!(
+                        "[{}] Not restarting, the instance is under maintenance set by {}",
+                        name, maintenance.set_by_name
+                    );
+                    return;
+                }
+                match __self.start(CausedBy::System, false).await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        error!("[{}] Failed to restart server: {}", name, e);
+                        // a start that fails never reaches the exit handler, so retry from here
+                        delay = match __self.next_restart_attempt(&name, &policy) {
+                            Some(delay) => delay,
+                            None => return,
+                        };
+                    }
+                }
+            }
+        });
+    }
+}
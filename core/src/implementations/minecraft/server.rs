@@ -1,36 +1,85 @@
-use std::path::PathBuf;
+use std::collections::VecDeque;
 use std::process::Stdio;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
 
 use color_eyre::eyre::{eyre, Context};
-use sysinfo::{Pid, PidExt, ProcessExt, SystemExt};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use sysinfo::SystemExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::OwnedMutexGuard;
+use tracing::{error, info, warn};
 
+use crate::announcements::AnnouncementsConfig;
+use crate::command_queue::{CommandPriority, CommandQueueConfig, CommandQueueStatus, Stdin};
+use crate::console_capture::CommandOutput;
+use crate::console_history::ConsoleHistoryPage;
+use crate::diagnostics::run_preflight;
 use crate::error::{Error, ErrorKind};
 use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
 use crate::implementations::minecraft::line_parser::{
-    parse_player_joined, parse_player_left, parse_player_msg, parse_server_started,
-    parse_system_msg, PlayerMessage,
+    parse_eula_required, parse_server_started, parse_system_msg,
 };
+use crate::implementations::minecraft::log_rules::{LogClassification, LogLineKind};
 use crate::implementations::minecraft::player::MinecraftPlayer;
 use crate::implementations::minecraft::util::name_to_uuid;
 use crate::macro_executor::{DefaultWorkerOptionGenerator, SpawnResult};
+use crate::maintenance;
+use crate::prelude::app_state;
+use crate::process_tree::kill_tree;
+use crate::restart_policy::ExitKind;
 use crate::traits::t_configurable::TConfigurable;
 use crate::traits::t_macro::TaskEntry;
-use crate::traits::t_server::{MonitorReport, State, StateAction, TServer};
+use crate::traits::t_server::{MonitorReport, RconStatus, State, StateAction, TServer};
 
 use crate::types::Snowflake;
-use crate::util::{dont_spawn_terminal, list_dir};
+use crate::util::dont_spawn_terminal;
 
+use super::forge::ForgeLaunchTarget;
+use super::hooks::Hook;
+use super::jvm_args::jvm_args;
+use super::launch_failure::STARTUP_OUTPUT_LINES;
 use super::r#macro::resolve_macro_invocation;
-use super::{Flavour, ForgeBuildVersion, MinecraftInstance};
-use tracing::{error, info, warn};
+use super::rcon::RconTransport;
+use super::restart::exit_kind;
+use super::server_launchers::QUILT_LAUNCHER;
+use super::transition::RestartPlan;
+use super::{Flavour, MinecraftInstance};
 
-#[async_trait::async_trait]
-impl TServer for MinecraftInstance {
-    async fn start(&self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
+/// How long a sampled `MonitorReport` is reused before /proc is read again
+const MONITOR_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for the exit status once the server closed its output
+const PROCESS_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a forced kill waits for the output task to notice the exit before cleaning up itself
+const KILL_CLEANUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl MinecraftInstance {
+    fn stop_timeout_message(&self, stop_timeout_secs: u32) -> String {
+        format!(
+            "The server did not stop within {} seconds, it can be force stopped with \
+             POST /api/v1/instance/{}/kill and {{\"confirm\": true}}",
+            stop_timeout_secs, self.uuid
+        )
+    }
+
+    /// Starts the server, the caller holds the transition lock
+    async fn start_locked(&self, cause_by: CausedBy) -> Result<(), Error> {
+        // the server is still up during a graceful stop, starting it just calls the stop off
+        if self.cancel_graceful_stop(false).await {
+            return Ok(());
+        }
         let config = self.config.lock().await.clone();
+        if self.state().await == State::Stopped {
+            self.apply_pending_update().await?;
+            // fail before spawning anything, with an error that says which check failed
+            if let Some(error) = run_preflight(&self.uuid, &self.preflight_target().await?, false)
+                .await
+                .start_error()
+            {
+                return Err(error);
+            }
+            self.validate_java(&config).await?;
+        }
         self.state.lock().await.try_transition(
             StateAction::UserStart,
             Some(&|state| {
@@ -46,13 +95,8 @@ impl TServer for MinecraftInstance {
                 });
             }),
         )?;
-
-        if !port_scanner::local_port_available(config.port as u16) {
-            return Err(Error {
-                kind: ErrorKind::Internal,
-                source: eyre!("Port {} is already in use", config.port),
-            });
-        }
+        self.stop_requested.store(false, Ordering::SeqCst);
+        self.killed.store(false, Ordering::SeqCst);
 
         let prelaunch = resolve_macro_invocation(&self.path_to_instance, "prelaunch");
         if let Some(prelaunch) = prelaunch {
@@ -81,6 +125,7 @@ impl TServer for MinecraftInstance {
                         pid,
                         name: "prelaunch".to_string(),
                         creation_time: chrono::Utc::now().timestamp(),
+                        caused_by: CausedBy::System,
                     },
                 );
                 tokio::select! {
@@ -99,102 +144,54 @@ impl TServer for MinecraftInstance {
             );
         }
 
-        let jre = if let Some(jre) = &config.java_cmd {
-            PathBuf::from(jre)
-        } else {
-            self.path_to_runtimes
-                .join("java")
-                .join(format!("jre{}", config.jre_major_version))
-                .join(if std::env::consts::OS == "macos" {
-                    "Contents/Home/bin"
-                } else {
-                    "bin"
-                })
-                .join("java")
-        };
+        if let Some(pre_start_hook) = &config.pre_start_hook {
+            if let Err(e) = self
+                .run_instance_hook(Hook::PreStart, pre_start_hook, &config.name)
+                .await
+            {
+                self.abort_start(&config.name, &cause_by, "The pre_start hook failed")
+                    .await?;
+                return Err(e);
+            }
+        }
+
+        // a kill during the prelaunch script or hook found nothing to kill, the server isn't
+        // spawned after it
+        if self.stop_requested.load(Ordering::SeqCst) {
+            self.abort_start(
+                &config.name,
+                &cause_by,
+                "Server was killed before it was spawned",
+            )
+            .await?;
+            return Err(eyre!("The server was killed before it was spawned").into());
+        }
+
+        let jre = self.java_path(&config);
 
         let mut server_start_command = Command::new(&jre);
-        let server_start_command = server_start_command
-            .arg(format!("-Xmx{}M", config.max_ram))
-            .arg(format!("-Xms{}M", config.min_ram))
-            .args(
-                &config
-                    .cmd_args
-                    .iter()
-                    .filter(|s| !s.is_empty())
-                    .collect::<Vec<&String>>(),
-            );
+        let server_start_command = server_start_command.args(jvm_args(&config));
 
         let server_start_command = match &config.flavour {
             Flavour::Forge { build_version } => {
-                let ForgeBuildVersion(build_version) = build_version
-                    .as_ref()
-                    .ok_or_else(|| eyre!("Forge version not found"))?;
-                let version_parts: Vec<&str> = config.version.split('.').collect();
-                let major_version: i32 = version_parts[1]
-                    .parse()
-                    .context("Unable to parse major Minecraft version for Forge")?;
-
-                if 17 <= major_version {
-                    let forge_args = match std::env::consts::OS {
-                        "windows" => "win_args.txt",
-                        _ => "unix_args.txt",
-                    };
-
-                    let mut full_forge_args = std::ffi::OsString::from("@");
-                    full_forge_args.push(
-                        self.path_to_instance
-                            .join("libraries")
-                            .join("net")
-                            .join("minecraftforge")
-                            .join("forge")
-                            .join(build_version.as_str())
-                            .join(forge_args)
-                            .into_os_string()
-                            .as_os_str(),
-                    );
-
-                    server_start_command.arg(full_forge_args)
-                } else if (7..=16).contains(&major_version) {
-                    let files = list_dir(&self.path_to_instance, Some(false))
-                        .await
-                        .context("Failed to find forge.jar")?;
-                    let forge_jar_name = files
-                        .iter()
-                        .find(|p| {
-                            p.extension().unwrap_or_default() == "jar"
-                                && p.file_name()
-                                    .unwrap_or_default()
-                                    .to_str()
-                                    .unwrap_or_default()
-                                    .starts_with(format!("forge-{}-", config.version,).as_str())
-                        })
-                        .ok_or_else(|| eyre!("Failed to find forge.jar"))?;
-                    server_start_command
-                        .arg("-jar")
-                        .arg(&self.path_to_instance.join(forge_jar_name))
-                } else {
-                    // 1.5 doesn't work due to JRE issues
-                    // 1.4 doesn't work since forge doesn't provide an installer
-                    let files = list_dir(&self.path_to_instance, Some(false))
-                        .await
-                        .context("Failed to find minecraftforge.jar")?;
-                    let server_jar_name = files
-                        .iter()
-                        .find(|p| {
-                            p.extension().unwrap_or_default() == "jar"
-                                && p.file_name()
-                                    .unwrap_or_default()
-                                    .to_str()
-                                    .unwrap_or_default()
-                                    .starts_with("minecraftforge")
-                        })
-                        .ok_or_else(|| eyre!("Failed to find minecraftforge.jar"))?;
-                    server_start_command
-                        .arg("-jar")
-                        .arg(&self.path_to_instance.join(server_jar_name))
+                match ForgeLaunchTarget::detect(
+                    &self.path_to_instance,
+                    &config.version,
+                    build_version.as_ref(),
+                )
+                .await?
+                {
+                    ForgeLaunchTarget::ArgsFile(args_file) => {
+                        let mut full_forge_args = std::ffi::OsString::from("@");
+                        full_forge_args.push(args_file.as_os_str());
+                        server_start_command.arg(full_forge_args)
+                    }
+                    ForgeLaunchTarget::Jar(jar) => server_start_command.arg("-jar").arg(jar),
                 }
             }
+            Flavour::Quilt { .. } => server_start_command
+                .arg("-jar")
+                .arg(&self.path_to_instance.join(QUILT_LAUNCHER)),
             _ => server_start_command
                 .arg("-jar")
                 .arg(&self.path_to_instance.join("server.jar")),
@@ -202,7 +199,17 @@ impl TServer for MinecraftInstance {
 
         let server_start_command = server_start_command
             .arg("nogui")
-            .current_dir(&self.path_to_instance);
+            .current_dir(&self.path_to_instance)
+            .envs(&config.env);
+        // a group of its own lets a forced kill reach everything the server spawned
+        #[cfg(unix)]
+        server_start_command.process_group(0);
+        let launch_command = std::iter::once(server_start_command.as_std().get_program())
+            .chain(server_start_command.as_std().get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        self.snapshot
+            .update(|snapshot| snapshot.launch_command = Some(launch_command));
 
         match dont_spawn_terminal(server_start_command)
             .stdout(Stdio::piped())
@@ -218,7 +225,42 @@ impl TServer for MinecraftInstance {
                     );
                     eyre!("Failed to take stdin during startup")
                 })?;
-                self.stdin.lock().await.replace(stdin);
+                self.command_queue.open().await;
+                self.spawn_announcements_task().await;
+                self.spawn_ping_task().await;
+                self.spawn_idle_task().await;
+                let transport = RconTransport {
+                    // the connection is only made for a run started with RCON enabled
+                    rcon: config.use_rcon.then(|| self.rcon_conn.clone()),
+                    stdin: Stdin(stdin),
+                    console_history: self.console_history.clone(),
+                    event_broadcaster: self.event_broadcaster.clone(),
+                    instance_uuid: self.uuid.clone(),
+                    instance_name: config.name.clone(),
+                };
+                self.command_queue.spawn_transport(transport, {
+                    let event_broadcaster = self.event_broadcaster.clone();
+                    let uuid = self.uuid.clone();
+                    let name = config.name.clone();
+                    move |cmd| {
+                        event_broadcaster.send(Event {
+                            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                                instance_uuid: uuid.clone(),
+                                instance_event_inner: InstanceEventInner::InstanceInput {
+                                    message: cmd.command.clone(),
+                                },
+                                instance_name: name.clone(),
+                            }),
+                            details: if cmd.count > 1 {
+                                format!("Coalesced {} identical commands", cmd.count)
+                            } else {
+                                "".to_string()
+                            },
+                            snowflake: Snowflake::default(),
+                            caused_by: cmd.caused_by.clone(),
+                        });
+                    }
+                });
                 let stdout = proc.stdout.take().ok_or_else(|| {
                     error!(
                         "[{}] Failed to take stdout during startup",
@@ -234,14 +276,19 @@ impl TServer for MinecraftInstance {
                     eyre!("Failed to take stderr during startup")
                 })?;
                 *self.process.lock().await = Some(proc);
-                tokio::task::spawn({
+                let output_task = tokio::task::spawn({
                     let mut __self = self.clone();
                     let event_broadcaster = __self.event_broadcaster.clone();
                     let uuid = __self.uuid.clone();
                     let name = config.name.clone();
                     let players_manager = __self.players_manager.clone();
+                    let log_rules = config.compiled_log_rules();
                     async move {
                         let mut did_start = false;
+                        let mut eula_required = false;
+                        let mut startup_output = VecDeque::new();
+                        let launched_at = SystemTime::now();
+                        let started_at = Instant::now();
 
                         let mut stdout_reader = BufReader::new(stdout);
                         let mut stderr_reader = BufReader::new(stderr);
@@ -283,6 +330,24 @@ impl TServer for MinecraftInstance {
                                         // info!("[{}] {}", name, line);
                                         warn!("[{}] {}", name, line);
                                     }
+                                    let snowflake = Snowflake::default();
+                                    // output is attributed to whoever is capturing the response
+                                    // to their command
+                                    let caused_by = __self
+                                        .console_capture
+                                        .offer(&line)
+                                        .unwrap_or(CausedBy::System);
+                                    __self
+                                        .console_history
+                                        .lock()
+                                        .await
+                                        .push(line.clone(), snowflake);
+                                    if !did_start {
+                                        if startup_output.len() == STARTUP_OUTPUT_LINES {
+                                            startup_output.pop_front();
+                                        }
+                                        startup_output.push_back(line.clone());
+                                    }
                                     event_broadcaster.send(Event {
                                         event_inner: EventInner::InstanceEvent(InstanceEvent {
                                             instance_uuid: uuid.clone(),
@@ -293,10 +358,13 @@ impl TServer for MinecraftInstance {
                                             instance_name: name.clone(),
                                         }),
                                         details: "".to_string(),
-                                        snowflake: Snowflake::default(),
-                                        caused_by: CausedBy::System,
+                                        snowflake,
+                                        caused_by,
                                     });
 
+                                    if !did_start && parse_eula_required(&line) {
+                                        eula_required = true;
+                                    }
                                     if parse_server_started(&line) && !did_start {
                                         did_start = true;
                                         __self
@@ -325,6 +393,12 @@ impl TServer for MinecraftInstance {
                                             )
                                             .unwrap();
                                         info!("[{}] Instance started", name);
+                                        __self
+                                            .record_run(|run| {
+                                                run.last_started_at =
+                                                    Some(chrono::Utc::now().timestamp())
+                                            })
+                                            .await;
 
                                         if let (Some(true), Some(rcon_psw), Some(rcon_port)) = {
                                             let lock = __self.configurable_manifest.lock().await;
@@ -387,54 +461,80 @@ impl TServer for MinecraftInstance {
                                             __self.rcon_conn.lock().await.take();
                                         }
                                     }
-                                    if let Some(system_msg) = parse_system_msg(&line) {
-                                        let _ = event_broadcaster.send(Event {
-                                            event_inner: EventInner::InstanceEvent(InstanceEvent {
-                                                instance_uuid: uuid.clone(),
-                                                instance_event_inner:
-                                                    InstanceEventInner::SystemMessage {
-                                                        message: line,
-                                                    },
-                                                instance_name: name.clone(),
-                                            }),
-                                            details: "".to_string(),
-                                            snowflake: Snowflake::default(),
-                                            caused_by: CausedBy::System,
-                                        });
-                                        if let Some(player_name) = parse_player_joined(&system_msg)
-                                        {
-                                            players_manager.lock().await.add_player(
-                                                MinecraftPlayer {
-                                                    name: player_name.clone(),
-                                                    uuid: name_to_uuid(&player_name).await,
-                                                },
-                                                __self.name().await,
+                                    match log_rules.classify(&line) {
+                                        Some(LogClassification {
+                                            kind: LogLineKind::Chat,
+                                            player,
+                                            message,
+                                        }) => {
+                                            let message = message.unwrap_or_default();
+                                            __self.chat_history.lock().await.push(
+                                                player.clone(),
+                                                message.clone(),
+                                                false,
                                             );
-                                        } else if let Some(player_name) =
-                                            parse_player_left(&system_msg)
-                                        {
-                                            players_manager
-                                                .lock()
-                                                .await
-                                                .remove_by_name(&player_name, __self.name().await);
-                                        }
-                                    } else if let Some(PlayerMessage { player, message }) =
-                                        parse_player_msg(&line)
-                                    {
-                                        event_broadcaster.send(Event {
-                                            event_inner: EventInner::InstanceEvent(InstanceEvent {
-                                                instance_uuid: uuid.clone(),
-                                                instance_event_inner:
-                                                    InstanceEventInner::PlayerMessage {
-                                                        player,
-                                                        player_message: message,
+                                            event_broadcaster.send(Event {
+                                                event_inner: EventInner::InstanceEvent(
+                                                    InstanceEvent {
+                                                        instance_uuid: uuid.clone(),
+                                                        instance_event_inner:
+                                                            InstanceEventInner::PlayerMessage {
+                                                                player,
+                                                                player_message: message,
+                                                            },
+                                                        instance_name: name.clone(),
                                                     },
-                                                instance_name: name.clone(),
-                                            }),
-                                            details: "".to_string(),
-                                            snowflake: Snowflake::default(),
-                                            caused_by: CausedBy::System,
-                                        });
+                                                ),
+                                                details: "".to_string(),
+                                                snowflake: Snowflake::default(),
+                                                caused_by: CausedBy::System,
+                                            });
+                                        }
+                                        classification => {
+                                            if parse_system_msg(&line).is_some() {
+                                                let _ = event_broadcaster.send(Event {
+                                                    event_inner: EventInner::InstanceEvent(
+                                                        InstanceEvent {
+                                                            instance_uuid: uuid.clone(),
+                                                            instance_event_inner:
+                                                                InstanceEventInner::SystemMessage {
+                                                                    message: line,
+                                                                },
+                                                            instance_name: name.clone(),
+                                                        },
+                                                    ),
+                                                    details: "".to_string(),
+                                                    snowflake: Snowflake::default(),
+                                                    caused_by: CausedBy::System,
+                                                });
+                                            }
+                                            match classification {
+                                                Some(LogClassification {
+                                                    kind: LogLineKind::PlayerJoined,
+                                                    player,
+                                                    ..
+                                                }) => {
+                                                    players_manager.lock().await.add_player(
+                                                        MinecraftPlayer {
+                                                            uuid: name_to_uuid(&player).await,
+                                                            name: player,
+                                                        },
+                                                        __self.name().await,
+                                                    );
+                                                }
+                                                Some(LogClassification {
+                                                    kind: LogLineKind::PlayerLeft,
+                                                    player,
+                                                    ..
+                                                }) => {
+                                                    players_manager.lock().await.remove_by_name(
+                                                        &player,
+                                                        __self.name().await,
+                                                    );
+                                                }
+                                                _ => {}
+                                            }
+                                        }
                                     }
                                 } else {
                                     break;
@@ -442,6 +542,22 @@ impl TServer for MinecraftInstance {
                             }
                         }
                         info!("Instance {} process shutdown", name);
+                        let status = match __self.process.lock().await.as_mut() {
+                            Some(process) => {
+                                tokio::time::timeout(PROCESS_EXIT_TIMEOUT, process.wait())
+                                    .await
+                                    .ok()
+                                    .and_then(|status| status.ok())
+                            }
+                            None => None,
+                        };
+                        let exit_kind = exit_kind(
+                            __self.stop_requested.load(Ordering::SeqCst)
+                                || *__self.state.lock().await == State::Stopping,
+                            status,
+                        );
+                        // recorded before the state changes, so whoever sees it stopped sees why
+                        __self.record_exit(exit_kind, status).await;
                         __self
                             .state
                             .lock()
@@ -466,37 +582,65 @@ impl TServer for MinecraftInstance {
                             .unwrap();
                         __self.players_manager.lock().await.clear(name);
                         __self.rcon_conn.lock().await.take();
-                    }
-                });
-                self.config.lock().await.has_started = true;
-                self.write_config_to_file().await?;
-                let instance_uuid = self.uuid.clone();
-                let mut rx = self.event_broadcaster.subscribe();
-
-                if block {
-                    while let Ok(event) = rx.recv().await {
-                        if let EventInner::InstanceEvent(InstanceEvent {
-                            instance_uuid: event_instance_uuid,
-                            instance_event_inner: InstanceEventInner::StateTransition { to },
-                            ..
-                        }) = event.event_inner
-                        {
-                            if instance_uuid == event_instance_uuid {
-                                if to == State::Running {
-                                    return Ok(()); // Instance started successfully
-                                } else if to == State::Stopped {
-                                    return Err(eyre!(
-                                        "Instance exited unexpectedly before starting"
+                        __self.command_queue.close().await;
+                        let post_stop_hook = __self.config.lock().await.post_stop_hook.clone();
+                        if let Some(post_stop_hook) = post_stop_hook {
+                            // the server stays stopped whatever the hook does
+                            if let Err(e) = __self
+                                .run_instance_hook(Hook::PostStop, &post_stop_hook, &config.name)
+                                .await
+                            {
+                                let message = e.source.to_string();
+                                warn!("[{}] {}", config.name, message);
+                                event_broadcaster.send(Event {
+                                    event_inner: EventInner::InstanceEvent(InstanceEvent {
+                                        instance_name: config.name.clone(),
+                                        instance_uuid: __self.uuid.clone(),
+                                        instance_event_inner: InstanceEventInner::InstanceWarning {
+                                            message,
+                                        },
+                                    }),
+                                    snowflake: Snowflake::default(),
+                                    details: "The post_stop hook failed".to_string(),
+                                    caused_by: CausedBy::System,
+                                });
+                            }
+                        }
+                        if eula_required {
+                            // restarting can't help until the EULA is accepted
+                            warn!(
+                                "[{}] The server stopped, its EULA isn't accepted",
+                                config.name
+                            );
+                            event_broadcaster.send(Event {
+                                event_inner: EventInner::InstanceEvent(InstanceEvent {
+                                    instance_name: config.name.clone(),
+                                    instance_uuid: __self.uuid.clone(),
+                                    instance_event_inner: InstanceEventInner::EulaRequired,
+                                }),
+                                snowflake: Snowflake::default(),
+                                details: "The server requires its EULA to be accepted".to_string(),
+                                caused_by: CausedBy::System,
+                            });
+                        } else {
+                            if !did_start && exit_kind == ExitKind::Crashed {
+                                __self
+                                    .report_launch_failure(
+                                        startup_output.into(),
+                                        launched_at,
+                                        status,
                                     )
-                                    .into());
-                                }
+                                    .await;
                             }
+                            __self
+                                .handle_process_exit(exit_kind, status, started_at.elapsed())
+                                .await;
                         }
                     }
-                    Err(eyre!("Sender shutdown").into())
-                } else {
-                    Ok(())
-                }
+                });
+                *self.output_task.lock().await = Some(output_task);
+                self.config.lock().await.has_started = true;
+                self.write_config_to_file().await
             }
             Err(e) => {
                 error!("Failed to start server, {}", e);
@@ -526,7 +670,38 @@ impl TServer for MinecraftInstance {
             }
         }
     }
-    async fn stop(&self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
+
+    /// Goes back to stopped from a start that gave up before spawning the server
+    async fn abort_start(
+        &self,
+        name: &str,
+        cause_by: &CausedBy,
+        details: &str,
+    ) -> Result<(), Error> {
+        self.state.lock().await.try_transition(
+            StateAction::InstanceStop,
+            Some(&|state| {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::InstanceEvent(InstanceEvent {
+                        instance_name: name.to_string(),
+                        instance_uuid: self.uuid.clone(),
+                        instance_event_inner: InstanceEventInner::StateTransition { to: state },
+                    }),
+                    snowflake: Snowflake::default(),
+                    details: details.to_string(),
+                    caused_by: cause_by.clone(),
+                });
+            }),
+        )
+    }
+
+    /// Asks the server to stop, the caller holds the transition lock
+    async fn stop_locked(&self, cause_by: CausedBy) -> Result<(), Error> {
+        self.cancel_graceful_stop(true).await;
+        // a frozen server can't read the stop command
+        if self.state().await == State::Suspended {
+            self.resume_locked(cause_by.clone()).await?;
+        }
         let config = self.config.lock().await.clone();
 
         self.state.lock().await.try_transition(
@@ -544,99 +719,218 @@ impl TServer for MinecraftInstance {
                 });
             }),
         )?;
+        self.stop_requested.store(true, Ordering::SeqCst);
         let name = config.name.clone();
-        let _uuid = self.uuid.clone();
-        self.stdin
-            .lock()
-            .await
-            .as_mut()
-            .ok_or_else(|| {
-                error!("[{}] Failed to stop instance: stdin not available", name);
-                eyre!("Failed to stop instance: stdin not available")
-            })?
-            .write_all(b"stop\n")
+        self.command_queue
+            .push_with_priority("stop", cause_by.clone(), CommandPriority::Control)
             .await
-            .context("Failed to write to stdin")
             .map_err(|e| {
                 error!("[{}] Failed to stop instance: {}", name, e);
                 e
             })?;
         self.rcon_conn.lock().await.take();
-        let mut rx = self.event_broadcaster.subscribe();
-        let instance_uuid = self.uuid.clone();
+        Ok(())
+    }
+
+    /// Restarts the server as `plan` says, the transition lock is held until it's started again
+    async fn restart_locked(
+        &self,
+        plan: RestartPlan,
+        caused_by: CausedBy,
+        _transition: OwnedMutexGuard<()>,
+    ) -> Result<(), Error> {
+        let stop_timeout_secs = self.config.lock().await.stop_timeout_secs;
+        if plan == RestartPlan::AwaitRunning {
+            self.await_started().await?;
+        }
+        if plan != RestartPlan::AwaitStopped {
+            self.stop_locked(caused_by.clone()).await?;
+        }
+        self.await_stopped(stop_timeout_secs).await?;
+        self.start_locked(caused_by).await
+    }
+
+    async fn await_started(&self) -> Result<(), Error> {
+        match self
+            .await_state(&[State::Running, State::Stopped], None)
+            .await
+        {
+            Some(State::Running) => Ok(()),
+            Some(_) => Err(eyre!("Instance exited unexpectedly before starting").into()),
+            None => Err(eyre!("Sender shutdown").into()),
+        }
+    }
 
+    async fn await_stopped(&self, stop_timeout_secs: u32) -> Result<(), Error> {
+        let stop_timeout = Duration::from_secs(stop_timeout_secs as u64);
+        match self
+            .await_state(&[State::Stopped], Some(stop_timeout))
+            .await
+        {
+            Some(_) => Ok(()),
+            None => Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!(self.stop_timeout_message(stop_timeout_secs)),
+            }),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TServer for MinecraftInstance {
+    async fn start(&self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
+        app_state().migrating.check(&self.uuid)?;
+        maintenance::check_start(&self.path_to_instance, &self.uuid, &cause_by).await?;
+        let transition = self.transition_lock.acquire().await;
+        self.start_locked(cause_by).await?;
+        drop(transition);
         if block {
-            while let Ok(event) = rx.recv().await {
-                if let EventInner::InstanceEvent(InstanceEvent {
-                    instance_uuid: event_instance_uuid,
-                    instance_event_inner: InstanceEventInner::StateTransition { to },
-                    ..
-                }) = event.event_inner
-                {
-                    if instance_uuid == event_instance_uuid && to == State::Stopped {
-                        return Ok(());
-                    }
-                }
-            }
-            Err(eyre!("Sender shutdown").into())
+            self.await_started().await
         } else {
             Ok(())
         }
     }
+    async fn stop(&self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
+        let transition = self.transition_lock.acquire().await;
+        self.stop_locked(cause_by.clone()).await?;
+        drop(transition);
+        let config = self.config.lock().await.clone();
 
-    async fn restart(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
         if block {
-            self.stop(caused_by.clone(), block).await?;
-            self.start(caused_by, block).await
+            self.await_stopped(config.stop_timeout_secs).await
         } else {
-            self.state
-                .lock()
-                .await
-                .try_new_state(StateAction::UserStop, None)?;
+            let stop_timeout = Duration::from_secs(config.stop_timeout_secs as u64);
+            let __self = self.clone();
+            tokio::task::spawn(async move {
+                tokio::time::sleep(stop_timeout).await;
+                if __self.state().await == State::Stopping {
+                    let message = __self.stop_timeout_message(config.stop_timeout_secs);
+                    warn!("[{}] {}", config.name, message);
+                    __self.event_broadcaster.send(Event {
+                        event_inner: EventInner::InstanceEvent(InstanceEvent {
+                            instance_name: config.name.clone(),
+                            instance_uuid: __self.uuid.clone(),
+                            instance_event_inner: InstanceEventInner::InstanceWarning { message },
+                        }),
+                        snowflake: Snowflake::default(),
+                        details: "".to_string(),
+                        caused_by: cause_by,
+                    });
+                }
+            });
+            Ok(())
+        }
+    }
 
-            let mut __self = self.clone();
+    async fn restart(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        // refused before stopping, so the server isn't left down
+        maintenance::check_start(&self.path_to_instance, &self.uuid, &caused_by).await?;
+        let transition = self.transition_lock.acquire().await;
+        let plan = RestartPlan::for_state(self.state().await)?;
+        if block {
+            self.restart_locked(plan, caused_by, transition).await?;
+            self.await_started().await
+        } else {
+            let __self = self.clone();
             tokio::task::spawn(async move {
-                __self.stop(caused_by.clone(), true).await.unwrap();
-                __self.start(caused_by, block).await.unwrap()
+                if let Err(e) = __self.restart_locked(plan, caused_by, transition).await {
+                    error!("[{}] Failed to restart instance: {}", __self.uuid, e.source);
+                }
             });
             Ok(())
         }
     }
 
-    async fn kill(&self, _cause_by: CausedBy) -> Result<(), Error> {
+    async fn kill(&self, cause_by: CausedBy) -> Result<(), Error> {
+        // not behind the transition lock, killing is how a stop or restart that hangs is undone
         let config = self.config.lock().await.clone();
 
         if self.state().await == State::Stopped {
             warn!("[{}] Instance is already stopped", config.name.clone());
             return Err(eyre!("Instance is already stopped").into());
         }
+        self.cancel_graceful_stop(true).await;
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.killed.store(true, Ordering::SeqCst);
+        let pid = self.process.lock().await.as_ref().and_then(|p| p.id());
+        let killed = match pid {
+            Some(pid) => kill_tree(&mut self.system.lock().await, pid),
+            None => 0,
+        };
         if let Some(process) = self.process.lock().await.as_mut() {
-            process
-                .kill()
-                .await
-                .context("Failed to kill process")
-                .map_err(|e| {
-                    error!("[{}] Failed to kill instance: {}", config.name.clone(), e);
-                    e
-                })?;
+            let _ = process.start_kill();
         }
-        {
-            error!(
-                "[{}] Process not available, assuming instance is stopped",
-                config.name.clone()
+        warn!(
+            "[{}] Force killed the server, {} process(es) signalled",
+            config.name, killed
+        );
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_name: config.name.clone(),
+                instance_uuid: self.uuid.clone(),
+                instance_event_inner: InstanceEventInner::InstanceWarning {
+                    message: format!("Server force killed, {} process(es) signalled", killed),
+                },
+            }),
+            snowflake: Snowflake::default(),
+            details: "Instance was force killed".to_string(),
+            caused_by: cause_by.clone(),
+        });
+
+        // the output task normally sees the pipes close and does the cleanup
+        let cleaned_up = tokio::time::timeout(KILL_CLEANUP_TIMEOUT, async {
+            while self.state().await != State::Stopped {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok();
+        if !cleaned_up {
+            warn!(
+                "[{}] Server output did not close after the kill, cleaning up",
+                config.name
             );
+            if let Some(output_task) = self.output_task.lock().await.take() {
+                output_task.abort();
+            }
+            self.command_queue.close().await;
+            self.players_manager.lock().await.clear(config.name.clone());
+            self.rcon_conn.lock().await.take();
+            self.process.lock().await.take();
+            self.record_exit(ExitKind::Requested, None).await;
             *self.state.lock().await = State::Stopped;
-            self.event_broadcaster
-                .send(Event::new_instance_state_transition(
-                    self.uuid.clone(),
-                    config.name.clone(),
-                    State::Stopped,
-                ));
-            Err(eyre!("Process not available, assuming instance is stopped"))?;
+            self.event_broadcaster.send(Event {
+                event_inner: EventInner::InstanceEvent(InstanceEvent {
+                    instance_name: config.name.clone(),
+                    instance_uuid: self.uuid.clone(),
+                    instance_event_inner: InstanceEventInner::StateTransition {
+                        to: State::Stopped,
+                    },
+                }),
+                snowflake: Snowflake::default(),
+                details: "Instance was force killed".to_string(),
+                caused_by: cause_by,
+            });
         }
         Ok(())
     }
 
+    async fn suspend(&self, caused_by: CausedBy) -> Result<(), Error> {
+        self.suspend_server(caused_by).await
+    }
+
+    async fn resume(&self, caused_by: CausedBy) -> Result<(), Error> {
+        self.resume_server(caused_by).await
+    }
+
+    async fn auto_suspend(&self) -> Result<Option<u32>, Error> {
+        Ok(self.config.lock().await.auto_suspend_minutes)
+    }
+
+    async fn set_auto_suspend(&self, minutes: Option<u32>) -> Result<(), Error> {
+        self.set_auto_suspend_minutes(minutes).await
+    }
+
     async fn state(&self) -> State {
         *self.state.lock().await
     }
@@ -645,74 +939,170 @@ impl TServer for MinecraftInstance {
         let config = self.config.lock().await.clone();
         if self.state().await == State::Stopped {
             Err(eyre!("Instance is stopped").into())
+        } else if self.state().await == State::Suspended {
+            Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is suspended, resume it first"),
+            })
         } else {
-            match self.stdin.lock().await.as_mut() {
-                Some(stdin) => match {
-                    if command == "stop" {
-                        self.state.lock().await.try_new_state(
-                            StateAction::UserStop,
-                            Some(&|state| {
-                                self.event_broadcaster.send(Event {
-                                    event_inner: EventInner::InstanceEvent(InstanceEvent {
-                                        instance_name: config.name.clone(),
-                                        instance_uuid: self.uuid.clone(),
-                                        instance_event_inner: InstanceEventInner::StateTransition {
-                                            to: state,
-                                        },
-                                    }),
-                                    snowflake: Snowflake::default(),
-                                    details: "Starting server".to_string(),
-                                    caused_by: cause_by.clone(),
-                                });
+            if command == "stop" {
+                self.state.lock().await.try_new_state(
+                    StateAction::UserStop,
+                    Some(&|state| {
+                        self.event_broadcaster.send(Event {
+                            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                                instance_name: config.name.clone(),
+                                instance_uuid: self.uuid.clone(),
+                                instance_event_inner: InstanceEventInner::StateTransition {
+                                    to: state,
+                                },
                             }),
-                        )?;
-                    }
-                    stdin.write_all(format!("{}\n", command).as_bytes()).await
-                } {
-                    Ok(_) => Ok(()),
-                    Err(e) => {
-                        warn!(
-                            "[{}] Failed to send command to instance: {}",
-                            config.name.clone(),
-                            e
-                        );
-                        Err(e).context("Failed to send command to instance")?;
-                        unreachable!()
-                    }
-                },
-                None => {
-                    let err_msg =
-                        "Failed to write to stdin because stdin is None. Please report this bug.";
-                    error!("[{}] {}", config.name.clone(), err_msg);
-                    Err(eyre!(err_msg).into())
-                }
+                            snowflake: Snowflake::default(),
+                            details: "Starting server".to_string(),
+                            caused_by: cause_by.clone(),
+                        });
+                    }),
+                )?;
+                self.stop_requested.store(true, Ordering::SeqCst);
             }
+            self.command_queue
+                .push(command, cause_by)
+                .await
+                .map_err(|e| {
+                    warn!(
+                        "[{}] Failed to send command to instance: {}",
+                        config.name.clone(),
+                        e
+                    );
+                    e
+                })
+        }
+    }
+    async fn send_command_with_output(
+        &self,
+        command: &str,
+        caused_by: CausedBy,
+        timeout: Duration,
+    ) -> Result<CommandOutput, Error> {
+        if self.state().await != State::Running {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is not running"),
+            });
         }
+        let session = self.console_capture.begin(caused_by.clone()).await;
+        if command == "stop" {
+            self.send_command(command, caused_by).await?;
+            return Ok(session.collect(command, timeout).await);
+        }
+        let response = self
+            .command_queue
+            .push_for_response(command, caused_by)
+            .await?;
+        match response.await {
+            // went over RCON, which answers it directly
+            Ok(Some(response)) => Ok(CommandOutput {
+                lines: response.lines().map(str::to_string).collect(),
+                complete: true,
+            }),
+            Ok(None) => Ok(session.collect(command, timeout).await),
+            Err(_) => Err(eyre!("The server stopped before the command was sent").into()),
+        }
+    }
+    async fn stop_gracefully(&self, caused_by: CausedBy, seconds: u32) -> Result<(), Error> {
+        self.start_graceful_stop(caused_by, seconds).await
+    }
+    async fn rcon_status(&self) -> Result<RconStatus, Error> {
+        Ok(self.get_rcon_status().await)
+    }
+    async fn enable_rcon(&self, port: u32) -> Result<RconStatus, Error> {
+        self.setup_rcon(port).await
+    }
+    async fn disable_rcon(&self) -> Result<Option<u32>, Error> {
+        self.teardown_rcon().await
+    }
+    async fn pid(&self) -> Option<u32> {
+        self.process.lock().await.as_ref().and_then(|p| p.id())
     }
     async fn monitor(&self) -> MonitorReport {
+        let mut last_report = self.last_monitor_report.lock().await;
+        if let Some((sampled_at, report)) = last_report.as_ref() {
+            if sampled_at.elapsed() < MONITOR_SAMPLE_INTERVAL {
+                return report.clone();
+            }
+        }
         let mut sys = self.system.lock().await;
         sys.refresh_memory();
-        if let Some(pid) = self.process.lock().await.as_ref().and_then(|p| p.id()) {
-            sys.refresh_process(Pid::from_u32(pid));
-            let proc = (*sys).process(Pid::from_u32(pid));
-            if let Some(proc) = proc {
-                let cpu_usage =
-                    sys.process(Pid::from_u32(pid)).unwrap().cpu_usage() / sys.cpus().len() as f32;
-
-                let memory_usage = proc.memory();
-                let disk_usage = proc.disk_usage();
-                let start_time = proc.start_time();
-                MonitorReport {
-                    memory_usage: Some(memory_usage),
-                    disk_usage: Some(disk_usage.into()),
-                    cpu_usage: Some(cpu_usage),
-                    start_time: Some(start_time),
-                }
-            } else {
-                MonitorReport::default()
-            }
-        } else {
-            MonitorReport::default()
+        let report = match self.process.lock().await.as_ref().and_then(|p| p.id()) {
+            Some(pid) => self.process_tree.lock().await.sample(&mut sys, pid).map_or(
+                MonitorReport::default(),
+                |tree| MonitorReport {
+                    memory_usage: Some(tree.memory_usage),
+                    disk_usage: Some(tree.disk_usage),
+                    cpu_usage: Some(tree.cpu_usage),
+                    start_time: Some(tree.start_time),
+                    uptime: Some(tree.uptime),
+                    process_count: Some(tree.process_count),
+                    top_children: Some(tree.top_children),
+                },
+            ),
+            None => MonitorReport::default(),
+        };
+        last_report.replace((Instant::now(), report.clone()));
+        report
+    }
+
+    async fn command_queue_status(&self) -> Result<CommandQueueStatus, Error> {
+        Ok(self.command_queue.status().await)
+    }
+
+    async fn set_command_queue_config(&self, config: CommandQueueConfig) -> Result<(), Error> {
+        self.command_queue.set_config(config).await?;
+        self.config.lock().await.command_queue = config;
+        self.write_config_to_file().await
+    }
+
+    async fn console_history(
+        &self,
+        offset: Option<u64>,
+        count: usize,
+    ) -> Result<ConsoleHistoryPage, Error> {
+        Ok(self.console_history.lock().await.page(offset, count))
+    }
+
+    async fn clear_console_history(&self) -> Result<(), Error> {
+        self.console_history.lock().await.clear();
+        Ok(())
+    }
+
+    async fn announcements(&self) -> Result<AnnouncementsConfig, Error> {
+        Ok(self.config.lock().await.announcements.clone())
+    }
+
+    async fn set_announcements(&self, config: AnnouncementsConfig) -> Result<(), Error> {
+        config.validate()?;
+        self.config.lock().await.announcements = config;
+        self.write_config_to_file().await
+    }
+
+    async fn test_announcement(&self, id: &str) -> Result<(), Error> {
+        if self.state().await != State::Running {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance is not running"),
+            });
         }
+        let announcement = self
+            .config
+            .lock()
+            .await
+            .announcements
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Announcement not found"),
+            })?;
+        self.send_announcement(&announcement).await
     }
 }
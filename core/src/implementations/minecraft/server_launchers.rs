@@ -0,0 +1,177 @@
+//! Fabric and Quilt server launchers only depend on the game and loader version, so they are
+//! kept in the binaries directory and copied into every instance that needs them
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use once_cell::sync::Lazy;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+use crate::downloads::download_verified;
+use crate::error::Error;
+use crate::prelude::{path_to_binaries, path_to_tmp};
+use crate::util::DownloadProgress;
+
+use super::util::run_installer;
+use super::{FabricLoaderVersion, Flavour, QuiltLoaderVersion};
+
+/// What Quilt servers are launched with, the vanilla `server.jar` is installed next to it
+pub const QUILT_LAUNCHER: &str = "quilt-server-launch.jar";
+pub const QUILT_INSTALLER: &str = "quilt-installer.jar";
+
+/// Held while a launcher is put in the cache, so instances set up at once download it once
+static LAUNCHER_LOCKS: Lazy<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn path_to_launcher(loader: &str, minecraft_version: &str, loader_version: &str) -> PathBuf {
+    path_to_binaries()
+        .join("server_launchers")
+        .join(loader)
+        .join(minecraft_version)
+        .join(loader_version)
+}
+
+/// Copies the server launcher of a Fabric or Quilt `flavour` with resolved versions into the
+/// instance, downloading it from `url` first unless it is cached. For Quilt, `url` points to
+/// the installer, which is run with `jre`.
+///
+/// Returns whether the launcher had to be downloaded
+pub(super) async fn install_server_launcher(
+    flavour: &Flavour,
+    minecraft_version: &str,
+    url: &str,
+    jre: &Path,
+    path_to_instance: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+    on_output: &(dyn Fn(&str) + Send + Sync),
+) -> Result<bool, Error> {
+    let (loader, loader_version) = match flavour {
+        Flavour::Fabric {
+            loader_version: Some(FabricLoaderVersion(loader_version)),
+            ..
+        } => ("fabric", loader_version),
+        Flavour::Quilt {
+            loader_version: Some(QuiltLoaderVersion(loader_version)),
+            ..
+        } => ("quilt", loader_version),
+        _ => {
+            return Err(eyre!(
+                "No server launcher is cached for {} servers",
+                flavour.to_string()
+            )
+            .into())
+        }
+    };
+    let path_to_launcher = path_to_launcher(loader, minecraft_version, loader_version);
+    let lock = LAUNCHER_LOCKS
+        .lock()
+        .await
+        .entry(path_to_launcher.clone())
+        .or_default()
+        .clone();
+    let _guard = lock.lock().await;
+
+    let download = !path_to_launcher.is_dir();
+    if download {
+        let temp_dir = tempfile::tempdir_in(path_to_tmp()).context("Failed to create temp dir")?;
+        let staging = temp_dir.path().join(loader_version);
+        crate::util::fs::create_dir_all(&staging).await?;
+        if let Flavour::Quilt { .. } = flavour {
+            download_verified(
+                url,
+                None,
+                &temp_dir.path().join(QUILT_INSTALLER),
+                on_download,
+            )
+            .await?;
+            run_installer(
+                Command::new(jre)
+                    .arg("-jar")
+                    .arg(temp_dir.path().join(QUILT_INSTALLER))
+                    .arg("install")
+                    .arg("server")
+                    .arg(minecraft_version)
+                    .arg(loader_version)
+                    .arg("--download-server")
+                    .arg(format!("--install-dir={}", staging.display()))
+                    .current_dir(temp_dir.path()),
+                QUILT_INSTALLER,
+                on_output,
+            )
+            .await?;
+        } else {
+            download_verified(url, None, &staging.join("server.jar"), on_download).await?;
+        }
+        if let Some(parent) = path_to_launcher.parent() {
+            crate::util::fs::create_dir_all(parent).await?;
+        }
+        // the cache only ever holds complete launchers
+        crate::util::fs::rename(&staging, &path_to_launcher).await?;
+    }
+
+    let path_to_instance = path_to_instance.to_owned();
+    tokio::task::spawn_blocking(move || copy_dir_contents(&path_to_launcher, &path_to_instance))
+        .await
+        .context("Failed to copy the server launcher")??;
+    Ok(download)
+}
+
+fn copy_dir_contents(from: &Path, to: &Path) -> Result<(), Error> {
+    for entry in walkdir::WalkDir::new(from).min_depth(1) {
+        let entry = entry.context(format!("Failed to read {}", from.display()))?;
+        let relative = entry
+            .path()
+            .strip_prefix(from)
+            .context("Walked out of the launcher directory")?;
+        let target = to.join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target).context(format!(
+                "Failed to create directory at {}",
+                target.display()
+            ))?;
+        } else {
+            std::fs::copy(entry.path(), &target).context(format!(
+                "Failed to copy {} to {}",
+                entry.path().display(),
+                target.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_dir_contents() {
+        let from = tempfile::tempdir().unwrap();
+        let to = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(from.path().join("libraries/org/quiltmc")).unwrap();
+        std::fs::write(
+            from.path().join("libraries/org/quiltmc/loader.jar"),
+            "loader",
+        )
+        .unwrap();
+        std::fs::write(from.path().join(QUILT_LAUNCHER), "launcher").unwrap();
+        std::fs::write(to.path().join("server.properties"), "server-port=25565").unwrap();
+
+        copy_dir_contents(from.path(), to.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(to.path().join("libraries/org/quiltmc/loader.jar")).unwrap(),
+            "loader"
+        );
+        assert_eq!(
+            std::fs::read_to_string(to.path().join(QUILT_LAUNCHER)).unwrap(),
+            "launcher"
+        );
+        assert_eq!(
+            std::fs::read_to_string(to.path().join("server.properties")).unwrap(),
+            "server-port=25565"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+//! Server list ping, what the multiplayer menu uses to show a server's MOTD and player count.
+//!
+//! Unlike the log parsing, it works whatever the mods running on the server print
+
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::error::Error;
+use crate::traits::t_server::{State, TServer};
+
+use super::MinecraftInstance;
+
+pub const DEFAULT_PING_INTERVAL_SECS: u32 = 10;
+/// Consecutive failed pings of a running server before it is considered hung
+const FAILED_PINGS_BEFORE_DEGRADED: u32 = 3;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a disabled probe checks whether it was turned back on
+const DISABLED_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Status responses carry the server icon, but nothing near this
+const MAX_PACKET_LENGTH: usize = 2 * 1024 * 1024;
+
+/// What the server answered to a server list ping
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct ServerListPing {
+    /// The description with its formatting dropped
+    pub motd: String,
+    pub version: String,
+    pub protocol: i64,
+    pub online_players: u32,
+    pub max_players: u32,
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default)]
+#[ts(export)]
+pub struct PingStatus {
+    /// The last successful ping, kept while later ones fail
+    pub last_ping: Option<ServerListPing>,
+    /// Unix timestamp of the last successful ping
+    pub last_success: Option<i64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// The process is alive but the server stopped answering pings
+    pub degraded: bool,
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+async fn read_varint(reader: &mut (impl AsyncRead + Unpin)) -> Result<i32, Error> {
+    let mut value = 0u32;
+    for i in 0..5 {
+        let byte = reader
+            .read_u8()
+            .await
+            .context("Failed to read from the server")?;
+        value |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(eyre!("The server sent a VarInt longer than 5 bytes").into())
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_varint(buf, value.len() as i32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Prefixes the packet id and `data` with their length
+fn packet(id: i32, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_varint(&mut body, id);
+    body.extend_from_slice(data);
+    let mut packet = Vec::new();
+    write_varint(&mut packet, body.len() as i32);
+    packet.extend(body);
+    packet
+}
+
+fn handshake(host: &str, port: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    // -1 since the server's version isn't known yet, it answers a status request regardless
+    write_varint(&mut data, -1);
+    write_string(&mut data, host);
+    data.extend_from_slice(&port.to_be_bytes());
+    // the next state, 1 is status
+    write_varint(&mut data, 1);
+    packet(0x00, &data)
+}
+
+async fn read_status_response(stream: &mut (impl AsyncRead + Unpin)) -> Result<String, Error> {
+    let length = read_varint(stream).await?;
+    if length <= 0 || length as usize > MAX_PACKET_LENGTH {
+        return Err(eyre!("The server sent a packet of length {}", length).into());
+    }
+    let mut body = vec![0; length as usize];
+    stream
+        .read_exact(&mut body)
+        .await
+        .context("Failed to read the status response")?;
+    let mut body = body.as_slice();
+    let id = read_varint(&mut body).await?;
+    if id != 0x00 {
+        return Err(eyre!("Expected a status response, got packet {:#04x}", id).into());
+    }
+    let json_length = read_varint(&mut body).await?;
+    if json_length < 0 || json_length as usize > body.len() {
+        return Err(eyre!("The status response is cut short").into());
+    }
+    String::from_utf8(body[..json_length as usize].to_vec())
+        .context("The status response is not UTF-8")
+        .map_err(Into::into)
+}
+
+/// The plain text of a chat component, which older servers send as just a string
+fn chat_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(components) => components.iter().map(chat_text).collect(),
+        Value::Object(object) => {
+            let mut text = object
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            if let Some(extra) = object.get("extra") {
+                text.push_str(&chat_text(extra));
+            }
+            text
+        }
+        _ => String::new(),
+    }
+}
+
+fn parse_status(json: &str, latency: Duration) -> Result<ServerListPing, Error> {
+    let status: Value =
+        serde_json::from_str(json).context("The status response is not valid JSON")?;
+    Ok(ServerListPing {
+        motd: chat_text(&status["description"]),
+        version: status["version"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        protocol: status["version"]["protocol"].as_i64().unwrap_or(-1),
+        online_players: status["players"]["online"].as_u64().unwrap_or(0) as u32,
+        max_players: status["players"]["max"].as_u64().unwrap_or(0) as u32,
+        latency_ms: latency.as_millis() as u64,
+    })
+}
+
+/// Asks the server at `host:port` for its status, latency is how long the answer took
+pub async fn ping(host: &str, port: u16, timeout: Duration) -> Result<ServerListPing, Error> {
+    tokio::time::timeout(timeout, async {
+        let mut stream = TcpStream::connect((host, port))
+            .await
+            .context(format!("Failed to connect to {}:{}", host, port))?;
+        stream
+            .write_all(&handshake(host, port))
+            .await
+            .context("Failed to send the handshake")?;
+        let started = Instant::now();
+        stream
+            .write_all(&packet(0x00, &[]))
+            .await
+            .context("Failed to send the status request")?;
+        let json = read_status_response(&mut stream).await?;
+        parse_status(&json, started.elapsed())
+    })
+    .await
+    .map_err(|_| eyre!("The server did not answer within {:?}", timeout))?
+}
+
+impl MinecraftInstance {
+    pub async fn ping_status(&self) -> PingStatus {
+        self.ping_status.lock().await.clone()
+    }
+
+    async fn record_ping(&self, result: Result<ServerListPing, Error>) {
+        let mut status = self.ping_status.lock().await;
+        match result {
+            Ok(ping) => {
+                if status.degraded {
+                    info!(
+                        "[{}] Server answers pings again",
+                        self.config.lock().await.name
+                    );
+                }
+                status.last_ping = Some(ping);
+                status.last_success = Some(chrono::Utc::now().timestamp());
+                status.consecutive_failures = 0;
+                status.last_error = None;
+                status.degraded = false;
+            }
+            Err(e) => {
+                status.consecutive_failures += 1;
+                status.last_error = Some(e.source.to_string());
+                if !status.degraded && status.consecutive_failures >= FAILED_PINGS_BEFORE_DEGRADED {
+                    warn!(
+                        "[{}] Server is running but did not answer {} pings: {}",
+                        self.config.lock().await.name,
+                        status.consecutive_failures,
+                        e.source
+                    );
+                    status.degraded = true;
+                }
+            }
+        }
+        let degraded = status.degraded;
+        drop(status);
+        self.snapshot.update(|s| s.degraded = degraded);
+    }
+
+    /// Pings the server on its port while it runs, the probe ends once the process exits.
+    ///
+    /// The interval is read from the config before every ping, 0 disables the probe
+    pub(super) async fn spawn_ping_task(&self) {
+        *self.ping_status.lock().await = PingStatus::default();
+        let __self = self.clone();
+        let handle = tokio::task::spawn(async move {
+            loop {
+                let interval_secs = __self.config.lock().await.ping_interval_secs;
+                if interval_secs == 0 {
+                    tokio::time::sleep(DISABLED_POLL_INTERVAL).await;
+                } else {
+                    tokio::time::sleep(Duration::from_secs(interval_secs as u64)).await;
+                }
+                match __self.state().await {
+                    State::Running => {}
+                    State::Stopped | State::Error => break,
+                    _ => continue,
+                }
+                if interval_secs == 0 {
+                    if __self.ping_status.lock().await.degraded {
+                        __self.clear_ping_status().await;
+                    }
+                    continue;
+                }
+                let port = __self.config.lock().await.port;
+                let result = match u16::try_from(port) {
+                    Ok(port) => ping("127.0.0.1", port, PING_TIMEOUT).await,
+                    Err(_) => Err(eyre!("Port {} is out of range", port).into()),
+                };
+                __self.record_ping(result).await;
+            }
+            __self.clear_ping_status().await;
+        });
+        // a quick restart can outpace the previous task noticing the instance stopped
+        if let Some(previous) = self.ping_task.lock().await.replace(handle) {
+            previous.abort();
+        }
+    }
+
+    async fn clear_ping_status(&self) {
+        *self.ping_status.lock().await = PingStatus::default();
+        self.snapshot.update(|s| s.degraded = false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_varint() {
+        for (value, encoded) in [
+            (0, vec![0x00]),
+            (300, vec![0xac, 0x02]),
+            (2147483647, vec![0xff, 0xff, 0xff, 0xff, 0x07]),
+            (-1, vec![0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(buf, encoded);
+            assert_eq!(read_varint(&mut encoded.as_slice()).await.unwrap(), value);
+        }
+        assert!(read_varint(&mut [0xff; 6].as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let ping = parse_status(
+            r#"{
+                "version": {"name": "1.20.1", "protocol": 763},
+                "players": {"max": 20, "online": 3, "sample": []},
+                "description": {"text": "A ", "extra": [{"text": "Minecraft"}, " Server"]}
+            }"#,
+            Duration::from_millis(12),
+        )
+        .unwrap();
+        assert_eq!(
+            ping,
+            ServerListPing {
+                motd: "A Minecraft Server".to_string(),
+                version: "1.20.1".to_string(),
+                protocol: 763,
+                online_players: 3,
+                max_players: 20,
+                latency_ms: 12,
+            }
+        );
+        let ping = parse_status(
+            r#"{"description": "Legacy", "players": {"max": 5, "online": 0}}"#,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(ping.motd, "Legacy");
+        assert_eq!(ping.max_players, 5);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut expected = handshake("127.0.0.1", port);
+            expected.extend(packet(0x00, &[]));
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            let mut data = Vec::new();
+            write_string(
+                &mut data,
+                r#"{"version":{"name":"Paper 1.20.1","protocol":763},"players":{"max":20,"online":1},"description":"hi"}"#,
+            );
+            stream.write_all(&packet(0x00, &data)).await.unwrap();
+        });
+        let ping = ping("127.0.0.1", port, Duration::from_secs(5))
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(ping.version, "Paper 1.20.1");
+        assert_eq!(ping.online_players, 1);
+        assert_eq!(ping.motd, "hi");
+    }
+}
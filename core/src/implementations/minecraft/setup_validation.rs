@@ -0,0 +1,62 @@
+use sysinfo::{System, SystemExt};
+
+use crate::error::Error;
+use crate::traits::t_configurable::manifest::{
+    ConfigurableValue, SettingValidationError, SetupValue,
+};
+
+use super::jvm_args::{split_args, validate_jvm_args, validate_ram};
+use super::versions::check_loader_version;
+use super::{FlavourKind, MinecraftInstance};
+
+impl MinecraftInstance {
+    /// Every problem with a setup value by setting, checked the way
+    /// [`MinecraftInstance::construct_setup_config`] reads it and without creating anything. The
+    /// setup manifest only offers versions that exist, so a typo'd version is refused here
+    /// instead of halfway through the setup. Name and port depend on the other instances and are
+    /// left to the caller
+    pub async fn setup_value_errors(
+        setup_value: &SetupValue,
+        flavour: FlavourKind,
+    ) -> Result<Vec<SettingValidationError>, Error> {
+        let mut errors = Self::setup_manifest(&flavour)
+            .await?
+            .setup_value_errors(setup_value);
+        let value = |setting_id: &str| {
+            setup_value
+                .get_unique_setting(setting_id)
+                .and_then(|setting| setting.get_value())
+        };
+
+        if let (
+            Some(ConfigurableValue::UnsignedInteger(min_ram)),
+            Some(ConfigurableValue::UnsignedInteger(max_ram)),
+        ) = (value("min_ram"), value("max_ram"))
+        {
+            let mut system = System::new();
+            system.refresh_memory();
+            if let Err(e) = validate_ram(*min_ram, *max_ram, system.total_memory() / 1024 / 1024) {
+                errors.push(SettingValidationError::new("max_ram", e.source));
+            }
+        }
+
+        if let Some(ConfigurableValue::String(args)) = value("cmd_args") {
+            if let Err(e) = validate_jvm_args(&split_args(args)) {
+                errors.push(SettingValidationError::new("cmd_args", e.source));
+            }
+        }
+
+        // the loader can only be looked up for a version that exists
+        let version_refused = errors.iter().any(|error| error.setting_id == "version");
+        if let (Some(ConfigurableValue::Enum(version)), Some(ConfigurableValue::Enum(loader))) =
+            (value("version"), value("loader_version"))
+        {
+            if !version_refused {
+                if let Err(e) = check_loader_version(flavour, version, loader).await {
+                    errors.push(SettingValidationError::new("loader_version", e.source));
+                }
+            }
+        }
+        Ok(errors)
+    }
+}
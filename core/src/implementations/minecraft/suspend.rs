@@ -0,0 +1,279 @@
+//! Low-power mode: an idle server's processes are frozen with SIGSTOP and thawed with SIGCONT.
+//!
+//! A frozen server keeps its port, so nothing else can listen on it for a wake up. Connections
+//! to it still wait in the kernel's accept queue though, which the idle watcher reads on Linux.
+//! The client that knocked is answered as soon as the server is resumed
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::eyre;
+use tracing::{info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_server::{invalid_transition, State, StateAction, TServer};
+use crate::types::Snowflake;
+
+use super::MinecraftInstance;
+
+/// How often the idle watcher looks at the player count, and at the port while suspended
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// `st` of a listening socket in /proc/net/tcp
+const TCP_LISTEN: &str = "0A";
+
+/// Connections waiting to be accepted on `port`, read from the contents of /proc/net/tcp or
+/// /proc/net/tcp6. For a listening socket the kernel reports its accept queue as `rx_queue`
+fn pending_connections(table: &str, port: u16) -> u32 {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local_port = u16::from_str_radix(fields.get(1)?.rsplit(':').next()?, 16).ok()?;
+            if local_port != port || *fields.get(3)? != TCP_LISTEN {
+                return None;
+            }
+            u32::from_str_radix(fields.get(4)?.split(':').nth(1)?, 16).ok()
+        })
+        .sum()
+}
+
+/// Whether a client is waiting on the suspended server's port
+#[cfg(target_os = "linux")]
+async fn has_pending_connections(port: u16) -> bool {
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        if let Ok(content) = tokio::fs::read_to_string(table).await {
+            if pending_connections(&content, port) > 0 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Elsewhere there is no accept queue to read, a suspended server is resumed by hand
+#[cfg(not(target_os = "linux"))]
+async fn has_pending_connections(_port: u16) -> bool {
+    false
+}
+
+/// SIGSTOP to suspend, SIGCONT to resume
+#[cfg(unix)]
+fn signal_server(pid: u32, suspend: bool) -> Result<(), Error> {
+    let signal = if suspend {
+        libc::SIGSTOP
+    } else {
+        libc::SIGCONT
+    };
+    // SAFETY: killpg only sends a signal. The server leads its own group since it was spawned
+    // with process_group(0), so its children are frozen and thawed along with it
+    if unsafe { libc::killpg(pid as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Failed to signal the server: {}",
+            std::io::Error::last_os_error()
+        )
+        .into())
+    }
+}
+
+#[cfg(not(unix))]
+fn signal_server(_pid: u32, _suspend: bool) -> Result<(), Error> {
+    Err(Error {
+        kind: ErrorKind::UnsupportedOperation,
+        source: eyre!("Suspending a server is only supported on Unix"),
+    })
+}
+
+impl MinecraftInstance {
+    /// Signals the server and moves it to `Suspended` or back to `Running`
+    async fn freeze_or_thaw(&self, suspend: bool, caused_by: CausedBy) -> Result<(), Error> {
+        let name = self.config.lock().await.name.clone();
+        let pid = self.pid().await;
+        let mut state = self.state.lock().await;
+        let current = *state;
+        let to = state.try_new_state(
+            if suspend {
+                StateAction::UserSuspend
+            } else {
+                StateAction::UserResume
+            },
+            None,
+        )?;
+        let pid = pid.ok_or_else(|| invalid_transition(current, "The server has no process"))?;
+        signal_server(pid, suspend)?;
+        *state = to;
+        drop(state);
+        info!(
+            "[{}] Server {}",
+            name,
+            if suspend { "suspended" } else { "resumed" }
+        );
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_name: name,
+                instance_uuid: self.uuid.clone(),
+                instance_event_inner: InstanceEventInner::StateTransition { to },
+            }),
+            snowflake: Snowflake::default(),
+            details: if suspend {
+                "Suspending server".to_string()
+            } else {
+                "Resuming server".to_string()
+            },
+            caused_by,
+        });
+        Ok(())
+    }
+
+    pub(super) async fn suspend_server(&self, caused_by: CausedBy) -> Result<(), Error> {
+        let _transition = self.transition_lock.acquire().await;
+        self.freeze_or_thaw(true, caused_by).await
+    }
+
+    /// Resumes the server, the caller holds the transition lock
+    pub(super) async fn resume_locked(&self, caused_by: CausedBy) -> Result<(), Error> {
+        self.freeze_or_thaw(false, caused_by).await
+    }
+
+    pub(super) async fn resume_server(&self, caused_by: CausedBy) -> Result<(), Error> {
+        let _transition = self.transition_lock.acquire().await;
+        self.resume_locked(caused_by).await
+    }
+
+    pub(super) async fn set_auto_suspend_minutes(&self, minutes: Option<u32>) -> Result<(), Error> {
+        if minutes == Some(0) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Auto suspend needs at least a minute, leave it empty to turn it off"
+                ),
+            });
+        }
+        self.config.lock().await.auto_suspend_minutes = minutes;
+        self.write_config_to_file().await
+    }
+
+    /// Fails if `path` is in, or holds, the active world while the server is suspended. The
+    /// world is resumed with whatever it had in memory, files changed underneath would corrupt it
+    pub async fn ensure_world_writable(&self, path: &Path) -> Result<(), Error> {
+        if *self.state.lock().await != State::Suspended {
+            return Ok(());
+        }
+        let world = self.path_to_instance.join(self.active_world().await);
+        if path.starts_with(&world) || world.starts_with(path) {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("The server is suspended, resume it before changing its world"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Suspends the server after `auto_suspend_minutes` without players and resumes it when a
+    /// client connects, the watcher ends once the process exits
+    pub(super) async fn spawn_idle_task(&self) {
+        let __self = self.clone();
+        let handle = tokio::task::spawn(async move {
+            let mut empty_since: Option<Instant> = None;
+            loop {
+                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                let (minutes, port) = {
+                    let config = __self.config.lock().await;
+                    (config.auto_suspend_minutes, config.port)
+                };
+                match __self.state().await {
+                    State::Running => {}
+                    State::Suspended => {
+                        empty_since = None;
+                        // without auto suspend it stays suspended until resumed by hand
+                        if minutes.is_none() {
+                            continue;
+                        }
+                        let port = match u16::try_from(port) {
+                            Ok(port) => port,
+                            Err(_) => continue,
+                        };
+                        if has_pending_connections(port).await {
+                            if let Err(e) = __self.resume_server(CausedBy::System).await {
+                                warn!("[{}] Failed to resume the server: {}", __self.uuid, e);
+                            }
+                        }
+                        continue;
+                    }
+                    State::Stopped | State::Error => break,
+                    _ => {
+                        empty_since = None;
+                        continue;
+                    }
+                }
+                let minutes = match minutes {
+                    Some(minutes) => minutes,
+                    None => {
+                        empty_since = None;
+                        continue;
+                    }
+                };
+                if __self.players_manager.lock().await.count() > 0 {
+                    empty_since = None;
+                    continue;
+                }
+                let since = *empty_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= Duration::from_secs(minutes as u64 * 60) {
+                    empty_since = None;
+                    if let Err(e) = __self.suspend_server(CausedBy::System).await {
+                        warn!("[{}] Failed to suspend the server: {}", __self.uuid, e);
+                    }
+                }
+            }
+        });
+        // a quick restart can outpace the previous task noticing the instance stopped
+        if let Some(previous) = self.idle_task.lock().await.replace(handle) {
+            previous.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_connections() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:63DD 00000000:0000 0A 00000000:00000002 00:00000000 00000000  1000        0 31337 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:63DD 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000  1000        0 31338 1 0000000000000000 20 4 30 10 -1
+   2: 00000000:0050 00000000:0000 0A 00000000:00000005 00:00000000 00000000     0        0 31339 1 0000000000000000 100 0 0 10 0";
+        assert_eq!(pending_connections(table, 25565), 2);
+        assert_eq!(pending_connections(table, 80), 5);
+        assert_eq!(pending_connections(table, 25566), 0);
+        assert_eq!(pending_connections("", 25565), 0);
+    }
+
+    #[test]
+    fn test_suspend_transitions() {
+        let mut state = State::Running;
+        state
+            .try_transition(StateAction::UserSuspend, None)
+            .unwrap();
+        assert_eq!(state, State::Suspended);
+        assert!(state.try_new_state(StateAction::UserSuspend, None).is_err());
+        assert!(state.try_new_state(StateAction::UserStart, None).is_err());
+        assert!(state.try_new_state(StateAction::UserStop, None).is_err());
+        // the process can still exit, e.g. when it's killed
+        assert_eq!(
+            state
+                .try_new_state(StateAction::InstanceStop, None)
+                .unwrap(),
+            State::Stopped
+        );
+        state.try_transition(StateAction::UserResume, None).unwrap();
+        assert_eq!(state, State::Running);
+        assert!(state.try_new_state(StateAction::UserResume, None).is_err());
+        assert!(State::Stopped
+            .try_new_state(StateAction::UserSuspend, None)
+            .is_err());
+    }
+}
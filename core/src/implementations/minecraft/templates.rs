@@ -0,0 +1,130 @@
+//! Capturing the settings of a server into a template, and applying a template to a freshly set
+//! up server
+
+use indexmap::IndexMap;
+use tracing::warn;
+
+use crate::auth::user::User;
+use crate::error::Error;
+use crate::templates::{copy_template_files, Template, TemplateSettings, Templates};
+use crate::traits::t_configurable::TConfigurable;
+
+use super::configurable::CmdArgSetting;
+use super::{FlavourKind, MinecraftInstance};
+
+/// Properties a new instance keeps its own values of
+const INSTANCE_PROPERTIES: [&str; 4] = ["server-port", "query.port", "rcon.port", "rcon.password"];
+
+impl MinecraftInstance {
+    pub async fn flavour_kind(&self) -> FlavourKind {
+        FlavourKind::from(&self.config.lock().await.flavour)
+    }
+
+    pub async fn template_settings(&self) -> Result<TemplateSettings, Error> {
+        let java_cmd = CmdArgSetting::JavaCmd(String::new()).get_identifier();
+        let cmd_args = self
+            .configurable_manifest()
+            .await
+            .get_section(CmdArgSetting::get_section_id())
+            .map(|section| {
+                section
+                    .all_settings()
+                    .iter()
+                    .filter(|(id, _)| id.as_str() != java_cmd)
+                    .filter_map(|(id, setting)| Some((id.clone(), setting.get_value()?.clone())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let game_settings = self
+            .game_settings()
+            .await?
+            .into_iter()
+            .filter(|(key, _)| !INSTANCE_PROPERTIES.contains(&key.as_str()))
+            .filter_map(|(key, setting)| Some((key, setting.get_value()?.clone())))
+            .collect();
+        let config = self.config.lock().await;
+        Ok(TemplateSettings {
+            cmd_args,
+            restart_policy: config.restart_policy(),
+            auto_suspend_minutes: config.auto_suspend_minutes,
+            game_settings,
+        })
+    }
+
+    /// Copies the template's files over the server's and merges its settings. A setting the
+    /// server or `requester` may not set, or a template made for another flavour or version, only
+    /// adds a warning
+    pub async fn apply_template(
+        &self,
+        template: &Template,
+        templates: &Templates,
+        requester: &User,
+    ) -> Result<Vec<String>, Error> {
+        let mut warnings = Vec::new();
+        let (flavour, version) = {
+            let config = self.config.lock().await;
+            (FlavourKind::from(&config.flavour), config.version.clone())
+        };
+        if template.flavour != flavour {
+            warnings.push(format!(
+                "The template was made for {} servers, this one is {}",
+                template.flavour.to_string(),
+                flavour.to_string()
+            ));
+        }
+        if template.version != version {
+            warnings.push(format!(
+                "The template was made for Minecraft {}, this server runs {}",
+                template.version, version
+            ));
+        }
+        copy_template_files(&templates.files_dir(&template.id), &self.path_to_instance).await?;
+
+        let settings = &template.settings;
+        let section_id = CmdArgSetting::get_section_id();
+        for (id, value) in &settings.cmd_args {
+            // held to what the requester could set through the settings endpoint
+            let applied = match self
+                .check_setting_update(requester, section_id, id, value)
+                .await
+            {
+                Ok(()) => {
+                    self.update_configurable(section_id, id, value.clone())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = applied {
+                warnings.push(format!("{} was left as it was: {}", id, e.source));
+            }
+        }
+        if let Err(e) = self.set_restart_policy(settings.restart_policy).await {
+            warnings.push(format!(
+                "The restart policy was left as it was: {}",
+                e.source
+            ));
+        }
+        if let Err(e) = self
+            .set_auto_suspend_minutes(settings.auto_suspend_minutes)
+            .await
+        {
+            warnings.push(format!("Auto suspend was left as it was: {}", e.source));
+        }
+        // one at a time, so a property this version doesn't know only skips itself
+        for (key, value) in &settings.game_settings {
+            if INSTANCE_PROPERTIES.contains(&key.as_str()) {
+                continue;
+            }
+            if let Err(e) = self
+                .set_game_settings(IndexMap::from([(key.clone(), value.clone())]))
+                .await
+            {
+                warnings.push(format!("{} was left as it was: {}", key, e.source));
+            }
+        }
+        for warning in &warnings {
+            warn!("[{}] Template {}: {}", self.uuid, template.name, warning);
+        }
+        Ok(warnings)
+    }
+}
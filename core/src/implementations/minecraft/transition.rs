@@ -0,0 +1,268 @@
+//! Starts, stops and restarts of a server run one at a time, whether they come from the
+//! dashboard, a macro, the scheduler or an automatic restart
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::error::Error;
+use crate::events::{EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_server::{invalid_transition, State, TServer};
+
+use super::MinecraftInstance;
+
+/// Held while a transition is under way, a second one waits for it and then checks the state
+/// the first one left behind
+#[derive(Clone, Default)]
+pub struct TransitionLock(Arc<Mutex<()>>);
+
+impl TransitionLock {
+    pub async fn acquire(&self) -> OwnedMutexGuard<()> {
+        self.0.clone().lock_owned().await
+    }
+}
+
+/// What a restart does, depending on the state it finds the server in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPlan {
+    StopAndStart,
+    /// Lets the start under way finish, then restarts
+    AwaitRunning,
+    /// Lets the stop under way finish, then starts
+    AwaitStopped,
+}
+
+impl RestartPlan {
+    pub fn for_state(state: State) -> Result<Self, Error> {
+        match state {
+            // stopping resumes a suspended server first
+            State::Running | State::Suspended => Ok(RestartPlan::StopAndStart),
+            State::Starting => Ok(RestartPlan::AwaitRunning),
+            State::Stopping => Ok(RestartPlan::AwaitStopped),
+            State::Stopped | State::Error => Err(invalid_transition(
+                state,
+                "Cannot restart an instance that is not running",
+            )),
+        }
+    }
+}
+
+impl MinecraftInstance {
+    /// Waits until the server is in one of `states` and returns it.
+    ///
+    /// `None` if it didn't get there within `timeout`
+    pub(super) async fn await_state(
+        &self,
+        states: &[State],
+        timeout: Option<Duration>,
+    ) -> Option<State> {
+        // subscribed before looking at the state, so a transition in between isn't missed
+        let mut rx = self.event_broadcaster.subscribe();
+        let wait = async {
+            loop {
+                let state = self.state().await;
+                if states.contains(&state) {
+                    return Some(state);
+                }
+                loop {
+                    match rx.recv().await {
+                        Ok(event) => {
+                            if let EventInner::InstanceEvent(InstanceEvent {
+                                instance_uuid,
+                                instance_event_inner: InstanceEventInner::StateTransition { to },
+                                ..
+                            }) = event.event_inner
+                            {
+                                if instance_uuid == self.uuid && states.contains(&to) {
+                                    return Some(to);
+                                }
+                            }
+                        }
+                        // missed transitions, the state is looked at again
+                        Err(RecvError::Lagged(_)) => break,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait).await.ok().flatten(),
+            None => wait.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::traits::t_server::StateAction;
+
+    /// Goes through the same transitions as the server, counting the processes it has up
+    #[derive(Clone)]
+    struct FakeServer {
+        transition_lock: TransitionLock,
+        state: Arc<Mutex<State>>,
+        children: Arc<AtomicUsize>,
+        most_children: Arc<AtomicUsize>,
+        /// How long spawning or stopping the process takes
+        delay: Duration,
+    }
+
+    impl FakeServer {
+        fn new(delay: Duration) -> Self {
+            FakeServer {
+                transition_lock: TransitionLock::default(),
+                state: Arc::new(Mutex::new(State::Stopped)),
+                children: Arc::new(AtomicUsize::new(0)),
+                most_children: Arc::new(AtomicUsize::new(0)),
+                delay,
+            }
+        }
+
+        /// Polls until the server is in `state`
+        async fn reach(&self, state: State) {
+            while *self.state.lock().await != state {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        async fn start(&self) -> Result<(), Error> {
+            let _transition = self.transition_lock.acquire().await;
+            self.state
+                .lock()
+                .await
+                .try_transition(StateAction::UserStart, None)?;
+            // spawning takes a while, long enough for other calls to pile up
+            tokio::time::sleep(self.delay).await;
+            let children = self.children.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_children.fetch_max(children, Ordering::SeqCst);
+            self.state
+                .lock()
+                .await
+                .try_transition(StateAction::InstanceStart, None)
+        }
+
+        async fn stop(&self) -> Result<(), Error> {
+            let _transition = self.transition_lock.acquire().await;
+            self.state
+                .lock()
+                .await
+                .try_transition(StateAction::UserStop, None)?;
+            tokio::time::sleep(self.delay).await;
+            self.children.fetch_sub(1, Ordering::SeqCst);
+            self.state
+                .lock()
+                .await
+                .try_transition(StateAction::InstanceStop, None)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_starts_and_stops() {
+        let server = FakeServer::new(Duration::from_millis(1));
+        let calls: Vec<_> = (0..64)
+            .map(|i| {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if i % 3 == 2 {
+                        server.stop().await
+                    } else {
+                        server.start().await
+                    }
+                })
+            })
+            .collect();
+        for call in calls {
+            if let Err(e) = call.await.unwrap() {
+                assert_eq!(e.code(), ErrorCode::InvalidStateTransition);
+            }
+        }
+        assert_eq!(server.most_children.load(Ordering::SeqCst), 1);
+        let children = server.children.load(Ordering::SeqCst);
+        match *server.state.lock().await {
+            State::Running => assert_eq!(children, 1),
+            state => assert_eq!((state, children), (State::Stopped, 0)),
+        }
+    }
+
+    fn assert_refused(result: Result<(), Error>, state: &str) {
+        let error = result.unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidStateTransition);
+        assert_eq!(
+            serde_json::to_value(&error).unwrap()["details"],
+            serde_json::json!({ "state": state })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_double_start() {
+        let server = FakeServer::new(Duration::from_millis(50));
+        let first = tokio::spawn({
+            let server = server.clone();
+            async move { server.start().await }
+        });
+        server.reach(State::Starting).await;
+        // waits for the first start, then finds the server running
+        assert_refused(server.start().await, "Running");
+        first.await.unwrap().unwrap();
+        assert_eq!(*server.state.lock().await, State::Running);
+        assert_eq!(server.most_children.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stop_during_start() {
+        let server = FakeServer::new(Duration::from_millis(50));
+        let start = tokio::spawn({
+            let server = server.clone();
+            async move { server.start().await }
+        });
+        server.reach(State::Starting).await;
+        // queued behind the start rather than refused for the server not running yet
+        server.stop().await.unwrap();
+        start.await.unwrap().unwrap();
+        assert_eq!(*server.state.lock().await, State::Stopped);
+        assert_eq!(server.children.load(Ordering::SeqCst), 0);
+        assert_refused(server.stop().await, "Stopped");
+    }
+
+    #[tokio::test]
+    async fn test_start_during_stop() {
+        let server = FakeServer::new(Duration::from_millis(50));
+        server.start().await.unwrap();
+        let stop = tokio::spawn({
+            let server = server.clone();
+            async move { server.stop().await }
+        });
+        server.reach(State::Stopping).await;
+        // the old process is gone before the new one is spawned
+        server.start().await.unwrap();
+        stop.await.unwrap().unwrap();
+        assert_eq!(*server.state.lock().await, State::Running);
+        assert_eq!(server.children.load(Ordering::SeqCst), 1);
+        assert_eq!(server.most_children.load(Ordering::SeqCst), 1);
+        assert_refused(server.start().await, "Running");
+    }
+
+    #[test]
+    fn test_restart_plan() {
+        assert_eq!(
+            RestartPlan::for_state(State::Starting).unwrap(),
+            RestartPlan::AwaitRunning
+        );
+        assert_eq!(
+            RestartPlan::for_state(State::Stopping).unwrap(),
+            RestartPlan::AwaitStopped
+        );
+        let error = RestartPlan::for_state(State::Stopped).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidStateTransition);
+        assert_eq!(
+            serde_json::to_value(&error).unwrap()["details"],
+            serde_json::json!({ "state": "Stopped" })
+        );
+    }
+}
@@ -1,13 +1,26 @@
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 use indexmap::IndexMap;
 use serde_json::{self, Value};
-use std::{collections::BTreeMap, path::Path, str::FromStr};
-use tokio::io::AsyncBufReadExt;
+use std::process::Stdio;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::Path,
+    str::FromStr,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
 
 use super::{
     FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, PaperBuildVersion,
+    QuiltInstallerVersion, QuiltLoaderVersion,
 };
+use crate::downloads::Checksum;
 use crate::error::Error;
+use crate::java::adoptium_url;
+use crate::util::dont_spawn_terminal;
+
+/// Installer output kept for the error when it fails
+const INSTALLER_OUTPUT_LINES: usize = 20;
 
 pub async fn read_properties_from_path(
     path_to_properties: &Path,
@@ -34,22 +47,159 @@ pub async fn read_properties_from_path(
         if line.starts_with('#') {
             continue;
         }
-        // split the line into key and value
-        let mut split = line.split('=');
-        let key = split
-            .next()
-            .ok_or_else(|| eyre!("Failed to read key from properties file"))?
-            .trim();
-        let value = split
-            .next()
-            .ok_or_else(|| eyre!("Failed to read value from properties file for key {}", key))?
-            .trim();
+        // split the line into key and value, an escaped value may contain more '='
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            eyre!(
+                "Failed to read value from properties file for key {}",
+                line.trim()
+            )
+        })?;
+        let (key, value) = (key.trim(), value.trim());
 
         ret.insert(key.to_string(), value.to_string());
     }
     Ok(ret)
 }
 
+/// Applies `updates` to the content of a properties file in place.
+///
+/// Comments, blank lines and keys not updated are kept as they are, keys not in the file yet are
+/// appended
+pub fn merge_properties(content: &str, updates: &IndexMap<String, String>) -> String {
+    let mut written = std::collections::HashSet::new();
+    let mut merged = String::new();
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        let key = if trimmed.starts_with('#') || trimmed.starts_with('!') {
+            None
+        } else {
+            trimmed.split_once('=').map(|(key, _)| key.trim())
+        };
+        match key.and_then(|key| updates.get_key_value(key)) {
+            Some((key, value)) if written.insert(key) => {
+                merged.push_str(&format!("{}={}\n", key, value));
+            }
+            // a duplicated key would shadow the update, so only the first one is kept
+            Some(_) => {}
+            None => {
+                merged.push_str(line);
+                merged.push('\n');
+            }
+        }
+    }
+    for (key, value) in updates {
+        if !written.contains(key) {
+            merged.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    merged
+}
+
+/// Escapes a value for a properties file the way Java writes one. Backslashes, separators
+/// and line breaks are escaped and anything outside printable ASCII becomes a `\uXXXX`
+/// sequence, so the file reads the same whichever charset the server assumes
+pub fn escape_property_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '=' | ':' | '#' | '!' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // a leading space would be trimmed when the file is read
+            ' ' if i == 0 => escaped.push_str("\\ "),
+            ' '..='~' => escaped.push(c),
+            _ => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    escaped.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// Reverses [`escape_property_value`], unknown escapes resolve to the escaped character
+pub fn unescape_property_value(value: &str) -> String {
+    let mut units: Vec<u16> = Vec::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('f') => '\x0c',
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    match u16::from_str_radix(&hex, 16) {
+                        Ok(unit) => units.push(unit),
+                        Err(_) => units.extend("\\u".encode_utf16().chain(hex.encode_utf16())),
+                    }
+                    continue;
+                }
+                Some(c) => c,
+                None => break,
+            },
+            c => c,
+        };
+        let mut buf = [0u16; 2];
+        units.extend_from_slice(c.encode_utf16(&mut buf));
+    }
+    String::from_utf16_lossy(&units)
+}
+
+/// Runs a loader's installer headless, `on_output` sees every line it prints
+pub async fn run_installer(
+    command: &mut Command,
+    name: &str,
+    on_output: &(dyn Fn(&str) + Send + Sync),
+) -> Result<(), Error> {
+    let mut installer = dont_spawn_terminal(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .spawn()
+        .context(format!("Failed to start {}", name))?;
+    let stdout = installer
+        .stdout
+        .take()
+        .ok_or_else(|| eyre!("Failed to take the output of {}", name))?;
+    let mut reader = BufReader::new(stdout);
+    let mut tail = VecDeque::with_capacity(INSTALLER_OUTPUT_LINES);
+    let mut line = Vec::new();
+    while reader
+        .read_until(b'\n', &mut line)
+        .await
+        .context(format!("Failed to read the output of {}", name))?
+        > 0
+    {
+        let text = String::from_utf8_lossy(&line).trim_end().to_string();
+        line.clear();
+        on_output(&text);
+        if tail.len() == INSTALLER_OUTPUT_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(text);
+    }
+    let status = installer.wait().await.context(format!("{} failed", name))?;
+    if !status.success() {
+        return Err(eyre!(
+            "{} exited with {}:\n{}",
+            name,
+            status,
+            Vec::from(tail).join("\n")
+        )
+        .into());
+    }
+    Ok(())
+}
+
 // Returns the jar url and the updated flavour with version information
 pub async fn get_server_jar_url(version: &str, flavour: &Flavour) -> Option<(String, Flavour)> {
     match flavour {
@@ -58,13 +208,20 @@ pub async fn get_server_jar_url(version: &str, flavour: &Flavour) -> Option<(Str
             loader_version,
             installer_version,
         } => get_fabric_jar_url(version, loader_version, installer_version).await,
+        Flavour::Quilt {
+            loader_version,
+            installer_version,
+        } => get_quilt_installer_url(version, loader_version, installer_version)
+            .await
+            .ok(),
         Flavour::Paper { build_version } => get_paper_jar_url(version, build_version).await,
         Flavour::Spigot => todo!(),
         Flavour::Forge { build_version } => get_forge_jar_url(version, build_version).await.ok(),
     }
 }
 
-pub async fn get_vanilla_jar_url(version: &str) -> Option<(String, Flavour)> {
+/// The server download Mojang lists for the version, with its url and sha1
+async fn get_vanilla_server_download(version: &str) -> Option<Value> {
     let client = reqwest::Client::new();
     let response_text = client
         .get("https://launchermeta.mojang.com/mc/game/version_manifest.json")
@@ -95,43 +252,58 @@ pub async fn get_vanilla_jar_url(version: &str) -> Option<(String, Flavour)> {
     if response["downloads"]["server"]["url"] == serde_json::Value::Null {
         return None;
     }
+    Some(response["downloads"]["server"].clone())
+}
 
+pub async fn get_vanilla_jar_url(version: &str) -> Option<(String, Flavour)> {
+    let download = get_vanilla_server_download(version).await?;
     Some((
-        response["downloads"]["server"]["url"]
-            .to_string()
-            .replace('\"', ""),
+        download["url"].to_string().replace('\"', ""),
         Flavour::Vanilla,
     ))
 }
 
+/// The checksum upstream publishes for the server jar of a flavour resolved by
+/// `get_server_jar_url`. Fabric, Quilt and Forge publish none for what is downloaded
+pub async fn get_server_jar_checksum(version: &str, flavour: &Flavour) -> Option<Checksum> {
+    match flavour {
+        Flavour::Vanilla => get_vanilla_server_download(version)
+            .await?
+            .get("sha1")?
+            .as_str()
+            .map(Checksum::sha1),
+        Flavour::Paper {
+            build_version: Some(PaperBuildVersion(build)),
+        } => {
+            let build: Value = reqwest::Client::new()
+                .get(format!(
+                    "https://api.papermc.io/v2/projects/paper/versions/{}/builds/{}",
+                    version, build
+                ))
+                .send()
+                .await
+                .ok()?
+                .json()
+                .await
+                .ok()?;
+            build["downloads"]["application"]["sha256"]
+                .as_str()
+                .map(Checksum::sha256)
+        }
+        _ => None,
+    }
+}
+
 pub async fn get_fabric_jar_url(
     version: &str,
     fabric_loader_version: &Option<FabricLoaderVersion>,
     fabric_installer_version: &Option<FabricInstallerVersion>,
 ) -> Option<(String, Flavour)> {
-    let mut loader_version = String::new();
-    let mut installer_version = String::new();
     let client = reqwest::Client::new();
 
-    if let (Some(FabricLoaderVersion(l)), Some(FabricInstallerVersion(i))) =
-        (fabric_loader_version, fabric_installer_version)
-    {
-        loader_version = l.to_string();
-        installer_version = i.to_string();
-        return Some((
-            format!(
-                "https://meta.fabricmc.net/v2/versions/loader/{}/{}/{}/server/jar",
-                version, loader_version, installer_version
-            ),
-            Flavour::Fabric {
-                loader_version: Some(FabricLoaderVersion(loader_version)),
-                installer_version: Some(FabricInstallerVersion(installer_version)),
-            },
-        ));
-    }
-
-    if fabric_loader_version.is_none() {
-        loader_version = serde_json::Value::from_str(
+    let loader_version = match fabric_loader_version {
+        Some(FabricLoaderVersion(l)) => l.to_string(),
+        None => serde_json::Value::from_str(
             client
                 .get(format!(
                     "https://meta.fabricmc.net/v2/versions/loader/{}",
@@ -193,11 +365,12 @@ pub async fn get_fabric_jar_url(
         .get("loader")?
         .get("version")?
         .as_str()?
-        .to_string();
-    }
+        .to_string(),
+    };
 
-    if fabric_installer_version.is_none() {
-        installer_version = serde_json::Value::from_str(
+    let installer_version = match fabric_installer_version {
+        Some(FabricInstallerVersion(i)) => i.to_string(),
+        None => serde_json::Value::from_str(
             client
                 .get("https://meta.fabricmc.net/v2/versions/installer")
                 .send()
@@ -215,8 +388,6 @@ pub async fn get_fabric_jar_url(
         .max_by(|a, b| {
             // sort the version string in the form of "1.2.3"
             let a_version = a
-                .get("loader")
-                .unwrap()
                 .get("version")
                 .unwrap()
                 .as_str()
@@ -224,8 +395,6 @@ pub async fn get_fabric_jar_url(
                 .split('.')
                 .collect::<Vec<&str>>();
             let b_version = b
-                .get("loader")
-                .unwrap()
                 .get("version")
                 .unwrap()
                 .as_str()
@@ -243,8 +412,8 @@ pub async fn get_fabric_jar_url(
         })?
         .get("version")?
         .as_str()?
-        .to_string();
-    }
+        .to_string(),
+    };
     Some((
         format!(
             "https://meta.fabricmc.net/v2/versions/loader/{}/{}/{}/server/jar",
@@ -257,6 +426,76 @@ pub async fn get_fabric_jar_url(
     ))
 }
 
+/// Returns the url of the Quilt installer, Quilt doesn't serve a ready server launcher
+pub async fn get_quilt_installer_url(
+    version: &str,
+    quilt_loader_version: &Option<QuiltLoaderVersion>,
+    quilt_installer_version: &Option<QuiltInstallerVersion>,
+) -> Result<(String, Flavour), Error> {
+    let client = reqwest::Client::new();
+
+    let loader_version = match quilt_loader_version {
+        Some(QuiltLoaderVersion(l)) => l.to_string(),
+        None => {
+            let loaders: Value = client
+                .get(format!(
+                    "https://meta.quiltmc.org/v3/versions/loader/{}",
+                    version
+                ))
+                .send()
+                .await
+                .context("Failed to get quilt loader versions, http request failed")?
+                .json()
+                .await
+                .context("Failed to get quilt loader versions, response is not valid json")?;
+            // listed newest first, betas carry a pre-release suffix
+            loaders
+                .as_array()
+                .context("Failed to get quilt loader versions, response is not an array")?
+                .iter()
+                .filter_map(|loader| loader["loader"]["version"].as_str())
+                .find(|loader| !loader.contains('-'))
+                .with_context(|| format!("Quilt has no stable loader for version {}", version))?
+                .to_string()
+        }
+    };
+
+    let installers: Value = client
+        .get("https://meta.quiltmc.org/v3/versions/installer")
+        .send()
+        .await
+        .context("Failed to get quilt installer versions, http request failed")?
+        .json()
+        .await
+        .context("Failed to get quilt installer versions, response is not valid json")?;
+    let installers = installers
+        .as_array()
+        .context("Failed to get quilt installer versions, response is not an array")?;
+    let installer = match quilt_installer_version {
+        Some(QuiltInstallerVersion(i)) => installers
+            .iter()
+            .find(|installer| installer["version"].as_str() == Some(i.as_str())),
+        None => installers.first(),
+    }
+    .context("Failed to get quilt installer versions, version not found")?;
+
+    Ok((
+        installer["url"]
+            .as_str()
+            .context("Failed to get quilt installer versions, url is not a string")?
+            .to_string(),
+        Flavour::Quilt {
+            loader_version: Some(QuiltLoaderVersion(loader_version)),
+            installer_version: Some(QuiltInstallerVersion(
+                installer["version"]
+                    .as_str()
+                    .context("Failed to get quilt installer versions, version is not a string")?
+                    .to_string(),
+            )),
+        },
+    ))
+}
+
 pub async fn get_paper_jar_url(
     version: &str,
     paper_build_version: &Option<PaperBuildVersion>,
@@ -357,16 +596,6 @@ pub async fn get_forge_jar_url(
 
 pub async fn get_jre_url(version: &str) -> Option<(String, u64)> {
     let client = reqwest::Client::new();
-    let os = if std::env::consts::OS == "macos" {
-        "mac"
-    } else {
-        std::env::consts::OS
-    };
-    let arch = if std::env::consts::ARCH == "x86_64" {
-        "x64"
-    } else {
-        std::env::consts::ARCH
-    };
 
     let major_java_version = {
         let val = match serde_json::Value::from_str(
@@ -414,13 +643,7 @@ pub async fn get_jre_url(version: &str) -> Option<(String, u64)> {
         }
     };
 
-    Some((
-        format!(
-            "https://api.adoptium.net/v3/binary/latest/{}/ga/{}/{}/jre/hotspot/normal/eclipse",
-            major_java_version, os, arch
-        ),
-        major_java_version,
-    ))
+    Some((adoptium_url(major_java_version as u32), major_java_version))
 }
 
 pub async fn name_to_uuid(name: impl AsRef<str>) -> Option<String> {
@@ -443,11 +666,43 @@ pub async fn name_to_uuid(name: impl AsRef<str>) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use crate::minecraft::{
-        util::{get_forge_jar_url, get_server_jar_url},
+        util::{
+            escape_property_value, get_forge_jar_url, get_server_jar_url, merge_properties,
+            unescape_property_value,
+        },
         FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, PaperBuildVersion,
     };
+    use indexmap::IndexMap;
     use tokio;
 
+    #[test]
+    fn test_merge_properties() {
+        let content = "#Minecraft server properties\n#Mon Jan 01 00:00:00 UTC 2024\nmax-players=20\n\nmotd=A Minecraft Server\ncustom-plugin-key=keep me\n";
+        let updates = IndexMap::from([
+            ("max-players".to_string(), "40".to_string()),
+            ("pvp".to_string(), "false".to_string()),
+        ]);
+        assert_eq!(
+            merge_properties(content, &updates),
+            "#Minecraft server properties\n#Mon Jan 01 00:00:00 UTC 2024\nmax-players=40\n\nmotd=A Minecraft Server\ncustom-plugin-key=keep me\npvp=false\n"
+        );
+        assert_eq!(merge_properties("", &IndexMap::new()), "");
+    }
+
+    #[test]
+    fn test_escape_property_value() {
+        let motd = "\u{a7}6Welcome\n\u{a7}r caf\u{e9} \u{1f525} C:\\ a=b";
+        let escaped = escape_property_value(motd);
+        assert_eq!(
+            escaped,
+            "\\u00A76Welcome\\n\\u00A7r caf\\u00E9 \\uD83D\\uDD25 C\\:\\\\ a\\=b"
+        );
+        assert!(escaped.is_ascii());
+        assert_eq!(unescape_property_value(&escaped), motd);
+        assert_eq!(escape_property_value(" padded"), "\\ padded");
+        assert_eq!(unescape_property_value("\\ padded"), " padded");
+    }
+
     #[tokio::test]
     async fn test_get_vanilla_jar_url() {
         assert_eq!(super::get_vanilla_jar_url("1.18.2").await, Some(("https://piston-data.mojang.com/v1/objects/c8f83c5655308435b3dcf03c06d9fe8740a77469/server.jar".to_string(), Flavour::Vanilla)));
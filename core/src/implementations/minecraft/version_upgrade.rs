@@ -0,0 +1,248 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+
+use crate::downloads::{download_jar, Checksum};
+use crate::error::{Error, ErrorKind};
+use crate::events::{Event, ProgressionEventID};
+use crate::java::{downloaded_runtime_dir, install_runtime, runtime_executable};
+use crate::prelude::path_to_tmp;
+use crate::traits::t_server::State;
+use crate::util::{format_byte, format_byte_download, DownloadProgress};
+
+use super::forge::{run_forge_installer, FORGE_INSTALLER};
+use super::server_launchers::{install_server_launcher, QUILT_INSTALLER};
+use super::util::{get_jre_url, get_server_jar_checksum, get_server_jar_url};
+use super::{Flavour, FlavourKind, MinecraftInstance};
+
+impl MinecraftInstance {
+    /// Installs another game version into the stopped instance, reporting to an existing
+    /// progression event.
+    ///
+    /// The new jar is downloaded next to the instance first, so a failed download leaves the
+    /// old jar and config untouched
+    pub async fn upgrade_version(
+        &self,
+        version: String,
+        progression_event_id: &ProgressionEventID,
+    ) -> Result<(), Error> {
+        if *self.state.lock().await != State::Stopped {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance must be stopped before changing its version"),
+            });
+        }
+        let (flavour, old_major, java_cmd) = {
+            let config = self.config.lock().await;
+            (
+                config.flavour.clone(),
+                config.jre_major_version,
+                config.java_cmd.clone(),
+            )
+        };
+        // builds pinned for the old version don't exist for the new one
+        let flavour = Flavour::from(FlavourKind::from(&flavour));
+        let flavour_name = flavour.to_string();
+        let (jar_url, flavour) = get_server_jar_url(&version, &flavour)
+            .await
+            .ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Could not find a {} server.jar for version {}",
+                    flavour_name,
+                    version
+                ),
+            })?;
+        let (_, jre_major_version) = get_jre_url(&version).await.ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Could not find the Java version {} needs", version),
+        })?;
+
+        let path_to_java_runtimes = self.path_to_runtimes.join("java");
+        let runtime_dir = downloaded_runtime_dir(&path_to_java_runtimes, jre_major_version as u32);
+        if !runtime_dir.exists() {
+            let event_broadcaster = self.event_broadcaster.clone();
+            install_runtime(
+                jre_major_version as u32,
+                &path_to_java_runtimes,
+                &move |dl| {
+                    if let Some(total) = dl.total {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            progression_event_id,
+                            format!(
+                                "2/3: Downloading JRE {}",
+                                format_byte_download(dl.downloaded, total)
+                            ),
+                            0.0,
+                        ));
+                    }
+                },
+            )
+            .await?;
+        }
+
+        let jar_name = match flavour {
+            Flavour::Forge { .. } => FORGE_INSTALLER,
+            Flavour::Quilt { .. } => QUILT_INSTALLER,
+            _ => "server.jar",
+        };
+        let on_download = {
+            let event_broadcaster = self.event_broadcaster.clone();
+            move |dl: DownloadProgress| {
+                let (downloaded, progress) = match dl.total {
+                    Some(total) => (
+                        format_byte_download(dl.downloaded, total),
+                        dl.step as f64 / total as f64,
+                    ),
+                    None => (format_byte(dl.downloaded), 0.0),
+                };
+                let speed = dl
+                    .speed
+                    .map(|speed| format!(" ({}/s)", format_byte(speed)))
+                    .unwrap_or_default();
+                event_broadcaster.send(Event::new_progression_event_update(
+                    progression_event_id,
+                    format!(
+                        "2/3: Downloading {} {} {}{}",
+                        flavour_name, jar_name, downloaded, speed
+                    ),
+                    progress,
+                ));
+            }
+        };
+        let on_output = |line: &str| {
+            self.event_broadcaster
+                .send(Event::new_progression_event_update(
+                    progression_event_id,
+                    format!("3/3: Installing {}: {}", version, line),
+                    0.0,
+                ));
+        };
+        let jre = runtime_executable(&runtime_dir);
+        if let Flavour::Fabric { .. } | Flavour::Quilt { .. } = flavour {
+            // launchers are completed in the shared cache before anything is copied over
+            install_server_launcher(
+                &flavour,
+                &version,
+                &jar_url,
+                &jre,
+                &self.path_to_instance,
+                &on_download,
+                &on_output,
+            )
+            .await?;
+        } else {
+            let checksum = get_server_jar_checksum(&version, &flavour).await;
+            swap_jar(
+                &jar_url,
+                checksum.as_ref(),
+                jar_name,
+                &path_to_tmp(),
+                &self.path_to_instance,
+                &on_download,
+            )
+            .await?;
+
+            self.event_broadcaster
+                .send(Event::new_progression_event_update(
+                    progression_event_id,
+                    format!("3/3: Installing {}", version),
+                    1.0,
+                ));
+            if let Flavour::Forge { .. } = flavour {
+                run_forge_installer(&jre, &self.path_to_instance, &on_output).await?;
+            }
+        }
+
+        let mut config = self.config.lock().await;
+        // a runtime lodestone downloaded for the old version is swapped for the new one's,
+        // a custom java command is left to the user
+        let uses_downloaded_java = java_cmd.map_or(true, |cmd| {
+            Path::new(&cmd).starts_with(&path_to_java_runtimes)
+        });
+        if uses_downloaded_java && old_major != jre_major_version {
+            config.java_cmd = Some(jre.to_string_lossy().to_string());
+        }
+        config.jre_major_version = jre_major_version;
+        config.version = version;
+        config.flavour = flavour;
+        drop(config);
+        self.write_config_to_file().await
+    }
+}
+
+/// Downloads the jar into a temporary directory under `temp_parent`, then moves it over the one
+/// in `path_to_instance`. The old jar stays as it was if the download fails
+async fn swap_jar(
+    jar_url: &str,
+    checksum: Option<&Checksum>,
+    jar_name: &str,
+    temp_parent: &Path,
+    path_to_instance: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<(), Error> {
+    crate::util::fs::create_dir_all(temp_parent).await?;
+    let temp_dir = tempfile::tempdir_in(temp_parent).context("Failed to create temp dir")?;
+    download_jar(
+        jar_url,
+        checksum,
+        &temp_dir.path().join(jar_name),
+        on_download,
+    )
+    .await?;
+    crate::util::fs::rename(
+        temp_dir.path().join(jar_name),
+        path_to_instance.join(jar_name),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::prelude::init_paths;
+
+    #[tokio::test]
+    async fn test_failed_download_keeps_old_jar() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let instance = tempfile::tempdir().unwrap();
+        let temp_parent = tempfile::tempdir().unwrap();
+        std::fs::write(instance.path().join("server.jar"), "old jar").unwrap();
+
+        // a download server that has no such jar
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/server.jar", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await;
+                let _ = socket
+                    .write_all(
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await;
+            }
+        });
+
+        let e = swap_jar(
+            &url,
+            None,
+            "server.jar",
+            temp_parent.path(),
+            instance.path(),
+            &|_| {},
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::External));
+        assert_eq!(
+            std::fs::read_to_string(instance.path().join("server.jar")).unwrap(),
+            "old jar"
+        );
+        // nothing half downloaded is left behind
+        assert_eq!(std::fs::read_dir(temp_parent.path()).unwrap().count(), 0);
+    }
+}
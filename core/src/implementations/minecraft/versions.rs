@@ -1,9 +1,18 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
 use color_eyre::eyre::{eyre, Context};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::warn;
 use ts_rs::TS;
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+use crate::prelude::path_to_stores;
+
+use super::FlavourKind;
 
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export)]
@@ -184,9 +193,630 @@ pub async fn get_forge_versions() -> Result<MinecraftVersions, Error> {
     group_minecraft_versions(&versions).await
 }
 
+/// How long an upstream document is served from memory before it is fetched again
+const UPSTREAM_TTL: Duration = Duration::from_secs(60 * 60);
+
+const MOJANG_MANIFEST_URL: &str = "https://launchermeta.mojang.com/mc/game/version_manifest.json";
+const PAPER_PROJECT_URL: &str = "https://api.papermc.io/v2/projects/paper";
+const FABRIC_VERSIONS_URL: &str = "https://meta.fabricmc.net/v2/versions";
+const FABRIC_LOADERS_URL: &str = "https://meta.fabricmc.net/v2/versions/loader";
+const QUILT_GAME_URL: &str = "https://meta.quiltmc.org/v3/versions/game";
+const QUILT_LOADERS_URL: &str = "https://meta.quiltmc.org/v3/versions/loader";
+const QUILT_INSTALLERS_URL: &str = "https://meta.quiltmc.org/v3/versions/installer";
+const FORGE_METADATA_URL: &str =
+    "https://files.minecraftforge.net/net/minecraftforge/forge/maven-metadata.json";
+const FORGE_PROMOTIONS_URL: &str =
+    "https://files.minecraftforge.net/net/minecraftforge/forge/promotions_slim.json";
+
+struct CachedDocument {
+    value: Value,
+    fetched_at: Instant,
+}
+
+static UPSTREAM_CACHE: Lazy<Mutex<HashMap<&'static str, CachedDocument>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn path_to_cached_document(name: &str) -> std::path::PathBuf {
+    path_to_stores()
+        .join("version_cache")
+        .join(format!("{name}.json"))
+}
+
+async fn fetch(url: &str) -> Result<Value, Error> {
+    Ok(reqwest::Client::new()
+        .get(url)
+        .send()
+        .await
+        .context(format!("Failed to fetch {url}"))?
+        .error_for_status()
+        .context(format!("Failed to fetch {url}"))?
+        .json()
+        .await
+        .context(format!("{url} did not return valid json"))?)
+}
+
+/// Fetches a JSON document at most once per `UPSTREAM_TTL`.
+///
+/// Every fetched copy is also kept on disk, so while upstream is unreachable the last copy
+/// is served instead, even across restarts
+async fn fetch_cached(name: &'static str, url: &str) -> Result<Value, Error> {
+    let mut cache = UPSTREAM_CACHE.lock().await;
+    if let Some(cached) = cache.get(name) {
+        if cached.fetched_at.elapsed() < UPSTREAM_TTL {
+            return Ok(cached.value.clone());
+        }
+    }
+    let value = match fetch(url).await {
+        Ok(value) => {
+            let path = path_to_cached_document(name);
+            let written = async {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(&path, value.to_string()).await
+            }
+            .await;
+            if let Err(e) = written {
+                warn!("Failed to keep a copy of {}: {}", url, e);
+            }
+            value
+        }
+        Err(e) => {
+            if let Some(cached) = cache.get(name) {
+                warn!("Serving a stale copy of {}: {}", url, e);
+                return Ok(cached.value.clone());
+            }
+            let on_disk = tokio::fs::read(path_to_cached_document(name))
+                .await
+                .ok()
+                .and_then(|content| serde_json::from_slice::<Value>(&content).ok());
+            match on_disk {
+                Some(value) => {
+                    warn!("Serving the copy of {} kept on disk: {}", url, e);
+                    value
+                }
+                None => return Err(e),
+            }
+        }
+    };
+    cache.insert(
+        name,
+        CachedDocument {
+            value: value.clone(),
+            fetched_at: Instant::now(),
+        },
+    );
+    Ok(value)
+}
+
+/// Whether going from `from` to `to` goes back in time, by their order in Mojang's manifest.
+/// None if either version isn't listed there
+pub async fn is_downgrade(from: &str, to: &str) -> Result<Option<bool>, Error> {
+    let manifest = fetch_cached("mojang_manifest", MOJANG_MANIFEST_URL).await?;
+    let versions = manifest["versions"]
+        .as_array()
+        .ok_or_else(|| eyre!("Failed to get vanilla versions. Mojang API changed?"))?;
+    Ok(downgrade_in(versions, from, to))
+}
+
+/// Whether `to` is listed after `from` in the manifest's `versions`, `None` if either is missing
+fn downgrade_in(versions: &[Value], from: &str, to: &str) -> Option<bool> {
+    // the manifest lists the newest version first
+    let position = |id: &str| {
+        let id = id.replace('_', "-");
+        versions.iter().position(|version| {
+            version["id"]
+                .as_str()
+                .map_or(false, |version| version.replace('_', "-") == id)
+        })
+    };
+    match (position(from), position(to)) {
+        (Some(from), Some(to)) => Some(to > from),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionKind {
+    Release,
+    Snapshot,
+    OldBeta,
+    OldAlpha,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct VersionEntry {
+    pub id: String,
+    pub kind: VersionKind,
+    pub is_latest_stable: bool,
+}
+
+/// Forge builds promoted for one Minecraft version
+#[derive(Serialize, Clone, Debug, Default, PartialEq, TS)]
+#[ts(export)]
+pub struct ForgeBuilds {
+    pub recommended: Option<String>,
+    pub latest: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+#[serde(tag = "flavour", rename_all = "snake_case")]
+pub enum FlavourVersionExtra {
+    Vanilla,
+    Paper {
+        /// Newest build of the latest stable version
+        latest_build: Option<u32>,
+    },
+    Fabric {
+        /// Newest stable loader and installer, they work with every game version listed
+        loader_version: Option<String>,
+        installer_version: Option<String>,
+    },
+    Quilt {
+        /// Newest stable loader and installer
+        loader_version: Option<String>,
+        installer_version: Option<String>,
+    },
+    Forge {
+        /// Keyed by Minecraft version
+        builds: BTreeMap<String, ForgeBuilds>,
+    },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct LoaderVersion {
+    pub version: String,
+    pub stable: bool,
+}
+
+/// The versions a flavour can be set up with, newest first
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct FlavourVersions {
+    pub versions: Vec<VersionEntry>,
+    pub latest_stable: Option<String>,
+    pub extra: FlavourVersionExtra,
+}
+
+/// Mojang's version types keyed by id, with `_` spelled as `-` like the other upstreams do
+async fn mojang_version_kinds() -> Result<HashMap<String, VersionKind>, Error> {
+    let manifest = fetch_cached("mojang_manifest", MOJANG_MANIFEST_URL).await?;
+    Ok(manifest["versions"]
+        .as_array()
+        .ok_or_else(|| eyre!("Failed to get vanilla versions. Mojang API changed?"))?
+        .iter()
+        .filter_map(|version| {
+            let kind = match version["type"].as_str()? {
+                "release" => VersionKind::Release,
+                "snapshot" => VersionKind::Snapshot,
+                "old_beta" => VersionKind::OldBeta,
+                "old_alpha" => VersionKind::OldAlpha,
+                _ => return None,
+            };
+            Some((version["id"].as_str()?.replace('_', "-"), kind))
+        })
+        .collect())
+}
+
+/// Builds the list from ids ordered newest first. Ids Mojang doesn't know are taken to be
+/// snapshots so they are never offered as the stable choice
+fn version_list(
+    ids: Vec<String>,
+    kinds: &HashMap<String, VersionKind>,
+) -> (Vec<VersionEntry>, Option<String>) {
+    let mut versions: Vec<VersionEntry> = ids
+        .into_iter()
+        .map(|id| VersionEntry {
+            kind: kinds
+                .get(&id.replace('_', "-"))
+                .copied()
+                .unwrap_or(VersionKind::Snapshot),
+            id,
+            is_latest_stable: false,
+        })
+        .collect();
+    let latest_stable = versions
+        .iter_mut()
+        .find(|version| version.kind == VersionKind::Release)
+        .map(|version| {
+            version.is_latest_stable = true;
+            version.id.clone()
+        });
+    (versions, latest_stable)
+}
+
+fn string_array(value: &Value, what: &str) -> Result<Vec<String>, Error> {
+    Ok(value
+        .as_array()
+        .ok_or_else(|| eyre!("Failed to get {what}, expected an array"))?
+        .iter()
+        .filter_map(|item| item.as_str().map(str::to_string))
+        .collect())
+}
+
+pub async fn get_vanilla_setup_versions() -> Result<FlavourVersions, Error> {
+    let manifest = fetch_cached("mojang_manifest", MOJANG_MANIFEST_URL).await?;
+    let kinds = mojang_version_kinds().await?;
+    let ids = manifest["versions"]
+        .as_array()
+        .ok_or_else(|| eyre!("Failed to get vanilla versions. Mojang API changed?"))?
+        .iter()
+        .filter_map(|version| version["id"].as_str().map(str::to_string))
+        .collect();
+    let (versions, latest_stable) = version_list(ids, &kinds);
+    Ok(FlavourVersions {
+        versions,
+        latest_stable: manifest["latest"]["release"]
+            .as_str()
+            .map(str::to_string)
+            .or(latest_stable),
+        extra: FlavourVersionExtra::Vanilla,
+    })
+}
+
+pub async fn get_paper_setup_versions() -> Result<FlavourVersions, Error> {
+    let project = fetch_cached("paper_project", PAPER_PROJECT_URL).await?;
+    let mut ids = string_array(&project["versions"], "paper versions")?;
+    ids.reverse();
+    let (versions, latest_stable) = version_list(ids, &mojang_version_kinds().await?);
+    let latest_build = match &latest_stable {
+        Some(version) => {
+            // only the stable version's builds are fetched, one request per version adds up
+            let builds = fetch_cached(
+                "paper_latest_stable_builds",
+                &format!("{PAPER_PROJECT_URL}/versions/{version}"),
+            )
+            .await?;
+            builds["builds"]
+                .as_array()
+                .and_then(|builds| builds.iter().filter_map(Value::as_u64).max())
+                .map(|build| build as u32)
+        }
+        None => None,
+    };
+    Ok(FlavourVersions {
+        versions,
+        latest_stable,
+        extra: FlavourVersionExtra::Paper { latest_build },
+    })
+}
+
+pub async fn get_fabric_setup_versions() -> Result<FlavourVersions, Error> {
+    let response = fetch_cached("fabric_versions", FABRIC_VERSIONS_URL).await?;
+    let entries = |key: &str| -> Result<Vec<(String, bool)>, Error> {
+        Ok(response[key]
+            .as_array()
+            .ok_or_else(|| eyre!("Failed to get fabric versions. {key} is not an array"))?
+            .iter()
+            .filter_map(|item| {
+                Some((
+                    item["version"].as_str()?.to_string(),
+                    item["stable"].as_bool().unwrap_or(false),
+                ))
+            })
+            .collect())
+    };
+    let newest_stable = |entries: Vec<(String, bool)>| {
+        entries
+            .into_iter()
+            .find(|(_, stable)| *stable)
+            .map(|(version, _)| version)
+    };
+    let game = entries("game")?;
+    // fabric marks releases as stable itself, so Mojang's manifest isn't needed
+    let kinds = game
+        .iter()
+        .map(|(version, stable)| {
+            let kind = if *stable {
+                VersionKind::Release
+            } else {
+                VersionKind::Snapshot
+            };
+            (version.replace('_', "-"), kind)
+        })
+        .collect();
+    let ids = game.into_iter().map(|(version, _)| version).collect();
+    let (versions, latest_stable) = version_list(ids, &kinds);
+    Ok(FlavourVersions {
+        versions,
+        latest_stable,
+        extra: FlavourVersionExtra::Fabric {
+            loader_version: newest_stable(entries("loader")?),
+            installer_version: newest_stable(entries("installer")?),
+        },
+    })
+}
+
+pub async fn get_quilt_setup_versions() -> Result<FlavourVersions, Error> {
+    let game = fetch_cached("quilt_game", QUILT_GAME_URL).await?;
+    let game: Vec<(String, bool)> = game
+        .as_array()
+        .ok_or_else(|| eyre!("Failed to get quilt versions. Response is not an array"))?
+        .iter()
+        .filter_map(|item| {
+            Some((
+                item["version"].as_str()?.to_string(),
+                item["stable"].as_bool().unwrap_or(false),
+            ))
+        })
+        .collect();
+    let kinds = game
+        .iter()
+        .map(|(version, stable)| {
+            let kind = if *stable {
+                VersionKind::Release
+            } else {
+                VersionKind::Snapshot
+            };
+            (version.replace('_', "-"), kind)
+        })
+        .collect();
+    let ids = game.into_iter().map(|(version, _)| version).collect();
+    let (versions, latest_stable) = version_list(ids, &kinds);
+    let loaders = fetch_cached("quilt_loaders", QUILT_LOADERS_URL).await?;
+    let installers = fetch_cached("quilt_installers", QUILT_INSTALLERS_URL).await?;
+    Ok(FlavourVersions {
+        versions,
+        latest_stable,
+        extra: FlavourVersionExtra::Quilt {
+            loader_version: loader_versions(&loaders)
+                .into_iter()
+                .find(|loader| loader.stable)
+                .map(|loader| loader.version),
+            installer_version: installers[0]["version"].as_str().map(str::to_string),
+        },
+    })
+}
+
+/// Reads a loader list of either meta API, with or without the game version in the url.
+/// Quilt doesn't flag stable loaders, its betas carry a pre-release suffix instead
+fn loader_versions(response: &Value) -> Vec<LoaderVersion> {
+    response
+        .as_array()
+        .map(|loaders| {
+            loaders
+                .iter()
+                .filter_map(|item| {
+                    // lists for one game version pair every loader with its mappings
+                    let loader = item.get("loader").unwrap_or(item);
+                    let version = loader["version"].as_str()?.to_string();
+                    Some(LoaderVersion {
+                        stable: loader["stable"]
+                            .as_bool()
+                            .unwrap_or_else(|| !version.contains('-')),
+                        version,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn loader_name(flavour: FlavourKind) -> Result<&'static str, Error> {
+    match flavour {
+        FlavourKind::Fabric => Ok("Fabric"),
+        FlavourKind::Quilt => Ok("Quilt"),
+        _ => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "{} servers have no mod loader to choose",
+                flavour.to_string()
+            ),
+        }),
+    }
+}
+
+/// Loader versions of Fabric or Quilt, newest first. Given a game version, only the loaders
+/// that can run it are listed
+pub async fn get_loader_versions(
+    flavour: FlavourKind,
+    minecraft_version: Option<&str>,
+) -> Result<Vec<LoaderVersion>, Error> {
+    loader_name(flavour)?;
+    let (name, url) = match flavour {
+        FlavourKind::Quilt => ("quilt_loaders", QUILT_LOADERS_URL),
+        _ => ("fabric_loaders", FABRIC_LOADERS_URL),
+    };
+    let response = match minecraft_version {
+        Some(minecraft_version) => {
+            let mut url = url::Url::parse(url).context("Invalid loader list url")?;
+            url.path_segments_mut()
+                .map_err(|_| eyre!("Invalid loader list url"))?
+                .push(minecraft_version);
+            let response = reqwest::Client::new()
+                .get(url.clone())
+                .send()
+                .await
+                .context(format!("Failed to fetch {url}"))?;
+            // unknown game versions are answered with an error by some meta versions
+            if response.status().is_client_error() {
+                return Ok(Vec::new());
+            }
+            response
+                .error_for_status()
+                .context(format!("Failed to fetch {url}"))?
+                .json()
+                .await
+                .context(format!("{url} did not return valid json"))?
+        }
+        None => fetch_cached(name, url).await?,
+    };
+    Ok(loader_versions(&response))
+}
+
+/// Fails unless the loader exists for the game version, so a setup doesn't fail halfway through
+pub async fn check_loader_version(
+    flavour: FlavourKind,
+    minecraft_version: &str,
+    loader_version: &str,
+) -> Result<(), Error> {
+    let name = loader_name(flavour)?;
+    let loaders = get_loader_versions(flavour, Some(minecraft_version)).await?;
+    if loaders.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} does not support Minecraft {}", name, minecraft_version),
+        });
+    }
+    if !loaders
+        .iter()
+        .any(|loader| loader.version == loader_version)
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "{} loader {} is not available for Minecraft {}",
+                name,
+                loader_version,
+                minecraft_version
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Reads forge's promotions, keyed like `1.20.1-recommended`, into builds per Minecraft version
+fn forge_builds(promotions: &Value) -> BTreeMap<String, ForgeBuilds> {
+    let mut builds: BTreeMap<String, ForgeBuilds> = BTreeMap::new();
+    if let Some(promos) = promotions["promos"].as_object() {
+        for (key, build) in promos {
+            let build = match build.as_str() {
+                Some(build) => build.to_string(),
+                None => continue,
+            };
+            if let Some(version) = key.strip_suffix("-recommended") {
+                builds.entry(version.to_string()).or_default().recommended = Some(build);
+            } else if let Some(version) = key.strip_suffix("-latest") {
+                builds.entry(version.to_string()).or_default().latest = Some(build);
+            }
+        }
+    }
+    builds
+}
+
+pub async fn get_forge_setup_versions() -> Result<FlavourVersions, Error> {
+    let metadata = fetch_cached("forge_metadata", FORGE_METADATA_URL).await?;
+    let mut ids: Vec<String> = metadata
+        .as_object()
+        .ok_or_else(|| eyre!("Failed to get forge versions. Metadata is not an object"))?
+        .keys()
+        .cloned()
+        .collect();
+    ids.reverse();
+    let (versions, latest_stable) = version_list(ids, &mojang_version_kinds().await?);
+    let promotions = fetch_cached("forge_promotions", FORGE_PROMOTIONS_URL).await?;
+    Ok(FlavourVersions {
+        versions,
+        latest_stable,
+        extra: FlavourVersionExtra::Forge {
+            builds: forge_builds(&promotions),
+        },
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_version_list() {
+        let kinds = HashMap::from([
+            ("1.20.2".to_string(), VersionKind::Release),
+            ("23w40a".to_string(), VersionKind::Snapshot),
+            ("1.7.10-pre4".to_string(), VersionKind::Snapshot),
+        ]);
+        let (versions, latest_stable) = version_list(
+            vec![
+                "23w40a".to_string(),
+                "1.20.2".to_string(),
+                "1.7.10_pre4".to_string(),
+                "1.21-custom".to_string(),
+            ],
+            &kinds,
+        );
+        assert_eq!(latest_stable.as_deref(), Some("1.20.2"));
+        assert!(versions[1].is_latest_stable);
+        assert_eq!(versions[2].kind, VersionKind::Snapshot);
+        assert_eq!(versions[3].kind, VersionKind::Snapshot);
+    }
+
+    #[test]
+    fn test_forge_builds() {
+        let promotions = serde_json::json!({
+            "promos": {
+                "1.20.1-recommended": "47.1.0",
+                "1.20.1-latest": "47.2.0",
+                "1.20.2-latest": "48.0.1"
+            }
+        });
+        let builds = forge_builds(&promotions);
+        assert_eq!(
+            builds["1.20.1"],
+            ForgeBuilds {
+                recommended: Some("47.1.0".to_string()),
+                latest: Some("47.2.0".to_string()),
+            }
+        );
+        assert_eq!(builds["1.20.2"].recommended, None);
+    }
+
+    #[test]
+    fn test_loader_versions() {
+        let fabric = serde_json::json!([
+            { "version": "0.14.22", "stable": true },
+            { "version": "0.14.22+build.1", "stable": false }
+        ]);
+        assert_eq!(
+            loader_versions(&fabric),
+            vec![
+                LoaderVersion {
+                    version: "0.14.22".to_string(),
+                    stable: true,
+                },
+                LoaderVersion {
+                    version: "0.14.22+build.1".to_string(),
+                    stable: false,
+                },
+            ]
+        );
+        let quilt_for_game = serde_json::json!([
+            { "loader": { "version": "0.20.0-beta.9" }, "intermediary": {} },
+            { "loader": { "version": "0.19.2" }, "intermediary": {} }
+        ]);
+        let loaders = loader_versions(&quilt_for_game);
+        assert!(!loaders[0].stable);
+        assert_eq!(
+            loaders[1],
+            LoaderVersion {
+                version: "0.19.2".to_string(),
+                stable: true,
+            }
+        );
+        assert!(loader_versions(&serde_json::json!({ "error": "not found" })).is_empty());
+    }
+
+    #[test]
+    fn test_downgrade_order() {
+        let versions = serde_json::json!([
+            { "id": "1.20.2" },
+            { "id": "1.20.1" },
+            { "id": "1.7.10_pre4" },
+        ]);
+        let versions = versions.as_array().unwrap();
+        assert_eq!(downgrade_in(versions, "1.20.1", "1.20.2"), Some(false));
+        assert_eq!(downgrade_in(versions, "1.20.2", "1.20.1"), Some(true));
+        // ids spelled with a dash or an underscore are the same version
+        assert_eq!(downgrade_in(versions, "1.20.1", "1.7.10-pre4"), Some(true));
+        // an order that can't be told is refused rather than guessed
+        assert_eq!(downgrade_in(versions, "1.20.1", "1.21-custom"), None);
+        assert_eq!(downgrade_in(versions, "unknown", "1.20.2"), None);
+    }
+
     #[test]
     fn test_paper_versions() {
         let rt = tokio::runtime::Runtime::new().unwrap();
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use flate2::read::GzDecoder;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::disk_usage::dir_size;
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::State;
+use crate::util::{
+    check_archive_entries, extended_length_path, extract_archive, rand_alphanumeric,
+};
+
+use super::configurable::ServerPropertySetting;
+use super::MinecraftInstance;
+
+/// Bukkit style servers keep the other dimensions next to the world, named after it
+const DIMENSION_SUFFIXES: [&str; 2] = ["_nether", "_the_end"];
+const DEFAULT_LEVEL_NAME: &str = "world";
+/// Deeper nesting than this is not a level.dat the game wrote
+const MAX_NBT_DEPTH: u32 = 512;
+
+/// A world directory of the instance, with its Bukkit style dimensions
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct WorldInfo {
+    pub name: String,
+    /// Whether `level-name` points at this world
+    pub active: bool,
+    /// Bytes used by the world and its dimension directories
+    pub size: u64,
+    pub seed: Option<i64>,
+    /// Directories such as `world_nether` that belong to this world
+    pub dimensions: Vec<String>,
+}
+
+/// The part of an NBT tree needed to find the seed, other payloads are skipped
+enum Tag {
+    Long(i64),
+    Compound(HashMap<String, Tag>),
+    Other,
+}
+
+struct NbtReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> NbtReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn len(&mut self) -> Option<usize> {
+        let len = i32::from_be_bytes(self.take(4)?.try_into().ok()?);
+        Some(len.max(0) as usize)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        Some(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+
+    fn skip_array(&mut self, element_size: usize) -> Option<Tag> {
+        let len = self.len()?;
+        self.take(len.checked_mul(element_size)?)?;
+        Some(Tag::Other)
+    }
+
+    fn payload(&mut self, tag_type: u8, depth: u32) -> Option<Tag> {
+        if depth > MAX_NBT_DEPTH {
+            return None;
+        }
+        match tag_type {
+            1 => self.take(1).map(|_| Tag::Other),
+            2 => self.take(2).map(|_| Tag::Other),
+            3 | 5 => self.take(4).map(|_| Tag::Other),
+            4 => Some(Tag::Long(i64::from_be_bytes(
+                self.take(8)?.try_into().ok()?,
+            ))),
+            6 => self.take(8).map(|_| Tag::Other),
+            7 => self.skip_array(1),
+            8 => self.string().map(|_| Tag::Other),
+            9 => {
+                let element_type = self.u8()?;
+                for _ in 0..self.len()? {
+                    self.payload(element_type, depth + 1)?;
+                }
+                Some(Tag::Other)
+            }
+            10 => {
+                let mut compound = HashMap::new();
+                loop {
+                    let tag_type = self.u8()?;
+                    if tag_type == 0 {
+                        return Some(Tag::Compound(compound));
+                    }
+                    let name = self.string()?;
+                    compound.insert(name, self.payload(tag_type, depth + 1)?);
+                }
+            }
+            11 => self.skip_array(4),
+            12 => self.skip_array(8),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a gzipped `level.dat`, returning the seed if it has one.
+///
+/// Fails if the file isn't NBT, which is how an upload is told apart from a random directory
+fn read_level_dat(path: &Path) -> Result<Option<i64>, Error> {
+    let file = std::fs::File::open(extended_length_path(path))
+        .context(format!("Failed to open {}", path.display()))?;
+    let mut data = Vec::new();
+    GzDecoder::new(file)
+        .read_to_end(&mut data)
+        .context(format!("{} is not gzip compressed", path.display()))?;
+    let mut reader = NbtReader { data: &data };
+    let root = match reader.u8() {
+        Some(10) => reader
+            .string()
+            .and_then(|_| reader.payload(10, 0))
+            .ok_or_else(|| eyre!("{} is not valid NBT", path.display()))?,
+        _ => return Err(eyre!("{} is not valid NBT", path.display()).into()),
+    };
+    fn child<'a>(tag: &'a Tag, name: &str) -> Option<&'a Tag> {
+        match tag {
+            Tag::Compound(compound) => compound.get(name),
+            _ => None,
+        }
+    }
+    let data = match child(&root, "Data") {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    // 1.16 moved the seed into the world generation settings
+    let seed = child(data, "WorldGenSettings")
+        .and_then(|settings| child(settings, "seed"))
+        .or_else(|| child(data, "RandomSeed"));
+    Ok(match seed {
+        Some(Tag::Long(seed)) => Some(*seed),
+        _ => None,
+    })
+}
+
+fn is_world(dir: &Path) -> bool {
+    extended_length_path(dir.join("level.dat")).is_file()
+}
+
+/// Whether `name` is a bare directory name, so it can't leave the instance directory
+fn check_world_name(name: &str) -> Result<(), Error> {
+    let is_bare = Path::new(name).file_name().and_then(|n| n.to_str()) == Some(name);
+    if name.is_empty() || !is_bare || name.starts_with('.') {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} is not a world name", name),
+        });
+    }
+    Ok(())
+}
+
+/// The dimension directories of the world `name` that exist in `root`
+fn dimensions_of(root: &Path, name: &str) -> Vec<String> {
+    DIMENSION_SUFFIXES
+        .iter()
+        .map(|suffix| format!("{}{}", name, suffix))
+        .filter(|dimension| extended_length_path(root.join(dimension)).is_dir())
+        .collect()
+}
+
+/// Every directory of `root` holding a `level.dat`, dimensions grouped under their world
+fn find_worlds(root: &Path, active: &str) -> Result<Vec<WorldInfo>, Error> {
+    let mut names: Vec<String> = std::fs::read_dir(extended_length_path(root))
+        .context(format!("Failed to read {}", root.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map_or(false, |t| t.is_dir()))
+        .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+        .filter(|name| !name.starts_with('.') && is_world(&root.join(name)))
+        .collect();
+    names.sort();
+    let is_dimension = |name: &str| {
+        DIMENSION_SUFFIXES.iter().any(|suffix| {
+            name.strip_suffix(suffix)
+                .map_or(false, |world| names.iter().any(|other| other == world))
+        })
+    };
+    Ok(names
+        .iter()
+        .filter(|name| !is_dimension(name.as_str()))
+        .map(|name| {
+            let dimensions = dimensions_of(root, name);
+            let size = std::iter::once(name)
+                .chain(dimensions.iter())
+                .map(|dir| dir_size(&root.join(dir)))
+                .sum();
+            WorldInfo {
+                name: name.clone(),
+                active: name == active,
+                size,
+                seed: read_level_dat(&root.join(name).join("level.dat"))
+                    .ok()
+                    .flatten(),
+                dimensions,
+            }
+        })
+        .collect())
+}
+
+/// Finds the world in an extracted archive, either at its top or in one of its directories,
+/// along with the dimension directories next to it
+fn locate_uploaded_world(extracted: &Path) -> Result<(PathBuf, Vec<(String, PathBuf)>), Error> {
+    if is_world(extracted) {
+        return Ok((extracted.to_path_buf(), Vec::new()));
+    }
+    let worlds = find_worlds(extracted, "")?;
+    let world = match worlds.as_slice() {
+        [world] => world,
+        [] => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The archive has no level.dat, it is not a world"),
+            })
+        }
+        _ => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The archive holds more than one world, upload them one at a time"),
+            })
+        }
+    };
+    let dimensions = world
+        .dimensions
+        .iter()
+        .map(|dimension| {
+            (
+                dimension[world.name.len()..].to_string(),
+                extracted.join(dimension),
+            )
+        })
+        .collect();
+    Ok((extracted.join(&world.name), dimensions))
+}
+
+/// Extracts an uploaded `.zip` or `.tar.gz` world into `root/name`, and any Bukkit style
+/// dimensions into `root/name_nether` and `root/name_the_end`.
+///
+/// The archive is extracted next to the instance first, so nothing named `name` appears unless
+/// it holds a readable `level.dat`. Returns the directories created
+pub fn install_world_archive(
+    archive: &Path,
+    root: &Path,
+    name: &str,
+    max_path_length: usize,
+) -> Result<Vec<PathBuf>, Error> {
+    check_world_name(name)?;
+    let staging = root.join(format!(".world_upload_{}", rand_alphanumeric(8)));
+    let result = (|| {
+        std::fs::create_dir_all(extended_length_path(&staging))
+            .context(format!("Failed to create {}", staging.display()))?;
+        check_archive_entries(archive, &staging, max_path_length)?;
+        extract_archive(archive, &staging, &|_| false, &mut |_| Ok(()))?;
+        let (world, dimensions) = locate_uploaded_world(&staging)?;
+        read_level_dat(&world.join("level.dat")).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: e
+                .source
+                .wrap_err("The uploaded world has a corrupted level.dat"),
+        })?;
+        let mut moves = vec![(world, root.join(name))];
+        for (suffix, dimension) in dimensions {
+            moves.push((dimension, root.join(format!("{}{}", name, suffix))));
+        }
+        if let Some((_, taken)) = moves
+            .iter()
+            .find(|(_, target)| extended_length_path(target).exists())
+        {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("{} already exists", taken.display()),
+            });
+        }
+        let mut created = Vec::new();
+        for (from, to) in moves {
+            std::fs::rename(extended_length_path(&from), extended_length_path(&to))
+                .context(format!("Failed to move the world to {}", to.display()))?;
+            created.push(to);
+        }
+        Ok(created)
+    })();
+    if extended_length_path(&staging).exists() {
+        if let Err(e) = std::fs::remove_dir_all(extended_length_path(&staging)) {
+            tracing::warn!("Failed to clean up {}: {}", staging.display(), e);
+        }
+    }
+    result
+}
+
+impl MinecraftInstance {
+    async fn require_stopped(&self, action: &str) -> Result<(), Error> {
+        if *self.state.lock().await != State::Stopped {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Instance must be stopped before {}", action),
+            });
+        }
+        Ok(())
+    }
+
+    /// The world `level-name` in server.properties points at
+    pub async fn active_world(&self) -> String {
+        self.configurable_manifest
+            .lock()
+            .await
+            .get_unique_setting_key(
+                &ServerPropertySetting::LevelName(String::new()).get_identifier(),
+            )
+            .and_then(|setting| setting.get_value().map(|v| v.try_as_string().cloned()))
+            .and_then(Result::ok)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| DEFAULT_LEVEL_NAME.to_string())
+    }
+
+    pub async fn list_worlds(&self) -> Result<Vec<WorldInfo>, Error> {
+        let root = self.path_to_instance.clone();
+        let active = self.active_world().await;
+        tokio::task::spawn_blocking(move || find_worlds(&root, &active))
+            .await
+            .context("Failed to spawn blocking task")?
+    }
+
+    /// Points `level-name` at another world, its dimensions follow since they are named after it
+    pub async fn switch_world(&self, name: &str) -> Result<(), Error> {
+        check_world_name(name)?;
+        self.require_stopped("switching worlds").await?;
+        if !is_world(&self.path_to_instance.join(name)) {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} is not a world of this instance", name),
+            });
+        }
+        self.set_game_settings(IndexMap::from([(
+            ServerPropertySetting::LevelName(String::new()).get_identifier(),
+            ConfigurableValue::String(name.to_string()),
+        )]))
+        .await
+    }
+
+    /// Moves a world and its dimensions aside to `<name>_<timestamp>_old`, so the server
+    /// generates a new one on its next start. Returns the directories that were moved
+    pub async fn reset_world(&self, name: &str) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+        check_world_name(name)?;
+        self.require_stopped("resetting a world").await?;
+        let world = self.path_to_instance.join(name);
+        if !is_world(&world) {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} is not a world of this instance", name),
+            });
+        }
+        // the dimensions keep their suffix so the old set can still be switched to
+        let old_name = format!(
+            "{}_{}_old",
+            name,
+            chrono::Local::now().format("%Y%m%d%H%M%S")
+        );
+        let mut moves = vec![(world, self.path_to_instance.join(&old_name))];
+        for dimension in dimensions_of(&self.path_to_instance, name) {
+            let suffix = &dimension[name.len()..];
+            moves.push((
+                self.path_to_instance.join(&dimension),
+                self.path_to_instance
+                    .join(format!("{}{}", old_name, suffix)),
+            ));
+        }
+        let mut moved = Vec::new();
+        for (from, to) in moves {
+            crate::util::fs::rename(&from, &to).await?;
+            moved.push((from, to));
+        }
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn named(tag_type: u8, name: &str) -> Vec<u8> {
+        let mut bytes = vec![tag_type];
+        bytes.extend((name.len() as u16).to_be_bytes());
+        bytes.extend(name.as_bytes());
+        bytes
+    }
+
+    /// A level.dat with the seed where 1.16 and later keep it
+    fn level_dat(seed: i64) -> Vec<u8> {
+        let mut nbt = named(10, "");
+        nbt.extend(named(10, "Data"));
+        nbt.extend(named(8, "LevelName"));
+        nbt.extend(5u16.to_be_bytes());
+        nbt.extend(b"world");
+        nbt.extend(named(9, "ServerBrands"));
+        nbt.push(8);
+        nbt.extend(1i32.to_be_bytes());
+        nbt.extend(5u16.to_be_bytes());
+        nbt.extend(b"paper");
+        nbt.extend(named(10, "WorldGenSettings"));
+        nbt.extend(named(4, "seed"));
+        nbt.extend(seed.to_be_bytes());
+        nbt.extend([0, 0, 0]);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&nbt).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_read_level_dat() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("level.dat");
+        std::fs::write(&path, level_dat(-4172144997902289642)).unwrap();
+        assert_eq!(read_level_dat(&path).unwrap(), Some(-4172144997902289642));
+        std::fs::write(&path, "not a level").unwrap();
+        assert!(read_level_dat(&path).is_err());
+    }
+
+    #[test]
+    fn test_find_worlds() {
+        let dir = tempfile::tempdir().unwrap();
+        for world in ["world", "world_nether", "world_the_end", "creative", "logs"] {
+            std::fs::create_dir(dir.path().join(world)).unwrap();
+            if world != "logs" {
+                std::fs::write(dir.path().join(world).join("level.dat"), level_dat(1)).unwrap();
+            }
+        }
+        let worlds = find_worlds(dir.path(), "world").unwrap();
+        assert_eq!(worlds.len(), 2);
+        assert_eq!(worlds[0].name, "creative");
+        assert!(!worlds[0].active);
+        assert!(worlds[0].dimensions.is_empty());
+        assert_eq!(worlds[1].name, "world");
+        assert!(worlds[1].active);
+        assert_eq!(worlds[1].seed, Some(1));
+        assert_eq!(worlds[1].dimensions, vec!["world_nether", "world_the_end"]);
+    }
+
+    #[test]
+    fn test_check_world_name() {
+        assert!(check_world_name("world").is_ok());
+        assert!(check_world_name("").is_err());
+        assert!(check_world_name("../world").is_err());
+        assert!(check_world_name(".world_upload_abc").is_err());
+    }
+}
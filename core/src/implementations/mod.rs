@@ -1,2 +1,3 @@
+pub mod command;
 pub mod generic;
 pub mod minecraft;
@@ -0,0 +1,214 @@
+//! The info of each instance as last built, so the dashboard polling the instance list doesn't
+//! go through every instance and its `.lodestone_config` each time
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+
+use dashmap::DashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, InstanceEventInner};
+use crate::snapshot::InstanceSnapshot;
+use crate::traits::InstanceInfo;
+use crate::types::InstanceUuid;
+
+struct CachedInfo {
+    /// What the instance published when the info was built, any change to its state, settings
+    /// or players publishes a new one
+    snapshot: Arc<InstanceSnapshot>,
+    info: InstanceInfo,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: DashMap<InstanceUuid, CachedInfo>,
+    /// Bumped by every invalidation, so an info built from what was read before it isn't cached
+    generation: AtomicU64,
+}
+
+impl Entries {
+    fn invalidate(&self, instance_uuid: &InstanceUuid) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.entries.remove(instance_uuid);
+    }
+}
+
+/// Caches the info of each instance until the instance changes.
+///
+/// The fields taken from the instance are checked against its snapshot on every read, so they
+/// never lag behind it. Tags and maintenance live on disk instead, their entries are dropped on
+/// the events announcing a change to them
+#[derive(Clone)]
+pub struct InstanceInfoCache {
+    inner: Arc<Entries>,
+}
+
+impl InstanceInfoCache {
+    pub fn new(event_broadcaster: &EventBroadcaster) -> Self {
+        let cache = Self {
+            inner: Arc::new(Entries::default()),
+        };
+        cache.watch(event_broadcaster);
+        cache
+    }
+
+    /// The cached info, if it was built from the instance's current `snapshot`
+    pub fn get(
+        &self,
+        instance_uuid: &InstanceUuid,
+        snapshot: &Arc<InstanceSnapshot>,
+    ) -> Option<InstanceInfo> {
+        let entry = self.inner.entries.get(instance_uuid)?;
+        if !Arc::ptr_eq(&entry.snapshot, snapshot) {
+            return None;
+        }
+        let mut info = entry.info.clone();
+        // the one field that changes without the instance doing anything
+        info.uptime_seconds = snapshot.uptime_seconds();
+        Some(info)
+    }
+
+    /// Builds the info again and caches it, unless the instance was invalidated meanwhile
+    pub async fn build<F>(
+        &self,
+        instance_uuid: &InstanceUuid,
+        snapshot: Arc<InstanceSnapshot>,
+        build: F,
+    ) -> InstanceInfo
+    where
+        F: Future<Output = InstanceInfo>,
+    {
+        let generation = self.inner.generation.load(Ordering::SeqCst);
+        let info = build.await;
+        if self.inner.generation.load(Ordering::SeqCst) == generation {
+            self.inner.entries.insert(
+                instance_uuid.clone(),
+                CachedInfo {
+                    snapshot,
+                    info: info.clone(),
+                },
+            );
+        }
+        info
+    }
+
+    pub fn invalidate(&self, instance_uuid: &InstanceUuid) {
+        self.inner.invalidate(instance_uuid);
+    }
+
+    /// Drops the entries whose tags or maintenance changed. The task ends once every copy of the
+    /// cache is dropped
+    fn watch(&self, event_broadcaster: &EventBroadcaster) {
+        let inner: Weak<Entries> = Arc::downgrade(&self.inner);
+        let mut rx = event_broadcaster.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        // whichever events were missed, nothing cached can be trusted
+                        match inner.upgrade() {
+                            Some(inner) => {
+                                inner.generation.fetch_add(1, Ordering::SeqCst);
+                                inner.entries.clear();
+                            }
+                            None => return,
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                if let EventInner::InstanceEvent(instance_event) = &event.event_inner {
+                    if matches!(
+                        instance_event.instance_event_inner,
+                        InstanceEventInner::TagsChanged { .. }
+                            | InstanceEventInner::MaintenanceChanged { .. }
+                    ) {
+                        inner.invalidate(&instance_event.instance_uuid);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::run_record::RunRecord;
+    use crate::snapshot::Snapshot;
+    use crate::traits::t_configurable::{Game, MinecraftVariant};
+    use crate::traits::t_server::State;
+
+    async fn read(
+        cache: &InstanceInfoCache,
+        uuid: &InstanceUuid,
+        snapshot: &Snapshot<InstanceSnapshot>,
+        builds: &AtomicUsize,
+    ) -> InstanceInfo {
+        let snapshot = snapshot.load();
+        if let Some(info) = cache.get(uuid, &snapshot) {
+            return info;
+        }
+        let info = async {
+            builds.fetch_add(1, Ordering::SeqCst);
+            InstanceInfo::from_snapshot(&snapshot, uuid.clone(), 0, "".to_string())
+        };
+        cache.build(uuid, snapshot.clone(), info).await
+    }
+
+    #[tokio::test]
+    async fn test_setting_change_is_read_right_away() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let cache = InstanceInfoCache::new(&event_broadcaster);
+        let uuid = InstanceUuid::default();
+        let snapshot = Snapshot::new(InstanceSnapshot {
+            name: "test".to_string(),
+            game_type: Game::MinecraftJava {
+                variant: MinecraftVariant::Vanilla,
+            },
+            description: "".to_string(),
+            version: "1.20.1".to_string(),
+            port: 25565,
+            auto_start: false,
+            restart_on_crash: false,
+            state: State::Stopped,
+            player_count: Some(0),
+            max_player_count: Some(20),
+            player_list: Some(HashSet::new()),
+            launch_command: None,
+            eula_accepted: Some(true),
+            last_started: None,
+            last_run: RunRecord::default(),
+            degraded: false,
+        });
+        let builds = AtomicUsize::new(0);
+
+        assert_eq!(read(&cache, &uuid, &snapshot, &builds).await.port, 25565);
+        assert_eq!(read(&cache, &uuid, &snapshot, &builds).await.port, 25565);
+        assert_eq!(builds.load(Ordering::SeqCst), 1);
+
+        // what the instance's setters do on a settings write
+        snapshot.update(|s| s.port = 25566);
+        assert_eq!(read(&cache, &uuid, &snapshot, &builds).await.port, 25566);
+        assert_eq!(read(&cache, &uuid, &snapshot, &builds).await.port, 25566);
+        assert_eq!(builds.load(Ordering::SeqCst), 2);
+
+        // an invalidation while building keeps the stale info out of the cache
+        let stale = async {
+            cache.invalidate(&uuid);
+            InstanceInfo::from_snapshot(&snapshot.load(), uuid.clone(), 0, "".to_string())
+        };
+        cache.build(&uuid, snapshot.load(), stale).await;
+        assert!(cache.get(&uuid, &snapshot.load()).is_none());
+    }
+}
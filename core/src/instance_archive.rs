@@ -0,0 +1,305 @@
+//! Portable archives of a whole instance, for moving it to another Lodestone host
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use ts_rs::TS;
+
+use crate::archive_manifest::{ArchiveManifest, HashingReader, MANIFEST_FILE_NAME};
+use crate::backups::{archive_entries, ArchiveEntry};
+use crate::error::{Error, ErrorKind};
+use crate::handlers::instance_fs::read_dot_lodestone_config;
+use crate::prelude::{GameInstance, VERSION};
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+use crate::traits::t_configurable::{Game, GameType, TConfigurable};
+use crate::traits::TInstance;
+use crate::types::{AutoStartOrder, DotLodestoneConfig, InstanceUuid, ProtectedFilesPolicy};
+
+/// Bumped whenever an older Lodestone could no longer import the archive correctly
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+/// Name of the metadata inside an export, at the archive root
+pub const EXPORT_METADATA_FILE_NAME: &str = ".lodestone_export.json";
+/// Never exported as is: `.lodestone_config` holds the instance's UUID on this host and the
+/// importing host writes its own, the other two are written by the export itself
+const EXCLUDED_FILES: [&str; 3] = [
+    ".lodestone_config",
+    EXPORT_METADATA_FILE_NAME,
+    MANIFEST_FILE_NAME,
+];
+
+/// What an export holds besides the instance files. Nothing in it is tied to the exporting
+/// host, there is no UUID or absolute path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ExportMetadata {
+    pub format_version: u32,
+    pub lodestone_version: String,
+    pub exported_at: i64,
+    pub name: String,
+    pub description: String,
+    pub game_type: GameType,
+    /// The flavour of the game, e.g. Paper for Minecraft
+    pub game: Game,
+    pub version: String,
+    pub port: u32,
+    pub settings: IndexMap<String, ConfigurableValue>,
+    pub protected_files: ProtectedFilesPolicy,
+    pub disk_quota: Option<u64>,
+    pub auto_start_order: AutoStartOrder,
+}
+
+impl ExportMetadata {
+    pub async fn new(instance: &GameInstance) -> Result<Self, Error> {
+        let info = instance.get_instance_info().await;
+        let dot_lodestone_config = read_dot_lodestone_config(&instance.path().await).await?;
+        // instances without typed game settings are exported without them
+        let settings = instance
+            .game_settings()
+            .await
+            .map(|settings| {
+                settings
+                    .into_iter()
+                    .filter_map(|(key, setting)| setting.get_value().cloned().map(|v| (key, v)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Self {
+            format_version: EXPORT_FORMAT_VERSION,
+            lodestone_version: VERSION.with(|v| v.to_string()),
+            exported_at: chrono::Utc::now().timestamp(),
+            name: info.name,
+            description: info.description,
+            game_type: *dot_lodestone_config.game_type(),
+            game: info.game_type,
+            version: info.version,
+            port: info.port,
+            settings,
+            protected_files: dot_lodestone_config.protected_files().clone(),
+            disk_quota: dot_lodestone_config.disk_quota(),
+            auto_start_order: dot_lodestone_config.auto_start_order(),
+        })
+    }
+
+    /// The `.lodestone_config` of the imported instance
+    pub fn dot_lodestone_config(&self, uuid: InstanceUuid) -> DotLodestoneConfig {
+        let mut config = DotLodestoneConfig::new(uuid, self.game_type);
+        config.set_protected_files(self.protected_files.clone());
+        config.set_disk_quota(self.disk_quota);
+        config.set_auto_start_order(self.auto_start_order);
+        config
+    }
+
+    /// Checks the format version before the rest, so an export of a newer format is reported
+    /// as such instead of as malformed
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        struct FormatVersion {
+            format_version: u32,
+        }
+        let malformed = |e: serde_json::Error| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Export metadata is malformed: {}", e),
+        };
+        let format_version = serde_json::from_slice::<FormatVersion>(bytes)
+            .map_err(malformed)?
+            .format_version;
+        if format_version != EXPORT_FORMAT_VERSION {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "The export has format version {}, this version of Lodestone imports version {}",
+                    format_version,
+                    EXPORT_FORMAT_VERSION
+                ),
+            });
+        }
+        serde_json::from_slice(bytes).map_err(malformed)
+    }
+}
+
+/// Everything of the instance that goes into an export, its backups and the files tied to
+/// this host left out
+pub fn export_entries(instance_path: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    Ok(archive_entries(instance_path)?
+        .into_iter()
+        .filter(|entry| !EXCLUDED_FILES.contains(&entry.name.as_str()))
+        .collect())
+}
+
+/// Writes the export as a tar.gz into `writer`: the metadata first, then `entries`, then their
+/// checksum manifest. `on_progress` gets the total bytes archived so far
+pub fn write_export(
+    metadata: &ExportMetadata,
+    entries: &[ArchiveEntry],
+    writer: impl Write,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64, Error> {
+    let mut builder = tar::Builder::new(GzEncoder::new(writer, flate2::Compression::default()));
+    let mut append = |name: &str, size: u64, reader: &mut dyn Read| -> Result<(), Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, name, reader)
+            .context(format!("Failed to write {} to the export", name))?;
+        Ok(())
+    };
+    let metadata =
+        serde_json::to_vec_pretty(metadata).context("Failed to serialize export metadata")?;
+    append(
+        EXPORT_METADATA_FILE_NAME,
+        metadata.len() as u64,
+        &mut metadata.as_slice(),
+    )?;
+    let mut manifest = ArchiveManifest::default();
+    let mut archived = 0;
+    for entry in entries {
+        // directories are created by the files in them, empty ones aren't worth keeping
+        let size = match entry.size {
+            Some(size) => size,
+            None => continue,
+        };
+        let file =
+            std::fs::File::open(&entry.path).context(format!("Failed to open {}", entry.name))?;
+        // a file the running server appends to must not outgrow its header
+        let mut reader = HashingReader::new(file.take(size));
+        append(&entry.name, size, &mut reader)?;
+        manifest.insert(Path::new(&entry.name), reader.finish());
+        archived += size;
+        on_progress(archived);
+    }
+    let manifest = manifest.to_bytes()?;
+    append(
+        MANIFEST_FILE_NAME,
+        manifest.len() as u64,
+        &mut manifest.as_slice(),
+    )?;
+    builder
+        .into_inner()
+        .context("Failed to finish the export")?
+        .finish()
+        .context("Failed to finish the export")?
+        .flush()
+        .context("Failed to finish the export")?;
+    Ok(archived)
+}
+
+/// Reads the metadata of an export without extracting it
+pub fn read_export_metadata(archive: &Path) -> Result<ExportMetadata, Error> {
+    let file =
+        std::fs::File::open(archive).context(format!("Failed to open {}", archive.display()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let not_an_export = |e: std::io::Error| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Not a Lodestone instance export: {}", e),
+    };
+    for entry in archive.entries().map_err(not_an_export)? {
+        let mut entry = entry.map_err(not_an_export)?;
+        if entry.path().map_err(not_an_export)?.as_os_str() != EXPORT_METADATA_FILE_NAME {
+            continue;
+        }
+        let mut metadata = Vec::new();
+        entry.read_to_end(&mut metadata).map_err(not_an_export)?;
+        return ExportMetadata::from_slice(&metadata);
+    }
+    Err(Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(
+            "Not a Lodestone instance export, it has no {}",
+            EXPORT_METADATA_FILE_NAME
+        ),
+    })
+}
+
+/// Hands whatever is written to it to an async receiver, so an export written on a blocking
+/// thread can be streamed as a response body
+pub struct ChannelWriter(pub mpsc::Sender<Result<Vec<u8>, std::io::Error>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(Ok(buf.to_vec())).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "The download was closed")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::t_configurable::MinecraftVariant;
+
+    fn metadata() -> ExportMetadata {
+        ExportMetadata {
+            format_version: EXPORT_FORMAT_VERSION,
+            lodestone_version: "0.5.0".to_string(),
+            exported_at: 0,
+            name: "survival".to_string(),
+            description: "".to_string(),
+            game_type: GameType::MinecraftJava,
+            game: Game::MinecraftJava {
+                variant: MinecraftVariant::Vanilla,
+            },
+            version: "1.20.1".to_string(),
+            port: 25565,
+            settings: IndexMap::from([(
+                "motd".to_string(),
+                ConfigurableValue::String("hi".into()),
+            )]),
+            protected_files: ProtectedFilesPolicy::default(),
+            disk_quota: None,
+            auto_start_order: AutoStartOrder::default(),
+        }
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let instance = temp.path().join("instance");
+        std::fs::create_dir_all(instance.join("world")).unwrap();
+        std::fs::create_dir_all(instance.join("backups")).unwrap();
+        std::fs::write(instance.join("world/level.dat"), "level").unwrap();
+        std::fs::write(instance.join("backups/old.zip"), "old").unwrap();
+        std::fs::write(instance.join(".lodestone_config"), "{}").unwrap();
+
+        let entries = export_entries(&instance).unwrap();
+        assert!(entries
+            .iter()
+            .all(|entry| entry.name != ".lodestone_config"));
+        let archive = temp.path().join("export.tar.gz");
+        let archived = write_export(
+            &metadata(),
+            &entries,
+            std::fs::File::create(&archive).unwrap(),
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(archived, 5);
+        assert_eq!(read_export_metadata(&archive).unwrap(), metadata());
+        let report = crate::archive_manifest::verify_archive(&archive)
+            .unwrap()
+            .unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 1);
+    }
+
+    #[test]
+    fn test_format_version() {
+        let mut future = serde_json::to_value(metadata()).unwrap();
+        future["format_version"] = (EXPORT_FORMAT_VERSION + 1).into();
+        future["name"] = serde_json::json!({ "renamed": true });
+        let e = ExportMetadata::from_slice(&serde_json::to_vec(&future).unwrap()).unwrap_err();
+        assert!(e.source.to_string().contains("format version"));
+        assert!(ExportMetadata::from_slice(b"{}").is_err());
+    }
+}
@@ -0,0 +1,660 @@
+//! Moving an instance directory under another root, e.g. onto a second disk.
+//!
+//! The files are always copied, never renamed, so the destination can be on any filesystem. The
+//! source is only removed once the copy is verified, and a journal in the stores lets the next
+//! start finish or undo a migration the daemon didn't get to complete
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashSet;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::error::{Error, ErrorCode, ErrorKind};
+use crate::types::InstanceUuid;
+use crate::util::{strip_extended_length_prefix, walk_dir, MAX_TRAVERSAL_DEPTH};
+
+/// Left in the destination while it is being filled, a directory without it is never removed
+const MIGRATION_MARKER: &str = ".lodestone_migration";
+/// Copied last so a partial copy never loads as an instance
+const DOT_LODESTONE_CONFIG: &str = ".lodestone_config";
+/// Files whose content is compared after the copy, on top of every file's size
+const VERIFY_SAMPLE_SIZE: usize = 32;
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Instances whose directory is being copied. They stay loaded meanwhile, but refuse to start or be
+/// deleted. A deletion holds the flag too while it takes the instance out
+#[derive(Clone, Default)]
+pub struct MigratingInstances(Arc<DashSet<InstanceUuid>>);
+
+impl MigratingInstances {
+    /// Marks the instance as migrating until the guard is dropped
+    pub fn try_begin(&self, instance_uuid: &InstanceUuid) -> Result<MigratingGuard, Error> {
+        if !self.0.insert(instance_uuid.clone()) {
+            return Err(migrating_error());
+        }
+        Ok(MigratingGuard {
+            migrating: self.clone(),
+            instance_uuid: instance_uuid.clone(),
+        })
+    }
+
+    pub fn check(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        if self.0.contains(instance_uuid) {
+            return Err(migrating_error());
+        }
+        Ok(())
+    }
+}
+
+fn migrating_error() -> Error {
+    Error::coded(
+        ErrorCode::InstanceMigrating,
+        "The instance is being migrated",
+    )
+}
+
+pub struct MigratingGuard {
+    migrating: MigratingInstances,
+    instance_uuid: InstanceUuid,
+}
+
+impl Drop for MigratingGuard {
+    fn drop(&mut self) {
+        self.migrating.0.remove(&self.instance_uuid);
+    }
+}
+
+/// Where instances moved out of the instances directory are, so they are restored on start
+#[derive(Clone)]
+pub struct InstanceLocations {
+    path: PathBuf,
+    journal_dir: PathBuf,
+    locations: Arc<Mutex<HashMap<InstanceUuid, PathBuf>>>,
+}
+
+impl InstanceLocations {
+    pub async fn new(stores_dir: &Path) -> Result<Self, Error> {
+        let path = stores_dir.join("instance_locations.json");
+        let locations = match tokio::fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content)
+                .context(format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => Err(e).context(format!("Failed to read {}", path.display()))?,
+        };
+        Ok(Self {
+            path,
+            journal_dir: stores_dir.join("migrations"),
+            locations: Arc::new(Mutex::new(locations)),
+        })
+    }
+
+    pub async fn paths(&self) -> Vec<PathBuf> {
+        self.locations.lock().await.values().cloned().collect()
+    }
+
+    /// Records where an instance is, `None` once it's back in the instances directory or deleted
+    pub async fn set(
+        &self,
+        instance_uuid: &InstanceUuid,
+        path: Option<PathBuf>,
+    ) -> Result<(), Error> {
+        let mut locations = self.locations.lock().await;
+        let old = match path {
+            Some(path) => locations.insert(instance_uuid.clone(), path),
+            None => locations.remove(instance_uuid),
+        };
+        let written = match serde_json::to_vec_pretty(&*locations) {
+            Ok(content) => tokio::fs::write(&self.path, content)
+                .await
+                .context(format!("Failed to write {}", self.path.display())),
+            Err(e) => Err(e).context("Failed to serialize instance locations"),
+        };
+        if let Err(e) = written {
+            match old {
+                Some(old) => locations.insert(instance_uuid.clone(), old),
+                None => locations.remove(instance_uuid),
+            };
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn journal_path(&self, instance_uuid: &InstanceUuid) -> PathBuf {
+        self.journal_dir.join(format!("{}.json", instance_uuid))
+    }
+}
+
+/// What the next start needs to finish or undo an interrupted migration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Journal {
+    instance_uuid: InstanceUuid,
+    source: PathBuf,
+    destination: PathBuf,
+    /// Set once the copy is verified, from then on the destination is the instance
+    committed: bool,
+}
+
+/// An instance directory being copied to `destination`
+#[derive(Debug)]
+pub struct Migration {
+    pub instance_uuid: InstanceUuid,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Relative paths, parents before their children
+    dirs: Vec<PathBuf>,
+    files: Vec<(PathBuf, u64)>,
+    symlinks: Vec<PathBuf>,
+    /// Total size of the files
+    pub bytes: u64,
+}
+
+impl Migration {
+    /// Walks the instance directory and checks it can go under `destination_root`, which must
+    /// already exist so a disk that isn't mounted isn't filled in its place. Blocks while walking
+    pub fn plan(
+        instance_uuid: InstanceUuid,
+        source: &Path,
+        destination_root: &Path,
+    ) -> Result<Self, Error> {
+        let bad_request = |message: String| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(message),
+        };
+        if !destination_root.is_absolute() {
+            return Err(bad_request(format!(
+                "{} isn't an absolute path",
+                destination_root.display()
+            )));
+        }
+        if !destination_root.is_dir() {
+            return Err(bad_request(format!(
+                "{} isn't a directory, create it or mount its disk first",
+                destination_root.display()
+            )));
+        }
+        let name = source
+            .file_name()
+            .ok_or_else(|| bad_request(format!("{} has no name", source.display())))?;
+        let destination = destination_root.join(name);
+        if destination.exists() {
+            return Err(bad_request(format!(
+                "{} already exists",
+                destination.display()
+            )));
+        }
+        let canonical_source = std::fs::canonicalize(source)
+            .context(format!("Failed to resolve {}", source.display()))?;
+        let canonical_root = std::fs::canonicalize(destination_root)
+            .context(format!("Failed to resolve {}", destination_root.display()))?;
+        if canonical_root.starts_with(&canonical_source) {
+            return Err(bad_request(
+                "The destination can't be inside the instance".to_string(),
+            ));
+        }
+
+        let mut migration = Self {
+            instance_uuid,
+            source: source.to_path_buf(),
+            destination,
+            dirs: Vec::new(),
+            files: Vec::new(),
+            symlinks: Vec::new(),
+            bytes: 0,
+        };
+        for entry in walk_dir(source, MAX_TRAVERSAL_DEPTH).skip(1) {
+            let entry = entry?;
+            let path = strip_extended_length_prefix(entry.path());
+            let relative = path
+                .strip_prefix(source)
+                .context("Walked outside of the instance")?
+                .to_path_buf();
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                migration.dirs.push(relative);
+            } else if file_type.is_symlink() {
+                migration.symlinks.push(relative);
+            } else {
+                let size = entry
+                    .metadata()
+                    .context(format!("Failed to read metadata of {}", path.display()))?
+                    .len();
+                migration.bytes += size;
+                migration.files.push((relative, size));
+            }
+        }
+        // the config last, so a partial copy never loads as an instance
+        migration
+            .files
+            .sort_by_key(|(path, _)| path == Path::new(DOT_LODESTONE_CONFIG));
+        Ok(migration)
+    }
+
+    fn journal(&self, committed: bool) -> Journal {
+        Journal {
+            instance_uuid: self.instance_uuid.clone(),
+            source: self.source.clone(),
+            destination: self.destination.clone(),
+            committed,
+        }
+    }
+
+    /// Copies the instance, calling `on_progress` with the bytes copied since its last call.
+    /// Blocks until done
+    pub fn copy(
+        &self,
+        locations: &InstanceLocations,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Error> {
+        write_journal(
+            &locations.journal_path(&self.instance_uuid),
+            &self.journal(false),
+        )?;
+        std::fs::create_dir(&self.destination)
+            .context(format!("Failed to create {}", self.destination.display()))?;
+        std::fs::write(
+            self.destination.join(MIGRATION_MARKER),
+            serde_json::to_vec_pretty(&self.journal(false))
+                .context("Failed to serialize migration marker")?,
+        )
+        .context(format!(
+            "Failed to write the migration marker in {}",
+            self.destination.display()
+        ))?;
+        for dir in &self.dirs {
+            let path = self.destination.join(dir);
+            std::fs::create_dir(&path).context(format!("Failed to create {}", path.display()))?;
+        }
+        for (file, _) in &self.files {
+            copy_file(
+                &self.source.join(file),
+                &self.destination.join(file),
+                on_progress,
+            )?;
+        }
+        for link in &self.symlinks {
+            copy_symlink(&self.source.join(link), &self.destination.join(link))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the copy holds as many files as the source, each of the same size, and that a
+    /// sample of them has the same content. Blocks until done
+    pub fn verify(&self) -> Result<(), Error> {
+        let verification_failed = |message: String| Error {
+            kind: ErrorKind::Internal,
+            source: eyre!("The copy doesn't match the instance: {}", message),
+        };
+        let copied = walk_dir(&self.destination, MAX_TRAVERSAL_DEPTH)
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .count();
+        // the marker isn't part of the instance
+        if copied != self.files.len() + 1 {
+            return Err(verification_failed(format!(
+                "{} files were copied out of {}",
+                copied.saturating_sub(1),
+                self.files.len()
+            )));
+        }
+        for (file, size) in &self.files {
+            let copied_size = std::fs::metadata(self.destination.join(file))
+                .map(|metadata| metadata.len())
+                .map_err(|e| verification_failed(format!("{}: {}", file.display(), e)))?;
+            if copied_size != *size {
+                return Err(verification_failed(format!(
+                    "{} is {} bytes instead of {}",
+                    file.display(),
+                    copied_size,
+                    size
+                )));
+            }
+        }
+        for (file, _) in self
+            .files
+            .choose_multiple(&mut thread_rng(), VERIFY_SAMPLE_SIZE)
+        {
+            if hash_file(&self.source.join(file))? != hash_file(&self.destination.join(file))? {
+                return Err(verification_failed(format!(
+                    "the content of {} differs",
+                    file.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the copy, the instance stays where it was
+    pub fn discard(&self, locations: &InstanceLocations) {
+        discard_copy(&self.destination);
+        let _ = std::fs::remove_file(locations.journal_path(&self.instance_uuid));
+    }
+
+    /// Makes the copy the instance, an interrupted migration is finished on the next start
+    /// from here on
+    pub async fn commit(
+        &self,
+        locations: &InstanceLocations,
+        instances_dir: &Path,
+    ) -> Result<(), Error> {
+        write_journal(
+            &locations.journal_path(&self.instance_uuid),
+            &self.journal(true),
+        )?;
+        locations
+            .set(
+                &self.instance_uuid,
+                location(&self.destination, instances_dir),
+            )
+            .await
+    }
+
+    /// Removes the source and the migration's bookkeeping once committed
+    pub async fn finish(&self, locations: &InstanceLocations) -> Result<(), Error> {
+        finish(locations, &self.journal(true)).await
+    }
+}
+
+/// `None` for a directory the scan of the instances directory finds on its own
+fn location(instance_dir: &Path, instances_dir: &Path) -> Option<PathBuf> {
+    if instance_dir.parent() == Some(instances_dir) {
+        None
+    } else {
+        Some(instance_dir.to_path_buf())
+    }
+}
+
+fn write_journal(path: &Path, journal: &Journal) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .context(format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(
+        path,
+        serde_json::to_vec_pretty(journal).context("Failed to serialize migration journal")?,
+    )
+    .context(format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+fn copy_file(from: &Path, to: &Path, on_progress: &mut dyn FnMut(u64)) -> Result<(), Error> {
+    let copy = || -> std::io::Result<()> {
+        let mut reader = File::open(from)?;
+        let mut writer = File::create(to)?;
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buf[..read])?;
+            on_progress(read as u64);
+        }
+        writer.sync_all()?;
+        std::fs::set_permissions(to, reader.metadata()?.permissions())
+    };
+    copy().context(format!(
+        "Failed to copy {} to {}",
+        from.display(),
+        to.display()
+    ))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), Error> {
+    let target = std::fs::read_link(from).context(format!("Failed to read {}", from.display()))?;
+    std::os::unix::fs::symlink(target, to)
+        .context(format!("Failed to create the symlink {}", to.display()))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, _to: &Path) -> Result<(), Error> {
+    Err(Error {
+        kind: ErrorKind::UnsupportedOperation,
+        source: eyre!(
+            "{} is a symlink, which can't be migrated on this platform",
+            from.display()
+        ),
+    })
+}
+
+fn hash_file(path: &Path) -> Result<blake3::Hash, Error> {
+    let mut file = File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher).context(format!("Failed to read {}", path.display()))?;
+    Ok(hasher.finalize())
+}
+
+/// Removes a partial copy, only if the marker shows the migration created it
+fn discard_copy(destination: &Path) {
+    if !destination.join(MIGRATION_MARKER).is_file() {
+        return;
+    }
+    if let Err(e) = std::fs::remove_dir_all(destination) {
+        error!(
+            "Failed to remove the partial copy at {}, remove it by hand: {}",
+            destination.display(),
+            e
+        );
+    }
+}
+
+async fn finish(locations: &InstanceLocations, journal: &Journal) -> Result<(), Error> {
+    if journal.source != journal.destination && journal.source.exists() {
+        crate::util::fs::remove_dir_all(&journal.source).await?;
+    }
+    let _ = tokio::fs::remove_file(journal.destination.join(MIGRATION_MARKER)).await;
+    let _ = tokio::fs::remove_file(locations.journal_path(&journal.instance_uuid)).await;
+    Ok(())
+}
+
+/// Finishes the migrations the last daemon committed and undoes the others, before instances
+/// are restored so neither copy is loaded twice
+pub async fn recover(locations: &InstanceLocations, instances_dir: &Path) {
+    let mut dir = match tokio::fs::read_dir(&locations.journal_dir).await {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let journal: Journal = match tokio::fs::read(entry.path())
+            .await
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+        {
+            Some(journal) => journal,
+            None => {
+                warn!(
+                    "Ignoring the migration journal {}, it can't be read",
+                    entry.path().display()
+                );
+                continue;
+            }
+        };
+        if journal.committed {
+            info!(
+                "Finishing the migration of instance {} to {}, interrupted by the last shutdown",
+                journal.instance_uuid,
+                journal.destination.display()
+            );
+            let finished = match locations
+                .set(
+                    &journal.instance_uuid,
+                    location(&journal.destination, instances_dir),
+                )
+                .await
+            {
+                Ok(()) => finish(locations, &journal).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = finished {
+                error!(
+                    "Failed to finish the migration of instance {}: {}",
+                    journal.instance_uuid, e.source
+                );
+            }
+        } else {
+            warn!(
+                "The migration of instance {} to {} was interrupted, removing the partial copy. The instance stays at {}",
+                journal.instance_uuid,
+                journal.destination.display(),
+                journal.source.display()
+            );
+            let destination = journal.destination.clone();
+            let _ = tokio::task::spawn_blocking(move || discard_copy(&destination)).await;
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance_dir(root: &Path) -> PathBuf {
+        let source = root.join("instances").join("survival");
+        std::fs::create_dir_all(source.join("world").join("region")).unwrap();
+        std::fs::write(source.join(DOT_LODESTONE_CONFIG), "{}").unwrap();
+        std::fs::write(source.join("server.properties"), "server-port=25565").unwrap();
+        std::fs::write(
+            source.join("world").join("region").join("r.0.0.mca"),
+            vec![7; 3 * COPY_BUFFER_SIZE + 5],
+        )
+        .unwrap();
+        source
+    }
+
+    #[test]
+    fn test_migrating_flag() {
+        let migrating = MigratingInstances::default();
+        let instance_uuid = InstanceUuid::default();
+        migrating.check(&instance_uuid).unwrap();
+        let guard = migrating.try_begin(&instance_uuid).unwrap();
+        for error in [
+            migrating.check(&instance_uuid).unwrap_err(),
+            migrating.try_begin(&instance_uuid).map(|_| ()).unwrap_err(),
+        ] {
+            assert_eq!(error.code(), ErrorCode::InstanceMigrating);
+        }
+        // another instance isn't held up
+        migrating.try_begin(&InstanceUuid::default()).unwrap();
+        drop(guard);
+        migrating.check(&instance_uuid).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = instance_dir(dir.path());
+        let destination_root = dir.path().join("disk2");
+        std::fs::create_dir(&destination_root).unwrap();
+        let locations = InstanceLocations::new(dir.path()).await.unwrap();
+        let instance_uuid = InstanceUuid::default();
+
+        let migration = Migration::plan(instance_uuid.clone(), &source, &destination_root).unwrap();
+        assert_eq!(migration.bytes, 3 * COPY_BUFFER_SIZE as u64 + 5 + 2 + 17);
+        assert_eq!(
+            migration.files.last().unwrap().0,
+            PathBuf::from(DOT_LODESTONE_CONFIG)
+        );
+        let mut copied = 0;
+        migration
+            .copy(&locations, &mut |bytes| copied += bytes)
+            .unwrap();
+        assert_eq!(copied, migration.bytes);
+        migration.verify().unwrap();
+        migration
+            .commit(&locations, &dir.path().join("instances"))
+            .await
+            .unwrap();
+        migration.finish(&locations).await.unwrap();
+
+        assert!(!source.exists());
+        let destination = destination_root.join("survival");
+        assert!(!destination.join(MIGRATION_MARKER).exists());
+        assert_eq!(
+            std::fs::read(destination.join("server.properties")).unwrap(),
+            b"server-port=25565"
+        );
+        // restored from there on the next start
+        let locations = InstanceLocations::new(dir.path()).await.unwrap();
+        assert_eq!(locations.paths().await, vec![destination]);
+
+        // an instance can't be moved where it already is
+        assert!(Migration::plan(
+            instance_uuid,
+            &destination_root.join("survival"),
+            &destination_root
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_catches_a_bad_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = instance_dir(dir.path());
+        let destination_root = dir.path().join("disk2");
+        std::fs::create_dir(&destination_root).unwrap();
+        let locations = InstanceLocations::new(dir.path()).await.unwrap();
+
+        let migration =
+            Migration::plan(InstanceUuid::default(), &source, &destination_root).unwrap();
+        migration.copy(&locations, &mut |_| {}).unwrap();
+        std::fs::write(
+            migration.destination.join("server.properties"),
+            "server-port=1",
+        )
+        .unwrap();
+        assert!(migration.verify().is_err());
+        std::fs::remove_file(migration.destination.join("server.properties")).unwrap();
+        assert!(migration.verify().is_err());
+
+        migration.discard(&locations);
+        assert!(!migration.destination.exists());
+        assert!(source.join(DOT_LODESTONE_CONFIG).exists());
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let instances_dir = dir.path().join("instances");
+        let source = instance_dir(dir.path());
+        let destination_root = dir.path().join("disk2");
+        std::fs::create_dir(&destination_root).unwrap();
+        let locations = InstanceLocations::new(dir.path()).await.unwrap();
+
+        // interrupted while copying, the partial copy goes
+        let migration =
+            Migration::plan(InstanceUuid::default(), &source, &destination_root).unwrap();
+        migration.copy(&locations, &mut |_| {}).unwrap();
+        recover(&locations, &instances_dir).await;
+        assert!(!migration.destination.exists());
+        assert!(source.exists());
+        assert!(locations.paths().await.is_empty());
+
+        // interrupted after the commit, the source goes
+        let migration =
+            Migration::plan(InstanceUuid::default(), &source, &destination_root).unwrap();
+        migration.copy(&locations, &mut |_| {}).unwrap();
+        write_journal(
+            &locations.journal_path(&migration.instance_uuid),
+            &migration.journal(true),
+        )
+        .unwrap();
+        recover(&locations, &instances_dir).await;
+        assert!(!source.exists());
+        assert!(migration.destination.join(DOT_LODESTONE_CONFIG).exists());
+        assert!(!migration.destination.join(MIGRATION_MARKER).exists());
+        assert_eq!(locations.paths().await, vec![migration.destination.clone()]);
+        assert!(!locations.journal_path(&migration.instance_uuid).exists());
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::Error;
+use crate::global_settings::DEFAULT_MAX_PATH_LENGTH;
+use crate::implementations::minecraft::Flavour;
+use crate::prelude::path_to_binaries;
+use crate::util::{
+    dont_spawn_terminal, download_file, unzip_file_async, DownloadProgress, UnzipOption,
+};
+
+/// `java -version` is reported as failed past this, a broken install must not hang a start
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Majors Adoptium publishes long term builds of, downloads pick the oldest that fits
+const LTS_MAJORS: [u32; 4] = [8, 11, 17, 21];
+
+#[cfg(target_os = "windows")]
+const JAVA_EXECUTABLE: &str = "java.exe";
+#[cfg(not(target_os = "windows"))]
+const JAVA_EXECUTABLE: &str = "java";
+
+/// Directories JDK installers put their runtimes in, one runtime per subdirectory
+#[cfg(target_os = "linux")]
+const SYSTEM_JAVA_DIRS: &[&str] = &["/usr/lib/jvm", "/usr/java", "/opt/java", "/opt/jdk"];
+#[cfg(target_os = "macos")]
+const SYSTEM_JAVA_DIRS: &[&str] = &[
+    "/Library/Java/JavaVirtualMachines",
+    "/opt/homebrew/opt",
+    "/usr/local/opt",
+];
+#[cfg(target_os = "windows")]
+const SYSTEM_JAVA_DIRS: &[&str] = &[
+    "C:\\Program Files\\Java",
+    "C:\\Program Files\\Eclipse Adoptium",
+    "C:\\Program Files\\Microsoft",
+    "C:\\Program Files\\Zulu",
+];
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+const SYSTEM_JAVA_DIRS: &[&str] = &[];
+
+/// Where a detected runtime was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum JavaSource {
+    /// Downloaded by lodestone into the shared runtimes directory
+    Downloaded,
+    Path,
+    /// A common install directory of the host
+    System,
+    /// Pinned by path rather than picked from the detected runtimes
+    Custom,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct JavaRuntime {
+    pub path: PathBuf,
+    /// As printed by `java -version`, e.g. `17.0.8` or `1.8.0_382`
+    pub version: String,
+    pub major_version: u32,
+    pub source: JavaSource,
+}
+
+/// Java majors a game version runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct JavaRequirement {
+    pub min: u32,
+    /// Newest major known to work, `None` if any newer one does
+    pub max: Option<u32>,
+}
+
+impl JavaRequirement {
+    /// `None` for versions that aren't a release, e.g. snapshots, their requirement is unknown
+    pub fn for_minecraft(version: &str, flavour: &Flavour) -> Option<Self> {
+        let mut parts = version.split('.');
+        if parts.next()? != "1" {
+            return None;
+        }
+        let minor: u32 = parts.next()?.parse().ok()?;
+        let patch: u32 = match parts.next() {
+            Some(patch) => patch.parse().ok()?,
+            None => 0,
+        };
+        let min = if minor > 20 || (minor == 20 && patch >= 5) {
+            21
+        } else if minor >= 18 {
+            17
+        } else if minor == 17 {
+            16
+        } else {
+            8
+        };
+        // old Forge relies on internals later Java versions removed
+        let max = match flavour {
+            Flavour::Forge { .. } if minor <= 12 => Some(8),
+            Flavour::Forge { .. } if minor <= 16 => Some(11),
+            _ => None,
+        };
+        Some(Self { min, max })
+    }
+
+    pub fn is_met_by(&self, major_version: u32) -> bool {
+        major_version >= self.min && self.max.map_or(true, |max| major_version <= max)
+    }
+
+    /// The major worth downloading for this requirement
+    pub fn preferred_major(&self) -> u32 {
+        LTS_MAJORS
+            .into_iter()
+            .find(|major| self.is_met_by(*major))
+            .unwrap_or(self.min)
+    }
+}
+
+impl std::fmt::Display for JavaRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "Java {}", self.min),
+            Some(max) => write!(f, "Java {} to {}", self.min, max),
+            None => write!(f, "Java {} or newer", self.min),
+        }
+    }
+}
+
+/// The Java an instance launches with and what its game version needs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct JavaSelection {
+    pub path: PathBuf,
+    /// `None` if the executable is missing or could not tell its version
+    pub runtime: Option<JavaRuntime>,
+    pub requirement: Option<JavaRequirement>,
+}
+
+/// The shared directory downloaded runtimes are kept in
+pub fn path_to_java_runtimes() -> PathBuf {
+    path_to_binaries().join("java")
+}
+
+pub fn downloaded_runtime_dir(runtimes_dir: &Path, major_version: u32) -> PathBuf {
+    runtimes_dir.join(format!("jre{}", major_version))
+}
+
+/// The executable inside a runtime's top directory
+pub fn runtime_executable(runtime_dir: &Path) -> PathBuf {
+    let home = if std::env::consts::OS == "macos" && runtime_dir.join("Contents").is_dir() {
+        runtime_dir.join("Contents").join("Home")
+    } else {
+        runtime_dir.to_path_buf()
+    };
+    home.join("bin").join(JAVA_EXECUTABLE)
+}
+
+pub fn adoptium_url(major_version: u32) -> String {
+    let os = if std::env::consts::OS == "macos" {
+        "mac"
+    } else {
+        std::env::consts::OS
+    };
+    let arch = if std::env::consts::ARCH == "x86_64" {
+        "x64"
+    } else {
+        std::env::consts::ARCH
+    };
+    format!(
+        "https://api.adoptium.net/v3/binary/latest/{}/ga/{}/{}/jre/hotspot/normal/eclipse",
+        major_version, os, arch
+    )
+}
+
+/// Reads the version out of `java -version` output
+fn parse_java_version(output: &str) -> Option<(String, u32)> {
+    let line = output.lines().find(|line| line.contains(" version \""))?;
+    let version = line.split('"').nth(1)?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = match parts.next()?.parse().ok()? {
+        // before Java 9 versions read 1.8.0_382
+        1 => parts.next()?.parse().ok()?,
+        major => major,
+    };
+    Some((version.to_string(), major))
+}
+
+/// Runs `java -version`, `None` if the executable is missing or doesn't answer
+pub async fn probe(path: &Path, source: JavaSource) -> Option<JavaRuntime> {
+    let mut command = tokio::process::Command::new(path);
+    let output = tokio::time::timeout(
+        PROBE_TIMEOUT,
+        dont_spawn_terminal(&mut command)
+            .arg("-version")
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .ok()?
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // the version goes to stderr, some wrappers print it to stdout instead
+    let (version, major_version) = parse_java_version(&String::from_utf8_lossy(&output.stderr))
+        .or_else(|| parse_java_version(&String::from_utf8_lossy(&output.stdout)))?;
+    Some(JavaRuntime {
+        path: path.to_path_buf(),
+        version,
+        major_version,
+        source,
+    })
+}
+
+fn runtimes_in(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| runtime_executable(&entry.path()))
+                .filter(|executable| executable.is_file())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn candidates(runtimes_dir: &Path) -> Vec<(PathBuf, JavaSource)> {
+    let downloaded = runtimes_in(runtimes_dir)
+        .into_iter()
+        .map(|path| (path, JavaSource::Downloaded));
+    let on_path = which::which_all(JAVA_EXECUTABLE)
+        .map(|paths| paths.collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|path| (path, JavaSource::Path));
+    let system = SYSTEM_JAVA_DIRS
+        .iter()
+        .flat_map(|dir| runtimes_in(Path::new(dir)))
+        .map(|path| (path, JavaSource::System));
+    let mut seen = HashSet::new();
+    downloaded
+        .chain(on_path)
+        .chain(system)
+        .filter(|(path, _)| {
+            seen.insert(std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+        })
+        .collect()
+}
+
+/// Every working runtime on the host, those lodestone downloaded first
+pub async fn detect_runtimes(runtimes_dir: &Path) -> Vec<JavaRuntime> {
+    let candidates = candidates(runtimes_dir);
+    futures::future::join_all(candidates.iter().map(|(path, source)| probe(path, *source)))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Downloads the Temurin runtime of `major_version` into `runtimes_dir` unless it's already there,
+/// returning its executable
+pub async fn install_runtime(
+    major_version: u32,
+    runtimes_dir: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<PathBuf, Error> {
+    let runtime_dir = downloaded_runtime_dir(runtimes_dir, major_version);
+    if runtime_dir.exists() {
+        return Ok(runtime_executable(&runtime_dir));
+    }
+    tokio::fs::create_dir_all(runtimes_dir)
+        .await
+        .context("Could not create the Java runtimes directory")?;
+    let downloaded = download_file(
+        &adoptium_url(major_version),
+        runtimes_dir,
+        None,
+        on_download,
+        true,
+    )
+    .await?;
+
+    let unzipped_content = unzip_file_async(
+        &downloaded,
+        UnzipOption::ToDir(runtimes_dir.to_path_buf()),
+        DEFAULT_MAX_PATH_LENGTH as usize,
+        false,
+    )
+    .await?;
+    if unzipped_content.len() != 1 {
+        return Err(eyre!(
+            "Expected only one file in the JRE archive, got {}",
+            unzipped_content.len()
+        )
+        .into());
+    }
+
+    tokio::fs::remove_file(&downloaded).await.context(format!(
+        "Could not remove downloaded JRE file {}",
+        downloaded.display()
+    ))?;
+
+    let unzipped = unzipped_content.iter().last().unwrap();
+    tokio::fs::rename(unzipped, &runtime_dir)
+        .await
+        .context(format!(
+            "Could not rename JRE directory {}",
+            unzipped.display()
+        ))?;
+    Ok(runtime_executable(&runtime_dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_java_version() {
+        assert_eq!(
+            parse_java_version(
+                "openjdk version \"17.0.8\" 2023-07-18\nOpenJDK Runtime Environment Temurin-17.0.8+7 (build 17.0.8+7)"
+            ),
+            Some(("17.0.8".to_string(), 17))
+        );
+        assert_eq!(
+            parse_java_version("java version \"1.8.0_382\"\nJava(TM) SE Runtime Environment"),
+            Some(("1.8.0_382".to_string(), 8))
+        );
+        assert_eq!(
+            parse_java_version(
+                "Picked up _JAVA_OPTIONS: -Xmx1G\nopenjdk version \"21\" 2023-09-19"
+            ),
+            Some(("21".to_string(), 21))
+        );
+        assert_eq!(parse_java_version("bash: java: command not found"), None);
+    }
+
+    #[test]
+    fn test_minecraft_requirement() {
+        let vanilla = |version| JavaRequirement::for_minecraft(version, &Flavour::Vanilla);
+        assert_eq!(vanilla("1.20.1").unwrap().min, 17);
+        assert_eq!(vanilla("1.20.5").unwrap().min, 21);
+        assert_eq!(vanilla("1.21").unwrap().min, 21);
+        assert_eq!(vanilla("1.17.1").unwrap().preferred_major(), 17);
+        assert_eq!(vanilla("1.12.2").unwrap().preferred_major(), 8);
+        assert!(vanilla("1.12.2").unwrap().is_met_by(17));
+        assert_eq!(vanilla("23w31a"), None);
+
+        let forge = JavaRequirement::for_minecraft(
+            "1.12.2",
+            &Flavour::Forge {
+                build_version: None,
+            },
+        )
+        .unwrap();
+        assert!(forge.is_met_by(8));
+        assert!(!forge.is_met_by(17));
+        assert_eq!(forge.to_string(), "Java 8");
+    }
+}
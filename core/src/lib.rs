@@ -3,57 +3,70 @@
 use crate::error::ErrorKind;
 use crate::event_broadcaster::EventBroadcaster;
 use crate::handlers::extension::get_extension_routes;
+use crate::handlers::health::{get_health_routes, HealthState};
+use crate::handlers::openapi::get_openapi_routes;
 use crate::migration::migrate;
 use crate::prelude::{
-    init_app_state, init_paths, lodestone_path, path_to_global_settings, path_to_stores,
-    path_to_tmp, path_to_users, VERSION,
+    init_app_state, init_paths, lodestone_path, path_to_global_settings, path_to_instances,
+    path_to_stores, path_to_tmp, path_to_users, VERSION,
 };
 use crate::traits::t_configurable::GameType;
-use crate::traits::t_server::State;
+use crate::traits::t_server::RconStatus;
 use crate::{
     db::write::write_event_to_db_task,
     global_settings::GlobalSettingsData,
     handlers::{
-        checks::get_checks_routes, core_info::get_core_info_routes, events::get_events_routes,
+        audit::get_audit_routes, checks::get_checks_routes, core_info::get_core_info_routes,
+        events::get_events_routes, feature_stubs::get_feature_stub_routes,
         gateway::get_gateway_routes, global_fs::get_global_fs_routes,
         global_settings::get_global_settings_routes, instance::*,
-        instance_config::get_instance_config_routes, instance_fs::get_instance_fs_routes,
-        instance_macro::get_instance_macro_routes, instance_players::get_instance_players_routes,
-        instance_server::get_instance_server_routes,
+        instance_announcements::get_instance_announcements_routes,
+        instance_backups::get_instance_backups_routes, instance_chat::get_instance_chat_routes,
+        instance_config::get_instance_config_routes, instance_datapacks::get_instance_datapacks_routes,
+        instance_diagnostics::get_instance_diagnostics_routes, instance_fs::get_instance_fs_routes,
+        instance_game::get_instance_game_routes, instance_logs::get_instance_logs_routes,
+        instance_macro::get_instance_macro_routes,
+        instance_macro_triggers::get_instance_macro_triggers_routes,
+        instance_mods::get_instance_mods_routes,
+        instance_players::get_instance_players_routes,
+        instance_server::get_instance_server_routes, instance_tasks::get_instance_tasks_routes,
+        instance_worlds::get_instance_worlds_routes,
         instance_setup_configs::get_instance_setup_config_routes, monitor::get_monitor_routes,
-        playitgg::get_playitgg_routes, setup::get_setup_route, system::get_system_routes,
-        users::get_user_routes,
+        networks::get_network_routes, notifications::get_notification_routes, playitgg::get_playitgg_routes,
+        ports::get_ports_routes, setup::get_setup_route, system::get_system_routes, templates::get_templates_routes,
+        users::get_user_routes, util::cloned_handles,
     },
     util::rand_alphanumeric,
 };
+#[cfg(feature = "s3-backups")]
+use handlers::remote_backups::get_remote_backups_routes;
 
 use auth::user::UsersManager;
-use axum::Router;
+use axum::{Extension, Router};
 
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use color_eyre::eyre::Context;
+use color_eyre::eyre::{eyre, Context};
 use color_eyre::Report;
 use dashmap::DashMap;
 use error::Error;
 use events::{CausedBy, Event};
-use futures::Future;
+use futures::{Future, StreamExt};
 use global_settings::GlobalSettings;
-use implementations::{generic, minecraft};
+use implementations::{command, generic, minecraft};
 use macro_executor::MacroExecutor;
 use playitgg::utils::is_valid_secret_key;
 use port_manager::PortManager;
 use prelude::GameInstance;
-use reqwest::{header, Method};
+use reqwest::header;
 use ringbuffer::{AllocRingBuffer, RingBufferWrite};
 
-use fs3::FileExt;
 use semver::Version;
 use sqlx::{sqlite::SqliteConnectOptions, Pool};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
-    collections::{HashMap, HashSet},
-    net::SocketAddr,
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
@@ -64,10 +77,7 @@ use tokio::{
     select,
     sync::{broadcast::error::RecvError, Mutex, RwLock},
 };
-use tower_http::{
-    cors::{Any, CorsLayer},
-    trace::TraceLayer,
-};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter};
@@ -75,26 +85,70 @@ use traits::{t_configurable::TConfigurable, t_server::MonitorReport, t_server::T
 use types::{DotLodestoneConfig, InstanceUuid};
 use uuid::Uuid;
 
+mod announcements;
+mod api_version;
+mod archive_manifest;
+mod audit;
 pub mod auth;
+mod auto_start;
+mod backups;
+mod broken_instances;
+mod cancellation;
+mod capacity;
 mod command_console;
+mod command_queue;
+mod console_capture;
+mod console_history;
+mod creation_status;
+mod daemon;
 pub mod db;
+mod demo;
 mod deno_ops;
+mod diagnostics;
+mod disk_usage;
 mod docker_bridge;
+mod downloads;
 pub mod error;
 mod event_broadcaster;
 mod events;
 mod extension;
+pub mod features;
+mod fs_search;
+mod fs_watch;
 pub mod global_settings;
 mod handlers;
+mod http_config;
+mod idempotency;
 pub mod implementations;
+mod info_cache;
+mod instance_archive;
+mod instance_migration;
+mod java;
 pub mod macro_executor;
+mod macro_triggers;
+mod maintenance;
 mod migration;
+mod networks;
+mod notifications;
 mod output_types;
 pub mod playitgg;
 mod port_manager;
 pub mod prelude;
+mod process_tree;
+#[cfg(feature = "s3-backups")]
+mod remote_backups;
+mod request_context;
+mod restart_policy;
+mod run_record;
+mod scheduler;
+mod shutdown;
+mod snapshot;
+mod startup;
 pub mod tauri_export;
+mod templates;
+mod tls;
 mod traits;
+mod trash;
 pub mod types;
 pub mod util;
 use handlers::global_fs::DownloadableFile;
@@ -110,6 +164,7 @@ pub struct AppState {
     uuid: String,
     up_since: i64,
     global_settings: Arc<Mutex<GlobalSettings>>,
+    admission: capacity::Admission,
     system: Arc<Mutex<sysinfo::System>>,
     port_manager: Arc<Mutex<PortManager>>,
     first_time_setup_key: Arc<Mutex<Option<String>>>,
@@ -119,6 +174,35 @@ pub struct AppState {
     sqlite_pool: sqlx::SqlitePool,
     docker_bridge: docker_bridge::DockerBridge,
     playit_keep_running: Arc<Mutex<Option<Arc<AtomicBool>>>>,
+    scheduler: scheduler::Scheduler,
+    macro_triggers: macro_triggers::MacroTriggers,
+    broken_instances: broken_instances::BrokenInstances,
+    /// Instances moved out of the instances directory
+    instance_locations: instance_migration::InstanceLocations,
+    migrating: instance_migration::MigratingInstances,
+    backup_manager: backups::BackupManager,
+    creation_registry: creation_status::CreationRegistry,
+    cancellation_registry: cancellation::CancellationRegistry,
+    audit_log: audit::AuditLog,
+    notification_manager: notifications::NotificationManager,
+    networks: networks::Networks,
+    templates: templates::Templates,
+    idempotency: idempotency::IdempotencyStore,
+    login_limiter: auth::login_limiter::LoginLimiter,
+    disk_usage: disk_usage::DiskUsageTracker,
+    instance_info: info_cache::InstanceInfoCache,
+    fs_watchers: fs_watch::FsWatchManager,
+    demo_mode: bool,
+    http_port: u16,
+    /// Set once startup finished, `/readyz` answers 503 until then
+    ready: Arc<AtomicBool>,
+    startup_timings: Arc<Mutex<Option<startup::StartupTimings>>>,
+    /// Requests served on the deprecated unprefixed paths
+    legacy_traffic: api_version::LegacyTraffic,
+    /// Cancelled to shut the daemon down, e.g. by `POST /system/shutdown`
+    shutdown: CancellationToken,
+    /// Allowed origins set through the daemon settings
+    cors_origins: http_config::OriginOverride,
 }
 
 impl AppState {
@@ -135,12 +219,93 @@ impl AppState {
     }
 }
 
+/// Loads the instance in one directory of the instances directory
+pub(crate) async fn restore_instance(
+    path: &Path,
+    event_broadcaster: EventBroadcaster,
+    macro_executor: MacroExecutor,
+) -> Result<(InstanceUuid, GameInstance), Error> {
+    let dot_lodestone_config_file = std::fs::File::open(path.join(".lodestone_config"))
+        .context("Failed to read .lodestone_config file")?;
+    let dot_lodestone_config: DotLodestoneConfig =
+        serde_json::from_reader(dot_lodestone_config_file)
+            .context("Failed to parse .lodestone_config file")?;
+
+    debug!("restoring instance: {}", path.display());
+    let instance: GameInstance = match dot_lodestone_config.game_type() {
+        GameType::MinecraftJava => {
+            let instance = minecraft::MinecraftInstance::restore(
+                path.to_owned(),
+                dot_lodestone_config.clone(),
+                event_broadcaster,
+                macro_executor,
+            )
+            .await
+            .context("Failed to restore Minecraft Java instance")?;
+            if let Some(server_jar) = instance.preflight_target().await?.server_jar {
+                if !server_jar.is_file() {
+                    return Err(Error {
+                        kind: ErrorKind::NotFound,
+                        source: eyre!("The server jar {} is missing", server_jar.display()),
+                    });
+                }
+            }
+            debug!("Restored Minecraft Java instance successfully");
+            instance.into()
+        }
+        GameType::Generic => {
+            let instance = generic::GenericInstance::restore(
+                path.to_owned(),
+                dot_lodestone_config.clone(),
+                event_broadcaster,
+                macro_executor,
+            )
+            .await
+            .context("Failed to restore atom instance")?;
+            debug!("Restored Generic instance successfully");
+            instance.into()
+        }
+        GameType::Command => {
+            let instance = command::CommandInstance::restore(
+                path.to_owned(),
+                dot_lodestone_config.clone(),
+                event_broadcaster,
+            )
+            .await
+            .context("Failed to restore command instance")?;
+            debug!("Restored command instance successfully");
+            instance.into()
+        }
+        GameType::MinecraftBedrock => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Minecraft Bedrock instances are not supported"),
+            })
+        }
+    };
+    Ok((dot_lodestone_config.uuid().to_owned(), instance))
+}
+
+/// Instances restored at once
+const RESTORE_CONCURRENCY: usize = 8;
+
+/// Loads every instance directory, along with the ones migrated to `external_paths`. Ones that
+/// fail to load are returned with the reason instead, without keeping the others from loading
 async fn restore_instances(
     instances_path: &Path,
+    external_paths: Vec<PathBuf>,
     event_broadcaster: EventBroadcaster,
     macro_executor: MacroExecutor,
-) -> Result<DashMap<InstanceUuid, GameInstance>, Error> {
+) -> Result<
+    (
+        DashMap<InstanceUuid, GameInstance>,
+        Vec<broken_instances::BrokenInstance>,
+    ),
+    Error,
+> {
     let ret: DashMap<InstanceUuid, GameInstance> = DashMap::new();
+    let mut broken = Vec::new();
+    let mut paths = Vec::new();
 
     for entry in instances_path
         .read_dir()
@@ -153,77 +318,76 @@ async fn restore_instances(
                 continue;
             }
         };
-        let dot_lodestone_config_file = match std::fs::File::open(path.join(".lodestone_config")) {
-            Ok(v) => v,
-            Err(e) => {
-                error!("Error while restoring instance {}, failed to read .lodestone_config file : {e}", path.display());
-                continue;
-            }
-        };
-        let dot_lodestone_config: DotLodestoneConfig = match serde_json::from_reader(
-            dot_lodestone_config_file,
-        ) {
-            Ok(v) => v,
-            Err(e) => {
-                error!("Error while restoring instance {}, failed to parse .lodestone_config file : {e}", path.display());
-                continue;
+        // stray files aren't instances
+        if !path.is_dir() {
+            continue;
+        }
+        paths.push(path);
+    }
+    for path in external_paths {
+        if !path.is_dir() {
+            error!(
+                "Error while restoring instance {} : the directory is missing",
+                path.display()
+            );
+            broken.push(broken_instances::BrokenInstance::new(
+                path,
+                "The instance directory is missing, is its disk mounted?".to_string(),
+            ));
+            continue;
+        }
+        paths.push(path);
+    }
+    // kept in order, so of two instances with the same UUID the same one loads on every start
+    let restored: Vec<(PathBuf, Result<(InstanceUuid, GameInstance), Error>)> =
+        futures::stream::iter(paths)
+            .map(|path| {
+                // spawned, restoring reads files blocking and would otherwise run one at a time
+                let restoring = tokio::spawn({
+                    let path = path.clone();
+                    let event_broadcaster = event_broadcaster.clone();
+                    let macro_executor = macro_executor.clone();
+                    async move { restore_instance(&path, event_broadcaster, macro_executor).await }
+                });
+                async move {
+                    let result = restoring.await.unwrap_or_else(|e| {
+                        Err(Error {
+                            kind: ErrorKind::Internal,
+                            source: eyre!("Restoring the instance panicked: {e}"),
+                        })
+                    });
+                    (path, result)
+                }
+            })
+            .buffered(RESTORE_CONCURRENCY)
+            .collect()
+            .await;
+    for (path, result) in restored {
+        match result {
+            Ok((uuid, _)) if ret.contains_key(&uuid) => {
+                warn!("UUID {} is repeated.", uuid.to_string());
+                broken.push(broken_instances::BrokenInstance::new(
+                    path,
+                    format!("Another instance already has the UUID {}", uuid),
+                ));
             }
-        };
-
-        debug!("restoring instance: {}", path.display());
-        let uuid_instance: (InstanceUuid, GameInstance) = match dot_lodestone_config.game_type() {
-            GameType::MinecraftJava => {
-                let instance = match minecraft::MinecraftInstance::restore(
-                    path.to_owned(),
-                    dot_lodestone_config.clone(),
-                    event_broadcaster.clone(),
-                    macro_executor.clone(),
-                )
-                .await
-                {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!(
-                            "Error while restoring Minecraft Java instance {} : {e}",
-                            path.display()
-                        );
-                        continue;
-                    }
-                };
-                debug!("Restored Minecraft Java instance successfully");
-                (dot_lodestone_config.uuid().to_owned(), instance.into())
+            Ok((uuid, instance)) => {
+                ret.insert(uuid, instance);
             }
-            GameType::Generic => {
-                let instance = match generic::GenericInstance::restore(
-                    path.to_owned(),
-                    dot_lodestone_config.clone(),
-                    event_broadcaster.clone(),
-                    macro_executor.clone(),
-                )
-                .await
-                {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!(
-                            "Error while restoring atom instance {} : {e}",
-                            path.display()
-                        );
-                        continue;
-                    }
-                };
-                debug!("Restored Generic instance successfully");
-                (dot_lodestone_config.uuid().to_owned(), instance.into())
+            Err(e) => {
+                error!(
+                    "Error while restoring instance {} : {}",
+                    path.display(),
+                    e.source
+                );
+                broken.push(broken_instances::BrokenInstance::new(
+                    path,
+                    format!("{:#}", e.source),
+                ));
             }
-            GameType::MinecraftBedrock => todo!(),
-        };
-        let uuid = uuid_instance.0;
-        let instance = uuid_instance.1;
-        if ret.contains_key(&uuid) {
-            warn!("UUID {} is repeated.", uuid.to_string());
         }
-        ret.insert(uuid, instance);
     }
-    Ok(ret)
+    Ok((ret, broken))
 }
 
 fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
@@ -398,6 +562,34 @@ pub struct Args {
     pub is_desktop: bool,
     #[arg(short, long)]
     pub lodestone_path: Option<PathBuf>,
+    /// Run against seeded data in a temporary directory that is wiped on exit
+    #[arg(long, default_value = "false")]
+    pub demo: bool,
+    /// Seed of the demo data, the same seed always produces the same data
+    #[arg(long, default_value = "0")]
+    pub demo_seed: u64,
+    /// HTTP port, when unset the first free port from 16662 is used
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Seconds running instances get to stop on shutdown before they are killed
+    #[arg(long, default_value_t = shutdown::DEFAULT_SHUTDOWN_TIMEOUT_SECS)]
+    pub shutdown_timeout: u64,
+    /// Leave running instances running on shutdown, the next start warns about them
+    #[arg(long, default_value = "false")]
+    pub no_stop_instances: bool,
+}
+
+/// Resolves on SIGTERM, what service managers stop the daemon with. Never on other platforms
+async fn terminate_signal() {
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+            return;
+        }
+        Err(e) => warn!("Failed to listen for SIGTERM: {}", e),
+    }
+    std::future::pending::<()>().await
 }
 
 pub async fn run(
@@ -411,10 +603,23 @@ pub async fn run(
     ),
     Error,
 > {
+    let mut startup = startup::StartupTimer::new();
     let _ = color_eyre::install().map_err(|e| {
         error!("Failed to install color_eyre: {}", e);
     });
-    let lodestone_path = if let Some(path) = args.lodestone_path {
+    let demo_dir = if args.demo {
+        Some(
+            tempfile::Builder::new()
+                .prefix("lodestone_demo")
+                .tempdir()
+                .context("Failed to create demo directory")?,
+        )
+    } else {
+        None
+    };
+    let lodestone_path = if let Some(demo_dir) = &demo_dir {
+        demo_dir.path().to_path_buf()
+    } else if let Some(path) = args.lodestone_path {
         path
     } else {
         PathBuf::from(match std::env::var("LODESTONE_PATH") {
@@ -453,31 +658,51 @@ pub async fn run(
         warn!("Lodestone Core is not meant to be run as a standalone program. Please use Lodestone CLI instead.");
         warn!("Download it here: https://github.com/Lodestone-Team/lodestone_cli")
     }
-    check_for_core_update().await;
+    if args.demo {
+        info!("Lodestone Core running in demo mode, all data is discarded on exit");
+    } else {
+        // in the background, a slow or unreachable GitHub shouldn't hold up startup
+        tokio::spawn(check_for_core_update());
+    }
     output_sys_info();
 
-    let lockfile_path = lodestone_path.join("lodestone.lock");
-    let lock_file =
-        std::fs::File::create(lockfile_path.as_path()).expect("failed to create lockfile");
-    if lock_file.try_lock_exclusive().is_err() {
-        return Err(Error {
-            kind: ErrorKind::Internal,
-            source: Report::msg("Another instance of lodestone is running"),
-        });
+    let lock_file = daemon::lock_data_dir(&lodestone_path)?;
+    let http_config = http_config::HttpConfig::from_env()?;
+    // a configured certificate that can't be used fails startup instead of falling back to HTTP
+    let tls_paths = tls::TlsPaths::from_env(&lodestone_path)?;
+    let tls_config = match &tls_paths {
+        Some(paths) => Some(RustlsConfig::from_config(Arc::new(
+            tls::load_server_config(paths)?,
+        ))),
+        None => None,
+    };
+    let http_port = daemon::pick_http_port(args.port)?;
+    if args.port.is_none() && http_port != daemon::DEFAULT_HTTP_PORT {
+        info!(
+            "Port {} is taken, likely by another daemon, using {} instead",
+            daemon::DEFAULT_HTTP_PORT,
+            http_port
+        );
     }
 
     let _ = migrate(&lodestone_path).map_err(|e| {
         error!("Error while migrating lodestone: {}. Lodestone will still start, but one or more instance may be in an erroneous state", e);
     });
 
-    let path_to_instances = lodestone_path.join("instances");
+    let path_to_instances = path_to_instances().clone();
 
     let (tx, _rx) = EventBroadcaster::new(512);
+    startup.phase("paths and migration");
 
     let mut users_manager = UsersManager::new(tx.clone(), HashMap::new(), path_to_users().clone());
 
     users_manager.load_users().await?;
 
+    let demo_data = args.demo.then(|| demo::DemoData::generate(args.demo_seed));
+    if let Some(demo_data) = &demo_data {
+        demo_data.seed_users(&mut users_manager).await?;
+    }
+
     let mut global_settings = GlobalSettings::new(
         path_to_global_settings().clone(),
         tx.clone(),
@@ -485,56 +710,90 @@ pub async fn run(
     );
 
     global_settings.load_from_file().await?;
+    let bind_address = global_settings
+        .bind_address()
+        .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    let cors_origins = http_config::OriginOverride::default();
+    cors_origins.set(global_settings.cors_allowed_origins());
 
     let first_time_setup_key = if !users_manager.as_ref().iter().any(|(_, user)| user.is_owner) {
-        let key = rand_alphanumeric(16);
-        // log the first time setup key in green so it's easy to find
-        info!(
-            "First time setup key: {}",
-            ansi_term::Color::Green.paint(key.clone())
-        );
-        info!("This is a one-time, in-memory randomly generated key that allows you to create the owner account.");
-        info!(
-            "{}",
-            ansi_term::Color::Red.paint("DO NOT SHARE THIS KEY WITH ANYONE!")
-        );
-        Some(key)
+        match std::env::var("LODESTONE_SETUP_KEY") {
+            Ok(key) if !key.is_empty() => {
+                info!("Using the first time setup key from LODESTONE_SETUP_KEY");
+                Some(key)
+            }
+            _ => {
+                let key = rand_alphanumeric(16);
+                // log the first time setup key in green so it's easy to find
+                info!(
+                    "First time setup key: {}",
+                    ansi_term::Color::Green.paint(key.clone())
+                );
+                info!("This is a one-time, in-memory randomly generated key that allows you to create the owner account.");
+                info!(
+                    "{}",
+                    ansi_term::Color::Red.paint("DO NOT SHARE THIS KEY WITH ANYONE!")
+                );
+                Some(key)
+            }
+        }
     } else {
         None
     };
 
-    let playitgg_key = if let Ok(playitgg_file) =
+    // validated in the background once the state is set up, it takes a request to playit.gg
+    let unvalidated_playitgg_key = if let Ok(playitgg_file) =
         tokio::fs::read_to_string(lodestone_path.join("playit.toml")).await
     {
         let toml_data: toml::Table = toml::from_str(&playitgg_file).unwrap();
-        if let Some(res) = toml_data["secret_key"].as_str() {
-            if is_valid_secret_key(res.to_string()).await {
-                println!("Validated playitgg key...");
-                Some(res.to_string())
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+        toml_data["secret_key"].as_str().map(str::to_string)
     } else {
         None
     };
+    startup.phase("users and settings");
 
     let macro_executor = MacroExecutor::new(tx.clone(), tokio::runtime::Handle::current());
-    let instances = restore_instances(&path_to_instances, tx.clone(), macro_executor.clone())
-        .await
-        .map_err(|_| Error {
-            kind: ErrorKind::Internal,
-            source: Report::msg("failed to restore instances"),
-        })?;
+    let instance_locations = instance_migration::InstanceLocations::new(path_to_stores()).await?;
+    // before restoring, so an interrupted migration doesn't load the instance twice
+    instance_migration::recover(&instance_locations, &path_to_instances).await;
+    let (instances, broken_instances) = restore_instances(
+        &path_to_instances,
+        instance_locations.paths().await,
+        tx.clone(),
+        macro_executor.clone(),
+    )
+    .await
+    .map_err(|_| Error {
+        kind: ErrorKind::Internal,
+        source: Report::msg("failed to restore instances"),
+    })?;
+    startup.phase("restoring instances");
+    for detached in shutdown::take_detached_instances(&lodestone_path).await {
+        warn!(
+            "Instance {} ({}) is still running as process {} from before the last shutdown, its console can't be reattached. Stop the process before starting the instance again",
+            detached.name, detached.uuid, detached.pid
+        );
+    }
 
-    let mut allocated_ports = HashSet::new();
-    for instance_entry in instances.iter() {
-        allocated_ports.insert(instance_entry.value().port().await);
+    let mut allocated_ports = HashMap::new();
+    // the ports are awaited, which mustn't happen while holding a guard into the map
+    for (uuid, instance) in cloned_handles(&instances) {
+        allocated_ports.insert(instance.port().await, uuid.clone());
+        if let Ok(RconStatus {
+            port: Some(rcon_port),
+            ..
+        }) = instance.rcon_status().await
+        {
+            allocated_ports.insert(rcon_port, uuid);
+        }
     }
+    startup.phase("allocating ports");
+    let port_range = global_settings.port_range();
+    let cancellation_registry = cancellation::CancellationRegistry::new();
+    let instances = Arc::new(instances);
+    let global_settings = Arc::new(Mutex::new(global_settings));
     let shared_state = AppState {
-        instances: Arc::new(instances),
+        instances: instances.clone(),
         users_manager: Arc::new(RwLock::new(users_manager)),
         events_buffer: Arc::new(Mutex::new(AllocRingBuffer::with_capacity(512))),
         console_out_buffer: Arc::new(Mutex::new(HashMap::new())),
@@ -542,13 +801,14 @@ pub async fn run(
         event_broadcaster: tx.clone(),
         uuid: Uuid::new_v4().to_string(),
         up_since: chrono::Utc::now().timestamp(),
-        port_manager: Arc::new(Mutex::new(PortManager::new(allocated_ports))),
+        port_manager: Arc::new(Mutex::new(PortManager::new(allocated_ports, port_range))),
         first_time_setup_key: Arc::new(Mutex::new(first_time_setup_key)),
-        playitgg_key: Arc::new(Mutex::new(playitgg_key)),
+        playitgg_key: Arc::new(Mutex::new(None)),
         system: Arc::new(Mutex::new(sysinfo::System::new_all())),
         download_urls: Arc::new(Mutex::new(HashMap::new())),
         playit_keep_running: Arc::new(Mutex::new(None)),
-        global_settings: Arc::new(Mutex::new(global_settings)),
+        global_settings: global_settings.clone(),
+        admission: capacity::Admission::new(instances, global_settings),
         macro_executor,
         sqlite_pool: Pool::connect_with(
             SqliteConnectOptions::from_str(&format!(
@@ -572,24 +832,59 @@ pub async fn run(
         )
         .await
         .unwrap(),
+        scheduler: scheduler::Scheduler::new(path_to_stores().join("scheduled_tasks.json"))
+            .await?,
+        macro_triggers: macro_triggers::MacroTriggers::new(
+            path_to_stores().join("macro_triggers.json"),
+        )
+        .await?,
+        broken_instances: broken_instances::BrokenInstances::new(broken_instances),
+        instance_locations,
+        migrating: instance_migration::MigratingInstances::default(),
+        backup_manager: backups::BackupManager::new(cancellation_registry.clone()),
+        creation_registry: creation_status::CreationRegistry::new(),
+        cancellation_registry,
+        audit_log: audit::AuditLog::new(path_to_stores().join("audit")),
+        notification_manager: notifications::NotificationManager::new(
+            path_to_stores().join("notifications.json"),
+        )
+        .await?,
+        networks: networks::Networks::new(path_to_stores().join("networks.json")).await?,
+        templates: templates::Templates::new(path_to_stores().join("templates")).await?,
+        idempotency: idempotency::IdempotencyStore::new(),
+        login_limiter: auth::login_limiter::LoginLimiter::new(),
+        disk_usage: disk_usage::DiskUsageTracker::new(),
+        instance_info: info_cache::InstanceInfoCache::new(&tx),
+        fs_watchers: fs_watch::FsWatchManager::new(),
+        demo_mode: args.demo,
+        http_port,
+        ready: Arc::new(AtomicBool::new(false)),
+        startup_timings: Arc::new(Mutex::new(None)),
+        legacy_traffic: api_version::LegacyTraffic::default(),
+        shutdown: CancellationToken::new(),
+        cors_origins,
     };
+    startup.phase("loading state");
+
+    if let Some(key) = unvalidated_playitgg_key {
+        let playitgg_key = shared_state.playitgg_key.clone();
+        tokio::spawn(async move {
+            if is_valid_secret_key(key.clone()).await {
+                println!("Validated playitgg key...");
+                *playitgg_key.lock().await = Some(key);
+            }
+        });
+    }
 
     command_console::init(shared_state.clone());
     init_app_state(shared_state.clone());
 
-    for mut entry in shared_state.instances.iter_mut() {
-        let instance = entry.value_mut();
-        if instance.auto_start().await {
-            info!("Auto starting instance {}", instance.name().await);
-            if let Err(e) = instance.start(CausedBy::System, false).await {
-                error!(
-                    "Failed to start instance {}: {:?}",
-                    entry.value().name().await,
-                    e
-                );
-            }
-        }
-    }
+    // in the background so a slow start doesn't hold up the API
+    tokio::spawn(auto_start::auto_start_instances(
+        shared_state.instances.clone(),
+        shared_state.admission.clone(),
+        tx.clone(),
+    ));
 
     let event_buffer_task = {
         let event_buffer = shared_state.events_buffer.clone();
@@ -611,6 +906,9 @@ pub async fn run(
                     }
                 }
                 let event = result.unwrap();
+                if event.is_event_file_change() {
+                    continue;
+                }
                 if event.is_event_console_message() {
                     console_out_buffer
                         .lock()
@@ -625,7 +923,16 @@ pub async fn run(
         }
     };
 
-    let write_to_db_task = write_event_to_db_task(tx.subscribe(), shared_state.sqlite_pool.clone());
+    let flush_events = CancellationToken::new();
+    let mut write_to_db_task = tokio::spawn(write_event_to_db_task(
+        tx.subscribe(),
+        shared_state.sqlite_pool.clone(),
+        flush_events.clone(),
+    ));
+
+    if let Some(demo_data) = &demo_data {
+        demo_data.seed_events(&tx);
+    }
 
     let monitor_report_task = {
         let monitor_buffer = shared_state.monitor_buffer.clone();
@@ -633,12 +940,12 @@ pub async fn run(
         async move {
             let mut interval = tokio::time::interval(Duration::from_secs(1));
             loop {
-                for entry in instances.iter() {
-                    let report = entry.value().monitor().await;
+                for (uuid, instance) in cloned_handles(&instances) {
+                    let report = instance.monitor().await;
                     monitor_buffer
                         .lock()
                         .await
-                        .entry(entry.key().to_owned())
+                        .entry(uuid)
                         .or_insert_with(|| AllocRingBuffer::with_capacity(64))
                         .push(report);
                 }
@@ -647,30 +954,88 @@ pub async fn run(
         }
     };
 
-    let tls_config_result = RustlsConfig::from_pem_file(
-        lodestone_path.join("tls").join("cert.pem"),
-        lodestone_path.join("tls").join("key.pem"),
-    )
-    .await;
+    let scheduler_task = shared_state
+        .scheduler
+        .clone()
+        .run(
+            shared_state.instances.clone(),
+            shared_state.admission.clone(),
+            tx.clone(),
+            shared_state.users_manager.clone(),
+            shared_state.global_settings.clone(),
+        );
+
+    let macro_triggers_task = shared_state
+        .macro_triggers
+        .clone()
+        .run(shared_state.instances.clone(), tx.clone());
+
+    let backup_schedule_task = shared_state
+        .backup_manager
+        .clone()
+        .run_schedules(
+            shared_state.instances.clone(),
+            tx.clone(),
+            shared_state.global_settings.clone(),
+        );
+
+    let creation_status_task = shared_state.creation_registry.clone().run(&tx);
+    let cancellation_task = shared_state.cancellation_registry.clone().run(&tx);
+
+    let audit_task = shared_state
+        .audit_log
+        .clone()
+        .run(shared_state.instances.clone(), &tx);
+
+    let notification_task = shared_state
+        .notification_manager
+        .clone()
+        .run(shared_state.users_manager.clone(), &tx);
+
+    let trash_purge_task = trash::run_purge(
+        shared_state.instances.clone(),
+        shared_state.global_settings.clone(),
+    );
+
+    startup.phase("starting tasks");
+    let startup_timings = startup.finish();
+    startup_timings.log();
+    *shared_state.startup_timings.lock().await = Some(startup_timings);
+
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+    let no_stop_instances = args.no_stop_instances;
 
     Ok((
         {
             let shared_state = shared_state.clone();
             async move {
-                let cors = CorsLayer::new()
-                    .allow_methods([
-                        Method::GET,
-                        Method::POST,
-                        Method::PATCH,
-                        Method::PUT,
-                        Method::DELETE,
-                        Method::OPTIONS,
-                    ])
-                    .allow_headers([header::ORIGIN, header::CONTENT_TYPE, header::AUTHORIZATION]) // Note I can't find X-Auth-Token but it was in the original rocket version, hope it's fine
-                    .allow_origin(Any);
-
-                let trace = TraceLayer::new_for_http();
+                let request_id_header =
+                    header::HeaderName::from_static(request_context::REQUEST_ID_HEADER);
+                let cors = http_config.cors.layer(
+                    &[
+                        request_id_header,
+                        header::HeaderName::from_static(idempotency::IDEMPOTENCY_KEY_HEADER),
+                        header::HeaderName::from_static(idempotency::IDEMPOTENT_REPLAYED_HEADER),
+                    ],
+                    shared_state.cors_origins.clone(),
+                );
+
+                // logs the route instead of the URI, which can hold setup keys and tokens
+                let log_requests = axum::middleware::from_fn(request_context::log_requests);
+                let replay_idempotent = axum::middleware::from_fn_with_state(
+                    shared_state.idempotency.clone(),
+                    idempotency::replay_idempotent,
+                );
+
+                // routes of the optional subsystems compiled in, the stubs answer the others
+                #[allow(unused_mut)]
+                let mut feature_routes = Router::new();
+                #[cfg(feature = "s3-backups")]
+                {
+                    feature_routes =
+                        feature_routes.merge(get_remote_backups_routes(shared_state.clone()));
+                }
 
                 let api_routes = Router::new()
                     .merge(get_events_routes(shared_state.clone()))
@@ -678,9 +1043,25 @@ pub async fn run(
                     .merge(get_instance_server_routes(shared_state.clone()))
                     .merge(get_instance_config_routes(shared_state.clone()))
                     .merge(get_instance_players_routes(shared_state.clone()))
+                    .merge(get_instance_chat_routes(shared_state.clone()))
+                    .merge(get_instance_mods_routes(shared_state.clone()))
+                    .merge(get_instance_worlds_routes(shared_state.clone()))
+                    .merge(get_instance_datapacks_routes(shared_state.clone()))
+                    .merge(get_instance_logs_routes(shared_state.clone()))
+                    .merge(get_instance_game_routes(shared_state.clone()))
+                    .merge(get_instance_announcements_routes(shared_state.clone()))
+                    .merge(get_instance_backups_routes(shared_state.clone()))
+                    .merge(get_instance_diagnostics_routes(shared_state.clone()))
+                    .merge(get_instance_tasks_routes(shared_state.clone()))
+                    .merge(get_instance_macro_triggers_routes(shared_state.clone()))
+                    .merge(get_audit_routes(shared_state.clone()))
+                    .merge(get_notification_routes(shared_state.clone()))
+                    .merge(get_network_routes(shared_state.clone()))
+                    .merge(get_templates_routes(shared_state.clone()))
                     .merge(get_instance_routes(shared_state.clone()))
                     .merge(get_system_routes(shared_state.clone()))
                     .merge(get_checks_routes(shared_state.clone()))
+                    .merge(get_ports_routes(shared_state.clone()))
                     .merge(get_user_routes(shared_state.clone()))
                     .merge(get_core_info_routes(shared_state.clone()))
                     .merge(get_setup_route(shared_state.clone()))
@@ -692,43 +1073,47 @@ pub async fn run(
                     .merge(get_gateway_routes(shared_state.clone()))
                     .merge(get_extension_routes(shared_state.clone()))
                     .merge(get_playitgg_routes(shared_state.clone()))
-                    .layer(cors)
-                    .layer(trace);
-                let app = Router::new().nest("/api/v1", api_routes);
-                #[allow(unused_variables, unused_mut)]
-                let mut port = 16_662_u16;
-                #[cfg(not(debug_assertions))]
-                if port_scanner::scan_port(port) {
-                    error!("Port {port} is already in use, exiting");
-                    std::process::exit(1);
-                }
-                #[cfg(debug_assertions)]
-                while port_scanner::scan_port(port) {
-                    debug!("Port {port} is already in use, trying next port");
-                    port += 1;
-                }
-                let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], port));
+                    .merge(get_openapi_routes(shared_state.clone()))
+                    .merge(feature_routes)
+                    .merge(get_feature_stub_routes())
+                    .layer(replay_idempotent)
+                    .layer(log_requests)
+                    .layer(Extension(http_config.trusted_proxies))
+                    .layer(cors);
+                let legacy_traffic = shared_state.legacy_traffic.clone();
+                let app = api_version::mount(api_routes, legacy_traffic.clone())
+                    .merge(api_version::get_api_versions_routes(legacy_traffic))
+                    .merge(get_health_routes(HealthState {
+                        ready: shared_state.ready.clone(),
+                        instances: shared_state.instances.clone(),
+                        broken_instances: shared_state.broken_instances.clone(),
+                    }));
+                let addr = SocketAddr::new(bind_address, http_port);
                 let axum_server_handle = axum_server::Handle::new();
                 tokio::spawn({
                     let axum_server_handle = axum_server_handle.clone();
                     async move {
-                        match tls_config_result {
-                            Ok(config) => {
-                                info!("TLS enabled");
+                        match (tls_config, tls_paths) {
+                            (Some(config), Some(paths)) => {
+                                info!("TLS enabled with the certificate {}", paths.cert.display());
                                 info!("Lodestone Core live on {addr}");
                                 info!("Note that Lodestone Core does not host the web dashboard itself. Please visit https://www.lodestone.cc for setup instructions.");
-                                axum_server::bind_rustls(addr, config)
+                                // renewed certificates are picked up without a restart
+                                let watch_task = tokio::spawn(tls::watch(config.clone(), paths));
+                                let served = axum_server::bind_rustls(addr, config)
                                     .handle(axum_server_handle)
-                                    .serve(app.into_make_service())
-                                    .await
+                                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                                    .await;
+                                watch_task.abort();
+                                served
                             }
-                            Err(e) => {
-                                warn!("Invalid TLS config : {e}, using HTTP");
+                            _ => {
+                                info!("TLS disabled, set {} and {} to serve HTTPS", tls::TLS_CERT_VAR, tls::TLS_KEY_VAR);
                                 info!("Lodestone Core live on {addr}");
                                 info!("Note that Lodestone Core does not host the web dashboard itself. Please visit https://www.lodestone.cc for setup instructions.");
                                 axum_server::bind(addr)
                                     .handle(axum_server_handle)
-                                    .serve(app.into_make_service())
+                                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                                     .await
                             }
                         }
@@ -737,66 +1122,56 @@ pub async fn run(
                 });
                 // capture file into the move block
                 let _lock_file = lock_file;
+                // the demo directory is removed once dropped, after shutdown
+                let _demo_dir = demo_dir;
+                // instances are restored and every task is set up, only serving is left
+                shared_state.ready.store(true, Ordering::SeqCst);
                 select! {
-                    _ = write_to_db_task => info!("Write to db task exited"),
+                    _ = &mut write_to_db_task => info!("Write to db task exited"),
                     _ = event_buffer_task => info!("Event buffer task exited"),
                     _ = monitor_report_task => info!("Monitor report task exited"),
+                    _ = scheduler_task => info!("Scheduler task exited"),
+                    _ = macro_triggers_task => info!("Macro triggers task exited"),
+                    _ = backup_schedule_task => info!("Backup schedule task exited"),
+                    _ = creation_status_task => info!("Creation status task exited"),
+                    _ = cancellation_task => info!("Cancellation task exited"),
+                    _ = audit_task => info!("Audit task exited"),
+                    _ = notification_task => info!("Notification task exited"),
+                    _ = trash_purge_task => info!("Trash purge task exited"),
                     _ = shutdown_rx => info!("Shutdown signal received"),
+                    _ = shared_state.shutdown.cancelled() => info!("Shutdown requested through the API"),
                     _ = tokio::signal::ctrl_c() => info!("Ctrl+C received"),
+                    _ = terminate_signal() => info!("SIGTERM received"),
                 }
                 info!("Shutting down web server");
-                axum_server_handle.shutdown();
-                info!("Signalling all instances to stop");
-                // cleanup
-                let mut handles = vec![];
+                // requests in flight get to finish, new connections are refused
+                axum_server_handle.graceful_shutdown(Some(Duration::from_secs(10)));
                 shared_state.download_urls.lock().await.clear();
                 let _ = tokio::fs::remove_dir_all(path_to_tmp()).await.map_err(|e| {
                     error!("Failed to remove tmp dir : {}", e);
                     e
                 });
-                for entry in shared_state.instances.iter() {
-                    let instance = entry.value().clone();
-                    match instance.state().await {
-                        State::Starting => {
-                            let handle = tokio::spawn({
-                                let instance = instance.clone();
-                                async move {
-                                    info!(
-                                        "Killing instance that is starting : {}",
-                                        instance.uuid().await
-                                    );
-                                    if let Err(e) = instance.kill(CausedBy::System).await {
-                                        error!(
-                                        "Failed to stop instance {} : {}. Instance may need manual cleanup",
-                                        instance.uuid().await,
-                                        e
-                                    );
-                                    }
-                                }
-                            });
-                            handles.push(handle);
-                        }
-                        State::Running => {
-                            let handle = tokio::spawn({
-                                let instance = instance.clone();
-                                async move {
-                                    if let Err(e) = instance.stop(CausedBy::System, false).await {
-                                        error!(
-                                        "Failed to stop instance {} : {}. Instance may need manual cleanup",
-                                        instance.uuid().await,
-                                        e
-                                    );
-                                    }
-                                }
-                            });
-                            handles.push(handle);
-                        }
-                        State::Error | State::Stopped | State::Stopping => continue,
+                // cloned out so no map guard is held while an instance answers
+                let instances: Vec<GameInstance> = shared_state
+                    .instances
+                    .iter()
+                    .map(|entry| entry.value().clone())
+                    .collect();
+                if no_stop_instances {
+                    if let Err(e) = shutdown::detach_instances(instances, &lodestone_path).await {
+                        error!("Failed to record the instances left running: {}", e);
                     }
+                } else {
+                    info!(
+                        "Stopping all instances, killing those still running after {} seconds",
+                        shutdown_timeout.as_secs()
+                    );
+                    shutdown::stop_instances(instances, shutdown_timeout).await;
                 }
-                for handle in handles {
-                    let _ = handle.await;
-                }
+                // the console output and events of the shutdown are written before exiting
+                flush_events.cancel();
+                let _ = write_to_db_task.await;
+                shared_state.sqlite_pool.close().await;
                 shared_state.instances.clear();
                 shared_state.macro_executor.shutdown_all();
                 // exit
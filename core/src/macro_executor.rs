@@ -15,7 +15,7 @@ use dashmap::DashMap;
 use deno_runtime::permissions::{Permissions, PermissionsOptions};
 use futures_util::Future;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use tokio::{sync::mpsc, task::LocalSet};
 use tracing::{debug, error, log::warn};
 use ts_rs::TS;
@@ -27,8 +27,8 @@ use crate::{
     },
     error::{Error, ErrorKind},
     event_broadcaster::EventBroadcaster,
-    events::{CausedBy, EventInner, MacroEvent, MacroEventInner},
-    traits::t_macro::ExitStatus,
+    events::{CausedBy, Event, EventInner, MacroEvent, MacroEventInner},
+    traits::t_macro::{ExitStatus, MacroOverlap},
     types::InstanceUuid,
 };
 
@@ -305,17 +305,46 @@ impl MacroExecutor {
         &self,
         path_to_main_module: PathBuf,
         args: Vec<String>,
+        caused_by: CausedBy,
+        worker_options_generator: Box<dyn WorkerOptionGenerator>,
+        pre_injection_code: Option<String>,
+        permissions: Option<PermissionsOptions>,
+        instance_uuid: Option<InstanceUuid>,
+    ) -> Result<SpawnResult, Error> {
+        self.spawn_with_pid(
+            self.reserve_pid(),
+            path_to_main_module,
+            args,
+            caused_by,
+            worker_options_generator,
+            pre_injection_code,
+            permissions,
+            instance_uuid,
+        )
+        .await
+    }
+
+    /// A pid for a macro that is spawned later with [`MacroExecutor::spawn_with_pid`],
+    /// so a queued run can be referred to before it starts
+    pub fn reserve_pid(&self) -> MacroPID {
+        MacroPID(self.next_process_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Like [`MacroExecutor::spawn`], with a pid from [`MacroExecutor::reserve_pid`]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn spawn_with_pid(
+        &self,
+        pid: MacroPID,
+        path_to_main_module: PathBuf,
+        args: Vec<String>,
         _caused_by: CausedBy,
         worker_options_generator: Box<dyn WorkerOptionGenerator>,
         pre_injection_code: Option<String>,
         permissions: Option<PermissionsOptions>,
         instance_uuid: Option<InstanceUuid>,
     ) -> Result<SpawnResult, Error> {
-        let pid = MacroPID(self.next_process_id.fetch_add(1, Ordering::SeqCst));
-        let exit_future = Box::pin({
-            let __self = self.clone();
-            async move { __self.wait_with_timeout(pid).await }
-        });
+        // subscribe before the macro starts, a short macro may stop before the future is polled
+        let exit_future = Box::pin(Self::wait_for_exit(self.event_broadcaster.subscribe(), pid));
         let detach_future = Box::pin({
             let __self = self.clone();
             async move {
@@ -335,7 +364,7 @@ impl MacroExecutor {
             move || {
                 let _guard = rt.enter();
                 let local = LocalSet::new();
-                local.spawn_local({
+                let main_task = local.spawn_local({
                     let event_broadcaster = event_broadcaster.clone();
                     let instance_uuid = instance_uuid.clone();
                     async move {
@@ -392,6 +421,19 @@ impl MacroExecutor {
                             Ok(v) => v,
                             Err(e) => {
                                 error!("Error resolving main module: {}", e);
+                                event_broadcaster.send(
+                                    MacroEvent {
+                                        macro_pid: pid,
+                                        macro_event_inner: MacroEventInner::Stopped {
+                                            exit_status: ExitStatus::Error {
+                                                error_msg: e.to_string(),
+                                                time: chrono::Utc::now().timestamp(),
+                                            },
+                                        },
+                                        instance_uuid,
+                                    }
+                                    .into(),
+                                );
                                 return;
                             }
                         };
@@ -470,6 +512,7 @@ impl MacroExecutor {
                                     .into(),
                                 );
                             }
+                            return;
                         }
 
                         debug!("Macro event loop exited");
@@ -496,20 +539,23 @@ impl MacroExecutor {
                 // spawned tasks have returned.
                 rt.block_on(local);
                 debug!("MacroExecutor thread exited");
-                event_broadcaster.send(
-                    MacroEvent {
-                        macro_pid: pid,
-                        macro_event_inner: MacroEventInner::Stopped {
-                            exit_status: ExitStatus::Error {
-                                time: chrono::Utc::now().timestamp(),
-                                error_msg: "Macro executor thread unexpectedly panicked"
-                                    .to_string(),
+                // every other way out of the main task reports its own exit status
+                if rt.block_on(main_task).is_err() {
+                    event_broadcaster.send(
+                        MacroEvent {
+                            macro_pid: pid,
+                            macro_event_inner: MacroEventInner::Stopped {
+                                exit_status: ExitStatus::Error {
+                                    time: chrono::Utc::now().timestamp(),
+                                    error_msg: "Macro executor thread unexpectedly panicked"
+                                        .to_string(),
+                                },
                             },
-                        },
-                        instance_uuid: instance_uuid.clone(),
-                    }
-                    .into(),
-                );
+                            instance_uuid: instance_uuid.clone(),
+                        }
+                        .into(),
+                    );
+                }
             }
         });
 
@@ -580,10 +626,16 @@ impl MacroExecutor {
     }
 
     /// wait for a macro to finish
-    async fn wait_with_timeout(&self, taget_macro_pid: MacroPID) -> Result<ExitStatus, Error> {
-        let mut rx = self.event_broadcaster.subscribe();
+    async fn wait_for_exit(
+        mut rx: tokio::sync::broadcast::Receiver<Event>,
+        taget_macro_pid: MacroPID,
+    ) -> Result<ExitStatus, Error> {
         loop {
-            let event = rx.recv().await.unwrap();
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(e) => return Err(eyre!("Failed to receive macro stopped event: {}", e).into()),
+            };
             if let EventInner::MacroEvent(MacroEvent {
                 macro_pid,
                 macro_event_inner,
@@ -603,16 +655,29 @@ impl MacroExecutor {
         self.exit_status_table.get(&pid).map(|v| v.clone())
     }
 
+    /// Ends a reserved pid that never got to run, e.g. because it was aborted while queued
+    pub fn record_exit(
+        &self,
+        pid: MacroPID,
+        exit_status: ExitStatus,
+        instance_uuid: Option<InstanceUuid>,
+    ) {
+        // recorded right away so a queued run checking its status can't miss it
+        self.exit_status_table.insert(pid, exit_status.clone());
+        self.event_broadcaster.send(
+            MacroEvent {
+                macro_pid: pid,
+                macro_event_inner: MacroEventInner::Stopped { exit_status },
+                instance_uuid,
+            }
+            .into(),
+        );
+    }
+
     pub async fn get_config_manifest(
         path: &PathBuf,
     ) -> Result<IndexMap<String, SettingManifest>, Error> {
-        match extract_config_code(&fs::read_to_string(path).await?) {
-            Ok(optional_code) => match optional_code {
-                Some((var_name, definition)) => get_config_from_code(&var_name, &definition),
-                None => Ok(IndexMap::<String, SettingManifest>::new()),
-            },
-            Err(e) => Err(e),
-        }
+        config_manifest_from_code(&fs::read_to_string(path).await?)
     }
 
     pub fn shutdown_all(&self) {
@@ -622,6 +687,108 @@ impl MacroExecutor {
     }
 }
 
+/// The settings declared by a macro's `LodestoneConfig` class, empty if it declares none
+pub fn config_manifest_from_code(code: &str) -> Result<IndexMap<String, SettingManifest>, Error> {
+    match extract_config_code(code)? {
+        Some((var_name, definition)) => get_config_from_code(&var_name, &definition),
+        None => Ok(IndexMap::new()),
+    }
+}
+
+/// Turns `{"delay": 10, "reason": "update"}` into `["--delay=10", "--reason=update"]`,
+/// the form `parseArgs` from Deno's standard library reads back
+pub fn to_macro_args(args: Map<String, Value>) -> Result<Vec<String>, Error> {
+    args.into_iter()
+        .map(|(key, value)| {
+            if key.is_empty() || key.contains(|c: char| c == '=' || c.is_whitespace()) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid macro argument name {:?}", key),
+                });
+            }
+            Ok(match value {
+                Value::String(value) => format!("--{key}={value}"),
+                value => format!("--{key}={value}"),
+            })
+        })
+        .collect()
+}
+
+/// What a macro says about itself in the comments at the top of its main module
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MacroMetadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub overlap: MacroOverlap,
+}
+
+///
+/// parse the comments before the first line of code, e.g.
+///
+/// ```ts
+/// /**
+///  * Warns players, then restarts the server
+///  * @name Timed restart
+///  * @overlap queue
+///  */
+/// ```
+///
+/// untagged lines make up the description, unknown tags are ignored
+///
+pub fn parse_macro_metadata(code: &str) -> MacroMetadata {
+    let mut metadata = MacroMetadata::default();
+    let mut description: Vec<&str> = Vec::new();
+    let mut in_block = false;
+    for line in code.lines() {
+        let line = line.trim();
+        let text = if in_block {
+            let (text, closed) = match line.find("*/") {
+                Some(end) => (&line[..end], true),
+                None => (line, false),
+            };
+            in_block = !closed;
+            text.trim_start_matches('*')
+        } else if let Some(comment) = line.strip_prefix("//") {
+            comment
+        } else if let Some(comment) = line.strip_prefix("/*") {
+            let comment = comment.trim_start_matches('*');
+            match comment.find("*/") {
+                Some(end) => &comment[..end],
+                None => {
+                    in_block = true;
+                    comment
+                }
+            }
+        } else if line.is_empty() || line.starts_with("#!") {
+            continue;
+        } else {
+            break;
+        };
+        let text = text.trim();
+        if let Some(tag) = text.strip_prefix('@') {
+            let (key, value) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            let value = value.trim();
+            match key {
+                "name" if !value.is_empty() => metadata.name = Some(value.to_string()),
+                "description" if !value.is_empty() => description.push(value),
+                "overlap" => {
+                    metadata.overlap = match value {
+                        "queue" => MacroOverlap::Queue,
+                        _ => MacroOverlap::Reject,
+                    }
+                }
+                _ => {}
+            }
+        } else if !text.is_empty() {
+            description.push(text);
+        }
+    }
+    if !description.is_empty() {
+        metadata.description = Some(description.join(" "));
+    }
+    metadata
+}
+
 ///
 /// extract the class definition and the name of the declared config instance
 /// from the typescript code
@@ -990,9 +1157,12 @@ mod tests {
     use crate::event_broadcaster::EventBroadcaster;
     use crate::events::CausedBy;
     use crate::macro_executor::{
-        extract_config_code, get_config_from_code, parse_config_single, SpawnResult,
+        extract_config_code, get_config_from_code, parse_config_single, parse_macro_metadata,
+        to_macro_args, MacroMetadata, SpawnResult,
     };
     use crate::traits::t_configurable::manifest::ConfigurableValue;
+    use crate::traits::t_macro::MacroOverlap;
+    use serde_json::json;
 
     struct BasicMainWorkerGenerator;
 
@@ -1104,6 +1274,56 @@ mod tests {
         exit_future.await.unwrap();
     }
 
+    #[test]
+    fn test_to_macro_args() {
+        let args = json!({"reason": "update", "delay": 10, "dry_run": true, "names": ["a", "b"]});
+        assert_eq!(
+            to_macro_args(args.as_object().unwrap().clone()).unwrap(),
+            vec![
+                "--delay=10",
+                "--dry_run=true",
+                "--names=[\"a\",\"b\"]",
+                "--reason=update",
+            ]
+        );
+        let args = json!({"a=b": 1});
+        assert!(to_macro_args(args.as_object().unwrap().clone()).is_err());
+    }
+
+    #[test]
+    fn test_macro_metadata_parsing() {
+        let metadata = parse_macro_metadata(
+            r#"
+            /**
+             * Warns players,
+             * then restarts the server
+             * @name Timed restart
+             * @overlap queue
+             */
+            // @unknown tag
+            import { readLines } from "https://deno.land/std@0.104.0/io/mod.ts";
+            // not part of the header
+            "#,
+        );
+        assert_eq!(
+            metadata,
+            MacroMetadata {
+                name: Some("Timed restart".to_string()),
+                description: Some("Warns players, then restarts the server".to_string()),
+                overlap: MacroOverlap::Queue,
+            }
+        );
+
+        let metadata = parse_macro_metadata("// Says hi\n// @overlap whatever\nconsole.log('hi');");
+        assert_eq!(metadata.description.as_deref(), Some("Says hi"));
+        assert_eq!(metadata.overlap, MacroOverlap::Reject);
+
+        assert_eq!(
+            parse_macro_metadata("console.log('hi'); // @name Hi"),
+            MacroMetadata::default()
+        );
+    }
+
     #[test]
     fn test_macro_config_extraction() {
         // should return None if no there is no config definition
@@ -0,0 +1,798 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, EventInner, InstanceEvent, InstanceEventInner};
+use crate::macro_executor::to_macro_args;
+use crate::scheduler::parse_cron;
+use crate::traits::t_macro::TMacro;
+use crate::traits::t_player::TPlayer;
+use crate::traits::t_server::State;
+use crate::traits::GameInstance;
+use crate::types::{InstanceUuid, Snowflake};
+
+const CRON_TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// Events kept per instance for dry runs
+const RECENT_EVENTS_PER_INSTANCE: usize = 500;
+/// How far back a dry run of a cron trigger looks
+const CRON_DRY_RUN_WINDOW_HOURS: i64 = 24;
+/// fancy-regex backtracks, a line that takes more steps than this counts as not matching
+const REGEX_BACKTRACK_LIMIT: usize = 100_000;
+/// Longer console lines are cut before matching
+const MAX_MATCHED_LINE_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum TriggerCondition {
+    /// Any state change if `to` is unset
+    StateChange {
+        #[serde(default)]
+        to: Option<State>,
+    },
+    PlayerJoin,
+    PlayerLeave,
+    ConsoleLine {
+        regex: String,
+    },
+    Cron {
+        cron: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct MacroTriggerConfig {
+    pub name: String,
+    pub condition: TriggerCondition,
+    pub macro_name: String,
+    /// Passed to the macro as `--key=value`.
+    ///
+    /// Values may reference `{instance}` and `{time}`, `{state}` for state changes, `{player}`
+    /// for joins and leaves, and `{line}` plus numbered and named regex groups for console lines
+    #[serde(default)]
+    pub args: IndexMap<String, String>,
+    /// Matches within this many seconds of the last run are dropped
+    #[serde(default = "default_debounce_seconds")]
+    pub debounce_seconds: u64,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_debounce_seconds() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl MacroTriggerConfig {
+    fn validate(&self) -> Result<Matcher, Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Trigger name cannot be empty"),
+            });
+        }
+        if self.macro_name.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Macro name cannot be empty"),
+            });
+        }
+        // the values are only known once the trigger fires
+        to_macro_args(
+            self.args
+                .keys()
+                .map(|key| (key.clone(), Value::Null))
+                .collect(),
+        )?;
+        Matcher::new(&self.condition)
+    }
+
+    fn render_args(&self, vars: &IndexMap<String, String>) -> IndexMap<String, String> {
+        self.args
+            .iter()
+            .map(|(key, template)| {
+                let value = vars
+                    .iter()
+                    .fold(template.clone(), |value, (var, var_value)| {
+                        value.replace(&format!("{{{}}}", var), var_value)
+                    });
+                (key.clone(), value)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct MacroTrigger {
+    pub id: Snowflake,
+    #[serde(flatten)]
+    pub config: MacroTriggerConfig,
+    /// Unix timestamp in milliseconds of the last run
+    pub last_fired: Option<i64>,
+    /// Error of the last run, if it failed to start
+    pub last_error: Option<String>,
+}
+
+/// A recent event a trigger would have fired on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct TriggerMatch {
+    /// Unix timestamp in milliseconds
+    pub time: i64,
+    pub description: String,
+    pub args: IndexMap<String, String>,
+    /// Dropped because an earlier match was too recent
+    pub debounced: bool,
+}
+
+/// A trigger condition, with its regex or cron expression parsed once
+enum Matcher {
+    StateChange(Option<State>),
+    PlayerJoin,
+    PlayerLeave,
+    ConsoleLine(fancy_regex::Regex),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Matcher {
+    fn new(condition: &TriggerCondition) -> Result<Self, Error> {
+        Ok(match condition {
+            TriggerCondition::StateChange { to } => Matcher::StateChange(*to),
+            TriggerCondition::PlayerJoin => Matcher::PlayerJoin,
+            TriggerCondition::PlayerLeave => Matcher::PlayerLeave,
+            TriggerCondition::ConsoleLine { regex } => Matcher::ConsoleLine(
+                fancy_regex::RegexBuilder::new(regex)
+                    .backtrack_limit(REGEX_BACKTRACK_LIMIT)
+                    .build()
+                    .map_err(|e| Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Invalid regex \"{}\": {}", regex, e),
+                    })?,
+            ),
+            TriggerCondition::Cron { cron } => Matcher::Cron(Box::new(parse_cron(cron)?)),
+        })
+    }
+
+    /// The template variables of every match in the event, a join of two players matches twice
+    fn matches(&self, event: &InstanceEvent) -> Vec<(String, IndexMap<String, String>)> {
+        let mut matches = Vec::new();
+        match (self, &event.instance_event_inner) {
+            (Matcher::StateChange(expected), InstanceEventInner::StateTransition { to }) => {
+                if expected.map_or(true, |expected| expected == *to) {
+                    matches.push((
+                        format!("{} changed to {:?}", event.instance_name, to),
+                        IndexMap::from([("state".to_string(), format!("{:?}", to))]),
+                    ));
+                }
+            }
+            (Matcher::PlayerJoin, InstanceEventInner::PlayerChange { players_joined, .. }) => {
+                for player in players_joined {
+                    matches.push((
+                        format!("{} joined {}", player.get_name(), event.instance_name),
+                        IndexMap::from([("player".to_string(), player.get_name())]),
+                    ));
+                }
+            }
+            (Matcher::PlayerLeave, InstanceEventInner::PlayerChange { players_left, .. }) => {
+                for player in players_left {
+                    matches.push((
+                        format!("{} left {}", player.get_name(), event.instance_name),
+                        IndexMap::from([("player".to_string(), player.get_name())]),
+                    ));
+                }
+            }
+            (Matcher::ConsoleLine(regex), InstanceEventInner::InstanceOutput { message }) => {
+                let mut end = message.len().min(MAX_MATCHED_LINE_LENGTH);
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let line = &message[..end];
+                // an error means the backtrack limit was hit
+                if let Ok(Some(captures)) = regex.captures(line) {
+                    let mut vars = IndexMap::from([("line".to_string(), line.to_string())]);
+                    for (i, name) in regex.capture_names().enumerate() {
+                        let value = captures
+                            .get(i)
+                            .map(|m| m.as_str().to_string())
+                            .unwrap_or_default();
+                        if let Some(name) = name {
+                            vars.insert(name.to_string(), value.clone());
+                        }
+                        vars.insert(i.to_string(), value);
+                    }
+                    matches.push((
+                        format!("{} printed \"{}\"", event.instance_name, line),
+                        vars,
+                    ));
+                }
+            }
+            _ => {}
+        }
+        matches
+    }
+}
+
+/// A trigger to fire on an instance at a time, with its template variables
+type DueTrigger = (InstanceUuid, MacroTrigger, i64, IndexMap<String, String>);
+
+struct ArmedTrigger {
+    trigger: MacroTrigger,
+    matcher: Matcher,
+}
+
+/// Runs macros when instance events or cron schedules match a trigger.
+///
+/// Triggers are persisted in a single store shared by all instances
+#[derive(Clone)]
+pub struct MacroTriggers {
+    triggers: Arc<Mutex<HashMap<InstanceUuid, Vec<ArmedTrigger>>>>,
+    recent_events: Arc<Mutex<HashMap<InstanceUuid, VecDeque<(i64, InstanceEvent)>>>>,
+    path_to_store: PathBuf,
+}
+
+impl MacroTriggers {
+    pub async fn new(path_to_store: PathBuf) -> Result<Self, Error> {
+        let stored: HashMap<InstanceUuid, Vec<MacroTrigger>> =
+            match tokio::fs::read(&path_to_store).await {
+                Ok(data) if !data.is_empty() => serde_json::from_slice(&data).context(format!(
+                    "Failed to parse macro triggers at {}",
+                    path_to_store.display()
+                ))?,
+                _ => HashMap::new(),
+            };
+        let mut triggers = HashMap::new();
+        for (instance_uuid, stored) in stored {
+            let mut armed = Vec::new();
+            for trigger in stored {
+                match trigger.config.validate() {
+                    Ok(matcher) => armed.push(ArmedTrigger { trigger, matcher }),
+                    Err(e) => error!(
+                        "Dropping invalid macro trigger \"{}\" of {}: {}",
+                        trigger.config.name, instance_uuid, e
+                    ),
+                }
+            }
+            triggers.insert(instance_uuid, armed);
+        }
+        Ok(Self {
+            triggers: Arc::new(Mutex::new(triggers)),
+            recent_events: Arc::new(Mutex::new(HashMap::new())),
+            path_to_store,
+        })
+    }
+
+    async fn write_to_file(
+        &self,
+        triggers: &HashMap<InstanceUuid, Vec<ArmedTrigger>>,
+    ) -> Result<(), Error> {
+        let stored: HashMap<&InstanceUuid, Vec<&MacroTrigger>> = triggers
+            .iter()
+            .map(|(instance_uuid, armed)| {
+                (instance_uuid, armed.iter().map(|a| &a.trigger).collect())
+            })
+            .collect();
+        tokio::fs::write(
+            &self.path_to_store,
+            serde_json::to_string_pretty(&stored).context("Failed to serialize macro triggers")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write macro triggers to {}",
+            self.path_to_store.display()
+        ))?;
+        Ok(())
+    }
+
+    pub async fn list(&self, instance_uuid: &InstanceUuid) -> Vec<MacroTrigger> {
+        self.triggers
+            .lock()
+            .await
+            .get(instance_uuid)
+            .map(|armed| armed.iter().map(|a| a.trigger.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn get(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: &Snowflake,
+    ) -> Result<MacroTrigger, Error> {
+        self.triggers
+            .lock()
+            .await
+            .get(instance_uuid)
+            .and_then(|armed| armed.iter().find(|a| a.trigger.id == *id))
+            .map(|a| a.trigger.clone())
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Trigger not found"),
+            })
+    }
+
+    pub async fn create(
+        &self,
+        instance_uuid: &InstanceUuid,
+        config: MacroTriggerConfig,
+    ) -> Result<MacroTrigger, Error> {
+        let matcher = config.validate()?;
+        let trigger = MacroTrigger {
+            id: Snowflake::new(),
+            config,
+            last_fired: None,
+            last_error: None,
+        };
+        let mut triggers = self.triggers.lock().await;
+        triggers
+            .entry(instance_uuid.clone())
+            .or_default()
+            .push(ArmedTrigger {
+                trigger: trigger.clone(),
+                matcher,
+            });
+        self.write_to_file(&triggers).await?;
+        Ok(trigger)
+    }
+
+    pub async fn update(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: &Snowflake,
+        config: MacroTriggerConfig,
+    ) -> Result<MacroTrigger, Error> {
+        let matcher = config.validate()?;
+        let mut triggers = self.triggers.lock().await;
+        let armed = triggers
+            .get_mut(instance_uuid)
+            .and_then(|armed| armed.iter_mut().find(|a| a.trigger.id == *id))
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Trigger not found"),
+            })?;
+        armed.trigger.config = config;
+        armed.matcher = matcher;
+        let trigger = armed.trigger.clone();
+        self.write_to_file(&triggers).await?;
+        Ok(trigger)
+    }
+
+    pub async fn delete(&self, instance_uuid: &InstanceUuid, id: &Snowflake) -> Result<(), Error> {
+        let mut triggers = self.triggers.lock().await;
+        let instance_triggers = triggers.get_mut(instance_uuid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Trigger not found"),
+        })?;
+        let len = instance_triggers.len();
+        instance_triggers.retain(|a| a.trigger.id != *id);
+        if instance_triggers.len() == len {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Trigger not found"),
+            });
+        }
+        self.write_to_file(&triggers).await
+    }
+
+    /// Drops every trigger of an instance, called when the instance is deleted
+    pub async fn remove_instance(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        self.recent_events.lock().await.remove(instance_uuid);
+        let mut triggers = self.triggers.lock().await;
+        if triggers.remove(instance_uuid).is_some() {
+            self.write_to_file(&triggers).await?;
+        }
+        Ok(())
+    }
+
+    /// The recent events of an instance a trigger with this config would have fired on,
+    /// oldest first, without running anything
+    pub async fn dry_run(
+        &self,
+        instance_uuid: &InstanceUuid,
+        config: &MacroTriggerConfig,
+    ) -> Result<Vec<TriggerMatch>, Error> {
+        let matcher = config.validate()?;
+        let debounce_millis = (config.debounce_seconds * 1000) as i64;
+        let mut last_fired: Option<i64> = None;
+        let mut matches = Vec::new();
+        let mut record = |time: i64, description: String, mut vars: IndexMap<String, String>| {
+            vars.insert("instance".to_string(), instance_uuid.to_string());
+            vars.insert("time".to_string(), rfc3339(time));
+            let debounced = last_fired.map_or(false, |last| time - last < debounce_millis);
+            if !debounced {
+                last_fired = Some(time);
+            }
+            matches.push(TriggerMatch {
+                time,
+                description,
+                args: config.render_args(&vars),
+                debounced,
+            });
+        };
+        if let Matcher::Cron(schedule) = &matcher {
+            let now = Utc::now();
+            let since = now - chrono::Duration::hours(CRON_DRY_RUN_WINDOW_HOURS);
+            for time in schedule
+                .after(&since)
+                .take_while(|time| *time <= now)
+                .take(RECENT_EVENTS_PER_INSTANCE)
+            {
+                record(
+                    time.timestamp_millis(),
+                    "Scheduled".to_string(),
+                    IndexMap::new(),
+                );
+            }
+        } else if let Some(events) = self.recent_events.lock().await.get(instance_uuid) {
+            for (time, event) in events {
+                for (description, vars) in matcher.matches(event) {
+                    record(*time, description, vars);
+                }
+            }
+        }
+        Ok(matches)
+    }
+
+    async fn remember(&self, time: i64, event: &InstanceEvent) {
+        if !matches!(
+            event.instance_event_inner,
+            InstanceEventInner::StateTransition { .. }
+                | InstanceEventInner::PlayerChange { .. }
+                | InstanceEventInner::InstanceOutput { .. }
+        ) {
+            return;
+        }
+        let mut recent_events = self.recent_events.lock().await;
+        let events = recent_events
+            .entry(event.instance_uuid.clone())
+            .or_default();
+        if events.len() == RECENT_EVENTS_PER_INSTANCE {
+            events.pop_front();
+        }
+        events.push_back((time, event.clone()));
+    }
+
+    /// Marks a trigger as fired, returns false if it fired too recently or no longer exists
+    async fn try_fire(&self, instance_uuid: &InstanceUuid, id: &Snowflake, time: i64) -> bool {
+        let mut triggers = self.triggers.lock().await;
+        let trigger = match triggers
+            .get_mut(instance_uuid)
+            .and_then(|armed| armed.iter_mut().find(|a| a.trigger.id == *id))
+        {
+            Some(armed) => &mut armed.trigger,
+            None => return false,
+        };
+        let debounce_millis = (trigger.config.debounce_seconds * 1000) as i64;
+        if trigger
+            .last_fired
+            .map_or(false, |last| time - last < debounce_millis)
+        {
+            return false;
+        }
+        trigger.last_fired = Some(time);
+        true
+    }
+
+    async fn record_run(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: &Snowflake,
+        result: &Result<(), Error>,
+    ) {
+        let mut triggers = self.triggers.lock().await;
+        if let Some(armed) = triggers
+            .get_mut(instance_uuid)
+            .and_then(|armed| armed.iter_mut().find(|a| a.trigger.id == *id))
+        {
+            armed.trigger.last_error = result.as_ref().err().map(|e| e.to_string());
+        }
+        if let Err(e) = self.write_to_file(&triggers).await {
+            error!("Failed to persist macro trigger run: {}", e);
+        }
+    }
+
+    /// Enabled triggers of the instance matching the event, with their template variables
+    async fn matching(&self, event: &InstanceEvent, time: i64) -> Vec<DueTrigger> {
+        let mut due = Vec::new();
+        if let Some(armed) = self.triggers.lock().await.get(&event.instance_uuid) {
+            for a in armed.iter().filter(|a| a.trigger.config.enabled) {
+                for (_, vars) in a.matcher.matches(event) {
+                    due.push((event.instance_uuid.clone(), a.trigger.clone(), time, vars));
+                }
+            }
+        }
+        due
+    }
+
+    /// Enabled cron triggers firing in `(after, until]`
+    async fn due_cron(&self, after: DateTime<Utc>, until: DateTime<Utc>) -> Vec<DueTrigger> {
+        let mut due = Vec::new();
+        for (instance_uuid, armed) in self.triggers.lock().await.iter() {
+            for a in armed.iter().filter(|a| a.trigger.config.enabled) {
+                if let Matcher::Cron(schedule) = &a.matcher {
+                    if schedule
+                        .after(&after)
+                        .next()
+                        .map_or(false, |next| next <= until)
+                    {
+                        due.push((
+                            instance_uuid.clone(),
+                            a.trigger.clone(),
+                            until.timestamp_millis(),
+                            IndexMap::new(),
+                        ));
+                    }
+                }
+            }
+        }
+        due
+    }
+
+    /// Evaluates triggers against the event stream and the clock until the daemon shuts down
+    pub async fn run(
+        self,
+        instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+        event_broadcaster: EventBroadcaster,
+    ) {
+        let mut rx = event_broadcaster.subscribe();
+        let mut interval = tokio::time::interval(CRON_TICK_INTERVAL);
+        let mut last_tick = Utc::now();
+        loop {
+            let due = tokio::select! {
+                event = rx.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Macro triggers missed {} events", skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    match &event.event_inner {
+                        EventInner::InstanceEvent(instance_event) => {
+                            let time = event.snowflake.timestamp_millis();
+                            self.remember(time, instance_event).await;
+                            self.matching(instance_event, time).await
+                        }
+                        _ => continue,
+                    }
+                }
+                _ = interval.tick() => {
+                    let now = Utc::now();
+                    let due = self.due_cron(last_tick, now).await;
+                    last_tick = now;
+                    due
+                }
+            };
+            for (instance_uuid, trigger, time, mut vars) in due {
+                let instance = match instances.get(&instance_uuid) {
+                    Some(instance) => instance.value().clone(),
+                    None => continue,
+                };
+                if !self.try_fire(&instance_uuid, &trigger.id, time).await {
+                    continue;
+                }
+                vars.insert("instance".to_string(), instance_uuid.to_string());
+                vars.insert("time".to_string(), rfc3339(time));
+                tokio::spawn(self.clone().fire(instance_uuid, instance, trigger, vars));
+            }
+        }
+    }
+
+    async fn fire(
+        self,
+        instance_uuid: InstanceUuid,
+        instance: GameInstance,
+        trigger: MacroTrigger,
+        vars: IndexMap<String, String>,
+    ) {
+        info!(
+            "Macro trigger \"{}\" on {} fired, running {}",
+            trigger.config.name, instance_uuid, trigger.config.macro_name
+        );
+        let result = async {
+            let args = to_macro_args(
+                trigger
+                    .config
+                    .render_args(&vars)
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::String(value)))
+                    .collect::<Map<String, Value>>(),
+            )?;
+            let config = instance
+                .validate_local_config(&trigger.config.macro_name, None)
+                .await?;
+            instance
+                .run_macro(
+                    &trigger.config.macro_name,
+                    args,
+                    if config.is_empty() {
+                        None
+                    } else {
+                        Some(config)
+                    },
+                    // anything the macro does is attributed to its pid, the run to the trigger
+                    CausedBy::Trigger {
+                        trigger_id: trigger.id.to_string(),
+                    },
+                )
+                .await
+                .map(|_| ())
+        }
+        .await;
+        if let Err(e) = &result {
+            warn!(
+                "Macro trigger \"{}\" on {} failed: {}",
+                trigger.config.name, instance_uuid, e
+            );
+        }
+        self.record_run(&instance_uuid, &trigger.id, &result).await;
+    }
+}
+
+fn rfc3339(time_millis: i64) -> String {
+    Utc.timestamp_millis_opt(time_millis)
+        .single()
+        .map(|time: DateTime<Utc>| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn config(condition: TriggerCondition) -> MacroTriggerConfig {
+        MacroTriggerConfig {
+            name: "Greeter".to_string(),
+            condition,
+            macro_name: "greet".to_string(),
+            args: IndexMap::from([
+                ("who".to_string(), "{player}{1}".to_string()),
+                ("at".to_string(), "{instance}".to_string()),
+            ]),
+            debounce_seconds: 5,
+            enabled: true,
+        }
+    }
+
+    fn output(line: &str) -> InstanceEvent {
+        InstanceEvent {
+            instance_uuid: InstanceUuid::from("INSTANCE_test".to_string()),
+            instance_name: "test".to_string(),
+            instance_event_inner: InstanceEventInner::InstanceOutput {
+                message: line.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_trigger_validation() {
+        assert!(config(TriggerCondition::ConsoleLine {
+            regex: "(unclosed".to_string()
+        })
+        .validate()
+        .is_err());
+        assert!(config(TriggerCondition::Cron {
+            cron: "not a cron".to_string()
+        })
+        .validate()
+        .is_err());
+        let mut bad_args = config(TriggerCondition::PlayerJoin);
+        bad_args.args.insert("a=b".to_string(), "c".to_string());
+        assert!(bad_args.validate().is_err());
+        assert!(config(TriggerCondition::PlayerJoin).validate().is_ok());
+    }
+
+    #[test]
+    fn test_console_line_matching() {
+        let matcher = Matcher::new(&TriggerCondition::ConsoleLine {
+            regex: r"(?P<name>\w+) fell out of the world".to_string(),
+        })
+        .unwrap();
+        assert!(matcher.matches(&output("Steve joined the game")).is_empty());
+        let matches = matcher.matches(&output("[Server] Steve fell out of the world"));
+        assert_eq!(matches.len(), 1);
+        let vars = &matches[0].1;
+        assert_eq!(vars["name"], "Steve");
+        assert_eq!(vars["1"], "Steve");
+        assert_eq!(vars["line"], "[Server] Steve fell out of the world");
+
+        let args = config(TriggerCondition::PlayerJoin).render_args(vars);
+        // unknown variables are left as is
+        assert_eq!(args["who"], "{player}Steve");
+
+        // exponential backtracking hits the limit instead of hanging
+        let matcher = Matcher::new(&TriggerCondition::ConsoleLine {
+            regex: r"^(a+)+\1b$".to_string(),
+        })
+        .unwrap();
+        assert!(matcher.matches(&output(&"a".repeat(64))).is_empty());
+    }
+
+    #[test]
+    fn test_state_matching() {
+        let matcher = Matcher::new(&TriggerCondition::StateChange {
+            to: Some(State::Error),
+        })
+        .unwrap();
+        let transition = |to| InstanceEvent {
+            instance_event_inner: InstanceEventInner::StateTransition { to },
+            ..output("")
+        };
+        assert!(matcher.matches(&transition(State::Stopped)).is_empty());
+        assert_eq!(matcher.matches(&transition(State::Error)).len(), 1);
+        let matcher = Matcher::new(&TriggerCondition::PlayerJoin).unwrap();
+        let no_players = InstanceEvent {
+            instance_event_inner: InstanceEventInner::PlayerChange {
+                player_list: HashSet::new(),
+                players_joined: HashSet::new(),
+                players_left: HashSet::new(),
+            },
+            ..output("")
+        };
+        assert!(matcher.matches(&no_players).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_and_store() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("macro_triggers.json");
+        let instance_uuid = InstanceUuid::from("INSTANCE_test".to_string());
+        let triggers = MacroTriggers::new(path.clone()).await.unwrap();
+
+        let died = config(TriggerCondition::ConsoleLine {
+            regex: r"(\w+) died".to_string(),
+        });
+        for (time, line) in [
+            (0, "Alex died"),
+            (1000, "Steve died"),
+            (10_000, "Herobrine"),
+        ] {
+            triggers.remember(time, &output(line)).await;
+        }
+        let matches = triggers.dry_run(&instance_uuid, &died).await.unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].args["who"], "{player}Alex");
+        assert_eq!(matches[0].args["at"], "INSTANCE_test");
+        assert!(!matches[0].debounced);
+        // within 5 seconds of the first
+        assert!(matches[1].debounced);
+
+        let trigger = triggers.create(&instance_uuid, died.clone()).await.unwrap();
+        assert!(triggers.try_fire(&instance_uuid, &trigger.id, 0).await);
+        assert!(!triggers.try_fire(&instance_uuid, &trigger.id, 4999).await);
+        assert!(triggers.try_fire(&instance_uuid, &trigger.id, 5000).await);
+
+        // triggers survive a daemon restart
+        let triggers = MacroTriggers::new(path).await.unwrap();
+        assert_eq!(
+            triggers
+                .get(&instance_uuid, &trigger.id)
+                .await
+                .unwrap()
+                .config,
+            died
+        );
+        triggers.remove_instance(&instance_uuid).await.unwrap();
+        assert!(triggers.list(&instance_uuid).await.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! An instance under maintenance refuses to start, so no other admin, schedule or crash restart
+//! brings it up while someone works on its files
+
+use std::path::Path;
+
+use crate::auth::user::UserAction;
+use crate::error::{Error, ErrorCode};
+use crate::events::CausedBy;
+use crate::handlers::instance_fs::read_dot_lodestone_config;
+use crate::prelude::app_state;
+use crate::types::{InstanceUuid, Maintenance};
+
+/// The maintenance the instance at `path` is under, if any
+pub async fn maintenance(path: &Path) -> Result<Option<Maintenance>, Error> {
+    Ok(read_dot_lodestone_config(path)
+        .await?
+        .maintenance()
+        .cloned())
+}
+
+fn refusal(maintenance: &Maintenance) -> Error {
+    let message = match &maintenance.reason {
+        Some(reason) => format!(
+            "The instance is under maintenance, set by {}: {}",
+            maintenance.set_by_name, reason
+        ),
+        None => format!(
+            "The instance is under maintenance, set by {}",
+            maintenance.set_by_name
+        ),
+    };
+    Error::coded(ErrorCode::MaintenanceMode, message).with_details(maintenance)
+}
+
+/// Refuses to start the instance at `path` while it's under maintenance, unless `caused_by` is
+/// the user who set it or one allowed to bypass it
+pub async fn check_start(
+    path: &Path,
+    uuid: &InstanceUuid,
+    caused_by: &CausedBy,
+) -> Result<(), Error> {
+    let maintenance = match maintenance(path).await? {
+        Some(maintenance) => maintenance,
+        None => return Ok(()),
+    };
+    let user_id = match caused_by {
+        CausedBy::User { user_id, .. } => user_id,
+        _ => return Err(refusal(&maintenance)),
+    };
+    if *user_id == maintenance.set_by {
+        return Ok(());
+    }
+    // the instance can't see who started it beyond the cause, so the permission is looked up here
+    let may_bypass = app_state()
+        .users_manager
+        .read()
+        .await
+        .get_user(user_id)
+        .map_or(false, |user| {
+            user.can_perform_action(&UserAction::BypassMaintenance(uuid.clone()))
+        });
+    if may_bypass {
+        Ok(())
+    } else {
+        Err(refusal(&maintenance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::user_id::UserId;
+
+    #[tokio::test]
+    async fn test_only_users_may_start_under_maintenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let uuid = InstanceUuid::default();
+        let mut config = crate::types::DotLodestoneConfig::new(
+            uuid.clone(),
+            crate::traits::t_configurable::GameType::MinecraftJava,
+        );
+        crate::handlers::instance_fs::write_dot_lodestone_config(dir.path(), &config)
+            .await
+            .unwrap();
+        check_start(dir.path(), &uuid, &CausedBy::System)
+            .await
+            .unwrap();
+
+        let set_by = UserId::default();
+        config.set_maintenance(Some(Maintenance {
+            reason: Some("rolling back the nether".to_string()),
+            set_by: set_by.clone(),
+            set_by_name: "alex".to_string(),
+            since: 0,
+        }));
+        crate::handlers::instance_fs::write_dot_lodestone_config(dir.path(), &config)
+            .await
+            .unwrap();
+        for caused_by in [
+            CausedBy::System,
+            CausedBy::Schedule {
+                task_id: "nightly".to_string(),
+            },
+        ] {
+            let error = check_start(dir.path(), &uuid, &caused_by)
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::MaintenanceMode);
+            assert!(error.to_string().contains("rolling back the nether"));
+        }
+        check_start(
+            dir.path(),
+            &uuid,
+            &CausedBy::User {
+                user_id: set_by,
+                user_name: "alex".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+    }
+}
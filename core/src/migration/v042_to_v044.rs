@@ -4,7 +4,14 @@ use color_eyre::eyre::Context;
 use serde_json::{json, Value};
 use tracing::error;
 
-use crate::{error::Error, implementations::minecraft::RestoreConfig};
+use crate::{
+    console_history::DEFAULT_CONSOLE_HISTORY_SIZE,
+    error::Error,
+    implementations::minecraft::{
+        server_list_ping::DEFAULT_PING_INTERVAL_SECS, RestoreConfig, DEFAULT_STOP_TIMEOUT_SECS,
+    },
+    restart_policy::RestartPolicy,
+};
 
 use super::RestoreConfigV042;
 
@@ -25,6 +32,23 @@ impl From<RestoreConfigV042> for RestoreConfig {
             jre_major_version: config.jre_major_version,
             has_started: config.has_started,
             java_cmd: None,
+            command_queue: Default::default(),
+            console_history_size: DEFAULT_CONSOLE_HISTORY_SIZE,
+            announcements: Default::default(),
+            restart_policy: Some(RestartPolicy::from_restart_on_crash(
+                config.restart_on_crash,
+            )),
+            backup_schedule: Default::default(),
+            use_rcon: false,
+            stop_timeout_secs: DEFAULT_STOP_TIMEOUT_SECS,
+            extra_jvm_args: Vec::new(),
+            ping_interval_secs: DEFAULT_PING_INTERVAL_SECS,
+            env: Default::default(),
+            pre_start_hook: None,
+            post_stop_hook: None,
+            pending_build: None,
+            auto_suspend_minutes: None,
+            log_rules: None,
         }
     }
 }
@@ -0,0 +1,840 @@
+//! Groups of a Velocity or BungeeCord proxy and the servers behind it, with the proxy's server
+//! list and the backends' forwarding settings kept in line with the group
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+use crate::traits::t_configurable::{Game, MinecraftVariant, TConfigurable};
+use crate::traits::t_server::{State, TServer};
+use crate::traits::GameInstance;
+use crate::types::{InstanceUuid, Snowflake};
+use crate::util::{fs, rand_alphanumeric};
+
+const REDACTED: &str = "<redacted>";
+/// Velocity reads the secret from this file in its directory
+const VELOCITY_SECRET_FILE: &str = "forwarding.secret";
+const FORWARDING_SECRET_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ProxyKind {
+    Velocity,
+    Bungeecord,
+}
+
+impl ProxyKind {
+    fn config_file(self) -> &'static str {
+        match self {
+            ProxyKind::Velocity => "velocity.toml",
+            ProxyKind::Bungeecord => "config.yml",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct NetworkMember {
+    pub instance_uuid: InstanceUuid,
+    /// Name of the server in the proxy's server list
+    pub server_name: String,
+    /// Address the proxy reaches the server at, the port is the instance's own
+    pub host: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Network {
+    pub id: Snowflake,
+    pub name: String,
+    pub proxy_kind: ProxyKind,
+    pub proxy: InstanceUuid,
+    /// In the order the proxy tries them
+    pub members: Vec<NetworkMember>,
+    /// Velocity's modern forwarding secret, shared with every Paper backend
+    pub forwarding_secret: String,
+}
+
+impl Network {
+    pub fn redacted(mut self) -> Self {
+        self.forwarding_secret = REDACTED.to_string();
+        self
+    }
+
+    pub fn contains(&self, instance_uuid: &InstanceUuid) -> bool {
+        self.proxy == *instance_uuid || self.member(instance_uuid).is_some()
+    }
+
+    fn member(&self, instance_uuid: &InstanceUuid) -> Option<&NetworkMember> {
+        self.members
+            .iter()
+            .find(|member| member.instance_uuid == *instance_uuid)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub proxy_kind: ProxyKind,
+    pub proxy: InstanceUuid,
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NetworkMemberConfig {
+    /// A slug of the instance's name when unset
+    #[serde(default)]
+    pub server_name: Option<String>,
+    #[serde(default = "default_host")]
+    pub host: String,
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// What a sync did, instances that were running are left for the next one
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, TS)]
+#[ts(export)]
+pub struct NetworkSyncReport {
+    /// Instances whose files were rewritten
+    pub updated: Vec<InstanceUuid>,
+    /// Running instances whose files were left alone, sync again once they're stopped
+    pub skipped_running: Vec<InstanceUuid>,
+    pub warnings: Vec<String>,
+}
+
+fn network_not_found() -> Error {
+    Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Network not found"),
+    }
+}
+
+fn is_valid_server_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Networks are persisted in a single store, an instance belongs to at most one of them
+#[derive(Clone)]
+pub struct Networks {
+    networks: Arc<Mutex<Vec<Network>>>,
+    path_to_store: PathBuf,
+}
+
+impl Networks {
+    pub async fn new(path_to_store: PathBuf) -> Result<Self, Error> {
+        let networks = match tokio::fs::read(&path_to_store).await {
+            Ok(data) if !data.is_empty() => serde_json::from_slice(&data).context(format!(
+                "Failed to parse networks at {}",
+                path_to_store.display()
+            ))?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            networks: Arc::new(Mutex::new(networks)),
+            path_to_store,
+        })
+    }
+
+    async fn write_to_file(&self, networks: &[Network]) -> Result<(), Error> {
+        fs::write_atomic(
+            &self.path_to_store,
+            serde_json::to_string_pretty(networks).context("Failed to serialize networks")?,
+        )
+        .await
+    }
+
+    pub async fn list(&self) -> Vec<Network> {
+        self.networks.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &Snowflake) -> Result<Network, Error> {
+        self.networks
+            .lock()
+            .await
+            .iter()
+            .find(|network| network.id == *id)
+            .cloned()
+            .ok_or_else(network_not_found)
+    }
+
+    fn ensure_unclaimed(networks: &[Network], instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        match networks
+            .iter()
+            .find(|network| network.contains(instance_uuid))
+        {
+            Some(network) => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The instance is already in network \"{}\"", network.name),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn create(&self, config: NetworkConfig) -> Result<Network, Error> {
+        if config.name.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Network name cannot be empty"),
+            });
+        }
+        let mut networks = self.networks.lock().await;
+        Self::ensure_unclaimed(&networks, &config.proxy)?;
+        let network = Network {
+            id: Snowflake::new(),
+            name: config.name,
+            proxy_kind: config.proxy_kind,
+            proxy: config.proxy,
+            members: Vec::new(),
+            forwarding_secret: rand_alphanumeric(FORWARDING_SECRET_LENGTH),
+        };
+        networks.push(network.clone());
+        if let Err(e) = self.write_to_file(&networks).await {
+            networks.pop();
+            return Err(e);
+        }
+        Ok(network)
+    }
+
+    pub async fn delete(&self, id: &Snowflake) -> Result<Network, Error> {
+        let mut networks = self.networks.lock().await;
+        let index = networks
+            .iter()
+            .position(|network| network.id == *id)
+            .ok_or_else(network_not_found)?;
+        let network = networks.remove(index);
+        if let Err(e) = self.write_to_file(&networks).await {
+            networks.insert(index, network);
+            return Err(e);
+        }
+        Ok(network)
+    }
+
+    /// `instance_name` names the server in the proxy when the config doesn't
+    pub async fn add_member(
+        &self,
+        id: &Snowflake,
+        instance_uuid: &InstanceUuid,
+        instance_name: &str,
+        config: NetworkMemberConfig,
+    ) -> Result<Network, Error> {
+        let server_name = config
+            .server_name
+            .unwrap_or_else(|| crate::util::slugify(instance_name));
+        if !is_valid_server_name(&server_name) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Server name \"{}\" must be letters, digits, dashes and underscores",
+                    server_name
+                ),
+            });
+        }
+        if config.host.trim().is_empty() || config.host.contains(char::is_whitespace) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Host \"{}\" is not a valid address", config.host),
+            });
+        }
+        let mut networks = self.networks.lock().await;
+        Self::ensure_unclaimed(&networks, instance_uuid)?;
+        let network = networks
+            .iter_mut()
+            .find(|network| network.id == *id)
+            .ok_or_else(network_not_found)?;
+        if network
+            .members
+            .iter()
+            .any(|member| member.server_name == server_name)
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The network already has a server named \"{}\"", server_name),
+            });
+        }
+        network.members.push(NetworkMember {
+            instance_uuid: instance_uuid.clone(),
+            server_name,
+            host: config.host,
+        });
+        let network = network.clone();
+        if let Err(e) = self.write_to_file(&networks).await {
+            if let Some(stored) = networks.iter_mut().find(|n| n.id == *id) {
+                stored.members.pop();
+            }
+            return Err(e);
+        }
+        Ok(network)
+    }
+
+    pub async fn remove_member(
+        &self,
+        id: &Snowflake,
+        instance_uuid: &InstanceUuid,
+    ) -> Result<Network, Error> {
+        let mut networks = self.networks.lock().await;
+        let network = networks
+            .iter_mut()
+            .find(|network| network.id == *id)
+            .ok_or_else(network_not_found)?;
+        let index = network
+            .members
+            .iter()
+            .position(|member| member.instance_uuid == *instance_uuid)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("The instance is not a member of the network"),
+            })?;
+        let member = network.members.remove(index);
+        let network = network.clone();
+        if let Err(e) = self.write_to_file(&networks).await {
+            if let Some(stored) = networks.iter_mut().find(|n| n.id == *id) {
+                stored.members.insert(index, member);
+            }
+            return Err(e);
+        }
+        Ok(network)
+    }
+
+    /// Drops the networks of a deleted proxy and the memberships of a deleted backend
+    pub async fn remove_instance(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        let mut networks = self.networks.lock().await;
+        if !networks
+            .iter()
+            .any(|network| network.contains(instance_uuid))
+        {
+            return Ok(());
+        }
+        networks.retain(|network| network.proxy != *instance_uuid);
+        for network in networks.iter_mut() {
+            network
+                .members
+                .retain(|member| member.instance_uuid != *instance_uuid);
+        }
+        self.write_to_file(&networks).await
+    }
+}
+
+/// The instance if it's there and stopped, otherwise why it's left out goes into the report
+async fn stopped_instance(
+    instances: &DashMap<InstanceUuid, GameInstance>,
+    instance_uuid: &InstanceUuid,
+    report: &mut NetworkSyncReport,
+) -> Option<GameInstance> {
+    let instance = match instances.get(instance_uuid) {
+        Some(instance) => instance.clone(),
+        None => {
+            report
+                .warnings
+                .push(format!("Instance {} no longer exists", instance_uuid));
+            return None;
+        }
+    };
+    if instance.state().await != State::Stopped {
+        report.skipped_running.push(instance_uuid.clone());
+        return None;
+    }
+    Some(instance)
+}
+
+/// Rewrites the proxy's server list and the backends' forwarding settings of every stopped
+/// instance in the network
+pub async fn sync_network(
+    network: &Network,
+    instances: &DashMap<InstanceUuid, GameInstance>,
+) -> NetworkSyncReport {
+    let mut report = NetworkSyncReport::default();
+    for member in &network.members {
+        if let Some(instance) =
+            stopped_instance(instances, &member.instance_uuid, &mut report).await
+        {
+            match configure_backend(network, &instance, true, &mut report).await {
+                Ok(()) => report.updated.push(member.instance_uuid.clone()),
+                Err(e) => report.warnings.push(format!(
+                    "Failed to configure {}: {}",
+                    member.server_name, e.source
+                )),
+            }
+        }
+    }
+    if let Some(proxy) = stopped_instance(instances, &network.proxy, &mut report).await {
+        match configure_proxy(network, &proxy, instances, &mut report).await {
+            Ok(()) => report.updated.push(network.proxy.clone()),
+            Err(e) => report
+                .warnings
+                .push(format!("Failed to configure the proxy: {}", e.source)),
+        }
+    }
+    report
+}
+
+/// Turns forwarding off on a backend leaving the network and puts `online-mode` back on
+pub async fn leave_network(
+    network: &Network,
+    instance_uuid: &InstanceUuid,
+    instances: &DashMap<InstanceUuid, GameInstance>,
+    report: &mut NetworkSyncReport,
+) {
+    if let Some(instance) = stopped_instance(instances, instance_uuid, report).await {
+        match configure_backend(network, &instance, false, report).await {
+            Ok(()) => report.updated.push(instance_uuid.clone()),
+            Err(e) => report.warnings.push(format!(
+                "Failed to restore the settings of {}: {}",
+                instance_uuid, e.source
+            )),
+        }
+    }
+}
+
+async fn configure_backend(
+    network: &Network,
+    instance: &GameInstance,
+    joined: bool,
+    report: &mut NetworkSyncReport,
+) -> Result<(), Error> {
+    let variant = match instance.game_type().await {
+        Game::MinecraftJava { variant } => variant,
+        _ => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Only Minecraft Java servers can be behind a proxy"),
+            })
+        }
+    };
+    // the proxy authenticates players, the backend only takes them from it
+    instance
+        .set_game_settings(IndexMap::from([(
+            "online-mode".to_string(),
+            ConfigurableValue::Boolean(!joined),
+        )]))
+        .await?;
+    let path = instance.path().await;
+    match (network.proxy_kind, variant) {
+        (ProxyKind::Velocity, MinecraftVariant::Paper) => {
+            let mut values = vec![(&["proxies", "velocity", "enabled"][..], joined.to_string())];
+            if joined {
+                values.push((
+                    &["proxies", "velocity", "online-mode"][..],
+                    "true".to_string(),
+                ));
+                values.push((
+                    &["proxies", "velocity", "secret"][..],
+                    format!("'{}'", network.forwarding_secret),
+                ));
+            }
+            edit_yaml_file(&path.join("config").join("paper-global.yml"), &values).await
+        }
+        (ProxyKind::Bungeecord, MinecraftVariant::Paper | MinecraftVariant::Spigot) => {
+            edit_yaml_file(
+                &path.join("spigot.yml"),
+                &[(&["settings", "bungeecord"][..], joined.to_string())],
+            )
+            .await
+        }
+        (_, variant) => {
+            if joined {
+                report.warnings.push(format!(
+                    "{} servers need a forwarding mod or plugin to take players from {:?}, \
+                     only online-mode was turned off on {}",
+                    variant_name(&variant),
+                    network.proxy_kind,
+                    instance.name().await
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn variant_name(variant: &MinecraftVariant) -> String {
+    match variant {
+        MinecraftVariant::Other { name } => name.clone(),
+        variant => format!("{:?}", variant),
+    }
+}
+
+async fn configure_proxy(
+    network: &Network,
+    proxy: &GameInstance,
+    instances: &DashMap<InstanceUuid, GameInstance>,
+    report: &mut NetworkSyncReport,
+) -> Result<(), Error> {
+    let mut servers = Vec::new();
+    for member in &network.members {
+        let instance = instances
+            .get(&member.instance_uuid)
+            .map(|instance| instance.clone());
+        match instance {
+            Some(instance) => servers.push((
+                member.server_name.clone(),
+                format!("{}:{}", member.host, instance.port().await),
+            )),
+            None => report.warnings.push(format!(
+                "{} was left out of the proxy, its instance no longer exists",
+                member.server_name
+            )),
+        }
+    }
+    let path = proxy.path().await;
+    let config_path = path.join(network.proxy_kind.config_file());
+    let content = match tokio::fs::read_to_string(&config_path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => Err(e).context(format!("Failed to read {}", config_path.display()))?,
+    };
+    let content = match network.proxy_kind {
+        ProxyKind::Velocity => {
+            fs::write_atomic(path.join(VELOCITY_SECRET_FILE), &network.forwarding_secret).await?;
+            velocity_config(&content, &servers)?
+        }
+        ProxyKind::Bungeecord => bungeecord_config(&content, &servers),
+    };
+    fs::write_atomic(&config_path, content).await
+}
+
+/// `velocity.toml` with modern forwarding and `servers` as its server list, tried in order.
+///
+/// Comments of the file aren't kept
+pub fn velocity_config(content: &str, servers: &[(String, String)]) -> Result<String, Error> {
+    let mut config: toml::Table =
+        toml::from_str(content).context("Failed to parse velocity.toml")?;
+    config.insert(
+        "player-info-forwarding-mode".to_string(),
+        toml::Value::from("modern"),
+    );
+    config.insert(
+        "forwarding-secret-file".to_string(),
+        toml::Value::from(VELOCITY_SECRET_FILE),
+    );
+    let mut server_list = toml::Table::new();
+    for (name, address) in servers {
+        server_list.insert(name.clone(), toml::Value::from(address.as_str()));
+    }
+    server_list.insert(
+        "try".to_string(),
+        toml::Value::Array(
+            servers
+                .iter()
+                .map(|(name, _)| toml::Value::from(name.as_str()))
+                .collect(),
+        ),
+    );
+    config.insert("servers".to_string(), toml::Value::Table(server_list));
+    // forced hosts naming a server that's gone keep the proxy from starting
+    if let Some(toml::Value::Table(forced_hosts)) = config.get_mut("forced-hosts") {
+        for targets in forced_hosts.values_mut() {
+            if let toml::Value::Array(targets) = targets {
+                targets.retain(|target| {
+                    servers
+                        .iter()
+                        .any(|(name, _)| target.as_str() == Some(name.as_str()))
+                });
+            }
+        }
+        forced_hosts.retain(|_, targets| {
+            targets
+                .as_array()
+                .map_or(false, |targets| !targets.is_empty())
+        });
+    }
+    Ok(toml::to_string(&config).context("Failed to serialize velocity.toml")?)
+}
+
+/// BungeeCord's `config.yml` with IP forwarding and `servers` as its server list, the
+/// listeners' priorities follow the list's order
+pub fn bungeecord_config(content: &str, servers: &[(String, String)]) -> String {
+    let mut block = if servers.is_empty() {
+        vec!["servers: {}".to_string()]
+    } else {
+        vec!["servers:".to_string()]
+    };
+    for (name, address) in servers {
+        block.push(format!("  {}:", name));
+        block.push(format!("    motd: '{}'", name));
+        block.push(format!("    address: {}", address));
+        block.push("    restricted: false".to_string());
+    }
+    let content = replace_yaml_block(content, "servers", &block);
+    let names: Vec<&str> = servers.iter().map(|(name, _)| name.as_str()).collect();
+    let content = replace_yaml_lists(&content, "priorities", &names);
+    set_yaml_value(&content, &["ip_forward"], "true")
+}
+
+async fn edit_yaml_file(path: &Path, values: &[(&[&str], String)]) -> Result<(), Error> {
+    let mut content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => Err(e).context(format!("Failed to read {}", path.display()))?,
+    };
+    for (key_path, value) in values {
+        content = set_yaml_value(&content, key_path, value);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write_atomic(path, content).await
+}
+
+/// Indentation and content of a line that holds a key or list item, `None` for blank lines
+/// and comments
+fn yaml_line(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        None
+    } else {
+        Some((line.len() - trimmed.len(), trimmed))
+    }
+}
+
+fn yaml_key(trimmed: &str) -> Option<&str> {
+    if trimmed.starts_with('-') {
+        return None;
+    }
+    trimmed
+        .split_once(':')
+        .map(|(key, _)| key.trim().trim_matches(|c| c == '\'' || c == '"'))
+}
+
+/// First line from `start` that is no longer inside a block whose key is indented by `indent`
+fn yaml_block_end(lines: &[String], start: usize, indent: usize) -> usize {
+    lines[start..]
+        .iter()
+        .position(|line| matches!(yaml_line(line), Some((line_indent, _)) if line_indent <= indent))
+        .map_or(lines.len(), |offset| start + offset)
+}
+
+fn join_lines(lines: Vec<String>) -> String {
+    let mut content = lines.join("\n");
+    content.push('\n');
+    content
+}
+
+/// Sets the scalar at `key_path` of a block style YAML document, adding the keys that are
+/// missing. Everything else, comments included, is kept as it is
+pub fn set_yaml_value(content: &str, key_path: &[&str], value: &str) -> String {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let mut start = 0;
+    let mut end = lines.len();
+    let mut parent_indent: Option<usize> = None;
+    for (depth, key) in key_path.iter().enumerate() {
+        let last = depth + 1 == key_path.len();
+        let mut child_indent = None;
+        let mut found = None;
+        for (index, line) in lines.iter().enumerate().take(end).skip(start) {
+            let (indent, trimmed) = match yaml_line(line) {
+                Some(line) => line,
+                None => continue,
+            };
+            let child_indent = *child_indent.get_or_insert(indent);
+            if indent == child_indent && yaml_key(trimmed) == Some(key) {
+                found = Some((index, indent));
+                break;
+            }
+        }
+        match found {
+            Some((index, indent)) => {
+                let padding = " ".repeat(indent);
+                if last {
+                    lines[index] = format!("{}{}: {}", padding, key, value);
+                    return join_lines(lines);
+                }
+                // an inline mapping like `velocity: {}` is replaced by the block below it
+                lines[index] = format!("{}{}:", padding, key);
+                start = index + 1;
+                end = yaml_block_end(&lines, start, indent);
+                parent_indent = Some(indent);
+            }
+            None => {
+                let indent = child_indent
+                    .or_else(|| parent_indent.map(|indent| indent + 2))
+                    .unwrap_or(0);
+                let missing = key_path[depth..].iter().enumerate().map(|(level, key)| {
+                    let padding = " ".repeat(indent + level * 2);
+                    if depth + level + 1 == key_path.len() {
+                        format!("{}{}: {}", padding, key, value)
+                    } else {
+                        format!("{}{}:", padding, key)
+                    }
+                });
+                lines.splice(end..end, missing.collect::<Vec<_>>());
+                return join_lines(lines);
+            }
+        }
+    }
+    join_lines(lines)
+}
+
+/// Replaces the top level block of `key` with `block`, appending it if there's none
+fn replace_yaml_block(content: &str, key: &str, block: &[String]) -> String {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let start = lines.iter().position(
+        |line| matches!(yaml_line(line), Some((0, trimmed)) if yaml_key(trimmed) == Some(key)),
+    );
+    match start {
+        Some(start) => {
+            let end = yaml_block_end(&lines, start + 1, 0);
+            lines.splice(start..end, block.iter().cloned());
+        }
+        None => lines.extend(block.iter().cloned()),
+    }
+    join_lines(lines)
+}
+
+/// Replaces the items of every list under `key`, wherever it's nested
+fn replace_yaml_lists(content: &str, key: &str, items: &[&str]) -> String {
+    let lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let mut replaced = Vec::with_capacity(lines.len());
+    let mut index = 0;
+    while index < lines.len() {
+        let line = &lines[index];
+        index += 1;
+        let indent = match yaml_line(line) {
+            Some((indent, trimmed)) if yaml_key(trimmed) == Some(key) => indent,
+            _ => {
+                replaced.push(line.clone());
+                continue;
+            }
+        };
+        // items sit at the key's indentation or deeper, both are written at its indentation
+        while index < lines.len() {
+            match yaml_line(&lines[index]) {
+                Some((item_indent, trimmed))
+                    if item_indent > indent
+                        || (item_indent == indent && trimmed.starts_with('-')) =>
+                {
+                    index += 1
+                }
+                _ => break,
+            }
+        }
+        let padding = " ".repeat(indent);
+        if items.is_empty() {
+            replaced.push(format!("{}{}: []", padding, key));
+        } else {
+            replaced.push(format!("{}{}:", padding, key));
+            replaced.extend(items.iter().map(|item| format!("{}- {}", padding, item)));
+        }
+    }
+    join_lines(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers() -> Vec<(String, String)> {
+        vec![
+            ("lobby".to_string(), "127.0.0.1:25566".to_string()),
+            ("survival".to_string(), "127.0.0.1:25567".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_set_yaml_value() {
+        let paper_global = "# comment\nproxies:\n  bungee-cord:\n    online-mode: true\n  velocity:\n    enabled: false\n    online-mode: false\n    secret: ''\nscoreboards: {}\n";
+        let updated = set_yaml_value(paper_global, &["proxies", "velocity", "enabled"], "true");
+        let updated = set_yaml_value(&updated, &["proxies", "velocity", "secret"], "'abc'");
+        assert_eq!(
+            updated,
+            "# comment\nproxies:\n  bungee-cord:\n    online-mode: true\n  velocity:\n    enabled: true\n    online-mode: false\n    secret: 'abc'\nscoreboards: {}\n"
+        );
+
+        let spigot = "settings:\n  debug: false\nmessages:\n  whitelist: no\n";
+        assert_eq!(
+            set_yaml_value(spigot, &["settings", "bungeecord"], "true"),
+            "settings:\n  debug: false\n  bungeecord: true\nmessages:\n  whitelist: no\n"
+        );
+        assert_eq!(
+            set_yaml_value("", &["proxies", "velocity", "enabled"], "true"),
+            "proxies:\n  velocity:\n    enabled: true\n"
+        );
+    }
+
+    #[test]
+    fn test_velocity_config() {
+        let content = "config-version = \"2.6\"\nbind = \"0.0.0.0:25577\"\n\n[servers]\nlobby = \"127.0.0.1:30066\"\nfactions = \"127.0.0.1:30067\"\ntry = [\"lobby\"]\n\n[forced-hosts]\n\"lobby.example.com\" = [\"lobby\"]\n\"factions.example.com\" = [\"factions\"]\n";
+        let config: toml::Table =
+            toml::from_str(&velocity_config(content, &servers()).unwrap()).unwrap();
+        assert_eq!(config["bind"].as_str(), Some("0.0.0.0:25577"));
+        assert_eq!(
+            config["player-info-forwarding-mode"].as_str(),
+            Some("modern")
+        );
+        let server_list = config["servers"].as_table().unwrap();
+        assert_eq!(server_list["survival"].as_str(), Some("127.0.0.1:25567"));
+        assert!(!server_list.contains_key("factions"));
+        assert_eq!(
+            server_list["try"],
+            toml::Value::Array(vec!["lobby".into(), "survival".into()])
+        );
+        let forced_hosts = config["forced-hosts"].as_table().unwrap();
+        assert!(forced_hosts.contains_key("lobby.example.com"));
+        assert!(!forced_hosts.contains_key("factions.example.com"));
+    }
+
+    #[test]
+    fn test_bungeecord_config() {
+        let content = "listeners:\n- query_port: 25577\n  priorities:\n  - lobby\n  - pvp\n  host: 0.0.0.0:25577\nip_forward: false\nservers:\n  lobby:\n    motd: 'Just another server'\n    address: localhost:25565\n    restricted: false\nonline_mode: true\n";
+        assert_eq!(
+            bungeecord_config(content, &servers()),
+            "listeners:\n- query_port: 25577\n  priorities:\n  - lobby\n  - survival\n  host: 0.0.0.0:25577\nip_forward: true\nservers:\n  lobby:\n    motd: 'lobby'\n    address: 127.0.0.1:25566\n    restricted: false\n  survival:\n    motd: 'survival'\n    address: 127.0.0.1:25567\n    restricted: false\nonline_mode: true\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memberships() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("networks.json");
+        let networks = Networks::new(store.clone()).await.unwrap();
+        let proxy = InstanceUuid::from("proxy".to_string());
+        let backend = InstanceUuid::from("backend".to_string());
+        let network = networks
+            .create(NetworkConfig {
+                name: "Network".to_string(),
+                proxy_kind: ProxyKind::Velocity,
+                proxy: proxy.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(network.forwarding_secret.len(), FORWARDING_SECRET_LENGTH);
+        let member_config = || NetworkMemberConfig {
+            server_name: None,
+            host: default_host(),
+        };
+        let network = networks
+            .add_member(&network.id, &backend, "My Lobby", member_config())
+            .await
+            .unwrap();
+        assert_eq!(network.members[0].server_name, "my-lobby");
+        // an instance is only ever in one network
+        assert!(networks
+            .add_member(&network.id, &backend, "Lobby", member_config())
+            .await
+            .is_err());
+        assert!(networks
+            .add_member(&network.id, &proxy, "Proxy", member_config())
+            .await
+            .is_err());
+
+        let reloaded = Networks::new(store).await.unwrap();
+        assert_eq!(reloaded.list().await, vec![network.clone()]);
+        reloaded.remove_instance(&backend).await.unwrap();
+        assert!(reloaded.get(&network.id).await.unwrap().members.is_empty());
+        reloaded.remove_instance(&proxy).await.unwrap();
+        assert!(reloaded.list().await.is_empty());
+    }
+}
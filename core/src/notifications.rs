@@ -0,0 +1,664 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::TimeZone;
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::auth::user::UsersManager;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{
+    CausedBy, Event, EventInner, InstanceEventInner, ProgressionEventInner, ProgressionStartValue,
+    UserEventInner,
+};
+use crate::traits::t_player::TPlayer;
+use crate::traits::t_server::State;
+use crate::types::{InstanceUuid, Snowflake};
+
+/// Attempts per notification, the first one included
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(2);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Shown in place of the secret header value when targets are listed
+const REDACTED: &str = "<redacted>";
+
+/// Classes of events a target can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum NotificationEvent {
+    InstanceCrashed,
+    InstanceStarted,
+    BackupFailed,
+    PlayerJoined,
+    UserCreated,
+}
+
+/// Shape of the request body sent to a target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum WebhookFormat {
+    Discord,
+    Slack,
+    /// The [`Notification`] itself as JSON
+    Generic,
+}
+
+/// A header sent with every request, e.g. a shared secret the receiver checks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct SecretHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct NotificationTargetConfig {
+    pub name: String,
+    pub url: String,
+    pub format: WebhookFormat,
+    #[serde(default)]
+    pub secret_header: Option<SecretHeader>,
+    pub events: HashSet<NotificationEvent>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl NotificationTargetConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Target name cannot be empty"),
+            });
+        }
+        let url = reqwest::Url::parse(&self.url).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid webhook URL \"{}\": {}", self.url, e),
+        })?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Webhook URL must be http or https"),
+            });
+        }
+        if let Some(header) = &self.secret_header {
+            if reqwest::header::HeaderName::from_bytes(header.name.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(&header.value).is_err()
+            {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid secret header"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct NotificationTarget {
+    pub id: Snowflake,
+    #[serde(flatten)]
+    pub config: NotificationTargetConfig,
+}
+
+impl NotificationTarget {
+    /// The target as returned by the API, without the secret header value
+    pub fn redacted(mut self) -> Self {
+        if let Some(header) = self.config.secret_header.as_mut() {
+            header.value = REDACTED.to_string();
+        }
+        self
+    }
+}
+
+/// What is sent to a target, rendered into its format on delivery
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Notification {
+    /// `None` for test notifications
+    pub event: Option<NotificationEvent>,
+    pub title: String,
+    pub message: String,
+    pub instance_uuid: Option<InstanceUuid>,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+}
+
+impl Notification {
+    fn new(
+        event: Option<NotificationEvent>,
+        title: impl Into<String>,
+        message: impl Into<String>,
+        instance_uuid: Option<InstanceUuid>,
+    ) -> Self {
+        Self {
+            event,
+            title: title.into(),
+            message: message.into(),
+            instance_uuid,
+            timestamp: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    fn render(&self, format: WebhookFormat) -> serde_json::Value {
+        match format {
+            WebhookFormat::Discord => {
+                let color = match self.event {
+                    Some(NotificationEvent::InstanceCrashed | NotificationEvent::BackupFailed) => {
+                        0xe5484d
+                    }
+                    Some(NotificationEvent::InstanceStarted) => 0x30a46c,
+                    _ => 0x5b5bd6,
+                };
+                json!({
+                    "username": "Lodestone",
+                    "embeds": [{
+                        "title": self.title,
+                        "description": self.message,
+                        "color": color,
+                        "timestamp": chrono::Utc
+                            .timestamp_opt(self.timestamp, 0)
+                            .single()
+                            .map(|timestamp| timestamp.to_rfc3339()),
+                    }],
+                })
+            }
+            WebhookFormat::Slack => json!({
+                "text": format!("*{}*\n{}", self.title, self.message),
+            }),
+            WebhookFormat::Generic => json!(self),
+        }
+    }
+}
+
+/// Whether a failed delivery is worth another attempt
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+    INITIAL_RETRY_DELAY * 2u32.pow(attempt.saturating_sub(1))
+}
+
+/// Sends notifications to webhook targets registered by admins.
+///
+/// Targets are persisted in a single store
+#[derive(Clone)]
+pub struct NotificationManager {
+    targets: Arc<Mutex<Vec<NotificationTarget>>>,
+    path_to_store: PathBuf,
+    http: reqwest::Client,
+}
+
+impl NotificationManager {
+    pub async fn new(path_to_store: PathBuf) -> Result<Self, Error> {
+        let targets = match tokio::fs::read(&path_to_store).await {
+            Ok(data) if !data.is_empty() => serde_json::from_slice(&data).context(format!(
+                "Failed to parse notification targets at {}",
+                path_to_store.display()
+            ))?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            targets: Arc::new(Mutex::new(targets)),
+            path_to_store,
+            http: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()
+                .context("Failed to create HTTP client")?,
+        })
+    }
+
+    async fn write_to_file(&self, targets: &[NotificationTarget]) -> Result<(), Error> {
+        tokio::fs::write(
+            &self.path_to_store,
+            serde_json::to_string_pretty(targets)
+                .context("Failed to serialize notification targets")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write notification targets to {}",
+            self.path_to_store.display()
+        ))?;
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Vec<NotificationTarget> {
+        self.targets.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &Snowflake) -> Result<NotificationTarget, Error> {
+        self.targets
+            .lock()
+            .await
+            .iter()
+            .find(|t| t.id == *id)
+            .cloned()
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Notification target not found"),
+            })
+    }
+
+    pub async fn create(
+        &self,
+        config: NotificationTargetConfig,
+    ) -> Result<NotificationTarget, Error> {
+        config.validate()?;
+        let target = NotificationTarget {
+            id: Snowflake::new(),
+            config,
+        };
+        let mut targets = self.targets.lock().await;
+        targets.push(target.clone());
+        self.write_to_file(&targets).await?;
+        Ok(target)
+    }
+
+    /// Replaces a target's config, a redacted secret header value keeps the stored one
+    pub async fn update(
+        &self,
+        id: &Snowflake,
+        mut config: NotificationTargetConfig,
+    ) -> Result<NotificationTarget, Error> {
+        let mut targets = self.targets.lock().await;
+        let target = targets
+            .iter_mut()
+            .find(|t| t.id == *id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Notification target not found"),
+            })?;
+        if let (Some(header), Some(old_header)) = (
+            config.secret_header.as_mut(),
+            target.config.secret_header.as_ref(),
+        ) {
+            if header.value == REDACTED {
+                header.value = old_header.value.clone();
+            }
+        }
+        config.validate()?;
+        target.config = config;
+        let target = target.clone();
+        self.write_to_file(&targets).await?;
+        Ok(target)
+    }
+
+    pub async fn delete(&self, id: &Snowflake) -> Result<(), Error> {
+        let mut targets = self.targets.lock().await;
+        let len = targets.len();
+        targets.retain(|t| t.id != *id);
+        if targets.len() == len {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Notification target not found"),
+            });
+        }
+        self.write_to_file(&targets).await
+    }
+
+    /// Sends a single request, without retrying
+    async fn send(
+        &self,
+        target: &NotificationTarget,
+        notification: &Notification,
+    ) -> Result<(), (Error, bool)> {
+        let mut request = self
+            .http
+            .post(&target.config.url)
+            .json(&notification.render(target.config.format));
+        if let Some(header) = &target.config.secret_header {
+            request = request.header(&header.name, &header.value);
+        }
+        let response = request.send().await.map_err(|e| {
+            (
+                Error {
+                    kind: ErrorKind::External,
+                    source: eyre!("Failed to reach {}: {}", target.config.name, e),
+                },
+                true,
+            )
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err((
+                Error {
+                    kind: ErrorKind::External,
+                    source: eyre!("{} responded with {}", target.config.name, status),
+                },
+                is_retryable(status),
+            ))
+        }
+    }
+
+    /// Sends a test notification to a target once, failures are returned to the caller
+    pub async fn send_test(&self, id: &Snowflake, caused_by: CausedBy) -> Result<(), Error> {
+        let target = self.get(id).await?;
+        let requested_by = match caused_by {
+            CausedBy::User { user_name, .. } => format!(" by {}", user_name),
+            _ => String::new(),
+        };
+        let notification = Notification::new(
+            None,
+            "Test notification",
+            format!("Sent from Lodestone{}", requested_by),
+            None,
+        );
+        self.send(&target, &notification).await.map_err(|(e, _)| e)
+    }
+
+    /// Sends with exponential backoff until the target accepts or attempts run out
+    async fn deliver(self, target: NotificationTarget, notification: Notification) {
+        for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+            match self.send(&target, &notification).await {
+                Ok(()) => return,
+                Err((e, retryable)) => {
+                    if !retryable || attempt == MAX_DELIVERY_ATTEMPTS {
+                        warn!(
+                            "Giving up on notification \"{}\" after {} attempts: {}",
+                            notification.title, attempt, e.source
+                        );
+                        return;
+                    }
+                    let delay = retry_delay(attempt);
+                    info!("{}, retrying in {}s", e.source, delay.as_secs());
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Hands a notification to every enabled target subscribed to its event.
+    ///
+    /// Each delivery runs in its own task so a slow target doesn't hold up the others
+    async fn dispatch(&self, notification: Notification) {
+        let targets: Vec<NotificationTarget> = self
+            .targets
+            .lock()
+            .await
+            .iter()
+            .filter(|t| {
+                t.config.enabled
+                    && notification
+                        .event
+                        .map_or(false, |event| t.config.events.contains(&event))
+            })
+            .cloned()
+            .collect();
+        for target in targets {
+            tokio::spawn(self.clone().deliver(target, notification.clone()));
+        }
+    }
+
+    /// Turns events into notifications until the event broadcaster closes.
+    ///
+    /// Subscribes right away so no event sent after this call is missed
+    pub fn run(
+        self,
+        users_manager: Arc<RwLock<UsersManager>>,
+        event_broadcaster: &EventBroadcaster,
+    ) -> impl std::future::Future<Output = ()> {
+        let mut rx = event_broadcaster.subscribe();
+        async move {
+            let mut classifier = EventClassifier::default();
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Notifications lagged, skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let mut notification = match classifier.classify(&event) {
+                    Some(notification) => notification,
+                    None => continue,
+                };
+                if let EventInner::UserEvent(user_event) = &event.event_inner {
+                    if let Some(user) = users_manager.read().await.get_user(&user_event.user_id) {
+                        notification.message = format!("{} was created", user.username);
+                    }
+                }
+                self.dispatch(notification).await;
+            }
+        }
+    }
+}
+
+/// Tracks the progressions needed to tell what an end event belongs to
+#[derive(Default)]
+struct EventClassifier {
+    /// Backup progressions in flight, by event id
+    backups: HashMap<Snowflake, (InstanceUuid, String)>,
+    /// Instances suspended, getting back to running from there is a resume, not a start
+    suspended: HashSet<InstanceUuid>,
+}
+
+impl EventClassifier {
+    fn classify(&mut self, event: &Event) -> Option<Notification> {
+        match &event.event_inner {
+            EventInner::InstanceEvent(instance_event) => {
+                let name = &instance_event.instance_name;
+                let instance_uuid = Some(instance_event.instance_uuid.clone());
+                match &instance_event.instance_event_inner {
+                    InstanceEventInner::StateTransition {
+                        to: State::Suspended,
+                    } => {
+                        self.suspended.insert(instance_event.instance_uuid.clone());
+                        None
+                    }
+                    InstanceEventInner::StateTransition { to: State::Running }
+                        if self.suspended.remove(&instance_event.instance_uuid) =>
+                    {
+                        None
+                    }
+                    InstanceEventInner::StateTransition { to: State::Running } => {
+                        Some(Notification::new(
+                            Some(NotificationEvent::InstanceStarted),
+                            format!("{} started", name),
+                            format!("{} is up and running", name),
+                            instance_uuid,
+                        ))
+                    }
+                    InstanceEventInner::StateTransition { .. } => {
+                        self.suspended.remove(&instance_event.instance_uuid);
+                        None
+                    }
+                    InstanceEventInner::InstanceError { message } => Some(Notification::new(
+                        Some(NotificationEvent::InstanceCrashed),
+                        format!("{} crashed", name),
+                        message.clone(),
+                        instance_uuid,
+                    )),
+                    InstanceEventInner::PlayerChange { players_joined, .. }
+                        if !players_joined.is_empty() =>
+                    {
+                        let mut players: Vec<String> =
+                            players_joined.iter().map(|p| p.get_name()).collect();
+                        players.sort();
+                        Some(Notification::new(
+                            Some(NotificationEvent::PlayerJoined),
+                            format!("Player joined {}", name),
+                            format!("{} joined {}", players.join(", "), name),
+                            instance_uuid,
+                        ))
+                    }
+                    _ => None,
+                }
+            }
+            EventInner::UserEvent(user_event) => match user_event.user_event_inner {
+                UserEventInner::UserCreated => Some(Notification::new(
+                    Some(NotificationEvent::UserCreated),
+                    "New user",
+                    format!("User {} was created", user_event.user_id),
+                    None,
+                )),
+                _ => None,
+            },
+            EventInner::ProgressionEvent(progression) => {
+                match progression.progression_event_inner() {
+                    ProgressionEventInner::ProgressionStart {
+                        progression_name,
+                        inner: Some(ProgressionStartValue::BackupRun { instance_uuid, .. }),
+                        ..
+                    } => {
+                        self.backups.insert(
+                            progression.event_id(),
+                            (instance_uuid.clone(), progression_name.clone()),
+                        );
+                        None
+                    }
+                    ProgressionEventInner::ProgressionEnd {
+                        success, message, ..
+                    } => {
+                        let (instance_uuid, progression_name) =
+                            self.backups.remove(&progression.event_id())?;
+                        if *success {
+                            return None;
+                        }
+                        Some(Notification::new(
+                            Some(NotificationEvent::BackupFailed),
+                            format!("{} failed", progression_name),
+                            message
+                                .clone()
+                                .unwrap_or_else(|| "Backup failed".to_string()),
+                            Some(instance_uuid),
+                        ))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(url: &str) -> NotificationTargetConfig {
+        NotificationTargetConfig {
+            name: "discord".to_string(),
+            url: url.to_string(),
+            format: WebhookFormat::Discord,
+            secret_header: Some(SecretHeader {
+                name: "X-Secret".to_string(),
+                value: "hunter2".to_string(),
+            }),
+            events: HashSet::from([NotificationEvent::BackupFailed]),
+            enabled: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_target_crud() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notifications.json");
+        let manager = NotificationManager::new(path.clone()).await.unwrap();
+        assert!(manager.create(config("ftp://example.com")).await.is_err());
+        let target = manager
+            .create(config("https://discord.com/api/webhooks/1/abc"))
+            .await
+            .unwrap();
+
+        // a redacted secret sent back keeps the stored one
+        let mut new_config = target.clone().redacted().config;
+        new_config.name = "renamed".to_string();
+        manager.update(&target.id, new_config).await.unwrap();
+        let reloaded = NotificationManager::new(path).await.unwrap();
+        let updated = reloaded.get(&target.id).await.unwrap();
+        assert_eq!(updated.config.name, "renamed");
+        assert_eq!(updated.config.secret_header.unwrap().value, "hunter2");
+
+        reloaded.delete(&target.id).await.unwrap();
+        assert!(reloaded.get(&target.id).await.is_err());
+    }
+
+    #[test]
+    fn test_backup_failure_is_classified() {
+        let mut classifier = EventClassifier::default();
+        let instance_uuid = InstanceUuid::default();
+        let (start, event_id) = Event::new_progression_event_start(
+            "Backing up test",
+            None,
+            Some(ProgressionStartValue::BackupRun {
+                instance_uuid: instance_uuid.clone(),
+                backup_id: Snowflake::default(),
+                scheduled: true,
+            }),
+            CausedBy::System,
+        );
+        assert!(classifier.classify(&start).is_none());
+        let end = Event::new_progression_event_end(
+            event_id,
+            false,
+            Some("Backup failed: disk full"),
+            None,
+        );
+        let notification = classifier.classify(&end).unwrap();
+        assert_eq!(notification.event, Some(NotificationEvent::BackupFailed));
+        assert_eq!(notification.instance_uuid, Some(instance_uuid));
+        assert_eq!(notification.message, "Backup failed: disk full");
+        assert!(classifier.backups.is_empty());
+    }
+
+    #[test]
+    fn test_resume_is_not_a_start() {
+        let mut classifier = EventClassifier::default();
+        let instance_uuid = InstanceUuid::default();
+        let transition = |to| {
+            Event::new_instance_state_transition(instance_uuid.clone(), "test".to_string(), to)
+        };
+        let started = classifier.classify(&transition(State::Running)).unwrap();
+        assert_eq!(started.event, Some(NotificationEvent::InstanceStarted));
+        assert!(classifier.classify(&transition(State::Suspended)).is_none());
+        assert!(classifier.classify(&transition(State::Running)).is_none());
+        assert!(classifier.suspended.is_empty());
+    }
+
+    #[test]
+    fn test_render_formats() {
+        let notification = Notification::new(
+            Some(NotificationEvent::InstanceCrashed),
+            "Survival crashed",
+            "Server crashed (exit status: 1)",
+            None,
+        );
+        let discord = notification.render(WebhookFormat::Discord);
+        assert_eq!(discord["embeds"][0]["title"], "Survival crashed");
+        let slack = notification.render(WebhookFormat::Slack);
+        assert_eq!(
+            slack["text"],
+            "*Survival crashed*\nServer crashed (exit status: 1)"
+        );
+        let generic = notification.render(WebhookFormat::Generic);
+        assert_eq!(generic["event"], "instance_crashed");
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(2), Duration::from_secs(4));
+        assert_eq!(retry_delay(4), Duration::from_secs(16));
+    }
+}
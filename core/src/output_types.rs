@@ -23,8 +23,11 @@ impl From<&Event> for ClientEvent {
     fn from(event: &Event) -> Self {
         let level = match &event.event_inner {
             EventInner::InstanceEvent(i) => match i.instance_event_inner {
-                InstanceEventInner::InstanceError { .. } => EventLevel::Error,
-                InstanceEventInner::InstanceWarning { .. } => EventLevel::Warning,
+                InstanceEventInner::InstanceError { .. }
+                | InstanceEventInner::LaunchFailed { .. } => EventLevel::Error,
+                InstanceEventInner::InstanceWarning { .. } | InstanceEventInner::EulaRequired => {
+                    EventLevel::Warning
+                }
                 _ => EventLevel::Info,
             },
             EventInner::UserEvent(_) => EventLevel::Info,
@@ -52,6 +55,7 @@ impl From<&Event> for ClientEvent {
             },
             EventInner::FSEvent(_) => EventLevel::Info,
             EventInner::PlayitggRunnerEvent(_) => EventLevel::Info,
+            EventInner::DaemonSettingsEvent(_) => EventLevel::Info,
         };
         ClientEvent {
             event_inner: event.event_inner.clone(),
@@ -95,6 +95,15 @@ pub struct PlayitSignupData {
     pub claim_code: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/playitgg/start_cli",
+    tag = "playitgg",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn start_cli(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<()>, Error> {
@@ -144,6 +153,15 @@ pub async fn start_cli(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    post,
+    path = "/playitgg/stop_cli",
+    tag = "playitgg",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn stop_cli(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<()>, Error> {
@@ -164,6 +182,15 @@ pub async fn stop_cli(
     Ok(Json(()))
 }
 
+#[utoipa::path(
+    get,
+    path = "/playitgg/cli_is_running",
+    tag = "playitgg",
+    responses(
+        (status = 200, description = "Success", body = bool),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn cli_is_running(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<bool>, Error> {
@@ -174,6 +201,15 @@ pub async fn cli_is_running(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/playitgg/generate_signup_link",
+    tag = "playitgg",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn generate_signup_link(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<PlayitSignupData>, Error> {
@@ -325,6 +361,15 @@ pub async fn generate_signup_link(
     Ok(ret_data)
 }
 
+#[utoipa::path(
+    post,
+    path = "/playitgg/verify_key",
+    tag = "playitgg",
+    responses(
+        (status = 200, description = "Success", body = bool),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn verify_key(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<bool>, Error> {
@@ -335,6 +380,15 @@ pub async fn verify_key(
     Ok(Json(is_valid_secret_key(secret_key).await))
 }
 
+#[utoipa::path(
+    get,
+    path = "/playitgg/get_tunnels",
+    tag = "playitgg",
+    responses(
+        (status = 200, description = "Success"),
+        (status = "default", description = "Failure, see the code", body = Error),
+    ),
+)]
 pub async fn get_tunnels(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<Vec<PlayitTunnelInfo>>, Error> {
@@ -1,12 +1,21 @@
-use std::{collections::HashSet, net::SocketAddrV4};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4, TcpListener},
+};
 
 use color_eyre::eyre::{eyre, Context};
 use serde::{Deserialize, Serialize};
+use ts_rs::TS;
 
-use crate::error::Error;
+use crate::{
+    error::{Error, ErrorCode, ErrorKind},
+    global_settings::PortRange,
+    types::InstanceUuid,
+};
 
 pub struct PortManager {
-    allocated_ports: HashSet<u32>,
+    allocated_ports: HashMap<u32, InstanceUuid>,
+    range: PortRange,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -15,42 +24,127 @@ pub struct PortStatus {
     pub is_allocated: bool,
 }
 
+#[derive(Debug, Serialize, Clone, TS)]
+#[ts(export)]
+pub struct PortAllocation {
+    pub port: u32,
+    pub instance_uuid: InstanceUuid,
+}
+
+/// Whether a server could listen on `port` right now, found by briefly binding it on all
+/// interfaces the way game servers do
+pub fn is_bindable(port: u32) -> bool {
+    match u16::try_from(port) {
+        Ok(port) if port != 0 => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok(),
+        _ => false,
+    }
+}
+
 impl PortManager {
-    pub fn new(allocated_ports: HashSet<u32>) -> PortManager {
-        PortManager { allocated_ports }
-    }
-    #[allow(dead_code)]
-    pub fn allocate(&mut self, start_port: u32) -> u32 {
-        if self.allocated_ports.contains(&start_port) {
-            let mut new_port = start_port + 1;
-            while self.allocated_ports.contains(&new_port)
-                || !port_scanner::local_port_available(new_port as u16)
-            {
-                new_port += 1;
-            }
-            self.allocated_ports.insert(new_port);
-            new_port
-        } else {
-            self.allocated_ports.insert(start_port);
+    pub fn new(allocated_ports: HashMap<u32, InstanceUuid>, range: PortRange) -> PortManager {
+        PortManager {
+            allocated_ports,
+            range,
+        }
+    }
+
+    pub fn set_range(&mut self, range: PortRange) {
+        self.range = range;
+    }
+
+    fn is_free(&self, port: u32) -> bool {
+        !self.allocated_ports.contains_key(&port) && is_bindable(port)
+    }
+
+    /// Free ports of the range, starting at `start_port` if it is in the range and wrapping
+    /// around to the start of the range
+    fn free_ports(&self, start_port: u32) -> impl Iterator<Item = u32> + '_ {
+        let start_port = if self.range.contains(start_port) {
             start_port
+        } else {
+            self.range.start
+        };
+        (start_port..=self.range.end)
+            .chain(self.range.start..start_port)
+            .filter(|port| self.is_free(*port))
+    }
+
+    /// Allocates the first free port of the range at or after `start_port` to `owner`.
+    ///
+    /// Other daemons on the host keep their own tables, so a port is only handed out once
+    /// binding it succeeds
+    pub fn allocate(&mut self, start_port: u32, owner: &InstanceUuid) -> Result<u32, Error> {
+        let port = self.free_ports(start_port).next().ok_or_else(|| Error {
+            kind: ErrorKind::Conflict,
+            source: eyre!(
+                "No free port left between {} and {}",
+                self.range.start,
+                self.range.end
+            ),
+        })?;
+        self.allocated_ports.insert(port, owner.clone());
+        Ok(port)
+    }
+
+    /// Allocates a port the user picked, which may lie outside the range
+    pub fn claim(&mut self, port: u32, owner: &InstanceUuid) -> Result<(), Error> {
+        match self.allocated_ports.get(&port) {
+            Some(holder) if holder == owner => return Ok(()),
+            Some(holder) => {
+                return Err(Error::coded(
+                    ErrorCode::PortInUse,
+                    format!("Port {} is already allocated to instance {}", port, holder),
+                )
+                .with_details(serde_json::json!({ "port": port, "instance_uuid": holder })))
+            }
+            None => {}
+        }
+        if !is_bindable(port) {
+            return Err(Error::coded(
+                ErrorCode::PortInUse,
+                format!("Port {} is already in use by another program", port),
+            )
+            .with_details(serde_json::json!({ "port": port })));
         }
+        self.allocated_ports.insert(port, owner.clone());
+        Ok(())
+    }
+
+    /// Up to `count` free ports of the range, without allocating them
+    pub fn suggest(&self, count: usize) -> Vec<u32> {
+        self.free_ports(self.range.start).take(count).collect()
+    }
+
+    pub fn allocations(&self) -> Vec<PortAllocation> {
+        let mut allocations: Vec<PortAllocation> = self
+            .allocated_ports
+            .iter()
+            .map(|(port, instance_uuid)| PortAllocation {
+                port: *port,
+                instance_uuid: instance_uuid.clone(),
+            })
+            .collect();
+        allocations.sort_by_key(|allocation| allocation.port);
+        allocations
     }
 
     pub fn port_status(&self, port: u32) -> PortStatus {
         PortStatus {
-            is_in_use: !port_scanner::local_port_available(port as u16),
-            is_allocated: self.allocated_ports.contains(&port),
+            is_in_use: !is_bindable(port),
+            is_allocated: self.allocated_ports.contains_key(&port),
         }
     }
 
-    pub fn add_port(&mut self, port: u32) {
-        self.allocated_ports.insert(port);
-    }
-
     pub fn deallocate(&mut self, port: u32) {
         self.allocated_ports.remove(&port);
     }
 
+    /// Releases every port held by an instance
+    pub fn deallocate_instance(&mut self, instance_uuid: &InstanceUuid) {
+        self.allocated_ports
+            .retain(|_, holder| holder != instance_uuid);
+    }
+
     pub async fn open_port(&self, port: u16) -> Result<(), Error> {
         tokio::task::spawn_blocking(move || {
             if let Ok(local_ip) = local_ip_address::local_ip() {
@@ -80,3 +174,66 @@ impl PortManager {
         .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_range(len: u32) -> PortRange {
+        // find a run of ports nothing on the test machine listens on
+        let mut start = 40000;
+        while !(start..start + len).all(is_bindable) {
+            start += len;
+        }
+        PortRange {
+            start,
+            end: start + len - 1,
+        }
+    }
+
+    #[test]
+    fn test_allocate_within_range() {
+        let range = free_range(3);
+        let mut port_manager = PortManager::new(HashMap::new(), range);
+        let a = InstanceUuid::default();
+        let b = InstanceUuid::default();
+        assert_eq!(port_manager.allocate(range.start, &a).unwrap(), range.start);
+        assert_eq!(
+            port_manager.allocate(range.start, &b).unwrap(),
+            range.start + 1
+        );
+        // a start outside the range falls back to the range
+        assert_eq!(port_manager.allocate(1, &b).unwrap(), range.start + 2);
+        assert!(port_manager.allocate(range.start, &a).is_err());
+        assert!(port_manager.suggest(5).is_empty());
+
+        port_manager.deallocate_instance(&b);
+        assert_eq!(
+            port_manager.suggest(5),
+            vec![range.start + 1, range.start + 2]
+        );
+        assert_eq!(port_manager.allocations().len(), 1);
+    }
+
+    #[test]
+    fn test_claim() {
+        let range = free_range(2);
+        let mut port_manager = PortManager::new(HashMap::new(), range);
+        let a = InstanceUuid::default();
+        port_manager.claim(range.start, &a).unwrap();
+        port_manager.claim(range.start, &a).unwrap();
+        let e = port_manager
+            .claim(range.start, &InstanceUuid::default())
+            .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::Conflict));
+        assert_eq!(e.code(), ErrorCode::PortInUse);
+        let json = serde_json::to_value(&e).unwrap();
+        assert_eq!(json["details"]["port"], range.start);
+        assert_eq!(json["details"]["instance_uuid"], a.to_string());
+
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, range.end as u16)).unwrap();
+        assert!(port_manager.claim(range.end, &a).is_err());
+        drop(listener);
+        assert!(port_manager.claim(range.end, &a).is_ok());
+    }
+}
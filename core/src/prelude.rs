@@ -60,10 +60,13 @@ pub fn app_state() -> &'static AppState {
 ///
 /// Also creates the directories if they don't exist.
 pub fn init_paths(lodestone_path: PathBuf) {
-    let path_to_instances = lodestone_path.join("instances");
+    let path_to_global_settings = lodestone_path.join("global_settings.json");
+    // a path set through the daemon settings takes effect from the next start
+    let path_to_instances =
+        crate::global_settings::configured_instances_path(&path_to_global_settings)
+            .unwrap_or_else(|| lodestone_path.join("instances"));
     let path_to_binaries = lodestone_path.join("bin");
     let path_to_stores = lodestone_path.join("stores");
-    let path_to_global_settings = lodestone_path.join("global_settings.json");
     let path_to_users = lodestone_path.join("stores").join("users.json");
     let path_to_tmp = lodestone_path.join("tmp");
 
@@ -101,6 +104,7 @@ lazy_static! {
         ));
 }
 
+use crate::command::CommandInstance;
 use crate::generic::GenericInstance;
 use crate::minecraft::MinecraftInstance;
 use crate::AppState;
@@ -117,4 +121,5 @@ use crate::AppState;
 pub enum GameInstance {
     MinecraftInstance,
     GenericInstance,
+    CommandInstance,
 }
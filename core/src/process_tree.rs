@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{CpuRefreshKind, Pid, PidExt, ProcessExt, RefreshKind, System, SystemExt};
+use ts_rs::TS;
+
+use crate::traits::t_server::DiskUsage;
+
+/// Number of child processes reported in `ProcessTreeReport::top_children`
+const TOP_CHILDREN_COUNT: usize = 3;
+/// On platforms without cheap child discovery, the full process table is rescanned this often
+#[cfg(not(target_os = "linux"))]
+const FULL_RESCAN_INTERVAL: u32 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct ChildProcessReport {
+    pub pid: u32,
+    pub name: String,
+    pub memory_usage: u64,
+    pub cpu_usage: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessTreeReport {
+    /// Resident memory of the whole tree
+    pub memory_usage: u64,
+    /// CPU usage of the whole tree, normalized by the number of cpus
+    pub cpu_usage: f32,
+    pub disk_usage: DiskUsage,
+    /// Start time of the root process
+    pub start_time: u64,
+    /// Run time of the root process in seconds
+    pub uptime: u64,
+    pub process_count: u32,
+    /// Largest descendants of the root by memory
+    pub top_children: Vec<ChildProcessReport>,
+}
+
+/// Tracks the process tree rooted at a server process between samples.
+///
+/// PIDs are identified together with their start time, so a PID that got reused by an
+/// unrelated process is never attributed to the instance.
+#[derive(Debug, Default)]
+pub struct ProcessTreeTracker {
+    /// pid -> start time of every process known to be in the tree, root included
+    members: HashMap<Pid, u64>,
+    root: Option<Pid>,
+    #[cfg(not(target_os = "linux"))]
+    samples_since_rescan: u32,
+}
+
+impl ProcessTreeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sample(&mut self, sys: &mut System, root_pid: u32) -> Option<ProcessTreeReport> {
+        let root = Pid::from_u32(root_pid);
+        if !sys.refresh_process(root) {
+            self.members.clear();
+            self.root = None;
+            return None;
+        }
+        let root_start_time = sys.process(root)?.start_time();
+        if self.root != Some(root) || self.members.get(&root) != Some(&root_start_time) {
+            self.members.clear();
+            self.members.insert(root, root_start_time);
+            self.root = Some(root);
+        }
+
+        // drop members that exited or whose pid now belongs to another process
+        let mut mismatch = false;
+        self.members.retain(|pid, start_time| {
+            if *pid == root {
+                return true;
+            }
+            let alive = sys.refresh_process(*pid)
+                && sys
+                    .process(*pid)
+                    .map_or(false, |p| p.start_time() == *start_time);
+            mismatch |= !alive;
+            alive
+        });
+
+        self.discover_children(sys, root, mismatch);
+
+        let mut report = ProcessTreeReport {
+            memory_usage: 0,
+            cpu_usage: 0.0,
+            disk_usage: DiskUsage {
+                total_written_bytes: 0,
+                written_bytes: 0,
+                total_read_bytes: 0,
+                read_bytes: 0,
+            },
+            start_time: root_start_time,
+            uptime: 0,
+            process_count: 0,
+            top_children: Vec::new(),
+        };
+        let cpu_count = sys.cpus().len().max(1) as f32;
+        for pid in self.members.keys() {
+            let proc = match sys.process(*pid) {
+                Some(proc) => proc,
+                None => continue,
+            };
+            let disk_usage = proc.disk_usage();
+            report.memory_usage += proc.memory();
+            report.cpu_usage += proc.cpu_usage() / cpu_count;
+            report.disk_usage.total_written_bytes += disk_usage.total_written_bytes;
+            report.disk_usage.written_bytes += disk_usage.written_bytes;
+            report.disk_usage.total_read_bytes += disk_usage.total_read_bytes;
+            report.disk_usage.read_bytes += disk_usage.read_bytes;
+            report.process_count += 1;
+            if *pid == root {
+                report.uptime = proc.run_time();
+            } else {
+                report.top_children.push(ChildProcessReport {
+                    pid: pid.as_u32(),
+                    name: proc.name().to_string(),
+                    memory_usage: proc.memory(),
+                    cpu_usage: proc.cpu_usage() / cpu_count,
+                });
+            }
+        }
+        report
+            .top_children
+            .sort_by(|a, b| b.memory_usage.cmp(&a.memory_usage));
+        report.top_children.truncate(TOP_CHILDREN_COUNT);
+        Some(report)
+    }
+
+    fn add_member(&mut self, sys: &mut System, pid: Pid) -> bool {
+        if self.members.contains_key(&pid) || !sys.refresh_process(pid) {
+            return false;
+        }
+        match sys.process(pid) {
+            Some(proc) => {
+                self.members.insert(pid, proc.start_time());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Walks `/proc/<pid>/task/<tid>/children` from the root, which only touches processes
+    /// that are actually in the tree
+    #[cfg(target_os = "linux")]
+    fn discover_children(&mut self, sys: &mut System, root: Pid, _mismatch: bool) {
+        let mut stack = vec![root];
+        while let Some(pid) = stack.pop() {
+            let tasks = match std::fs::read_dir(format!("/proc/{}/task", pid.as_u32())) {
+                Ok(tasks) => tasks,
+                Err(_) => continue,
+            };
+            for task in tasks.filter_map(|t| t.ok()) {
+                let children = match std::fs::read_to_string(task.path().join("children")) {
+                    Ok(children) => children,
+                    Err(_) => continue,
+                };
+                for child in children
+                    .split_whitespace()
+                    .filter_map(|c| c.parse::<u32>().ok())
+                    .map(Pid::from_u32)
+                {
+                    // a child we already know about still has to be walked for grandchildren
+                    if self.add_member(sys, child) || self.members.contains_key(&child) {
+                        stack.push(child);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rescans the process table and follows parent links
+    #[cfg(not(target_os = "linux"))]
+    fn discover_children(&mut self, sys: &mut System, root: Pid, mismatch: bool) {
+        self.samples_since_rescan += 1;
+        if !mismatch && self.members.len() > 1 && self.samples_since_rescan < FULL_RESCAN_INTERVAL {
+            return;
+        }
+        self.samples_since_rescan = 0;
+        sys.refresh_processes();
+        let parents: Vec<(Pid, Pid)> = sys
+            .processes()
+            .iter()
+            .filter_map(|(pid, proc)| proc.parent().map(|parent| (*pid, parent)))
+            .collect();
+        let mut stack = vec![root];
+        while let Some(parent) = stack.pop() {
+            for (pid, _) in parents.iter().filter(|(_, p)| *p == parent) {
+                if self.add_member(sys, *pid) {
+                    stack.push(*pid);
+                }
+            }
+        }
+    }
+}
+
+/// A `System` for sampling one instance's processes. Only the CPU list is read up front, the
+/// processes are refreshed as they're sampled, which keeps restoring many instances cheap
+pub fn new_process_system() -> System {
+    System::new_with_specifics(RefreshKind::new().with_cpu(CpuRefreshKind::new()))
+}
+
+/// SIGKILLs every process in the tree rooted at `root_pid`, returning how many were signalled.
+///
+/// On unix the root's process group is killed as well, which also reaches processes a wrapper
+/// script left orphaned outside the tree
+pub fn kill_tree(sys: &mut System, root_pid: u32) -> usize {
+    let mut tracker = ProcessTreeTracker::new();
+    tracker.sample(sys, root_pid);
+    let killed = tracker
+        .members
+        .keys()
+        .filter_map(|pid| sys.process(*pid))
+        .filter(|proc| proc.kill())
+        .count();
+    #[cfg(unix)]
+    // SAFETY: killpg only sends a signal. A group with this id exists only if the root leads it,
+    // so this can't hit an unrelated group
+    unsafe {
+        libc::killpg(root_pid as libc::pid_t, libc::SIGKILL);
+    }
+    killed
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_process_tree_aggregation() {
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg("sleep 5 & sleep 5 & sh -c 'sleep 5; true' & wait")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let root_pid = child.id().unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+        let mut sys = System::new();
+        let mut tracker = ProcessTreeTracker::new();
+        let report = tracker.sample(&mut sys, root_pid).unwrap();
+        // sh, 3 sleeps and the nested sh
+        assert_eq!(report.process_count, 5);
+        assert_eq!(report.top_children.len(), TOP_CHILDREN_COUNT);
+        assert!(report.memory_usage >= report.top_children[0].memory_usage);
+        assert!(report
+            .top_children
+            .windows(2)
+            .all(|w| w[0].memory_usage >= w[1].memory_usage));
+
+        // a pid with a different start time is not attributed to the tree
+        let (pid, start_time) = tracker
+            .members
+            .iter()
+            .find(|(pid, _)| pid.as_u32() != root_pid)
+            .map(|(pid, start_time)| (*pid, *start_time))
+            .unwrap();
+        tracker.members.insert(pid, start_time + 1);
+        let report = tracker.sample(&mut sys, root_pid).unwrap();
+        assert_eq!(report.process_count, 5);
+        assert_eq!(tracker.members.get(&pid), Some(&start_time));
+
+        child.kill().await.unwrap();
+        child.wait().await.unwrap();
+        assert!(tracker.sample(&mut sys, root_pid).is_none());
+    }
+    #[tokio::test]
+    async fn test_kill_tree() {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            // the nested sh exits right away, orphaning its sleep
+            .arg("sleep 30 & echo $!; sh -c 'sleep 30 & echo $!'; wait")
+            .process_group(0)
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let root_pid = child.id().unwrap();
+        let mut stdout = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut sleeps = Vec::new();
+        for _ in 0..2 {
+            let pid: u32 = stdout.next_line().await.unwrap().unwrap().parse().unwrap();
+            sleeps.push(Pid::from_u32(pid));
+        }
+
+        let mut sys = System::new();
+        assert!(kill_tree(&mut sys, root_pid) >= 2);
+        child.wait().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        for pid in sleeps {
+            let alive = sys.refresh_process(pid)
+                && sys
+                    .process(pid)
+                    .map_or(false, |p| p.status() != sysinfo::ProcessStatus::Zombie);
+            assert!(!alive, "{pid} survived");
+        }
+    }
+}
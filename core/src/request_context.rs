@@ -0,0 +1,217 @@
+//! The HTTP request a task is serving, so logs, errors and events can be tied back to it
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{debug, info, warn};
+
+use crate::http_config::ClientAddr;
+
+/// Taken from the request if the client sent one, echoed in the response either way
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+pub struct RequestContext {
+    id: String,
+    /// Who the request authenticated as, once it did
+    user: Mutex<Option<String>>,
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<RequestContext>;
+}
+
+/// Id of the request the current task is serving
+pub fn current_request_id() -> Option<String> {
+    CURRENT.try_with(|context| context.id.clone()).ok()
+}
+
+/// Records who the request the current task is serving authenticated as
+pub fn set_user(user_name: &str) {
+    let _ = CURRENT.try_with(|context| {
+        *context
+            .user
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(user_name.to_string());
+    });
+}
+
+/// Keeps the request of the current task for `future`, for work a handler spawns that outlives
+/// it, e.g. setting up an instance
+pub fn propagate<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let context = CURRENT.try_with(Arc::clone).ok();
+    async move {
+        match context {
+            Some(context) => CURRENT.scope(context, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Ids are logged and echoed in a header, a client sending anything else gets a new one
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Assigns each request an id and logs it once answered.
+///
+/// Only the route pattern is logged, never the path itself, query or body, so setup keys and
+/// tokens in them stay out of the logs
+pub async fn log_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let method = request.method().clone();
+    // behind a trusted reverse proxy, the client it forwarded the request for
+    let client = ClientAddr::of(request.extensions(), request.headers())
+        .ip
+        .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("<unmatched>", |route| route.as_str())
+        .to_string();
+    let context = Arc::new(RequestContext {
+        id: id.clone(),
+        user: Mutex::new(None),
+    });
+    let started = Instant::now();
+    let mut response = CURRENT.scope(context.clone(), next.run(request)).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let user = context
+        .user
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+        .unwrap_or_else(|| "-".to_string());
+    let status = response.status();
+    if status.is_server_error() {
+        warn!(
+            request_id = %id, %method, %route, %user, %client, status = status.as_u16(), latency_ms,
+            "Request failed"
+        );
+    } else if status.is_client_error() {
+        info!(
+            request_id = %id, %method, %route, %user, %client, status = status.as_u16(), latency_ms,
+            "Request refused"
+        );
+    } else {
+        debug!(
+            request_id = %id, %method, %route, %user, %client, status = status.as_u16(), latency_ms,
+            "Request handled"
+        );
+    }
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use axum::{middleware::from_fn, routing::get, Json, Router};
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+    use crate::error::{Error, ErrorKind};
+    use crate::events::{CausedBy, Event, EventInner};
+
+    async fn failing() -> Result<Json<()>, Error> {
+        set_user("owner");
+        Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })
+    }
+
+    /// The request id of a progression started by a task the handler spawned
+    async fn spawned_progression() -> Json<Option<String>> {
+        let request_id = tokio::spawn(propagate(async {
+            let (event, _) =
+                Event::new_progression_event_start("Setup", None, None, CausedBy::System);
+            match event.event_inner {
+                EventInner::ProgressionEvent(progression) => progression.request_id().cloned(),
+                _ => None,
+            }
+        }))
+        .await
+        .unwrap();
+        Json(request_id)
+    }
+
+    fn serve() -> SocketAddr {
+        let app = Router::new()
+            .route("/failing", get(failing))
+            .route("/progression", get(spawned_progression))
+            .layer(from_fn(log_requests));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let addr = serve();
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{}/failing", addr))
+            .header(REQUEST_ID_HEADER, "dashboard-42")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "dashboard-42");
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["request_id"], "dashboard-42");
+
+        // a header that can't be logged safely is replaced
+        let response = client
+            .get(format!("http://{}/failing", addr))
+            .header(REQUEST_ID_HEADER, "a b")
+            .send()
+            .await
+            .unwrap();
+        let id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_ne!(id, "a b");
+        assert!(is_valid_request_id(&id));
+
+        let response = client
+            .get(format!("http://{}/progression", addr))
+            .send()
+            .await
+            .unwrap();
+        let id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let progression_request_id: Option<String> = response.json().await.unwrap();
+        assert_eq!(progression_request_id, Some(id));
+
+        assert_eq!(current_request_id(), None);
+    }
+}
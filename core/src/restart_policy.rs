@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Longest delay the exponential backoff can grow to
+pub const MAX_RESTART_BACKOFF_SECONDS: u32 = 60 * 60;
+/// A server that stays up this long gets its retry budget back
+pub const RESTART_ATTEMPTS_RESET_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS, Default)]
+#[serde(rename_all = "kebab-case")]
+#[ts(export)]
+pub enum RestartMode {
+    #[default]
+    Never,
+    /// Restart only when the server exits with a failure status without being asked to stop
+    OnCrash,
+    /// Restart whenever the server exits without being asked to stop
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    /// Consecutive restarts attempted before giving up
+    pub max_retries: u32,
+    /// Delay before the first restart, doubled on every following attempt
+    pub backoff_seconds: u32,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RestartMode::Never,
+            max_retries: 5,
+            backoff_seconds: 5,
+        }
+    }
+}
+
+/// Why the server process went away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+    /// A stop or kill was requested through lodestone
+    Requested,
+    /// The process exited on its own with a success status
+    Clean,
+    /// The process exited on its own with a failure status or was killed by a signal
+    Crashed,
+}
+
+impl RestartPolicy {
+    /// Policy equivalent to the legacy `restart_on_crash` flag
+    pub fn from_restart_on_crash(restart_on_crash: bool) -> Self {
+        Self {
+            mode: if restart_on_crash {
+                RestartMode::OnCrash
+            } else {
+                RestartMode::Never
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.mode != RestartMode::Never && self.max_retries == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Max retries must be at least 1"),
+            });
+        }
+        if self.backoff_seconds > MAX_RESTART_BACKOFF_SECONDS {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Backoff cannot be longer than {} seconds",
+                    MAX_RESTART_BACKOFF_SECONDS
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn should_restart(&self, exit: ExitKind) -> bool {
+        match (self.mode, exit) {
+            (_, ExitKind::Requested) => false,
+            (RestartMode::Never, _) => false,
+            (RestartMode::OnCrash, ExitKind::Clean) => false,
+            (RestartMode::OnCrash, ExitKind::Crashed) => true,
+            (RestartMode::Always, _) => true,
+        }
+    }
+
+    /// Delay before restart number `attempt`, starting at 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2_u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_secs(
+            (self.backoff_seconds as u64)
+                .saturating_mul(factor)
+                .min(MAX_RESTART_BACKOFF_SECONDS as u64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_policy() {
+        let policy = RestartPolicy {
+            mode: RestartMode::OnCrash,
+            max_retries: 3,
+            backoff_seconds: 10,
+        };
+        assert!(policy.should_restart(ExitKind::Crashed));
+        assert!(!policy.should_restart(ExitKind::Clean));
+        assert!(!policy.should_restart(ExitKind::Requested));
+
+        let always = RestartPolicy {
+            mode: RestartMode::Always,
+            ..policy
+        };
+        assert!(always.should_restart(ExitKind::Clean));
+        assert!(!always.should_restart(ExitKind::Requested));
+        assert!(!RestartPolicy::default().should_restart(ExitKind::Crashed));
+
+        assert_eq!(policy.backoff(1), Duration::from_secs(10));
+        assert_eq!(policy.backoff(2), Duration::from_secs(20));
+        assert_eq!(policy.backoff(4), Duration::from_secs(80));
+        assert_eq!(
+            policy.backoff(100),
+            Duration::from_secs(MAX_RESTART_BACKOFF_SECONDS as u64)
+        );
+
+        assert_eq!(
+            RestartPolicy::from_restart_on_crash(true).mode,
+            RestartMode::OnCrash
+        );
+        assert!(RestartPolicy {
+            max_retries: 0,
+            ..policy
+        }
+        .validate()
+        .is_err());
+        let parsed: RestartPolicy =
+            serde_json::from_str(r#"{"mode": "on-crash", "max_retries": 2, "backoff_seconds": 1}"#)
+                .unwrap();
+        assert_eq!(parsed.mode, RestartMode::OnCrash);
+    }
+}
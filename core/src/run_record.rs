@@ -0,0 +1,123 @@
+//! When an instance last ran and how it stopped, kept in its directory so it survives daemon
+//! restarts
+
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ts_rs::TS;
+use utoipa::ToSchema;
+
+use crate::error::Error;
+use crate::restart_policy::ExitKind;
+use crate::util::fs;
+
+pub const RUN_RECORD_FILE_NAME: &str = ".lodestone_run.json";
+
+/// Why the server last stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ExitReason {
+    /// Stopped through lodestone, or by a `stop` typed in the console
+    UserStop,
+    /// Exited with a failure status or a signal nobody sent
+    Crash,
+    /// Force killed through lodestone
+    Killed,
+    /// Stopped or killed because the daemon was shutting down
+    DaemonShutdown,
+}
+
+impl ExitReason {
+    /// A kill counts as a requested exit, so it's told apart by `killed`
+    pub fn classify(exit_kind: ExitKind, killed: bool, shutting_down: bool) -> Self {
+        match exit_kind {
+            ExitKind::Crashed => ExitReason::Crash,
+            _ if shutting_down => ExitReason::DaemonShutdown,
+            _ if killed => ExitReason::Killed,
+            // a clean exit nobody asked for is usually a `stop` from a plugin or the console
+            ExitKind::Requested | ExitKind::Clean => ExitReason::UserStop,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// Unix timestamp in seconds of when the server last got to running
+    #[serde(default)]
+    pub last_started_at: Option<i64>,
+    /// Unix timestamp in seconds
+    #[serde(default)]
+    pub last_stopped_at: Option<i64>,
+    /// `None` when killed by a signal or the status couldn't be collected
+    #[serde(default)]
+    pub last_exit_code: Option<i32>,
+    #[serde(default)]
+    pub last_exit_reason: Option<ExitReason>,
+}
+
+/// An instance that never ran, or whose record is unreadable, gets an empty one
+pub async fn read_run_record(instance_path: &Path) -> RunRecord {
+    let path = instance_path.join(RUN_RECORD_FILE_NAME);
+    match tokio::fs::read(&path).await {
+        Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable {}: {}", path.display(), e);
+            RunRecord::default()
+        }),
+        Err(_) => RunRecord::default(),
+    }
+}
+
+pub async fn write_run_record(instance_path: &Path, record: &RunRecord) -> Result<(), Error> {
+    fs::write_atomic(
+        instance_path.join(RUN_RECORD_FILE_NAME),
+        serde_json::to_vec_pretty(record).context("Failed to serialize the run record")?,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            ExitReason::classify(ExitKind::Crashed, false, false),
+            ExitReason::Crash
+        );
+        assert_eq!(
+            ExitReason::classify(ExitKind::Requested, true, false),
+            ExitReason::Killed
+        );
+        assert_eq!(
+            ExitReason::classify(ExitKind::Requested, true, true),
+            ExitReason::DaemonShutdown
+        );
+        assert_eq!(
+            ExitReason::classify(ExitKind::Clean, false, false),
+            ExitReason::UserStop
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_record_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_run_record(dir.path()).await, RunRecord::default());
+        let record = RunRecord {
+            last_started_at: Some(1_700_000_000),
+            last_stopped_at: Some(1_700_007_200),
+            last_exit_code: Some(1),
+            last_exit_reason: Some(ExitReason::Crash),
+        };
+        write_run_record(dir.path(), &record).await.unwrap();
+        assert_eq!(read_run_record(dir.path()).await, record);
+
+        tokio::fs::write(dir.path().join(RUN_RECORD_FILE_NAME), "{")
+            .await
+            .unwrap();
+        assert_eq!(read_run_record(dir.path()).await, RunRecord::default());
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::auth::user::{User, UserAction, UsersManager};
+use crate::auth::user_id::UserId;
+use crate::capacity::Admission;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event};
+use crate::global_settings::GlobalSettings;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_macro::TMacro;
+use crate::traits::t_server::TServer;
+use crate::traits::GameInstance;
+use crate::types::{InstanceUuid, Snowflake};
+
+const SCHEDULER_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parses a cron expression, evaluated in UTC.
+///
+/// Accepts the standard 5 field crontab syntax as well as a leading seconds field
+pub fn parse_cron(expression: &str) -> Result<cron::Schedule, Error> {
+    // the cron crate wants a seconds field, crontab doesn't have one
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression.trim())
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&expression).map_err(|e| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Invalid cron expression \"{}\": {}", expression, e),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum ScheduledAction {
+    Start,
+    Stop,
+    Restart,
+    Command {
+        command: String,
+    },
+    Macro {
+        name: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+impl ScheduledAction {
+    /// Lifecycle actions on the same instance are never run concurrently
+    fn is_lifecycle(&self) -> bool {
+        matches!(
+            self,
+            ScheduledAction::Start | ScheduledAction::Stop | ScheduledAction::Restart
+        )
+    }
+
+    /// Whether `user` may do themselves what the task does, checked when the task is saved and
+    /// again every time it fires
+    pub fn check_allowed(
+        &self,
+        user: &User,
+        instance_uuid: &InstanceUuid,
+        safe_mode: bool,
+    ) -> Result<(), Error> {
+        let uuid = instance_uuid.clone();
+        match self {
+            ScheduledAction::Start => user.try_action(&UserAction::StartInstance(uuid), safe_mode),
+            ScheduledAction::Stop => user.try_action(&UserAction::StopInstance(uuid), safe_mode),
+            ScheduledAction::Restart => {
+                user.try_action(&UserAction::StopInstance(uuid.clone()), safe_mode)?;
+                user.try_action(&UserAction::StartInstance(uuid), safe_mode)
+            }
+            ScheduledAction::Command { command } => {
+                user.try_action(&UserAction::AccessConsole(uuid), safe_mode)?;
+                user.check_console_command(instance_uuid, command)
+            }
+            ScheduledAction::Macro { .. } => {
+                user.try_action(&UserAction::AccessMacro(Some(uuid)), safe_mode)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ScheduledTaskConfig {
+    pub name: String,
+    pub cron: String,
+    pub action: ScheduledAction,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ScheduledTaskConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Task name cannot be empty"),
+            });
+        }
+        if let ScheduledAction::Command { command } = &self.action {
+            if command.trim().is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Command cannot be empty"),
+                });
+            }
+        }
+        parse_cron(&self.cron).map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ScheduledTask {
+    pub id: Snowflake,
+    #[serde(flatten)]
+    pub config: ScheduledTaskConfig,
+    /// Who last saved the task, it fires with their permissions
+    #[serde(default)]
+    pub created_by: Option<UserId>,
+    /// Unix timestamp in milliseconds of the last run
+    pub last_run: Option<i64>,
+    /// Error of the last run, if it failed
+    pub last_error: Option<String>,
+}
+
+impl ScheduledTask {
+    /// Whether the task fires in `(after, until]`
+    pub fn is_due(&self, after: DateTime<Utc>, until: DateTime<Utc>) -> bool {
+        self.config.enabled
+            && parse_cron(&self.config.cron)
+                .ok()
+                .and_then(|schedule| schedule.after(&after).next())
+                .map_or(false, |next| next <= until)
+    }
+
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        if !self.config.enabled {
+            return None;
+        }
+        parse_cron(&self.config.cron)
+            .ok()?
+            .after(&Utc::now())
+            .next()
+    }
+}
+
+/// Runs per-instance scheduled tasks inside the daemon.
+///
+/// Tasks are persisted in a single store shared by all instances
+#[derive(Clone)]
+pub struct Scheduler {
+    tasks: Arc<Mutex<HashMap<InstanceUuid, Vec<ScheduledTask>>>>,
+    /// Instances with a lifecycle action in flight, used to coalesce overlapping restarts
+    in_flight: Arc<Mutex<HashSet<InstanceUuid>>>,
+    path_to_store: PathBuf,
+}
+
+impl Scheduler {
+    pub async fn new(path_to_store: PathBuf) -> Result<Self, Error> {
+        let tasks = match tokio::fs::read(&path_to_store).await {
+            Ok(data) if !data.is_empty() => serde_json::from_slice(&data).context(format!(
+                "Failed to parse scheduled tasks at {}",
+                path_to_store.display()
+            ))?,
+            _ => HashMap::new(),
+        };
+        Ok(Self {
+            tasks: Arc::new(Mutex::new(tasks)),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            path_to_store,
+        })
+    }
+
+    async fn write_to_file(
+        &self,
+        tasks: &HashMap<InstanceUuid, Vec<ScheduledTask>>,
+    ) -> Result<(), Error> {
+        tokio::fs::write(
+            &self.path_to_store,
+            serde_json::to_string_pretty(tasks).context("Failed to serialize scheduled tasks")?,
+        )
+        .await
+        .context(format!(
+            "Failed to write scheduled tasks to {}",
+            self.path_to_store.display()
+        ))?;
+        Ok(())
+    }
+
+    pub async fn list(&self, instance_uuid: &InstanceUuid) -> Vec<ScheduledTask> {
+        self.tasks
+            .lock()
+            .await
+            .get(instance_uuid)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn get(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: &Snowflake,
+    ) -> Result<ScheduledTask, Error> {
+        self.tasks
+            .lock()
+            .await
+            .get(instance_uuid)
+            .and_then(|tasks| tasks.iter().find(|t| t.id == *id))
+            .cloned()
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Task not found"),
+            })
+    }
+
+    pub async fn create(
+        &self,
+        instance_uuid: &InstanceUuid,
+        config: ScheduledTaskConfig,
+        created_by: UserId,
+    ) -> Result<ScheduledTask, Error> {
+        config.validate()?;
+        let task = ScheduledTask {
+            id: Snowflake::new(),
+            config,
+            created_by: Some(created_by),
+            last_run: None,
+            last_error: None,
+        };
+        let mut tasks = self.tasks.lock().await;
+        tasks
+            .entry(instance_uuid.clone())
+            .or_default()
+            .push(task.clone());
+        self.write_to_file(&tasks).await?;
+        Ok(task)
+    }
+
+    pub async fn update(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: &Snowflake,
+        config: ScheduledTaskConfig,
+        created_by: UserId,
+    ) -> Result<ScheduledTask, Error> {
+        config.validate()?;
+        let mut tasks = self.tasks.lock().await;
+        let task = tasks
+            .get_mut(instance_uuid)
+            .and_then(|tasks| tasks.iter_mut().find(|t| t.id == *id))
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Task not found"),
+            })?;
+        task.config = config;
+        task.created_by = Some(created_by);
+        let task = task.clone();
+        self.write_to_file(&tasks).await?;
+        Ok(task)
+    }
+
+    pub async fn delete(&self, instance_uuid: &InstanceUuid, id: &Snowflake) -> Result<(), Error> {
+        let mut tasks = self.tasks.lock().await;
+        let instance_tasks = tasks.get_mut(instance_uuid).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Task not found"),
+        })?;
+        let len = instance_tasks.len();
+        instance_tasks.retain(|t| t.id != *id);
+        if instance_tasks.len() == len {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Task not found"),
+            });
+        }
+        self.write_to_file(&tasks).await
+    }
+
+    /// Drops every task of an instance, called when the instance is deleted
+    pub async fn remove_instance(&self, instance_uuid: &InstanceUuid) -> Result<(), Error> {
+        let mut tasks = self.tasks.lock().await;
+        if tasks.remove(instance_uuid).is_some() {
+            self.write_to_file(&tasks).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks a lifecycle action as in flight, returns false if one already is
+    async fn try_begin_lifecycle(&self, instance_uuid: &InstanceUuid) -> bool {
+        self.in_flight.lock().await.insert(instance_uuid.clone())
+    }
+
+    async fn end_lifecycle(&self, instance_uuid: &InstanceUuid) {
+        self.in_flight.lock().await.remove(instance_uuid);
+    }
+
+    async fn record_run(
+        &self,
+        instance_uuid: &InstanceUuid,
+        id: &Snowflake,
+        result: &Result<(), Error>,
+    ) {
+        let mut tasks = self.tasks.lock().await;
+        if let Some(task) = tasks
+            .get_mut(instance_uuid)
+            .and_then(|tasks| tasks.iter_mut().find(|t| t.id == *id))
+        {
+            task.last_run = Some(Utc::now().timestamp_millis());
+            task.last_error = result.as_ref().err().map(|e| e.to_string());
+        }
+        if let Err(e) = self.write_to_file(&tasks).await {
+            error!("Failed to persist scheduled task run: {}", e);
+        }
+    }
+
+    /// Fires due tasks until the daemon shuts down
+    pub async fn run(
+        self,
+        instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+        admission: Admission,
+        event_broadcaster: EventBroadcaster,
+        users_manager: Arc<RwLock<UsersManager>>,
+        global_settings: Arc<Mutex<GlobalSettings>>,
+    ) {
+        let mut interval = tokio::time::interval(SCHEDULER_TICK_INTERVAL);
+        let mut last_tick = Utc::now();
+        loop {
+            interval.tick().await;
+            let now = Utc::now();
+            let mut due = Vec::new();
+            for (instance_uuid, tasks) in self.tasks.lock().await.iter() {
+                // an instance that failed to load keeps its tasks for when it loads again, a
+                // deleted one already had them removed
+                if !instances.contains_key(instance_uuid) {
+                    continue;
+                }
+                due.extend(
+                    tasks
+                        .iter()
+                        .filter(|t| t.is_due(last_tick, now))
+                        .map(|t| (instance_uuid.clone(), t.clone())),
+                );
+            }
+            last_tick = now;
+            for (instance_uuid, task) in due {
+                let instance = match instances.get(&instance_uuid) {
+                    Some(instance) => instance.value().clone(),
+                    None => continue,
+                };
+                tokio::spawn(self.clone().fire(
+                    instance_uuid,
+                    instance,
+                    task,
+                    admission.clone(),
+                    event_broadcaster.clone(),
+                    users_manager.clone(),
+                    global_settings.clone(),
+                ));
+            }
+        }
+    }
+
+    async fn fire(
+        self,
+        instance_uuid: InstanceUuid,
+        instance: GameInstance,
+        task: ScheduledTask,
+        admission: Admission,
+        event_broadcaster: EventBroadcaster,
+        users_manager: Arc<RwLock<UsersManager>>,
+        global_settings: Arc<Mutex<GlobalSettings>>,
+    ) {
+        let lifecycle = task.config.action.is_lifecycle();
+        if lifecycle && !self.try_begin_lifecycle(&instance_uuid).await {
+            info!(
+                "Skipping scheduled task \"{}\" on {}, another lifecycle action is still running",
+                task.config.name, instance_uuid
+            );
+            return;
+        }
+        let caused_by = CausedBy::Schedule {
+            task_id: task.id.to_string(),
+        };
+        let (progression_start, event_id) = Event::new_progression_event_start(
+            format!(
+                "Running scheduled task \"{}\" on {}",
+                task.config.name,
+                instance.name().await
+            ),
+            None,
+            None,
+            caused_by.clone(),
+        );
+        event_broadcaster.send(progression_start);
+
+        let creator = match &task.created_by {
+            Some(uid) => users_manager.read().await.get_user(uid),
+            None => None,
+        };
+        let allowed = match creator {
+            Some(creator) => {
+                let safe_mode = global_settings.lock().await.safe_mode();
+                task.config
+                    .action
+                    .check_allowed(&creator, &instance_uuid, safe_mode)
+            }
+            None => Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!(
+                    "The user who saved this task no longer exists, save it again to run it as you"
+                ),
+            }),
+        };
+        let result = match (allowed, &task.config.action) {
+            (Err(e), _) => Err(e),
+            (Ok(()), ScheduledAction::Start) => admission.start(&instance, caused_by, true).await,
+            (Ok(()), ScheduledAction::Stop) => instance.stop(caused_by, true).await,
+            (Ok(()), ScheduledAction::Restart) => instance.restart(caused_by, true).await,
+            (Ok(()), ScheduledAction::Command { command }) => {
+                instance.send_command(command, caused_by).await
+            }
+            (Ok(()), ScheduledAction::Macro { name, args }) => instance
+                .run_macro(name, args.clone(), None, caused_by)
+                .await
+                .map(|_| ()),
+        };
+        if lifecycle {
+            self.end_lifecycle(&instance_uuid).await;
+        }
+
+        match &result {
+            Ok(_) => event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(format!("Scheduled task \"{}\" completed", task.config.name)),
+                None,
+            )),
+            Err(e) => {
+                warn!(
+                    "Scheduled task \"{}\" on {} failed: {}",
+                    task.config.name, instance_uuid, e
+                );
+                event_broadcaster.send(Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(format!(
+                        "Scheduled task \"{}\" failed: {}",
+                        task.config.name, e
+                    )),
+                    None,
+                ));
+            }
+        }
+        self.record_run(&instance_uuid, &task.id, &result).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::permission::{CommandPolicy, UserPermission};
+    use crate::error::ErrorCode;
+
+    fn restart_config(cron: &str) -> ScheduledTaskConfig {
+        ScheduledTaskConfig {
+            name: "Nightly restart".to_string(),
+            cron: cron.to_string(),
+            action: ScheduledAction::Restart,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_task_is_due() {
+        let task = ScheduledTask {
+            id: Snowflake::new(),
+            config: restart_config("0 4 * * *"),
+            created_by: None,
+            last_run: None,
+            last_error: None,
+        };
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        assert!(task.is_due(at("2023-01-01T03:59:59Z"), at("2023-01-01T04:00:00Z")));
+        assert!(!task.is_due(at("2023-01-01T04:00:00Z"), at("2023-01-01T04:00:01Z")));
+        assert!(!task.is_due(at("2023-01-01T03:00:00Z"), at("2023-01-01T03:59:59Z")));
+
+        let mut disabled = task;
+        disabled.config.enabled = false;
+        assert!(!disabled.is_due(at("2023-01-01T03:59:59Z"), at("2023-01-01T04:00:00Z")));
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_store() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("scheduled_tasks.json");
+        let instance_uuid = InstanceUuid::from("INSTANCE_test".to_string());
+        let uid = UserId::default();
+
+        let scheduler = Scheduler::new(path.clone()).await.unwrap();
+        assert!(scheduler
+            .create(&instance_uuid, restart_config("not a cron"), uid.clone())
+            .await
+            .is_err());
+        let task = scheduler
+            .create(&instance_uuid, restart_config("0 4 * * *"), uid.clone())
+            .await
+            .unwrap();
+        let updated = scheduler
+            .update(
+                &instance_uuid,
+                &task.id,
+                restart_config("30 4 * * *"),
+                uid.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated.config.cron, "30 4 * * *");
+        assert_eq!(updated.created_by, Some(uid));
+
+        // tasks survive a daemon restart
+        let scheduler = Scheduler::new(path.clone()).await.unwrap();
+        assert_eq!(scheduler.list(&instance_uuid).await, vec![updated.clone()]);
+
+        scheduler.remove_instance(&instance_uuid).await.unwrap();
+        assert!(scheduler.list(&instance_uuid).await.is_empty());
+        assert!(scheduler.get(&instance_uuid, &updated.id).await.is_err());
+        assert!(scheduler.delete(&instance_uuid, &updated.id).await.is_err());
+    }
+
+    #[test]
+    fn test_action_needs_permission() {
+        let uuid = InstanceUuid::from("INSTANCE_test".to_string());
+        let mut permissions = UserPermission::default();
+        permissions.can_access_instance_setting.insert(uuid.clone());
+        let mut user = User::new("test".to_string(), "test", false, false, permissions);
+        let command = ScheduledAction::Command {
+            command: "op test".to_string(),
+        };
+        let macro_action = ScheduledAction::Macro {
+            name: "test".to_string(),
+            args: Vec::new(),
+        };
+
+        // settings access alone doesn't let a task do anything
+        for action in [
+            &ScheduledAction::Start,
+            &ScheduledAction::Stop,
+            &ScheduledAction::Restart,
+            &command,
+            &macro_action,
+        ] {
+            let error = action.check_allowed(&user, &uuid, false).unwrap_err();
+            assert!(matches!(error.kind, ErrorKind::PermissionDenied));
+        }
+
+        user.permissions.can_start_instance.insert(uuid.clone());
+        assert!(ScheduledAction::Start
+            .check_allowed(&user, &uuid, false)
+            .is_ok());
+        assert!(ScheduledAction::Restart
+            .check_allowed(&user, &uuid, false)
+            .is_err());
+        user.permissions.can_stop_instance.insert(uuid.clone());
+        assert!(ScheduledAction::Restart
+            .check_allowed(&user, &uuid, false)
+            .is_ok());
+
+        // the console command policy applies to scheduled commands too
+        user.permissions
+            .can_access_instance_console
+            .insert(uuid.clone());
+        user.permissions
+            .command_policies
+            .insert(uuid.clone(), CommandPolicy::Allow(vec!["say".to_string()]));
+        let error = command.check_allowed(&user, &uuid, false).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::CommandNotAllowed);
+        let say = ScheduledAction::Command {
+            command: "say hi".to_string(),
+        };
+        assert!(say.check_allowed(&user, &uuid, false).is_ok());
+
+        user.permissions
+            .can_access_instance_macro
+            .insert(uuid.clone());
+        assert!(macro_action.check_allowed(&user, &uuid, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_actions_coalesce() {
+        let temp = tempfile::tempdir().unwrap();
+        let scheduler = Scheduler::new(temp.path().join("scheduled_tasks.json"))
+            .await
+            .unwrap();
+        let instance_uuid = InstanceUuid::from("INSTANCE_test".to_string());
+        assert!(scheduler.try_begin_lifecycle(&instance_uuid).await);
+        assert!(!scheduler.try_begin_lifecycle(&instance_uuid).await);
+        scheduler.end_lifecycle(&instance_uuid).await;
+        assert!(scheduler.try_begin_lifecycle(&instance_uuid).await);
+    }
+}
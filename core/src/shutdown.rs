@@ -0,0 +1,172 @@
+//! Shutting the daemon down without taking running servers down mid-save
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, PidExt, System, SystemExt};
+use tracing::{error, info, warn};
+
+use crate::error::Error;
+use crate::events::CausedBy;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+/// How long running instances get to stop before they are killed
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 60;
+/// Instances left running by the last daemon, in the data directory
+const DETACHED_INSTANCES_FILE: &str = "detached_instances.json";
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the daemon is stopping its instances to exit, so their exits aren't taken for crashes
+/// or user stops
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// An instance still running when the daemon exited without stopping it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DetachedInstance {
+    pub uuid: InstanceUuid,
+    pub name: String,
+    pub pid: u32,
+}
+
+fn path_to_detached_instances(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(DETACHED_INSTANCES_FILE)
+}
+
+async fn stop_instance(instance: GameInstance, timeout: Duration) {
+    let name = instance.name().await;
+    match instance.state().await {
+        // a start can't be interrupted gracefully
+        State::Starting => {
+            info!("Killing instance {}, it is still starting", name);
+        }
+        // stopping resumes a suspended server first
+        State::Running | State::Suspended => {
+            match tokio::time::timeout(timeout, instance.stop(CausedBy::System, true)).await {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => warn!("Failed to stop instance {}, killing it: {}", name, e),
+                Err(_) => warn!(
+                    "Instance {} didn't stop within {} seconds, killing it",
+                    name,
+                    timeout.as_secs()
+                ),
+            }
+        }
+        State::Error | State::Stopped | State::Stopping => return,
+    }
+    if let Err(e) = instance.kill(CausedBy::System).await {
+        error!(
+            "Failed to kill instance {}: {}. Instance may need manual cleanup",
+            name, e
+        );
+    }
+}
+
+/// Stops every running instance at once, killing those that don't stop within `timeout`
+pub async fn stop_instances(instances: Vec<GameInstance>, timeout: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    let handles: Vec<_> = instances
+        .into_iter()
+        .map(|instance| tokio::spawn(stop_instance(instance, timeout)))
+        .collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+/// Leaves the running instances running and records their processes for the next start
+pub async fn detach_instances(
+    instances: Vec<GameInstance>,
+    lodestone_path: &Path,
+) -> Result<(), Error> {
+    let mut detached = Vec::new();
+    for instance in instances {
+        if let Some(pid) = instance.pid().await {
+            detached.push(DetachedInstance {
+                uuid: instance.uuid().await,
+                name: instance.name().await,
+                pid,
+            });
+        }
+    }
+    for instance in &detached {
+        info!(
+            "Leaving instance {} running as process {}",
+            instance.name, instance.pid
+        );
+    }
+    let path = path_to_detached_instances(lodestone_path);
+    if detached.is_empty() {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Ok(());
+    }
+    tokio::fs::write(
+        &path,
+        serde_json::to_vec_pretty(&detached).context("Failed to serialize detached instances")?,
+    )
+    .await
+    .context(format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// The instances the last daemon left running whose process is still alive.
+///
+/// Their console can't be reattached, the record is consumed so each is reported once
+pub async fn take_detached_instances(lodestone_path: &Path) -> Vec<DetachedInstance> {
+    let path = path_to_detached_instances(lodestone_path);
+    let content = match tokio::fs::read(&path).await {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    let detached: Vec<DetachedInstance> = match serde_json::from_slice(&content) {
+        Ok(detached) => detached,
+        Err(e) => {
+            warn!("Ignoring {}, it can't be parsed: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    let mut sys = System::new();
+    detached
+        .into_iter()
+        .filter(|instance| sys.refresh_process(Pid::from_u32(instance.pid)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_take_detached_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(take_detached_instances(dir.path()).await.is_empty());
+
+        let alive = DetachedInstance {
+            uuid: InstanceUuid::default(),
+            name: "survival".to_string(),
+            pid: std::process::id(),
+        };
+        let gone = DetachedInstance {
+            uuid: InstanceUuid::default(),
+            name: "creative".to_string(),
+            pid: u32::MAX,
+        };
+        std::fs::write(
+            path_to_detached_instances(dir.path()),
+            serde_json::to_vec(&vec![alive.clone(), gone]).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(take_detached_instances(dir.path()).await, vec![alive]);
+        // reported once
+        assert!(take_detached_instances(dir.path()).await.is_empty());
+    }
+}
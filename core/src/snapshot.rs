@@ -0,0 +1,310 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock, Weak};
+
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, InstanceEventInner};
+use crate::run_record::RunRecord;
+use crate::traits::t_configurable::Game;
+use crate::traits::t_player::Player;
+use crate::traits::t_server::State;
+use crate::types::InstanceUuid;
+
+/// A value readers copy out without ever waiting on the operation that updates it.
+///
+/// Writers replace the value as a whole, the lock is only held to clone or swap an `Arc`
+pub struct Snapshot<T> {
+    inner: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Clone for Snapshot<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Snapshot<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(value))),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(value);
+    }
+
+    /// Applies `f` to a copy of the current value and publishes the result
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let mut guard = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(Arc::make_mut(&mut guard));
+    }
+
+    fn downgrade(&self) -> Weak<RwLock<Arc<T>>> {
+        Arc::downgrade(&self.inner)
+    }
+}
+
+/// The informational part of an instance, what the dashboard polls
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceSnapshot {
+    pub name: String,
+    pub game_type: Game,
+    pub description: String,
+    pub version: String,
+    pub port: u32,
+    pub auto_start: bool,
+    pub restart_on_crash: bool,
+    pub state: State,
+    pub player_count: Option<u32>,
+    pub max_player_count: Option<u32>,
+    pub player_list: Option<HashSet<Player>>,
+    /// Program and arguments of the last launch, for debugging
+    pub launch_command: Option<Vec<String>>,
+    /// `None` for games without an EULA
+    pub eula_accepted: Option<bool>,
+    /// When the server last got to running since the daemon started
+    pub last_started: Option<i64>,
+    /// Persisted for the games that keep one, survives daemon restarts
+    pub last_run: RunRecord,
+    /// The process is alive but the server stopped responding
+    pub degraded: bool,
+}
+
+impl InstanceSnapshot {
+    /// Seconds since the server got to running, only while it runs
+    pub fn uptime_seconds(&self) -> Option<i64> {
+        match self.state {
+            State::Running | State::Suspended => self
+                .last_run
+                .last_started_at
+                .or(self.last_started)
+                .map(|started_at| (chrono::Utc::now().timestamp() - started_at).max(0)),
+            _ => None,
+        }
+    }
+
+    fn apply(&mut self, event: &InstanceEventInner) {
+        match event {
+            InstanceEventInner::StateTransition { to } => {
+                let resumed = self.state == State::Suspended;
+                self.state = *to;
+                self.degraded = false;
+                // the server doesn't get to running without its EULA accepted
+                if *to == State::Running && self.eula_accepted == Some(false) {
+                    self.eula_accepted = Some(true);
+                }
+                if *to == State::Running && !resumed {
+                    self.last_started = Some(chrono::Utc::now().timestamp());
+                }
+                if *to == State::Stopped {
+                    self.player_count = self.player_count.map(|_| 0);
+                    self.player_list = self.player_list.as_ref().map(|_| HashSet::new());
+                }
+            }
+            InstanceEventInner::EulaRequired => {
+                self.eula_accepted = Some(false);
+            }
+            InstanceEventInner::PlayerChange { player_list, .. } => {
+                self.player_count = Some(player_list.len() as u32);
+                self.player_list = Some(player_list.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Keeps the state and players of `snapshot` in sync with the instance's events.
+///
+/// The task ends once every copy of the snapshot is dropped
+pub fn watch_instance_events(
+    snapshot: &Snapshot<InstanceSnapshot>,
+    instance_uuid: InstanceUuid,
+    event_broadcaster: &EventBroadcaster,
+) {
+    let snapshot = snapshot.downgrade();
+    let mut rx = event_broadcaster.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let snapshot = match snapshot.upgrade() {
+                Some(inner) => Snapshot { inner },
+                None => return,
+            };
+            if let EventInner::InstanceEvent(instance_event) = &event.event_inner {
+                if instance_event.instance_uuid == instance_uuid {
+                    snapshot.update(|s| s.apply(&instance_event.instance_event_inner));
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::events::Event;
+    use crate::traits::t_configurable::MinecraftVariant;
+
+    fn instance_snapshot(state: State) -> InstanceSnapshot {
+        InstanceSnapshot {
+            name: "test".to_string(),
+            game_type: Game::MinecraftJava {
+                variant: MinecraftVariant::Vanilla,
+            },
+            description: "".to_string(),
+            version: "1.20.1".to_string(),
+            port: 25565,
+            auto_start: false,
+            restart_on_crash: false,
+            state,
+            player_count: Some(0),
+            max_player_count: Some(20),
+            player_list: Some(HashSet::new()),
+            launch_command: None,
+            eula_accepted: Some(true),
+            last_started: None,
+            last_run: RunRecord::default(),
+            degraded: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_reads_do_not_wait_on_start() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let uuid = InstanceUuid::default();
+        let snapshot = Snapshot::new(instance_snapshot(State::Stopped));
+        watch_instance_events(&snapshot, uuid.clone(), &event_broadcaster);
+
+        // a slow start holding the operational lock the whole time
+        let operation_lock = Arc::new(tokio::sync::Mutex::new(()));
+        let start = tokio::spawn({
+            let operation_lock = operation_lock.clone();
+            let event_broadcaster = event_broadcaster.clone();
+            let uuid = uuid.clone();
+            async move {
+                let _guard = operation_lock.lock().await;
+                event_broadcaster.send(Event::new_instance_state_transition(
+                    uuid.clone(),
+                    "test".to_string(),
+                    State::Starting,
+                ));
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                event_broadcaster.send(Event::new_instance_state_transition(
+                    uuid,
+                    "test".to_string(),
+                    State::Running,
+                ));
+            }
+        });
+
+        let mut seen_starting = false;
+        while !start.is_finished() || snapshot.load().state != State::Running {
+            let begin = Instant::now();
+            let state = snapshot.load().state;
+            assert!(begin.elapsed() < Duration::from_millis(10));
+            seen_starting |= state == State::Starting;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(seen_starting);
+        assert_eq!(snapshot.load().name, "test");
+    }
+
+    #[tokio::test]
+    async fn test_slow_start_settles() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(64);
+        let uuid = InstanceUuid::default();
+        let snapshot = Snapshot::new(instance_snapshot(State::Stopped));
+        watch_instance_events(&snapshot, uuid.clone(), &event_broadcaster);
+        let mut events = event_broadcaster.subscribe();
+        // stands in for how long a start used to be waited on
+        let wait_timeout = Duration::from_millis(100);
+
+        let start = tokio::spawn({
+            let event_broadcaster = event_broadcaster.clone();
+            let uuid = uuid.clone();
+            async move {
+                event_broadcaster.send(Event::new_instance_state_transition(
+                    uuid.clone(),
+                    "test".to_string(),
+                    State::Starting,
+                ));
+                tokio::time::sleep(wait_timeout * 5).await;
+                event_broadcaster.send(Event::new_instance_state_transition(
+                    uuid,
+                    "test".to_string(),
+                    State::Running,
+                ));
+            }
+        });
+
+        // a waiter giving up early doesn't disturb the start
+        let waited = tokio::time::timeout(wait_timeout, async {
+            while snapshot.load().state != State::Running {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await;
+        assert!(waited.is_err());
+        assert_eq!(snapshot.load().state, State::Starting);
+        assert_eq!(snapshot.load().uptime_seconds(), None);
+
+        start.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while snapshot.load().state != State::Running {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let settled = snapshot.load();
+        assert!(settled.last_started.is_some());
+        assert!(settled.uptime_seconds().is_some());
+
+        // each transition was announced once, in order
+        let mut transitions = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let EventInner::InstanceEvent(instance_event) = event.event_inner {
+                if let InstanceEventInner::StateTransition { to } =
+                    instance_event.instance_event_inner
+                {
+                    transitions.push(to);
+                }
+            }
+        }
+        assert_eq!(transitions, vec![State::Starting, State::Running]);
+    }
+
+    #[test]
+    fn test_eula_required() {
+        let mut snapshot = instance_snapshot(State::Starting);
+        snapshot.apply(&InstanceEventInner::StateTransition { to: State::Stopped });
+        snapshot.apply(&InstanceEventInner::EulaRequired);
+        assert_eq!(snapshot.eula_accepted, Some(false));
+        snapshot.apply(&InstanceEventInner::StateTransition { to: State::Running });
+        assert_eq!(snapshot.eula_accepted, Some(true));
+        assert!(snapshot.last_started.is_some());
+    }
+}
@@ -0,0 +1,85 @@
+//! How long each phase of startup took, logged once serving starts and shown in `GET /info` so a
+//! slow start can be traced to its cause
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use ts_rs::TS;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct StartupPhase {
+    pub name: String,
+    pub millis: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, TS)]
+#[ts(export)]
+pub struct StartupTimings {
+    /// In the order they ran
+    pub phases: Vec<StartupPhase>,
+    pub total_millis: u64,
+}
+
+impl StartupTimings {
+    pub fn log(&self) {
+        info!("Startup took {} ms", self.total_millis);
+        for phase in &self.phases {
+            info!("  {}: {} ms", phase.name, phase.millis);
+        }
+    }
+}
+
+pub struct StartupTimer {
+    started: Instant,
+    phase_started: Instant,
+    phases: Vec<StartupPhase>,
+}
+
+impl StartupTimer {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            phase_started: now,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Ends the phase running since the last one ended
+    pub fn phase(&mut self, name: &str) {
+        let now = Instant::now();
+        self.phases.push(StartupPhase {
+            name: name.to_string(),
+            millis: now.duration_since(self.phase_started).as_millis() as u64,
+        });
+        self.phase_started = now;
+    }
+
+    pub fn finish(self) -> StartupTimings {
+        StartupTimings {
+            phases: self.phases,
+            total_millis: self.started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases_add_up() {
+        let mut timer = StartupTimer::new();
+        timer.phase("settings");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        timer.phase("instances");
+        let timings = timer.finish();
+        let names: Vec<&str> = timings.phases.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["settings", "instances"]);
+        assert!(timings.phases[1].millis >= 5);
+        let sum: u64 = timings.phases.iter().map(|p| p.millis).sum();
+        assert!(sum <= timings.total_millis);
+    }
+}
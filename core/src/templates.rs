@@ -0,0 +1,428 @@
+//! Instance templates: the settings and a chosen set of files of a Minecraft instance, applied
+//! to new instances once their flavour is set up.
+//!
+//! Each template is a directory of the store holding `template.json` and a `files` directory
+//! laid out like the instance it was captured from
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::backups::BACKUPS_DIR_NAME;
+use crate::error::{Error, ErrorKind};
+use crate::implementations::minecraft::FlavourKind;
+use crate::restart_policy::RestartPolicy;
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+use crate::trash::TRASH_DIR_NAME;
+use crate::types::Snowflake;
+use crate::util::{fs, strip_extended_length_prefix, walk_dir, MAX_TRAVERSAL_DEPTH};
+
+const TEMPLATE_FILE_NAME: &str = "template.json";
+const FILES_DIR_NAME: &str = "files";
+/// Never captured: Lodestone's own state, and server.properties which is captured as settings
+/// so the new instance keeps its own port
+const EXCLUDED_FILES: [&str; 5] = [
+    ".lodestone_config",
+    ".lodestone_minecraft_config.json",
+    "server.properties",
+    BACKUPS_DIR_NAME,
+    TRASH_DIR_NAME,
+];
+
+/// Settings a template applies. The environment and hooks are left out, their values are often
+/// secrets and every template is visible to whoever can create instances
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct TemplateSettings {
+    /// Values of the command line section, without `java_cmd` since runtimes differ per machine
+    pub cmd_args: IndexMap<String, ConfigurableValue>,
+    pub restart_policy: RestartPolicy,
+    pub auto_suspend_minutes: Option<u32>,
+    /// server.properties without the ports, the new instance gets ports of its own
+    pub game_settings: IndexMap<String, ConfigurableValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Template {
+    pub id: Snowflake,
+    pub name: String,
+    pub description: Option<String>,
+    /// What the template was made for, applying it elsewhere only warns
+    pub flavour: FlavourKind,
+    pub version: String,
+    pub settings: TemplateSettings,
+    /// Files and directories copied into new instances, relative to the instance
+    pub files: Vec<String>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct TemplateConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Instance the settings and files are taken from, it must be stopped
+    pub instance_uuid: crate::types::InstanceUuid,
+    /// Files or directories of the instance to copy, e.g. `plugins` or `bukkit.yml`
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+fn template_not_found() -> Error {
+    Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Template not found"),
+    }
+}
+
+/// `path` as `/` separated components, failing for anything that could leave the instance or
+/// isn't allowed in a template
+fn normalize_template_path(path: &str) -> Result<String, Error> {
+    let mut components = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(component) => components.push(component.to_string_lossy()),
+            Component::CurDir => {}
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{} must be a path inside the instance", path),
+                })
+            }
+        }
+    }
+    match components.first() {
+        None => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("A template can't hold the whole instance, pick files or directories"),
+        }),
+        Some(first) if EXCLUDED_FILES.contains(&first.as_ref()) => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} can't be part of a template", first),
+        }),
+        Some(_) => Ok(components.join("/")),
+    }
+}
+
+/// Copies the file or directory `relative` from `from` to the same place under `to`, symlinks
+/// are skipped so nothing outside `from` is copied
+fn copy_into(from: &Path, to: &Path, relative: &str) -> Result<(), Error> {
+    let source = from.join(relative);
+    for entry in walk_dir(&source, MAX_TRAVERSAL_DEPTH) {
+        let entry = entry?;
+        let path = strip_extended_length_prefix(entry.path());
+        let target = to.join(
+            path.strip_prefix(from)
+                .context("Walked out of the source directory")?,
+        );
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target).context(format!(
+                "Failed to create directory at {}",
+                target.display()
+            ))?;
+        } else if entry.file_type().is_file() {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).context(format!(
+                    "Failed to create directory at {}",
+                    parent.display()
+                ))?;
+            }
+            std::fs::copy(&path, &target).context(format!(
+                "Failed to copy {} to {}",
+                path.display(),
+                target.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Templates are kept in memory and persisted one directory each
+#[derive(Clone)]
+pub struct Templates {
+    templates: Arc<Mutex<Vec<Template>>>,
+    dir: PathBuf,
+}
+
+impl Templates {
+    pub async fn new(dir: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).await?;
+        let mut templates = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .context(format!("Failed to read templates at {}", dir.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context(format!("Failed to read templates at {}", dir.display()))?
+        {
+            // a template still being captured when the daemon stopped
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path().join(TEMPLATE_FILE_NAME);
+            let data = match tokio::fs::read(&path).await {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            match serde_json::from_slice::<Template>(&data) {
+                Ok(template) => templates.push(template),
+                Err(e) => warn!("Skipping template at {}: {}", path.display(), e),
+            }
+        }
+        templates.sort_by_key(|template| template.id);
+        Ok(Self {
+            templates: Arc::new(Mutex::new(templates)),
+            dir,
+        })
+    }
+
+    fn template_dir(&self, id: &Snowflake) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    /// Where the files of a template are, laid out like an instance
+    pub fn files_dir(&self, id: &Snowflake) -> PathBuf {
+        self.template_dir(id).join(FILES_DIR_NAME)
+    }
+
+    pub async fn list(&self) -> Vec<Template> {
+        self.templates.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &Snowflake) -> Result<Template, Error> {
+        self.templates
+            .lock()
+            .await
+            .iter()
+            .find(|template| template.id == *id)
+            .cloned()
+            .ok_or_else(template_not_found)
+    }
+
+    /// Stores a template with `files` copied from `instance_path`
+    pub async fn create(
+        &self,
+        config: TemplateConfig,
+        flavour: FlavourKind,
+        version: String,
+        settings: TemplateSettings,
+        instance_path: &Path,
+    ) -> Result<Template, Error> {
+        let name = config.name.trim().to_string();
+        if name.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Template name cannot be empty"),
+            });
+        }
+        let instance_root = instance_path
+            .canonicalize()
+            .context(format!("Failed to resolve {}", instance_path.display()))?;
+        let mut files = Vec::new();
+        for file in &config.files {
+            let file = normalize_template_path(file)?;
+            // a symlink on the way could point anywhere on the machine
+            let inside = match instance_path.join(&file).canonicalize() {
+                Ok(resolved) => resolved.starts_with(&instance_root),
+                Err(_) => false,
+            };
+            if !inside || instance_path.join(&file).is_symlink() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{} doesn't exist in the instance", file),
+                });
+            }
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        let template = Template {
+            id: Snowflake::new(),
+            name,
+            description: config.description,
+            flavour,
+            version,
+            settings,
+            files,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        // the directory only gets its final name once complete
+        let partial = self
+            .dir
+            .join(format!(".{}.partial", template.id.to_string()));
+        let result = tokio::task::spawn_blocking({
+            let files_dir = partial.join(FILES_DIR_NAME);
+            let instance_path = instance_path.to_owned();
+            let files = template.files.clone();
+            move || {
+                std::fs::create_dir_all(&files_dir).context(format!(
+                    "Failed to create directory at {}",
+                    files_dir.display()
+                ))?;
+                for file in &files {
+                    copy_into(&instance_path, &files_dir, file)?;
+                }
+                Ok::<(), Error>(())
+            }
+        })
+        .await
+        .context("Failed to spawn blocking task")?;
+        let result = match result {
+            Ok(()) => {
+                fs::write_all(
+                    partial.join(TEMPLATE_FILE_NAME),
+                    serde_json::to_string_pretty(&template)
+                        .context("Failed to serialize template")?,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(()) => fs::rename(&partial, self.template_dir(&template.id)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&partial).await;
+            return Err(e);
+        }
+        self.templates.lock().await.push(template.clone());
+        Ok(template)
+    }
+
+    pub async fn delete(&self, id: &Snowflake) -> Result<Template, Error> {
+        let mut templates = self.templates.lock().await;
+        let index = templates
+            .iter()
+            .position(|template| template.id == *id)
+            .ok_or_else(template_not_found)?;
+        fs::remove_dir_all(self.template_dir(id)).await?;
+        Ok(templates.remove(index))
+    }
+}
+
+/// Copies the files of a template into an instance, replacing those already there
+pub async fn copy_template_files(files_dir: &Path, instance_path: &Path) -> Result<(), Error> {
+    let files_dir = files_dir.to_owned();
+    let instance_path = instance_path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut entries = std::fs::read_dir(&files_dir)
+            .context(format!("Failed to read {}", files_dir.display()))?;
+        entries.try_for_each(|entry| {
+            let entry = entry.context(format!("Failed to read {}", files_dir.display()))?;
+            copy_into(
+                &files_dir,
+                &instance_path,
+                &entry.file_name().to_string_lossy(),
+            )
+        })
+    })
+    .await
+    .context("Failed to spawn blocking task")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_template_path() {
+        assert_eq!(normalize_template_path("plugins").unwrap(), "plugins");
+        assert_eq!(
+            normalize_template_path("./plugins/Essentials/config.yml").unwrap(),
+            "plugins/Essentials/config.yml"
+        );
+        for path in [
+            "",
+            ".",
+            "../other",
+            "/etc/passwd",
+            "plugins/../../other",
+            "server.properties",
+            "backups/1.zip",
+            ".lodestone_config",
+        ] {
+            assert!(normalize_template_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_template_store() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let instance = temp_dir.path().join("instance");
+        std::fs::create_dir_all(instance.join("plugins/Essentials")).unwrap();
+        std::fs::write(instance.join("plugins/Essentials/config.yml"), "motd: hi").unwrap();
+        std::fs::write(instance.join("bukkit.yml"), "settings: {}").unwrap();
+        std::fs::write(instance.join("server.properties"), "server-port=25565").unwrap();
+
+        let store_dir = temp_dir.path().join("templates");
+        let templates = Templates::new(store_dir.clone()).await.unwrap();
+        let settings = TemplateSettings {
+            cmd_args: IndexMap::new(),
+            restart_policy: RestartPolicy::from_restart_on_crash(false),
+            auto_suspend_minutes: None,
+            game_settings: IndexMap::new(),
+        };
+        let config = |files: &[&str]| TemplateConfig {
+            name: "Survival".to_string(),
+            description: None,
+            instance_uuid: Default::default(),
+            files: files.iter().map(|file| file.to_string()).collect(),
+        };
+        assert!(templates
+            .create(
+                config(&["missing.yml"]),
+                FlavourKind::Paper,
+                "1.20.4".to_string(),
+                settings.clone(),
+                &instance,
+            )
+            .await
+            .is_err());
+        let template = templates
+            .create(
+                config(&["plugins", "bukkit.yml", "./bukkit.yml"]),
+                FlavourKind::Paper,
+                "1.20.4".to_string(),
+                settings,
+                &instance,
+            )
+            .await
+            .unwrap();
+        assert_eq!(template.files, vec!["plugins", "bukkit.yml"]);
+
+        // the store reads back what was written
+        let reloaded = Templates::new(store_dir).await.unwrap();
+        assert_eq!(reloaded.list().await, vec![template.clone()]);
+
+        let new_instance = temp_dir.path().join("new_instance");
+        std::fs::create_dir_all(&new_instance).unwrap();
+        std::fs::write(new_instance.join("bukkit.yml"), "settings: old").unwrap();
+        copy_template_files(&reloaded.files_dir(&template.id), &new_instance)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(new_instance.join("plugins/Essentials/config.yml")).unwrap(),
+            "motd: hi"
+        );
+        assert_eq!(
+            std::fs::read_to_string(new_instance.join("bukkit.yml")).unwrap(),
+            "settings: {}"
+        );
+        assert!(!new_instance.join("server.properties").exists());
+
+        reloaded.delete(&template.id).await.unwrap();
+        assert!(reloaded.get(&template.id).await.is_err());
+        assert!(!reloaded.files_dir(&template.id).exists());
+    }
+}
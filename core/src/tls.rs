@@ -0,0 +1,296 @@
+//! HTTPS for the daemon's listener, with certificates reloaded in place when they are renewed
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use color_eyre::eyre::eyre;
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use tracing::{info, warn};
+
+use crate::error::{Error, ErrorKind};
+
+/// PEM certificate chain, the server's certificate first
+pub const TLS_CERT_VAR: &str = "LODESTONE_TLS_CERT";
+/// PEM private key of the certificate
+pub const TLS_KEY_VAR: &str = "LODESTONE_TLS_KEY";
+/// How often the files are checked for a renewal, SIGHUP reloads them right away
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsPaths {
+    /// The paths from the environment, else `tls/cert.pem` and `tls/key.pem` in the data
+    /// directory if they exist. `None` serves plain HTTP
+    pub fn from_env(lodestone_path: &Path) -> Result<Option<Self>, Error> {
+        let cert = std::env::var_os(TLS_CERT_VAR).map(PathBuf::from);
+        let key = std::env::var_os(TLS_KEY_VAR).map(PathBuf::from);
+        let (cert, key) = match (cert, key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => {
+                let cert = lodestone_path.join("tls").join("cert.pem");
+                let key = lodestone_path.join("tls").join("key.pem");
+                match (cert.exists(), key.exists()) {
+                    (false, false) => return Ok(None),
+                    (true, false) => {
+                        return Err(tls_error(format!("{} is missing", key.display())))
+                    }
+                    (false, true) => {
+                        return Err(tls_error(format!("{} is missing", cert.display())))
+                    }
+                    (true, true) => (cert, key),
+                }
+            }
+            (Some(_), None) => {
+                return Err(tls_error(format!(
+                    "{} is set but {} isn't",
+                    TLS_CERT_VAR, TLS_KEY_VAR
+                )))
+            }
+            (None, Some(_)) => {
+                return Err(tls_error(format!(
+                    "{} is set but {} isn't",
+                    TLS_KEY_VAR, TLS_CERT_VAR
+                )))
+            }
+        };
+        Ok(Some(Self { cert, key }))
+    }
+
+    /// Last modification of either file, a renewal changes it
+    fn modified(&self) -> Option<SystemTime> {
+        let cert = std::fs::metadata(&self.cert)
+            .and_then(|m| m.modified())
+            .ok()?;
+        let key = std::fs::metadata(&self.key)
+            .and_then(|m| m.modified())
+            .ok()?;
+        Some(cert.max(key))
+    }
+}
+
+fn tls_error(message: impl std::fmt::Display) -> Error {
+    Error {
+        kind: ErrorKind::Internal,
+        source: eyre!("Invalid TLS configuration: {}", message),
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>, Error> {
+    let file = File::open(path)
+        .map_err(|e| tls_error(format!("failed to open {}: {}", path.display(), e)))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| tls_error(format!("{} isn't valid PEM: {}", path.display(), e)))
+}
+
+/// Schemes a key is asked to sign with to check it against the certificate, with what verifies
+/// them
+static KEY_CHECKS: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Whether `key` is the private key of `cert`, told by verifying a signature it makes
+fn key_matches(paths: &TlsPaths, cert: &Certificate, key: &PrivateKey) -> Result<bool, Error> {
+    let signing_key = rustls::sign::any_supported_type(key).map_err(|_| {
+        tls_error(format!(
+            "{} holds a private key of an unsupported type, use RSA, ECDSA P-256/P-384 or Ed25519",
+            paths.key.display()
+        ))
+    })?;
+    let end_entity = webpki::EndEntityCert::try_from(cert.0.as_slice()).map_err(|e| {
+        tls_error(format!(
+            "the first certificate in {} can't be parsed: {:?}",
+            paths.cert.display(),
+            e
+        ))
+    })?;
+    let schemes: Vec<SignatureScheme> = KEY_CHECKS.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = match signing_key.choose_scheme(&schemes) {
+        Some(signer) => signer,
+        None => return Ok(false),
+    };
+    let algorithm = match KEY_CHECKS
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+    {
+        Some((_, algorithm)) => algorithm,
+        None => return Ok(false),
+    };
+    let message = b"lodestone tls key check";
+    let signature = signer
+        .sign(message)
+        .map_err(|e| tls_error(format!("{} can't sign: {}", paths.key.display(), e)))?;
+    Ok(end_entity
+        .verify_signature(algorithm, message, &signature)
+        .is_ok())
+}
+
+/// Reads and checks the certificate and key, every error names the file and the problem
+pub fn load_server_config(paths: &TlsPaths) -> Result<ServerConfig, Error> {
+    let certs: Vec<Certificate> = read_pem(&paths.cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(tls_error(format!(
+            "{} holds no PEM certificate",
+            paths.cert.display()
+        )));
+    }
+    let key = read_pem(&paths.key)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| tls_error(format!("{} holds no PEM private key", paths.key.display())))?;
+    if !key_matches(paths, &certs[0], &key)? {
+        return Err(tls_error(format!(
+            "the private key in {} doesn't belong to the certificate in {}",
+            paths.key.display(),
+            paths.cert.display()
+        )));
+    }
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| tls_error(format!("{}: {}", paths.cert.display(), e)))?;
+    // WebSocket upgrades are HTTP/1.1, browsers open a separate HTTP/1.1 connection for them
+    // when h2 is negotiated
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+async fn reload(config: &RustlsConfig, paths: &TlsPaths) {
+    match load_server_config(paths) {
+        Ok(server_config) => {
+            config.reload_from_config(Arc::new(server_config));
+            info!("Reloaded the TLS certificate from {}", paths.cert.display());
+        }
+        Err(e) => warn!(
+            "Keeping the current TLS certificate, the new one can't be used: {}",
+            e.source
+        ),
+    }
+}
+
+/// Reloads the certificate when its files change or on SIGHUP, a bad renewal keeps the
+/// certificate in use
+pub async fn watch(config: RustlsConfig, paths: TlsPaths) {
+    let mut last_modified = paths.modified();
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, the TLS certificate is only reloaded when its files change: {}", e);
+            None
+        }
+    };
+    loop {
+        #[cfg(unix)]
+        let hung_up = async {
+            match hangup.as_mut() {
+                Some(hangup) => {
+                    hangup.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hung_up = std::future::pending::<()>();
+        tokio::select! {
+            _ = hung_up => {
+                info!("SIGHUP received, reloading the TLS certificate");
+                last_modified = paths.modified();
+                reload(&config, &paths).await;
+            }
+            _ = interval.tick() => {
+                let modified = paths.modified();
+                if modified.is_some() && modified != last_modified {
+                    last_modified = modified;
+                    reload(&config, &paths).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(key: &str) -> TlsPaths {
+        TlsPaths {
+            cert: PathBuf::from("testdata/tls/cert.pem"),
+            key: PathBuf::from("testdata/tls").join(key),
+        }
+    }
+
+    #[test]
+    fn test_load_server_config() {
+        assert!(load_server_config(&paths("key.pem")).is_ok());
+
+        let error = load_server_config(&paths("other_key.pem")).unwrap_err();
+        assert!(error.source.to_string().contains("doesn't belong"));
+
+        // the certificate where the key should be
+        let error = load_server_config(&TlsPaths {
+            cert: PathBuf::from("testdata/tls/cert.pem"),
+            key: PathBuf::from("testdata/tls/cert.pem"),
+        })
+        .unwrap_err();
+        assert!(error.source.to_string().contains("no PEM private key"));
+
+        let error = load_server_config(&paths("missing.pem")).unwrap_err();
+        assert!(error.source.to_string().contains("missing.pem"));
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = TlsPaths {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        std::fs::copy("testdata/tls/cert.pem", &paths.cert).unwrap();
+        std::fs::copy("testdata/tls/key.pem", &paths.key).unwrap();
+        let config = RustlsConfig::from_config(Arc::new(load_server_config(&paths).unwrap()));
+        let before = config.get_inner();
+
+        // a renewal gone wrong keeps the certificate in use
+        std::fs::copy("testdata/tls/other_key.pem", &paths.key).unwrap();
+        reload(&config, &paths).await;
+        assert!(Arc::ptr_eq(&before, &config.get_inner()));
+
+        std::fs::copy("testdata/tls/key.pem", &paths.key).unwrap();
+        reload(&config, &paths).await;
+        assert!(!Arc::ptr_eq(&before, &config.get_inner()));
+    }
+}
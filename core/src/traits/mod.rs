@@ -1,10 +1,12 @@
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 
 use serde::{Deserialize, Serialize};
 
 use ts_rs::TS;
+use utoipa::ToSchema;
 
 use self::t_configurable::Game;
 use self::t_player::Player;
@@ -18,7 +20,7 @@ pub mod t_macro;
 pub mod t_player;
 pub mod t_server;
 
-#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq, ToSchema)]
 #[ts(export)]
 pub struct InstanceInfo {
     pub uuid: InstanceUuid,
@@ -34,31 +36,105 @@ pub struct InstanceInfo {
     pub state: State,
     pub player_count: Option<u32>,
     pub max_player_count: Option<u32>,
+    /// Shaped by the game the instance runs
+    #[schema(value_type = Option<Vec<Object>>)]
     pub player_list: Option<HashSet<Player>>,
+    /// Program and arguments the server was last launched with, for debugging
+    #[serde(default)]
+    pub launch_command: Option<Vec<String>>,
+    /// Whether the game's EULA is accepted, `None` for games without one
+    #[serde(default)]
+    pub eula_accepted: Option<bool>,
+    /// URL-safe form of the name, scripts can look the instance up by it
+    #[serde(default)]
+    pub slug: String,
+    /// When the server last got to running since the daemon started
+    #[serde(default)]
+    pub last_started: Option<i64>,
+    /// Unix timestamp in seconds, unlike `last_started` it survives daemon restarts
+    #[serde(default)]
+    pub last_started_at: Option<i64>,
+    /// Unix timestamp in seconds
+    #[serde(default)]
+    pub last_stopped_at: Option<i64>,
+    /// Seconds since the server got to running, only while it runs
+    #[serde(default)]
+    pub uptime_seconds: Option<i64>,
+    #[serde(default)]
+    pub last_exit_code: Option<i32>,
+    #[serde(default)]
+    pub last_exit_reason: Option<ExitReason>,
+    /// Normalized tags from the instance's `.lodestone_config`, only filled in by the instance
+    /// list and info endpoints
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Set while the instance is under maintenance, filled in like `tags`
+    #[serde(default)]
+    pub maintenance: Option<Maintenance>,
+    /// The process is alive but the server stopped answering, only detected for Minecraft
+    #[serde(default)]
+    pub degraded: bool,
 }
+use crate::command::CommandInstance;
 use crate::generic::GenericInstance;
 use crate::minecraft::MinecraftInstance;
 use crate::prelude::GameInstance;
-use crate::types::InstanceUuid;
+use crate::run_record::ExitReason;
+use crate::snapshot::InstanceSnapshot;
+use crate::types::{InstanceUuid, Maintenance};
+use crate::util::slugify;
 #[async_trait]
 #[enum_dispatch::enum_dispatch]
 pub trait TInstance: TConfigurable + TMacro + TPlayerManagement + TServer + Clone {
+    /// Informational fields of the instance, never waits on an operation in progress
+    fn snapshot(&self) -> Arc<InstanceSnapshot>;
+
+    /// Built only from wait-free reads, safe to poll while the instance starts or stops
     async fn get_instance_info(&self) -> InstanceInfo {
+        InstanceInfo::from_snapshot(
+            &self.snapshot(),
+            self.uuid().await,
+            self.creation_time().await,
+            self.path().await.display().to_string(),
+        )
+    }
+}
+
+impl InstanceInfo {
+    /// The info as of `snapshot`, without the tags and maintenance kept on disk
+    pub fn from_snapshot(
+        snapshot: &InstanceSnapshot,
+        uuid: InstanceUuid,
+        creation_time: i64,
+        path: String,
+    ) -> Self {
         InstanceInfo {
-            uuid: self.uuid().await,
-            name: self.name().await,
-            game_type: self.game_type().await,
-            description: self.description().await,
-            version: self.version().await,
-            port: self.port().await,
-            creation_time: self.creation_time().await,
-            path: self.path().await.display().to_string(),
-            auto_start: self.auto_start().await,
-            restart_on_crash: self.restart_on_crash().await,
-            state: self.state().await,
-            player_count: self.get_player_count().await.ok(),
-            max_player_count: self.get_max_player_count().await.ok(),
-            player_list: self.get_player_list().await.ok(),
+            uuid,
+            name: snapshot.name.clone(),
+            game_type: snapshot.game_type.clone(),
+            description: snapshot.description.clone(),
+            version: snapshot.version.clone(),
+            port: snapshot.port,
+            creation_time,
+            path,
+            auto_start: snapshot.auto_start,
+            restart_on_crash: snapshot.restart_on_crash,
+            state: snapshot.state,
+            player_count: snapshot.player_count,
+            max_player_count: snapshot.max_player_count,
+            player_list: snapshot.player_list.clone(),
+            launch_command: snapshot.launch_command.clone(),
+            eula_accepted: snapshot.eula_accepted,
+            slug: slugify(&snapshot.name),
+            last_started: snapshot.last_started,
+            last_started_at: snapshot.last_run.last_started_at,
+            last_stopped_at: snapshot.last_run.last_stopped_at,
+            uptime_seconds: snapshot.uptime_seconds(),
+            last_exit_code: snapshot.last_run.last_exit_code,
+            last_exit_reason: snapshot.last_run.last_exit_reason,
+            tags: Vec::new(),
+            maintenance: None,
+            degraded: snapshot.degraded,
         }
     }
 }
@@ -5,11 +5,13 @@ use indexmap::IndexMap;
 pub use serde::{Deserialize, Serialize};
 pub use serde_json;
 use ts_rs::TS;
+use utoipa::ToSchema;
 
 use crate::error::Error;
 use crate::error::ErrorKind;
+use crate::types::Snowflake;
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, ToSchema)]
 #[ts(export)]
 #[serde(tag = "type", content = "value")]
 pub enum ConfigurableValue {
@@ -51,7 +53,7 @@ impl From<bool> for ConfigurableValue {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, TS)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, TS, ToSchema)]
 #[serde(tag = "type")]
 #[ts(export)]
 pub enum ConfigurableValueType {
@@ -76,18 +78,45 @@ impl ToString for ConfigurableValueType {
     }
 }
 
+/// Enums like the game version have too many options to spell out in an error
+const MAX_LISTED_OPTIONS: usize = 10;
+
+fn check_bounds<T: PartialOrd + std::fmt::Display>(
+    value: &T,
+    min: &Option<T>,
+    max: &Option<T>,
+) -> Result<(), Error> {
+    if let Some(min) = min {
+        if value < min {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Must be at least {}, got {}", min, value),
+            });
+        }
+    }
+    if let Some(max) = max {
+        if value > max {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Must be at most {}, got {}", max, value),
+            });
+        }
+    }
+    Ok(())
+}
+
 impl ConfigurableValueType {
     pub fn type_check(&self, value: &ConfigurableValue) -> Result<(), Error> {
         match (self, value) {
             (ConfigurableValueType::String { regex }, ConfigurableValue::String(value)) => {
                 if let Some(regex) = regex {
-                    if let Ok(regex) = fancy_regex::Regex::new(regex) {
-                        if let Ok(true) = regex.is_match(value) {
+                    if let Ok(compiled) = fancy_regex::Regex::new(regex) {
+                        if let Ok(true) = compiled.is_match(value) {
                             Ok(())
                         } else {
                             Err(Error {
                                 kind: ErrorKind::BadRequest,
-                                source: eyre!("Value does not match regex"),
+                                source: eyre!("Does not match the pattern {}", regex),
                             })
                         }
                     } else {
@@ -101,82 +130,80 @@ impl ConfigurableValueType {
                 }
             }
             (ConfigurableValueType::Integer { min, max }, ConfigurableValue::Integer(value)) => {
-                if let Some(min) = min {
-                    if value < min {
-                        return Err(Error {
-                            kind: ErrorKind::BadRequest,
-                            source: eyre!("Value is too small"),
-                        });
-                    }
-                }
-                if let Some(max) = max {
-                    if value > max {
-                        return Err(Error {
-                            kind: ErrorKind::BadRequest,
-                            source: eyre!("Value is too large"),
-                        });
-                    }
-                }
-                Ok(())
+                check_bounds(value, min, max)
             }
             (
                 ConfigurableValueType::UnsignedInteger { min, max },
                 ConfigurableValue::UnsignedInteger(value),
-            ) => {
-                if let Some(min) = min {
-                    if value < min {
-                        return Err(Error {
-                            kind: ErrorKind::BadRequest,
-                            source: eyre!("Value is too small"),
-                        });
-                    }
-                }
-                if let Some(max) = max {
-                    if value > max {
-                        return Err(Error {
-                            kind: ErrorKind::BadRequest,
-                            source: eyre!("Value is too large"),
-                        });
-                    }
-                }
-                Ok(())
-            }
+            ) => check_bounds(value, min, max),
             (ConfigurableValueType::Float { min, max }, ConfigurableValue::Float(value)) => {
-                if let Some(min) = min {
-                    if value < min {
-                        return Err(Error {
-                            kind: ErrorKind::BadRequest,
-                            source: eyre!("Value is too small"),
-                        });
-                    }
-                }
-                if let Some(max) = max {
-                    if value > max {
-                        return Err(Error {
-                            kind: ErrorKind::BadRequest,
-                            source: eyre!("Value is too large"),
-                        });
-                    }
-                }
-                Ok(())
+                check_bounds(value, min, max)
             }
             (ConfigurableValueType::Boolean, ConfigurableValue::Boolean(_)) => Ok(()),
             (ConfigurableValueType::Enum { options }, ConfigurableValue::Enum(value)) => {
                 if options.contains(value) {
                     Ok(())
+                } else if options.len() > MAX_LISTED_OPTIONS {
+                    Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("{} is not one of the {} options", value, options.len()),
+                    })
                 } else {
                     Err(Error {
                         kind: ErrorKind::BadRequest,
-                        source: eyre!("Value is not in enum"),
+                        source: eyre!("Must be one of {}, got {}", options.join(", "), value),
                     })
                 }
             }
             _ => Err(Error {
                 kind: ErrorKind::BadRequest,
-                source: eyre!("Type mismatch"),
+                source: eyre!(
+                    "Expected {}, found {}",
+                    self.to_string(),
+                    value.infer_type().to_string()
+                ),
             }),
         }
     }
+
+    /// Reads a value of this type from its text form, as in a config file
+    pub fn parse_value(&self, raw: &str) -> Result<ConfigurableValue, Error> {
+        let value = match self {
+            ConfigurableValueType::String { .. } => ConfigurableValue::String(raw.to_string()),
+            ConfigurableValueType::Integer { .. } => raw
+                .parse()
+                .map(ConfigurableValue::Integer)
+                .map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Expected integer, found {}: {}", raw, e),
+                })?,
+            ConfigurableValueType::UnsignedInteger { .. } => raw
+                .parse()
+                .map(ConfigurableValue::UnsignedInteger)
+                .map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Expected unsigned integer, found {}: {}", raw, e),
+                })?,
+            ConfigurableValueType::Float { .. } => raw
+                .parse()
+                .map(ConfigurableValue::Float)
+                .map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Expected float, found {}: {}", raw, e),
+                })?,
+            ConfigurableValueType::Boolean => {
+                raw.parse()
+                    .map(ConfigurableValue::Boolean)
+                    .map_err(|e| Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Expected boolean, found {}: {}", raw, e),
+                    })?
+            }
+            ConfigurableValueType::Enum { .. } => ConfigurableValue::Enum(raw.to_string()),
+        };
+        self.type_check(&value)?;
+        Ok(value)
+    }
 }
 
 impl ToString for ConfigurableValue {
@@ -279,7 +306,7 @@ impl ConfigurableValue {
 
 // A SettingManifest contains a unique identifier, a name and a description
 // and a value
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub struct SettingManifest {
     setting_id: String, // static, cannot change at runtime
@@ -291,6 +318,8 @@ pub struct SettingManifest {
     is_secret: bool,                          // ??
     is_required: bool,                        // ??
     is_mutable: bool,                         // CAN change at runtime
+    #[serde(default)]
+    requires_restart: bool, // only takes effect once the instance restarts
 }
 
 impl SettingManifest {
@@ -300,6 +329,12 @@ impl SettingManifest {
     pub fn get_identifier(&self) -> &String {
         &self.setting_id
     }
+    pub fn get_value_type(&self) -> &ConfigurableValueType {
+        &self.value_type
+    }
+    pub fn is_secret(&self) -> bool {
+        self.is_secret
+    }
     /// # WARNING
     /// Will infer the type of the value from the value itself
     ///
@@ -327,6 +362,7 @@ impl SettingManifest {
             is_secret,
             is_required: true,
             is_mutable,
+            requires_restart: false,
         }
     }
     #[allow(clippy::too_many_arguments)]
@@ -355,6 +391,7 @@ impl SettingManifest {
             is_secret,
             is_required: false,
             is_mutable,
+            requires_restart: false,
         }
     }
 
@@ -383,6 +420,7 @@ impl SettingManifest {
                 is_secret,
                 is_required: true,
                 is_mutable,
+                requires_restart: false,
             }
         } else {
             Self {
@@ -395,10 +433,26 @@ impl SettingManifest {
                 default_value,
                 is_secret,
                 is_mutable,
+                requires_restart: false,
             }
         }
     }
 
+    pub fn requires_restart(&self) -> bool {
+        self.requires_restart
+    }
+
+    /// Marks a setting the instance only reads when it starts
+    pub fn with_restart_required(mut self) -> Self {
+        self.requires_restart = true;
+        self
+    }
+
+    pub fn with_default_value(mut self, default_value: Option<ConfigurableValue>) -> Self {
+        self.default_value = default_value;
+        self
+    }
+
     fn set_value_type_safe(&mut self, value: ConfigurableValue) -> Result<(), Error> {
         self.value_type
             .type_check(&value)
@@ -444,7 +498,7 @@ impl SettingManifest {
 
 // A Setting section contains a name and a description (for UI)
 // A Setting section contains a list of InstanceSetting
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub struct SectionManifest {
     pub(super) section_id: String,
@@ -515,7 +569,7 @@ impl SectionManifest {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub struct SetupManifest {
     pub setting_sections: IndexMap<String, SectionManifest>,
@@ -536,6 +590,54 @@ impl SetupManifest {
         Ok(())
     }
 
+    /// Every problem with the value at once, by setting. Unlike [`Self::validate_setup_value`] it
+    /// also reports the required settings left out
+    pub fn setup_value_errors(&self, value: &SetupValue) -> Vec<SettingValidationError> {
+        let mut errors = Vec::new();
+        for (section_id, section_value) in value.setting_sections.iter() {
+            let section = match self.setting_sections.get(section_id) {
+                Some(section) => section,
+                None => {
+                    errors.push(SettingValidationError::new(
+                        section_id.as_str(),
+                        "Section not found",
+                    ));
+                    continue;
+                }
+            };
+            for (setting_id, setting_value) in section_value.settings.iter() {
+                let checked = match section.settings.get(setting_id) {
+                    Some(setting) => setting.validate_setting(&setting_value.value),
+                    None => Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Setting not found"),
+                    }),
+                };
+                if let Err(e) = checked {
+                    errors.push(SettingValidationError::new(setting_id.as_str(), e.source));
+                }
+            }
+        }
+        for section in self.setting_sections.values() {
+            for (setting_id, setting) in section.settings.iter() {
+                let given = value
+                    .get_unique_setting(setting_id)
+                    .and_then(|setting| setting.get_value())
+                    .is_some();
+                if setting.is_required
+                    && !given
+                    && !errors.iter().any(|error| &error.setting_id == setting_id)
+                {
+                    errors.push(SettingValidationError::new(
+                        setting_id.as_str(),
+                        "Setting is required",
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
     pub fn validate_section(
         &self,
         section_key: &str,
@@ -552,7 +654,7 @@ impl SetupManifest {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub struct SetupValue {
     pub name: String,
@@ -560,6 +662,10 @@ pub struct SetupValue {
     pub auto_start: bool,
     pub restart_on_crash: bool,
     pub setting_sections: IndexMap<String, SectionManifestValue>,
+    /// Template applied once the server is set up, Minecraft only
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub template_id: Option<Snowflake>,
 }
 
 impl SetupValue {
@@ -577,7 +683,7 @@ impl SetupValue {
 
 // A setting manifest indicates if the instance has implemented functionalities for smart, lodestone controlled feature
 // A setting manifest has an ordered list of Setting Section
-#[derive(Debug, Clone, Serialize, Deserialize, TS, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, ToSchema)]
 #[ts(export)]
 pub struct ConfigurableManifest {
     auto_start: bool,
@@ -728,7 +834,7 @@ impl ConfigurableManifest {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, TS)]
+#[derive(Clone, Debug, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub struct SettingManifestValue {
     pub(super) value: Option<ConfigurableValue>,
@@ -740,7 +846,7 @@ impl SettingManifestValue {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, TS)]
+#[derive(Clone, Debug, Serialize, Deserialize, TS, ToSchema)]
 #[ts(export)]
 pub struct SectionManifestValue {
     pub(super) settings: IndexMap<String, SettingManifestValue>,
@@ -767,6 +873,50 @@ impl SettingManifest {
     }
 }
 
+/// Why the value given for one setting was refused
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, ToSchema)]
+#[ts(export)]
+pub struct SettingValidationError {
+    pub setting_id: String,
+    pub message: String,
+}
+
+impl SettingValidationError {
+    pub fn new(setting_id: impl Into<String>, message: impl std::fmt::Display) -> Self {
+        Self {
+            setting_id: setting_id.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Every refused setting of an update, so the error body can list them by field
+#[derive(Debug, Clone)]
+pub struct SettingValidationErrors(pub Vec<SettingValidationError>);
+
+impl std::fmt::Display for SettingValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid settings: {}",
+            self.0
+                .iter()
+                .map(|e| format!("{} ({})", e.setting_id, e.message))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+impl From<SettingValidationErrors> for Error {
+    fn from(errors: SettingValidationErrors) -> Self {
+        Error {
+            kind: ErrorKind::BadRequest,
+            source: color_eyre::Report::msg(errors),
+        }
+    }
+}
+
 impl SectionManifest {
     pub fn validate_section(&self, value: &SectionManifestValue) -> Result<(), Error> {
         for (setting_id, setting_value) in value.settings.iter() {
@@ -815,3 +965,72 @@ impl SettingLocalCache {
         &self.value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setup_value_errors() {
+        let setting = |id: &str, value: ConfigurableValue| {
+            (
+                id.to_string(),
+                SettingManifest::new_required_value(
+                    id.to_string(),
+                    id.to_string(),
+                    String::new(),
+                    value,
+                    None,
+                    false,
+                    true,
+                ),
+            )
+        };
+        let manifest = SetupManifest {
+            setting_sections: IndexMap::from([(
+                "section".to_string(),
+                SectionManifest::new(
+                    "section".to_string(),
+                    "Section".to_string(),
+                    String::new(),
+                    IndexMap::from([
+                        setting("port", ConfigurableValue::UnsignedInteger(25565)),
+                        setting("motd", ConfigurableValue::String("hi".to_string())),
+                    ]),
+                ),
+            )]),
+        };
+        let given = |value: Option<ConfigurableValue>| SettingManifestValue { value };
+        let value = SetupValue {
+            name: "Survival".to_string(),
+            description: None,
+            auto_start: false,
+            restart_on_crash: false,
+            setting_sections: IndexMap::from([
+                (
+                    "section".to_string(),
+                    SectionManifestValue {
+                        settings: IndexMap::from([
+                            (
+                                "port".to_string(),
+                                given(Some(ConfigurableValue::String("25565".to_string()))),
+                            ),
+                            ("unknown".to_string(), given(None)),
+                        ]),
+                    },
+                ),
+                (
+                    "other".to_string(),
+                    SectionManifestValue {
+                        settings: IndexMap::new(),
+                    },
+                ),
+            ]),
+            template_id: None,
+        };
+        let errors = manifest.setup_value_errors(&value);
+        let ids: Vec<&str> = errors.iter().map(|e| e.setting_id.as_str()).collect();
+        assert_eq!(ids, ["port", "unknown", "other", "motd"]);
+        assert_eq!(errors[3].message, "Setting is required");
+    }
+}
@@ -4,28 +4,38 @@ pub use std::path::PathBuf;
 use async_trait::async_trait;
 use color_eyre::eyre::eyre;
 use enum_kinds::EnumKind;
+use indexmap::IndexMap;
 pub use serde::{Deserialize, Serialize};
 pub use serde_json;
 use ts_rs::TS;
+use utoipa::ToSchema;
 
 use self::manifest::ConfigurableManifest;
 use self::manifest::ConfigurableValue;
+use self::manifest::SettingManifest;
+use crate::backups::BackupSchedule;
+use crate::diagnostics::PreflightTarget;
 use crate::error::Error;
 use crate::error::ErrorKind;
+use crate::events::CausedBy;
 use crate::implementations::minecraft::Flavour;
+use crate::java::JavaSelection;
+use crate::restart_policy::RestartPolicy;
+use crate::traits::CommandInstance;
 use crate::traits::GameInstance;
 use crate::traits::GenericInstance;
 use crate::traits::MinecraftInstance;
 
 use crate::types::InstanceUuid;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, ToSchema)]
 #[serde(tag = "type")]
 #[ts(export)]
 pub enum MinecraftVariant {
     Vanilla,
     Forge,
     Fabric,
+    Quilt,
     Paper,
     Spigot,
     Other { name: String },
@@ -34,8 +44,8 @@ pub enum MinecraftVariant {
 /// The type of game this instance is
 ///
 /// Meant to be consumed by frontend to display the correct icon
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, EnumKind)]
-#[enum_kind(GameType, derive(Serialize, Deserialize, TS))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, EnumKind, ToSchema)]
+#[enum_kind(GameType, derive(Serialize, Deserialize, TS, ToSchema))]
 #[serde(tag = "type")]
 #[ts(export)]
 pub enum Game {
@@ -47,6 +57,8 @@ pub enum Game {
         game_name: GameType,       //used for identifying the "game" ("Minecraft")
         game_display_name: String, //displaying to the user what on earth this is ("MinecraftGlowstone")
     },
+    /// Runs a user supplied start command
+    Command,
 }
 
 #[test]
@@ -63,6 +75,9 @@ impl From<Flavour> for Game {
             Flavour::Fabric { .. } => Self::MinecraftJava {
                 variant: MinecraftVariant::Fabric,
             },
+            Flavour::Quilt { .. } => Self::MinecraftJava {
+                variant: MinecraftVariant::Quilt,
+            },
             Flavour::Paper { .. } => Self::MinecraftJava {
                 variant: MinecraftVariant::Paper,
             },
@@ -76,10 +91,13 @@ impl From<Flavour> for Game {
     }
 }
 
+/// Getters up to `restart_on_crash` are wait-free: implementations serve them from a snapshot
+/// updated by the setters, so they never wait on a start, a stop or the game's runtime.
+/// Everything else may block until the instance is done with an operation in progress.
 #[async_trait]
 #[enum_dispatch::enum_dispatch]
 pub trait TConfigurable {
-    // getters
+    // wait-free getters
     async fn uuid(&self) -> InstanceUuid;
     async fn name(&self) -> String;
     async fn game_type(&self) -> Game;
@@ -91,6 +109,10 @@ pub trait TConfigurable {
     /// does start when lodestone starts
     async fn auto_start(&self) -> bool;
     async fn restart_on_crash(&self) -> bool;
+    // getters that may block
+    async fn restart_policy(&self) -> RestartPolicy {
+        RestartPolicy::from_restart_on_crash(self.restart_on_crash().await)
+    }
     // setters
     async fn set_name(&self, name: String) -> Result<(), Error>;
     async fn set_description(&self, description: String) -> Result<(), Error>;
@@ -112,6 +134,12 @@ pub trait TConfigurable {
             source: eyre!("This instance does not support setting restart on crash"),
         })
     }
+    async fn set_restart_policy(&self, _restart_policy: RestartPolicy) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support setting a restart policy"),
+        })
+    }
     async fn set_backup_period(&self, _backup_period: Option<u32>) -> Result<(), Error> {
         Err(Error {
             kind: ErrorKind::UnsupportedOperation,
@@ -119,6 +147,78 @@ pub trait TConfigurable {
         })
     }
 
+    async fn backup_schedule(&self) -> Result<BackupSchedule, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support scheduled backups"),
+        })
+    }
+    async fn set_backup_schedule(&self, _backup_schedule: BackupSchedule) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support scheduled backups"),
+        })
+    }
+
+    async fn preflight_target(&self) -> Result<PreflightTarget, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support diagnostics"),
+        })
+    }
+
+    /// Typed settings of the game's own configuration file, keyed like the file
+    async fn game_settings(&self) -> Result<IndexMap<String, SettingManifest>, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support game settings"),
+        })
+    }
+
+    /// Validates every value before writing any, keys not given are left untouched.
+    /// Invalid values are reported together as [`manifest::SettingValidationErrors`]
+    async fn set_game_settings(
+        &self,
+        _settings: IndexMap<String, ConfigurableValue>,
+    ) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support game settings"),
+        })
+    }
+
+    /// Accepts the game's EULA on behalf of the owner, the next start goes through
+    async fn accept_eula(&self) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance has no EULA to accept"),
+        })
+    }
+
+    /// The Java runtime the instance launches with, and which ones its game version runs on
+    async fn java_selection(&self) -> Result<JavaSelection, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not run on Java"),
+        })
+    }
+
+    /// Pins the instance to the Java executable at `path` once it's checked to fit
+    async fn set_java(&self, _path: PathBuf) -> Result<JavaSelection, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not run on Java"),
+        })
+    }
+
+    /// Downloads a runtime fitting the game version in the background and pins it when done
+    async fn download_java(&self, _caused_by: CausedBy) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not run on Java"),
+        })
+    }
+
     async fn change_version(&self, _version: String) -> Result<(), Error> {
         Err(Error {
             kind: ErrorKind::UnsupportedOperation,
@@ -15,6 +15,16 @@ use crate::traits::t_configurable::manifest::{SettingLocalCache, SettingManifest
 use serde::Deserialize;
 use serde::Serialize;
 
+/// What happens when a macro is started while a run of it hasn't finished
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum MacroOverlap {
+    #[default]
+    Reject,
+    /// The run starts once the earlier ones have finished
+    Queue,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
 #[ts(export)]
 pub struct MacroEntry {
@@ -22,16 +32,27 @@ pub struct MacroEntry {
     pub last_run: Option<i64>,
     // relative path to instance root
     pub path: PathBuf,
+    /// `@name` from the macro's header comment
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub overlap: MacroOverlap,
+    /// Settings declared by the macro's `LodestoneConfig` class
+    pub config: IndexMap<String, SettingManifest>,
+    /// Why `config` couldn't be parsed, if it couldn't
+    pub config_error: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
 #[ts(export)]
 pub struct TaskEntry {
     pub name: String,
+    /// When the run was requested, a queued run may start later
     pub creation_time: i64,
     pub pid: MacroPID,
+    pub caused_by: CausedBy,
 }
 
+/// A finished run, it ended at `exit_status.time()`
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, TS)]
 #[ts(export)]
 pub struct HistoryEntry {
@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
 use crate::implementations::generic::player::GenericPlayer;
 use crate::minecraft::player::MinecraftPlayer;
 use crate::traits::GameInstance;
@@ -35,6 +36,64 @@ impl Hash for Player {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct OnlinePlayer {
+    pub player: Player,
+    /// Unix timestamp in seconds, None if the instance doesn't track sessions
+    pub joined_at: Option<i64>,
+}
+
+/// A stretch of time a player spent on the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct PlayerSession {
+    pub player: Player,
+    /// Unix timestamp in seconds
+    pub joined_at: i64,
+    /// Unix timestamp in seconds, None while the player is still online
+    pub left_at: Option<i64>,
+}
+
+/// A list of players the game keeps, such as the whitelist
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum PlayerListKind {
+    Whitelist,
+    Ops,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct PlayerListEntry {
+    pub name: String,
+    pub uuid: String,
+    /// Permission level, only for operators
+    pub level: Option<u32>,
+}
+
+/// Where a change to a player list took effect
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum PlayerListChangeTarget {
+    /// Through a console command, the running server applied it immediately
+    Live,
+    /// Written to the list file, read by the server on its next start
+    File,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct PlayerListChange {
+    pub applied_to: PlayerListChangeTarget,
+    /// False if the player was already in, or already absent from, the list
+    pub changed: bool,
+    /// None when removing a player that wasn't in the list
+    pub entry: Option<PlayerListEntry>,
+}
+
 #[async_trait]
 #[enum_dispatch::enum_dispatch]
 pub trait TPlayerManagement {
@@ -57,10 +116,64 @@ pub trait TPlayerManagement {
         })
     }
 
+    async fn get_online_players(&self) -> Result<Vec<OnlinePlayer>, Error> {
+        Ok(self
+            .get_player_list()
+            .await?
+            .into_iter()
+            .map(|player| OnlinePlayer {
+                player,
+                joined_at: None,
+            })
+            .collect())
+    }
+
+    /// Sessions that were still going at or after `since`, most recent first
+    async fn get_player_sessions(&self, _since: Option<i64>) -> Result<Vec<PlayerSession>, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Player session history is unsupported for this instance"),
+        })
+    }
+
     async fn set_max_player_count(&self, _max_player_count: u32) -> Result<(), Error> {
         Err(Error {
             kind: ErrorKind::UnsupportedOperation,
             source: eyre!("Setting max player count is unsupported for this instance"),
         })
     }
+
+    async fn get_player_list_entries(
+        &self,
+        _list: PlayerListKind,
+    ) -> Result<Vec<PlayerListEntry>, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Player lists are unsupported for this instance"),
+        })
+    }
+
+    async fn add_to_player_list(
+        &self,
+        _list: PlayerListKind,
+        _name: &str,
+        _caused_by: CausedBy,
+    ) -> Result<PlayerListChange, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Player lists are unsupported for this instance"),
+        })
+    }
+
+    async fn remove_from_player_list(
+        &self,
+        _list: PlayerListKind,
+        _name: &str,
+        _caused_by: CausedBy,
+    ) -> Result<PlayerListChange, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Player lists are unsupported for this instance"),
+        })
+    }
 }
@@ -1,14 +1,24 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
 use bollard::secret::ContainerState;
 use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use ts_rs::TS;
+use utoipa::ToSchema;
 
+use crate::announcements::AnnouncementsConfig;
+use crate::command_queue::{CommandQueueConfig, CommandQueueStatus};
+use crate::console_capture::CommandOutput;
+use crate::console_history::ConsoleHistoryPage;
+use crate::error::{ErrorCode, ErrorKind};
 use crate::events::CausedBy;
+use crate::process_tree::ChildProcessReport;
 use crate::Error;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, Copy, ToSchema)]
 #[serde(rename = "InstanceState")]
 #[ts(export)]
 pub enum State {
@@ -17,6 +27,8 @@ pub enum State {
     Stopping,
     Stopped,
     Error,
+    /// The process is frozen, it keeps its memory but uses no CPU until resumed
+    Suspended,
 }
 
 impl State {
@@ -42,6 +54,8 @@ pub enum StateAction {
     UserStop,
     InstanceStart,
     InstanceStop,
+    UserSuspend,
+    UserResume,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
@@ -71,6 +85,42 @@ pub struct MonitorReport {
     pub disk_usage: Option<DiskUsage>,
     pub cpu_usage: Option<f32>,
     pub start_time: Option<u64>,
+    /// Seconds since the process started
+    pub uptime: Option<u64>,
+    /// Number of processes in the server's process tree, the server itself included
+    pub process_count: Option<u32>,
+    /// Child processes using the most memory
+    pub top_children: Option<Vec<ChildProcessReport>>,
+}
+
+impl MonitorReport {
+    /// Report of an instance that is not running
+    pub fn zeroed() -> Self {
+        Self {
+            memory_usage: Some(0),
+            disk_usage: Some(DiskUsage {
+                total_written_bytes: 0,
+                written_bytes: 0,
+                total_read_bytes: 0,
+                read_bytes: 0,
+            }),
+            cpu_usage: Some(0.0),
+            start_time: None,
+            uptime: Some(0),
+            process_count: Some(0),
+            top_children: Some(Vec::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RconStatus {
+    /// Whether commands are sent over RCON when a connection is up
+    pub enabled: bool,
+    /// RCON connects once the server has started, commands go to stdin until then
+    pub connected: bool,
+    pub port: Option<u32>,
 }
 
 impl ToString for State {
@@ -81,10 +131,16 @@ impl ToString for State {
             State::Stopping => "Stopping".to_string(),
             State::Stopped => "Stopped".to_string(),
             State::Error => "Error".to_string(),
+            State::Suspended => "Suspended".to_string(),
         }
     }
 }
 
+/// The error for an action the state doesn't allow, the details carry the state
+pub fn invalid_transition(state: State, message: &str) -> Error {
+    Error::coded(ErrorCode::InvalidStateTransition, message).with_details(json!({ "state": state }))
+}
+
 impl State {
     pub fn try_new_state(
         &self,
@@ -92,30 +148,62 @@ impl State {
         on_transit: Option<&dyn Fn(State)>,
     ) -> Result<State, Error> {
         let state = match (*self, action) {
-            (State::Starting, StateAction::UserStart) => {
-                Err(eyre!("Cannot start an instance that is already starting"))
-            }
-            (State::Starting, StateAction::UserStop) => {
-                Err(eyre!("Cannot stop an instance that is starting"))
-            }
+            (State::Starting, StateAction::UserStart) => Err(invalid_transition(
+                *self,
+                "Cannot start an instance that is already starting",
+            )),
+            (State::Starting, StateAction::UserStop) => Err(invalid_transition(
+                *self,
+                "Cannot stop an instance that is starting",
+            )),
             (_, StateAction::InstanceStart) => Ok(State::Running),
             (_, StateAction::InstanceStop) => Ok(State::Stopped),
-            (State::Running, StateAction::UserStart) => {
-                Err(eyre!("Cannot start an instance that is already running"))
-            }
+            (State::Running, StateAction::UserStart) => Err(invalid_transition(
+                *self,
+                "Cannot start an instance that is already running",
+            )),
             (State::Running, StateAction::UserStop) => Ok(State::Stopping),
-            (State::Stopping, StateAction::UserStart) => {
-                Err(eyre!("Cannot start an instance that is stopping"))
-            }
-            (State::Stopping, StateAction::UserStop) => {
-                Err(eyre!("Cannot stop an instance that is already stopping"))
-            }
-            (State::Stopped, StateAction::UserStart) => Ok(State::Starting),
-            (State::Stopped, StateAction::UserStop) => {
-                Err(eyre!("Cannot stop an instance that is already stopped"))
-            }
-            (State::Error, StateAction::UserStart) => todo!(),
-            (State::Error, StateAction::UserStop) => todo!(),
+            (State::Stopping, StateAction::UserStart) => Err(invalid_transition(
+                *self,
+                "Cannot start an instance that is stopping",
+            )),
+            (State::Stopping, StateAction::UserStop) => Err(invalid_transition(
+                *self,
+                "Cannot stop an instance that is already stopping",
+            )),
+            // an instance in error has no server running, so it starts like a stopped one
+            (State::Stopped | State::Error, StateAction::UserStart) => Ok(State::Starting),
+            (State::Stopped, StateAction::UserStop) => Err(invalid_transition(
+                *self,
+                "Cannot stop an instance that is already stopped",
+            )),
+            (State::Error, StateAction::UserStop) => Err(invalid_transition(
+                *self,
+                "Cannot stop an instance that is not running",
+            )),
+            (State::Suspended, StateAction::UserStart) => Err(invalid_transition(
+                *self,
+                "Cannot start an instance that is suspended, resume it instead",
+            )),
+            // the frozen server can't read the stop command, it's resumed first
+            (State::Suspended, StateAction::UserStop) => Err(invalid_transition(
+                *self,
+                "Cannot stop an instance that is suspended, resume it first",
+            )),
+            (State::Running, StateAction::UserSuspend) => Ok(State::Suspended),
+            (State::Suspended, StateAction::UserSuspend) => Err(invalid_transition(
+                *self,
+                "Cannot suspend an instance that is already suspended",
+            )),
+            (_, StateAction::UserSuspend) => Err(invalid_transition(
+                *self,
+                "Cannot suspend an instance that is not running",
+            )),
+            (State::Suspended, StateAction::UserResume) => Ok(State::Running),
+            (_, StateAction::UserResume) => Err(invalid_transition(
+                *self,
+                "Cannot resume an instance that is not suspended",
+            )),
         }?;
         if let Some(on_transit) = on_transit {
             on_transit(state);
@@ -142,8 +230,126 @@ pub trait TServer {
     async fn start(&self, caused_by: CausedBy, block: bool) -> Result<(), Error>;
     async fn stop(&self, caused_by: CausedBy, block: bool) -> Result<(), Error>;
     async fn restart(&self, caused_by: CausedBy, block: bool) -> Result<(), Error>;
+    /// Warns players for `seconds` before stopping, a second request or a start cancels it
+    async fn stop_gracefully(&self, _caused_by: CausedBy, _seconds: u32) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Graceful stop is unsupported for this instance"),
+        })
+    }
     async fn kill(&self, caused_by: CausedBy) -> Result<(), Error>;
+    /// Freezes the server's processes, they keep their memory but stop using the CPU
+    async fn suspend(&self, _caused_by: CausedBy) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Suspending is unsupported for this instance"),
+        })
+    }
+    async fn resume(&self, _caused_by: CausedBy) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Suspending is unsupported for this instance"),
+        })
+    }
+    /// Minutes without players before the server is suspended, `None` if it never is
+    async fn auto_suspend(&self) -> Result<Option<u32>, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Suspending is unsupported for this instance"),
+        })
+    }
+    async fn set_auto_suspend(&self, _minutes: Option<u32>) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Suspending is unsupported for this instance"),
+        })
+    }
+    /// Authoritative state, may wait on a transition in progress.
+    /// `TInstance::snapshot` has a wait-free copy for informational reads
     async fn state(&self) -> State;
+    /// Id of the server's process while it runs
+    async fn pid(&self) -> Option<u32> {
+        None
+    }
     async fn send_command(&self, command: &str, caused_by: CausedBy) -> Result<(), Error>;
+    /// Sends a command and returns what the server printed in response, waiting at most `timeout`
+    async fn send_command_with_output(
+        &self,
+        _command: &str,
+        _caused_by: CausedBy,
+        _timeout: Duration,
+    ) -> Result<CommandOutput, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Capturing command output is unsupported for this instance"),
+        })
+    }
     async fn monitor(&self) -> MonitorReport;
+    async fn command_queue_status(&self) -> Result<CommandQueueStatus, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not have a command queue"),
+        })
+    }
+    async fn set_command_queue_config(&self, _config: CommandQueueConfig) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not have a command queue"),
+        })
+    }
+    async fn console_history(
+        &self,
+        _offset: Option<u64>,
+        _count: usize,
+    ) -> Result<ConsoleHistoryPage, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not keep a console history"),
+        })
+    }
+    async fn clear_console_history(&self) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not keep a console history"),
+        })
+    }
+    async fn rcon_status(&self) -> Result<RconStatus, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support RCON"),
+        })
+    }
+    /// Configures the server to accept RCON on `port`, effective from its next start
+    async fn enable_rcon(&self, _port: u32) -> Result<RconStatus, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support RCON"),
+        })
+    }
+    /// Returns the port RCON was using, if it was enabled
+    async fn disable_rcon(&self) -> Result<Option<u32>, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support RCON"),
+        })
+    }
+    async fn announcements(&self) -> Result<AnnouncementsConfig, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support announcements"),
+        })
+    }
+    async fn set_announcements(&self, _config: AnnouncementsConfig) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support announcements"),
+        })
+    }
+    /// Sends the announcement right away, regardless of its schedule
+    async fn test_announcement(&self, _id: &str) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support announcements"),
+        })
+    }
 }
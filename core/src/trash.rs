@@ -0,0 +1,283 @@
+//! Deleted instance files are moved into the instance's trash, from where they can be restored
+//! until they are purged
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::disk_usage::dir_size;
+use crate::error::{Error, ErrorCode};
+use crate::global_settings::GlobalSettings;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::types::InstanceUuid;
+use crate::util::extended_length_path;
+
+/// Left out of backups, but counted against the disk quota like any other file
+pub const TRASH_DIR_NAME: &str = ".lodestone_trash";
+pub const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub fn trash_dir(instance_path: &Path) -> PathBuf {
+    instance_path.join(TRASH_DIR_NAME)
+}
+
+/// Something deleted from an instance, kept at `<trash>/<id>/<path>`
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct TrashEntry {
+    pub id: String,
+    /// Where it was, relative to the instance
+    pub path: PathBuf,
+    /// Unix timestamp in seconds
+    pub deleted_at: i64,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+fn entry_dir(instance_path: &Path, id: &str) -> PathBuf {
+    trash_dir(instance_path).join(id)
+}
+
+fn entry_metadata(instance_path: &Path, id: &str) -> PathBuf {
+    trash_dir(instance_path).join(format!("{}.json", id))
+}
+
+fn not_in_trash(id: &str) -> Error {
+    Error::coded(
+        ErrorCode::FileNotFound,
+        format!("Nothing with id {} is in the trash", id),
+    )
+}
+
+/// Moves `path`, which must be inside the instance but not its root or trash, into the trash
+pub async fn move_to_trash(instance_path: &Path, path: &Path) -> Result<TrashEntry, Error> {
+    let relative = match path.strip_prefix(instance_path) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative.to_path_buf(),
+        _ => {
+            return Err(eyre!("{} can't be moved to the trash", path.display()).into());
+        }
+    };
+    let is_dir = extended_length_path(path).is_dir();
+    let size = if is_dir {
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || dir_size(&path))
+            .await
+            .context("Failed to spawn blocking task")?
+    } else {
+        tokio::fs::metadata(extended_length_path(path))
+            .await
+            .context(format!("Failed to read metadata of {}", path.display()))?
+            .len()
+    };
+    let deleted_at = chrono::Utc::now();
+    // ids sort by deletion time, the suffix tells apart deletions within the same millisecond
+    let mut id = deleted_at.timestamp_millis().to_string();
+    let mut suffix = 1;
+    while entry_dir(instance_path, &id).exists() {
+        id = format!("{}-{}", deleted_at.timestamp_millis(), suffix);
+        suffix += 1;
+    }
+    let entry = TrashEntry {
+        id,
+        path: relative,
+        deleted_at: deleted_at.timestamp(),
+        is_dir,
+        size,
+    };
+    let destination = entry_dir(instance_path, &entry.id).join(&entry.path);
+    if let Some(parent) = destination.parent() {
+        crate::util::fs::create_dir_all(parent).await?;
+    }
+    crate::util::fs::rename(path, &destination).await?;
+    tokio::fs::write(
+        entry_metadata(instance_path, &entry.id),
+        serde_json::to_vec_pretty(&entry).context("Failed to serialize trash entry")?,
+    )
+    .await
+    .context("Failed to write trash entry")?;
+    Ok(entry)
+}
+
+/// What is in the trash, most recently deleted first
+pub async fn list_trash(instance_path: &Path) -> Result<Vec<TrashEntry>, Error> {
+    let trash = trash_dir(instance_path);
+    if !trash.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    let mut dir = tokio::fs::read_dir(&trash)
+        .await
+        .context(format!("Failed to read {}", trash.display()))?;
+    while let Some(file) = dir
+        .next_entry()
+        .await
+        .context(format!("Failed to read {}", trash.display()))?
+    {
+        if file.path().extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+        // an entry whose metadata is unreadable is left for a purge to clean up
+        match tokio::fs::read(file.path())
+            .await
+            .ok()
+            .and_then(|content| serde_json::from_slice::<TrashEntry>(&content).ok())
+        {
+            Some(entry) => entries.push(entry),
+            None => warn!("Ignoring unreadable trash entry {}", file.path().display()),
+        }
+    }
+    entries.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(b.id.cmp(&a.id)));
+    Ok(entries)
+}
+
+pub async fn get_trash_entry(instance_path: &Path, id: &str) -> Result<TrashEntry, Error> {
+    // ids are file names, anything else can't be one
+    if id.is_empty() || id.contains(|c: char| !(c.is_ascii_digit() || c == '-')) {
+        return Err(not_in_trash(id));
+    }
+    let content = tokio::fs::read(entry_metadata(instance_path, id))
+        .await
+        .map_err(|_| not_in_trash(id))?;
+    Ok(serde_json::from_slice(&content).context("Failed to parse trash entry")?)
+}
+
+/// Puts the entry back where it was deleted from, unless something took its place since
+pub async fn restore_from_trash(instance_path: &Path, id: &str) -> Result<TrashEntry, Error> {
+    let entry = get_trash_entry(instance_path, id).await?;
+    let original = instance_path.join(&entry.path);
+    if extended_length_path(&original).exists() {
+        return Err(Error::coded(
+            ErrorCode::Conflict,
+            format!(
+                "{} already exists, move or delete it before restoring",
+                entry.path.display()
+            ),
+        )
+        .with_details(serde_json::json!({ "path": entry.path })));
+    }
+    if let Some(parent) = original.parent() {
+        crate::util::fs::create_dir_all(parent).await?;
+    }
+    crate::util::fs::rename(entry_dir(instance_path, id).join(&entry.path), &original).await?;
+    purge_trash_entry(instance_path, id).await?;
+    Ok(entry)
+}
+
+/// Deletes the entry for good
+pub async fn purge_trash_entry(instance_path: &Path, id: &str) -> Result<(), Error> {
+    let dir = entry_dir(instance_path, id);
+    if dir.exists() {
+        crate::util::fs::remove_dir_all(&dir).await?;
+    }
+    let metadata = entry_metadata(instance_path, id);
+    if metadata.exists() {
+        crate::util::fs::remove_file(&metadata).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the entries older than `retention_days` for good, returning how many there were
+pub async fn purge_expired(instance_path: &Path, retention_days: u32) -> Result<usize, Error> {
+    let cutoff = chrono::Utc::now().timestamp() - retention_days as i64 * 24 * 60 * 60;
+    let mut purged = 0;
+    for entry in list_trash(instance_path).await? {
+        if entry.deleted_at < cutoff {
+            purge_trash_entry(instance_path, &entry.id).await?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Purges expired trash of every instance every hour, a retention of 0 days keeps trash forever
+pub async fn run_purge(
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let retention_days = global_settings.lock().await.trash_retention_days();
+        if retention_days == 0 {
+            continue;
+        }
+        let instances: Vec<(InstanceUuid, GameInstance)> = instances
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (uuid, instance) in instances {
+            match purge_expired(&instance.path().await, retention_days).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired trash entries of {}", purged, uuid),
+                Err(e) => warn!("Failed to purge the trash of {}: {}", uuid, e.source),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[tokio::test]
+    async fn test_trash_and_restore() {
+        let instance = tempfile::tempdir().unwrap();
+        let root = instance.path();
+        std::fs::create_dir_all(root.join("config/mod")).unwrap();
+        std::fs::write(root.join("config/mod/settings.toml"), "radius = 3").unwrap();
+
+        let entry = move_to_trash(root, &root.join("config/mod")).await.unwrap();
+        assert!(!root.join("config/mod").exists());
+        assert!(entry_dir(root, &entry.id)
+            .join("config/mod/settings.toml")
+            .is_file());
+        assert_eq!(entry.path, PathBuf::from("config/mod"));
+        assert!(entry.is_dir);
+        assert_eq!(entry.size, 10);
+        assert_eq!(list_trash(root).await.unwrap(), vec![entry.clone()]);
+
+        // something took its place
+        std::fs::create_dir_all(root.join("config/mod")).unwrap();
+        let error = restore_from_trash(root, &entry.id).await.unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::Conflict));
+        std::fs::remove_dir(root.join("config/mod")).unwrap();
+
+        restore_from_trash(root, &entry.id).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("config/mod/settings.toml")).unwrap(),
+            "radius = 3"
+        );
+        assert!(list_trash(root).await.unwrap().is_empty());
+        assert!(restore_from_trash(root, &entry.id).await.is_err());
+        assert!(get_trash_entry(root, "../config").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired() {
+        let instance = tempfile::tempdir().unwrap();
+        let root = instance.path();
+        std::fs::write(root.join("old.txt"), "old").unwrap();
+        std::fs::write(root.join("new.txt"), "new").unwrap();
+        let mut old = move_to_trash(root, &root.join("old.txt")).await.unwrap();
+        let new = move_to_trash(root, &root.join("new.txt")).await.unwrap();
+        old.deleted_at -= 31 * 24 * 60 * 60;
+        std::fs::write(
+            entry_metadata(root, &old.id),
+            serde_json::to_vec(&old).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(purge_expired(root, 30).await.unwrap(), 1);
+        assert_eq!(list_trash(root).await.unwrap(), vec![new]);
+        assert!(!entry_dir(root, &old.id).exists());
+    }
+}
@@ -1,15 +1,23 @@
+use std::collections::HashSet;
 use std::fmt::Display;
+use std::path::Path;
 
+use color_eyre::eyre::eyre;
+
+use crate::auth::user_id::UserId;
+use crate::error::{Error, ErrorKind};
 use crate::migration::DotLodestoneConfigV043;
 use crate::traits::t_configurable::GameType;
 use crate::{
-    implementations::minecraft::Flavour, migration::RestoreConfigV042, prelude::SNOWFLAKE_GENERATOR,
+    implementations::minecraft::Flavour, migration::RestoreConfigV042,
+    prelude::LODESTONE_EPOCH_MIL, prelude::SNOWFLAKE_GENERATOR,
 };
 use serde::{Deserialize, Serialize};
 use serde_aux::prelude::*;
 use ts_rs::TS;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, TS, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, TS, Copy)]
 #[ts(export)]
 #[serde(into = "String")]
 #[derive(sqlx::Type)]
@@ -42,6 +50,11 @@ fn test_snowflake() {
     // deserializing
     let snowflake2: Snowflake = serde_json::from_str(&snowflake_str).unwrap();
     assert_eq!(snowflake1, snowflake2);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    assert!((now - snowflake1.timestamp_millis()).abs() < 1000);
 }
 
 impl Default for Snowflake {
@@ -54,6 +67,11 @@ impl Snowflake {
     pub fn new() -> Self {
         Self(get_snowflake())
     }
+
+    /// Unix timestamp in milliseconds at which the snowflake was generated
+    pub fn timestamp_millis(&self) -> i64 {
+        (self.0 >> 22) + LODESTONE_EPOCH_MIL.with(|p| *p)
+    }
 }
 
 impl ToString for Snowflake {
@@ -66,17 +84,51 @@ fn get_snowflake() -> i64 {
     SNOWFLAKE_GENERATOR.lock().unwrap().real_time_generate()
 }
 
-#[derive(Debug, Clone, Eq, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, Eq, Serialize, Deserialize, TS, ToSchema)]
 #[serde(transparent)]
 #[ts(export)]
 #[derive(sqlx::Type)]
 #[sqlx(transparent)]
 pub struct InstanceUuid(String);
 
+/// UUIDs drawn before giving up on finding one no instance has, a clash is already unlikely
+const MAX_UUID_ATTEMPTS: usize = 16;
+
 impl InstanceUuid {
     pub fn no_prefix(&self) -> String {
         self.0.replace("INSTANCE_", "")
     }
+
+    /// The first 8 characters of the UUID, for display where the whole one doesn't fit
+    pub fn short(&self) -> String {
+        self.no_prefix().chars().take(8).collect()
+    }
+
+    /// A new UUID whose short form no instance in `existing` shares, so it stays unique even
+    /// where only the short form is shown
+    pub fn unique_among(existing: impl IntoIterator<Item = InstanceUuid>) -> Result<Self, Error> {
+        Self::unique_among_with(existing, InstanceUuid::default)
+    }
+
+    fn unique_among_with(
+        existing: impl IntoIterator<Item = InstanceUuid>,
+        mut generate: impl FnMut() -> InstanceUuid,
+    ) -> Result<Self, Error> {
+        let taken: HashSet<String> = existing.into_iter().map(|uuid| uuid.short()).collect();
+        for _ in 0..MAX_UUID_ATTEMPTS {
+            let uuid = generate();
+            if !taken.contains(&uuid.short()) {
+                return Ok(uuid);
+            }
+        }
+        Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "Failed to generate an instance UUID no instance has in {} attempts",
+                MAX_UUID_ATTEMPTS
+            ),
+        })
+    }
 }
 
 impl From<String> for InstanceUuid {
@@ -123,6 +175,78 @@ pub struct LodestoneMetadata {
     pub semver: semver::Version,
 }
 
+/// Which files of an instance only users allowed to manage protected files may write
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ProtectedFilesPolicy {
+    /// Extensions without the leading dot, compared case insensitively
+    pub extensions: Vec<String>,
+    /// Names of directories, wherever they are in the instance
+    pub directories: Vec<String>,
+}
+
+impl Default for ProtectedFilesPolicy {
+    fn default() -> Self {
+        Self {
+            extensions: [
+                "jar",
+                "lua",
+                "sh",
+                "exe",
+                "bat",
+                "cmd",
+                "msi",
+                "lodestone_config",
+                "out",
+                "inf",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            directories: vec!["mods".to_string()],
+        }
+    }
+}
+
+impl ProtectedFilesPolicy {
+    /// Decided by name only so it also works for a destination that doesn't exist yet
+    pub fn is_protected_dir(&self, path: impl AsRef<Path>) -> bool {
+        path.as_ref()
+            .file_name()
+            .and_then(|s| s.to_str())
+            .map_or(true, |name| self.directories.iter().any(|d| d == name))
+    }
+
+    /// Files without an extension are not protected, a dotfile like `.lodestone_config` uses
+    /// its name after the dot as the extension
+    pub fn is_protected_file(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let extension = path.extension().or_else(|| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix('.'))
+                .map(std::ffi::OsStr::new)
+        });
+        match extension {
+            Some(extension) => extension.to_str().map_or(true, |extension| {
+                self.extensions
+                    .iter()
+                    .any(|e| e.eq_ignore_ascii_case(extension))
+            }),
+            None => false,
+        }
+    }
+
+    pub fn is_protected(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        if path.is_dir() {
+            self.is_protected_dir(path)
+        } else {
+            self.is_protected_file(path)
+        }
+    }
+}
+
 /// A marker file to indicate to lodestone that the directory contains a lodestone instance
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
 #[ts(export)]
@@ -130,6 +254,45 @@ pub struct DotLodestoneConfig {
     game_type: GameType,
     uuid: InstanceUuid,
     creation_time: i64,
+    /// Absent in configs written before the policy was configurable
+    #[serde(default)]
+    protected_files: ProtectedFilesPolicy,
+    /// Most bytes the instance directory may take up through the fs endpoints
+    #[serde(default)]
+    disk_quota: Option<u64>,
+    #[serde(default)]
+    auto_start_order: AutoStartOrder,
+    /// Free-form labels for grouping instances, kept normalized by `set_tags`
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    maintenance: Option<Maintenance>,
+}
+
+/// Set while someone works on the instance, it isn't started until it's cleared except by whoever
+/// set it and users allowed to bypass maintenance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, ToSchema)]
+#[ts(export)]
+pub struct Maintenance {
+    pub reason: Option<String>,
+    #[schema(value_type = String)]
+    pub set_by: UserId,
+    pub set_by_name: String,
+    /// Unix timestamp in seconds
+    pub since: i64,
+}
+
+/// When an instance flagged to auto start is started relative to the others
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct AutoStartOrder {
+    /// Instances with a higher priority are running before lower ones are started, e.g. a
+    /// proxy before its backends
+    #[serde(default)]
+    pub priority: i32,
+    /// Seconds to wait before starting the instance once its priority's turn comes
+    #[serde(default)]
+    pub delay_seconds: u32,
 }
 
 impl From<RestoreConfigV042> for DotLodestoneConfig {
@@ -145,6 +308,11 @@ impl From<RestoreConfigV042> for DotLodestoneConfig {
             game_type,
             uuid: config.uuid,
             creation_time: config.creation_time,
+            protected_files: ProtectedFilesPolicy::default(),
+            disk_quota: None,
+            auto_start_order: AutoStartOrder::default(),
+            tags: Vec::new(),
+            maintenance: None,
         }
     }
 }
@@ -155,6 +323,11 @@ impl From<DotLodestoneConfigV043> for DotLodestoneConfig {
             game_type: config.game_type,
             uuid: config.uuid,
             creation_time: config.creation_time,
+            protected_files: ProtectedFilesPolicy::default(),
+            disk_quota: None,
+            auto_start_order: AutoStartOrder::default(),
+            tags: Vec::new(),
+            maintenance: None,
         }
     }
 }
@@ -165,6 +338,11 @@ impl DotLodestoneConfig {
             game_type,
             uuid,
             creation_time: chrono::Utc::now().timestamp(),
+            protected_files: ProtectedFilesPolicy::default(),
+            disk_quota: None,
+            auto_start_order: AutoStartOrder::default(),
+            tags: Vec::new(),
+            maintenance: None,
         }
     }
 
@@ -178,6 +356,84 @@ impl DotLodestoneConfig {
     pub fn game_type(&self) -> &GameType {
         &self.game_type
     }
+
+    pub fn protected_files(&self) -> &ProtectedFilesPolicy {
+        &self.protected_files
+    }
+
+    pub fn set_protected_files(&mut self, protected_files: ProtectedFilesPolicy) {
+        self.protected_files = protected_files;
+    }
+
+    pub fn disk_quota(&self) -> Option<u64> {
+        self.disk_quota
+    }
+
+    pub fn set_disk_quota(&mut self, disk_quota: Option<u64>) {
+        self.disk_quota = disk_quota;
+    }
+
+    pub fn auto_start_order(&self) -> AutoStartOrder {
+        self.auto_start_order
+    }
+
+    pub fn set_auto_start_order(&mut self, auto_start_order: AutoStartOrder) {
+        self.auto_start_order = auto_start_order;
+    }
+
+    pub fn maintenance(&self) -> Option<&Maintenance> {
+        self.maintenance.as_ref()
+    }
+
+    pub fn set_maintenance(&mut self, maintenance: Option<Maintenance>) {
+        self.maintenance = maintenance;
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Normalizes the tags, duplicates differing only in case or surrounding whitespace are
+    /// kept once
+    pub fn set_tags(&mut self, tags: impl IntoIterator<Item = String>) -> Result<(), Error> {
+        let mut normalized = Vec::new();
+        for tag in tags {
+            let tag = normalize_tag(&tag);
+            if tag.is_empty() {
+                continue;
+            }
+            if tag.chars().count() > MAX_TAG_LENGTH {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Tags can be at most {} characters long", MAX_TAG_LENGTH),
+                });
+            }
+            if !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+        if normalized.len() > MAX_TAGS {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("An instance can have at most {} tags", MAX_TAGS),
+            });
+        }
+        normalized.sort();
+        self.tags = normalized;
+        Ok(())
+    }
+}
+
+/// Most tags one instance can have
+pub const MAX_TAGS: usize = 32;
+/// Most characters in one tag
+pub const MAX_TAG_LENGTH: usize = 64;
+/// Most characters in the reason an instance is under maintenance
+pub const MAX_MAINTENANCE_REASON_LENGTH: usize = 256;
+
+/// The form tags are stored and compared in, so `Prod` and ` prod` are the same tag
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
 }
 
 #[test]
@@ -190,3 +446,76 @@ fn test_instance_uuid() {
     let uuid2: InstanceUuid = serde_json::from_str(&uuid_str).unwrap();
     assert_eq!(uuid1, uuid2);
 }
+
+#[test]
+fn test_protected_files_policy() {
+    let policy = ProtectedFilesPolicy::default();
+    assert!(policy.is_protected_file("server.jar"));
+    assert!(policy.is_protected_file("start.SH"));
+    assert!(policy.is_protected_file(".lodestone_config"));
+    assert!(!policy.is_protected_file("eula.txt"));
+    assert!(!policy.is_protected_file("whitelist"));
+    assert!(policy.is_protected_dir("mods"));
+    assert!(!policy.is_protected_dir("world"));
+
+    let policy = ProtectedFilesPolicy {
+        extensions: vec!["properties".to_string()],
+        directories: Vec::new(),
+    };
+    assert!(!policy.is_protected_file("server.jar"));
+    assert!(policy.is_protected_file("server.properties"));
+    assert!(!policy.is_protected_dir("mods"));
+
+    // configs written before the policy existed get the default one
+    let config: DotLodestoneConfig = serde_json::from_str(
+        r#"{"game_type":"MinecraftJava","uuid":"INSTANCE_test","creation_time":0}"#,
+    )
+    .unwrap();
+    assert_eq!(config.protected_files(), &ProtectedFilesPolicy::default());
+}
+
+#[test]
+fn test_unique_instance_uuid() {
+    let uuid = |s: &str| InstanceUuid::from(format!("INSTANCE_{}", s));
+    let existing = vec![
+        uuid("aaaaaaaa-0000-4000-8000-000000000000"),
+        uuid("bbbbbbbb-0000-4000-8000-000000000000"),
+    ];
+    assert_eq!(existing[0].short(), "aaaaaaaa");
+
+    // a redrawn UUID is checked against every existing one, not only those after the clash
+    let mut drawn = vec![
+        uuid("cccccccc-0000-4000-8000-000000000000"),
+        uuid("aaaaaaaa-1111-4000-8000-000000000000"),
+        uuid("bbbbbbbb-1111-4000-8000-000000000000"),
+    ];
+    let unique =
+        InstanceUuid::unique_among_with(existing.clone(), || drawn.pop().unwrap()).unwrap();
+    assert_eq!(unique.short(), "cccccccc");
+
+    let e = InstanceUuid::unique_among_with(existing.clone(), || existing[1].clone()).unwrap_err();
+    assert!(matches!(e.kind, ErrorKind::Internal));
+
+    let unique = InstanceUuid::unique_among(existing.clone()).unwrap();
+    assert!(!existing.contains(&unique));
+}
+
+#[test]
+fn test_tags() {
+    let mut config = DotLodestoneConfig::new(InstanceUuid::default(), GameType::MinecraftJava);
+    assert!(config.tags().is_empty());
+    config
+        .set_tags(["Prod", " prod ", "", "EU-West"].map(String::from))
+        .unwrap();
+    assert_eq!(config.tags(), ["eu-west", "prod"]);
+
+    let e = config
+        .set_tags(["x".repeat(MAX_TAG_LENGTH + 1)])
+        .unwrap_err();
+    assert!(matches!(e.kind, ErrorKind::BadRequest));
+    let e = config
+        .set_tags((0..=MAX_TAGS).map(|i| i.to_string()))
+        .unwrap_err();
+    assert!(matches!(e.kind, ErrorKind::BadRequest));
+    assert_eq!(config.tags(), ["eu-west", "prod"]);
+}
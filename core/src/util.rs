@@ -1,7 +1,7 @@
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::io::{Read, Write};
+use std::io::Write;
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
@@ -15,6 +15,7 @@ use ts_rs::TS;
 
 use flate2::read::GzDecoder;
 use tar::Archive;
+use tracing::warn;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Authentication {
@@ -22,7 +23,8 @@ pub struct Authentication {
     password: String,
 }
 
-use crate::error::Error;
+use crate::archive_manifest::{ArchiveManifest, HashingReader, MANIFEST_FILE_NAME};
+use crate::error::{Error, ErrorCode, ErrorKind};
 use crate::prelude::path_to_tmp;
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
@@ -37,6 +39,8 @@ pub struct DownloadProgress {
     pub downloaded: u64,
     pub step: u64,
     pub download_name: String,
+    /// Bytes per second since the transfer started
+    pub speed: Option<u64>,
 }
 pub async fn download_file(
     url: &str,
@@ -102,6 +106,7 @@ pub async fn download_file(
     let mut downloaded: u64 = 0;
     let mut new_downloaded: u64 = 0;
     let threshold = total_size.unwrap_or(500000) / 100;
+    let started = std::time::Instant::now();
     let mut stream = response.bytes_stream();
     while let Some(item) = stream.next().await {
         let chunk = item.context("Failed to read response")?;
@@ -117,6 +122,7 @@ pub async fn download_file(
                 downloaded,
                 step,
                 download_name: file_name.clone(),
+                speed: bytes_per_second(new_downloaded, started.elapsed()),
             });
             downloaded = new_downloaded;
         }
@@ -127,6 +133,14 @@ pub async fn download_file(
     Ok(path.join(&file_name))
 }
 
+/// None until enough time passed for the rate to mean anything
+pub fn bytes_per_second(bytes: u64, elapsed: std::time::Duration) -> Option<u64> {
+    if elapsed < std::time::Duration::from_millis(100) {
+        return None;
+    }
+    Some((bytes as f64 / elapsed.as_secs_f64()) as u64)
+}
+
 /// List all files in a directory
 /// files_or_dir = 0 -> files, 1 -> directories
 pub async fn list_dir(
@@ -136,7 +150,7 @@ pub async fn list_dir(
     let ret: Result<Vec<PathBuf>, Error> = tokio::task::spawn_blocking({
         let path = path.to_owned();
         move || {
-            Ok(std::fs::read_dir(&path)
+            Ok(std::fs::read_dir(extended_length_path(&path))
                 .context(format!("failed to read directory {}", path.display()))?
                 .filter_map(|entry| entry.ok())
                 .filter(|entry| entry.file_type().is_ok())
@@ -146,7 +160,7 @@ pub async fn list_dir(
                     Some(false) => entry.file_type().unwrap().is_file(),
                     None => true,
                 })
-                .map(|entry| entry.path())
+                .map(|entry| strip_extended_length_prefix(entry.path()))
                 .collect())
         }
     })
@@ -155,6 +169,143 @@ pub async fn list_dir(
     ret
 }
 
+/// Deepest directory nesting the fs helpers will traverse
+pub const MAX_TRAVERSAL_DEPTH: usize = 256;
+/// Longest single path component most filesystems accept
+pub const MAX_PATH_COMPONENT_LENGTH: usize = 255;
+/// Classic Win32 `MAX_PATH`, longer paths need the `\\?\` prefix
+#[cfg(windows)]
+const WINDOWS_MAX_PATH: usize = 260;
+
+/// Adds the `\\?\` extended-length prefix to absolute paths that exceed `MAX_PATH` on Windows.
+///
+/// Returns the path unchanged on other platforms
+pub fn extended_length_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    #[cfg(windows)]
+    {
+        if path.as_os_str().len() >= WINDOWS_MAX_PATH {
+            return with_extended_length_prefix(path);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Adds the `\\?\` prefix to any absolute path on Windows, regardless of its length.
+///
+/// Used for traversal roots, whose descendants may exceed `MAX_PATH` even if the root doesn't
+fn with_extended_length_prefix(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let s = path.to_string_lossy();
+        if path.is_absolute() && !s.starts_with(r"\\?\") {
+            // the extended-length syntax does no normalization, so separators must be backslashes
+            let s = s.replace('/', r"\");
+            return match s.strip_prefix(r"\\") {
+                Some(unc) => PathBuf::from(format!(r"\\?\UNC\{unc}")),
+                None => PathBuf::from(format!(r"\\?\{s}")),
+            };
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Inverse of `extended_length_path`, paths returned to clients should never carry the prefix
+pub fn strip_extended_length_prefix(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    #[cfg(windows)]
+    {
+        let s = path.to_string_lossy();
+        if let Some(unc) = s.strip_prefix(r"\\?\UNC\") {
+            return PathBuf::from(format!(r"\\{unc}"));
+        }
+        if let Some(local) = s.strip_prefix(r"\\?\") {
+            return PathBuf::from(local);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Checks that `path` is no longer than `max_len` bytes and that none of its components
+/// exceed `MAX_PATH_COMPONENT_LENGTH`
+pub fn check_path_length(path: impl AsRef<Path>, max_len: usize) -> Result<(), Error> {
+    let path = strip_extended_length_prefix(path);
+    let mut prefix = PathBuf::new();
+    for component in path.components() {
+        let name = component.as_os_str();
+        if name.len() > MAX_PATH_COMPONENT_LENGTH {
+            return Err(Error::coded(
+                ErrorCode::PathTooLong,
+                format!(
+                    "Path component \"{}\" is {} bytes long, exceeding the limit of {} bytes",
+                    name.to_string_lossy(),
+                    name.len(),
+                    MAX_PATH_COMPONENT_LENGTH
+                ),
+            ));
+        }
+        prefix.push(name);
+        if prefix.as_os_str().len() > max_len {
+            return Err(Error::coded(
+                ErrorCode::PathTooLong,
+                format!(
+                    "Path exceeds the maximum length of {} bytes at component \"{}\"",
+                    max_len,
+                    name.to_string_lossy()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Iterates over `path` and everything below it, parents before children.
+///
+/// The traversal keeps an explicit stack instead of recursing, and fails once the tree is
+/// nested deeper than `max_depth` rather than silently skipping the rest
+pub fn walk_dir(
+    path: impl AsRef<Path>,
+    max_depth: usize,
+) -> impl Iterator<Item = Result<walkdir::DirEntry, Error>> {
+    walkdir::WalkDir::new(with_extended_length_prefix(path.as_ref()))
+        .max_depth(max_depth + 1)
+        .into_iter()
+        .map(move |entry| {
+            let entry = entry.context("Failed to walk directory")?;
+            if entry.depth() > max_depth {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "{} is nested deeper than the limit of {} directories",
+                        strip_extended_length_prefix(entry.path()).display(),
+                        max_depth
+                    ),
+                });
+            }
+            Ok(entry)
+        })
+}
+
+/// Removes a directory and its content without recursion.
+///
+/// The whole tree is walked before anything is deleted, so a tree that is too deep is left intact
+pub fn remove_dir_tree(dir: impl AsRef<Path>) -> Result<(), Error> {
+    let entries = walk_dir(dir, MAX_TRAVERSAL_DEPTH).collect::<Result<Vec<_>, _>>()?;
+    // parents are yielded before their children
+    for entry in entries.iter().rev() {
+        let result = if entry.file_type().is_dir() {
+            std::fs::remove_dir(entry.path())
+        } else {
+            std::fs::remove_file(entry.path())
+        };
+        result.context(format!(
+            "Failed to remove {}",
+            strip_extended_length_prefix(entry.path()).display()
+        ))?;
+    }
+    Ok(())
+}
+
 pub fn resolve_path_conflict(path: PathBuf, predicate: Option<&dyn Fn(&Path) -> bool>) -> PathBuf {
     let predicate = predicate.unwrap_or(&Path::exists);
     let name = path
@@ -196,9 +347,104 @@ pub enum UnzipOption {
     ToDir(PathBuf),
 }
 
+/// Checks every entry of an archive against the destination before anything is extracted
+fn validate_archive_entry_paths(
+    file: &Path,
+    dest: &Path,
+    max_path_length: usize,
+) -> Result<(), Error> {
+    let check_entry = |name: &Path| -> Result<(), Error> {
+        if name.components().count() > MAX_TRAVERSAL_DEPTH {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Archive entry {} is nested deeper than the limit of {} directories",
+                    name.display(),
+                    MAX_TRAVERSAL_DEPTH
+                ),
+            });
+        }
+        check_path_length(dest.join(name), max_path_length)
+    };
+    let archive_file =
+        std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
+    if file.extension() == Some(OsStr::new("zip")) {
+        let mut archive = zip::ZipArchive::new(archive_file)
+            .context(format!("Failed to decompress file {}", file.display()))?;
+        for i in 0..archive.len() {
+            let entry = archive
+                .by_index(i)
+                .context(format!("Failed to read entry of {}", file.display()))?;
+            let name = entry.enclosed_name().ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Archive entry {} has an unsafe path", entry.name()),
+            })?;
+            check_entry(name)?;
+        }
+    } else {
+        let mut archive = Archive::new(GzDecoder::new(archive_file));
+        for entry in archive
+            .entries()
+            .context(format!("Failed to decompress file {}", file.display()))?
+        {
+            let entry = entry.context(format!("Failed to read entry of {}", file.display()))?;
+            let name = entry
+                .path()
+                .context(format!("Failed to read entry of {}", file.display()))?;
+            check_entry(&*name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Extracts a zip archive entry by entry, building every path from its components so the
+/// extended-length prefix of `dest` stays valid
+fn extract_zip(archive: &mut zip::ZipArchive<std::fs::File>, dest: &Path) -> Result<(), Error> {
+    for i in 0..archive.len() {
+        let mut entry = archive
+            .by_index(i)
+            .context("Failed to read archive entry")?;
+        let name = match entry.enclosed_name() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let out_path = name.components().fold(dest.to_path_buf(), |mut acc, c| {
+            acc.push(c.as_os_str());
+            acc
+        });
+        if entry.is_dir() {
+            std::fs::create_dir_all(&out_path)
+                .context(format!("Failed to create directory {}", name.display()))?;
+            continue;
+        }
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create directory for {}", name.display()))?;
+        }
+        let mut out_file = std::fs::File::create(&out_path)
+            .context(format!("Failed to create file {}", name.display()))?;
+        std::io::copy(&mut entry, &mut out_file)
+            .context(format!("Failed to extract {}", name.display()))?;
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&out_path, std::fs::Permissions::from_mode(mode))
+                .context(format!("Failed to set permissions of {}", name.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Extracts `file` according to `unzip_option`.
+///
+/// Every entry is checked against `max_path_length` before anything is written. With `verify`,
+/// the extracted files are checked against the archive's checksum manifest before they are
+/// moved into place; archives without a manifest are extracted with a warning
 pub fn unzip_file(
     file: impl AsRef<Path>,
     unzip_option: UnzipOption,
+    max_path_length: usize,
+    verify: bool,
 ) -> Result<HashSet<PathBuf>, Error> {
     let file = file.as_ref();
 
@@ -229,6 +475,16 @@ pub fn unzip_file(
         UnzipOption::ToDirectoryWithFileName => resolve_path_conflict(parent.join(file_stem), None),
         UnzipOption::ToDir(ref d) => d.to_owned(),
     };
+    // smart unzipping ends up in a directory named after the file at worst
+    validate_archive_entry_paths(
+        file,
+        &match unzip_option {
+            UnzipOption::Smart => parent.join(file_stem),
+            _ => dest.clone(),
+        },
+        max_path_length,
+    )?;
+
     let lodestone_tmp = path_to_tmp().clone();
     std::fs::create_dir_all(&lodestone_tmp).context(format!(
         "Failed to create temporary directory {}",
@@ -238,7 +494,7 @@ pub fn unzip_file(
     let temp_dest_dir = tempfile::tempdir_in(lodestone_tmp).context(
         "Failed to create temporary directory for unzipping. Please make sure you have enough space in your disk",
     )?;
-    let temp_dest = temp_dest_dir.path();
+    let temp_dest = &with_extended_length_prefix(temp_dest_dir.path());
 
     if file_extension == "gz" || file_extension == "tgz" {
         let tar_gz =
@@ -254,11 +510,26 @@ pub fn unzip_file(
             std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
         let mut archive = zip::ZipArchive::new(zip)
             .context(format!("Failed to decompress file {}", file.display()))?;
-        archive
-            .extract(temp_dest)
+        extract_zip(&mut archive, temp_dest)
             .context(format!("Failed to decompress file {}", file.display()))?;
     }
 
+    match ArchiveManifest::read_from_dir(temp_dest)? {
+        Some(manifest) if verify => manifest.verify_dir(temp_dest)?.into_result()?,
+        None if verify => warn!(
+            "{} has no checksum manifest, extracting without verification",
+            file.display()
+        ),
+        _ => {}
+    }
+    let manifest_path = temp_dest.join(MANIFEST_FILE_NAME);
+    if manifest_path.exists() {
+        std::fs::remove_file(&manifest_path).context(format!(
+            "Failed to remove {} from extracted files",
+            MANIFEST_FILE_NAME
+        ))?;
+    }
+
     let mut ret: HashSet<PathBuf> = HashSet::new();
 
     let temp_dir_content = std::fs::read_dir(temp_dest)
@@ -288,9 +559,9 @@ pub fn unzip_file(
             None,
         );
 
-        std::fs::rename(&temp_path, &entry_path).context(format!(
+        std::fs::rename(&temp_path, extended_length_path(&entry_path)).context(format!(
             "Failed to move {} to {}",
-            temp_path.display(),
+            strip_extended_length_prefix(&temp_path).display(),
             entry_path.display()
         ))?;
         ret.insert(entry_path);
@@ -302,9 +573,11 @@ pub fn unzip_file(
 pub async fn unzip_file_async(
     file: impl AsRef<Path>,
     unzip_option: UnzipOption,
+    max_path_length: usize,
+    verify: bool,
 ) -> Result<HashSet<PathBuf>, Error> {
     let _file = file.as_ref().to_owned();
-    tokio::task::spawn_blocking(move || unzip_file(_file, unzip_option))
+    tokio::task::spawn_blocking(move || unzip_file(_file, unzip_option, max_path_length, verify))
         .await
         .context(format!(
             "Failed to unzip file {} in a blocking task",
@@ -312,6 +585,206 @@ pub async fn unzip_file_async(
         ))?
 }
 
+/// What extracting an archive in place did
+#[derive(Serialize, Debug, Clone, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct ExtractSummary {
+    pub extracted: u64,
+    /// Existing files left untouched because `keep_existing` asked for it
+    pub skipped: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+fn is_tar_gz(file: &Path) -> Result<bool, Error> {
+    let name = file
+        .file_name()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if name.ends_with(".zip") {
+        Ok(false)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Ok(true)
+    } else {
+        Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Unsupported archive {}, expected .zip, .tar.gz or .tgz",
+                file.display()
+            ),
+        })
+    }
+}
+
+/// Calls `f` on the name of every entry, with a reader for regular files and `None` for
+/// directories. Links and other special entries are left out
+fn for_each_archive_entry(
+    file: &Path,
+    f: &mut dyn FnMut(&Path, Option<&mut dyn std::io::Read>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let tar_gz = is_tar_gz(file)?;
+    let archive_file =
+        std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
+    if tar_gz {
+        let mut archive = Archive::new(GzDecoder::new(archive_file));
+        for entry in archive
+            .entries()
+            .context(format!("Failed to decompress file {}", file.display()))?
+        {
+            let mut entry = entry.context(format!("Failed to read entry of {}", file.display()))?;
+            let name = entry
+                .path()
+                .context(format!("Failed to read entry of {}", file.display()))?
+                .into_owned();
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                f(&name, None)?;
+            } else if entry_type.is_file() {
+                f(&name, Some(&mut entry))?;
+            }
+        }
+    } else {
+        let mut archive = zip::ZipArchive::new(archive_file)
+            .context(format!("Failed to decompress file {}", file.display()))?;
+        for i in 0..archive.len() {
+            let mut entry = archive
+                .by_index(i)
+                .context(format!("Failed to read entry of {}", file.display()))?;
+            let name = PathBuf::from(entry.name());
+            if entry.is_dir() {
+                f(&name, None)?;
+            } else {
+                f(&name, Some(&mut entry))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks where every entry of the archive would land in `dest` before anything is written,
+/// returning how many entries there are
+pub fn check_archive_entries(
+    file: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    max_path_length: usize,
+) -> Result<u64, Error> {
+    let mut count = 0;
+    for_each_archive_entry(file.as_ref(), &mut |name, _| {
+        check_path_length(scoped_join_win_safe(dest.as_ref(), name)?, max_path_length)?;
+        count += 1;
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// The names of the entries at the top of the archive, its checksum manifest left out
+pub fn archive_top_level_names(file: impl AsRef<Path>) -> Result<HashSet<PathBuf>, Error> {
+    let mut names = HashSet::new();
+    for_each_archive_entry(file.as_ref(), &mut |name, _| {
+        if let Some(std::path::Component::Normal(first)) = name.components().next() {
+            if first != MANIFEST_FILE_NAME {
+                names.insert(PathBuf::from(first));
+            }
+        }
+        Ok(())
+    })?;
+    Ok(names)
+}
+
+/// Sums the uncompressed size of the regular files in a `.zip`, `.tar.gz` or `.tgz` archive, as
+/// recorded in the archive itself
+pub fn archive_uncompressed_size(file: impl AsRef<Path>) -> Result<u64, Error> {
+    let file = file.as_ref();
+    let archive_file =
+        std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
+    let mut total = 0;
+    if is_tar_gz(file)? {
+        let mut archive = Archive::new(GzDecoder::new(archive_file));
+        for entry in archive
+            .entries()
+            .context(format!("Failed to decompress file {}", file.display()))?
+        {
+            let entry = entry.context(format!("Failed to read entry of {}", file.display()))?;
+            if entry.header().entry_type().is_file() {
+                total += entry.size();
+            }
+        }
+    } else {
+        let mut archive = zip::ZipArchive::new(archive_file)
+            .context(format!("Failed to decompress file {}", file.display()))?;
+        for i in 0..archive.len() {
+            let entry = archive
+                .by_index(i)
+                .context(format!("Failed to read entry of {}", file.display()))?;
+            if !entry.is_dir() {
+                total += entry.size();
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Extracts a `.zip`, `.tar.gz` or `.tgz` archive straight into `dest`, replacing existing files
+/// unless `keep_existing` says otherwise.
+///
+/// Every entry name goes through `scoped_join_win_safe`, so an entry can't escape `dest`.
+/// `on_entry` is called with the number of entries handled so far, an error from it aborts the
+/// extraction and removes the files it had created. Files it had replaced stay replaced
+pub fn extract_archive(
+    file: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    keep_existing: &dyn Fn(&Path) -> bool,
+    on_entry: &mut dyn FnMut(u64) -> Result<(), Error>,
+) -> Result<ExtractSummary, Error> {
+    let dest = dest.as_ref();
+    let mut summary = ExtractSummary::default();
+    let mut created = Vec::new();
+    let mut aborted = false;
+    let mut done = 0;
+    let result = for_each_archive_entry(file.as_ref(), &mut |name, reader| {
+        let target = scoped_join_win_safe(dest, name)?;
+        match reader {
+            None => std::fs::create_dir_all(extended_length_path(&target))
+                .context(format!("Failed to create directory {}", target.display()))?,
+            Some(_) if extended_length_path(&target).is_file() && keep_existing(&target) => {
+                summary.skipped.push(target)
+            }
+            Some(reader) => {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(extended_length_path(parent)).context(format!(
+                        "Failed to create directory for {}",
+                        target.display()
+                    ))?;
+                }
+                if !extended_length_path(&target).exists() {
+                    created.push(target.clone());
+                }
+                let mut out_file = std::fs::File::create(extended_length_path(&target))
+                    .context(format!("Failed to create file {}", target.display()))?;
+                summary.bytes += std::io::copy(reader, &mut out_file)
+                    .context(format!("Failed to extract {}", target.display()))?;
+                summary.extracted += 1;
+            }
+        }
+        done += 1;
+        on_entry(done).map_err(|e| {
+            aborted = true;
+            e
+        })
+    });
+    if let Err(e) = result {
+        if aborted {
+            for path in created {
+                if let Err(e) = std::fs::remove_file(extended_length_path(&path)) {
+                    warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+        return Err(e);
+    }
+    Ok(summary)
+}
+
+/// Archives `files` into a zip at `dest`, along with a checksum manifest of every file
 pub fn zip_files(
     files: &[impl AsRef<Path>],
     dest: impl AsRef<Path>,
@@ -328,7 +801,7 @@ pub fn zip_files(
     let tmp_archive = tempfile::NamedTempFile::new_in(lodestone_tmp)
         .context("Failed to create temporary file for zipping")?;
 
-    let mut buffer = Vec::new();
+    let mut manifest = ArchiveManifest::default();
     let mut writer = zip::ZipWriter::new(&tmp_archive);
     let options = zip::write::FileOptions::default().unix_permissions(0o775);
     for entry_path in files.iter().map(|f| f.as_ref()) {
@@ -347,20 +820,19 @@ pub fn zip_files(
                     entry_path.display()
                 ))?;
 
-            for child_entry in walkdir::WalkDir::new(entry_path)
-                .into_iter()
-                .filter_map(|e| e.ok())
-            {
+            for child_entry in walk_dir(entry_path, MAX_TRAVERSAL_DEPTH) {
+                let child_entry = child_entry?;
                 let child_entry_path = child_entry.path();
+                let child_entry_name = strip_extended_length_prefix(child_entry_path);
                 let child_entry_dest =
-                    child_entry_path
+                    child_entry_name
                         .strip_prefix(entry_path.parent().context(format!(
                             "Failed to get parent for {}",
                             entry_path.display()
                         ))?)
                         .context(format!(
                             "Failed to strip prefix for {}",
-                            child_entry_path.display()
+                            child_entry_name.display()
                         ))?;
 
                 if child_entry_path.is_dir() {
@@ -382,16 +854,15 @@ pub fn zip_files(
                             child_entry_path.display()
                         ))?;
 
-                    let mut child_entry_file = std::fs::File::open(child_entry_path)
-                        .context(format!("Failed to open {}", child_entry_path.display()))?;
-                    child_entry_file
-                        .read_to_end(&mut buffer)
-                        .context(format!("Failed to read {}", child_entry_path.display()))?;
-                    writer.write_all(&buffer).context(format!(
+                    let mut child_entry_file = HashingReader::new(
+                        std::fs::File::open(child_entry_path)
+                            .context(format!("Failed to open {}", child_entry_path.display()))?,
+                    );
+                    std::io::copy(&mut child_entry_file, &mut writer).context(format!(
                         "Failed to write {} to archive",
                         child_entry_path.display()
                     ))?;
-                    buffer.clear();
+                    manifest.insert(child_entry_dest, child_entry_file.finish());
                 }
             }
         }
@@ -408,19 +879,24 @@ pub fn zip_files(
                 entry_path.display()
             ))?;
 
-            let mut entry_file = std::fs::File::open(entry_path)
-                .context(format!("Failed to open {}", entry_path.display()))?;
-            entry_file
-                .read_to_end(&mut buffer)
-                .context(format!("Failed to read {}", entry_path.display()))?;
-            writer.write_all(&buffer).context(format!(
+            let mut entry_file = HashingReader::new(
+                std::fs::File::open(entry_path)
+                    .context(format!("Failed to open {}", entry_path.display()))?,
+            );
+            std::io::copy(&mut entry_file, &mut writer).context(format!(
                 "Failed to write {} to archive",
                 entry_path.display()
             ))?;
-            buffer.clear();
+            manifest.insert(Path::new(entry_name), entry_file.finish());
         }
     }
 
+    writer
+        .start_file(MANIFEST_FILE_NAME, options)
+        .context("Failed to create checksum manifest in archive")?;
+    writer
+        .write_all(&manifest.to_bytes()?)
+        .context("Failed to write checksum manifest to archive")?;
     writer.finish().context("Zip failed")?;
     let dest = if overwrite_dest {
         dest.into()
@@ -455,6 +931,22 @@ pub fn rand_alphanumeric(len: usize) -> String {
     thread_rng().sample_iter(&Alphanumeric).take(len).collect()
 }
 
+/// Lowercase alphanumerics of `name`, every other run of characters collapsed into a dash
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if slug.ends_with('-') {
+        slug.pop();
+    }
+    slug
+}
+
 // safe_path only works on linux and messes up on windows
 // this is a hacky solution
 pub fn scoped_join_win_safe<R: AsRef<Path>, U: AsRef<Path>>(
@@ -482,15 +974,17 @@ pub fn scoped_join_win_safe<R: AsRef<Path>, U: AsRef<Path>>(
 pub mod fs {
     use std::path::Path;
 
-    use color_eyre::eyre::Context;
+    use color_eyre::eyre::{eyre, Context};
     use tokio::fs::File;
+    use tokio::io::AsyncWriteExt;
 
+    use super::extended_length_path;
     use crate::error::Error;
 
     pub async fn remove_file(file: impl AsRef<Path>) -> Result<(), Error> {
         let file = file.as_ref();
-        if file.is_file() {
-            tokio::fs::remove_file(file)
+        if extended_length_path(file).is_file() {
+            tokio::fs::remove_file(extended_length_path(file))
                 .await
                 .context(format!("Failed to remove file at {}", file.display()))?;
         }
@@ -499,7 +993,7 @@ pub mod fs {
 
     pub async fn write_all(file: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Result<(), Error> {
         let file = file.as_ref();
-        tokio::fs::write(file, data)
+        tokio::fs::write(extended_length_path(file), data)
             .await
             .context(format!("Failed to write to file at {}", file.display()))?;
         Ok(())
@@ -508,33 +1002,78 @@ pub mod fs {
     pub async fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
         let from = from.as_ref();
         let to = to.as_ref();
-        tokio::fs::rename(from, to).await.context(format!(
-            "Failed to rename file {} to {}",
-            from.display(),
-            to.display()
-        ))?;
+        tokio::fs::rename(extended_length_path(from), extended_length_path(to))
+            .await
+            .context(format!(
+                "Failed to rename file {} to {}",
+                from.display(),
+                to.display()
+            ))?;
         Ok(())
     }
 
+    /// Writes `data` to a temporary file next to `file` and renames it over `file`, so a crash
+    /// leaves either the old or the new content but never a mix of both
+    pub async fn write_atomic(file: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        let file = file.as_ref();
+        let file_name = file
+            .file_name()
+            .ok_or_else(|| eyre!("{} has no file name", file.display()))?;
+        let temp = file.with_file_name(format!(
+            ".{}.{}.tmp",
+            file_name.to_string_lossy(),
+            super::rand_alphanumeric(8)
+        ));
+        let result = async {
+            let mut temp_file = tokio::fs::File::create(extended_length_path(&temp))
+                .await
+                .context(format!("Failed to create file at {}", temp.display()))?;
+            temp_file
+                .write_all(data.as_ref())
+                .await
+                .context(format!("Failed to write to file at {}", temp.display()))?;
+            // keeps the mode of the file it replaces, e.g. of an executable start script
+            if let Ok(metadata) = tokio::fs::metadata(extended_length_path(file)).await {
+                temp_file
+                    .set_permissions(metadata.permissions())
+                    .await
+                    .context(format!("Failed to set permissions of {}", temp.display()))?;
+            }
+            temp_file
+                .sync_all()
+                .await
+                .context(format!("Failed to flush {} to disk", temp.display()))?;
+            drop(temp_file);
+            rename(&temp, file).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(extended_length_path(&temp)).await;
+        }
+        result
+    }
+
     pub async fn create_dir_all(dir: impl AsRef<Path>) -> Result<(), Error> {
         let dir = dir.as_ref();
-        tokio::fs::create_dir_all(dir)
+        tokio::fs::create_dir_all(extended_length_path(dir))
             .await
             .context(format!("Failed to create directory at {}", dir.display()))?;
         Ok(())
     }
 
     pub async fn remove_dir_all(dir: impl AsRef<Path>) -> Result<(), Error> {
-        let dir = dir.as_ref();
-        tokio::fs::remove_dir_all(dir)
-            .await
-            .context(format!("Failed to remove directory at {}", dir.display()))?;
-        Ok(())
+        let dir = dir.as_ref().to_owned();
+        tokio::task::spawn_blocking({
+            let dir = dir.clone();
+            move || super::remove_dir_tree(dir)
+        })
+        .await
+        .context(format!("Failed to remove directory at {}", dir.display()))?
     }
 
     pub async fn read_to_string(file: impl AsRef<Path>) -> Result<String, Error> {
         let file = file.as_ref();
-        let data = tokio::fs::read_to_string(file)
+        let data = tokio::fs::read_to_string(extended_length_path(file))
             .await
             .context(format!("Failed to read file at {}", file.display()))?;
         Ok(data)
@@ -542,7 +1081,7 @@ pub mod fs {
 
     pub async fn create(file: impl AsRef<Path>) -> Result<File, Error> {
         let file = file.as_ref();
-        let file = tokio::fs::File::create(file)
+        let file = tokio::fs::File::create(extended_length_path(file))
             .await
             .context(format!("Failed to create file at {}", file.display()))?;
         Ok(file)
@@ -639,13 +1178,41 @@ pub fn format_byte(mut bytes: u64) -> String {
 
 #[cfg(test)]
 mod tests {
+    use crate::archive_manifest::{
+        ArchiveManifest, HashingReader, VerificationReport, MANIFEST_FILE_NAME,
+    };
+    use crate::error::{Error, ErrorKind};
+    use crate::global_settings::DEFAULT_MAX_PATH_LENGTH;
     use crate::prelude::init_paths;
-    use crate::util::{resolve_path_conflict, unzip_file, zip_files, UnzipOption};
+    use crate::util::{
+        archive_top_level_names, check_archive_entries, check_path_length, extended_length_path,
+        extract_archive, list_dir, remove_dir_tree, resolve_path_conflict, slugify, unzip_file,
+        walk_dir, zip_files, UnzipOption, MAX_TRAVERSAL_DEPTH,
+    };
     use std::collections::HashSet;
-    use std::io::Read;
-    use std::path::PathBuf;
+    use std::ffi::OsStr;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
     use tokio;
 
+    const MAX_PATH: usize = DEFAULT_MAX_PATH_LENGTH as usize;
+
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("server.properties");
+        std::fs::write(&path, "server-port=25565\nmotd=a much longer old value\n").unwrap();
+        super::fs::write_atomic(&path, "server-port=25566\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "server-port=25566\n"
+        );
+        // no temporary file is left behind
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
     #[tokio::test]
     async fn test_unzip_file() {
         let temp_lodestone_path = tempfile::tempdir().unwrap();
@@ -661,7 +1228,13 @@ mod tests {
         test.insert(temp_path.join("constitution.txt"));
 
         assert_eq!(
-            unzip_file(&zip, UnzipOption::ToDir(temp_path.to_owned())).unwrap(),
+            unzip_file(
+                &zip,
+                UnzipOption::ToDir(temp_path.to_owned()),
+                MAX_PATH,
+                true
+            )
+            .unwrap(),
             test
         );
 
@@ -671,7 +1244,13 @@ mod tests {
         test.insert(temp_path.join("constitution_1.txt"));
 
         assert_eq!(
-            unzip_file(&zip, UnzipOption::ToDir(temp_path.to_owned())).unwrap(),
+            unzip_file(
+                &zip,
+                UnzipOption::ToDir(temp_path.to_owned()),
+                MAX_PATH,
+                true
+            )
+            .unwrap(),
             test
         );
     }
@@ -689,7 +1268,13 @@ mod tests {
         expected.insert(dest_path.join("sample"));
 
         assert_eq!(
-            unzip_file(&tar_gz, UnzipOption::ToDir(dest_path.clone())).unwrap(),
+            unzip_file(
+                &tar_gz,
+                UnzipOption::ToDir(dest_path.clone()),
+                MAX_PATH,
+                true
+            )
+            .unwrap(),
             expected
         );
         assert!(dest_path.join("sample").join("sample.exe").is_file());
@@ -700,7 +1285,13 @@ mod tests {
         expected.insert(dest_path.join("sample_1"));
 
         assert_eq!(
-            unzip_file(&tar_gz, UnzipOption::ToDir(dest_path.to_owned())).unwrap(),
+            unzip_file(
+                &tar_gz,
+                UnzipOption::ToDir(dest_path.to_owned()),
+                MAX_PATH,
+                true
+            )
+            .unwrap(),
             expected
         );
         assert!(dest_path.join("sample_1").join("sample.exe").is_file());
@@ -780,7 +1371,9 @@ mod tests {
         assert_eq!(
             unzip_file(
                 dest_path.join("test_dest_2.zip"),
-                UnzipOption::ToDir(dest_path.join("unzipped"))
+                UnzipOption::ToDir(dest_path.join("unzipped")),
+                MAX_PATH,
+                true,
             )
             .unwrap(),
             expected
@@ -831,4 +1424,291 @@ mod tests {
         buf_reader.read_to_string(&mut contents).unwrap();
         assert_eq!(contents.trim(), "test2_test2_test1");
     }
+
+    /// Builds a tree nested well beyond the 260 characters of Windows' `MAX_PATH`
+    fn create_deep_tree(root: &Path) -> PathBuf {
+        let deep = (0..30).fold(root.join("deep"), |acc, i| {
+            acc.join(format!("nested_{i:03}"))
+        });
+        assert!(deep.as_os_str().len() > 260);
+        std::fs::create_dir_all(extended_length_path(&deep)).unwrap();
+        std::fs::write(extended_length_path(deep.join("level.dat")), "deep").unwrap();
+        deep
+    }
+
+    #[test]
+    fn test_check_path_length() {
+        assert!(check_path_length("/a/b/c", 16).is_ok());
+        let e = check_path_length("/a/bb/ccc/dddd", 8).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+        assert!(e.to_string().contains("8 bytes"));
+        assert!(e.to_string().contains("\"ccc\""));
+
+        let long_component = "x".repeat(256);
+        let e = check_path_length(Path::new("/a").join(&long_component), 4096).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+        assert!(e.to_string().contains(&long_component));
+    }
+
+    #[tokio::test]
+    async fn test_deep_tree() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let deep = create_deep_tree(root);
+
+        // listing returns paths without the extended-length prefix
+        let listed = list_dir(deep.parent().unwrap(), None).await.unwrap();
+        assert_eq!(listed, vec![deep.clone()]);
+
+        let entries = walk_dir(root.join("deep"), MAX_TRAVERSAL_DEPTH)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        // "deep", the 30 nested directories and level.dat
+        assert_eq!(entries.len(), 32);
+        let e = walk_dir(root.join("deep"), 10)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+
+        // round trip through an archive
+        let archive = zip_files(&[root.join("deep")], root.join("deep.zip"), false).unwrap();
+        let unzipped = root.join("unzipped");
+        unzip_file(
+            &archive,
+            UnzipOption::ToDir(unzipped.clone()),
+            MAX_PATH,
+            true,
+        )
+        .unwrap();
+        let extracted = unzipped.join(deep.strip_prefix(root).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(extended_length_path(extracted.join("level.dat"))).unwrap(),
+            "deep"
+        );
+
+        // a destination that can't fit the entries is rejected before anything is written
+        let e = unzip_file(
+            &archive,
+            UnzipOption::ToDir(root.join("too_long")),
+            deep.as_os_str().len() - 20,
+            true,
+        )
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+        assert!(e.to_string().contains("maximum length"));
+        assert!(!root.join("too_long").exists());
+
+        remove_dir_tree(root.join("deep")).unwrap();
+        remove_dir_tree(&unzipped).unwrap();
+        assert!(!root.join("deep").exists());
+        assert!(!unzipped.exists());
+    }
+
+    #[tokio::test]
+    async fn test_unzip_verification() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+
+        // a tar.gz fixture with a manifest, tar carries no checksum of its own for file content
+        let files = [
+            ("world/level.dat", "level data"),
+            ("server.properties", "motd=hello"),
+        ];
+        let mut manifest = ArchiveManifest::default();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut reader = HashingReader::new(content.as_bytes());
+            std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+            manifest.insert(Path::new(path), reader.finish());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let manifest = manifest.to_bytes().unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, MANIFEST_FILE_NAME, manifest.as_slice())
+            .unwrap();
+        let mut tar = builder.into_inner().unwrap();
+        let write_archive = |tar: &[u8], name: &str| {
+            let path = root.join(name);
+            let mut encoder = flate2::write::GzEncoder::new(
+                std::fs::File::create(&path).unwrap(),
+                flate2::Compression::default(),
+            );
+            encoder.write_all(tar).unwrap();
+            encoder.finish().unwrap();
+            path
+        };
+
+        let intact = write_archive(&tar, "intact.tar.gz");
+        unzip_file(
+            &intact,
+            UnzipOption::ToDir(root.join("intact")),
+            MAX_PATH,
+            true,
+        )
+        .unwrap();
+        assert!(root.join("intact/world/level.dat").is_file());
+        assert!(!root.join("intact").join(MANIFEST_FILE_NAME).exists());
+
+        let offset = tar.windows(10).position(|w| w == b"level data").unwrap();
+        tar[offset] ^= 1;
+        let corrupted = write_archive(&tar, "corrupted.tar.gz");
+        let e = unzip_file(
+            &corrupted,
+            UnzipOption::ToDir(root.join("corrupted")),
+            MAX_PATH,
+            true,
+        )
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+        let report = e.source.downcast_ref::<VerificationReport>().unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report
+                .mismatched
+                .iter()
+                .map(|m| m.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["world/level.dat"]
+        );
+        assert!(report.missing.is_empty());
+        assert!(!root.join("corrupted").exists());
+
+        // the escape hatch extracts anyway
+        unzip_file(
+            &corrupted,
+            UnzipOption::ToDir(root.join("corrupted")),
+            MAX_PATH,
+            false,
+        )
+        .unwrap();
+        assert!(root.join("corrupted/world/level.dat").is_file());
+    }
+
+    #[test]
+    fn test_extract_archive_stays_in_dest() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("pack.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        for (name, content) in [
+            ("../../escaped.txt", "evil"),
+            ("mods/sodium.jar", "new"),
+            ("config/sodium.toml", "new"),
+        ] {
+            writer
+                .start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        let dest = temp.path().join("instance");
+        std::fs::create_dir_all(dest.join("mods")).unwrap();
+        std::fs::write(dest.join("mods/sodium.jar"), "old").unwrap();
+
+        assert_eq!(check_archive_entries(&archive, &dest, MAX_PATH).unwrap(), 3);
+        assert_eq!(archive_uncompressed_size(&archive).unwrap(), 10);
+        assert_eq!(
+            archive_top_level_names(&archive).unwrap(),
+            HashSet::from([PathBuf::from("mods"), PathBuf::from("config")])
+        );
+        let mut progress = Vec::new();
+        let summary = extract_archive(
+            &archive,
+            &dest,
+            &|path| path.extension() == Some(OsStr::new("jar")),
+            &mut |done| {
+                progress.push(done);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(progress, vec![1, 2, 3]);
+        assert_eq!(summary.extracted, 2);
+        assert_eq!(summary.skipped, vec![dest.join("mods").join("sodium.jar")]);
+        // the traversal is clamped to the destination
+        assert_eq!(
+            std::fs::read_to_string(dest.join("escaped.txt")).unwrap(),
+            "evil"
+        );
+        assert!(!temp.path().join("escaped.txt").exists());
+        let read = |path: &str| std::fs::read_to_string(dest.join(path)).unwrap();
+        assert_eq!(read("mods/sodium.jar"), "old");
+        assert_eq!(read("config/sodium.toml"), "new");
+
+        let summary = extract_archive(&archive, &dest, &|_| false, &mut |_| Ok(())).unwrap();
+        assert_eq!(summary.extracted, 3);
+        assert!(summary.skipped.is_empty());
+        assert_eq!(read("mods/sodium.jar"), "new");
+
+        // an abort from the callback removes the files the extraction created
+        let fresh = temp.path().join("fresh");
+        let e = extract_archive(&archive, &fresh, &|_| false, &mut |done| {
+            if done == 2 {
+                Err(Error {
+                    kind: ErrorKind::Conflict,
+                    source: color_eyre::eyre::eyre!("Cancelled"),
+                })
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err();
+        assert!(e.source.to_string().contains("Cancelled"));
+        assert!(walk_dir(&fresh, MAX_TRAVERSAL_DEPTH)
+            .filter_map(Result::ok)
+            .all(|entry| entry.file_type().is_dir()));
+    }
+
+    #[test]
+    fn test_extract_tar_gz() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("world.tgz");
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            std::fs::File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "world/level.dat", &b"data"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let dest = temp.path().join("instance");
+        assert_eq!(check_archive_entries(&archive, &dest, MAX_PATH).unwrap(), 1);
+        assert_eq!(archive_uncompressed_size(&archive).unwrap(), 4);
+        // a single top-level directory, the smart unzip doesn't wrap it in another one
+        assert_eq!(
+            archive_top_level_names(&archive).unwrap(),
+            HashSet::from([PathBuf::from("world")])
+        );
+        let summary = extract_archive(&archive, &dest, &|_| true, &mut |_| Ok(())).unwrap();
+        assert_eq!(summary.extracted, 1);
+        assert_eq!(summary.bytes, 4);
+        assert!(dest.join("world/level.dat").is_file());
+
+        std::fs::write(temp.path().join("world.rar"), "").unwrap();
+        let e = check_archive_entries(temp.path().join("world.rar"), &dest, MAX_PATH).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::BadRequest));
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("My Survival World"), "my-survival-world");
+        assert_eq!(slugify("  SkyBlock!! (1.20) "), "skyblock-1-20");
+        assert_eq!(slugify("---"), "");
+    }
 }
@@ -55,6 +55,11 @@ async fn main() {
         is_cli: false,
         is_desktop: true,
         lodestone_path: None,
+        demo: false,
+        demo_seed: 0,
+        port: None,
+        shutdown_timeout: 60,
+        no_stop_instances: false,
     })
     .await;
 